@@ -133,17 +133,18 @@ fn parse_http_response(socket: &mut dyn Read) -> Result<Option<String>, Error> {
     }
 }
 
-pub fn simple_api_command<T: Read + Write>(
+/// Sends a request to the `full_command` endpoint, such as `vmm.ping`.
+pub fn simple_api_full_command<T: Read + Write>(
     socket: &mut T,
     method: &str,
-    c: &str,
+    full_command: &str,
     request_body: Option<&str>,
 ) -> Result<(), Error> {
     socket
         .write_all(
             format!(
-                "{} /api/v1/vm.{} HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n",
-                method, c
+                "{} /api/v1/{} HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n",
+                method, full_command
             )
             .as_bytes(),
         )
@@ -170,3 +171,13 @@ pub fn simple_api_command<T: Read + Write>(
     }
     Ok(())
 }
+
+/// Sends a request to the `vm.<c>` endpoint.
+pub fn simple_api_command<T: Read + Write>(
+    socket: &mut T,
+    method: &str,
+    c: &str,
+    request_body: Option<&str>,
+) -> Result<(), Error> {
+    simple_api_full_command(socket, method, &format!("vm.{}", c), request_body)
+}
@@ -52,6 +52,8 @@ const IRQ_TYPE_LEVEL_HI: u32 = 4;
 // Keys and Buttons
 // System Power Down
 const KEY_POWER: u32 = 116;
+// System Sleep
+const KEY_SLEEP: u32 = 142;
 
 /// Trait for devices to be added to the Flattened Device Tree.
 pub trait DeviceInfoForFdt {
@@ -80,6 +82,7 @@ pub fn create_fdt<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHash
     gic_device: &dyn GicDevice,
     initrd: &Option<InitramfsConfig>,
     pci_space_address: &(u64, u64),
+    pmu: bool,
 ) -> FdtWriterResult<Vec<u8>> {
     // Allocate stuff necessary for the holding the blob.
     let mut fdt = FdtWriter::new().unwrap();
@@ -103,6 +106,9 @@ pub fn create_fdt<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHash
     create_chosen_node(&mut fdt, cmdline.to_str().unwrap(), initrd)?;
     create_gic_node(&mut fdt, gic_device)?;
     create_timer_node(&mut fdt)?;
+    if pmu {
+        create_pmu_node(&mut fdt)?;
+    }
     create_clock_node(&mut fdt)?;
     create_psci_node(&mut fdt)?;
     create_devices_node(&mut fdt, device_info)?;
@@ -263,6 +269,24 @@ fn create_timer_node(fdt: &mut FdtWriter) -> FdtWriterResult<()> {
     Ok(())
 }
 
+fn create_pmu_node(fdt: &mut FdtWriter) -> FdtWriterResult<()> {
+    // See
+    // https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/pmu.yaml
+    let compatible = "arm,armv8-pmuv3";
+    let irq = [
+        GIC_FDT_IRQ_TYPE_PPI,
+        super::layout::PMU_PPI,
+        IRQ_TYPE_LEVEL_HI,
+    ];
+
+    let pmu_node = fdt.begin_node("pmu")?;
+    fdt.property_string("compatible", compatible)?;
+    fdt.property_array_u32("interrupts", &irq)?;
+    fdt.end_node(pmu_node)?;
+
+    Ok(())
+}
+
 fn create_psci_node(fdt: &mut FdtWriter) -> FdtWriterResult<()> {
     let compatible = "arm,psci-0.2";
     let psci_node = fdt.begin_node("psci")?;
@@ -374,6 +398,12 @@ fn create_gpio_node<T: DeviceInfoForFdt + Clone + Debug>(
     let gpios = [GPIO_PHANDLE, 3, 0];
     fdt.property_array_u32("gpios", &gpios)?;
     fdt.end_node(gpio_keys_poweroff_node)?;
+    let gpio_keys_sleep_node = fdt.begin_node("button@2")?;
+    fdt.property_string("label", "GPIO Key Sleep")?;
+    fdt.property_u32("linux,code", KEY_SLEEP)?;
+    let gpios = [GPIO_PHANDLE, 4, 0];
+    fdt.property_array_u32("gpios", &gpios)?;
+    fdt.end_node(gpio_keys_sleep_node)?;
     fdt.end_node(gpio_keys_node)?;
 
     Ok(())
@@ -461,7 +491,7 @@ fn create_pci_nodes(
         (pci_device_size_64bit >> 32) as u32, // size
         pci_device_size_64bit as u32,
     ];
-    let bus_range = [0, 0]; // Only bus 0
+    let bus_range = [0, 0xff]; // Root bus and buses behind the bridges
     let reg = [PCI_MMCONFIG_START.0, PCI_MMCONFIG_SIZE];
 
     let pci_node = fdt.begin_node("pci")?;
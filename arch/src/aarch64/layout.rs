@@ -106,3 +106,9 @@ pub const IRQ_BASE: u32 = 32;
 
 /// Number of supported interrupts
 pub const IRQ_NUM: u32 = 256;
+
+/// First private peripheral interrupt (PPI) on aarch64
+pub const PPI_BASE: u32 = 16;
+
+/// PPI signaling the overflows of the PMU
+pub const PMU_PPI: u32 = 7;
@@ -143,6 +143,7 @@ pub fn configure_system<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::Bui
     initrd: &Option<super::InitramfsConfig>,
     pci_space_address: &(u64, u64),
     gic_device: &dyn GicDevice,
+    pmu: bool,
 ) -> super::Result<()> {
     let fdt_final = fdt::create_fdt(
         guest_mem,
@@ -152,6 +153,7 @@ pub fn configure_system<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::Bui
         gic_device,
         initrd,
         pci_space_address,
+        pmu,
     )
     .map_err(|_| Error::SetupFdt)?;
 
@@ -5,7 +5,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! Implements platform specific functionality.
-//! Supported platforms: x86_64, aarch64.
+//! Supported platforms: x86_64, aarch64, riscv64.
 #![allow(clippy::transmute_ptr_to_ptr, clippy::redundant_static_lifetimes)]
 
 #[macro_use]
@@ -25,6 +25,9 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     /// AArch64 specific error triggered during system configuration.
     AArch64Setup(aarch64::Error),
+    #[cfg(target_arch = "riscv64")]
+    /// RISC-V 64 specific error triggered during system configuration.
+    RiscV64Setup(riscv64::Error),
     /// The zero page extends past the end of guest_mem.
     ZeroPagePastRamEnd,
     /// Error writing the zero page of guest memory.
@@ -78,6 +81,16 @@ pub use aarch64::{
     layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, uefi, EntryPoint,
 };
 
+/// Module for riscv64 related functionality.
+#[cfg(target_arch = "riscv64")]
+pub mod riscv64;
+
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::{
+    arch_memory_regions, configure_system, fdt::DeviceInfoForFdt, get_kernel_start,
+    initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, EntryPoint,
+};
+
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
@@ -85,7 +98,7 @@ pub mod x86_64;
 pub use x86_64::{
     arch_memory_regions, configure_system, configure_vcpu, get_host_cpu_phys_bits,
     initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE, layout::CMDLINE_START, regs, CpuidPatch,
-    CpuidReg, EntryPoint,
+    CpuidReg, EntryPoint, SmbiosIdentity,
 };
 
 /// Safe wrapper for `sysconf(_SC_PAGESIZE)`.
@@ -110,7 +123,7 @@ pub enum DeviceType {
     /// Device Type: Virtio.
     Virtio(u32),
     /// Device Type: Serial.
-    #[cfg(target_arch = "aarch64")]
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
     Serial,
     /// Device Type: RTC.
     #[cfg(target_arch = "aarch64")]
@@ -131,13 +144,13 @@ impl fmt::Display for DeviceType {
 
 /// Structure to describe MMIO device information
 #[derive(Clone, Debug)]
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub struct MmioDeviceInfo {
     pub addr: u64,
     pub irq: u32,
 }
 
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 impl DeviceInfoForFdt for MmioDeviceInfo {
     fn addr(&self) -> u64 {
         self.addr
@@ -0,0 +1,238 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::Debug;
+use std::result;
+
+use super::super::DeviceType;
+use super::super::GuestMemoryMmap;
+use super::super::InitramfsConfig;
+use super::get_fdt_addr;
+use super::layout::{IRQ_NUM, PLIC_SIZE, PLIC_START, RAM_64BIT_START};
+use vm_fdt::{FdtWriter, FdtWriterResult};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryError};
+
+// This is a value for uniquely identifying the FDT node declaring the PLIC.
+const PLIC_PHANDLE: u32 = 1;
+// The interrupt controller of each hart gets its own phandle, starting from
+// this value.
+const CPU_INTC_PHANDLE_BASE: u32 = 2;
+
+// Read the documentation specified when appending the root node to the FDT.
+const ADDRESS_CELLS: u32 = 0x2;
+const SIZE_CELLS: u32 = 0x2;
+
+// Frequency of the timer, as reported by the KVM RISC-V implementation.
+const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+// Frequency of the clock of the 16550 UART.
+const SERIAL_CLOCK_FREQUENCY: u32 = 1_843_200;
+
+// Local interrupts of the harts, from
+// https://github.com/torvalds/linux/blob/master/arch/riscv/include/asm/csr.h
+const IRQ_S_EXT: u32 = 9;
+
+/// Trait for devices to be added to the Flattened Device Tree.
+pub trait DeviceInfoForFdt {
+    /// Returns the address where this device will be loaded.
+    fn addr(&self) -> u64;
+    /// Returns the associated interrupt for this device.
+    fn irq(&self) -> u32;
+    /// Returns the amount of memory that needs to be reserved for this device.
+    fn length(&self) -> u64;
+}
+
+/// Errors thrown while configuring the Flattened Device Tree for riscv64.
+#[derive(Debug)]
+pub enum Error {
+    /// Failure in writing FDT in memory.
+    WriteFdtToMemory(GuestMemoryError),
+}
+type Result<T> = result::Result<T, Error>;
+
+/// Creates the flattened device tree for this riscv64 VM.
+pub fn create_fdt<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHasher>(
+    guest_mem: &GuestMemoryMmap,
+    cmdline: &CStr,
+    num_cpus: u32,
+    device_info: &HashMap<(DeviceType, String), T, S>,
+    initrd: &Option<InitramfsConfig>,
+) -> FdtWriterResult<Vec<u8>> {
+    let mut fdt = FdtWriter::new().unwrap();
+
+    let root_node = fdt.begin_node("")?;
+    fdt.property_string("compatible", "linux,dummy-virt")?;
+    fdt.property_u32("#address-cells", ADDRESS_CELLS)?;
+    fdt.property_u32("#size-cells", SIZE_CELLS)?;
+    // Every device interrupt is routed through the PLIC.
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE)?;
+    create_cpu_nodes(&mut fdt, num_cpus)?;
+    create_memory_node(&mut fdt, guest_mem)?;
+    create_chosen_node(&mut fdt, cmdline.to_str().unwrap(), initrd)?;
+    create_plic_node(&mut fdt, num_cpus)?;
+    create_devices_node(&mut fdt, device_info)?;
+
+    fdt.end_node(root_node)?;
+
+    let fdt_final = fdt.finish()?;
+
+    Ok(fdt_final)
+}
+
+pub fn write_fdt_to_memory(fdt_final: Vec<u8>, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    // Write FDT to memory.
+    let fdt_address = GuestAddress(get_fdt_addr());
+    guest_mem
+        .write_slice(fdt_final.as_slice(), fdt_address)
+        .map_err(Error::WriteFdtToMemory)?;
+    Ok(())
+}
+
+// Following are the auxiliary function for creating the different nodes that we append to our FDT.
+fn create_cpu_nodes(fdt: &mut FdtWriter, num_cpus: u32) -> FdtWriterResult<()> {
+    // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/riscv/cpus.yaml.
+    let cpus_node = fdt.begin_node("cpus")?;
+    fdt.property_u32("#address-cells", 0x1)?;
+    fdt.property_u32("#size-cells", 0x0)?;
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY)?;
+
+    for cpu_id in 0..num_cpus {
+        let cpu_node = fdt.begin_node(&format!("cpu@{:x}", cpu_id))?;
+        fdt.property_string("device_type", "cpu")?;
+        fdt.property_string("compatible", "riscv")?;
+        fdt.property_string("riscv,isa", "rv64imafdc")?;
+        fdt.property_string("mmu-type", "riscv,sv48")?;
+        fdt.property_string("status", "okay")?;
+        // The hart ID.
+        fdt.property_u32("reg", cpu_id)?;
+
+        let intc_node = fdt.begin_node("interrupt-controller")?;
+        fdt.property_string("compatible", "riscv,cpu-intc")?;
+        fdt.property_u32("#interrupt-cells", 1)?;
+        fdt.property_null("interrupt-controller")?;
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE_BASE + cpu_id)?;
+        fdt.end_node(intc_node)?;
+
+        fdt.end_node(cpu_node)?;
+    }
+    fdt.end_node(cpus_node)?;
+    Ok(())
+}
+
+fn create_memory_node(fdt: &mut FdtWriter, guest_mem: &GuestMemoryMmap) -> FdtWriterResult<()> {
+    let mem_size = guest_mem.last_addr().raw_value() - RAM_64BIT_START + 1;
+    let mem_reg_prop = [RAM_64BIT_START, mem_size];
+
+    let memory_node = fdt.begin_node("memory")?;
+    fdt.property_string("device_type", "memory")?;
+    fdt.property_array_u64("reg", &mem_reg_prop)?;
+    fdt.end_node(memory_node)?;
+
+    Ok(())
+}
+
+fn create_chosen_node(
+    fdt: &mut FdtWriter,
+    cmdline: &str,
+    initrd: &Option<InitramfsConfig>,
+) -> FdtWriterResult<()> {
+    let chosen_node = fdt.begin_node("chosen")?;
+    fdt.property_string("bootargs", cmdline)?;
+
+    if let Some(initrd_config) = initrd {
+        let initrd_start = initrd_config.address.raw_value() as u64;
+        let initrd_end = initrd_config.address.raw_value() + initrd_config.size as u64;
+        fdt.property_u64("linux,initrd-start", initrd_start)?;
+        fdt.property_u64("linux,initrd-end", initrd_end)?;
+    }
+
+    fdt.end_node(chosen_node)?;
+
+    Ok(())
+}
+
+fn create_plic_node(fdt: &mut FdtWriter, num_cpus: u32) -> FdtWriterResult<()> {
+    // See
+    // https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/interrupt-controller/sifive,plic-1.0.0.yaml
+    let compatible = b"sifive,plic-1.0.0\0riscv,plic0\0";
+    // The guest runs in supervisor mode only, which is why each hart has a
+    // single context, receiving the supervisor external interrupts.
+    let mut interrupts: Vec<u32> = Vec::new();
+    for cpu_id in 0..num_cpus {
+        interrupts.extend_from_slice(&[CPU_INTC_PHANDLE_BASE + cpu_id, IRQ_S_EXT]);
+    }
+
+    let plic_node = fdt.begin_node(&format!("plic@{:x}", PLIC_START))?;
+    fdt.property("compatible", compatible)?;
+    fdt.property_u32("#address-cells", 0)?;
+    fdt.property_u32("#interrupt-cells", 1)?;
+    fdt.property_null("interrupt-controller")?;
+    fdt.property_array_u64("reg", &[PLIC_START, PLIC_SIZE])?;
+    fdt.property_u32("riscv,ndev", IRQ_NUM - 1)?;
+    fdt.property_array_u32("interrupts-extended", &interrupts)?;
+    fdt.property_u32("phandle", PLIC_PHANDLE)?;
+    fdt.end_node(plic_node)?;
+
+    Ok(())
+}
+
+fn create_virtio_node<T: DeviceInfoForFdt + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> FdtWriterResult<()> {
+    let device_reg_prop = [dev_info.addr(), dev_info.length()];
+
+    let virtio_node = fdt.begin_node(&format!("virtio_mmio@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "virtio,mmio")?;
+    fdt.property_array_u64("reg", &device_reg_prop)?;
+    fdt.property_u32("interrupts", dev_info.irq())?;
+    fdt.end_node(virtio_node)?;
+
+    Ok(())
+}
+
+fn create_serial_node<T: DeviceInfoForFdt + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> FdtWriterResult<()> {
+    let serial_reg_prop = [dev_info.addr(), dev_info.length()];
+
+    let serial_node = fdt.begin_node(&format!("uart@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "ns16550a")?;
+    fdt.property_array_u64("reg", &serial_reg_prop)?;
+    fdt.property_u32("clock-frequency", SERIAL_CLOCK_FREQUENCY)?;
+    fdt.property_u32("interrupts", dev_info.irq())?;
+    fdt.end_node(serial_node)?;
+
+    Ok(())
+}
+
+fn create_devices_node<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHasher>(
+    fdt: &mut FdtWriter,
+    dev_info: &HashMap<(DeviceType, String), T, S>,
+) -> FdtWriterResult<()> {
+    // Create one temp Vec to store all virtio devices
+    let mut ordered_virtio_device: Vec<&T> = Vec::new();
+
+    for ((device_type, _device_id), info) in dev_info {
+        match device_type {
+            DeviceType::Serial => create_serial_node(fdt, info)?,
+            DeviceType::Virtio(_) => {
+                ordered_virtio_device.push(info);
+            }
+        }
+    }
+
+    // Sort out virtio devices by address from low to high and insert them into fdt table,
+    // the same way it's done on aarch64.
+    ordered_virtio_device.sort_by_key(|&a| a.addr());
+    ordered_virtio_device.reverse();
+    for ordered_device_info in ordered_virtio_device.drain(..) {
+        create_virtio_node(fdt, ordered_device_info)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//
+// Memory layout of RISC-V 64 guest:
+//
+// Physical  +---------------------------------------------------------------+
+// address   |                                                               |
+// end       |                                                               |
+//           ~                   ~                       ~                   ~
+//           |                                                               |
+//           |                            DRAM                               |
+//           |                                                               |
+// 2GB       +---------------------------------------------------------------+
+//           |                                                               |
+//           |                           MMIO space                          |
+//           |                                                               |
+// 1GB       +---------------------------------------------------------------+
+//           |                                                               |
+//           |                        Legacy devices space                   |
+//           |                                                               |
+// 256 M     +---------------------------------------------------------------+
+//           |                              PLIC                             |
+// 192 M     +---------------------------------------------------------------+
+//           |                            Reserved                           |
+// 0GB       +---------------------------------------------------------------+
+//
+// There is no CLINT, as the timer and the inter-processor interrupts of the
+// supervisor mode guest are provided through the SBI implemented by KVM.
+//
+
+use vm_memory::GuestAddress;
+
+/// Platform-Level Interrupt Controller, routing the external interrupts.
+pub const PLIC_START: u64 = 0x0c00_0000;
+pub const PLIC_SIZE: u64 = 0x0400_0000;
+
+/// Space 0x1000_0000 ~ 0x1001_0000 is reserved for legacy devices.
+pub const LEGACY_SERIAL_MAPPED_IO_START: u64 = 0x1000_0000;
+
+/// Starting from 0x4000_0000 (1GiB) to 0x8000_0000 (2GiB) is used for MMIO devices.
+pub const MEM_32BIT_DEVICES_START: GuestAddress = GuestAddress(0x4000_0000);
+pub const MEM_32BIT_DEVICES_SIZE: u64 = 0x4000_0000;
+
+/// Start of RAM on RISC-V 64.
+pub const RAM_64BIT_START: u64 = 0x8000_0000;
+
+/// Kernel command line maximum size.
+/// As per `arch/riscv/include/uapi/asm/setup.h`.
+pub const CMDLINE_MAX_SIZE: usize = 1024;
+
+/// FDT is at the beginning of RAM.
+pub const FDT_START: u64 = RAM_64BIT_START;
+pub const FDT_MAX_SIZE: usize = 0x20_0000;
+
+/// Kernel start after FDT, which keeps it aligned on 2MiB as expected by
+/// https://www.kernel.org/doc/Documentation/riscv/boot.rst.
+pub const KERNEL_START: u64 = FDT_START + FDT_MAX_SIZE as u64;
+
+/// First usable interrupt on riscv64, as the interrupt source 0 of the PLIC
+/// means "no interrupt".
+pub const IRQ_BASE: u32 = 1;
+
+/// Number of supported interrupts
+pub const IRQ_NUM: u32 = 64;
@@ -0,0 +1,187 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Platform specific functionality of RISC-V 64 guests.
+//!
+//! Only the memory layout and the device tree describing the platform are
+//! handled here. Setting the vCPUs up relies on the KVM RISC-V API, which
+//! the hypervisor crate doesn't expose yet.
+
+/// Module for the flattened device tree.
+pub mod fdt;
+/// Layout for this riscv64 system.
+pub mod layout;
+
+pub use self::fdt::DeviceInfoForFdt;
+use crate::DeviceType;
+use crate::GuestMemoryMmap;
+use crate::RegionType;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::Debug;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestUsize};
+
+/// Errors thrown while configuring riscv64 system.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create a FDT.
+    SetupFdt,
+
+    /// Failed to write FDT to memory.
+    WriteFdtToMemory(fdt::Error),
+
+    /// Failed to compute the initramfs address.
+    InitramfsAddress,
+}
+
+impl From<Error> for super::Error {
+    fn from(e: Error) -> super::Error {
+        super::Error::RiscV64Setup(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+/// Specifies the entry point address where the guest must start
+/// executing code.
+pub struct EntryPoint {
+    /// Address in guest memory where the guest must start execution
+    pub entry_addr: GuestAddress,
+}
+
+pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, RegionType)> {
+    vec![
+        // 0 ~ 1 GiB: PLIC and legacy devices
+        (
+            GuestAddress(0),
+            layout::MEM_32BIT_DEVICES_START.0 as usize,
+            RegionType::Reserved,
+        ),
+        // 1 GiB ~ 2 GiB: MMIO space
+        (
+            layout::MEM_32BIT_DEVICES_START,
+            layout::MEM_32BIT_DEVICES_SIZE as usize,
+            RegionType::SubRegion,
+        ),
+        // 2 GiB ~ : Ram
+        (
+            GuestAddress(layout::RAM_64BIT_START),
+            size as usize,
+            RegionType::Ram,
+        ),
+    ]
+}
+
+/// Configures the system and should be called once per vm before starting vcpu threads.
+pub fn configure_system<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHasher>(
+    guest_mem: &GuestMemoryMmap,
+    cmdline_cstring: &CStr,
+    num_cpus: u32,
+    device_info: &HashMap<(DeviceType, String), T, S>,
+    initrd: &Option<super::InitramfsConfig>,
+) -> super::Result<()> {
+    let fdt_final = fdt::create_fdt(guest_mem, cmdline_cstring, num_cpus, device_info, initrd)
+        .map_err(|_| Error::SetupFdt)?;
+
+    fdt::write_fdt_to_memory(fdt_final, guest_mem).map_err(Error::WriteFdtToMemory)?;
+
+    Ok(())
+}
+
+/// Returns the memory address where the initramfs could be loaded.
+pub fn initramfs_load_addr(
+    guest_mem: &GuestMemoryMmap,
+    initramfs_size: usize,
+) -> super::Result<u64> {
+    let round_to_pagesize = |size| (size + (super::PAGE_SIZE - 1)) & !(super::PAGE_SIZE - 1);
+    match guest_mem
+        .last_addr()
+        .checked_sub(round_to_pagesize(initramfs_size) as u64 - 1)
+    {
+        Some(offset) => {
+            if guest_mem.address_in_range(offset) {
+                Ok(offset.raw_value())
+            } else {
+                Err(super::Error::RiscV64Setup(Error::InitramfsAddress))
+            }
+        }
+        None => Err(super::Error::RiscV64Setup(Error::InitramfsAddress)),
+    }
+}
+
+/// Returns the memory address where the kernel could be loaded.
+pub fn get_kernel_start() -> u64 {
+    layout::KERNEL_START
+}
+
+// Auxiliary function to get the address where the device tree blob is loaded.
+fn get_fdt_addr() -> u64 {
+    layout::FDT_START
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[derive(Clone, Debug)]
+    struct DeviceInfo {
+        addr: u64,
+        irq: u32,
+    }
+
+    impl DeviceInfoForFdt for DeviceInfo {
+        fn addr(&self) -> u64 {
+            self.addr
+        }
+        fn irq(&self) -> u32 {
+            self.irq
+        }
+        fn length(&self) -> u64 {
+            0x1000
+        }
+    }
+
+    #[test]
+    fn test_arch_memory_regions_dram() {
+        let regions = arch_memory_regions((1usize << 32) as u64); //4GB
+        assert_eq!(3, regions.len());
+        assert_eq!(GuestAddress(layout::RAM_64BIT_START), regions[2].0);
+        assert_eq!(1usize << 32, regions[2].1);
+        assert_eq!(RegionType::Ram, regions[2].2);
+    }
+
+    #[test]
+    fn test_create_fdt() {
+        let regions = vec![(GuestAddress(layout::RAM_64BIT_START), 0x1000_0000)];
+        let mem = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let dev_info: HashMap<(DeviceType, String), DeviceInfo> = [
+            (
+                (DeviceType::Serial, DeviceType::Serial.to_string()),
+                DeviceInfo {
+                    addr: layout::LEGACY_SERIAL_MAPPED_IO_START,
+                    irq: layout::IRQ_BASE,
+                },
+            ),
+            (
+                (DeviceType::Virtio(1), "virtio".to_string()),
+                DeviceInfo {
+                    addr: layout::MEM_32BIT_DEVICES_START.0,
+                    irq: layout::IRQ_BASE + 1,
+                },
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+
+        assert!(configure_system(
+            &mem,
+            &CString::new("console=ttyS0").unwrap(),
+            2,
+            &dev_info,
+            &None,
+        )
+        .is_ok());
+    }
+}
@@ -101,6 +101,9 @@ pub const IOAPIC_SIZE: u64 = 0x20;
 // APIC
 pub const APIC_START: GuestAddress = GuestAddress(0xfee0_0000);
 
+// TPM, at the address the firmware probes it at.
+pub const TPM_START: GuestAddress = GuestAddress(0xfed4_0000);
+
 /// Address for the TSS setup.
 pub const KVM_TSS_ADDRESS: GuestAddress = GuestAddress(0xfffb_d000);
 
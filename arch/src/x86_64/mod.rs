@@ -27,6 +27,7 @@ use vm_memory::{
     GuestMemoryRegion, GuestUsize,
 };
 mod smbios;
+pub use smbios::SmbiosIdentity;
 use std::arch::x86_64;
 #[cfg(feature = "tdx")]
 pub mod tdx;
@@ -145,9 +146,15 @@ pub enum Error {
     /// Cannot set the local interruption due to bad configuration.
     LocalIntConfiguration(anyhow::Error),
 
+    /// Failed to restrict the paravirtualized features to the CPUID.
+    PvFeatureEnforcement(anyhow::Error),
+
     /// Error setting up SMBIOS table
     SmbiosSetup(smbios::Error),
 
+    /// SMBIOS and MP tables not fitting below the high RAM
+    BiosTablesTooLarge,
+
     /// Could not find any SGX EPC section
     NoSgxEpcSection,
 
@@ -316,6 +323,7 @@ pub fn configure_vcpu(
     vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
     cpuid: CpuId,
     kvm_hyperv: bool,
+    kvmclock: bool,
 ) -> super::Result<()> {
     // Per vCPU CPUID changes; common are handled via CpuManager::generate_common_cpuid()
     let mut cpuid = cpuid;
@@ -329,6 +337,13 @@ pub fn configure_vcpu(
         fd.enable_hyperv_synic().unwrap();
     }
 
+    // Without enforcement, KVM keeps handling the kvmclock MSRs even though
+    // the CPUID doesn't advertise them.
+    if !kvmclock {
+        fd.enable_pv_feature_enforcement()
+            .map_err(|e| Error::PvFeatureEnforcement(e.into()))?;
+    }
+
     regs::setup_msrs(fd).map_err(Error::MsrsConfiguration)?;
     if let Some(kernel_entry_point) = kernel_entry_point {
         // Safe to unwrap because this method is called after the VM is configured
@@ -396,21 +411,30 @@ pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, Region
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `pci_irqs` - IRQ of each slot of the PCI bus 0, for the legacy interrupts.
+/// * `identity` - Identity of the VM reported through SMBIOS.
 #[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     cmdline_addr: GuestAddress,
     initramfs: &Option<InitramfsConfig>,
     _num_cpus: u8,
+    pci_irqs: &[u8],
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    identity: &SmbiosIdentity,
 ) -> super::Result<()> {
-    let size = smbios::setup_smbios(guest_mem).map_err(Error::SmbiosSetup)?;
+    let size = smbios::setup_smbios(guest_mem, identity).map_err(Error::SmbiosSetup)?;
 
-    // Place the MP table after the SMIOS table aligned to 16 bytes
+    // Place the MP table after the SMIOS table aligned to 16 bytes, both
+    // staying in the BIOS area below the high RAM.
     let offset = GuestAddress(layout::SMBIOS_START).unchecked_add(size);
     let offset = GuestAddress((offset.0 + 16) & !0xf);
-    mptable::setup_mptable(offset, guest_mem, _num_cpus).map_err(Error::MpTableSetup)?;
+    let mp_size = mptable::compute_mp_size(_num_cpus, pci_irqs.len()) as u64;
+    if offset.unchecked_add(mp_size) > layout::HIGH_RAM_START {
+        return Err(Error::BiosTablesTooLarge.into());
+    }
+    mptable::setup_mptable(offset, guest_mem, _num_cpus, pci_irqs).map_err(Error::MpTableSetup)?;
 
     // Check that the RAM is not smaller than the RSDP start address
     if let Some(rsdp_addr) = rsdp_addr {
@@ -752,8 +776,10 @@ mod tests {
             GuestAddress(0),
             &None,
             1,
+            &[],
             Some(layout::RSDP_POINTER),
             None,
+            &SmbiosIdentity::default(),
         );
         assert!(config_err.is_err());
 
@@ -767,7 +793,17 @@ mod tests {
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
 
-        configure_system(&gm, GuestAddress(0), &None, no_vcpus, None, None).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            &None,
+            no_vcpus,
+            &[],
+            None,
+            None,
+            &SmbiosIdentity::default(),
+        )
+        .unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
@@ -778,9 +814,29 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), &None, no_vcpus, None, None).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            &None,
+            no_vcpus,
+            &[],
+            None,
+            None,
+            &SmbiosIdentity::default(),
+        )
+        .unwrap();
 
-        configure_system(&gm, GuestAddress(0), &None, no_vcpus, None, None).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            &None,
+            no_vcpus,
+            &[],
+            None,
+            None,
+            &SmbiosIdentity::default(),
+        )
+        .unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
@@ -791,9 +847,29 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), &None, no_vcpus, None, None).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            &None,
+            no_vcpus,
+            &[],
+            None,
+            None,
+            &SmbiosIdentity::default(),
+        )
+        .unwrap();
 
-        configure_system(&gm, GuestAddress(0), &None, no_vcpus, None, None).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            &None,
+            no_vcpus,
+            &[],
+            None,
+            None,
+            &SmbiosIdentity::default(),
+        )
+        .unwrap();
     }
 
     #[test]
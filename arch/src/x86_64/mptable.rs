@@ -89,6 +89,13 @@ const MPC_SPEC: i8 = 4;
 const MPC_OEM: [c_char; 8] = char_array!(c_char; 'F', 'C', ' ', ' ', ' ', ' ', ' ', ' ');
 const MPC_PRODUCT_ID: [c_char; 12] = ['0' as c_char; 12];
 const BUS_TYPE_ISA: [u8; 6] = char_array!(u8; 'I', 'S', 'A', ' ', ' ', ' ');
+const BUS_TYPE_PCI: [u8; 6] = char_array!(u8; 'P', 'C', 'I', ' ', ' ', ' ');
+const ISA_BUS_ID: u8 = 0;
+// The first PCI bus listed in the MP table is the PCI bus 0, no matter its
+// MP bus ID.
+const PCI_BUS_ID: u8 = 1;
+// Number of interrupt pins (INTA# to INTD#) of a PCI slot.
+const PCI_IRQ_PINS: u8 = 4;
 const APIC_VERSION: u8 = 0x14;
 const CPU_STEPPING: u32 = 0x600;
 const CPU_FEATURE_APIC: u32 = 0x200;
@@ -109,18 +116,28 @@ fn mpf_intel_compute_checksum(v: &mpspec::mpf_intel) -> u8 {
     (!checksum).wrapping_add(1)
 }
 
-fn compute_mp_size(num_cpus: u8) -> usize {
+pub fn compute_mp_size(num_cpus: u8, num_pci_slots: usize) -> usize {
     mem::size_of::<MpfIntelWrapper>()
         + mem::size_of::<MpcTableWrapper>()
         + mem::size_of::<MpcCpuWrapper>() * (num_cpus as usize)
         + mem::size_of::<MpcIoapicWrapper>()
-        + mem::size_of::<MpcBusWrapper>()
+        + mem::size_of::<MpcBusWrapper>() * 2
         + mem::size_of::<MpcIntsrcWrapper>() * 16
+        + mem::size_of::<MpcIntsrcWrapper>() * num_pci_slots * PCI_IRQ_PINS as usize
         + mem::size_of::<MpcLintsrcWrapper>() * 2
 }
 
 /// Performs setup of the MP table for the given `num_cpus`.
-pub fn setup_mptable(offset: GuestAddress, mem: &GuestMemoryMmap, num_cpus: u8) -> Result<()> {
+///
+/// `pci_irqs` holds the IRQ each slot of the PCI bus 0 is routed to, which
+/// lets the guest find the legacy interrupts of the PCI devices without
+/// ACPI.
+pub fn setup_mptable(
+    offset: GuestAddress,
+    mem: &GuestMemoryMmap,
+    num_cpus: u8,
+    pci_irqs: &[u8],
+) -> Result<()> {
     if num_cpus as u32 > MAX_SUPPORTED_CPUS {
         return Err(Error::TooManyCpus);
     }
@@ -128,7 +145,7 @@ pub fn setup_mptable(offset: GuestAddress, mem: &GuestMemoryMmap, num_cpus: u8)
     // Used to keep track of the next base pointer into the MP table.
     let mut base_mp = offset;
 
-    let mp_size = compute_mp_size(num_cpus);
+    let mp_size = compute_mp_size(num_cpus, pci_irqs.len());
 
     if offset.unchecked_add(mp_size as u64) >= HIGH_RAM_START {
         warn!("Skipping mptable creation due to insufficient space");
@@ -194,13 +211,24 @@ pub fn setup_mptable(offset: GuestAddress, mem: &GuestMemoryMmap, num_cpus: u8)
         let size = mem::size_of::<MpcBusWrapper>();
         let mut mpc_bus = MpcBusWrapper(mpspec::mpc_bus::default());
         mpc_bus.0.type_ = mpspec::MP_BUS as u8;
-        mpc_bus.0.busid = 0;
+        mpc_bus.0.busid = ISA_BUS_ID;
         mpc_bus.0.bustype = BUS_TYPE_ISA;
         mem.write_obj(mpc_bus, base_mp)
             .map_err(Error::WriteMpcBus)?;
         base_mp = base_mp.unchecked_add(size as u64);
         checksum = checksum.wrapping_add(compute_checksum(&mpc_bus.0));
     }
+    {
+        let size = mem::size_of::<MpcBusWrapper>();
+        let mut mpc_bus = MpcBusWrapper(mpspec::mpc_bus::default());
+        mpc_bus.0.type_ = mpspec::MP_BUS as u8;
+        mpc_bus.0.busid = PCI_BUS_ID;
+        mpc_bus.0.bustype = BUS_TYPE_PCI;
+        mem.write_obj(mpc_bus, base_mp)
+            .map_err(Error::WriteMpcBus)?;
+        base_mp = base_mp.unchecked_add(size as u64);
+        checksum = checksum.wrapping_add(compute_checksum(&mpc_bus.0));
+    }
     {
         let size = mem::size_of::<MpcIoapicWrapper>();
         let mut mpc_ioapic = MpcIoapicWrapper(mpspec::mpc_ioapic::default());
@@ -221,7 +249,7 @@ pub fn setup_mptable(offset: GuestAddress, mem: &GuestMemoryMmap, num_cpus: u8)
         mpc_intsrc.0.type_ = mpspec::MP_INTSRC as u8;
         mpc_intsrc.0.irqtype = mpspec::MP_IRQ_SOURCE_TYPES_MP_INT as u8;
         mpc_intsrc.0.irqflag = mpspec::MP_IRQDIR_DEFAULT as u16;
-        mpc_intsrc.0.srcbus = 0;
+        mpc_intsrc.0.srcbus = ISA_BUS_ID;
         mpc_intsrc.0.srcbusirq = i;
         mpc_intsrc.0.dstapic = ioapicid;
         mpc_intsrc.0.dstirq = i;
@@ -230,6 +258,26 @@ pub fn setup_mptable(offset: GuestAddress, mem: &GuestMemoryMmap, num_cpus: u8)
         base_mp = base_mp.unchecked_add(size as u64);
         checksum = checksum.wrapping_add(compute_checksum(&mpc_intsrc.0));
     }
+    // Same routing as the ACPI _PRT, every pin of a slot sharing its IRQ.
+    // The polarity and trigger mode conform to the PCI bus, meaning the
+    // interrupts are active low and level triggered.
+    for (slot, irq) in pci_irqs.iter().enumerate() {
+        for pin in 0..PCI_IRQ_PINS {
+            let size = mem::size_of::<MpcIntsrcWrapper>();
+            let mut mpc_intsrc = MpcIntsrcWrapper(mpspec::mpc_intsrc::default());
+            mpc_intsrc.0.type_ = mpspec::MP_INTSRC as u8;
+            mpc_intsrc.0.irqtype = mpspec::MP_IRQ_SOURCE_TYPES_MP_INT as u8;
+            mpc_intsrc.0.irqflag = mpspec::MP_IRQDIR_DEFAULT as u16;
+            mpc_intsrc.0.srcbus = PCI_BUS_ID;
+            mpc_intsrc.0.srcbusirq = ((slot as u8) << 2) | pin;
+            mpc_intsrc.0.dstapic = ioapicid;
+            mpc_intsrc.0.dstirq = *irq;
+            mem.write_obj(mpc_intsrc, base_mp)
+                .map_err(Error::WriteMpcIntsrc)?;
+            base_mp = base_mp.unchecked_add(size as u64);
+            checksum = checksum.wrapping_add(compute_checksum(&mpc_intsrc.0));
+        }
+    }
     {
         let size = mem::size_of::<MpcLintsrcWrapper>();
         let mut mpc_lintsrc = MpcLintsrcWrapper(mpspec::mpc_lintsrc::default());
@@ -302,27 +350,28 @@ mod tests {
     fn bounds_check() {
         let num_cpus = 4;
         let mem =
-            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus))]).unwrap();
+            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus, 0))]).unwrap();
 
-        setup_mptable(MPTABLE_START, &mem, num_cpus).unwrap();
+        setup_mptable(MPTABLE_START, &mem, num_cpus, &[]).unwrap();
     }
 
     #[test]
     fn bounds_check_fails() {
         let num_cpus = 4;
-        let mem = GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus) - 1)])
-            .unwrap();
+        let mem =
+            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus, 0) - 1)])
+                .unwrap();
 
-        assert!(setup_mptable(MPTABLE_START, &mem, num_cpus).is_err());
+        assert!(setup_mptable(MPTABLE_START, &mem, num_cpus, &[]).is_err());
     }
 
     #[test]
     fn mpf_intel_checksum() {
         let num_cpus = 1;
         let mem =
-            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus))]).unwrap();
+            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus, 0))]).unwrap();
 
-        setup_mptable(MPTABLE_START, &mem, num_cpus).unwrap();
+        setup_mptable(MPTABLE_START, &mem, num_cpus, &[]).unwrap();
 
         let mpf_intel: MpfIntelWrapper = mem.read_obj(MPTABLE_START).unwrap();
 
@@ -336,9 +385,9 @@ mod tests {
     fn mpc_table_checksum() {
         let num_cpus = 4;
         let mem =
-            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus))]).unwrap();
+            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(num_cpus, 0))]).unwrap();
 
-        setup_mptable(MPTABLE_START, &mem, num_cpus).unwrap();
+        setup_mptable(MPTABLE_START, &mem, num_cpus, &[]).unwrap();
 
         let mpf_intel: MpfIntelWrapper = mem.read_obj(MPTABLE_START).unwrap();
         let mpc_offset = GuestAddress(mpf_intel.0.physptr as GuestUsize);
@@ -367,12 +416,12 @@ mod tests {
     fn cpu_entry_count() {
         let mem = GuestMemoryMmap::from_ranges(&[(
             MPTABLE_START,
-            compute_mp_size(MAX_SUPPORTED_CPUS as u8),
+            compute_mp_size(MAX_SUPPORTED_CPUS as u8, 0),
         )])
         .unwrap();
 
         for i in 0..MAX_SUPPORTED_CPUS as u8 {
-            setup_mptable(MPTABLE_START, &mem, i).unwrap();
+            setup_mptable(MPTABLE_START, &mem, i, &[]).unwrap();
 
             let mpf_intel: MpfIntelWrapper = mem.read_obj(MPTABLE_START).unwrap();
             let mpc_offset = GuestAddress(mpf_intel.0.physptr as GuestUsize);
@@ -402,10 +451,50 @@ mod tests {
     #[test]
     fn cpu_entry_count_max() {
         let cpus = MAX_SUPPORTED_CPUS + 1;
-        let mem =
-            GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(cpus as u8))]).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(MPTABLE_START, compute_mp_size(cpus as u8, 0))])
+            .unwrap();
 
-        let result = setup_mptable(MPTABLE_START, &mem, cpus as u8);
+        let result = setup_mptable(MPTABLE_START, &mem, cpus as u8, &[]);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn pci_intsrc_entries() {
+        let num_cpus = 2;
+        let pci_irqs: Vec<u8> = (0..32).map(|slot| 5 + slot % 8).collect();
+        let mem = GuestMemoryMmap::from_ranges(&[(
+            MPTABLE_START,
+            compute_mp_size(num_cpus, pci_irqs.len()),
+        )])
+        .unwrap();
+
+        setup_mptable(MPTABLE_START, &mem, num_cpus, &pci_irqs).unwrap();
+
+        let mpf_intel: MpfIntelWrapper = mem.read_obj(MPTABLE_START).unwrap();
+        let mpc_offset = GuestAddress(mpf_intel.0.physptr as GuestUsize);
+        let mpc_table: MpcTableWrapper = mem.read_obj(mpc_offset).unwrap();
+        let mpc_end = mpc_offset
+            .checked_add(mpc_table.0.length as GuestUsize)
+            .unwrap();
+
+        let mut entry_offset = mpc_offset
+            .checked_add(mem::size_of::<MpcTableWrapper>() as GuestUsize)
+            .unwrap();
+        let mut pci_intsrc_count = 0;
+        while entry_offset < mpc_end {
+            let entry_type: u8 = mem.read_obj(entry_offset).unwrap();
+            if entry_type as u32 == mpspec::MP_INTSRC {
+                let intsrc: MpcIntsrcWrapper = mem.read_obj(entry_offset).unwrap();
+                if intsrc.0.srcbus == PCI_BUS_ID {
+                    let slot = intsrc.0.srcbusirq >> 2;
+                    assert_eq!(intsrc.0.dstirq, pci_irqs[slot as usize]);
+                    pci_intsrc_count += 1;
+                }
+            }
+            entry_offset = entry_offset
+                .checked_add(table_entry_size(entry_type) as GuestUsize)
+                .unwrap();
+        }
+        assert_eq!(pci_intsrc_count, 32 * PCI_IRQ_PINS as usize);
+    }
 }
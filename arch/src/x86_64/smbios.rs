@@ -6,7 +6,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::layout::SMBIOS_START;
+use crate::layout::{HIGH_RAM_START, SMBIOS_START};
 use crate::GuestMemoryMmap;
 use std::fmt::{self, Display};
 use std::mem;
@@ -20,7 +20,7 @@ use vm_memory::{Address, Bytes, GuestAddress};
 pub enum Error {
     /// There was too little guest memory to store the entire SMBIOS table.
     NotEnoughMemory,
-    /// The SMBIOS table has too little address space to be stored.
+    /// The SMBIOS table doesn't fit below the high RAM.
     AddressOverflow,
     /// Failure while zeroing out the memory for the SMBIOS table.
     Clear,
@@ -38,7 +38,7 @@ impl Display for Error {
 
         let description = match self {
             NotEnoughMemory => "There was too little guest memory to store the SMBIOS table",
-            AddressOverflow => "The SMBIOS table has too little address space to be stored",
+            AddressOverflow => "The SMBIOS table doesn't fit below the high RAM",
             Clear => "Failure while zeroing out the memory for the SMBIOS table",
             WriteSmbiosEp => "Failure to write SMBIOS entrypoint structure",
             WriteData => "Failure to write additional data to memory",
@@ -54,6 +54,7 @@ pub type Result<T> = result::Result<T, Error>;
 const SM3_MAGIC_IDENT: &[u8; 5usize] = b"_SM3_";
 const BIOS_INFORMATION: u8 = 0;
 const SYSTEM_INFORMATION: u8 = 1;
+const OEM_STRINGS: u8 = 11;
 const END_OF_TABLE: u8 = 127;
 const PCI_SUPPORTED: u64 = 1 << 7;
 const IS_VIRTUAL_MACHINE: u8 = 1 << 4;
@@ -138,15 +139,56 @@ impl Clone for SmbiosSysInfo {
 
 unsafe impl ByteValued for SmbiosSysInfo {}
 
+#[repr(packed)]
+#[derive(Default, Copy)]
+pub struct SmbiosOemStrings {
+    pub typ: u8,
+    pub length: u8,
+    pub handle: u16,
+    pub count: u8,
+}
+
+impl Clone for SmbiosOemStrings {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+unsafe impl ByteValued for SmbiosOemStrings {}
+
+/// Identity of the VM reported through the system information and the OEM
+/// strings.
+#[derive(Clone, Debug, Default)]
+pub struct SmbiosIdentity {
+    pub serial_number: Option<String>,
+    /// UUID in its RFC 4122 byte order.
+    pub uuid: Option<[u8; 16]>,
+    pub oem_strings: Vec<String>,
+}
+
+// SMBIOS stores the first three fields of the UUID in little endian.
+fn smbios_uuid(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut bytes = *uuid;
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
 fn write_and_incr<T: ByteValued>(
     mem: &GuestMemoryMmap,
     val: T,
     mut curptr: GuestAddress,
 ) -> Result<GuestAddress> {
-    mem.write_obj(val, curptr).map_err(|_| Error::WriteData)?;
-    curptr = curptr
+    let next = curptr
         .checked_add(mem::size_of::<T>() as u64)
         .ok_or(Error::NotEnoughMemory)?;
+    // The tables live in the BIOS area, the kernel being loaded right above.
+    if next > HIGH_RAM_START {
+        return Err(Error::AddressOverflow);
+    }
+    mem.write_obj(val, curptr).map_err(|_| Error::WriteData)?;
+    curptr = next;
     Ok(curptr)
 }
 
@@ -162,7 +204,15 @@ fn write_string(
     Ok(curptr)
 }
 
-pub fn setup_smbios(mem: &GuestMemoryMmap) -> Result<u64> {
+/// Writes the SMBIOS entrypoint and tables at `SMBIOS_START`, returning
+/// their total size.
+pub fn setup_smbios(mem: &GuestMemoryMmap, identity: &SmbiosIdentity) -> Result<u64> {
+    // An empty string can't be written, as it would end the string set.
+    let serial_number = identity
+        .serial_number
+        .as_deref()
+        .filter(|serial_number| !serial_number.is_empty());
+
     let physptr = GuestAddress(SMBIOS_START)
         .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
         .ok_or(Error::NotEnoughMemory)?;
@@ -195,11 +245,32 @@ pub fn setup_smbios(mem: &GuestMemoryMmap) -> Result<u64> {
             handle,
             manufacturer: 1, // First string written in this section
             product_name: 2, // Second string written in this section
+            // Third string written in this section, if any
+            serial_number: serial_number.map_or(0, |_| 3),
+            uuid: identity.uuid.as_ref().map(smbios_uuid).unwrap_or_default(),
             ..Default::default()
         };
         curptr = write_and_incr(mem, smbios_sysinfo, curptr)?;
         curptr = write_string(mem, "Cloud Hypervisor", curptr)?;
         curptr = write_string(mem, "cloud-hypervisor", curptr)?;
+        if let Some(serial_number) = serial_number {
+            curptr = write_string(mem, serial_number, curptr)?;
+        }
+        curptr = write_and_incr(mem, 0u8, curptr)?;
+    }
+
+    if !identity.oem_strings.is_empty() {
+        handle += 1;
+        let smbios_oem_strings = SmbiosOemStrings {
+            typ: OEM_STRINGS,
+            length: mem::size_of::<SmbiosOemStrings>() as u8,
+            handle,
+            count: identity.oem_strings.len() as u8,
+        };
+        curptr = write_and_incr(mem, smbios_oem_strings, curptr)?;
+        for oem_string in identity.oem_strings.iter() {
+            curptr = write_string(mem, oem_string, curptr)?;
+        }
         curptr = write_and_incr(mem, 0u8, curptr)?;
     }
 
@@ -233,7 +304,7 @@ pub fn setup_smbios(mem: &GuestMemoryMmap) -> Result<u64> {
             .map_err(|_| Error::WriteSmbiosEp)?;
     }
 
-    Ok(curptr.unchecked_offset_from(physptr))
+    Ok(curptr.unchecked_offset_from(GuestAddress(SMBIOS_START)))
 }
 
 #[cfg(test)]
@@ -263,10 +334,91 @@ mod tests {
     fn entrypoint_checksum() {
         let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
 
-        setup_smbios(&mem).unwrap();
+        setup_smbios(&mem, &SmbiosIdentity::default()).unwrap();
 
         let smbios_ep: Smbios30Entrypoint = mem.read_obj(GuestAddress(SMBIOS_START)).unwrap();
 
         assert_eq!(compute_checksum(&smbios_ep), 0);
     }
+
+    #[test]
+    fn identity() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
+        let identity = SmbiosIdentity {
+            serial_number: Some("vm-0042".to_owned()),
+            uuid: Some([
+                0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff,
+            ]),
+            oem_strings: vec!["owner=alice".to_owned()],
+        };
+
+        setup_smbios(&mem, &identity).unwrap();
+
+        let sysinfo_addr = GuestAddress(SMBIOS_START)
+            .unchecked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
+            .unchecked_add(mem::size_of::<SmbiosBiosInfo>() as u64 + 20);
+        let sysinfo: SmbiosSysInfo = mem.read_obj(sysinfo_addr).unwrap();
+        assert_eq!(sysinfo.typ, SYSTEM_INFORMATION);
+        assert_eq!(sysinfo.serial_number, 3);
+        let uuid = sysinfo.uuid;
+        assert_eq!(
+            uuid,
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff,
+            ]
+        );
+
+        let strings_addr = sysinfo_addr.unchecked_add(mem::size_of::<SmbiosSysInfo>() as u64);
+        let mut strings = vec![0u8; 43];
+        mem.read_slice(&mut strings, strings_addr).unwrap();
+        assert_eq!(
+            &strings[..],
+            &b"Cloud Hypervisor\0cloud-hypervisor\0vm-0042\0\0"[..]
+        );
+
+        let oem_strings: SmbiosOemStrings = mem
+            .read_obj(strings_addr.unchecked_add(strings.len() as u64))
+            .unwrap();
+        assert_eq!(oem_strings.typ, OEM_STRINGS);
+        assert_eq!(oem_strings.count, 1);
+    }
+
+    #[test]
+    fn empty_serial_number() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
+        let identity = SmbiosIdentity {
+            serial_number: Some(String::new()),
+            ..Default::default()
+        };
+
+        setup_smbios(&mem, &identity).unwrap();
+
+        let sysinfo_addr = GuestAddress(SMBIOS_START)
+            .unchecked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
+            .unchecked_add(mem::size_of::<SmbiosBiosInfo>() as u64 + 20);
+        let sysinfo: SmbiosSysInfo = mem.read_obj(sysinfo_addr).unwrap();
+        assert_eq!(sysinfo.serial_number, 0);
+
+        let strings_addr = sysinfo_addr.unchecked_add(mem::size_of::<SmbiosSysInfo>() as u64);
+        let mut strings = vec![0u8; 35];
+        mem.read_slice(&mut strings, strings_addr).unwrap();
+        assert_eq!(&strings[..], &b"Cloud Hypervisor\0cloud-hypervisor\0\0"[..]);
+    }
+
+    #[test]
+    fn too_large() {
+        let start = GuestAddress(SMBIOS_START);
+        let mem = GuestMemoryMmap::from_ranges(&[(start, 0x20000)]).unwrap();
+        let identity = SmbiosIdentity {
+            oem_strings: vec!["x".repeat(0x10000)],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            setup_smbios(&mem, &identity),
+            Err(Error::AddressOverflow)
+        ));
+    }
 }
@@ -303,6 +303,25 @@ impl TdHob {
             0x403,
         )
     }
+
+    pub fn add_shared_memory_resource(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        physical_start: u64,
+        resource_length: u64,
+    ) -> Result<(), TdvfError> {
+        self.add_resource(
+            mem,
+            physical_start,
+            resource_length,
+            0x5, /* EFI_RESOURCE_MEMORY_RESERVED */
+            /*
+             * EFI_RESOURCE_ATTRIBUTE_PRESENT | EFI_RESOURCE_ATTRIBUTE_INITIALIZED | EFI_RESOURCE_ATTRIBUTE_TESTED
+             * and not EFI_RESOURCE_ATTRIBUTE_ENCRYPTED, for the region not to be accepted as private memory
+             */
+            0x7,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -2,6 +2,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use std::fs::File;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
 use thiserror::Error;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -17,9 +20,69 @@ pub enum DiskFileError {
 
 pub type DiskFileResult<T> = std::result::Result<T, DiskFileError>;
 
+/// Block sizes of a disk, in bytes, letting the guest align its I/O.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskTopology {
+    pub logical_block_size: u64,
+    pub physical_block_size: u64,
+    pub minimum_io_size: u64,
+    pub optimal_io_size: u64,
+}
+
+impl Default for DiskTopology {
+    fn default() -> Self {
+        Self {
+            logical_block_size: 512,
+            physical_block_size: 512,
+            minimum_io_size: 512,
+            optimal_io_size: 0,
+        }
+    }
+}
+
+// See include/uapi/linux/fs.h in the kernel code.
+const BLKSSZGET: libc::c_ulong = 0x1268;
+const BLKIOMIN: libc::c_ulong = 0x1278;
+const BLKIOOPT: libc::c_ulong = 0x1279;
+const BLKPBSZGET: libc::c_ulong = 0x127b;
+
+fn block_device_ioctl(file: &File, request: libc::c_ulong) -> std::io::Result<u64> {
+    let mut value: libc::c_uint = 0;
+    // Safe because the kernel only writes an unsigned int to the pointer.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), request, &mut value) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(u64::from(value))
+}
+
+impl DiskTopology {
+    /// Queries the topology of the host block device backing `file`. Regular
+    /// files only report the preferred I/O size of their filesystem.
+    pub fn probe(file: &File) -> std::io::Result<Self> {
+        let metadata = file.metadata()?;
+        if !metadata.file_type().is_block_device() {
+            return Ok(DiskTopology {
+                optimal_io_size: std::os::unix::fs::MetadataExt::blksize(&metadata),
+                ..Default::default()
+            });
+        }
+
+        Ok(DiskTopology {
+            logical_block_size: block_device_ioctl(file, BLKSSZGET)?,
+            physical_block_size: block_device_ioctl(file, BLKPBSZGET)?,
+            minimum_io_size: block_device_ioctl(file, BLKIOMIN)?,
+            optimal_io_size: block_device_ioctl(file, BLKIOOPT)?,
+        })
+    }
+}
+
 pub trait DiskFile: Send + Sync {
     fn size(&mut self) -> DiskFileResult<u64>;
     fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>>;
+    fn topology(&mut self) -> DiskTopology {
+        DiskTopology::default()
+    }
 }
 
 #[derive(Error, Debug)]
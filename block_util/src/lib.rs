@@ -14,9 +14,14 @@ extern crate log;
 pub mod async_io;
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod mirror;
+pub mod nbd;
+pub mod overlay;
 pub mod qcow_sync;
 pub mod raw_async;
 pub mod raw_sync;
+pub mod rbd;
+pub mod thread_pool;
 pub mod vhd;
 
 use crate::async_io::{AsyncIo, AsyncIoError, AsyncIoResult, DiskFileError, DiskFileResult};
@@ -85,20 +90,25 @@ fn build_device_id(disk_path: &Path) -> result::Result<String, Error> {
 }
 
 pub fn build_disk_image_id(disk_path: &Path) -> Vec<u8> {
-    let mut default_disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
     match build_device_id(disk_path) {
         Err(_) => {
             warn!("Could not generate device id. We'll use a default.");
+            vec![0; VIRTIO_BLK_ID_BYTES as usize]
         }
-        Ok(m) => {
-            // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
-            // This will also zero out any leftover bytes.
-            let disk_id = m.as_bytes();
-            let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
-            default_disk_image_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy])
-        }
+        Ok(m) => build_serial(&m),
     }
-    default_disk_image_id
+}
+
+/// Builds the identifier returned to VIRTIO_BLK_T_GET_ID requests from a
+/// user provided serial number.
+pub fn build_serial(serial: &str) -> Vec<u8> {
+    let mut disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
+    // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
+    // This will also zero out any leftover bytes.
+    let disk_id = serial.as_bytes();
+    let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
+    disk_image_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy]);
+    disk_image_id
 }
 
 #[derive(Debug)]
@@ -0,0 +1,563 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Condvar, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Granularity of the dirty tracking.
+pub const MIRROR_CLUSTER_SIZE: u64 = 0x10000;
+
+// Alignment of the copy buffer, suiting the images opened with O_DIRECT.
+const MIRROR_BUFFER_ALIGNMENT: usize = 0x1000;
+
+// Buffer the background copy goes through, aligned for O_DIRECT.
+struct CopyBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl CopyBuffer {
+    fn new() -> Self {
+        let layout =
+            Layout::from_size_align(MIRROR_CLUSTER_SIZE as usize, MIRROR_BUFFER_ALIGNMENT).unwrap();
+        // Safe because the layout has a non-zero size.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        CopyBuffer { ptr, layout }
+    }
+
+    fn as_mut_slice(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.layout.size());
+        // Safe because the buffer is valid for its whole layout size.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, len) }
+    }
+}
+
+impl Drop for CopyBuffer {
+    fn drop(&mut self) {
+        // Safe because the buffer has been allocated with this layout.
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+struct MirrorState {
+    // Clusters the target is missing the latest content of. Every cluster
+    // starts dirty, and is dirtied again whenever the guest writes to it.
+    dirty: Vec<bool>,
+    dirty_count: u64,
+    // Cluster the background copy resumes scanning from.
+    next: u64,
+    // Cluster being copied, without the lock being held.
+    copying: Option<u64>,
+    // Whether the target caught up with the source, the writes of the guest
+    // going to both from then on.
+    synced: bool,
+    pivoted: bool,
+    paused: bool,
+    stopped: bool,
+}
+
+impl MirrorState {
+    fn next_dirty(&mut self) -> Option<u64> {
+        if self.dirty_count == 0 {
+            return None;
+        }
+
+        let clusters = self.dirty.len() as u64;
+        let cluster = (0..clusters)
+            .map(|i| (self.next + i) % clusters)
+            .find(|cluster| self.dirty[*cluster as usize])?;
+        self.next = (cluster + 1) % clusters;
+
+        Some(cluster)
+    }
+
+    fn set_dirty(&mut self, cluster: u64) {
+        if !self.dirty[cluster as usize] {
+            self.dirty[cluster as usize] = true;
+            self.dirty_count += 1;
+        }
+    }
+}
+
+/// Mirror of a raw disk image onto a new backing file, while the guest keeps
+/// using the disk.
+///
+/// The content of the source is copied to the target in the background,
+/// the clusters written by the guest in the meantime being copied again.
+/// Once the target has caught up, the writes of the guest go to both, and
+/// the mirror can be pivoted, the disk then being entirely backed by the
+/// target.
+pub struct Mirror {
+    source: File,
+    target: File,
+    size: u64,
+    state: Mutex<MirrorState>,
+    // Wakes up the background copy when a cluster gets dirty, or when the
+    // copy has to pause or stop, and the pause once no copy is in flight.
+    changed: Condvar,
+}
+
+impl Mirror {
+    /// Creates a mirror of `source` onto `target`, which is grown to the
+    /// size of the source if needed. The source must only be written
+    /// through the mirror once the background copy is running.
+    pub fn new(mut source: File, mut target: File) -> io::Result<Self> {
+        let size = source.seek(SeekFrom::End(0))?;
+        let metadata = target.metadata()?;
+        if metadata.is_file() && metadata.len() < size {
+            target.set_len(size)?;
+        }
+        let target_size = target.seek(SeekFrom::End(0))?;
+        if target_size < size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "mirror target size 0x{:x} is smaller than the disk size 0x{:x}",
+                    target_size, size
+                ),
+            ));
+        }
+
+        let clusters = (size + MIRROR_CLUSTER_SIZE - 1) / MIRROR_CLUSTER_SIZE;
+        Ok(Mirror {
+            source,
+            target,
+            size,
+            state: Mutex::new(MirrorState {
+                dirty: vec![true; clusters as usize],
+                dirty_count: clusters,
+                next: 0,
+                copying: None,
+                synced: clusters == 0,
+                pivoted: false,
+                paused: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn clusters(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<u64>> {
+        let end = offset
+            .checked_add(len as u64)
+            .filter(|end| *end <= self.size)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        if len == 0 {
+            return Ok(0..0);
+        }
+
+        Ok(offset / MIRROR_CLUSTER_SIZE..(end - 1) / MIRROR_CLUSTER_SIZE + 1)
+    }
+
+    /// Number of bytes the target is still missing.
+    pub fn remaining(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        let clusters = state.dirty_count + state.copying.map_or(0, |_| 1);
+        std::cmp::min(clusters * MIRROR_CLUSTER_SIZE, self.size)
+    }
+
+    // Copies a cluster to the target, the lock not being held for the guest
+    // I/O to go on meanwhile. A write of the guest to the cluster dirties it
+    // again, hence a copy racing with it is redone.
+    fn copy_cluster(&self, buffer: &mut CopyBuffer, cluster: u64) -> io::Result<()> {
+        let start = cluster * MIRROR_CLUSTER_SIZE;
+        let len = std::cmp::min(MIRROR_CLUSTER_SIZE, self.size - start) as usize;
+        let data = buffer.as_mut_slice(len);
+        self.source.read_exact_at(data, start)?;
+        self.target.write_all_at(data, start)
+    }
+
+    /// Copies the dirty clusters to the target, waiting for the guest to
+    /// dirty some more once the target caught up, until the mirror is
+    /// pivoted or stopped. The mirror is stopped if the copy fails.
+    pub fn run(&self) -> io::Result<()> {
+        let mut buffer = CopyBuffer::new();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.pivoted || state.stopped {
+                return Ok(());
+            }
+            if state.paused {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            match state.next_dirty() {
+                Some(cluster) => {
+                    state.dirty[cluster as usize] = false;
+                    state.dirty_count -= 1;
+                    state.copying = Some(cluster);
+                    drop(state);
+
+                    let result = self.copy_cluster(&mut buffer, cluster);
+
+                    state = self.state.lock().unwrap();
+                    state.copying = None;
+                    // Let a pending pause go on.
+                    self.changed.notify_all();
+                    if let Err(e) = result {
+                        state.stopped = true;
+                        return Err(e);
+                    }
+                }
+                None => {
+                    state.synced = true;
+                    state = self.changed.wait(state).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Switches the disk to the target, once the target caught up with the
+    /// source, which the background copy tells with no bytes remaining.
+    pub fn pivot(&self) -> io::Result<()> {
+        // The copied clusters are made durable before the guest can flush
+        // the target alone.
+        self.target.sync_all()?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the mirror has been stopped",
+            ));
+        }
+        if !state.synced || state.dirty_count > 0 || state.copying.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the mirror target hasn't caught up yet",
+            ));
+        }
+        state.pivoted = true;
+        self.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Holds the background copy, returning once no cluster is being copied.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+        while state.copying.is_some() {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Lets the background copy go on.
+    pub fn resume(&self) {
+        self.state.lock().unwrap().paused = false;
+        self.changed.notify_all();
+    }
+
+    /// Stops the background copy, the disk remaining backed by the source
+    /// unless the mirror has been pivoted.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let file = if state.pivoted {
+            &self.target
+        } else {
+            &self.source
+        };
+        file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.pivoted {
+            return self.target.write_all_at(buf, offset);
+        }
+
+        let clusters = self.clusters(offset, buf.len())?;
+        self.source.write_all_at(buf, offset)?;
+        if state.stopped {
+            return Ok(());
+        }
+
+        if state.synced {
+            // Only the cluster being copied can be missing the write.
+            self.target.write_all_at(buf, offset)?;
+            if let Some(cluster) = state.copying.filter(|c| clusters.contains(c)) {
+                state.set_dirty(cluster);
+                self.changed.notify_all();
+            }
+        } else {
+            for cluster in clusters {
+                state.set_dirty(cluster);
+            }
+            self.changed.notify_all();
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        if state.pivoted {
+            return self.target.sync_all();
+        }
+
+        self.source.sync_all()?;
+        if state.synced && !state.stopped {
+            self.target.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MirrorDiskSync {
+    mirror: Arc<Mirror>,
+}
+
+impl MirrorDiskSync {
+    pub fn new(mirror: Arc<Mirror>) -> Self {
+        MirrorDiskSync { mirror }
+    }
+}
+
+impl DiskFile for MirrorDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.mirror.size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(
+            Box::new(MirrorSync::new(self.mirror.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+}
+
+pub struct MirrorSync {
+    mirror: Arc<Mirror>,
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl MirrorSync {
+    fn new(mirror: Arc<Mirror>) -> io::Result<Self> {
+        Ok(MirrorSync {
+            mirror,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+            completion_list: Vec::new(),
+        })
+    }
+
+    fn complete_request(&mut self, user_data: u64, result: usize) {
+        self.completion_list.push((user_data, result as i32));
+        self.eventfd.write(1).unwrap();
+    }
+}
+
+impl AsyncIo for MirrorSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let mut offset = offset as u64;
+        for iovec in iovecs.iter() {
+            // Safe because the iovec points to guest memory which has
+            // been validated while parsing the request.
+            let buf =
+                unsafe { std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len) };
+            self.mirror
+                .read_at(buf, offset)
+                .map_err(AsyncIoError::ReadVectored)?;
+            offset += iovec.iov_len as u64;
+        }
+
+        let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, len);
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let mut offset = offset as u64;
+        for iovec in iovecs.iter() {
+            // Safe because the iovec points to guest memory which has
+            // been validated while parsing the request.
+            let buf =
+                unsafe { std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len) };
+            self.mirror
+                .write_at(buf, offset)
+                .map_err(AsyncIoError::WriteVectored)?;
+            offset += iovec.iov_len as u64;
+        }
+
+        let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, len);
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.mirror.sync().map_err(AsyncIoError::Fsync)?;
+
+        if let Some(user_data) = user_data {
+            self.complete_request(user_data, 0);
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn read_all(file: &File, size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        file.read_exact_at(&mut data, 0).unwrap();
+        data
+    }
+
+    // Copies the next dirty cluster as the background copy does.
+    fn copy_next(mirror: &Mirror, buffer: &mut CopyBuffer) -> Option<u64> {
+        let cluster = {
+            let mut state = mirror.state.lock().unwrap();
+            let cluster = state.next_dirty()?;
+            state.dirty[cluster as usize] = false;
+            state.dirty_count -= 1;
+            cluster
+        };
+        mirror.copy_cluster(buffer, cluster).unwrap();
+        Some(cluster)
+    }
+
+    #[test]
+    fn test_mirror() {
+        let size = 3 * MIRROR_CLUSTER_SIZE as usize + 0x200;
+        let source_file = TempFile::new().unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        source_file.as_file().write_all_at(&data, 0).unwrap();
+        let target_file = TempFile::new().unwrap();
+
+        let mirror = Arc::new(
+            Mirror::new(
+                source_file.as_file().try_clone().unwrap(),
+                target_file.as_file().try_clone().unwrap(),
+            )
+            .unwrap(),
+        );
+        // The target is grown to the size of the source.
+        assert_eq!(target_file.as_file().metadata().unwrap().len(), size as u64);
+        assert_eq!(mirror.remaining(), size as u64);
+
+        // Copy the first cluster, then write across the first two.
+        let mut buffer = CopyBuffer::new();
+        assert_eq!(copy_next(&mirror, &mut buffer), Some(0));
+        assert_eq!(mirror.remaining(), size as u64 - MIRROR_CLUSTER_SIZE);
+        let offset = MIRROR_CLUSTER_SIZE - 0x100;
+        mirror.write_at(&[0xffu8; 0x200], offset).unwrap();
+        assert_eq!(mirror.remaining(), size as u64);
+
+        // Accesses beyond the end of the disk are rejected.
+        assert!(mirror.write_at(&[0u8; 0x10], size as u64 - 0x8).is_err());
+
+        // The mirror can't be pivoted before the target caught up.
+        assert_eq!(
+            mirror.pivot().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // The background copy catches up, and then waits for more.
+        let copy_mirror = mirror.clone();
+        let copy = std::thread::spawn(move || copy_mirror.run());
+        while mirror.remaining() != 0 || !mirror.state.lock().unwrap().synced {
+            std::thread::yield_now();
+        }
+        let source = read_all(source_file.as_file(), size);
+        assert_eq!(read_all(target_file.as_file(), size), source);
+
+        // Once the target caught up, the writes go to both.
+        mirror.write_at(&[0xddu8; 0x10], 0x1000).unwrap();
+        assert_eq!(mirror.remaining(), 0);
+        let source = read_all(source_file.as_file(), size);
+        assert_eq!(read_all(target_file.as_file(), size), source);
+
+        mirror.pivot().unwrap();
+        copy.join().unwrap().unwrap();
+
+        // Once pivoted, the source isn't used anymore.
+        mirror.write_at(&[0xeeu8; 0x10], 0).unwrap();
+        assert_eq!(read_all(source_file.as_file(), size), source);
+        let mut buf = [0u8; 0x10];
+        mirror.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0xeeu8; 0x10]);
+    }
+
+    #[test]
+    fn test_mirror_pause() {
+        let source_file = TempFile::new().unwrap();
+        source_file
+            .as_file()
+            .set_len(2 * MIRROR_CLUSTER_SIZE)
+            .unwrap();
+        let target_file = TempFile::new().unwrap();
+        let mirror = Arc::new(
+            Mirror::new(
+                source_file.as_file().try_clone().unwrap(),
+                target_file.as_file().try_clone().unwrap(),
+            )
+            .unwrap(),
+        );
+
+        // Nothing gets copied while the mirror is paused.
+        mirror.pause();
+        let copy_mirror = mirror.clone();
+        let copy = std::thread::spawn(move || copy_mirror.run());
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(mirror.remaining(), 2 * MIRROR_CLUSTER_SIZE);
+
+        mirror.resume();
+        while mirror.remaining() != 0 {
+            std::thread::yield_now();
+        }
+        mirror.stop();
+        copy.join().unwrap().unwrap();
+
+        // A stopped mirror can't be pivoted.
+        assert!(mirror.pivot().is_err());
+    }
+
+    #[test]
+    fn test_mirror_target_too_small() {
+        let source_file = TempFile::new().unwrap();
+        source_file.as_file().set_len(0x1000).unwrap();
+        let target_file = TempFile::new().unwrap();
+        let target = File::open(target_file.as_path()).unwrap();
+
+        // A target which can't be grown must be large enough.
+        assert!(Mirror::new(source_file.as_file().try_clone().unwrap(), target).is_err());
+    }
+}
@@ -0,0 +1,785 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Network block device (NBD) client.
+//!
+//! Disks can be served by an NBD server such as qemu-nbd, reached either over
+//! TCP with `nbd://<ip>[:<port>]/<export>`, or over a UNIX domain socket with
+//! `nbd+unix:///<export>?socket=<socket_path>`. The export is selected through
+//! the fixed newstyle handshake, and structured replies are negotiated when
+//! the server supports them. Only one request is in flight at a time, and
+//! it fails if the server doesn't make progress within `NBD_IO_TIMEOUT`.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Port NBD servers listen on by default.
+pub const NBD_DEFAULT_PORT: u16 = 10809;
+
+/// Time after which connecting to the server, or sending a request to it and
+/// receiving its reply, fails if the server is unresponsive. The requests
+/// being handled synchronously, this bounds the time the guest I/O waits.
+pub const NBD_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Largest payload of a single request, which servers commonly enforce.
+const NBD_MAX_PAYLOAD_SIZE: usize = 32 << 20;
+
+// Handshake
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_OPT_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_INFO_EXPORT: u16 = 0;
+
+// Transmission
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Location of an NBD export.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NbdAddress {
+    Tcp { address: SocketAddr, export: String },
+    Unix { socket: PathBuf, export: String },
+}
+
+impl NbdAddress {
+    /// Whether a disk path designates an NBD export rather than a file.
+    pub fn is_nbd_uri(path: &str) -> bool {
+        path.starts_with("nbd://") || path.starts_with("nbd+unix://")
+    }
+
+    pub fn parse(uri: &str) -> io::Result<Self> {
+        if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            // The authority must be empty, the socket being passed as a query.
+            let rest = rest
+                .strip_prefix('/')
+                .ok_or_else(|| invalid_input(format!("unexpected host in {}", uri)))?;
+            let (export, query) = match rest.find('?') {
+                Some(index) => (&rest[..index], &rest[index + 1..]),
+                None => (rest, ""),
+            };
+            let socket = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("socket="))
+                .filter(|socket| !socket.is_empty())
+                .ok_or_else(|| invalid_input(format!("missing socket in {}", uri)))?;
+
+            Ok(NbdAddress::Unix {
+                socket: PathBuf::from(socket),
+                export: export.to_owned(),
+            })
+        } else if let Some(rest) = uri.strip_prefix("nbd://") {
+            let (authority, export) = match rest.find('/') {
+                Some(index) => (&rest[..index], &rest[index + 1..]),
+                None => (rest, ""),
+            };
+            // IPv6 addresses are enclosed in brackets, as they contain colons.
+            let (host, port) = if let Some(authority) = authority.strip_prefix('[') {
+                let index = authority
+                    .find(']')
+                    .ok_or_else(|| invalid_input(format!("invalid host in {}", uri)))?;
+                let port = &authority[index + 1..];
+                let port = if port.is_empty() {
+                    None
+                } else {
+                    Some(
+                        port.strip_prefix(':')
+                            .ok_or_else(|| invalid_input(format!("invalid port in {}", uri)))?,
+                    )
+                };
+                (&authority[..index], port)
+            } else {
+                match authority.rfind(':') {
+                    Some(index) => (&authority[..index], Some(&authority[index + 1..])),
+                    None => (authority, None),
+                }
+            };
+
+            // Host names are not resolved, to avoid depending on the
+            // resolver configuration of the host.
+            let ip = host
+                .parse::<IpAddr>()
+                .map_err(|_| invalid_input(format!("host is not an IP address in {}", uri)))?;
+            let port = match port {
+                Some(port) => port
+                    .parse::<u16>()
+                    .map_err(|_| invalid_input(format!("invalid port in {}", uri)))?,
+                None => NBD_DEFAULT_PORT,
+            };
+
+            Ok(NbdAddress::Tcp {
+                address: SocketAddr::new(ip, port),
+                export: export.to_owned(),
+            })
+        } else {
+            Err(invalid_input(format!("not an NBD URI: {}", uri)))
+        }
+    }
+}
+
+trait NbdStream: Read + Write + Send {}
+impl<T: Read + Write + Send> NbdStream for T {}
+
+fn read_u16(stream: &mut dyn NbdStream) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut dyn NbdStream) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut dyn NbdStream) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_vec(stream: &mut dyn NbdStream, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn send_option(stream: &mut dyn NbdStream, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)
+}
+
+fn option_reply(stream: &mut dyn NbdStream, option: u32) -> io::Result<(u32, Vec<u8>)> {
+    if read_u64(stream)? != NBD_OPT_REPLY_MAGIC {
+        return Err(invalid_data("invalid NBD option reply magic".to_owned()));
+    }
+    if read_u32(stream)? != option {
+        return Err(invalid_data(format!(
+            "NBD option reply doesn't match option {}",
+            option
+        )));
+    }
+    let reply = read_u32(stream)?;
+    let len = read_u32(stream)? as usize;
+    Ok((reply, read_vec(stream, len)?))
+}
+
+// Connection to an export, once the handshake is done.
+struct NbdClient {
+    stream: Box<dyn NbdStream>,
+    size: u64,
+    flags: u16,
+    structured_replies: bool,
+    handle: u64,
+}
+
+impl NbdClient {
+    fn connect(address: &NbdAddress) -> io::Result<Self> {
+        match address {
+            NbdAddress::Tcp { address, export } => {
+                let stream = TcpStream::connect_timeout(address, NBD_IO_TIMEOUT)?;
+                stream.set_read_timeout(Some(NBD_IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(NBD_IO_TIMEOUT))?;
+                Self::new(Box::new(stream), export)
+            }
+            NbdAddress::Unix { socket, export } => {
+                let stream = UnixStream::connect(socket)?;
+                stream.set_read_timeout(Some(NBD_IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(NBD_IO_TIMEOUT))?;
+                Self::new(Box::new(stream), export)
+            }
+        }
+    }
+
+    fn new(mut stream: Box<dyn NbdStream>, export: &str) -> io::Result<Self> {
+        if read_u64(&mut *stream)? != NBD_MAGIC {
+            return Err(invalid_data("not an NBD server".to_owned()));
+        }
+        if read_u64(&mut *stream)? != NBD_IHAVEOPT {
+            return Err(invalid_data(
+                "NBD server doesn't support the newstyle handshake".to_owned(),
+            ));
+        }
+        let handshake_flags = read_u16(&mut *stream)?;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(invalid_data(
+                "NBD server doesn't support the fixed newstyle handshake".to_owned(),
+            ));
+        }
+        let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+        if handshake_flags & NBD_FLAG_NO_ZEROES != 0 {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+        stream.write_all(&client_flags.to_be_bytes())?;
+
+        // Structured replies let the server describe the holes of the disk
+        // instead of sending zeroes, and report errors on reads without
+        // having to send the data first.
+        send_option(&mut *stream, NBD_OPT_STRUCTURED_REPLY, &[])?;
+        let mut structured_replies = false;
+        loop {
+            let (reply, _) = option_reply(&mut *stream, NBD_OPT_STRUCTURED_REPLY)?;
+            if reply == NBD_REP_ACK {
+                structured_replies = true;
+                break;
+            } else if reply & NBD_REP_FLAG_ERROR != 0 {
+                break;
+            }
+        }
+
+        let mut data = Vec::with_capacity(export.len() + 6);
+        data.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        data.extend_from_slice(export.as_bytes());
+        // No information request, the server always sends NBD_INFO_EXPORT.
+        data.extend_from_slice(&0u16.to_be_bytes());
+        send_option(&mut *stream, NBD_OPT_GO, &data)?;
+        let mut export_info = None;
+        loop {
+            let (reply, data) = option_reply(&mut *stream, NBD_OPT_GO)?;
+            if reply == NBD_REP_ACK {
+                break;
+            } else if reply == NBD_REP_INFO && data.len() >= 12 {
+                let mut info_type = [0u8; 2];
+                info_type.copy_from_slice(&data[0..2]);
+                if u16::from_be_bytes(info_type) == NBD_INFO_EXPORT {
+                    let mut size = [0u8; 8];
+                    size.copy_from_slice(&data[2..10]);
+                    let mut flags = [0u8; 2];
+                    flags.copy_from_slice(&data[10..12]);
+                    export_info = Some((u64::from_be_bytes(size), u16::from_be_bytes(flags)));
+                }
+            } else if reply & NBD_REP_FLAG_ERROR != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "NBD export \"{}\" rejected: {}",
+                        export,
+                        String::from_utf8_lossy(&data)
+                    ),
+                ));
+            }
+        }
+
+        let (size, flags) = export_info.ok_or_else(|| {
+            invalid_data(format!("NBD server didn't describe export \"{}\"", export))
+        })?;
+
+        Ok(NbdClient {
+            stream,
+            size,
+            flags,
+            structured_replies,
+            handle: 0,
+        })
+    }
+
+    fn send_request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        self.handle = self.handle.wrapping_add(1);
+
+        let mut buf = Vec::with_capacity(28 + data.len());
+        buf.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&command.to_be_bytes());
+        buf.extend_from_slice(&self.handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)?;
+
+        Ok(self.handle)
+    }
+
+    // Receives the reply to the request identified by `handle`, filling `buf`
+    // with the data read from `offset` if it's a read request.
+    fn receive_reply(&mut self, handle: u64, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let structured_replies = self.structured_replies;
+        let stream = &mut *self.stream;
+        let mut error = None;
+        loop {
+            match read_u32(stream)? {
+                NBD_SIMPLE_REPLY_MAGIC => {
+                    let errno = read_u32(stream)?;
+                    if read_u64(stream)? != handle {
+                        return Err(invalid_data("unexpected NBD reply handle".to_owned()));
+                    }
+                    if errno != 0 {
+                        return Err(io::Error::from_raw_os_error(errno as i32));
+                    }
+                    return stream.read_exact(buf);
+                }
+                NBD_STRUCTURED_REPLY_MAGIC if structured_replies => {
+                    let flags = read_u16(stream)?;
+                    let reply_type = read_u16(stream)?;
+                    if read_u64(stream)? != handle {
+                        return Err(invalid_data("unexpected NBD reply handle".to_owned()));
+                    }
+                    let len = read_u32(stream)? as usize;
+
+                    match reply_type {
+                        NBD_REPLY_TYPE_NONE => {}
+                        // Skipping a malformed chunk would leave a part of the
+                        // buffer unfilled.
+                        NBD_REPLY_TYPE_OFFSET_DATA if len < 8 => {
+                            return Err(invalid_data("invalid NBD data chunk length".to_owned()));
+                        }
+                        NBD_REPLY_TYPE_OFFSET_HOLE if len != 12 => {
+                            return Err(invalid_data("invalid NBD hole chunk length".to_owned()));
+                        }
+                        NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE => {
+                            let chunk_offset = read_u64(stream)?;
+                            let chunk_len = if reply_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                                len - 8
+                            } else {
+                                read_u32(stream)? as usize
+                            };
+                            let start = chunk_offset
+                                .checked_sub(offset)
+                                .map(|start| start as usize)
+                                .filter(|start| {
+                                    start
+                                        .checked_add(chunk_len)
+                                        .map_or(false, |end| end <= buf.len())
+                                })
+                                .ok_or_else(|| {
+                                    invalid_data("NBD reply chunk out of range".to_owned())
+                                })?;
+                            let chunk = &mut buf[start..start + chunk_len];
+                            if reply_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                                stream.read_exact(chunk)?;
+                            } else {
+                                for byte in chunk.iter_mut() {
+                                    *byte = 0;
+                                }
+                            }
+                        }
+                        reply_type if reply_type & NBD_REPLY_TYPE_ERROR_BIT != 0 && len >= 6 => {
+                            let errno = read_u32(stream)?;
+                            let message_len = read_u16(stream)? as usize;
+                            let data = read_vec(stream, len - 6)?;
+                            let message = &data[..std::cmp::min(message_len, data.len())];
+                            warn!("NBD request failed: {}", String::from_utf8_lossy(message));
+                            error = Some(io::Error::from_raw_os_error(errno as i32));
+                        }
+                        _ => {
+                            // Unknown chunks can be ignored.
+                            read_vec(stream, len)?;
+                        }
+                    }
+
+                    if flags & NBD_REPLY_FLAG_DONE != 0 {
+                        return error.map_or(Ok(()), Err);
+                    }
+                }
+                _ => return Err(invalid_data("invalid NBD reply magic".to_owned())),
+            }
+        }
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut offset = offset;
+        for chunk in buf.chunks_mut(NBD_MAX_PAYLOAD_SIZE) {
+            let handle = self.send_request(NBD_CMD_READ, offset, chunk.len() as u32, &[])?;
+            self.receive_reply(handle, chunk, offset)?;
+            offset += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut offset = offset;
+        for chunk in buf.chunks(NBD_MAX_PAYLOAD_SIZE) {
+            let handle = self.send_request(NBD_CMD_WRITE, offset, chunk.len() as u32, chunk)?;
+            self.receive_reply(handle, &mut [], offset)?;
+            offset += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Without the flush command, the server writes the data through.
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+
+        let handle = self.send_request(NBD_CMD_FLUSH, 0, 0, &[])?;
+        self.receive_reply(handle, &mut [], 0)
+    }
+}
+
+impl Drop for NbdClient {
+    fn drop(&mut self) {
+        // The server doesn't reply to the disconnection.
+        if let Err(e) = self.send_request(NBD_CMD_DISC, 0, 0, &[]) {
+            warn!("Error disconnecting from NBD server: {}", e);
+        }
+    }
+}
+
+pub struct NbdDiskSync {
+    client: Arc<Mutex<NbdClient>>,
+}
+
+impl NbdDiskSync {
+    /// Connects to the NBD export at `address`. A read-only export can only
+    /// back a read-only disk.
+    pub fn new(address: &NbdAddress, readonly: bool) -> io::Result<Self> {
+        let client = NbdClient::connect(address)?;
+        if !readonly && client.flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "NBD export is read-only",
+            ));
+        }
+
+        Ok(NbdDiskSync {
+            client: Arc::new(Mutex::new(client)),
+        })
+    }
+}
+
+impl DiskFile for NbdDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.client.lock().unwrap().size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(
+            Box::new(NbdSync::new(self.client.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+}
+
+pub struct NbdSync {
+    client: Arc<Mutex<NbdClient>>,
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl NbdSync {
+    fn new(client: Arc<Mutex<NbdClient>>) -> io::Result<Self> {
+        Ok(NbdSync {
+            client,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+            completion_list: Vec::new(),
+        })
+    }
+
+    fn complete_request(&mut self, user_data: u64, result: usize) {
+        self.completion_list.push((user_data, result as i32));
+        self.eventfd.write(1).unwrap();
+    }
+}
+
+impl AsyncIo for NbdSync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let mut offset = offset as u64;
+        {
+            let mut client = self.client.lock().unwrap();
+            for iovec in iovecs.iter() {
+                // Safe because the iovec points to guest memory which has
+                // been validated while parsing the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len)
+                };
+                client
+                    .read_at(buf, offset)
+                    .map_err(AsyncIoError::ReadVectored)?;
+                offset += iovec.iov_len as u64;
+            }
+        }
+
+        let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, len);
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let mut offset = offset as u64;
+        {
+            let mut client = self.client.lock().unwrap();
+            for iovec in iovecs.iter() {
+                // Safe because the iovec points to guest memory which has
+                // been validated while parsing the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                };
+                client
+                    .write_at(buf, offset)
+                    .map_err(AsyncIoError::WriteVectored)?;
+                offset += iovec.iov_len as u64;
+            }
+        }
+
+        let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, len);
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.client
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(AsyncIoError::Fsync)?;
+
+        if let Some(user_data) = user_data {
+            self.complete_request(user_data, 0);
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn write_option_reply(stream: &mut UnixStream, option: u32, reply: u32, data: &[u8]) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_OPT_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&reply.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    fn write_chunk(stream: &mut UnixStream, flags: u16, reply_type: u16, handle: u64, data: &[u8]) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    // Serves a disk of `size` bytes, replying to the reads with a data chunk
+    // for the first half and a hole for the second one.
+    fn serve(mut stream: UnixStream, size: u64) {
+        let mut disk = vec![0u8; size as usize];
+
+        stream.write_all(&NBD_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&NBD_IHAVEOPT.to_be_bytes()).unwrap();
+        stream
+            .write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())
+            .unwrap();
+        assert_eq!(
+            read_u32(&mut stream).unwrap(),
+            NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+        );
+
+        loop {
+            assert_eq!(read_u64(&mut stream).unwrap(), NBD_IHAVEOPT);
+            let option = read_u32(&mut stream).unwrap();
+            let len = read_u32(&mut stream).unwrap() as usize;
+            let data = read_vec(&mut stream, len).unwrap();
+            match option {
+                NBD_OPT_STRUCTURED_REPLY => {
+                    write_option_reply(&mut stream, option, NBD_REP_ACK, &[]);
+                }
+                NBD_OPT_GO => {
+                    assert_eq!(&data[4..len - 2], b"export");
+                    let mut info = Vec::new();
+                    info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&NBD_FLAG_SEND_FLUSH.to_be_bytes());
+                    write_option_reply(&mut stream, option, NBD_REP_INFO, &info);
+                    write_option_reply(&mut stream, option, NBD_REP_ACK, &[]);
+                    break;
+                }
+                _ => panic!("unexpected option {}", option),
+            }
+        }
+
+        loop {
+            assert_eq!(read_u32(&mut stream).unwrap(), NBD_REQUEST_MAGIC);
+            read_u16(&mut stream).unwrap();
+            let command = read_u16(&mut stream).unwrap();
+            let handle = read_u64(&mut stream).unwrap();
+            let offset = read_u64(&mut stream).unwrap();
+            let len = read_u32(&mut stream).unwrap() as usize;
+            let range = offset as usize..offset as usize + len;
+
+            match command {
+                NBD_CMD_READ => {
+                    let half = len / 2;
+                    let mut data = offset.to_be_bytes().to_vec();
+                    data.extend_from_slice(&disk[range.start..range.start + half]);
+                    write_chunk(&mut stream, 0, NBD_REPLY_TYPE_OFFSET_DATA, handle, &data);
+                    let mut hole = (offset + half as u64).to_be_bytes().to_vec();
+                    hole.extend_from_slice(&((len - half) as u32).to_be_bytes());
+                    write_chunk(
+                        &mut stream,
+                        NBD_REPLY_FLAG_DONE,
+                        NBD_REPLY_TYPE_OFFSET_HOLE,
+                        handle,
+                        &hole,
+                    );
+                }
+                NBD_CMD_WRITE | NBD_CMD_FLUSH => {
+                    if command == NBD_CMD_WRITE {
+                        stream.read_exact(&mut disk[range]).unwrap();
+                    }
+                    let mut reply = NBD_SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+                    reply.extend_from_slice(&0u32.to_be_bytes());
+                    reply.extend_from_slice(&handle.to_be_bytes());
+                    stream.write_all(&reply).unwrap();
+                }
+                NBD_CMD_DISC => return,
+                _ => panic!("unexpected command {}", command),
+            }
+        }
+    }
+
+    #[test]
+    fn test_nbd_client() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve(server_stream, 0x10000));
+
+        let mut client = NbdClient::new(Box::new(client_stream), "export").unwrap();
+        assert_eq!(client.size, 0x10000);
+        assert!(client.structured_replies);
+
+        client.write_at(&[0xaau8; 0x400], 0x1000).unwrap();
+        client.flush().unwrap();
+
+        // The second half of the read is reported as a hole.
+        let mut buf = [0xffu8; 0x400];
+        client.read_at(&mut buf, 0x1000).unwrap();
+        assert_eq!(&buf[..0x200], &[0xaau8; 0x200][..]);
+        assert_eq!(&buf[0x200..], &[0u8; 0x200][..]);
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_nbd_invalid_chunk() {
+        let (client_stream, mut server_stream) = UnixStream::pair().unwrap();
+        let mut client = NbdClient {
+            stream: Box::new(client_stream),
+            size: 0x1000,
+            flags: 0,
+            structured_replies: true,
+            handle: 1,
+        };
+
+        // A hole chunk holds its offset and its length.
+        write_chunk(
+            &mut server_stream,
+            NBD_REPLY_FLAG_DONE,
+            NBD_REPLY_TYPE_OFFSET_HOLE,
+            1,
+            &0u64.to_be_bytes(),
+        );
+        let mut buf = [0xffu8; 0x10];
+        assert_eq!(
+            client.receive_reply(1, &mut buf, 0).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_nbd_address_parsing() {
+        assert_eq!(
+            NbdAddress::parse("nbd://192.168.1.2/disk0").unwrap(),
+            NbdAddress::Tcp {
+                address: "192.168.1.2:10809".parse().unwrap(),
+                export: "disk0".to_owned(),
+            }
+        );
+        assert_eq!(
+            NbdAddress::parse("nbd://[::1]:1234").unwrap(),
+            NbdAddress::Tcp {
+                address: "[::1]:1234".parse().unwrap(),
+                export: "".to_owned(),
+            }
+        );
+        assert_eq!(
+            NbdAddress::parse("nbd+unix:///disk0?socket=/tmp/nbd.sock").unwrap(),
+            NbdAddress::Unix {
+                socket: PathBuf::from("/tmp/nbd.sock"),
+                export: "disk0".to_owned(),
+            }
+        );
+        assert!(NbdAddress::parse("nbd://storage.local/disk0").is_err());
+        assert!(NbdAddress::parse("nbd://192.168.1.2:port/disk0").is_err());
+        assert!(NbdAddress::parse("nbd+unix:///disk0").is_err());
+        assert!(NbdAddress::parse("nbd+unix://host/disk0?socket=/tmp/nbd.sock").is_err());
+        assert!(NbdAddress::is_nbd_uri(
+            "nbd+unix:///disk0?socket=/tmp/nbd.sock"
+        ));
+        assert!(!NbdAddress::is_nbd_uri("/path/to/nbd://disk"));
+    }
+}
@@ -0,0 +1,439 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Granularity of the copy-on-write.
+pub const OVERLAY_CLUSTER_SIZE: u64 = 0x10000;
+
+// What an overlay is layered over: the base image, or the overlay below it
+// in a chain, both being only read from.
+enum Backing {
+    Image(File),
+    Overlay(Box<Overlay>),
+}
+
+impl Backing {
+    fn size(&mut self) -> io::Result<u64> {
+        match self {
+            Backing::Image(file) => file.seek(SeekFrom::End(0)),
+            Backing::Overlay(overlay) => Ok(overlay.size),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Backing::Image(file) => file.read_exact_at(buf, offset),
+            Backing::Overlay(overlay) => overlay.read_at(buf, offset),
+        }
+    }
+}
+
+/// Copy-on-write overlay layered over a read-only base image, possibly
+/// through a chain of read-only overlays.
+///
+/// The overlay is a sparse file holding the data written by the guest at the
+/// same offset it has in the disk, followed by a bitmap with one bit per
+/// cluster, set once the cluster has been copied to the overlay. This lets
+/// several VMs share the same base image, each VM only paying for the
+/// clusters it modified.
+struct Overlay {
+    base: Backing,
+    overlay: File,
+    size: u64,
+    bitmap: Vec<u8>,
+    bitmap_offset: u64,
+}
+
+impl Overlay {
+    // Opens the overlay at `overlay_path`, which is created if it doesn't
+    // exist yet and `writable` is set.
+    fn new(mut base: Backing, overlay_path: &Path, writable: bool) -> io::Result<Self> {
+        let size = base.size()?;
+        let clusters = (size + OVERLAY_CLUSTER_SIZE - 1) / OVERLAY_CLUSTER_SIZE;
+        let bitmap_offset = clusters * OVERLAY_CLUSTER_SIZE;
+        let bitmap_len = ((clusters + 7) / 8) as usize;
+        let overlay_len = bitmap_offset + bitmap_len as u64;
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(writable);
+        let (overlay, created) = match options.open(overlay_path) {
+            Ok(overlay) => (overlay, false),
+            Err(e) if writable && e.kind() == io::ErrorKind::NotFound => {
+                (options.create_new(true).open(overlay_path)?, true)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut bitmap = vec![0u8; bitmap_len];
+        if created {
+            // The file is sparse, which means an empty bitmap.
+            overlay.set_len(overlay_len)?;
+        } else {
+            let len = overlay.metadata()?.len();
+            if len != overlay_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "overlay size 0x{:x} doesn't match base image, expected 0x{:x}",
+                        len, overlay_len
+                    ),
+                ));
+            }
+            overlay.read_exact_at(&mut bitmap, bitmap_offset)?;
+        }
+
+        Ok(Overlay {
+            base,
+            overlay,
+            size,
+            bitmap,
+            bitmap_offset,
+        })
+    }
+
+    fn is_allocated(&self, cluster: u64) -> bool {
+        self.bitmap[(cluster / 8) as usize] & (1 << (cluster % 8)) != 0
+    }
+
+    fn allocate(&mut self, cluster: u64) -> io::Result<()> {
+        // Copy the content from the base image first, so that the parts of
+        // the cluster which are not going to be written remain valid.
+        let start = cluster * OVERLAY_CLUSTER_SIZE;
+        let len = std::cmp::min(OVERLAY_CLUSTER_SIZE, self.size - start) as usize;
+        let mut data = vec![0u8; len];
+        self.base.read_at(&mut data, start)?;
+        self.overlay.write_all_at(&data, start)?;
+
+        // The bitmap is only updated once the data has been copied.
+        let index = (cluster / 8) as usize;
+        self.bitmap[index] |= 1 << (cluster % 8);
+        self.overlay.write_all_at(
+            &self.bitmap[index..index + 1],
+            self.bitmap_offset + index as u64,
+        )
+    }
+
+    // Splits the access into chunks not crossing any cluster boundary.
+    fn chunks(&self, offset: u64, len: usize) -> io::Result<Vec<(u64, u64, usize)>> {
+        if offset
+            .checked_add(len as u64)
+            .map_or(true, |end| end > self.size)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut chunks = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let address = offset + done as u64;
+            let cluster = address / OVERLAY_CLUSTER_SIZE;
+            let cluster_end = (cluster + 1) * OVERLAY_CLUSTER_SIZE;
+            let count = std::cmp::min((cluster_end - address) as usize, len - done);
+            chunks.push((cluster, address, count));
+            done += count;
+        }
+
+        Ok(chunks)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        for (cluster, address, count) in self.chunks(offset, buf.len())? {
+            let buf = &mut buf[done..done + count];
+            if self.is_allocated(cluster) {
+                self.overlay.read_exact_at(buf, address)?;
+            } else {
+                self.base.read_at(buf, address)?;
+            }
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        for (cluster, address, count) in self.chunks(offset, buf.len())? {
+            if !self.is_allocated(cluster) {
+                self.allocate(cluster)?;
+            }
+            self.overlay
+                .write_all_at(&buf[done..done + count], address)?;
+            done += count;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct OverlayDiskSync {
+    overlay: Arc<Mutex<Overlay>>,
+}
+
+impl OverlayDiskSync {
+    /// Creates a disk backed by the read-only `base` image, layered with
+    /// the read-only `backing_overlays` from the bottom up, with every write
+    /// going to the overlay file located at `overlay_path`. The overlay is
+    /// created if it doesn't exist yet.
+    pub fn new(base: File, backing_overlays: &[PathBuf], overlay_path: &Path) -> io::Result<Self> {
+        let mut backing = Backing::Image(base);
+        for path in backing_overlays {
+            backing = Backing::Overlay(Box::new(Overlay::new(backing, path, false)?));
+        }
+
+        Ok(OverlayDiskSync {
+            overlay: Arc::new(Mutex::new(Overlay::new(backing, overlay_path, true)?)),
+        })
+    }
+}
+
+impl DiskFile for OverlayDiskSync {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.overlay.lock().unwrap().size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(
+            Box::new(OverlaySync::new(self.overlay.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+}
+
+pub struct OverlaySync {
+    overlay: Arc<Mutex<Overlay>>,
+    eventfd: EventFd,
+    completion_list: Vec<(u64, i32)>,
+}
+
+impl OverlaySync {
+    fn new(overlay: Arc<Mutex<Overlay>>) -> io::Result<Self> {
+        Ok(OverlaySync {
+            overlay,
+            eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+            completion_list: Vec::new(),
+        })
+    }
+
+    fn complete_request(&mut self, user_data: u64, result: usize) {
+        self.completion_list.push((user_data, result as i32));
+        self.eventfd.write(1).unwrap();
+    }
+}
+
+impl AsyncIo for OverlaySync {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let mut offset = offset as u64;
+        {
+            let overlay = self.overlay.lock().unwrap();
+            for iovec in iovecs.iter() {
+                // Safe because the iovec points to guest memory which has
+                // been validated while parsing the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len)
+                };
+                overlay
+                    .read_at(buf, offset)
+                    .map_err(AsyncIoError::ReadVectored)?;
+                offset += iovec.iov_len as u64;
+            }
+        }
+
+        let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, len);
+        Ok(())
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        let mut offset = offset as u64;
+        {
+            let mut overlay = self.overlay.lock().unwrap();
+            for iovec in iovecs.iter() {
+                // Safe because the iovec points to guest memory which has
+                // been validated while parsing the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                };
+                overlay
+                    .write_at(buf, offset)
+                    .map_err(AsyncIoError::WriteVectored)?;
+                offset += iovec.iov_len as u64;
+            }
+        }
+
+        let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.complete_request(user_data, len);
+        Ok(())
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.overlay
+            .lock()
+            .unwrap()
+            .overlay
+            .sync_all()
+            .map_err(AsyncIoError::Fsync)?;
+
+        if let Some(user_data) = user_data {
+            self.complete_request(user_data, 0);
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn create_base(size: usize) -> (TempFile, File) {
+        let temp_file = TempFile::new().unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        temp_file.as_file().write_all_at(&data, 0).unwrap();
+        let file = File::open(temp_file.as_path()).unwrap();
+        (temp_file, file)
+    }
+
+    #[test]
+    fn test_overlay_copy_on_write() {
+        let size = 3 * OVERLAY_CLUSTER_SIZE as usize + 0x200;
+        let (_base_file, base) = create_base(size);
+        let overlay_file = TempFile::new().unwrap();
+        let overlay_path = overlay_file.as_path().to_path_buf();
+        std::fs::remove_file(&overlay_path).unwrap();
+
+        let mut overlay = Overlay::new(
+            Backing::Image(base.try_clone().unwrap()),
+            &overlay_path,
+            true,
+        )
+        .unwrap();
+
+        // Write across the boundary of the first two clusters.
+        let offset = OVERLAY_CLUSTER_SIZE - 0x100;
+        overlay.write_at(&[0xffu8; 0x200], offset).unwrap();
+        assert!(overlay.is_allocated(0));
+        assert!(overlay.is_allocated(1));
+        assert!(!overlay.is_allocated(2));
+        assert!(!overlay.is_allocated(3));
+
+        let mut data = vec![0u8; size];
+        overlay.read_at(&mut data, 0).unwrap();
+        for (i, byte) in data.iter().enumerate() {
+            let in_write = i as u64 >= offset && (i as u64) < offset + 0x200;
+            let expected = if in_write { 0xff } else { (i % 251) as u8 };
+            assert_eq!(*byte, expected, "mismatch at 0x{:x}", i);
+        }
+
+        // The base image is left untouched.
+        let mut base_data = vec![0u8; 0x200];
+        base.read_exact_at(&mut base_data, offset).unwrap();
+        assert_ne!(base_data, vec![0xffu8; 0x200]);
+
+        // Writing to the last partial cluster works as well.
+        overlay
+            .write_at(&[0xeeu8; 0x10], size as u64 - 0x10)
+            .unwrap();
+        assert!(overlay.is_allocated(3));
+
+        // Accesses beyond the end of the disk are rejected.
+        assert!(overlay.write_at(&[0u8; 0x10], size as u64 - 0x8).is_err());
+
+        // The bitmap is persisted, so reopening the overlay gives the same
+        // content.
+        drop(overlay);
+        let overlay = Overlay::new(Backing::Image(base), &overlay_path, false).unwrap();
+        let mut reopened = vec![0u8; size];
+        overlay.read_at(&mut reopened, 0).unwrap();
+        assert_eq!(&reopened[..size - 0x10], &data[..size - 0x10]);
+        assert_eq!(&reopened[size - 0x10..], &[0xeeu8; 0x10]);
+    }
+
+    #[test]
+    fn test_overlay_size_mismatch() {
+        let (_base_file, base) = create_base(OVERLAY_CLUSTER_SIZE as usize);
+        let overlay_file = TempFile::new().unwrap();
+        overlay_file.as_file().set_len(0x1000).unwrap();
+
+        assert!(Overlay::new(Backing::Image(base), overlay_file.as_path(), true).is_err());
+    }
+
+    #[test]
+    fn test_overlay_chain() {
+        let size = 2 * OVERLAY_CLUSTER_SIZE as usize;
+        let (base_file, base) = create_base(size);
+        let lower_file = TempFile::new().unwrap();
+        let lower_path = lower_file.as_path().to_path_buf();
+        std::fs::remove_file(&lower_path).unwrap();
+        let upper_file = TempFile::new().unwrap();
+        let upper_path = upper_file.as_path().to_path_buf();
+        std::fs::remove_file(&upper_path).unwrap();
+
+        let mut lower =
+            Overlay::new(Backing::Image(base.try_clone().unwrap()), &lower_path, true).unwrap();
+        lower.write_at(&[0xffu8; 0x10], 0).unwrap();
+        drop(lower);
+
+        // A read-only overlay isn't created.
+        assert!(OverlayDiskSync::new(
+            base.try_clone().unwrap(),
+            &[upper_path.clone()],
+            &lower_path
+        )
+        .is_err());
+
+        let disk = OverlayDiskSync::new(base, &[lower_path.clone()], &upper_path).unwrap();
+        let mut overlay = disk.overlay.lock().unwrap();
+        overlay
+            .write_at(&[0xeeu8; 0x10], OVERLAY_CLUSTER_SIZE)
+            .unwrap();
+
+        // The lower overlay is read through, and left untouched.
+        let mut data = [0u8; 0x10];
+        overlay.read_at(&mut data, 0).unwrap();
+        assert_eq!(data, [0xffu8; 0x10]);
+        overlay.read_at(&mut data, OVERLAY_CLUSTER_SIZE).unwrap();
+        assert_eq!(data, [0xeeu8; 0x10]);
+        overlay.read_at(&mut data, 0x10).unwrap();
+        assert_eq!(data[0], 0x10);
+        assert!(!overlay.is_allocated(0));
+        let lower_len = std::fs::metadata(&lower_path).unwrap().len();
+        let lower = Overlay::new(
+            Backing::Image(File::open(base_file.as_path()).unwrap()),
+            &lower_path,
+            false,
+        )
+        .unwrap();
+        assert!(!lower.is_allocated(1));
+        assert_eq!(std::fs::metadata(&lower_path).unwrap().len(), lower_len);
+    }
+}
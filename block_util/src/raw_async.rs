@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::async_io::{
-    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, DiskTopology,
 };
 use io_uring::{opcode, squeue, types, IoUring};
 use std::fs::File;
@@ -35,6 +35,13 @@ impl DiskFile for RawFileDisk {
                 .map_err(DiskFileError::NewAsyncIo)?,
         ) as Box<dyn AsyncIo>)
     }
+
+    fn topology(&mut self) -> DiskTopology {
+        DiskTopology::probe(&self.file).unwrap_or_else(|e| {
+            warn!("Unable to get disk topology, using the default: {}", e);
+            DiskTopology::default()
+        })
+    }
 }
 
 pub struct RawFileAsync {
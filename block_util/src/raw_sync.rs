@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use crate::async_io::{
-    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, DiskTopology,
 };
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
@@ -31,6 +31,13 @@ impl DiskFile for RawFileDiskSync {
     fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
         Ok(Box::new(RawFileSync::new(self.file.as_raw_fd())) as Box<dyn AsyncIo>)
     }
+
+    fn topology(&mut self) -> DiskTopology {
+        DiskTopology::probe(&self.file).unwrap_or_else(|e| {
+            warn!("Unable to get disk topology, using the default: {}", e);
+            DiskTopology::default()
+        })
+    }
 }
 
 pub struct RawFileSync {
@@ -0,0 +1,444 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Ceph RADOS block device (RBD) backend.
+//!
+//! Disks can be images stored in a Ceph cluster, designated with
+//! `rbd:<pool>/<image>[@<snapshot>][:id=<user>][:conf=<ceph.conf>]`. The
+//! images are accessed through librbd, which is only linked when the `rbd`
+//! feature is enabled, while the paths can always be parsed so that they are
+//! validated consistently.
+
+use std::io;
+use std::path::PathBuf;
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Location of an RBD image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RbdAddress {
+    pub pool: String,
+    pub image: String,
+    /// Snapshot of the image, which is then read-only.
+    pub snapshot: Option<String>,
+    /// Ceph user the cluster is accessed as, `admin` by default.
+    pub id: Option<String>,
+    /// Ceph configuration file, looked up in the default locations if unset.
+    pub conf: Option<PathBuf>,
+}
+
+impl RbdAddress {
+    /// Whether a disk path designates an RBD image rather than a file.
+    pub fn is_rbd_path(path: &str) -> bool {
+        path.starts_with("rbd:")
+    }
+
+    pub fn parse(path: &str) -> io::Result<Self> {
+        let mut fields = path
+            .strip_prefix("rbd:")
+            .ok_or_else(|| invalid_input(format!("Not an RBD path: {}", path)))?
+            .split(':');
+
+        // split() always yields at least one field.
+        let (pool, image) = fields
+            .next()
+            .unwrap()
+            .split_once('/')
+            .ok_or_else(|| invalid_input(format!("Missing RBD pool or image: {}", path)))?;
+        let (image, snapshot) = match image.split_once('@') {
+            Some((image, snapshot)) => (image, Some(snapshot.to_owned())),
+            None => (image, None),
+        };
+        if pool.is_empty() || image.is_empty() || snapshot.as_deref() == Some("") {
+            return Err(invalid_input(format!(
+                "Missing RBD pool or image: {}",
+                path
+            )));
+        }
+
+        let mut address = RbdAddress {
+            pool: pool.to_owned(),
+            image: image.to_owned(),
+            snapshot,
+            ..Default::default()
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("id", id)) if !id.is_empty() => address.id = Some(id.to_owned()),
+                Some(("conf", conf)) if !conf.is_empty() => {
+                    address.conf = Some(PathBuf::from(conf))
+                }
+                _ => {
+                    return Err(invalid_input(format!(
+                        "Invalid RBD option {}: {}",
+                        field, path
+                    )))
+                }
+            }
+        }
+
+        Ok(address)
+    }
+}
+
+#[cfg(feature = "rbd")]
+pub use self::backend::{RbdDiskSync, RbdSync};
+
+#[cfg(feature = "rbd")]
+mod backend {
+    use super::RbdAddress;
+    use crate::async_io::{
+        AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+    };
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+    use std::sync::Arc;
+    use vmm_sys_util::eventfd::EventFd;
+
+    type RadosT = *mut c_void;
+    type RadosIoctxT = *mut c_void;
+    type RbdImageT = *mut c_void;
+
+    #[link(name = "rados")]
+    extern "C" {
+        fn rados_create(cluster: *mut RadosT, id: *const c_char) -> c_int;
+        fn rados_conf_read_file(cluster: RadosT, path: *const c_char) -> c_int;
+        fn rados_connect(cluster: RadosT) -> c_int;
+        fn rados_shutdown(cluster: RadosT);
+        fn rados_ioctx_create(
+            cluster: RadosT,
+            pool_name: *const c_char,
+            ioctx: *mut RadosIoctxT,
+        ) -> c_int;
+        fn rados_ioctx_destroy(ioctx: RadosIoctxT);
+    }
+
+    #[link(name = "rbd")]
+    extern "C" {
+        fn rbd_open(
+            ioctx: RadosIoctxT,
+            name: *const c_char,
+            image: *mut RbdImageT,
+            snap_name: *const c_char,
+        ) -> c_int;
+        fn rbd_open_read_only(
+            ioctx: RadosIoctxT,
+            name: *const c_char,
+            image: *mut RbdImageT,
+            snap_name: *const c_char,
+        ) -> c_int;
+        fn rbd_close(image: RbdImageT) -> c_int;
+        fn rbd_get_size(image: RbdImageT, size: *mut u64) -> c_int;
+        fn rbd_read(image: RbdImageT, ofs: u64, len: size_t, buf: *mut c_char) -> ssize_t;
+        fn rbd_write(image: RbdImageT, ofs: u64, len: size_t, buf: *const c_char) -> ssize_t;
+        fn rbd_flush(image: RbdImageT) -> c_int;
+    }
+
+    // librados and librbd report failures as negative errno values.
+    fn check(ret: c_int) -> io::Result<()> {
+        if ret < 0 {
+            Err(io::Error::from_raw_os_error(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn c_string(s: &[u8]) -> io::Result<CString> {
+        CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    // Handles on the cluster connection, the pool and the image, released
+    // in the reverse order when dropped.
+    struct RbdImage {
+        cluster: RadosT,
+        ioctx: RadosIoctxT,
+        image: RbdImageT,
+    }
+
+    // librbd images can be used concurrently from several threads.
+    unsafe impl Send for RbdImage {}
+    unsafe impl Sync for RbdImage {}
+
+    impl RbdImage {
+        fn open(address: &RbdAddress, readonly: bool) -> io::Result<Self> {
+            let id = address
+                .id
+                .as_ref()
+                .map(|id| c_string(id.as_bytes()))
+                .transpose()?;
+            let conf = address
+                .conf
+                .as_ref()
+                .map(|conf| c_string(conf.as_os_str().as_bytes()))
+                .transpose()?;
+            let pool = c_string(address.pool.as_bytes())?;
+            let name = c_string(address.image.as_bytes())?;
+            let snapshot = address
+                .snapshot
+                .as_ref()
+                .map(|snapshot| c_string(snapshot.as_bytes()))
+                .transpose()?;
+
+            let mut image = RbdImage {
+                cluster: ptr::null_mut(),
+                ioctx: ptr::null_mut(),
+                image: ptr::null_mut(),
+            };
+            // Safe because the strings outlive the calls, and the handles
+            // are only used once successfully initialized.
+            unsafe {
+                check(rados_create(
+                    &mut image.cluster,
+                    id.as_ref().map_or(ptr::null(), |id| id.as_ptr()),
+                ))?;
+                check(rados_conf_read_file(
+                    image.cluster,
+                    conf.as_ref().map_or(ptr::null(), |conf| conf.as_ptr()),
+                ))?;
+                check(rados_connect(image.cluster))?;
+                check(rados_ioctx_create(
+                    image.cluster,
+                    pool.as_ptr(),
+                    &mut image.ioctx,
+                ))?;
+                let snap_name = snapshot
+                    .as_ref()
+                    .map_or(ptr::null(), |snapshot| snapshot.as_ptr());
+                check(if readonly {
+                    rbd_open_read_only(image.ioctx, name.as_ptr(), &mut image.image, snap_name)
+                } else {
+                    rbd_open(image.ioctx, name.as_ptr(), &mut image.image, snap_name)
+                })?;
+            }
+
+            Ok(image)
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            let mut size = 0;
+            // Safe because the image is open.
+            check(unsafe { rbd_get_size(self.image, &mut size) })?;
+            Ok(size)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            // Safe because the buffer is valid for its whole length.
+            let ret = unsafe {
+                rbd_read(
+                    self.image,
+                    offset,
+                    buf.len(),
+                    buf.as_mut_ptr() as *mut c_char,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::from_raw_os_error(-ret as i32));
+            }
+            // Reads beyond the end of the image are shorter.
+            if (ret as usize) < buf.len() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            Ok(())
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            // Safe because the buffer is valid for its whole length.
+            let ret =
+                unsafe { rbd_write(self.image, offset, buf.len(), buf.as_ptr() as *const c_char) };
+            if ret < 0 {
+                return Err(io::Error::from_raw_os_error(-ret as i32));
+            }
+            if (ret as usize) < buf.len() {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            // Safe because the image is open.
+            check(unsafe { rbd_flush(self.image) })
+        }
+    }
+
+    impl Drop for RbdImage {
+        fn drop(&mut self) {
+            // Safe because only the initialized handles are released.
+            unsafe {
+                if !self.image.is_null() {
+                    if let Err(e) = check(rbd_close(self.image)) {
+                        warn!("Error closing RBD image: {}", e);
+                    }
+                }
+                if !self.ioctx.is_null() {
+                    rados_ioctx_destroy(self.ioctx);
+                }
+                if !self.cluster.is_null() {
+                    rados_shutdown(self.cluster);
+                }
+            }
+        }
+    }
+
+    pub struct RbdDiskSync {
+        image: Arc<RbdImage>,
+    }
+
+    impl RbdDiskSync {
+        /// Connects to the Ceph cluster and opens the image at `address`.
+        /// An image snapshot can only back a read-only disk.
+        pub fn new(address: &RbdAddress, readonly: bool) -> io::Result<Self> {
+            if !readonly && address.snapshot.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "RBD snapshots are read-only",
+                ));
+            }
+
+            Ok(RbdDiskSync {
+                image: Arc::new(RbdImage::open(address, readonly)?),
+            })
+        }
+    }
+
+    impl DiskFile for RbdDiskSync {
+        fn size(&mut self) -> DiskFileResult<u64> {
+            self.image.size().map_err(DiskFileError::Size)
+        }
+
+        fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+            Ok(
+                Box::new(RbdSync::new(self.image.clone()).map_err(DiskFileError::NewAsyncIo)?)
+                    as Box<dyn AsyncIo>,
+            )
+        }
+    }
+
+    pub struct RbdSync {
+        image: Arc<RbdImage>,
+        eventfd: EventFd,
+        completion_list: Vec<(u64, i32)>,
+    }
+
+    impl RbdSync {
+        fn new(image: Arc<RbdImage>) -> io::Result<Self> {
+            Ok(RbdSync {
+                image,
+                eventfd: EventFd::new(libc::EFD_NONBLOCK)?,
+                completion_list: Vec::new(),
+            })
+        }
+
+        fn complete_request(&mut self, user_data: u64, result: usize) {
+            self.completion_list.push((user_data, result as i32));
+            self.eventfd.write(1).unwrap();
+        }
+    }
+
+    impl AsyncIo for RbdSync {
+        fn notifier(&self) -> &EventFd {
+            &self.eventfd
+        }
+
+        fn read_vectored(
+            &mut self,
+            offset: libc::off_t,
+            iovecs: Vec<libc::iovec>,
+            user_data: u64,
+        ) -> AsyncIoResult<()> {
+            let mut offset = offset as u64;
+            for iovec in iovecs.iter() {
+                // Safe because the iovec points to guest memory which has
+                // been validated while parsing the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len)
+                };
+                self.image
+                    .read_at(buf, offset)
+                    .map_err(AsyncIoError::ReadVectored)?;
+                offset += iovec.iov_len as u64;
+            }
+
+            let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+            self.complete_request(user_data, len);
+            Ok(())
+        }
+
+        fn write_vectored(
+            &mut self,
+            offset: libc::off_t,
+            iovecs: Vec<libc::iovec>,
+            user_data: u64,
+        ) -> AsyncIoResult<()> {
+            let mut offset = offset as u64;
+            for iovec in iovecs.iter() {
+                // Safe because the iovec points to guest memory which has
+                // been validated while parsing the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                };
+                self.image
+                    .write_at(buf, offset)
+                    .map_err(AsyncIoError::WriteVectored)?;
+                offset += iovec.iov_len as u64;
+            }
+
+            let len = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+            self.complete_request(user_data, len);
+            Ok(())
+        }
+
+        fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+            self.image.flush().map_err(AsyncIoError::Fsync)?;
+
+            if let Some(user_data) = user_data {
+                self.complete_request(user_data, 0);
+            }
+
+            Ok(())
+        }
+
+        fn complete(&mut self) -> Vec<(u64, i32)> {
+            self.completion_list.drain(..).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rbd_address_parsing() {
+        assert_eq!(
+            RbdAddress::parse("rbd:pool0/disk0").unwrap(),
+            RbdAddress {
+                pool: "pool0".to_owned(),
+                image: "disk0".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RbdAddress::parse("rbd:pool0/disk0@snap0:id=libvirt:conf=/etc/ceph/ceph.conf").unwrap(),
+            RbdAddress {
+                pool: "pool0".to_owned(),
+                image: "disk0".to_owned(),
+                snapshot: Some("snap0".to_owned()),
+                id: Some("libvirt".to_owned()),
+                conf: Some(PathBuf::from("/etc/ceph/ceph.conf")),
+            }
+        );
+        assert!(RbdAddress::parse("rbd:disk0").is_err());
+        assert!(RbdAddress::parse("rbd:/disk0").is_err());
+        assert!(RbdAddress::parse("rbd:pool0/disk0@").is_err());
+        assert!(RbdAddress::parse("rbd:pool0/disk0:user=libvirt").is_err());
+        assert!(RbdAddress::parse("rbd:pool0/disk0:id=").is_err());
+        assert!(RbdAddress::is_rbd_path("rbd:pool0/disk0"));
+        assert!(!RbdAddress::is_rbd_path("/path/to/rbd:disk0"));
+    }
+}
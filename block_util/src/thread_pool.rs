@@ -0,0 +1,283 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult, DiskTopology,
+};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Offloads the blocking I/O of a synchronous disk backend to a pool of
+/// worker threads.
+///
+/// Synchronous backends perform each request from the queue thread, meaning
+/// one slow request stalls every request behind it. With this wrapper, the
+/// requests are handed over to the workers, each one relying on its own
+/// `AsyncIo` instance from the wrapped backend, and the completions are
+/// reported to the queue thread through the notifier eventfd, the same way
+/// io_uring does.
+pub struct ThreadPoolDisk {
+    disk: Box<dyn DiskFile>,
+    workers: usize,
+}
+
+impl ThreadPoolDisk {
+    pub fn new(disk: Box<dyn DiskFile>, workers: usize) -> Self {
+        ThreadPoolDisk { disk, workers }
+    }
+}
+
+impl DiskFile for ThreadPoolDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        self.disk.size()
+    }
+
+    fn topology(&mut self) -> DiskTopology {
+        self.disk.topology()
+    }
+
+    fn new_async_io(&self, ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        let mut ios = Vec::with_capacity(self.workers);
+        for _ in 0..self.workers {
+            ios.push(self.disk.new_async_io(ring_depth)?);
+        }
+
+        Ok(
+            Box::new(ThreadPoolIo::new(ios).map_err(DiskFileError::NewAsyncIo)?)
+                as Box<dyn AsyncIo>,
+        )
+    }
+}
+
+enum Job {
+    Read {
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    },
+    Write {
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    },
+    Fsync(Option<u64>),
+}
+
+// Safe because the iovecs point to guest memory, which remains mapped for as
+// long as the device using the disk exists.
+unsafe impl Send for Job {}
+
+type CompletionList = Arc<Mutex<Vec<(u64, i32)>>>;
+
+pub struct ThreadPoolIo {
+    sender: Option<Mutex<mpsc::Sender<Job>>>,
+    eventfd: EventFd,
+    completion_list: CompletionList,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPoolIo {
+    pub fn new(ios: Vec<Box<dyn AsyncIo>>) -> std::io::Result<Self> {
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK)?;
+        let completion_list = Arc::new(Mutex::new(Vec::new()));
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = Vec::with_capacity(ios.len());
+        for (i, io) in ios.into_iter().enumerate() {
+            let receiver = receiver.clone();
+            let completion_list = completion_list.clone();
+            let eventfd = eventfd.try_clone()?;
+            workers.push(
+                thread::Builder::new()
+                    .name(format!("disk_worker{}", i))
+                    .spawn(move || Self::work(io, receiver, completion_list, eventfd))?,
+            );
+        }
+
+        Ok(ThreadPoolIo {
+            sender: Some(Mutex::new(sender)),
+            eventfd,
+            completion_list,
+            workers,
+        })
+    }
+
+    fn work(
+        mut io: Box<dyn AsyncIo>,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        completion_list: CompletionList,
+        eventfd: EventFd,
+    ) {
+        loop {
+            // The channel is closed once the pool is dropped.
+            let job = receiver.lock().unwrap().recv();
+            let (user_data, result) = match job {
+                Ok(Job::Read {
+                    offset,
+                    iovecs,
+                    user_data,
+                }) => (Some(user_data), io.read_vectored(offset, iovecs, user_data)),
+                Ok(Job::Write {
+                    offset,
+                    iovecs,
+                    user_data,
+                }) => (
+                    Some(user_data),
+                    io.write_vectored(offset, iovecs, user_data),
+                ),
+                Ok(Job::Fsync(user_data)) => (user_data, io.fsync(user_data)),
+                Err(_) => return,
+            };
+
+            let mut completions = io.complete();
+            if let Err(e) = result {
+                error!("Failed executing disk request: {}", e);
+                // Report the failure the same way io_uring does, through a
+                // negative errno, so that the queue thread can handle it.
+                if let Some(user_data) = user_data {
+                    completions.push((user_data, -Self::errno(&e)));
+                }
+            }
+
+            if !completions.is_empty() {
+                completion_list.lock().unwrap().append(&mut completions);
+                eventfd.write(1).unwrap();
+            }
+        }
+    }
+
+    fn errno(e: &AsyncIoError) -> i32 {
+        match e {
+            AsyncIoError::ReadVectored(e)
+            | AsyncIoError::WriteVectored(e)
+            | AsyncIoError::Fsync(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        }
+    }
+
+    fn submit(&self, job: Job) -> std::io::Result<()> {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .send(job)
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "No disk worker available")
+            })
+    }
+}
+
+impl AsyncIo for ThreadPoolIo {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.submit(Job::Read {
+            offset,
+            iovecs,
+            user_data,
+        })
+        .map_err(AsyncIoError::ReadVectored)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: Vec<libc::iovec>,
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.submit(Job::Write {
+            offset,
+            iovecs,
+            user_data,
+        })
+        .map_err(AsyncIoError::WriteVectored)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        self.submit(Job::Fsync(user_data))
+            .map_err(AsyncIoError::Fsync)
+    }
+
+    fn complete(&mut self) -> Vec<(u64, i32)> {
+        self.completion_list.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Drop for ThreadPoolIo {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers terminate once the requests
+        // already submitted have been processed.
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if let Err(e) = worker.join() {
+                error!("Error joining disk worker: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_sync::RawFileDiskSync;
+    use std::fs::OpenOptions;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn wait_completions(io: &mut dyn AsyncIo, count: usize) -> Vec<(u64, i32)> {
+        let mut completions = Vec::new();
+        while completions.len() < count {
+            // The eventfd is non blocking, hence the need for polling.
+            if io.notifier().read().is_ok() {
+                completions.append(&mut io.complete());
+            } else {
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+        completions
+    }
+
+    #[test]
+    fn test_thread_pool_io() {
+        let temp_file = TempFile::new().unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_file.as_path())
+            .unwrap();
+        file.set_len(0x1000).unwrap();
+
+        let disk = ThreadPoolDisk::new(Box::new(RawFileDiskSync::new(file)), 4);
+        let mut io = disk.new_async_io(1).unwrap();
+
+        let mut write_buf = [0xa5u8; 0x200];
+        let iovecs = vec![libc::iovec {
+            iov_base: write_buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: write_buf.len(),
+        }];
+        io.write_vectored(0x200, iovecs, 1).unwrap();
+        assert_eq!(wait_completions(io.as_mut(), 1), vec![(1, 0x200)]);
+
+        let mut read_buf = [0u8; 0x200];
+        let iovecs = vec![libc::iovec {
+            iov_base: read_buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: read_buf.len(),
+        }];
+        io.read_vectored(0x200, iovecs, 2).unwrap();
+        io.fsync(Some(3)).unwrap();
+
+        let mut completions = wait_completions(io.as_mut(), 2);
+        completions.sort_unstable();
+        assert_eq!(completions, vec![(2, 0x200), (3, 0)]);
+        assert_eq!(read_buf, write_buf);
+    }
+}
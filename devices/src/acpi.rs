@@ -14,29 +14,62 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub const GED_DEVICE_ACPI_SIZE: usize = 0x1;
 
-/// A device for handling ACPI shutdown and reboot
+/// A device for handling ACPI shutdown, reboot and sleep
 pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
+    suspend_evt: EventFd,
+    sleep_states: Vec<u8>,
+    sleep_state: Option<u8>,
 }
 
 impl AcpiShutdownDevice {
     /// Constructs a device that will signal the given event when the guest requests it.
-    pub fn new(exit_evt: EventFd, reset_evt: EventFd) -> AcpiShutdownDevice {
+    /// The guest can only enter the S3 and S4 sleep states listed in `sleep_states`.
+    pub fn new(
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        suspend_evt: EventFd,
+        sleep_states: Vec<u8>,
+    ) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
+            suspend_evt,
+            sleep_states,
+            sleep_state: None,
         }
     }
+
+    /// Sleep state (S3 or S4) the guest entered, if it hasn't been woken up
+    /// since then.
+    pub fn sleep_state(&self) -> Option<u8> {
+        self.sleep_state
+    }
+
+    /// Sets the wake status, letting the guest resume from the sleep state.
+    pub fn wake(&mut self) {
+        self.sleep_state = None;
+    }
 }
 
-// Same I/O port used for shutdown and reboot
+// The ACPI DSDT table specifies the sleep states through these values
+pub const S3_SLEEP_VALUE: u8 = 3;
+pub const S4_SLEEP_VALUE: u8 = 4;
+pub const S5_SLEEP_VALUE: u8 = 5;
+
+// Same I/O port used for shutdown, reboot and sleep
 impl BusDevice for AcpiShutdownDevice {
-    // Spec has all fields as zero
+    // The wake status is the only field being reported, as the guest polls
+    // it after entering a sleep state.
     fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        const WAKE_STATUS_BIT: u8 = 7;
         for i in data.iter_mut() {
             *i = 0;
         }
+        if self.sleep_state.is_none() {
+            data[0] = 1 << WAKE_STATUS_BIT;
+        }
     }
 
     fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
@@ -46,15 +79,31 @@ impl BusDevice for AcpiShutdownDevice {
                 error!("Error triggering ACPI reset event: {}", e);
             }
         }
-        // The ACPI DSDT table specifies the S5 sleep state (shutdown) as value 5
-        const S5_SLEEP_VALUE: u8 = 5;
         const SLEEP_STATUS_EN_BIT: u8 = 5;
         const SLEEP_VALUE_BIT: u8 = 2;
-        if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
-            debug!("ACPI Shutdown signalled");
-            if let Err(e) = self.exit_evt.write(1) {
-                error!("Error triggering ACPI shutdown event: {}", e);
+        if data[0] & (1 << SLEEP_STATUS_EN_BIT) == 0 {
+            return None;
+        }
+        match (data[0] >> SLEEP_VALUE_BIT) & 0x7 {
+            S5_SLEEP_VALUE => {
+                debug!("ACPI Shutdown signalled");
+                if let Err(e) = self.exit_evt.write(1) {
+                    error!("Error triggering ACPI shutdown event: {}", e);
+                }
+            }
+            sleep_value @ S3_SLEEP_VALUE | sleep_value @ S4_SLEEP_VALUE
+                if !self.sleep_states.contains(&sleep_value) =>
+            {
+                warn!("Ignoring ACPI S{} sleep, which is not enabled", sleep_value);
             }
+            sleep_value @ S3_SLEEP_VALUE | sleep_value @ S4_SLEEP_VALUE => {
+                debug!("ACPI S{} sleep signalled", sleep_value);
+                self.sleep_state = Some(sleep_value);
+                if let Err(e) = self.suspend_evt.write(1) {
+                    error!("Error triggering ACPI suspend event: {}", e);
+                }
+            }
+            _ => {}
         }
         None
     }
@@ -163,6 +212,19 @@ impl Aml for AcpiGedDevice {
                                 &0x80usize,
                             )],
                         ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &16usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &16usize),
+                            vec![&aml::MethodCall::new("\\_SB_.ADP1.PSCN".into(), vec![])],
+                        ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &32usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &32usize),
+                            vec![&aml::Notify::new(
+                                &aml::Path::new("\\_SB_.SLPB"),
+                                &0x80usize,
+                            )],
+                        ),
                     ],
                 ),
             ],
@@ -171,6 +233,196 @@ impl Aml for AcpiGedDevice {
     }
 }
 
+pub const POWER_SUPPLY_DEVICE_ACPI_SIZE: usize = 0xc;
+
+// The battery is only reported through its charge level, against a fixed
+// design capacity and voltage.
+const BATTERY_DESIGN_CAPACITY_MWH: u32 = 50_000;
+const BATTERY_DESIGN_VOLTAGE_MV: u32 = 12_000;
+const BATTERY_UNKNOWN_RATE: u32 = 0xffff_ffff;
+
+// Battery state bits, as reported by _BST
+const BATTERY_DISCHARGING: u32 = 1 << 0;
+const BATTERY_CHARGING: u32 = 1 << 1;
+
+/// A device reporting an AC adapter, and optionally a battery, whose state is
+/// set by the VMM rather than by the guest.
+pub struct AcpiPowerSupplyDevice {
+    ac_online: bool,
+    battery: bool,
+    battery_level: u8,
+    address: GuestAddress,
+}
+
+impl AcpiPowerSupplyDevice {
+    pub fn new(
+        ac_online: bool,
+        battery: bool,
+        battery_level: u8,
+        address: GuestAddress,
+    ) -> AcpiPowerSupplyDevice {
+        AcpiPowerSupplyDevice {
+            ac_online,
+            battery,
+            battery_level: std::cmp::min(battery_level, 100),
+            address,
+        }
+    }
+
+    /// Updates the state reported to the guest, which must then be notified
+    /// through the GED device for it to be picked up.
+    pub fn set_state(&mut self, ac_online: bool, battery_level: u8) {
+        self.ac_online = ac_online;
+        self.battery_level = std::cmp::min(battery_level, 100);
+    }
+
+    fn battery_state(&self) -> u32 {
+        if !self.ac_online {
+            BATTERY_DISCHARGING
+        } else if self.battery_level < 100 {
+            BATTERY_CHARGING
+        } else {
+            0
+        }
+    }
+
+    fn battery_capacity(&self) -> u32 {
+        BATTERY_DESIGN_CAPACITY_MWH / 100 * self.battery_level as u32
+    }
+}
+
+// The registers are the AC adapter status, followed by the battery state and
+// its remaining capacity, as read by the _PSR and _BST methods.
+impl BusDevice for AcpiPowerSupplyDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let mut registers = [0u8; POWER_SUPPLY_DEVICE_ACPI_SIZE];
+        registers[0..4].copy_from_slice(&(self.ac_online as u32).to_le_bytes());
+        if self.battery {
+            registers[4..8].copy_from_slice(&self.battery_state().to_le_bytes());
+            registers[8..12].copy_from_slice(&self.battery_capacity().to_le_bytes());
+        }
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = registers
+                .get(offset as usize + i)
+                .copied()
+                .unwrap_or_default();
+        }
+    }
+}
+
+#[cfg(feature = "acpi")]
+impl Aml for AcpiPowerSupplyDevice {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let notify_adapter = aml::Notify::new(&aml::Path::new("\\_SB_.ADP1"), &0x80usize);
+        let notify_battery = aml::Notify::new(&aml::Path::new("\\_SB_.BAT0"), &0x80usize);
+        let mut notify: Vec<&dyn Aml> = vec![&notify_adapter];
+        if self.battery {
+            notify.push(&notify_battery);
+        }
+
+        let mut bytes = aml::Device::new(
+            "_SB_.ADP1".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ACPI0003"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::OpRegion::new(
+                    "PSST".into(),
+                    aml::OpRegionSpace::SystemMemory,
+                    self.address.0 as usize,
+                    POWER_SUPPLY_DEVICE_ACPI_SIZE,
+                ),
+                &aml::Field::new(
+                    "PSST".into(),
+                    aml::FieldAccessType::DWord,
+                    aml::FieldUpdateRule::Preserve,
+                    vec![
+                        aml::FieldEntry::Named(*b"ACON", 32),
+                        aml::FieldEntry::Named(*b"BSTA", 32),
+                        aml::FieldEntry::Named(*b"BCAP", 32),
+                    ],
+                ),
+                &aml::Method::new(
+                    "_PSR".into(),
+                    0,
+                    false,
+                    vec![&aml::Return::new(&aml::Path::new("ACON"))],
+                ),
+                // Called by the GED device when the state has changed
+                &aml::Method::new("PSCN".into(), 0, false, notify),
+            ],
+        )
+        .to_aml_bytes();
+
+        if self.battery {
+            bytes.extend_from_slice(
+                &aml::Device::new(
+                    "_SB_.BAT0".into(),
+                    vec![
+                        &aml::Name::new("_HID".into(), &aml::EisaName::new("PNP0C0A")),
+                        &aml::Name::new("_UID".into(), &aml::ZERO),
+                        &aml::Name::new(
+                            "_BIF".into(),
+                            &aml::Package::new(vec![
+                                // Power unit: mWh
+                                &aml::ZERO,
+                                &BATTERY_DESIGN_CAPACITY_MWH,
+                                &BATTERY_DESIGN_CAPACITY_MWH,
+                                // Technology: rechargeable
+                                &aml::ONE,
+                                &BATTERY_DESIGN_VOLTAGE_MV,
+                                // Warning and low capacity levels
+                                &(BATTERY_DESIGN_CAPACITY_MWH / 10),
+                                &(BATTERY_DESIGN_CAPACITY_MWH / 20),
+                                // Capacity granularities
+                                &aml::ONE,
+                                &aml::ONE,
+                                &"Virtual Battery",
+                                &"0",
+                                &"LION",
+                                &"Cloud Hypervisor",
+                            ]),
+                        ),
+                        // Filled with the battery state on every _BST call
+                        &aml::Name::new(
+                            "BSTP".into(),
+                            &aml::Package::new(vec![
+                                &aml::ZERO,
+                                &BATTERY_UNKNOWN_RATE,
+                                &aml::ZERO,
+                                &BATTERY_DESIGN_VOLTAGE_MV,
+                            ]),
+                        ),
+                        &aml::Method::new(
+                            "_BST".into(),
+                            0,
+                            true,
+                            vec![
+                                &aml::Store::new(
+                                    &aml::Index::new(
+                                        &aml::ZERO,
+                                        &aml::Path::new("BSTP"),
+                                        &aml::ZERO,
+                                    ),
+                                    &aml::Path::new("\\_SB_.ADP1.BSTA"),
+                                ),
+                                &aml::Store::new(
+                                    &aml::Index::new(&aml::ZERO, &aml::Path::new("BSTP"), &2usize),
+                                    &aml::Path::new("\\_SB_.ADP1.BCAP"),
+                                ),
+                                &aml::Return::new(&aml::Path::new("BSTP")),
+                            ],
+                        ),
+                    ],
+                )
+                .to_aml_bytes(),
+            );
+        }
+
+        bytes
+    }
+}
+
 pub struct AcpiPmTimerDevice {
     start: Instant,
 }
@@ -209,6 +209,26 @@ impl Ioapic {
         })
     }
 
+    /// Bring the IOAPIC back to its power-on state. Every redirection entry
+    /// is masked again, which also clears the Remote IRR and Delivery Status
+    /// bits that could otherwise be left set by a level triggered interrupt
+    /// pending at the time of the reset.
+    pub fn reset(&mut self) -> Result<()> {
+        self.id_reg = 0;
+        self.reg_sel = 0;
+        for irq in 0..NUM_IOAPIC_PINS {
+            self.reg_entries[irq] = 0x10000;
+            // Only entries that have been programmed by the guest need to be
+            // propagated to the interrupt source group.
+            if self.used_entries[irq] {
+                self.update_entry(irq)?;
+            }
+        }
+        self.used_entries = [false; NUM_IOAPIC_PINS];
+
+        Ok(())
+    }
+
     fn ioapic_write(&mut self, val: u32) {
         debug!("IOAPIC_W reg 0x{:x}, val 0x{:x}", self.reg_sel, val);
 
@@ -433,3 +453,140 @@ impl Snapshottable for Ioapic {
 impl Pausable for Ioapic {}
 impl Transportable for Ioapic {}
 impl Migratable for Ioapic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct TestInterruptGroup {
+        triggered: Mutex<Vec<InterruptIndex>>,
+        masked: Mutex<[bool; NUM_IOAPIC_PINS]>,
+    }
+
+    impl InterruptSourceGroup for TestInterruptGroup {
+        fn trigger(&self, index: InterruptIndex) -> io::Result<()> {
+            self.triggered.lock().unwrap().push(index);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(&self, _index: InterruptIndex, _config: InterruptSourceConfig) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn mask(&self, index: InterruptIndex) -> io::Result<()> {
+            self.masked.lock().unwrap()[index as usize] = true;
+            Ok(())
+        }
+
+        fn unmask(&self, index: InterruptIndex) -> io::Result<()> {
+            self.masked.lock().unwrap()[index as usize] = false;
+            Ok(())
+        }
+    }
+
+    struct TestInterruptManager {
+        group: Arc<Box<dyn InterruptSourceGroup>>,
+    }
+
+    impl InterruptManager for TestInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> io::Result<Arc<Box<dyn InterruptSourceGroup>>> {
+            Ok(self.group.clone())
+        }
+
+        fn destroy_group(&self, _group: Arc<Box<dyn InterruptSourceGroup>>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_ioapic() -> Ioapic {
+        let group: Arc<Box<dyn InterruptSourceGroup>> =
+            Arc::new(Box::new(TestInterruptGroup::default()));
+        Ioapic::new(
+            String::from("ioapic"),
+            GuestAddress(0xfee0_0000),
+            Arc::new(TestInterruptManager { group }),
+        )
+        .unwrap()
+    }
+
+    // Redirection table registers of the pin the tests program.
+    const TEST_PIN: usize = 4;
+    const IOAPIC_REG_REDTBL_LOW: u8 = IOWIN_OFF + TEST_PIN as u8 * IOWIN_SCALE;
+    const IOAPIC_REG_REDTBL_HIGH: u8 = IOAPIC_REG_REDTBL_LOW + 1;
+
+    fn write_reg(ioapic: &mut Ioapic, reg: u8, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, u32::from(reg));
+        ioapic.write(0, u64::from(IOREGSEL_OFF), &data);
+        LittleEndian::write_u32(&mut data, value);
+        ioapic.write(0, u64::from(IOWIN_OFF), &data);
+    }
+
+    fn read_reg(ioapic: &mut Ioapic, reg: u8) -> u32 {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, u32::from(reg));
+        ioapic.write(0, u64::from(IOREGSEL_OFF), &data);
+        ioapic.read(0, u64::from(IOWIN_OFF), &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    // Program the test pin as an unmasked, level triggered interrupt on
+    // vector 0x30.
+    fn program_level_irq(ioapic: &mut Ioapic) {
+        write_reg(ioapic, IOAPIC_REG_REDTBL_HIGH, 0);
+        write_reg(ioapic, IOAPIC_REG_REDTBL_LOW, 0x0000_8030);
+    }
+
+    #[test]
+    fn test_reset_clears_remote_irr() {
+        let mut ioapic = create_ioapic();
+        program_level_irq(&mut ioapic);
+
+        ioapic.service_irq(TEST_PIN).unwrap();
+        assert_eq!(remote_irr(ioapic.reg_entries[TEST_PIN]), 1);
+
+        ioapic.reset().unwrap();
+        assert_eq!(remote_irr(ioapic.reg_entries[TEST_PIN]), 0);
+        assert_eq!(interrupt_mask(ioapic.reg_entries[TEST_PIN]), 1);
+        assert_eq!(read_reg(&mut ioapic, IOAPIC_REG_REDTBL_LOW), 0x10000);
+        assert!(!ioapic.used_entries[TEST_PIN]);
+    }
+
+    #[test]
+    fn test_eoi_clears_remote_irr() {
+        let mut ioapic = create_ioapic();
+        program_level_irq(&mut ioapic);
+
+        ioapic.service_irq(TEST_PIN).unwrap();
+        ioapic.end_of_interrupt(0x30);
+        assert_eq!(remote_irr(ioapic.reg_entries[TEST_PIN]), 0);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut ioapic = create_ioapic();
+        program_level_irq(&mut ioapic);
+        write_reg(&mut ioapic, IOAPIC_REG_ID, 0x0300_0000);
+
+        let snapshot = ioapic.snapshot().unwrap();
+
+        let mut restored = create_ioapic();
+        restored.restore(snapshot).unwrap();
+        assert_eq!(restored.reg_entries, ioapic.reg_entries);
+        assert_eq!(restored.used_entries, ioapic.used_entries);
+        assert_eq!(read_reg(&mut restored, IOAPIC_REG_ID), 0x0300_0000);
+        assert_eq!(read_reg(&mut restored, IOAPIC_REG_REDTBL_LOW), 0x0000_8030);
+    }
+}
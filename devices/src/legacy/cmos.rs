@@ -6,6 +6,7 @@ use libc::{clock_gettime, gmtime_r, time_t, timespec, tm, CLOCK_REALTIME};
 use std::cmp::min;
 use std::mem;
 use std::sync::{Arc, Barrier};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use vm_device::BusDevice;
 
 const INDEX_MASK: u8 = 0x7f;
@@ -13,6 +14,20 @@ const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
 
+const SECONDS_ALARM: usize = 0x01;
+const MINUTES_ALARM: usize = 0x03;
+const HOURS_ALARM: usize = 0x05;
+const REG_B: usize = 0x0b;
+const REG_C: usize = 0x0c;
+const REG_B_24H: u8 = 1 << 1;
+const REG_B_BINARY: u8 = 1 << 2;
+const REG_B_AIE: u8 = 1 << 5;
+const REG_C_AF: u8 = 1 << 5;
+const REG_C_IRQF: u8 = 1 << 7;
+// Alarm values with the two upper bits set match any time.
+const ALARM_DONT_CARE: u8 = 0xc0;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
     index: u8,
@@ -42,6 +57,64 @@ impl Cmos {
 
         Cmos { index: 0, data }
     }
+
+    /// Returns the time left before the alarm programmed by the guest
+    /// fires, if the guest enabled the alarm interrupt.
+    pub fn alarm_timeout(&self) -> Option<Duration> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        self.seconds_to_alarm(now % SECONDS_PER_DAY)
+            .map(Duration::from_secs)
+    }
+
+    /// Flags the alarm as the reason the guest has been woken up for.
+    pub fn alarm_fired(&mut self) {
+        self.data[REG_C] |= REG_C_AF | REG_C_IRQF;
+    }
+
+    // The alarm fires at most one day later, as there is no date involved.
+    fn seconds_to_alarm(&self, seconds_of_day: u64) -> Option<u64> {
+        if self.data[REG_B] & REG_B_AIE == 0 {
+            return None;
+        }
+
+        let seconds = self.alarm_value(SECONDS_ALARM);
+        let minutes = self.alarm_value(MINUTES_ALARM);
+        let hours = self.alarm_value(HOURS_ALARM);
+        (1..=SECONDS_PER_DAY).find(|delay| {
+            let time = (seconds_of_day + delay) % SECONDS_PER_DAY;
+            seconds.map_or(true, |s| s == time % 60)
+                && minutes.map_or(true, |m| m == time / 60 % 60)
+                && hours.map_or(true, |h| h == time / 3600)
+        })
+    }
+
+    // Decodes an alarm register according to the data mode and the hour
+    // format selected through register B.
+    fn alarm_value(&self, index: usize) -> Option<u64> {
+        fn from_bcd(v: u8) -> u8 {
+            (v >> 4) * 10 + (v & 0xf)
+        }
+
+        let value = self.data[index];
+        if value & ALARM_DONT_CARE == ALARM_DONT_CARE {
+            return None;
+        }
+
+        let twelve_hours = index == HOURS_ALARM && self.data[REG_B] & REG_B_24H == 0;
+        let pm = twelve_hours && value & 0x80 != 0;
+        let value = if twelve_hours { value & 0x7f } else { value };
+        let value = if self.data[REG_B] & REG_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        };
+
+        if twelve_hours {
+            Some(u64::from(value % 12) + if pm { 12 } else { 0 })
+        } else {
+            Some(u64::from(value))
+        }
+    }
 }
 
 impl BusDevice for Cmos {
@@ -114,6 +187,8 @@ impl BusDevice for Cmos {
                     // Bit 5 for 32kHz clock. Bit 7 for Update in Progress
                     0x0a => 1 << 5 | (update_in_progress as u8) << 7,
                     0x32 => to_bcd(((year + 1900) / 100) as u8),
+                    // Reading register C acknowledges the pending interrupts.
+                    0x0c => std::mem::replace(&mut self.data[REG_C], 0),
                     _ => {
                         // self.index is always guaranteed to be in range via INDEX_MASK.
                         self.data[(self.index & INDEX_MASK) as usize]
@@ -124,3 +199,45 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm() {
+        let mut cmos = Cmos::new(0, 0);
+        cmos.data[SECONDS_ALARM] = 0x00;
+        cmos.data[MINUTES_ALARM] = 0x30;
+        cmos.data[HOURS_ALARM] = 0x12;
+
+        // The alarm is ignored until the guest enables its interrupt.
+        assert_eq!(cmos.seconds_to_alarm(0), None);
+
+        cmos.data[REG_B] = REG_B_AIE | REG_B_24H;
+        let noon = 12 * 3600;
+        assert_eq!(cmos.seconds_to_alarm(noon), Some(30 * 60));
+        // Already past, hence the next day.
+        assert_eq!(cmos.seconds_to_alarm(noon + 30 * 60), Some(SECONDS_PER_DAY));
+
+        // 12 hours format, 0x92 being 12 PM.
+        cmos.data[REG_B] = REG_B_AIE;
+        cmos.data[HOURS_ALARM] = 0x92;
+        assert_eq!(cmos.seconds_to_alarm(noon), Some(30 * 60));
+
+        // Binary mode, every hour.
+        cmos.data[REG_B] = REG_B_AIE | REG_B_24H | REG_B_BINARY;
+        cmos.data[MINUTES_ALARM] = 15;
+        cmos.data[HOURS_ALARM] = ALARM_DONT_CARE;
+        assert_eq!(cmos.seconds_to_alarm(noon + 20 * 60), Some(55 * 60));
+
+        // The alarm flags are cleared once read.
+        cmos.alarm_fired();
+        let mut data = [0u8];
+        cmos.write(0, INDEX_OFFSET, &[REG_C as u8]);
+        cmos.read(0, DATA_OFFSET, &mut data);
+        assert_eq!(data[0], REG_C_AF | REG_C_IRQF);
+        cmos.read(0, DATA_OFFSET, &mut data);
+        assert_eq!(data[0], 0);
+    }
+}
@@ -33,7 +33,9 @@ impl BusDevice for I8042Device {
     }
 
     fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
-        if data.len() == 1 && data[0] == 0xfe && offset == 3 {
+        // The commands 0xf0 to 0xff pulse the output lines whose bits are
+        // clear, the bit 0 being the reset line. Most guests use 0xfe.
+        if data.len() == 1 && data[0] & 0xf1 == 0xf0 && offset == 3 {
             debug!("i8042 reset signalled");
             if let Err(e) = self.reset_evt.write(1) {
                 error!("Error triggering i8042 reset event: {}", e);
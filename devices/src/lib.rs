@@ -20,9 +20,17 @@ pub mod interrupt_controller;
 #[cfg(target_arch = "x86_64")]
 pub mod ioapic;
 pub mod legacy;
+pub mod nvme;
+#[cfg(target_arch = "riscv64")]
+pub mod plic;
+#[cfg(feature = "acpi")]
+pub mod tpm;
+pub mod usb;
+#[cfg(target_arch = "x86_64")]
+pub mod vtd;
 
 #[cfg(feature = "acpi")]
-pub use self::acpi::{AcpiGedDevice, AcpiPmTimerDevice, AcpiShutdownDevice};
+pub use self::acpi::{AcpiGedDevice, AcpiPmTimerDevice, AcpiPowerSupplyDevice, AcpiShutdownDevice};
 
 bitflags! {
     pub struct AcpiNotificationFlags: u8 {
@@ -31,6 +39,8 @@ bitflags! {
         const MEMORY_DEVICES_CHANGED = 0b10;
         const PCI_DEVICES_CHANGED = 0b100;
         const POWER_BUTTON_CHANGED = 0b1000;
+        const POWER_SUPPLY_CHANGED = 0b10000;
+        const SLEEP_BUTTON_CHANGED = 0b100000;
     }
 }
 
@@ -0,0 +1,1524 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulated NVMe controller.
+//!
+//! Exposes a disk image through an NVM Express controller with a single
+//! namespace, for guests shipping NVMe drivers but no virtio ones. The
+//! registers are emulated from the vCPU thread, while the commands are
+//! processed by a dedicated thread, woken up through an eventfd each time
+//! the driver rings a doorbell.
+
+use anyhow::anyhow;
+use block_util::async_io::{AsyncIo, DiskFile, DiskFileError};
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciMassStorageSubclass, PciProgrammingInterface,
+};
+use seccomp::{BpfProgram, SeccompFilter};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_allocator::SystemAllocator;
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_device::BusDevice;
+use vm_memory::{
+    bitmap::AtomicBitmap, bitmap::Bitmap, Address, ByteValued, Bytes, GuestAddress,
+    GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestUsize,
+};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+// Same identifiers as the NVMe controller emulated by QEMU, which guests
+// already know about.
+const NVME_VENDOR_ID: u16 = 0x1b36;
+const NVME_DEVICE_ID: u16 = 0x0010;
+
+// BAR0 layout.
+const NVME_BAR_INDEX: usize = 0;
+const NVME_BAR_SIZE: u64 = 0x4000;
+const DOORBELL_BAR_OFFSET: u64 = 0x1000;
+const DOORBELL_SIZE: u64 = 0x1000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x2000;
+const MSIX_TABLE_SIZE: u64 = 0x1000;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x3000;
+const MSIX_PBA_SIZE: u64 = 0x1000;
+
+// Controller registers.
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+
+const NVME_VERSION_1_2: u32 = 0x0001_0200;
+
+const CAP_CQR: u64 = 1 << 16;
+const CAP_TO_SHIFT: u64 = 24;
+const CAP_CSS_NVM: u64 = 1 << 37;
+// Worst case time to wait for CSTS.RDY to change, in 500ms units.
+const CAP_TIMEOUT: u64 = 0xf;
+
+const CC_EN: u32 = 1;
+const CC_SHN_SHIFT: u32 = 14;
+const CC_SHN_MASK: u32 = 0x3 << CC_SHN_SHIFT;
+
+const CSTS_RDY: u32 = 1;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_COMPLETE: u32 = 0x2 << 2;
+
+// Only 4KiB memory pages are supported (CAP.MPSMIN = CAP.MPSMAX = 0).
+const PAGE_SIZE: u64 = 0x1000;
+// Maximum data transfer size, as a power of two of the page size.
+const MDTS: u8 = 5;
+const MAX_TRANSFER_SIZE: u64 = PAGE_SIZE << MDTS;
+
+const SECTOR_SHIFT: u8 = 9;
+const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+const NAMESPACE_ID: u32 = 1;
+const IDENTIFY_SIZE: usize = 0x1000;
+
+// Admin command set.
+const ADMIN_DELETE_IO_SQ: u8 = 0x00;
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_DELETE_IO_CQ: u8 = 0x04;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+const ADMIN_GET_FEATURES: u8 = 0x0a;
+const ADMIN_ASYNC_EVENT_REQUEST: u8 = 0x0c;
+const ADMIN_KEEP_ALIVE: u8 = 0x18;
+
+const IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+const IDENTIFY_CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+const IDENTIFY_CNS_NAMESPACE_DESCRIPTORS: u32 = 0x03;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+// NVM command set.
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+// Generic command status.
+const STATUS_SUCCESS: u16 = 0x00;
+const STATUS_INVALID_OPCODE: u16 = 0x01;
+const STATUS_INVALID_FIELD: u16 = 0x02;
+const STATUS_DATA_TRANSFER_ERROR: u16 = 0x04;
+const STATUS_INVALID_NAMESPACE: u16 = 0x0b;
+const STATUS_NAMESPACE_WRITE_PROTECTED: u16 = 0x20;
+const STATUS_LBA_OUT_OF_RANGE: u16 = 0x80;
+// Command specific status, with the status code type set to 1.
+const STATUS_COMPLETION_QUEUE_INVALID: u16 = 0x100;
+const STATUS_INVALID_QUEUE_IDENTIFIER: u16 = 0x101;
+const STATUS_INVALID_QUEUE_SIZE: u16 = 0x102;
+const STATUS_INVALID_INTERRUPT_VECTOR: u16 = 0x108;
+const STATUS_INVALID_QUEUE_DELETION: u16 = 0x10c;
+
+// Worker thread epoll tokens.
+const KICK_EVENT: u64 = 0;
+const KILL_EVENT: u64 = 1;
+const COMPLETION_EVENT: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed creating the MSI-X interrupt group.
+    CreateInterruptGroup(io::Error),
+    /// Failed adding the MSI-X capability.
+    CapabilitiesSetup(PciDeviceError),
+    /// Failed retrieving the disk size.
+    DiskSize(DiskFileError),
+    /// Failed creating the asynchronous I/O context.
+    CreateAsyncIo(DiskFileError),
+    /// Failed creating an eventfd.
+    EventFd(io::Error),
+    /// Failed spawning the worker thread.
+    SpawnWorker(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            CreateInterruptGroup(e) => write!(f, "failed creating interrupt group: {}", e),
+            CapabilitiesSetup(e) => write!(f, "failed setting up PCI capabilities: {}", e),
+            DiskSize(e) => write!(f, "failed getting disk size: {}", e),
+            CreateAsyncIo(e) => write!(f, "failed creating async I/O context: {}", e),
+            EventFd(e) => write!(f, "failed creating eventfd: {}", e),
+            SpawnWorker(e) => write!(f, "failed spawning worker thread: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+struct NvmeProgrammingInterface;
+
+impl PciProgrammingInterface for NvmeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        // NVM Express
+        0x02
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct SubmissionEntry {
+    opcode: u8,
+    flags: u8,
+    cid: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for SubmissionEntry {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct CompletionEntry {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for CompletionEntry {}
+
+// Requests sent to the worker when the driver updates the controller
+// configuration, or when the VM is paused, resumed or restored.
+enum ControllerRequest {
+    Enable { asq: u64, acq: u64, aqa: u32 },
+    Disable,
+    Shutdown,
+    // Stops processing the queues once the requests in flight completed,
+    // replying with the state of the queues.
+    Pause(mpsc::Sender<NvmeQueuesState>),
+    Resume,
+    Restore(NvmeQueuesState),
+}
+
+#[derive(Clone, Versionize)]
+struct SubmissionQueueState {
+    addr: u64,
+    size: u16,
+    head: u16,
+    cqid: u16,
+}
+
+#[derive(Clone, Versionize)]
+struct CompletionEntryState {
+    result: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+
+#[derive(Clone, Versionize)]
+struct CompletionQueueState {
+    addr: u64,
+    size: u16,
+    tail: u16,
+    phase: bool,
+    vector: Option<u16>,
+    pending: Vec<CompletionEntryState>,
+}
+
+#[derive(Clone, Versionize)]
+struct NvmeQueuesState {
+    sqs: Vec<Option<SubmissionQueueState>>,
+    cqs: Vec<Option<CompletionQueueState>>,
+}
+
+#[derive(Versionize)]
+pub struct NvmeControllerState {
+    cc: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    csts: u32,
+    doorbells: Vec<u32>,
+    queues: NvmeQueuesState,
+}
+
+impl VersionMapped for NvmeControllerState {}
+
+/// NVMe controller exposed on the PCI bus.
+pub struct NvmeController {
+    id: String,
+    configuration: PciConfiguration,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    bar_regions: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
+    cap: u64,
+    cc: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    csts: Arc<AtomicU32>,
+    doorbells: Arc<Vec<AtomicU32>>,
+    requests: mpsc::Sender<ControllerRequest>,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+    // State of the queues, known while the controller is paused.
+    paused_queues: Option<NvmeQueuesState>,
+}
+
+impl NvmeController {
+    /// Creates a controller exposing `disk` through `num_queues` I/O queues
+    /// of up to `queue_size` entries each.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        mut disk: Box<dyn DiskFile>,
+        readonly: bool,
+        num_queues: u16,
+        queue_size: u16,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        pci_device_bdf: u32,
+        seccomp_filter: BpfProgram,
+    ) -> Result<Self> {
+        let num_queues = std::cmp::max(num_queues, 1);
+        let disk_nsectors = disk.size().map_err(Error::DiskSize)? >> SECTOR_SHIFT;
+        // The disk I/O context is created right away rather than when the
+        // driver enables the controller, as this happens from a vCPU thread.
+        let disk_io = disk
+            .new_async_io(queue_size as u32)
+            .map_err(Error::CreateAsyncIo)?;
+
+        // One vector for the admin queue, and one for each I/O queue.
+        let msix_num = num_queues + 1;
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_num as InterruptIndex,
+            })
+            .map_err(Error::CreateInterruptGroup)?;
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            msix_num,
+            interrupt_source_group.clone(),
+            pci_device_bdf,
+        )));
+
+        let mut configuration = PciConfiguration::new(
+            NVME_VENDOR_ID,
+            NVME_DEVICE_ID,
+            0,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::NvmController,
+            Some(&NvmeProgrammingInterface),
+            PciHeaderType::Device,
+            NVME_VENDOR_ID,
+            NVME_DEVICE_ID,
+            Some(msix_config.clone()),
+        );
+        let msix_cap = MsixCap::new(
+            NVME_BAR_INDEX as u8,
+            msix_num,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            NVME_BAR_INDEX as u8,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+        configuration
+            .add_capability(&msix_cap)
+            .map_err(|e| Error::CapabilitiesSetup(PciDeviceError::CapabilitiesSetup(e)))?;
+
+        let cap = (queue_size as u64 - 1) | CAP_CQR | CAP_TIMEOUT << CAP_TO_SHIFT | CAP_CSS_NVM;
+        let csts = Arc::new(AtomicU32::new(0));
+        // Tail doorbell of each submission queue followed by the head
+        // doorbell of the matching completion queue.
+        let doorbells = Arc::new(
+            (0..2 * (num_queues as usize + 1))
+                .map(|_| AtomicU32::new(0))
+                .collect::<Vec<AtomicU32>>(),
+        );
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let (requests, receiver) = mpsc::channel();
+
+        let mut worker = NvmeWorker {
+            id: id.clone(),
+            memory,
+            disk_io,
+            disk_nsectors,
+            readonly,
+            num_queues,
+            queue_size,
+            msix_config: msix_config.clone(),
+            interrupt_source_group,
+            csts: csts.clone(),
+            doorbells: doorbells.clone(),
+            requests: receiver,
+            kick_evt: kick_evt.try_clone().map_err(Error::EventFd)?,
+            kill_evt: kill_evt.try_clone().map_err(Error::EventFd)?,
+            sqs: (0..=num_queues).map(|_| None).collect(),
+            cqs: (0..=num_queues).map(|_| None).collect(),
+            inflight: HashMap::new(),
+            next_user_data: 0,
+            paused: false,
+        };
+        let thread = thread::Builder::new()
+            .name(format!("{}_nvme", id))
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+                if let Err(e) = worker.run() {
+                    error!("Error running NVMe worker: {:?}", e);
+                }
+            })
+            .map_err(Error::SpawnWorker)?;
+
+        Ok(NvmeController {
+            id,
+            configuration,
+            msix_config,
+            bar_regions: Vec::new(),
+            cap,
+            cc: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            csts,
+            doorbells,
+            requests,
+            kick_evt,
+            kill_evt,
+            worker: Some(thread),
+            paused_queues: None,
+        })
+    }
+
+    fn state(&self) -> result::Result<NvmeControllerState, MigratableError> {
+        let queues = self.paused_queues.clone().ok_or_else(|| {
+            MigratableError::Snapshot(anyhow!("NVMe controller {} is not paused", self.id))
+        })?;
+
+        Ok(NvmeControllerState {
+            cc: self.cc,
+            aqa: self.aqa,
+            asq: self.asq,
+            acq: self.acq,
+            csts: self.csts.load(Ordering::Acquire),
+            doorbells: self
+                .doorbells
+                .iter()
+                .map(|doorbell| doorbell.load(Ordering::Acquire))
+                .collect(),
+            queues,
+        })
+    }
+
+    fn set_state(&mut self, state: NvmeControllerState) -> result::Result<(), MigratableError> {
+        if state.doorbells.len() != self.doorbells.len()
+            || state.queues.sqs.len() != self.doorbells.len() / 2
+            || state.queues.cqs.len() != self.doorbells.len() / 2
+        {
+            return Err(MigratableError::Restore(anyhow!(
+                "NVMe controller {} has a different number of queues",
+                self.id
+            )));
+        }
+
+        self.cc = state.cc;
+        self.aqa = state.aqa;
+        self.asq = state.asq;
+        self.acq = state.acq;
+        self.csts.store(state.csts, Ordering::Release);
+        for (doorbell, value) in self.doorbells.iter().zip(state.doorbells) {
+            doorbell.store(value, Ordering::Release);
+        }
+        self.paused_queues = Some(state.queues.clone());
+        self.send_request(ControllerRequest::Restore(state.queues));
+
+        Ok(())
+    }
+
+    fn send_request(&self, request: ControllerRequest) {
+        if self.requests.send(request).is_err() {
+            error!("{}: NVMe worker is not running", self.id);
+            return;
+        }
+        self.kick_evt.write(1).ok();
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            REG_CAP => self.cap as u32,
+            o if o == REG_CAP + 4 => (self.cap >> 32) as u32,
+            REG_VS => NVME_VERSION_1_2,
+            REG_CC => self.cc,
+            REG_CSTS => self.csts.load(Ordering::Acquire),
+            REG_AQA => self.aqa,
+            REG_ASQ => self.asq as u32,
+            o if o == REG_ASQ + 4 => (self.asq >> 32) as u32,
+            REG_ACQ => self.acq as u32,
+            o if o == REG_ACQ + 4 => (self.acq >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            REG_CC => self.write_cc(value),
+            REG_AQA => self.aqa = value,
+            REG_ASQ => self.asq = (self.asq & !0xffff_ffff) | value as u64,
+            o if o == REG_ASQ + 4 => self.asq = (self.asq & 0xffff_ffff) | (value as u64) << 32,
+            REG_ACQ => self.acq = (self.acq & !0xffff_ffff) | value as u64,
+            o if o == REG_ACQ + 4 => self.acq = (self.acq & 0xffff_ffff) | (value as u64) << 32,
+            _ => debug!(
+                "{}: Ignoring write to NVMe register 0x{:x}",
+                self.id, offset
+            ),
+        }
+    }
+
+    fn write_cc(&mut self, value: u32) {
+        let old = self.cc;
+        self.cc = value;
+
+        if old & CC_EN == 0 && value & CC_EN != 0 {
+            self.send_request(ControllerRequest::Enable {
+                asq: self.asq,
+                acq: self.acq,
+                aqa: self.aqa,
+            });
+        } else if old & CC_EN != 0 && value & CC_EN == 0 {
+            self.send_request(ControllerRequest::Disable);
+        }
+
+        if old & CC_SHN_MASK == 0 && value & CC_SHN_MASK != 0 {
+            self.send_request(ControllerRequest::Shutdown);
+        }
+    }
+
+    fn write_doorbell(&mut self, offset: u64, value: u32) {
+        match self.doorbells.get((offset / 4) as usize) {
+            Some(doorbell) => {
+                doorbell.store(value, Ordering::Release);
+                self.kick_evt.write(1).ok();
+            }
+            None => warn!("{}: Invalid NVMe doorbell 0x{:x}", self.id, offset),
+        }
+    }
+}
+
+impl Drop for NvmeController {
+    fn drop(&mut self) {
+        self.kill_evt.write(1).ok();
+        if let Some(worker) = self.worker.take() {
+            if let Err(e) = worker.join() {
+                error!("Error joining NVMe worker: {:?}", e);
+            }
+        }
+    }
+}
+
+impl PciDevice for NvmeController {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError> {
+        // Like virtio-block, a 32-bit BAR keeps the disk easily reachable
+        // from the firmware.
+        let region_type = PciBarRegionType::Memory32BitRegion;
+        let addr = allocator
+            .allocate_mmio_hole_addresses(None, NVME_BAR_SIZE, Some(NVME_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(NVME_BAR_SIZE))?;
+
+        let config = PciBarConfiguration::default()
+            .set_register_index(NVME_BAR_INDEX)
+            .set_address(addr.raw_value())
+            .set_size(NVME_BAR_SIZE)
+            .set_region_type(region_type);
+        self.configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?;
+
+        self.bar_regions.push((addr, NVME_BAR_SIZE, region_type));
+        Ok(self.bar_regions.clone())
+    }
+
+    fn free_bars(&mut self, allocator: &mut SystemAllocator) -> result::Result<(), PciDeviceError> {
+        for (addr, length, _) in self.bar_regions.drain(..) {
+            allocator.free_mmio_hole_addresses(addr, length);
+        }
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), io::Error> {
+        for (addr, _, _) in self.bar_regions.iter_mut() {
+            if addr.raw_value() == old_base {
+                *addr = GuestAddress(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < DOORBELL_BAR_OFFSET => {
+                if o % 4 != 0 || (data.len() != 4 && data.len() != 8) {
+                    warn!(
+                        "{}: Invalid NVMe register read: offset 0x{:x} length {}",
+                        self.id,
+                        o,
+                        data.len()
+                    );
+                    return;
+                }
+                for (i, chunk) in data.chunks_mut(4).enumerate() {
+                    chunk.copy_from_slice(&self.read_register(o + 4 * i as u64).to_le_bytes());
+                }
+            }
+            o if o < DOORBELL_BAR_OFFSET + DOORBELL_SIZE => {
+                // Doorbells are write-only.
+            }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
+            _ => (),
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match offset {
+            o if o < DOORBELL_BAR_OFFSET + DOORBELL_SIZE => {
+                if o % 4 != 0 || (data.len() != 4 && data.len() != 8) {
+                    warn!(
+                        "{}: Invalid NVMe register write: offset 0x{:x} length {}",
+                        self.id,
+                        o,
+                        data.len()
+                    );
+                    return None;
+                }
+                for (i, chunk) in data.chunks(4).enumerate() {
+                    let offset = o + 4 * i as u64;
+                    let mut value = [0u8; 4];
+                    value.copy_from_slice(chunk);
+                    let value = u32::from_le_bytes(value);
+                    if offset < DOORBELL_BAR_OFFSET {
+                        self.write_register(offset, value);
+                    } else {
+                        self.write_doorbell(offset - DOORBELL_BAR_OFFSET, value);
+                    }
+                }
+            }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
+            _ => (),
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl BusDevice for NvmeController {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl Pausable for NvmeController {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        if self.paused_queues.is_some() {
+            return Ok(());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        self.send_request(ControllerRequest::Pause(sender));
+        let queues = receiver.recv().map_err(|_| {
+            MigratableError::Pause(anyhow!("NVMe worker of {} is not running", self.id))
+        })?;
+        self.paused_queues = Some(queues);
+
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if self.paused_queues.take().is_some() {
+            self.send_request(ControllerRequest::Resume);
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshottable for NvmeController {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new_from_versioned_state(&self.id, &self.state()?)?;
+        snapshot.add_snapshot(self.configuration.snapshot()?);
+        snapshot.add_snapshot(self.msix_config.lock().unwrap().snapshot()?);
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let msix_id = self.msix_config.lock().unwrap().id();
+        if let Some(msix_snapshot) = snapshot.snapshots.get(&msix_id) {
+            self.msix_config
+                .lock()
+                .unwrap()
+                .restore(*msix_snapshot.clone())?;
+        }
+        if let Some(pci_config_snapshot) = snapshot.snapshots.get(&self.configuration.id()) {
+            self.configuration.restore(*pci_config_snapshot.clone())?;
+        }
+
+        self.set_state(snapshot.to_versioned_state(&self.id)?)
+    }
+}
+
+impl Transportable for NvmeController {}
+impl Migratable for NvmeController {}
+
+struct SubmissionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    cqid: u16,
+}
+
+struct CompletionQueue {
+    addr: GuestAddress,
+    size: u16,
+    tail: u16,
+    phase: bool,
+    vector: Option<u16>,
+    // Completions waiting for the driver to free some entries.
+    pending: VecDeque<CompletionEntry>,
+}
+
+struct NvmeWorker {
+    id: String,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    disk_io: Box<dyn AsyncIo>,
+    disk_nsectors: u64,
+    readonly: bool,
+    num_queues: u16,
+    queue_size: u16,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    csts: Arc<AtomicU32>,
+    doorbells: Arc<Vec<AtomicU32>>,
+    requests: mpsc::Receiver<ControllerRequest>,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+    // Submission queue and command identifier of the requests submitted to
+    // the disk, indexed by their user data.
+    inflight: HashMap<u64, (u16, u16)>,
+    next_user_data: u64,
+    // The queues aren't processed while the VM is paused.
+    paused: bool,
+}
+
+impl NvmeWorker {
+    fn run(&mut self) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        for (fd, token) in &[
+            (self.kick_evt.as_raw_fd(), KICK_EVENT),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.disk_io.notifier().as_raw_fd(), COMPLETION_EVENT),
+        ] {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, *token),
+            )?;
+        }
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 3];
+        loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    KICK_EVENT => {
+                        self.kick_evt.read().ok();
+                        self.process_requests();
+                        if !self.paused {
+                            self.process_queues();
+                        }
+                    }
+                    KILL_EVENT => return Ok(()),
+                    COMPLETION_EVENT => {
+                        self.disk_io.notifier().read().ok();
+                        self.process_completions();
+                    }
+                    _ => error!("{}: Unknown NVMe worker event", self.id),
+                }
+            }
+        }
+    }
+
+    fn process_requests(&mut self) {
+        while let Ok(request) = self.requests.try_recv() {
+            match request {
+                ControllerRequest::Enable { asq, acq, aqa } => self.enable(asq, acq, aqa),
+                ControllerRequest::Disable => self.disable(),
+                ControllerRequest::Shutdown => {
+                    if let Err(e) = self.disk_io.fsync(None) {
+                        error!("{}: Failed flushing disk on shutdown: {}", self.id, e);
+                    }
+                    self.csts.fetch_or(CSTS_SHST_COMPLETE, Ordering::AcqRel);
+                }
+                ControllerRequest::Pause(reply) => {
+                    if let Err(e) = self.drain() {
+                        error!("{}: Failed waiting for NVMe requests: {}", self.id, e);
+                    }
+                    self.paused = true;
+                    reply.send(self.queues_state()).ok();
+                }
+                ControllerRequest::Resume => self.paused = false,
+                ControllerRequest::Restore(state) => self.set_queues_state(state),
+            }
+        }
+    }
+
+    // Waits for the requests in flight to complete, posting their
+    // completions to the guest.
+    fn drain(&mut self) -> io::Result<()> {
+        if self.inflight.is_empty() {
+            return Ok(());
+        }
+
+        let epoll_fd = epoll::create(true)?;
+        let result = epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.disk_io.notifier().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, COMPLETION_EVENT),
+        )
+        .and_then(|_| {
+            let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 1];
+            while !self.inflight.is_empty() {
+                match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+                self.disk_io.notifier().read().ok();
+                self.process_completions();
+            }
+            Ok(())
+        });
+        // Safe because the fd is owned by this function.
+        unsafe { libc::close(epoll_fd) };
+
+        result
+    }
+
+    fn queues_state(&self) -> NvmeQueuesState {
+        NvmeQueuesState {
+            sqs: self
+                .sqs
+                .iter()
+                .map(|sq| {
+                    sq.as_ref().map(|sq| SubmissionQueueState {
+                        addr: sq.addr.raw_value(),
+                        size: sq.size,
+                        head: sq.head,
+                        cqid: sq.cqid,
+                    })
+                })
+                .collect(),
+            cqs: self
+                .cqs
+                .iter()
+                .map(|cq| {
+                    cq.as_ref().map(|cq| CompletionQueueState {
+                        addr: cq.addr.raw_value(),
+                        size: cq.size,
+                        tail: cq.tail,
+                        phase: cq.phase,
+                        vector: cq.vector,
+                        pending: cq
+                            .pending
+                            .iter()
+                            .map(|entry| CompletionEntryState {
+                                result: entry.result,
+                                sq_head: entry.sq_head,
+                                sq_id: entry.sq_id,
+                                cid: entry.cid,
+                                status: entry.status,
+                            })
+                            .collect(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn set_queues_state(&mut self, state: NvmeQueuesState) {
+        self.sqs = state
+            .sqs
+            .into_iter()
+            .map(|sq| {
+                sq.map(|sq| SubmissionQueue {
+                    addr: GuestAddress(sq.addr),
+                    size: sq.size,
+                    head: sq.head,
+                    cqid: sq.cqid,
+                })
+            })
+            .collect();
+        self.cqs = state
+            .cqs
+            .into_iter()
+            .map(|cq| {
+                cq.map(|cq| CompletionQueue {
+                    addr: GuestAddress(cq.addr),
+                    size: cq.size,
+                    tail: cq.tail,
+                    phase: cq.phase,
+                    vector: cq.vector,
+                    pending: cq
+                        .pending
+                        .into_iter()
+                        .map(|entry| CompletionEntry {
+                            result: entry.result,
+                            sq_head: entry.sq_head,
+                            sq_id: entry.sq_id,
+                            cid: entry.cid,
+                            status: entry.status,
+                            ..Default::default()
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+        self.inflight.clear();
+    }
+
+    fn enable(&mut self, asq: u64, acq: u64, aqa: u32) {
+        let sq_size = (aqa & 0xfff) as u16 + 1;
+        let cq_size = ((aqa >> 16) & 0xfff) as u16 + 1;
+        if sq_size < 2 || cq_size < 2 || asq % PAGE_SIZE != 0 || acq % PAGE_SIZE != 0 {
+            error!("{}: Invalid NVMe admin queues configuration", self.id);
+            self.csts.store(CSTS_CFS, Ordering::Release);
+            return;
+        }
+
+        self.sqs[0] = Some(SubmissionQueue {
+            addr: GuestAddress(asq),
+            size: sq_size,
+            head: 0,
+            cqid: 0,
+        });
+        self.cqs[0] = Some(CompletionQueue::new(GuestAddress(acq), cq_size, Some(0)));
+        self.csts.store(CSTS_RDY, Ordering::Release);
+    }
+
+    fn disable(&mut self) {
+        self.sqs.iter_mut().for_each(|sq| *sq = None);
+        self.cqs.iter_mut().for_each(|cq| *cq = None);
+        // Requests still being processed by the disk are completed once the
+        // controller is reset, their completion must be dropped.
+        self.inflight.clear();
+        for doorbell in self.doorbells.iter() {
+            doorbell.store(0, Ordering::Release);
+        }
+        self.csts.store(0, Ordering::Release);
+    }
+
+    fn process_queues(&mut self) {
+        // Some completions might have been waiting for the driver to free
+        // entries from their completion queue.
+        for cqid in 0..self.cqs.len() {
+            self.flush_completions(cqid as u16);
+        }
+
+        for sqid in 0..self.sqs.len() {
+            let sqid = sqid as u16;
+            while let Some(cmd) = self.next_command(sqid) {
+                let result = if sqid == 0 {
+                    self.admin_command(&cmd)
+                } else {
+                    self.io_command(sqid, &cmd)
+                };
+                if let Some((status, result)) = result {
+                    self.complete(sqid, cmd.cid, status, result);
+                }
+            }
+        }
+    }
+
+    fn next_command(&mut self, sqid: u16) -> Option<SubmissionEntry> {
+        let tail = self.doorbells[2 * sqid as usize].load(Ordering::Acquire) as u16;
+        let sq = self.sqs[sqid as usize].as_mut()?;
+        if sq.head == tail {
+            return None;
+        }
+        if tail >= sq.size {
+            warn!("{}: Invalid NVMe SQ{} tail {}", self.id, sqid, tail);
+            return None;
+        }
+
+        let addr = sq.addr.unchecked_add(sq.head as u64 * 64);
+        sq.head = (sq.head + 1) % sq.size;
+        match self.memory.memory().read_obj::<SubmissionEntry>(addr) {
+            Ok(cmd) => Some(cmd),
+            Err(e) => {
+                error!("{}: Failed reading NVMe command: {}", self.id, e);
+                None
+            }
+        }
+    }
+
+    fn complete(&mut self, sqid: u16, cid: u16, status: u16, result: u32) {
+        let (sq_head, cqid) = match &self.sqs[sqid as usize] {
+            Some(sq) => (sq.head, sq.cqid),
+            None => return,
+        };
+        if let Some(cq) = self.cqs[cqid as usize].as_mut() {
+            cq.pending.push_back(CompletionEntry {
+                result,
+                sq_head,
+                sq_id: sqid,
+                cid,
+                status: status << 1,
+                ..Default::default()
+            });
+        }
+        self.flush_completions(cqid);
+    }
+
+    fn flush_completions(&mut self, cqid: u16) {
+        let head = self.doorbells[2 * cqid as usize + 1].load(Ordering::Acquire) as u16;
+        let mem = self.memory.memory();
+        let cq = match self.cqs[cqid as usize].as_mut() {
+            Some(cq) => cq,
+            None => return,
+        };
+
+        let mut posted = false;
+        while (cq.tail + 1) % cq.size != head {
+            let mut entry = match cq.pending.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            entry.status |= cq.phase as u16;
+            let addr = cq.addr.unchecked_add(cq.tail as u64 * 16);
+            if let Err(e) = mem.write_obj(entry, addr) {
+                error!("{}: Failed writing NVMe completion: {}", self.id, e);
+            }
+            cq.tail += 1;
+            if cq.tail == cq.size {
+                cq.tail = 0;
+                cq.phase = !cq.phase;
+            }
+            posted = true;
+        }
+
+        if posted {
+            if let Some(vector) = cq.vector {
+                self.signal(vector);
+            }
+        }
+    }
+
+    fn signal(&self, vector: u16) {
+        let mut config = self.msix_config.lock().unwrap();
+        if !config.enabled() {
+            return;
+        }
+        // A masked vector must not be injected, its pending bit is set
+        // instead.
+        if config.masked() || config.table_entries[vector as usize].masked() {
+            config.set_pba_bit(vector, false);
+            return;
+        }
+
+        if let Err(e) = self
+            .interrupt_source_group
+            .trigger(vector as InterruptIndex)
+        {
+            error!("{}: Failed signalling NVMe interrupt: {}", self.id, e);
+        }
+    }
+
+    fn process_completions(&mut self) {
+        for (user_data, result) in self.disk_io.complete() {
+            let (sqid, cid) = match self.inflight.remove(&user_data) {
+                Some(request) => request,
+                None => continue,
+            };
+            let status = if result < 0 {
+                error!(
+                    "{}: NVMe request failed: {}",
+                    self.id,
+                    io::Error::from_raw_os_error(-result)
+                );
+                STATUS_DATA_TRANSFER_ERROR
+            } else {
+                STATUS_SUCCESS
+            };
+            self.complete(sqid, cid, status, 0);
+        }
+    }
+
+    // Returns None when the command doesn't complete right away.
+    fn admin_command(&mut self, cmd: &SubmissionEntry) -> Option<(u16, u32)> {
+        let status = match cmd.opcode {
+            ADMIN_IDENTIFY => self.identify(cmd),
+            ADMIN_CREATE_IO_CQ => self.create_io_cq(cmd),
+            ADMIN_CREATE_IO_SQ => self.create_io_sq(cmd),
+            ADMIN_DELETE_IO_SQ => self.delete_io_sq(cmd),
+            ADMIN_DELETE_IO_CQ => self.delete_io_cq(cmd),
+            ADMIN_GET_LOG_PAGE => {
+                // No log page is supported, report them empty.
+                let numd = ((cmd.cdw10 >> 16) & 0xffff | (cmd.cdw11 & 0xffff) << 16) as u64 + 1;
+                let len = std::cmp::min(numd * 4, MAX_TRANSFER_SIZE) as usize;
+                self.write_data(cmd, &vec![0u8; len])
+            }
+            ADMIN_SET_FEATURES | ADMIN_GET_FEATURES => {
+                if cmd.cdw10 & 0xff == FEATURE_NUMBER_OF_QUEUES {
+                    let allocated = (self.num_queues - 1) as u32;
+                    return Some((STATUS_SUCCESS, allocated | allocated << 16));
+                }
+                // Other features are accepted but have no effect.
+                STATUS_SUCCESS
+            }
+            ADMIN_ABORT => {
+                // Commands are never aborted.
+                return Some((STATUS_SUCCESS, 1));
+            }
+            // There is no asynchronous event to report, hence the request is
+            // left pending until the controller is reset.
+            ADMIN_ASYNC_EVENT_REQUEST => return None,
+            ADMIN_KEEP_ALIVE => STATUS_SUCCESS,
+            opcode => {
+                debug!("{}: Unsupported NVMe admin command 0x{:x}", self.id, opcode);
+                STATUS_INVALID_OPCODE
+            }
+        };
+
+        Some((status, 0))
+    }
+
+    fn identify(&self, cmd: &SubmissionEntry) -> u16 {
+        let mut data = vec![0u8; IDENTIFY_SIZE];
+        match cmd.cdw10 & 0xff {
+            IDENTIFY_CNS_CONTROLLER => {
+                data[0..2].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+                data[2..4].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+                copy_padded(&mut data[4..24], self.id.as_bytes());
+                copy_padded(&mut data[24..64], b"Cloud Hypervisor NVMe disk");
+                copy_padded(&mut data[64..72], b"1.0");
+                // Recommended arbitration burst.
+                data[72] = 6;
+                data[77] = MDTS;
+                data[80..84].copy_from_slice(&NVME_VERSION_1_2.to_le_bytes());
+                // Abort command limit and asynchronous event request limit.
+                data[258] = 3;
+                data[259] = 3;
+                // Submission and completion queue entry sizes.
+                data[512] = 0x66;
+                data[513] = 0x44;
+                data[516..520].copy_from_slice(&1u32.to_le_bytes());
+                // Volatile write cache present, relying on flush commands.
+                data[525] = 1;
+            }
+            IDENTIFY_CNS_NAMESPACE => {
+                if cmd.nsid != NAMESPACE_ID {
+                    return STATUS_INVALID_NAMESPACE;
+                }
+                // Namespace size, capacity and utilization.
+                for i in 0..3 {
+                    data[i * 8..(i + 1) * 8].copy_from_slice(&self.disk_nsectors.to_le_bytes());
+                }
+                // Write protected namespace.
+                data[99] = self.readonly as u8;
+                // Single LBA format with 512 bytes sectors.
+                data[128..132].copy_from_slice(&((SECTOR_SHIFT as u32) << 16).to_le_bytes());
+            }
+            IDENTIFY_CNS_ACTIVE_NAMESPACES => {
+                if cmd.nsid < NAMESPACE_ID {
+                    data[0..4].copy_from_slice(&NAMESPACE_ID.to_le_bytes());
+                }
+            }
+            IDENTIFY_CNS_NAMESPACE_DESCRIPTORS => {
+                if cmd.nsid != NAMESPACE_ID {
+                    return STATUS_INVALID_NAMESPACE;
+                }
+            }
+            _ => return STATUS_INVALID_FIELD,
+        }
+
+        self.write_data(cmd, &data)
+    }
+
+    fn create_io_cq(&mut self, cmd: &SubmissionEntry) -> u16 {
+        let qid = (cmd.cdw10 & 0xffff) as u16;
+        let size = (cmd.cdw10 >> 16) as u16;
+        let vector = (cmd.cdw11 >> 16) as u16;
+        if qid == 0 || qid > self.num_queues || self.cqs[qid as usize].is_some() {
+            return STATUS_INVALID_QUEUE_IDENTIFIER;
+        }
+        if size == 0 || size >= self.queue_size {
+            return STATUS_INVALID_QUEUE_SIZE;
+        }
+        // Physically contiguous queues are required.
+        if cmd.cdw11 & 0x1 == 0 || cmd.prp1 % PAGE_SIZE != 0 {
+            return STATUS_INVALID_FIELD;
+        }
+        if vector > self.num_queues {
+            return STATUS_INVALID_INTERRUPT_VECTOR;
+        }
+
+        let vector = if cmd.cdw11 & 0x2 != 0 {
+            Some(vector)
+        } else {
+            None
+        };
+        self.cqs[qid as usize] = Some(CompletionQueue::new(
+            GuestAddress(cmd.prp1),
+            size + 1,
+            vector,
+        ));
+        STATUS_SUCCESS
+    }
+
+    fn create_io_sq(&mut self, cmd: &SubmissionEntry) -> u16 {
+        let qid = (cmd.cdw10 & 0xffff) as u16;
+        let size = (cmd.cdw10 >> 16) as u16;
+        let cqid = (cmd.cdw11 >> 16) as u16;
+        if qid == 0 || qid > self.num_queues || self.sqs[qid as usize].is_some() {
+            return STATUS_INVALID_QUEUE_IDENTIFIER;
+        }
+        if cqid == 0 || cqid > self.num_queues || self.cqs[cqid as usize].is_none() {
+            return STATUS_COMPLETION_QUEUE_INVALID;
+        }
+        if size == 0 || size >= self.queue_size {
+            return STATUS_INVALID_QUEUE_SIZE;
+        }
+        if cmd.cdw11 & 0x1 == 0 || cmd.prp1 % PAGE_SIZE != 0 {
+            return STATUS_INVALID_FIELD;
+        }
+
+        self.doorbells[2 * qid as usize].store(0, Ordering::Release);
+        self.sqs[qid as usize] = Some(SubmissionQueue {
+            addr: GuestAddress(cmd.prp1),
+            size: size + 1,
+            head: 0,
+            cqid,
+        });
+        STATUS_SUCCESS
+    }
+
+    fn delete_io_sq(&mut self, cmd: &SubmissionEntry) -> u16 {
+        let qid = (cmd.cdw10 & 0xffff) as u16;
+        if qid == 0 || qid > self.num_queues || self.sqs[qid as usize].is_none() {
+            return STATUS_INVALID_QUEUE_IDENTIFIER;
+        }
+
+        self.sqs[qid as usize] = None;
+        self.inflight.retain(|_, (sqid, _)| *sqid != qid);
+        STATUS_SUCCESS
+    }
+
+    fn delete_io_cq(&mut self, cmd: &SubmissionEntry) -> u16 {
+        let qid = (cmd.cdw10 & 0xffff) as u16;
+        if qid == 0 || qid > self.num_queues || self.cqs[qid as usize].is_none() {
+            return STATUS_INVALID_QUEUE_IDENTIFIER;
+        }
+        if self.sqs.iter().flatten().any(|sq| sq.cqid == qid) {
+            return STATUS_INVALID_QUEUE_DELETION;
+        }
+
+        self.cqs[qid as usize] = None;
+        self.doorbells[2 * qid as usize + 1].store(0, Ordering::Release);
+        STATUS_SUCCESS
+    }
+
+    // Returns None when the command has been submitted to the disk.
+    fn io_command(&mut self, sqid: u16, cmd: &SubmissionEntry) -> Option<(u16, u32)> {
+        if cmd.nsid != NAMESPACE_ID {
+            return Some((STATUS_INVALID_NAMESPACE, 0));
+        }
+
+        let user_data = self.next_user_data;
+        let result = match cmd.opcode {
+            IO_FLUSH => self.disk_io.fsync(Some(user_data)),
+            IO_READ | IO_WRITE => {
+                let sector = cmd.cdw10 as u64 | (cmd.cdw11 as u64) << 32;
+                let nsectors = (cmd.cdw12 & 0xffff) as u64 + 1;
+                if sector
+                    .checked_add(nsectors)
+                    .map_or(true, |end| end > self.disk_nsectors)
+                {
+                    return Some((STATUS_LBA_OUT_OF_RANGE, 0));
+                }
+                if cmd.opcode == IO_WRITE && self.readonly {
+                    return Some((STATUS_NAMESPACE_WRITE_PROTECTED, 0));
+                }
+
+                let len = nsectors * SECTOR_SIZE;
+                let iovecs = match self.iovecs(cmd, len, cmd.opcode == IO_READ) {
+                    Ok(iovecs) => iovecs,
+                    Err(status) => return Some((status, 0)),
+                };
+                let offset = (sector * SECTOR_SIZE) as libc::off_t;
+                if cmd.opcode == IO_READ {
+                    self.disk_io.read_vectored(offset, iovecs, user_data)
+                } else {
+                    self.disk_io.write_vectored(offset, iovecs, user_data)
+                }
+            }
+            opcode => {
+                debug!("{}: Unsupported NVMe I/O command 0x{:x}", self.id, opcode);
+                return Some((STATUS_INVALID_OPCODE, 0));
+            }
+        };
+
+        if let Err(e) = result {
+            error!("{}: Failed submitting NVMe request: {}", self.id, e);
+            return Some((STATUS_DATA_TRANSFER_ERROR, 0));
+        }
+
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        self.inflight.insert(user_data, (sqid, cmd.cid));
+        None
+    }
+
+    // Walks the PRP entries describing the `len` bytes of the guest buffer
+    // associated with the command.
+    fn prp_segments(
+        &self,
+        cmd: &SubmissionEntry,
+        len: u64,
+    ) -> result::Result<Vec<(u64, u64)>, u16> {
+        if len > MAX_TRANSFER_SIZE {
+            return Err(STATUS_INVALID_FIELD);
+        }
+
+        let first = std::cmp::min(len, PAGE_SIZE - cmd.prp1 % PAGE_SIZE);
+        let mut segments = vec![(cmd.prp1, first)];
+        let mut remaining = len - first;
+        if remaining == 0 {
+            return Ok(segments);
+        }
+        if remaining <= PAGE_SIZE {
+            segments.push((cmd.prp2, remaining));
+            return Ok(segments);
+        }
+
+        // The second entry points to a list of PRP entries, whose last entry
+        // points to the next list when it doesn't fit in a single page.
+        let mem = self.memory.memory();
+        let mut list = cmd.prp2;
+        // Bound the number of lists, so that a guest chaining lists holding
+        // a single entry can't keep the worker busy forever.
+        for _ in 0..MAX_TRANSFER_SIZE / PAGE_SIZE {
+            if list % 8 != 0 {
+                return Err(STATUS_INVALID_FIELD);
+            }
+            let entries = (PAGE_SIZE - list % PAGE_SIZE) / 8;
+            for i in 0..entries {
+                let entry: u64 = mem
+                    .read_obj(GuestAddress(list + i * 8))
+                    .map_err(|_| STATUS_DATA_TRANSFER_ERROR)?;
+                if i == entries - 1 && remaining > PAGE_SIZE {
+                    list = entry;
+                    break;
+                }
+                let count = std::cmp::min(remaining, PAGE_SIZE);
+                segments.push((entry, count));
+                remaining -= count;
+                if remaining == 0 {
+                    return Ok(segments);
+                }
+            }
+        }
+
+        Err(STATUS_INVALID_FIELD)
+    }
+
+    fn iovecs(
+        &self,
+        cmd: &SubmissionEntry,
+        len: u64,
+        dirty: bool,
+    ) -> result::Result<Vec<libc::iovec>, u16> {
+        let mem = self.memory.memory();
+        let mut iovecs = Vec::new();
+        for (addr, len) in self.prp_segments(cmd, len)? {
+            let slice = mem
+                .get_slice(GuestAddress(addr), len as usize)
+                .map_err(|_| STATUS_DATA_TRANSFER_ERROR)?;
+            if dirty {
+                slice.bitmap().mark_dirty(0, len as usize);
+            }
+            iovecs.push(libc::iovec {
+                iov_base: slice.as_ptr() as *mut libc::c_void,
+                iov_len: len as libc::size_t,
+            });
+        }
+
+        Ok(iovecs)
+    }
+
+    fn write_data(&self, cmd: &SubmissionEntry, data: &[u8]) -> u16 {
+        let segments = match self.prp_segments(cmd, data.len() as u64) {
+            Ok(segments) => segments,
+            Err(status) => return status,
+        };
+
+        let mem = self.memory.memory();
+        let mut done = 0;
+        for (addr, len) in segments {
+            let len = len as usize;
+            if mem
+                .write_slice(&data[done..done + len], GuestAddress(addr))
+                .is_err()
+            {
+                return STATUS_DATA_TRANSFER_ERROR;
+            }
+            done += len;
+        }
+
+        STATUS_SUCCESS
+    }
+}
+
+impl CompletionQueue {
+    fn new(addr: GuestAddress, size: u16, vector: Option<u16>) -> Self {
+        CompletionQueue {
+            addr,
+            size,
+            tail: 0,
+            phase: true,
+            vector,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+// Fills an ASCII field of the identify data, padded with spaces.
+fn copy_padded(field: &mut [u8], value: &[u8]) {
+    for (i, byte) in field.iter_mut().enumerate() {
+        *byte = value.get(i).copied().unwrap_or(b' ');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_entry_sizes() {
+        assert_eq!(std::mem::size_of::<SubmissionEntry>(), 64);
+        assert_eq!(std::mem::size_of::<CompletionEntry>(), 16);
+    }
+
+    #[test]
+    fn test_copy_padded() {
+        let mut field = [0u8; 8];
+        copy_padded(&mut field, b"nvme");
+        assert_eq!(&field, b"nvme    ");
+
+        copy_padded(&mut field, b"too long value");
+        assert_eq!(&field, b"too long");
+    }
+}
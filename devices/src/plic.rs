@@ -0,0 +1,294 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! RISC-V Platform-Level Interrupt Controller (PLIC)
+//!
+//! This module implements the register interface of the PLIC, as described
+//! by https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc.
+//! Each hart has a single context, receiving the supervisor external
+//! interrupts, which is why context and hart numbers are interchangeable.
+//!
+//! Raising the external interrupt of the harts is left to the caller, which
+//! checks `has_pending_irq()` after any change of the PLIC state.
+
+use std::convert::TryInto;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+
+const PRIORITY_BASE: u64 = 0x0;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const CONTEXT_THRESHOLD: u64 = 0x0;
+const CONTEXT_CLAIM: u64 = 0x4;
+
+// Number of priority levels supported, 0 meaning "never interrupt".
+const MAX_PRIORITY: u32 = 7;
+
+pub struct Plic {
+    // Number of interrupt sources, including the reserved source 0.
+    num_sources: u32,
+    priorities: Vec<u32>,
+    pending: Vec<u32>,
+    // Sources claimed by a context and not completed yet.
+    claimed: Vec<u32>,
+    enables: Vec<Vec<u32>>,
+    thresholds: Vec<u32>,
+}
+
+impl Plic {
+    pub fn new(num_sources: u32, num_contexts: usize) -> Self {
+        let words = ((num_sources + 31) / 32) as usize;
+        Plic {
+            num_sources,
+            priorities: vec![0; num_sources as usize],
+            pending: vec![0; words],
+            claimed: vec![0; words],
+            enables: vec![vec![0; words]; num_contexts],
+            thresholds: vec![0; num_contexts],
+        }
+    }
+
+    /// Marks the interrupt source as pending, as signaled by a device.
+    pub fn set_pending(&mut self, source: u32) {
+        if source == 0 || source >= self.num_sources {
+            warn!("Invalid PLIC interrupt source {}", source);
+            return;
+        }
+        self.pending[(source / 32) as usize] |= 1 << (source % 32);
+    }
+
+    /// Returns whether the external interrupt of the context must be raised.
+    pub fn has_pending_irq(&self, context: usize) -> bool {
+        self.best_pending(context) != 0
+    }
+
+    fn is_set(bits: &[u32], source: u32) -> bool {
+        bits[(source / 32) as usize] & (1 << (source % 32)) != 0
+    }
+
+    // Highest priority source which is pending, enabled for the context and
+    // above its threshold, or 0 if there is none. The lowest source number
+    // wins when several sources share the same priority.
+    fn best_pending(&self, context: usize) -> u32 {
+        let enables = match self.enables.get(context) {
+            Some(enables) => enables,
+            None => return 0,
+        };
+
+        let mut best = 0;
+        let mut best_priority = self.thresholds[context];
+        for source in 1..self.num_sources {
+            let priority = self.priorities[source as usize];
+            if priority > best_priority
+                && Plic::is_set(&self.pending, source)
+                && !Plic::is_set(&self.claimed, source)
+                && Plic::is_set(enables, source)
+            {
+                best = source;
+                best_priority = priority;
+            }
+        }
+
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let source = self.best_pending(context);
+        if source != 0 {
+            let word = (source / 32) as usize;
+            self.pending[word] &= !(1 << (source % 32));
+            self.claimed[word] |= 1 << (source % 32);
+        }
+        source
+    }
+
+    fn complete(&mut self, source: u32) {
+        if source != 0 && source < self.num_sources {
+            self.claimed[(source / 32) as usize] &= !(1 << (source % 32));
+        }
+    }
+
+    fn read_reg(&mut self, offset: u64) -> Option<u32> {
+        let words = self.pending.len() as u64;
+        if offset < PENDING_BASE {
+            let source = (offset - PRIORITY_BASE) / 4;
+            return self.priorities.get(source as usize).copied();
+        }
+        if offset < ENABLE_BASE {
+            return self
+                .pending
+                .get(((offset - PENDING_BASE) / 4) as usize)
+                .copied();
+        }
+        if offset < CONTEXT_BASE {
+            let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+            if word >= words {
+                return None;
+            }
+            return self
+                .enables
+                .get(context)
+                .map(|enables| enables[word as usize]);
+        }
+
+        let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+        if context >= self.thresholds.len() {
+            return None;
+        }
+        match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+            CONTEXT_THRESHOLD => Some(self.thresholds[context]),
+            CONTEXT_CLAIM => Some(self.claim(context)),
+            _ => None,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) -> bool {
+        let words = self.pending.len() as u64;
+        if offset < PENDING_BASE {
+            let source = ((offset - PRIORITY_BASE) / 4) as usize;
+            // The priority of the source 0 is hardwired to 0.
+            if source == 0 || source >= self.priorities.len() {
+                return false;
+            }
+            self.priorities[source] = value.min(MAX_PRIORITY);
+            return true;
+        }
+        if offset < ENABLE_BASE {
+            // The pending bits are read-only.
+            return false;
+        }
+        if offset < CONTEXT_BASE {
+            let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+            if context >= self.enables.len() || word >= words {
+                return false;
+            }
+            // The source 0 doesn't exist, hence it can't be enabled.
+            let mask = if word == 0 { !1 } else { !0 };
+            self.enables[context][word as usize] = value & mask;
+            return true;
+        }
+
+        let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+        if context >= self.thresholds.len() {
+            return false;
+        }
+        match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+            CONTEXT_THRESHOLD => {
+                self.thresholds[context] = value.min(MAX_PRIORITY);
+                true
+            }
+            CONTEXT_CLAIM => {
+                self.complete(value);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl BusDevice for Plic {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        // Reading the claim register has side effects, which is why the
+        // access size is checked first.
+        let value = if data.len() == 4 {
+            self.read_reg(offset)
+        } else {
+            None
+        };
+        match value {
+            Some(value) => data.copy_from_slice(&value.to_le_bytes()),
+            None => warn!(
+                "Invalid PLIC read: offset 0x{:x}, data length {}",
+                offset,
+                data.len()
+            ),
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        let written = match data.try_into() {
+            Ok(bytes) => self.write_reg(offset, u32::from_le_bytes(bytes)),
+            Err(_) => false,
+        };
+        if !written {
+            warn!(
+                "Invalid PLIC write: offset 0x{:x}, data length {}",
+                offset,
+                data.len()
+            );
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(plic: &mut Plic, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        plic.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write(plic: &mut Plic, offset: u64, value: u32) {
+        plic.write(0, offset, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_plic_claim_complete() {
+        let mut plic = Plic::new(64, 2);
+
+        // Source 3 with priority 1, source 40 with priority 2, both enabled
+        // on the context 1.
+        write(&mut plic, PRIORITY_BASE + 3 * 4, 1);
+        write(&mut plic, PRIORITY_BASE + 40 * 4, 2);
+        write(&mut plic, ENABLE_BASE + ENABLE_STRIDE, 1 << 3);
+        write(&mut plic, ENABLE_BASE + ENABLE_STRIDE + 4, 1 << (40 - 32));
+
+        plic.set_pending(3);
+        plic.set_pending(40);
+        assert!(!plic.has_pending_irq(0));
+        assert!(plic.has_pending_irq(1));
+        assert_eq!(read(&mut plic, PENDING_BASE), 1 << 3);
+
+        // The highest priority source is claimed first.
+        let claim = CONTEXT_BASE + CONTEXT_STRIDE + CONTEXT_CLAIM;
+        assert_eq!(read(&mut plic, claim), 40);
+        assert_eq!(read(&mut plic, claim), 3);
+        assert_eq!(read(&mut plic, claim), 0);
+        assert!(!plic.has_pending_irq(1));
+
+        // A claimed source isn't delivered again until it is completed.
+        plic.set_pending(40);
+        assert!(!plic.has_pending_irq(1));
+        write(&mut plic, claim, 40);
+        assert!(plic.has_pending_irq(1));
+    }
+
+    #[test]
+    fn test_plic_threshold() {
+        let mut plic = Plic::new(32, 1);
+
+        write(&mut plic, PRIORITY_BASE + 4, 2);
+        write(&mut plic, ENABLE_BASE, 1 << 1);
+        write(&mut plic, CONTEXT_BASE + CONTEXT_THRESHOLD, 2);
+        plic.set_pending(1);
+        assert!(!plic.has_pending_irq(0));
+
+        write(&mut plic, CONTEXT_BASE + CONTEXT_THRESHOLD, 1);
+        assert!(plic.has_pending_irq(0));
+
+        // The source 0 is reserved.
+        write(&mut plic, ENABLE_BASE, 1);
+        assert_eq!(read(&mut plic, ENABLE_BASE), 0);
+        write(&mut plic, PRIORITY_BASE, 1);
+        assert_eq!(read(&mut plic, PRIORITY_BASE), 0);
+    }
+}
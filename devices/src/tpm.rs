@@ -0,0 +1,602 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! TPM 2.0 exposed through the Command Response Buffer (CRB) interface.
+//!
+//! The commands written by the guest to the buffer are forwarded as they are
+//! to a TPM emulator listening on a UNIX socket, such as swtpm, or to a host
+//! TPM through its resource manager character device. Only locality 0 is
+//! implemented, and the guest is expected to poll for the completion of the
+//! commands rather than rely on interrupts.
+//!
+//! When given the control channel of swtpm, the emulator is initialized
+//! through it when the device is created and whenever the VM is reset, as a
+//! TPM is on a platform reset.
+
+use acpi_tables::{aml, aml::Aml};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+use vm_device::BusDevice;
+use vm_memory::GuestAddress;
+
+/// Size of the MMIO region of the device, holding the registers of locality 0
+/// followed by the command and response buffer.
+pub const TPM_CRB_SIZE: u64 = 0x1000;
+/// Offset of the control area, as reported through the ACPI TPM2 table.
+pub const TPM_CRB_CONTROL_AREA_OFFSET: u64 = CRB_CTRL_REQ;
+
+// Registers of locality 0
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_LOC_STS: u64 = 0x0c;
+const CRB_INTF_ID: u64 = 0x30;
+const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_INT_ENABLE: u64 = 0x50;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_CMD_HADDR: u64 = 0x60;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+const CRB_DATA_BUFFER: u64 = 0x80;
+const CRB_BUFFER_SIZE: usize = (TPM_CRB_SIZE - CRB_DATA_BUFFER) as usize;
+
+const LOC_STATE_TPM_ESTABLISHED: u32 = 1 << 0;
+const LOC_STATE_LOC_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID_STS: u32 = 1 << 7;
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const LOC_STS_GRANTED: u32 = 1 << 0;
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CTRL_STS_TPM_IDLE: u32 = 1 << 1;
+const CTRL_START: u32 = 1 << 0;
+
+const INTF_ID_TYPE_CRB: u64 = 1;
+const INTF_ID_VERSION_CRB: u64 = 1 << 4;
+const INTF_ID_CAP_DATA_XFER_SIZE_64: u64 = 3 << 11;
+const INTF_ID_CAP_CRB: u64 = 1 << 14;
+const INTF_ID_SELECTOR_CRB: u64 = 1 << 17;
+const INTF_ID_VID: u64 = 0x1014;
+const INTF_ID_DID: u64 = 0x0001;
+
+// Every command and response starts with a tag, its size and a code.
+const TPM_HEADER_SIZE: usize = 10;
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_RC_FAILURE: u32 = 0x101;
+
+// The vCPU starting a command is blocked until the response, which can take
+// a few seconds for a key generation, but not forever.
+const TPM_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Control channel of swtpm, where every command and result is a big endian
+// u32. CMD_INIT takes the initialization flags, none of which is needed.
+const PTM_CMD_INIT: u32 = 0x02;
+const PTM_INIT_FLAGS: u32 = 0;
+const PTM_RES_SUCCESS: u32 = 0;
+const PTM_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the TPM emulator socket
+    ConnectSocket(PathBuf, io::Error),
+    /// Failed to open the TPM character device
+    OpenDevice(PathBuf, io::Error),
+    /// Failed to initialize the TPM emulator through its control channel
+    Init(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+
+        match self {
+            ConnectSocket(path, e) => write!(
+                f,
+                "Failed to connect to TPM socket {}: {}",
+                path.display(),
+                e
+            ),
+            OpenDevice(path, e) => {
+                write!(f, "Failed to open TPM device {}: {}", path.display(), e)
+            }
+            Init(e) => write!(f, "Failed to initialize TPM emulator: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+enum Backend {
+    Socket { stream: UnixStream, path: PathBuf },
+    Device { file: File, path: PathBuf },
+}
+
+impl Backend {
+    fn connect(path: &Path) -> io::Result<Self> {
+        Ok(Backend::Socket {
+            stream: UnixStream::connect(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Backend::Device {
+            file: OpenOptions::new().read(true).write(true).open(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn execute(
+        &mut self,
+        command: &[u8],
+        response: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        match self {
+            Backend::Socket { stream, .. } => execute(stream, command, response, timeout),
+            Backend::Device { file, .. } => execute(file, command, response, timeout),
+        }
+    }
+
+    // A response arriving after the timeout would be taken for the one of the
+    // next command, hence a new connection to drop it.
+    fn reopen(&mut self) -> io::Result<()> {
+        *self = match self {
+            Backend::Socket { path, .. } => Backend::connect(path)?,
+            Backend::Device { path, .. } => Backend::open(path)?,
+        };
+
+        Ok(())
+    }
+}
+
+// Waits for the backend to be readable until the deadline.
+fn wait_readable(fd: RawFd, deadline: Instant) -> io::Result<()> {
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because pollfd outlives the call and the count is 1.
+        let ret = unsafe {
+            libc::poll(
+                &mut pollfd,
+                1,
+                std::cmp::min(timeout.as_millis(), libc::c_int::MAX as u128) as libc::c_int,
+            )
+        };
+        match ret {
+            0 => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+            ret if ret > 0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// The response is read until it reaches the size announced by its header, as
+// a stream socket may deliver it in pieces.
+fn execute<T: Read + Write + AsRawFd>(
+    backend: &mut T,
+    command: &[u8],
+    response: &mut [u8],
+    timeout: Duration,
+) -> io::Result<usize> {
+    backend.write_all(command)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut len = 0;
+    loop {
+        wait_readable(backend.as_raw_fd(), deadline)?;
+        let count = backend.read(&mut response[len..])?;
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        len += count;
+
+        if len >= 6 {
+            let expected = u32::from_be_bytes([response[2], response[3], response[4], response[5]]);
+            if len >= std::cmp::min(expected as usize, response.len()) {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+/// A TPM 2.0 device using the CRB interface, backed by a TPM emulator or by
+/// a host TPM.
+pub struct Tpm {
+    backend: Backend,
+    // Control channel of the emulator, to initialize it on a reset
+    ctrl: Option<UnixStream>,
+    timeout: Duration,
+    address: GuestAddress,
+    loc_assigned: bool,
+    idle: bool,
+    int_enable: u32,
+    buffer: Vec<u8>,
+}
+
+impl Tpm {
+    /// Creates a device forwarding the commands to the TPM emulator listening
+    /// on the `path` UNIX socket. The emulator is initialized through its
+    /// `ctrl` control channel if given, and is otherwise expected to be
+    /// started without needing it.
+    pub fn connect(path: &Path, ctrl: Option<&Path>, address: GuestAddress) -> Result<Self> {
+        let backend =
+            Backend::connect(path).map_err(|e| Error::ConnectSocket(path.to_path_buf(), e))?;
+        let mut tpm = Self::new(backend, address);
+
+        if let Some(ctrl) = ctrl {
+            let stream = UnixStream::connect(ctrl)
+                .and_then(|stream| {
+                    stream.set_read_timeout(Some(PTM_TIMEOUT))?;
+                    Ok(stream)
+                })
+                .map_err(|e| Error::ConnectSocket(ctrl.to_path_buf(), e))?;
+            tpm.ctrl = Some(stream);
+            tpm.init()?;
+        }
+
+        Ok(tpm)
+    }
+
+    /// Creates a device forwarding the commands to the `path` TPM character
+    /// device, usually the resource manager of a host TPM, /dev/tpmrm0.
+    pub fn open(path: &Path, address: GuestAddress) -> Result<Self> {
+        let backend = Backend::open(path).map_err(|e| Error::OpenDevice(path.to_path_buf(), e))?;
+
+        Ok(Self::new(backend, address))
+    }
+
+    fn new(backend: Backend, address: GuestAddress) -> Self {
+        Tpm {
+            backend,
+            ctrl: None,
+            timeout: TPM_COMMAND_TIMEOUT,
+            address,
+            loc_assigned: false,
+            idle: true,
+            int_enable: 0,
+            buffer: vec![0; CRB_BUFFER_SIZE],
+        }
+    }
+
+    /// Guest address of the registers and the buffer.
+    pub fn address(&self) -> GuestAddress {
+        self.address
+    }
+
+    /// Brings the device back to its power-on state, initializing the
+    /// emulator again when its control channel is known. Without it, the
+    /// TPM keeps its state, PCRs included, across the reset.
+    pub fn reset(&mut self) -> Result<()> {
+        self.loc_assigned = false;
+        self.idle = true;
+        self.int_enable = 0;
+        self.buffer.iter_mut().for_each(|byte| *byte = 0);
+
+        self.init()
+    }
+
+    fn init(&mut self) -> Result<()> {
+        let ctrl = match &mut self.ctrl {
+            Some(ctrl) => ctrl,
+            None => return Ok(()),
+        };
+
+        let mut request = PTM_CMD_INIT.to_be_bytes().to_vec();
+        request.extend_from_slice(&PTM_INIT_FLAGS.to_be_bytes());
+        ctrl.write_all(&request).map_err(Error::Init)?;
+
+        let mut result = [0u8; 4];
+        ctrl.read_exact(&mut result).map_err(Error::Init)?;
+        match u32::from_be_bytes(result) {
+            PTM_RES_SUCCESS => Ok(()),
+            result => Err(Error::Init(io::Error::new(
+                io::ErrorKind::Other,
+                format!("CMD_INIT failed with 0x{:x}", result),
+            ))),
+        }
+    }
+
+    fn registers(&self) -> [u8; CRB_DATA_BUFFER as usize] {
+        let mut registers = [0u8; CRB_DATA_BUFFER as usize];
+        let mut set = |offset: u64, value: &[u8]| {
+            registers[offset as usize..offset as usize + value.len()].copy_from_slice(value)
+        };
+
+        let mut loc_state = LOC_STATE_TPM_ESTABLISHED | LOC_STATE_REG_VALID_STS;
+        let mut loc_sts = 0;
+        if self.loc_assigned {
+            loc_state |= LOC_STATE_LOC_ASSIGNED;
+            loc_sts |= LOC_STS_GRANTED;
+        }
+        set(CRB_LOC_STATE, &loc_state.to_le_bytes());
+        set(CRB_LOC_STS, &loc_sts.to_le_bytes());
+
+        let intf_id = INTF_ID_TYPE_CRB
+            | INTF_ID_VERSION_CRB
+            | INTF_ID_CAP_DATA_XFER_SIZE_64
+            | INTF_ID_CAP_CRB
+            | INTF_ID_SELECTOR_CRB
+            | INTF_ID_VID << 32
+            | INTF_ID_DID << 48;
+        set(CRB_INTF_ID, &intf_id.to_le_bytes());
+
+        let ctrl_sts = if self.idle { CTRL_STS_TPM_IDLE } else { 0 };
+        set(CRB_CTRL_STS, &ctrl_sts.to_le_bytes());
+        set(CRB_INT_ENABLE, &self.int_enable.to_le_bytes());
+
+        let buffer_address = self.address.0 + CRB_DATA_BUFFER;
+        set(CRB_CTRL_CMD_SIZE, &(CRB_BUFFER_SIZE as u32).to_le_bytes());
+        set(CRB_CTRL_CMD_LADDR, &(buffer_address as u32).to_le_bytes());
+        set(
+            CRB_CTRL_CMD_HADDR,
+            &((buffer_address >> 32) as u32).to_le_bytes(),
+        );
+        set(CRB_CTRL_RSP_SIZE, &(CRB_BUFFER_SIZE as u32).to_le_bytes());
+        set(CRB_CTRL_RSP_ADDR, &buffer_address.to_le_bytes());
+
+        registers
+    }
+
+    // The command is executed synchronously, the guest seeing the start bit
+    // cleared once the response is in the buffer.
+    fn start(&mut self) {
+        let size = u32::from_be_bytes([
+            self.buffer[2],
+            self.buffer[3],
+            self.buffer[4],
+            self.buffer[5],
+        ]) as usize;
+        if !(TPM_HEADER_SIZE..=CRB_BUFFER_SIZE).contains(&size) {
+            warn!("Invalid TPM command size: {}", size);
+            self.set_failure();
+            return;
+        }
+
+        let command = self.buffer[..size].to_vec();
+        if let Err(e) = self
+            .backend
+            .execute(&command, &mut self.buffer, self.timeout)
+        {
+            error!("Failed to execute TPM command: {}", e);
+            self.set_failure();
+            if let Err(e) = self.backend.reopen() {
+                error!("Failed to reopen TPM backend: {}", e);
+            }
+        }
+    }
+
+    fn set_failure(&mut self) {
+        self.buffer[0..2].copy_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+        self.buffer[2..6].copy_from_slice(&(TPM_HEADER_SIZE as u32).to_be_bytes());
+        self.buffer[6..10].copy_from_slice(&TPM_RC_FAILURE.to_be_bytes());
+    }
+}
+
+impl BusDevice for Tpm {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= CRB_DATA_BUFFER {
+            let start = (offset - CRB_DATA_BUFFER) as usize;
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = self.buffer.get(start + i).copied().unwrap_or_default();
+            }
+            return;
+        }
+
+        let registers = self.registers();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = registers
+                .get(offset as usize + i)
+                .copied()
+                .unwrap_or_default();
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset >= CRB_DATA_BUFFER {
+            let start = (offset - CRB_DATA_BUFFER) as usize;
+            if let Some(buffer) = self.buffer.get_mut(start..start + data.len()) {
+                buffer.copy_from_slice(data);
+            }
+            return None;
+        }
+
+        let mut value = [0u8; 4];
+        let len = std::cmp::min(data.len(), value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(value);
+
+        match offset {
+            CRB_LOC_CTRL => {
+                if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    self.loc_assigned = true;
+                }
+                if value & LOC_CTRL_RELINQUISH != 0 {
+                    self.loc_assigned = false;
+                }
+            }
+            CRB_CTRL_REQ => {
+                if value & CTRL_REQ_CMD_READY != 0 {
+                    self.idle = false;
+                }
+                if value & CTRL_REQ_GO_IDLE != 0 {
+                    self.idle = true;
+                }
+            }
+            CRB_CTRL_START => {
+                if value & CTRL_START != 0 && self.loc_assigned && !self.idle {
+                    self.start();
+                }
+            }
+            CRB_INT_ENABLE => self.int_enable = value,
+            _ => debug!("Ignoring TPM register write at 0x{:x}", offset),
+        }
+
+        None
+    }
+}
+
+impl Aml for Tpm {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::Device::new(
+            "_SB_.TPM0".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"MSFT0101"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new("_STA".into(), &0xfu8),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::AddressSpace::new_memory(
+                        aml::AddressSpaceCachable::NotCacheable,
+                        true,
+                        self.address.0,
+                        self.address.0 + TPM_CRB_SIZE - 1,
+                    )]),
+                ),
+            ],
+        )
+        .to_aml_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn test_command() {
+        let path = std::env::temp_dir().join(format!("ch-tpm-test-{}.sock", std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // TPM2_GetRandom of 4 bytes, and its response split in two writes.
+        let command = [
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x7b, 0x00, 0x04,
+        ];
+        let response = [
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xde, 0xad,
+            0xbe, 0xef,
+        ];
+        let emulator = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = [0u8; 12];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(received, command);
+            stream.write_all(&response[..6]).unwrap();
+            stream.write_all(&response[6..]).unwrap();
+        });
+
+        let address = GuestAddress(0xfed4_0000);
+        let mut tpm = Tpm::connect(&path, None, address).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut data = [0u8; 4];
+        tpm.read(0, CRB_CTRL_CMD_LADDR, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xfed4_0080);
+
+        tpm.write(0, CRB_LOC_CTRL, &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        tpm.read(0, CRB_LOC_STS, &mut data);
+        assert_eq!(u32::from_le_bytes(data), LOC_STS_GRANTED);
+        tpm.write(0, CRB_CTRL_REQ, &CTRL_REQ_CMD_READY.to_le_bytes());
+        tpm.read(0, CRB_CTRL_STS, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+
+        tpm.write(0, CRB_DATA_BUFFER, &command);
+        tpm.write(0, CRB_CTRL_START, &CTRL_START.to_le_bytes());
+        tpm.read(0, CRB_CTRL_START, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+
+        let mut received = [0u8; 16];
+        tpm.read(0, CRB_DATA_BUFFER, &mut received);
+        assert_eq!(received, response);
+
+        emulator.join().unwrap();
+    }
+
+    #[test]
+    fn test_reset() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("ch-tpm-reset-test-{}.sock", std::process::id()));
+        let ctrl_path = dir.join(format!("ch-tpm-reset-test-{}.ctrl", std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let ctrl_listener = UnixListener::bind(&ctrl_path).unwrap();
+
+        // CMD_INIT is expected on creation and on every reset.
+        let emulator = thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            let (mut ctrl, _) = ctrl_listener.accept().unwrap();
+            for _ in 0..2 {
+                let mut received = [0u8; 8];
+                ctrl.read_exact(&mut received).unwrap();
+                assert_eq!(received, [0, 0, 0, 2, 0, 0, 0, 0]);
+                ctrl.write_all(&PTM_RES_SUCCESS.to_be_bytes()).unwrap();
+            }
+        });
+
+        let address = GuestAddress(0xfed4_0000);
+        let mut tpm = Tpm::connect(&path, Some(&ctrl_path), address).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&ctrl_path).unwrap();
+
+        tpm.write(0, CRB_LOC_CTRL, &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        tpm.reset().unwrap();
+        let mut data = [0u8; 4];
+        tpm.read(0, CRB_LOC_STS, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+
+        emulator.join().unwrap();
+    }
+
+    #[test]
+    fn test_timeout() {
+        let path =
+            std::env::temp_dir().join(format!("ch-tpm-timeout-test-{}.sock", std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // The emulator never answers, and is connected to again.
+        let emulator = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = [0u8; 12];
+            stream.read_exact(&mut received).unwrap();
+            listener.accept().unwrap();
+        });
+
+        let address = GuestAddress(0xfed4_0000);
+        let mut tpm = Tpm::connect(&path, None, address).unwrap();
+        tpm.timeout = Duration::from_millis(100);
+
+        let command = [
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x7b, 0x00, 0x04,
+        ];
+        tpm.write(0, CRB_LOC_CTRL, &LOC_CTRL_REQUEST_ACCESS.to_le_bytes());
+        tpm.write(0, CRB_CTRL_REQ, &CTRL_REQ_CMD_READY.to_le_bytes());
+        tpm.write(0, CRB_DATA_BUFFER, &command);
+        tpm.write(0, CRB_CTRL_START, &CTRL_START.to_le_bytes());
+        emulator.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut data = [0u8; 4];
+        tpm.read(0, CRB_DATA_BUFFER + 6, &mut data);
+        assert_eq!(u32::from_be_bytes(data), TPM_RC_FAILURE);
+    }
+}
@@ -0,0 +1,521 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host USB device, accessed through usbfs.
+//!
+//! The interfaces of the device are detached from their host drivers and
+//! claimed for the whole life of the VM. The transfers are submitted as
+//! asynchronous URBs, whose completion is signalled by the device file
+//! becoming writable.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::result;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{
+    ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr,
+};
+
+// See include/uapi/linux/usbdevice_fs.h in the kernel code.
+const USBDEVFS_TYPE: u32 = b'U' as u32;
+ioctl_iowr_nr!(USBDEVFS_CONTROL, USBDEVFS_TYPE, 0, UsbdevfsCtrlTransfer);
+ioctl_ior_nr!(
+    USBDEVFS_SETINTERFACE,
+    USBDEVFS_TYPE,
+    4,
+    UsbdevfsSetInterface
+);
+ioctl_ior_nr!(USBDEVFS_SETCONFIGURATION, USBDEVFS_TYPE, 5, libc::c_uint);
+ioctl_ior_nr!(USBDEVFS_SUBMITURB, USBDEVFS_TYPE, 10, UsbdevfsUrb);
+ioctl_io_nr!(USBDEVFS_DISCARDURB, USBDEVFS_TYPE, 11);
+ioctl_iow_nr!(USBDEVFS_REAPURBNDELAY, USBDEVFS_TYPE, 13, *mut libc::c_void);
+ioctl_ior_nr!(USBDEVFS_RELEASEINTERFACE, USBDEVFS_TYPE, 16, libc::c_uint);
+ioctl_iowr_nr!(USBDEVFS_IOCTL, USBDEVFS_TYPE, 18, UsbdevfsIoctl);
+ioctl_ior_nr!(USBDEVFS_CLEAR_HALT, USBDEVFS_TYPE, 21, libc::c_uint);
+ioctl_io_nr!(USBDEVFS_CONNECT, USBDEVFS_TYPE, 23);
+ioctl_ior_nr!(
+    USBDEVFS_DISCONNECT_CLAIM,
+    USBDEVFS_TYPE,
+    27,
+    UsbdevfsDisconnectClaim
+);
+ioctl_io_nr!(USBDEVFS_GET_SPEED, USBDEVFS_TYPE, 31);
+
+const USBDEVFS_URB_TYPE_INTERRUPT: u8 = 1;
+const USBDEVFS_URB_TYPE_CONTROL: u8 = 2;
+const USBDEVFS_URB_TYPE_BULK: u8 = 3;
+
+// Values returned by USBDEVFS_GET_SPEED.
+const USB_SPEED_LOW: i32 = 1;
+const USB_SPEED_FULL: i32 = 2;
+const USB_SPEED_HIGH: i32 = 3;
+const USB_SPEED_WIRELESS: i32 = 4;
+
+const USB_DT_DEVICE: u8 = 0x01;
+const USB_DT_CONFIG: u8 = 0x02;
+const USB_DT_DEVICE_SIZE: usize = 18;
+const USB_DT_CONFIG_SIZE: usize = 9;
+
+const USB_REQ_GET_CONFIGURATION: u8 = 0x08;
+const CONTROL_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed opening the device file.
+    Open(PathBuf, io::Error),
+    /// Failed reading the device descriptors.
+    ReadDescriptors(io::Error),
+    /// The device descriptors are malformed.
+    InvalidDescriptors,
+    /// Failed getting the device speed.
+    GetSpeed(io::Error),
+    /// Failed getting the active configuration.
+    GetConfiguration(io::Error),
+    /// Failed claiming an interface from the host drivers.
+    ClaimInterface(u8, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Open(path, e) => write!(f, "failed opening {}: {}", path.display(), e),
+            ReadDescriptors(e) => write!(f, "failed reading device descriptors: {}", e),
+            InvalidDescriptors => write!(f, "invalid device descriptors"),
+            GetSpeed(e) => write!(f, "failed getting device speed: {}", e),
+            GetConfiguration(e) => write!(f, "failed getting active configuration: {}", e),
+            ClaimInterface(interface, e) => {
+                write!(f, "failed claiming interface {}: {}", interface, e)
+            }
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[repr(C)]
+struct UsbdevfsCtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut libc::c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsSetInterface {
+    interface: libc::c_uint,
+    altsetting: libc::c_uint,
+}
+
+#[repr(C)]
+struct UsbdevfsDisconnectClaim {
+    interface: libc::c_uint,
+    flags: libc::c_uint,
+    driver: [libc::c_char; 256],
+}
+
+#[repr(C)]
+struct UsbdevfsIoctl {
+    ifno: libc::c_int,
+    ioctl_code: libc::c_int,
+    data: *mut libc::c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsUrb {
+    urb_type: u8,
+    endpoint: u8,
+    status: libc::c_int,
+    flags: libc::c_uint,
+    buffer: *mut libc::c_void,
+    buffer_length: libc::c_int,
+    actual_length: libc::c_int,
+    start_frame: libc::c_int,
+    number_of_packets: libc::c_int,
+    error_count: libc::c_int,
+    signr: libc::c_uint,
+    usercontext: *mut libc::c_void,
+}
+
+/// Speed the device is operating at on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+/// Type of the transfers of an endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Versionize)]
+pub enum TransferType {
+    Control,
+    Interrupt,
+    Bulk,
+}
+
+/// Completed transfer, along with the buffer it was submitted with.
+pub struct Completion {
+    pub key: u64,
+    /// 0, or the negative errno the transfer failed with.
+    pub status: i32,
+    /// Number of bytes transferred, the setup packet of a control transfer
+    /// excluded.
+    pub actual_length: usize,
+    pub buffer: Vec<u8>,
+}
+
+// URB submitted to the kernel, which keeps writing to it until it is reaped.
+struct Urb {
+    urb: UsbdevfsUrb,
+    buffer: Vec<u8>,
+}
+
+// Safe because the only pointers the URB holds point to its own buffer.
+unsafe impl Send for Urb {}
+
+/// USB device of the host, passed through to the guest.
+pub struct UsbHostDevice {
+    // Closing the file cancels the URBs still in flight, which must happen
+    // before their buffers are freed, hence the declaration order.
+    file: File,
+    inflight: HashMap<u64, Box<Urb>>,
+    path: PathBuf,
+    speed: Speed,
+    // Value and number of interfaces of each configuration.
+    configurations: Vec<(u8, u8)>,
+    claimed: Vec<u8>,
+}
+
+impl UsbHostDevice {
+    /// Opens the usbfs file of the device, `/dev/bus/usb/<bus>/<device>`,
+    /// and claims the interfaces of its active configuration.
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+
+        // Reading the file returns the device descriptor, followed by all
+        // the configuration descriptors.
+        let mut descriptors = Vec::new();
+        file.read_to_end(&mut descriptors)
+            .map_err(Error::ReadDescriptors)?;
+        let configurations = parse_configurations(&descriptors).ok_or(Error::InvalidDescriptors)?;
+
+        // Safe because the ioctl doesn't take any argument.
+        let ret = unsafe { ioctl(&file, USBDEVFS_GET_SPEED()) };
+        let speed = match ret {
+            USB_SPEED_LOW => Speed::Low,
+            USB_SPEED_FULL => Speed::Full,
+            USB_SPEED_HIGH | USB_SPEED_WIRELESS => Speed::High,
+            ret if ret > USB_SPEED_WIRELESS => Speed::Super,
+            _ => return Err(Error::GetSpeed(io::Error::last_os_error())),
+        };
+
+        let mut device = UsbHostDevice {
+            file,
+            inflight: HashMap::new(),
+            path: path.to_path_buf(),
+            speed,
+            configurations,
+            claimed: Vec::new(),
+        };
+
+        let mut value = [0u8; 1];
+        device
+            .control(0x80, USB_REQ_GET_CONFIGURATION, 0, 0, &mut value)
+            .map_err(Error::GetConfiguration)?;
+        device.claim_interfaces(value[0])?;
+
+        Ok(device)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    // Synchronous control transfer, only used while opening the device.
+    fn control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &mut [u8],
+    ) -> io::Result<()> {
+        let transfer = UsbdevfsCtrlTransfer {
+            request_type,
+            request,
+            value,
+            index,
+            length: data.len() as u16,
+            timeout: CONTROL_TIMEOUT_MS,
+            data: data.as_mut_ptr() as *mut libc::c_void,
+        };
+        // Safe because the kernel only accesses the transfer and the data
+        // buffer, both living until the ioctl returns.
+        let ret = unsafe { ioctl_with_ref(&self.file, USBDEVFS_CONTROL(), &transfer) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn claim_interfaces(&mut self, configuration: u8) -> Result<()> {
+        let num_interfaces = self
+            .configurations
+            .iter()
+            .find(|(value, _)| *value == configuration)
+            .map_or(0, |(_, num)| *num);
+
+        for interface in 0..num_interfaces {
+            // Detaches whichever host driver is bound to the interface.
+            let mut claim = UsbdevfsDisconnectClaim {
+                interface: interface as libc::c_uint,
+                flags: 0,
+                driver: [0; 256],
+            };
+            // Safe because the kernel only accesses the claim structure.
+            let ret =
+                unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_DISCONNECT_CLAIM(), &mut claim) };
+            if ret < 0 {
+                return Err(Error::ClaimInterface(interface, io::Error::last_os_error()));
+            }
+            self.claimed.push(interface);
+        }
+
+        Ok(())
+    }
+
+    fn release_interfaces(&mut self) {
+        for interface in self.claimed.drain(..) {
+            let interface = interface as libc::c_uint;
+            // Safe because the kernel only reads the interface number.
+            unsafe { ioctl_with_ref(&self.file, USBDEVFS_RELEASEINTERFACE(), &interface) };
+        }
+    }
+
+    /// Selects the configuration the guest asked for, and claims its
+    /// interfaces.
+    pub fn set_configuration(&mut self, configuration: u8) -> io::Result<()> {
+        self.release_interfaces();
+
+        let value = configuration as libc::c_uint;
+        // Safe because the kernel only reads the configuration value.
+        let ret = unsafe { ioctl_with_ref(&self.file, USBDEVFS_SETCONFIGURATION(), &value) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.claim_interfaces(configuration).map_err(|e| match e {
+            Error::ClaimInterface(_, e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+        })
+    }
+
+    pub fn set_interface(&self, interface: u16, altsetting: u16) -> io::Result<()> {
+        let setting = UsbdevfsSetInterface {
+            interface: interface as libc::c_uint,
+            altsetting: altsetting as libc::c_uint,
+        };
+        // Safe because the kernel only reads the setting structure.
+        let ret = unsafe { ioctl_with_ref(&self.file, USBDEVFS_SETINTERFACE(), &setting) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    pub fn clear_halt(&self, endpoint: u8) -> io::Result<()> {
+        let endpoint = endpoint as libc::c_uint;
+        // Safe because the kernel only reads the endpoint address.
+        let ret = unsafe { ioctl_with_ref(&self.file, USBDEVFS_CLEAR_HALT(), &endpoint) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Submits a transfer on the endpoint at `address`, identified by `key`
+    /// once completed. The buffer of a control transfer starts with the
+    /// setup packet.
+    pub fn submit(
+        &mut self,
+        key: u64,
+        transfer_type: TransferType,
+        address: u8,
+        buffer: Vec<u8>,
+    ) -> io::Result<()> {
+        let urb_type = match transfer_type {
+            TransferType::Control => USBDEVFS_URB_TYPE_CONTROL,
+            TransferType::Interrupt => USBDEVFS_URB_TYPE_INTERRUPT,
+            TransferType::Bulk => USBDEVFS_URB_TYPE_BULK,
+        };
+        let mut urb = Box::new(Urb {
+            urb: UsbdevfsUrb {
+                urb_type,
+                endpoint: address,
+                status: 0,
+                flags: 0,
+                buffer: std::ptr::null_mut(),
+                buffer_length: buffer.len() as libc::c_int,
+                actual_length: 0,
+                start_frame: 0,
+                number_of_packets: 0,
+                error_count: 0,
+                signr: 0,
+                usercontext: key as *mut libc::c_void,
+            },
+            buffer,
+        });
+        urb.urb.buffer = urb.buffer.as_mut_ptr() as *mut libc::c_void;
+
+        // Safe because the URB and its buffer are kept alive until the
+        // kernel hands the URB back, or the file is closed.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_SUBMITURB(), &mut urb.urb) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.inflight.insert(key, urb);
+        Ok(())
+    }
+
+    /// Cancels the transfer identified by `key`, which still completes.
+    pub fn discard(&mut self, key: u64) {
+        if let Some(urb) = self.inflight.get_mut(&key) {
+            // Safe because the kernel only looks the URB up, by address.
+            unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_DISCARDURB(), &mut urb.urb) };
+        }
+    }
+
+    /// Returns the next completed transfer, if any.
+    pub fn reap(&mut self) -> io::Result<Option<Completion>> {
+        let mut urb: *mut UsbdevfsUrb = std::ptr::null_mut();
+        // Safe because the kernel only writes the address of a URB it was
+        // given back.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_REAPURBNDELAY(), &mut urb) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EAGAIN) {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+
+        // Safe because the URB belongs to an entry of the in-flight map,
+        // which is still alive.
+        let key = unsafe { (*urb).usercontext } as u64;
+        let urb = match self.inflight.remove(&key) {
+            Some(urb) => urb,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown URB reaped",
+                ))
+            }
+        };
+
+        Ok(Some(Completion {
+            key,
+            status: urb.urb.status,
+            actual_length: urb.urb.actual_length.max(0) as usize,
+            buffer: urb.buffer,
+        }))
+    }
+}
+
+impl AsRawFd for UsbHostDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for UsbHostDevice {
+    fn drop(&mut self) {
+        let claimed = self.claimed.clone();
+        self.release_interfaces();
+
+        // Gives the interfaces back to their host drivers.
+        for interface in claimed {
+            let mut command = UsbdevfsIoctl {
+                ifno: interface as libc::c_int,
+                ioctl_code: USBDEVFS_CONNECT() as libc::c_int,
+                data: std::ptr::null_mut(),
+            };
+            // Safe because the kernel only reads the command structure.
+            unsafe { ioctl_with_mut_ref(&self.file, USBDEVFS_IOCTL(), &mut command) };
+        }
+    }
+}
+
+// Returns the value and the number of interfaces of each configuration
+// described by the descriptors read from usbfs.
+fn parse_configurations(descriptors: &[u8]) -> Option<Vec<(u8, u8)>> {
+    let device = descriptors.get(..USB_DT_DEVICE_SIZE)?;
+    if device[1] != USB_DT_DEVICE {
+        return None;
+    }
+
+    let mut configurations = Vec::new();
+    let mut offset = USB_DT_DEVICE_SIZE;
+    for _ in 0..device[17] {
+        let config = descriptors.get(offset..offset + USB_DT_CONFIG_SIZE)?;
+        if config[1] != USB_DT_CONFIG {
+            return None;
+        }
+        let total_length = u16::from_le_bytes([config[2], config[3]]) as usize;
+        if total_length < USB_DT_CONFIG_SIZE {
+            return None;
+        }
+        configurations.push((config[5], config[4]));
+        offset += total_length;
+    }
+
+    Some(configurations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_configurations() {
+        let mut descriptors = vec![
+            18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x50, 0x10, 0x07, 0x04, 0x00, 0x01, 1, 2, 0, 2,
+        ];
+        // Two configurations, the first one followed by an interface
+        // descriptor.
+        descriptors.extend(&[9, 2, 18, 0, 1, 1, 0, 0x80, 50]);
+        descriptors.extend(&[9, 4, 0, 0, 1, 3, 0, 0, 0]);
+        descriptors.extend(&[9, 2, 9, 0, 2, 2, 0, 0x80, 50]);
+        assert_eq!(
+            parse_configurations(&descriptors),
+            Some(vec![(1, 1), (2, 2)])
+        );
+
+        descriptors.truncate(descriptors.len() - 1);
+        assert_eq!(parse_configurations(&descriptors), None);
+        assert_eq!(parse_configurations(&descriptors[..10]), None);
+    }
+}
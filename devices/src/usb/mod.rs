@@ -0,0 +1,11 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! USB host controller, and the host devices passed through to the guest.
+
+pub mod host;
+pub mod xhci;
+
+pub use self::host::UsbHostDevice;
+pub use self::xhci::XhciController;
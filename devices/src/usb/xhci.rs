@@ -0,0 +1,2437 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulated xHCI controller.
+//!
+//! Exposes USB devices of the host to the guest, each one on its own root
+//! hub port. Only the control, bulk and interrupt transfers are supported,
+//! which is what hardware tokens and license dongles rely on.
+//!
+//! The registers are emulated from the vCPU thread, while the command and
+//! transfer rings are processed by a dedicated thread, woken up through an
+//! eventfd each time the driver rings a doorbell. The same thread reaps the
+//! transfers completed by the host devices.
+//!
+//! The transfers in flight when the controller is snapshotted can't be
+//! carried over, the ring of their endpoint is rewound to submit them again
+//! once restored.
+
+use super::host::{Completion, Speed, TransferType, UsbHostDevice};
+use anyhow::anyhow;
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciProgrammingInterface, PciSerialBusSubClass,
+};
+use seccomp::{BpfProgram, SeccompFilter};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Instant;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_allocator::SystemAllocator;
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_device::BusDevice;
+use vm_memory::{
+    bitmap::AtomicBitmap, Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace,
+    GuestMemoryAtomic, GuestUsize,
+};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+/// Maximum number of devices attached to the controller.
+pub const MAX_USB_DEVICES: usize = 4;
+
+// Same identifiers as the xHCI controller emulated by QEMU, which guests
+// already know about.
+const XHCI_VENDOR_ID: u16 = 0x1b36;
+const XHCI_DEVICE_ID: u16 = 0x000d;
+
+// BAR0 layout.
+const XHCI_BAR_INDEX: usize = 0;
+const XHCI_BAR_SIZE: u64 = 0x4000;
+const OPERATIONAL_BAR_OFFSET: u64 = 0x40;
+const EXTENDED_CAPS_BAR_OFFSET: u64 = 0x800;
+const RUNTIME_BAR_OFFSET: u64 = 0x1000;
+const DOORBELL_BAR_OFFSET: u64 = 0x2000;
+const DOORBELL_SIZE: u64 = 0x1000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x3000;
+const MSIX_TABLE_SIZE: u64 = 0x800;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x3800;
+const MSIX_PBA_SIZE: u64 = 0x800;
+
+// The devices running at SuperSpeed are attached to the USB 3 ports, which
+// follow the USB 2 ones.
+const NUM_PORTS: usize = 2 * MAX_USB_DEVICES;
+const MAX_SLOTS: usize = 8;
+const MAX_ENDPOINTS: usize = 32;
+const NUM_INTERRUPTERS: u32 = 1;
+
+// Capability registers.
+const REG_CAPLENGTH: u64 = 0x00;
+const REG_HCSPARAMS1: u64 = 0x04;
+const REG_HCCPARAMS1: u64 = 0x10;
+const REG_DBOFF: u64 = 0x14;
+const REG_RTSOFF: u64 = 0x18;
+
+const XHCI_VERSION_1_0: u32 = 0x0100;
+const HCCPARAMS1_AC64: u32 = 1;
+
+// Operational registers.
+const REG_USBCMD: u64 = 0x00;
+const REG_USBSTS: u64 = 0x04;
+const REG_PAGESIZE: u64 = 0x08;
+const REG_DNCTRL: u64 = 0x14;
+const REG_CRCR: u64 = 0x18;
+const REG_DCBAAP: u64 = 0x30;
+const REG_CONFIG: u64 = 0x38;
+const PORT_REGS_OFFSET: u64 = 0x400;
+const PORT_REGS_SIZE: u64 = 0x10;
+
+const USBCMD_RS: u32 = 1;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBCMD_INTE: u32 = 1 << 2;
+
+const USBSTS_HCH: u32 = 1;
+const USBSTS_HSE: u32 = 1 << 2;
+const USBSTS_EINT: u32 = 1 << 3;
+const USBSTS_PCD: u32 = 1 << 4;
+const USBSTS_SRE: u32 = 1 << 10;
+const USBSTS_CNR: u32 = 1 << 11;
+const USBSTS_RW1C: u32 = USBSTS_HSE | USBSTS_EINT | USBSTS_PCD | USBSTS_SRE;
+
+const CRCR_RCS: u64 = 1;
+const CRCR_CS: u64 = 1 << 1;
+const CRCR_CA: u64 = 1 << 2;
+const CRCR_CRR: u32 = 1 << 3;
+
+const PORTSC_CCS: u32 = 1;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PLS_SHIFT: u32 = 5;
+const PORTSC_PLS_MASK: u32 = 0xf << PORTSC_PLS_SHIFT;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_SPEED_SHIFT: u32 = 10;
+const PORTSC_PIC_MASK: u32 = 0x3 << 14;
+const PORTSC_LWS: u32 = 1 << 16;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+const PORTSC_PLC: u32 = 1 << 22;
+const PORTSC_WRC: u32 = 1 << 19;
+const PORTSC_WAKE_MASK: u32 = 0x7 << 25;
+const PORTSC_WPR: u32 = 1 << 31;
+// Connect, enable, warm reset, over-current, reset, link state and config
+// error changes.
+const PORTSC_CHANGE_MASK: u32 = 0x7f << 17;
+const PORTSC_RW_MASK: u32 = PORTSC_PP | PORTSC_PIC_MASK | PORTSC_WAKE_MASK;
+
+const PLS_U0: u32 = 0;
+const PLS_U3: u32 = 3;
+const PLS_RX_DETECT: u32 = 5;
+const PLS_POLLING: u32 = 7;
+const PLS_RESUME: u32 = 15;
+
+// Protocol speed identifiers reported through PORTSC.
+const SPEED_FULL: u32 = 1;
+const SPEED_LOW: u32 = 2;
+const SPEED_HIGH: u32 = 3;
+const SPEED_SUPER: u32 = 4;
+
+// Runtime registers.
+const REG_MFINDEX: u64 = 0x00;
+const REG_IMAN: u64 = 0x20;
+const REG_IMOD: u64 = 0x24;
+const REG_ERSTSZ: u64 = 0x28;
+const REG_ERSTBA: u64 = 0x30;
+const REG_ERDP: u64 = 0x38;
+
+const IMAN_IP: u32 = 1;
+const IMAN_IE: u32 = 1 << 1;
+const IMOD_DEFAULT: u32 = 4000;
+const ERDP_EHB: u64 = 1 << 3;
+// The event ring is made of a single segment.
+const MIN_EVENT_RING_SIZE: u32 = 16;
+const MAX_EVENT_RING_SIZE: u32 = 4096;
+// Bound the events waiting for room in the event ring, so that a driver
+// which stops consuming them can't make the VMM grow without limit.
+const MAX_PENDING_EVENTS: usize = MAX_EVENT_RING_SIZE as usize;
+
+// Transfer Request Blocks.
+const TRB_SIZE: u64 = 16;
+const TRB_CYCLE: u32 = 1;
+const TRB_LINK_TC: u32 = 1 << 1;
+const TRB_ED: u32 = 1 << 2;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_BSR: u32 = 1 << 9;
+const TRB_DC: u32 = 1 << 9;
+const TRB_TYPE_SHIFT: u32 = 10;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP_STAGE: u32 = 2;
+const TRB_DATA_STAGE: u32 = 3;
+const TRB_STATUS_STAGE: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_EVENT_DATA: u32 = 7;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_STOP_ENDPOINT: u32 = 15;
+const TRB_SET_TR_DEQUEUE: u32 = 16;
+const TRB_RESET_DEVICE: u32 = 17;
+const TRB_NOOP_COMMAND: u32 = 23;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION_EVENT: u32 = 33;
+const TRB_PORT_STATUS_CHANGE_EVENT: u32 = 34;
+
+// Bound the number of TRBs looked at in a row, so that a guest linking
+// the rings onto themselves can't keep the worker busy forever.
+const MAX_RING_TRBS: usize = 1024;
+const MAX_TRANSFER_SIZE: u32 = 1 << 20;
+
+// Completion codes.
+const CC_SUCCESS: u32 = 1;
+const CC_DATA_BUFFER_ERROR: u32 = 2;
+const CC_BABBLE_DETECTED: u32 = 3;
+const CC_USB_TRANSACTION_ERROR: u32 = 4;
+const CC_TRB_ERROR: u32 = 5;
+const CC_STALL: u32 = 6;
+const CC_NO_SLOTS_AVAILABLE: u32 = 9;
+const CC_SLOT_NOT_ENABLED: u32 = 11;
+const CC_ENDPOINT_NOT_ENABLED: u32 = 12;
+const CC_SHORT_PACKET: u32 = 13;
+const CC_PARAMETER_ERROR: u32 = 17;
+const CC_CONTEXT_STATE_ERROR: u32 = 19;
+const CC_COMMAND_RING_STOPPED: u32 = 24;
+
+// Device contexts, made of 32 bytes entries.
+const CONTEXT_SIZE: u64 = 32;
+const SLOT_STATE_SHIFT: u32 = 27;
+const SLOT_STATE_ENABLED: u32 = 0;
+const SLOT_STATE_DEFAULT: u32 = 1;
+const SLOT_STATE_ADDRESSED: u32 = 2;
+const SLOT_STATE_CONFIGURED: u32 = 3;
+const CONTEXT_ENTRIES_SHIFT: u32 = 27;
+const EP_STATE_MASK: u32 = 0x7;
+const EP_STATE_DISABLED: u32 = 0;
+const EP_STATE_RUNNING: u32 = 1;
+const EP_STATE_HALTED: u32 = 2;
+const EP_STATE_STOPPED: u32 = 3;
+
+// Standard requests handled by the controller rather than by the device.
+const USB_REQ_CLEAR_FEATURE: u8 = 0x01;
+const USB_REQ_SET_ADDRESS: u8 = 0x05;
+const USB_REQ_SET_CONFIGURATION: u8 = 0x09;
+const USB_REQ_SET_INTERFACE: u8 = 0x0b;
+const USB_ENDPOINT_HALT: u16 = 0;
+const USB_SETUP_SIZE: usize = 8;
+
+// Worker thread epoll tokens, the devices following.
+const KICK_EVENT: u64 = 0;
+const KILL_EVENT: u64 = 1;
+const DEVICE_EVENT: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Too many devices for the controller.
+    TooManyDevices(usize),
+    /// Failed creating the MSI-X interrupt group.
+    CreateInterruptGroup(io::Error),
+    /// Failed adding the MSI-X capability.
+    CapabilitiesSetup(PciDeviceError),
+    /// Failed creating an eventfd.
+    EventFd(io::Error),
+    /// Failed spawning the worker thread.
+    SpawnWorker(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            TooManyDevices(num) => write!(
+                f,
+                "{} USB devices, at most {} are supported",
+                num, MAX_USB_DEVICES
+            ),
+            CreateInterruptGroup(e) => write!(f, "failed creating interrupt group: {}", e),
+            CapabilitiesSetup(e) => write!(f, "failed setting up PCI capabilities: {}", e),
+            EventFd(e) => write!(f, "failed creating eventfd: {}", e),
+            SpawnWorker(e) => write!(f, "failed spawning worker thread: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+struct XhciProgrammingInterface;
+
+impl PciProgrammingInterface for XhciProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        // USB3 xHCI
+        0x30
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for Trb {}
+
+impl Trb {
+    fn trb_type(&self) -> u32 {
+        (self.control >> TRB_TYPE_SHIFT) & 0x3f
+    }
+
+    fn cycle(&self) -> bool {
+        self.control & TRB_CYCLE != 0
+    }
+
+    fn slot_id(&self) -> usize {
+        (self.control >> 24) as usize
+    }
+
+    fn endpoint_id(&self) -> usize {
+        ((self.control >> 16) & 0x1f) as usize
+    }
+
+    fn transfer_length(&self) -> u32 {
+        self.status & 0x1_ffff
+    }
+
+    fn has_data(&self) -> bool {
+        matches!(self.trb_type(), TRB_NORMAL | TRB_DATA_STAGE)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Context {
+    dwords: [u32; 8],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for Context {}
+
+// Dequeue pointer and consumer cycle state of a ring.
+#[derive(Copy, Clone, Default)]
+struct Ring {
+    dequeue: u64,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new(pointer: u64) -> Self {
+        Ring {
+            dequeue: pointer & !0xf,
+            cycle: pointer & 1 != 0,
+        }
+    }
+
+    // Returns the next TRB owned by the controller, following the links.
+    fn next(&mut self, mem: &GuestMemoryMmap) -> Option<(u64, Trb)> {
+        for _ in 0..MAX_RING_TRBS {
+            let trb: Trb = mem.read_obj(GuestAddress(self.dequeue)).ok()?;
+            if trb.cycle() != self.cycle {
+                return None;
+            }
+            if trb.trb_type() != TRB_LINK {
+                let addr = self.dequeue;
+                self.dequeue += TRB_SIZE;
+                return Some((addr, trb));
+            }
+            self.dequeue = trb.parameter & !0xf;
+            if trb.control & TRB_LINK_TC != 0 {
+                self.cycle = !self.cycle;
+            }
+        }
+
+        None
+    }
+
+    fn pointer(&self) -> u64 {
+        self.dequeue | self.cycle as u64
+    }
+}
+
+// Producer side of the event ring.
+struct EventRing {
+    base: u64,
+    size: u32,
+    enqueue: u32,
+    cycle: bool,
+}
+
+struct Endpoint {
+    // None for the isochronous endpoints.
+    transfer_type: Option<TransferType>,
+    ring: Ring,
+    state: u32,
+    // Key of the transfer in flight, and the ring position of its first
+    // TRB.
+    inflight: Option<(u64, Ring)>,
+}
+
+struct Slot {
+    port: usize,
+    context: u64,
+    state: u32,
+    endpoints: Vec<Option<Endpoint>>,
+}
+
+// Transfer submitted to a host device.
+struct Transfer {
+    device: usize,
+    slot_id: usize,
+    dci: usize,
+    trbs: Vec<(u64, Trb)>,
+    // Guest buffers receiving the data of an IN transfer, which starts at
+    // `offset` in the transfer buffer.
+    segments: Vec<(u64, u32)>,
+    offset: usize,
+}
+
+#[derive(Versionize)]
+struct RingState {
+    dequeue: u64,
+    cycle: bool,
+}
+
+#[derive(Versionize)]
+struct EventRingState {
+    base: u64,
+    size: u32,
+    enqueue: u32,
+    cycle: bool,
+}
+
+#[derive(Versionize)]
+struct EventState {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+#[derive(Versionize)]
+struct EndpointState {
+    dci: u8,
+    transfer_type: Option<TransferType>,
+    ring: RingState,
+    state: u32,
+}
+
+#[derive(Versionize)]
+struct SlotState {
+    slot_id: u8,
+    port: u8,
+    context: u64,
+    state: u32,
+    endpoints: Vec<EndpointState>,
+}
+
+#[derive(Versionize)]
+pub struct XhciControllerState {
+    usbcmd: u32,
+    usbsts: u32,
+    dnctrl: u32,
+    config: u32,
+    dcbaap: u64,
+    command_ring: RingState,
+    command_ring_running: bool,
+    ports: Vec<u32>,
+    iman: u32,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    event_ring: Option<EventRingState>,
+    pending_events: Vec<EventState>,
+    slots: Vec<SlotState>,
+    doorbells: Vec<u32>,
+}
+
+impl VersionMapped for XhciControllerState {}
+
+impl From<Ring> for RingState {
+    fn from(ring: Ring) -> Self {
+        RingState {
+            dequeue: ring.dequeue,
+            cycle: ring.cycle,
+        }
+    }
+}
+
+impl From<&RingState> for Ring {
+    fn from(state: &RingState) -> Self {
+        Ring {
+            dequeue: state.dequeue,
+            cycle: state.cycle,
+        }
+    }
+}
+
+struct XhciState {
+    id: String,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    // None once the device is unplugged from the host.
+    devices: Vec<Option<UsbHostDevice>>,
+    port_devices: Vec<Option<usize>>,
+    usbcmd: u32,
+    usbsts: u32,
+    dnctrl: u32,
+    config: u32,
+    dcbaap: u64,
+    command_ring: Ring,
+    command_ring_running: bool,
+    ports: Vec<u32>,
+    iman: u32,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    event_ring: Option<EventRing>,
+    // Events waiting for the driver to free some entries.
+    pending_events: VecDeque<Trb>,
+    slots: Vec<Option<Slot>>,
+    // Endpoints whose doorbell was rung, for each slot, the command ring
+    // being the bit 0 of the slot 0.
+    doorbells: Vec<u32>,
+    transfers: HashMap<u64, Transfer>,
+    next_key: u64,
+    start: Instant,
+    // The rings aren't processed, nor the completed transfers reaped, while
+    // the VM is paused.
+    paused: bool,
+}
+
+/// xHCI controller exposed on the PCI bus.
+pub struct XhciController {
+    id: String,
+    configuration: PciConfiguration,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    bar_regions: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
+    state: Arc<Mutex<XhciState>>,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl XhciController {
+    /// Creates a controller with each of the `devices` attached to a root
+    /// hub port.
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        devices: Vec<UsbHostDevice>,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        pci_device_bdf: u32,
+        seccomp_filter: BpfProgram,
+    ) -> Result<Self> {
+        if devices.len() > MAX_USB_DEVICES {
+            return Err(Error::TooManyDevices(devices.len()));
+        }
+
+        let msix_num = NUM_INTERRUPTERS as u16;
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_num as InterruptIndex,
+            })
+            .map_err(Error::CreateInterruptGroup)?;
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            msix_num,
+            interrupt_source_group.clone(),
+            pci_device_bdf,
+        )));
+
+        let mut configuration = PciConfiguration::new(
+            XHCI_VENDOR_ID,
+            XHCI_DEVICE_ID,
+            0,
+            PciClassCode::SerialBusController,
+            &PciSerialBusSubClass::Usb,
+            Some(&XhciProgrammingInterface),
+            PciHeaderType::Device,
+            XHCI_VENDOR_ID,
+            XHCI_DEVICE_ID,
+            Some(msix_config.clone()),
+        );
+        let msix_cap = MsixCap::new(
+            XHCI_BAR_INDEX as u8,
+            msix_num,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            XHCI_BAR_INDEX as u8,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+        configuration
+            .add_capability(&msix_cap)
+            .map_err(|e| Error::CapabilitiesSetup(PciDeviceError::CapabilitiesSetup(e)))?;
+
+        let mut port_devices = vec![None; NUM_PORTS];
+        for (index, device) in devices.iter().enumerate() {
+            let port = match device.speed() {
+                Speed::Super => MAX_USB_DEVICES + index,
+                _ => index,
+            };
+            info!(
+                "{}: Attaching {} to USB port {}",
+                id,
+                device.path().display(),
+                port + 1
+            );
+            port_devices[port] = Some(index);
+        }
+
+        let mut state = XhciState {
+            id: id.clone(),
+            memory,
+            msix_config: msix_config.clone(),
+            interrupt_source_group,
+            devices: devices.into_iter().map(Some).collect(),
+            port_devices,
+            usbcmd: 0,
+            usbsts: 0,
+            dnctrl: 0,
+            config: 0,
+            dcbaap: 0,
+            command_ring: Ring::default(),
+            command_ring_running: false,
+            ports: vec![0; NUM_PORTS],
+            iman: 0,
+            imod: 0,
+            erstsz: 0,
+            erstba: 0,
+            erdp: 0,
+            event_ring: None,
+            pending_events: VecDeque::new(),
+            slots: (0..=MAX_SLOTS).map(|_| None).collect(),
+            doorbells: vec![0; MAX_SLOTS + 1],
+            transfers: HashMap::new(),
+            next_key: 0,
+            start: Instant::now(),
+            paused: false,
+        };
+        state.reset();
+        let state = Arc::new(Mutex::new(state));
+
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+
+        let worker = XhciWorker {
+            state: state.clone(),
+            kick_evt: kick_evt.try_clone().map_err(Error::EventFd)?,
+            kill_evt: kill_evt.try_clone().map_err(Error::EventFd)?,
+        };
+        let thread = thread::Builder::new()
+            .name(format!("{}_xhci", id))
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+                if let Err(e) = worker.run() {
+                    error!("Error running xHCI worker: {:?}", e);
+                }
+            })
+            .map_err(Error::SpawnWorker)?;
+
+        Ok(XhciController {
+            id,
+            configuration,
+            msix_config,
+            bar_regions: Vec::new(),
+            state,
+            kick_evt,
+            kill_evt,
+            worker: Some(thread),
+        })
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let state = self.state.lock().unwrap();
+        match offset {
+            o if o < OPERATIONAL_BAR_OFFSET => read_capability(o),
+            o if o < EXTENDED_CAPS_BAR_OFFSET => state.read_operational(o - OPERATIONAL_BAR_OFFSET),
+            o if o < RUNTIME_BAR_OFFSET => read_extended_capability(o - EXTENDED_CAPS_BAR_OFFSET),
+            o => state.read_runtime(o - RUNTIME_BAR_OFFSET),
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            o if OPERATIONAL_BAR_OFFSET <= o && o < EXTENDED_CAPS_BAR_OFFSET => {
+                if state.write_operational(o - OPERATIONAL_BAR_OFFSET, value) {
+                    self.kick_evt.write(1).ok();
+                }
+            }
+            o if RUNTIME_BAR_OFFSET <= o => state.write_runtime(o - RUNTIME_BAR_OFFSET, value),
+            o => debug!("{}: Ignoring write to xHCI register 0x{:x}", self.id, o),
+        }
+    }
+
+    fn write_doorbell(&mut self, offset: u64, value: u32) {
+        let slot_id = (offset / 4) as usize;
+        let target = value & 0xff;
+        let mut state = self.state.lock().unwrap();
+        if state.usbsts & USBSTS_HCH != 0 {
+            return;
+        }
+
+        match (slot_id, target) {
+            (0, 0) => state.command_ring_running = true,
+            (0, _) => {
+                warn!("{}: Invalid xHCI command doorbell {}", self.id, target);
+                return;
+            }
+            (slot_id, target)
+                if slot_id <= MAX_SLOTS && 0 < target && target < MAX_ENDPOINTS as u32 => {}
+            _ => {
+                warn!(
+                    "{}: Invalid xHCI doorbell {} target {}",
+                    self.id, slot_id, target
+                );
+                return;
+            }
+        }
+        state.doorbells[slot_id] |= 1 << target;
+        self.kick_evt.write(1).ok();
+    }
+}
+
+impl Drop for XhciController {
+    fn drop(&mut self) {
+        self.kill_evt.write(1).ok();
+        if let Some(worker) = self.worker.take() {
+            if let Err(e) = worker.join() {
+                error!("Error joining xHCI worker: {:?}", e);
+            }
+        }
+    }
+}
+
+impl PciDevice for XhciController {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError> {
+        // Like the NVMe controller, a 32-bit BAR keeps the devices easily
+        // reachable from the firmware.
+        let region_type = PciBarRegionType::Memory32BitRegion;
+        let addr = allocator
+            .allocate_mmio_hole_addresses(None, XHCI_BAR_SIZE, Some(XHCI_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(XHCI_BAR_SIZE))?;
+
+        let config = PciBarConfiguration::default()
+            .set_register_index(XHCI_BAR_INDEX)
+            .set_address(addr.raw_value())
+            .set_size(XHCI_BAR_SIZE)
+            .set_region_type(region_type);
+        self.configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?;
+
+        self.bar_regions.push((addr, XHCI_BAR_SIZE, region_type));
+        Ok(self.bar_regions.clone())
+    }
+
+    fn free_bars(&mut self, allocator: &mut SystemAllocator) -> result::Result<(), PciDeviceError> {
+        for (addr, length, _) in self.bar_regions.drain(..) {
+            allocator.free_mmio_hole_addresses(addr, length);
+        }
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), io::Error> {
+        for (addr, _, _) in self.bar_regions.iter_mut() {
+            if addr.raw_value() == old_base {
+                *addr = GuestAddress(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < DOORBELL_BAR_OFFSET => {
+                // The capability registers are commonly read with byte and
+                // word accesses.
+                let shift = (o % 4) as usize;
+                if shift + data.len() > 8 {
+                    warn!(
+                        "{}: Invalid xHCI register read: offset 0x{:x} length {}",
+                        self.id,
+                        o,
+                        data.len()
+                    );
+                    return;
+                }
+                let mut bytes = [0u8; 8];
+                for i in 0..(shift + data.len() + 3) / 4 {
+                    let value = self.read_register(o - shift as u64 + 4 * i as u64);
+                    bytes[4 * i..4 * i + 4].copy_from_slice(&value.to_le_bytes());
+                }
+                data.copy_from_slice(&bytes[shift..shift + data.len()]);
+            }
+            o if o < DOORBELL_BAR_OFFSET + DOORBELL_SIZE => {
+                // Doorbells read as zero.
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+            }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .read_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
+            _ => (),
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match offset {
+            o if o < DOORBELL_BAR_OFFSET + DOORBELL_SIZE => {
+                if o % 4 != 0 || (data.len() != 4 && data.len() != 8) {
+                    warn!(
+                        "{}: Invalid xHCI register write: offset 0x{:x} length {}",
+                        self.id,
+                        o,
+                        data.len()
+                    );
+                    return None;
+                }
+                for (i, chunk) in data.chunks(4).enumerate() {
+                    let offset = o + 4 * i as u64;
+                    let mut value = [0u8; 4];
+                    value.copy_from_slice(chunk);
+                    let value = u32::from_le_bytes(value);
+                    if offset < DOORBELL_BAR_OFFSET {
+                        self.write_register(offset, value);
+                    } else {
+                        self.write_doorbell(offset - DOORBELL_BAR_OFFSET, value);
+                    }
+                }
+            }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_table(o - MSIX_TABLE_BAR_OFFSET, data);
+            }
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => {
+                self.msix_config
+                    .lock()
+                    .unwrap()
+                    .write_pba(o - MSIX_PBA_BAR_OFFSET, data);
+            }
+            _ => (),
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl BusDevice for XhciController {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl Pausable for XhciController {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        // The worker only touches the rings with the state locked, it is
+        // done with them once the state is marked as paused.
+        self.state.lock().unwrap().paused = true;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            state.paused = false;
+            self.kick_evt.write(1).ok();
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshottable for XhciController {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let state = self.state.lock().unwrap();
+        if !state.paused {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "xHCI controller {} is not paused",
+                self.id
+            )));
+        }
+        let mut snapshot = Snapshot::new_from_versioned_state(&self.id, &state.state())?;
+        drop(state);
+        snapshot.add_snapshot(self.configuration.snapshot()?);
+        snapshot.add_snapshot(self.msix_config.lock().unwrap().snapshot()?);
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let msix_id = self.msix_config.lock().unwrap().id();
+        if let Some(msix_snapshot) = snapshot.snapshots.get(&msix_id) {
+            self.msix_config
+                .lock()
+                .unwrap()
+                .restore(*msix_snapshot.clone())?;
+        }
+        if let Some(pci_config_snapshot) = snapshot.snapshots.get(&self.configuration.id()) {
+            self.configuration.restore(*pci_config_snapshot.clone())?;
+        }
+
+        let state: XhciControllerState = snapshot.to_versioned_state(&self.id)?;
+        let mut xhci_state = self.state.lock().unwrap();
+        xhci_state.set_state(&state)?;
+        // The rings are processed again once the VM is resumed.
+        xhci_state.paused = true;
+
+        Ok(())
+    }
+}
+
+impl Transportable for XhciController {}
+impl Migratable for XhciController {}
+
+fn read_capability(offset: u64) -> u32 {
+    match offset {
+        REG_CAPLENGTH => XHCI_VERSION_1_0 << 16 | OPERATIONAL_BAR_OFFSET as u32,
+        REG_HCSPARAMS1 => MAX_SLOTS as u32 | NUM_INTERRUPTERS << 8 | (NUM_PORTS as u32) << 24,
+        REG_HCCPARAMS1 => HCCPARAMS1_AC64 | ((EXTENDED_CAPS_BAR_OFFSET / 4) as u32) << 16,
+        REG_DBOFF => DOORBELL_BAR_OFFSET as u32,
+        REG_RTSOFF => RUNTIME_BAR_OFFSET as u32,
+        _ => 0,
+    }
+}
+
+// Supported Protocol capabilities of the USB 2 and USB 3 ports.
+fn read_extended_capability(offset: u64) -> u32 {
+    const SUPPORTED_PROTOCOL: u32 = 2;
+    const NAME_STRING: u32 = 0x2042_5355; // "USB "
+    const USB2_PORT: u32 = 1;
+    const USB3_PORT: u32 = USB2_PORT + MAX_USB_DEVICES as u32;
+
+    match offset {
+        0x00 => SUPPORTED_PROTOCOL | 4 << 8 | 0x02 << 24,
+        0x04 | 0x14 => NAME_STRING,
+        0x08 => USB2_PORT | (MAX_USB_DEVICES as u32) << 8,
+        0x10 => SUPPORTED_PROTOCOL | 0x03 << 24,
+        0x18 => USB3_PORT | (MAX_USB_DEVICES as u32) << 8,
+        _ => 0,
+    }
+}
+
+fn set_low(register: u64, value: u32) -> u64 {
+    (register & !0xffff_ffff) | value as u64
+}
+
+fn set_high(register: u64, value: u32) -> u64 {
+    (register & 0xffff_ffff) | (value as u64) << 32
+}
+
+fn completion_code(status: i32) -> u32 {
+    match -status {
+        0 => CC_SUCCESS,
+        libc::EPIPE => CC_STALL,
+        libc::EOVERFLOW => CC_BABBLE_DETECTED,
+        _ => CC_USB_TRANSACTION_ERROR,
+    }
+}
+
+impl XhciState {
+    // The transfers in flight are left to the host devices, their endpoint
+    // being saved as if they hadn't been submitted yet, with its doorbell
+    // rung for them to be submitted again.
+    fn state(&self) -> XhciControllerState {
+        let mut doorbells = self.doorbells.clone();
+        let mut slots = Vec::new();
+        for (slot_id, slot) in self.slots.iter().enumerate() {
+            let slot = match slot {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let mut endpoints = Vec::new();
+            for (dci, endpoint) in slot.endpoints.iter().enumerate() {
+                let endpoint = match endpoint {
+                    Some(endpoint) => endpoint,
+                    None => continue,
+                };
+                let ring = match endpoint.inflight {
+                    Some((_, start)) => {
+                        doorbells[slot_id] |= 1 << dci;
+                        start
+                    }
+                    None => endpoint.ring,
+                };
+                endpoints.push(EndpointState {
+                    dci: dci as u8,
+                    transfer_type: endpoint.transfer_type,
+                    ring: ring.into(),
+                    state: endpoint.state,
+                });
+            }
+            slots.push(SlotState {
+                slot_id: slot_id as u8,
+                port: slot.port as u8,
+                context: slot.context,
+                state: slot.state,
+                endpoints,
+            });
+        }
+
+        XhciControllerState {
+            usbcmd: self.usbcmd,
+            usbsts: self.usbsts,
+            dnctrl: self.dnctrl,
+            config: self.config,
+            dcbaap: self.dcbaap,
+            command_ring: self.command_ring.into(),
+            command_ring_running: self.command_ring_running,
+            ports: self.ports.clone(),
+            iman: self.iman,
+            imod: self.imod,
+            erstsz: self.erstsz,
+            erstba: self.erstba,
+            erdp: self.erdp,
+            event_ring: self.event_ring.as_ref().map(|ring| EventRingState {
+                base: ring.base,
+                size: ring.size,
+                enqueue: ring.enqueue,
+                cycle: ring.cycle,
+            }),
+            pending_events: self
+                .pending_events
+                .iter()
+                .map(|event| EventState {
+                    parameter: event.parameter,
+                    status: event.status,
+                    control: event.control,
+                })
+                .collect(),
+            slots,
+            doorbells,
+        }
+    }
+
+    fn set_state(&mut self, state: &XhciControllerState) -> result::Result<(), MigratableError> {
+        let valid = state.ports.len() == NUM_PORTS
+            && state.doorbells.len() == MAX_SLOTS + 1
+            && state.pending_events.len() <= MAX_PENDING_EVENTS
+            && state.slots.iter().all(|slot| {
+                (1..=MAX_SLOTS).contains(&(slot.slot_id as usize))
+                    && slot.port as usize <= NUM_PORTS
+                    && slot
+                        .endpoints
+                        .iter()
+                        .all(|endpoint| (1..MAX_ENDPOINTS).contains(&(endpoint.dci as usize)))
+            });
+        if !valid {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid state for xHCI controller {}",
+                self.id
+            )));
+        }
+
+        self.reset();
+        self.usbcmd = state.usbcmd;
+        self.usbsts = state.usbsts;
+        self.dnctrl = state.dnctrl;
+        self.config = state.config;
+        self.dcbaap = state.dcbaap;
+        self.command_ring = (&state.command_ring).into();
+        self.command_ring_running = state.command_ring_running;
+        self.ports = state.ports.clone();
+        self.iman = state.iman;
+        self.imod = state.imod;
+        self.erstsz = state.erstsz;
+        self.erstba = state.erstba;
+        self.erdp = state.erdp;
+        self.event_ring = state.event_ring.as_ref().map(|ring| EventRing {
+            base: ring.base,
+            size: ring.size,
+            enqueue: ring.enqueue,
+            cycle: ring.cycle,
+        });
+        self.pending_events = state
+            .pending_events
+            .iter()
+            .map(|event| Trb {
+                parameter: event.parameter,
+                status: event.status,
+                control: event.control,
+            })
+            .collect();
+        for slot in state.slots.iter() {
+            let mut endpoints: Vec<Option<Endpoint>> = (0..MAX_ENDPOINTS).map(|_| None).collect();
+            for endpoint in slot.endpoints.iter() {
+                endpoints[endpoint.dci as usize] = Some(Endpoint {
+                    transfer_type: endpoint.transfer_type,
+                    ring: (&endpoint.ring).into(),
+                    state: endpoint.state,
+                    inflight: None,
+                });
+            }
+            self.slots[slot.slot_id as usize] = Some(Slot {
+                port: slot.port as usize,
+                context: slot.context,
+                state: slot.state,
+                endpoints,
+            });
+        }
+        self.doorbells = state.doorbells.clone();
+
+        Ok(())
+    }
+
+    fn read_operational(&self, offset: u64) -> u32 {
+        match offset {
+            REG_USBCMD => self.usbcmd,
+            REG_USBSTS => self.usbsts,
+            REG_PAGESIZE => 1,
+            REG_DNCTRL => self.dnctrl,
+            REG_CRCR if self.command_ring_running => CRCR_CRR,
+            REG_DCBAAP => self.dcbaap as u32,
+            o if o == REG_DCBAAP + 4 => (self.dcbaap >> 32) as u32,
+            REG_CONFIG => self.config,
+            o if o >= PORT_REGS_OFFSET => {
+                let port = ((o - PORT_REGS_OFFSET) / PORT_REGS_SIZE) as usize;
+                match self.ports.get(port) {
+                    Some(portsc) if (o - PORT_REGS_OFFSET) % PORT_REGS_SIZE == 0 => *portsc,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    // Returns whether the worker needs to be woken up.
+    fn write_operational(&mut self, offset: u64, value: u32) -> bool {
+        match offset {
+            REG_USBCMD => return self.write_usbcmd(value),
+            REG_USBSTS => self.usbsts &= !(value & USBSTS_RW1C),
+            REG_DNCTRL => self.dnctrl = value,
+            REG_CRCR => self.write_crcr(value as u64),
+            o if o == REG_CRCR + 4 && !self.command_ring_running => {
+                self.command_ring.dequeue = set_high(self.command_ring.dequeue, value)
+            }
+            REG_DCBAAP => self.dcbaap = set_low(self.dcbaap, value & !0x3f),
+            o if o == REG_DCBAAP + 4 => self.dcbaap = set_high(self.dcbaap, value),
+            REG_CONFIG => self.config = value & 0xff,
+            o if o >= PORT_REGS_OFFSET => {
+                let port = ((o - PORT_REGS_OFFSET) / PORT_REGS_SIZE) as usize;
+                if port < NUM_PORTS && (o - PORT_REGS_OFFSET) % PORT_REGS_SIZE == 0 {
+                    self.write_portsc(port, value);
+                }
+            }
+            o => debug!("{}: Ignoring write to xHCI register 0x{:x}", self.id, o),
+        }
+
+        false
+    }
+
+    fn write_usbcmd(&mut self, value: u32) -> bool {
+        if value & USBCMD_HCRST != 0 {
+            // The transfers in flight are cancelled from the worker, which
+            // clears the bit once done.
+            self.usbcmd = USBCMD_HCRST;
+            self.usbsts |= USBSTS_CNR;
+            return true;
+        }
+
+        let old = self.usbcmd;
+        self.usbcmd = value;
+        if old & USBCMD_RS == 0 && value & USBCMD_RS != 0 {
+            self.usbsts &= !USBSTS_HCH;
+        } else if old & USBCMD_RS != 0 && value & USBCMD_RS == 0 {
+            self.usbsts |= USBSTS_HCH;
+            self.command_ring_running = false;
+        }
+
+        false
+    }
+
+    fn write_crcr(&mut self, value: u64) {
+        if !self.command_ring_running {
+            let high = self.command_ring.dequeue & !0xffff_ffff;
+            self.command_ring = Ring::new(high | (value & !0x3f) | (value & CRCR_RCS));
+        } else if value & (CRCR_CS | CRCR_CA) != 0 {
+            self.command_ring_running = false;
+            let pointer = self.command_ring.dequeue;
+            self.post_command_completion(pointer, CC_COMMAND_RING_STOPPED, 0);
+        }
+    }
+
+    fn read_runtime(&self, offset: u64) -> u32 {
+        match offset {
+            REG_MFINDEX => (self.start.elapsed().as_micros() / 125) as u32 & 0x3fff,
+            REG_IMAN => self.iman,
+            REG_IMOD => self.imod,
+            REG_ERSTSZ => self.erstsz,
+            REG_ERSTBA => self.erstba as u32,
+            o if o == REG_ERSTBA + 4 => (self.erstba >> 32) as u32,
+            REG_ERDP => self.erdp as u32,
+            o if o == REG_ERDP + 4 => (self.erdp >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_runtime(&mut self, offset: u64, value: u32) {
+        match offset {
+            REG_IMAN => {
+                self.iman = (self.iman & !IMAN_IE) | (value & IMAN_IE);
+                self.iman &= !(value & IMAN_IP);
+            }
+            REG_IMOD => self.imod = value,
+            REG_ERSTSZ => self.erstsz = value & 0xffff,
+            REG_ERSTBA => {
+                self.erstba = set_low(self.erstba, value & !0x3f);
+                self.setup_event_ring();
+            }
+            o if o == REG_ERSTBA + 4 => {
+                self.erstba = set_high(self.erstba, value);
+                self.setup_event_ring();
+            }
+            REG_ERDP => {
+                // The event handler busy bit is cleared by writing it.
+                let ehb = if value as u64 & ERDP_EHB != 0 {
+                    0
+                } else {
+                    self.erdp & ERDP_EHB
+                };
+                self.erdp = set_low(self.erdp, value & !0xf) | ehb;
+                self.update_event_ring();
+            }
+            o if o == REG_ERDP + 4 => {
+                self.erdp = set_high(self.erdp, value);
+                self.update_event_ring();
+            }
+            o => debug!("{}: Ignoring write to xHCI register 0x{:x}", self.id, o),
+        }
+    }
+
+    fn write_portsc(&mut self, port: usize, value: u32) {
+        let old = self.ports[port];
+        let mut portsc = old & !(value & PORTSC_CHANGE_MASK);
+        portsc = (portsc & !PORTSC_RW_MASK) | (value & PORTSC_RW_MASK);
+        if value & PORTSC_PED != 0 {
+            portsc &= !PORTSC_PED;
+        }
+
+        if value & PORTSC_LWS != 0 {
+            let old_pls = (old & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT;
+            let pls = match (value & PORTSC_PLS_MASK) >> PORTSC_PLS_SHIFT {
+                PLS_RESUME => PLS_U0,
+                pls => pls,
+            };
+            portsc = (portsc & !PORTSC_PLS_MASK) | pls << PORTSC_PLS_SHIFT;
+            if old_pls == PLS_U3 && pls == PLS_U0 {
+                portsc |= PORTSC_PLC;
+            }
+        }
+
+        // The host device isn't reset along with the port, its configuration
+        // being reset once the guest selects one.
+        if value & (PORTSC_PR | PORTSC_WPR) != 0 && portsc & PORTSC_CCS != 0 {
+            portsc = (portsc & !PORTSC_PLS_MASK) | PLS_U0 << PORTSC_PLS_SHIFT;
+            portsc |= PORTSC_PED | PORTSC_PRC;
+            if value & PORTSC_WPR != 0 && port >= MAX_USB_DEVICES {
+                portsc |= PORTSC_WRC;
+            }
+        }
+
+        self.ports[port] = portsc;
+        self.port_changed(port, old);
+    }
+
+    fn port_changed(&mut self, port: usize, old: u32) {
+        let changes = self.ports[port] & PORTSC_CHANGE_MASK;
+        if changes & !(old & PORTSC_CHANGE_MASK) == 0 {
+            return;
+        }
+
+        self.usbsts |= USBSTS_PCD;
+        self.post_event(Trb {
+            parameter: ((port + 1) as u64) << 24,
+            status: CC_SUCCESS << 24,
+            control: TRB_PORT_STATUS_CHANGE_EVENT << TRB_TYPE_SHIFT,
+        });
+    }
+
+    fn connect_port(&mut self, port: usize) {
+        let device = match self.port_devices[port].and_then(|i| self.devices[i].as_ref()) {
+            Some(device) => device,
+            None => {
+                self.ports[port] = PORTSC_PP | PLS_RX_DETECT << PORTSC_PLS_SHIFT;
+                return;
+            }
+        };
+
+        let speed = match device.speed() {
+            Speed::Low => SPEED_LOW,
+            Speed::Full => SPEED_FULL,
+            Speed::High => SPEED_HIGH,
+            Speed::Super => SPEED_SUPER,
+        };
+        // The USB 3 ports are enabled as soon as the link is trained, while
+        // the USB 2 ones wait for a reset.
+        let (ped, pls) = if speed == SPEED_SUPER {
+            (PORTSC_PED, PLS_U0)
+        } else {
+            (0, PLS_POLLING)
+        };
+        self.ports[port] = PORTSC_CCS
+            | ped
+            | pls << PORTSC_PLS_SHIFT
+            | PORTSC_PP
+            | speed << PORTSC_SPEED_SHIFT
+            | PORTSC_CSC;
+    }
+
+    fn reset(&mut self) {
+        for key in self.transfers.keys().copied().collect::<Vec<u64>>() {
+            self.cancel_transfer(key);
+        }
+        for slot in self.slots.iter_mut() {
+            *slot = None;
+        }
+        for doorbell in self.doorbells.iter_mut() {
+            *doorbell = 0;
+        }
+
+        self.usbcmd = 0;
+        self.usbsts = USBSTS_HCH;
+        self.dnctrl = 0;
+        self.config = 0;
+        self.dcbaap = 0;
+        self.command_ring = Ring::default();
+        self.command_ring_running = false;
+        self.iman = 0;
+        self.imod = IMOD_DEFAULT;
+        self.erstsz = 0;
+        self.erstba = 0;
+        self.erdp = 0;
+        self.event_ring = None;
+        self.pending_events.clear();
+
+        for port in 0..NUM_PORTS {
+            self.connect_port(port);
+        }
+    }
+
+    fn setup_event_ring(&mut self) {
+        self.event_ring = None;
+        // Only the first segment of the table is used.
+        let mem = self.memory.memory();
+        let base: u64 = match mem.read_obj(GuestAddress(self.erstba)) {
+            Ok(base) => base,
+            Err(_) => return,
+        };
+        let size: u32 = match mem.read_obj(GuestAddress(self.erstba + 8)) {
+            Ok(size) => size & 0xffff,
+            Err(_) => return,
+        };
+        if base == 0 || size < MIN_EVENT_RING_SIZE || size > MAX_EVENT_RING_SIZE {
+            return;
+        }
+
+        self.event_ring = Some(EventRing {
+            base: base & !0x3f,
+            size,
+            enqueue: 0,
+            cycle: true,
+        });
+    }
+
+    // Posts the events the driver made room for, and interrupts it again if
+    // it left some unprocessed.
+    fn update_event_ring(&mut self) {
+        self.flush_events();
+
+        let unprocessed = match &self.event_ring {
+            Some(ring) => ring.base + ring.enqueue as u64 * TRB_SIZE != self.erdp & !0xf,
+            None => false,
+        };
+        if unprocessed {
+            self.interrupt();
+        }
+    }
+
+    fn post_event(&mut self, event: Trb) {
+        if self.usbsts & USBSTS_HCH != 0 {
+            return;
+        }
+
+        // The driver is no longer consuming the events, which is reported
+        // as a host controller error for it to reset the controller.
+        if self.pending_events.len() >= MAX_PENDING_EVENTS {
+            if self.usbsts & USBSTS_HSE == 0 {
+                error!("{}: Too many xHCI events pending", self.id);
+                self.usbsts |= USBSTS_HSE;
+            }
+            return;
+        }
+
+        self.pending_events.push_back(event);
+        self.flush_events();
+    }
+
+    fn flush_events(&mut self) {
+        let ring = match self.event_ring.as_mut() {
+            Some(ring) => ring,
+            None => return,
+        };
+
+        let mem = self.memory.memory();
+        let dequeue = self.erdp & !0xf;
+        let mut posted = false;
+        while let Some(mut event) = self.pending_events.front().copied() {
+            // The ring is full when only one entry is left.
+            let next = (ring.enqueue + 1) % ring.size;
+            if ring.base + next as u64 * TRB_SIZE == dequeue {
+                break;
+            }
+
+            event.control = (event.control & !TRB_CYCLE) | ring.cycle as u32;
+            let addr = GuestAddress(ring.base + ring.enqueue as u64 * TRB_SIZE);
+            if let Err(e) = mem.write_obj(event, addr) {
+                error!("{}: Failed writing xHCI event: {}", self.id, e);
+                self.usbsts |= USBSTS_HSE;
+                return;
+            }
+            self.pending_events.pop_front();
+            ring.enqueue = next;
+            if next == 0 {
+                ring.cycle = !ring.cycle;
+            }
+            posted = true;
+        }
+
+        if posted {
+            self.interrupt();
+        }
+    }
+
+    fn interrupt(&mut self) {
+        self.usbsts |= USBSTS_EINT;
+        // No interrupt is sent until the driver is done handling the events
+        // of the previous one.
+        if self.iman & IMAN_IE == 0 || self.erdp & ERDP_EHB != 0 {
+            return;
+        }
+        self.iman |= IMAN_IP;
+        self.erdp |= ERDP_EHB;
+        if self.usbcmd & USBCMD_INTE != 0 {
+            self.signal(0);
+        }
+    }
+
+    fn signal(&self, vector: u16) {
+        let mut config = self.msix_config.lock().unwrap();
+        if !config.enabled() {
+            return;
+        }
+        // A masked vector must not be injected, its pending bit is set
+        // instead.
+        if config.masked() || config.table_entries[vector as usize].masked() {
+            config.set_pba_bit(vector, false);
+            return;
+        }
+
+        if let Err(e) = self
+            .interrupt_source_group
+            .trigger(vector as InterruptIndex)
+        {
+            error!("{}: Failed signalling xHCI interrupt: {}", self.id, e);
+        }
+    }
+
+    fn post_command_completion(&mut self, pointer: u64, code: u32, slot_id: usize) {
+        self.post_event(Trb {
+            parameter: pointer,
+            status: code << 24,
+            control: TRB_COMMAND_COMPLETION_EVENT << TRB_TYPE_SHIFT | (slot_id as u32) << 24,
+        });
+    }
+
+    fn post_transfer_event(
+        &mut self,
+        slot_id: usize,
+        dci: usize,
+        pointer: u64,
+        code: u32,
+        residual: u32,
+        event_data: bool,
+    ) {
+        let mut control =
+            TRB_TRANSFER_EVENT << TRB_TYPE_SHIFT | (dci as u32) << 16 | (slot_id as u32) << 24;
+        if event_data {
+            control |= TRB_ED;
+        }
+        self.post_event(Trb {
+            parameter: pointer,
+            status: code << 24 | (residual & 0xff_ffff),
+            control,
+        });
+    }
+
+    fn process_doorbells(&mut self) {
+        if self.usbcmd & USBCMD_HCRST != 0 {
+            self.reset();
+            return;
+        }
+        if self.usbsts & USBSTS_HCH != 0 {
+            return;
+        }
+
+        if self.doorbells[0] != 0 {
+            self.doorbells[0] = 0;
+            self.process_commands();
+        }
+        for slot_id in 1..=MAX_SLOTS {
+            let doorbells = std::mem::replace(&mut self.doorbells[slot_id], 0);
+            for dci in 1..MAX_ENDPOINTS {
+                if doorbells & (1 << dci) == 0 {
+                    continue;
+                }
+                // Ringing the doorbell restarts a stopped endpoint.
+                if let Some(endpoint) = self.endpoint_mut(slot_id, dci) {
+                    if endpoint.state == EP_STATE_STOPPED {
+                        endpoint.state = EP_STATE_RUNNING;
+                    }
+                }
+                self.process_endpoint(slot_id, dci);
+            }
+        }
+    }
+
+    fn process_commands(&mut self) {
+        let mem = self.memory.memory();
+        for _ in 0..MAX_RING_TRBS {
+            if !self.command_ring_running {
+                return;
+            }
+            let (addr, trb) = match self.command_ring.next(&mem) {
+                Some(command) => command,
+                None => return,
+            };
+
+            let slot_id = trb.slot_id();
+            let (code, slot_id) = match trb.trb_type() {
+                TRB_ENABLE_SLOT => self.enable_slot(),
+                TRB_DISABLE_SLOT => (self.disable_slot(slot_id), slot_id),
+                TRB_ADDRESS_DEVICE => (self.address_device(&trb), slot_id),
+                TRB_CONFIGURE_ENDPOINT => (self.configure_endpoint(&trb), slot_id),
+                TRB_EVALUATE_CONTEXT => (self.evaluate_context(&trb), slot_id),
+                TRB_RESET_ENDPOINT => (self.reset_endpoint(&trb), slot_id),
+                TRB_STOP_ENDPOINT => (self.stop_endpoint(&trb), slot_id),
+                TRB_SET_TR_DEQUEUE => (self.set_tr_dequeue(&trb), slot_id),
+                TRB_RESET_DEVICE => (self.reset_device(slot_id), slot_id),
+                TRB_NOOP_COMMAND => (CC_SUCCESS, 0),
+                trb_type => {
+                    debug!("{}: Unsupported xHCI command {}", self.id, trb_type);
+                    (CC_TRB_ERROR, 0)
+                }
+            };
+            self.post_command_completion(addr, code, slot_id);
+        }
+    }
+
+    fn slot_mut(&mut self, slot_id: usize) -> Option<&mut Slot> {
+        self.slots.get_mut(slot_id).and_then(|slot| slot.as_mut())
+    }
+
+    fn endpoint_mut(&mut self, slot_id: usize, dci: usize) -> Option<&mut Endpoint> {
+        self.slot_mut(slot_id)
+            .and_then(|slot| slot.endpoints.get_mut(dci))
+            .and_then(|endpoint| endpoint.as_mut())
+    }
+
+    fn read_context(&self, addr: u64) -> Option<Context> {
+        self.memory.memory().read_obj(GuestAddress(addr)).ok()
+    }
+
+    fn write_context(&self, addr: u64, context: Context) {
+        if let Err(e) = self.memory.memory().write_obj(context, GuestAddress(addr)) {
+            error!("{}: Failed writing xHCI context: {}", self.id, e);
+        }
+    }
+
+    fn write_slot_state(&mut self, slot_id: usize, state: u32, address: u32) {
+        let slot = match self.slot_mut(slot_id) {
+            Some(slot) => slot,
+            None => return,
+        };
+        slot.state = state;
+        // The slot has no output context until its device is addressed.
+        let addr = slot.context;
+        if addr == 0 {
+            return;
+        }
+        let entries = (1..MAX_ENDPOINTS)
+            .rev()
+            .find(|dci| slot.endpoints[*dci].is_some())
+            .unwrap_or(1) as u32;
+
+        if let Some(mut context) = self.read_context(addr) {
+            context.dwords[0] = (context.dwords[0] & !(0x1f << CONTEXT_ENTRIES_SHIFT))
+                | entries << CONTEXT_ENTRIES_SHIFT;
+            context.dwords[3] = state << SLOT_STATE_SHIFT | address;
+            self.write_context(addr, context);
+        }
+    }
+
+    // Reflects the state and the dequeue pointer of the endpoint in its
+    // output context.
+    fn write_endpoint_state(&mut self, slot_id: usize, dci: usize) {
+        let addr = match self.slot_mut(slot_id) {
+            Some(slot) if slot.context != 0 => slot.context + dci as u64 * CONTEXT_SIZE,
+            _ => return,
+        };
+        let (state, pointer) = match self.endpoint_mut(slot_id, dci) {
+            Some(endpoint) => (endpoint.state, endpoint.ring.pointer()),
+            None => (EP_STATE_DISABLED, 0),
+        };
+
+        if let Some(mut context) = self.read_context(addr) {
+            context.dwords[0] = (context.dwords[0] & !EP_STATE_MASK) | state;
+            if state != EP_STATE_DISABLED {
+                context.dwords[2] = pointer as u32;
+                context.dwords[3] = (pointer >> 32) as u32;
+            }
+            self.write_context(addr, context);
+        }
+    }
+
+    fn enable_slot(&mut self) -> (u32, usize) {
+        let max_slots = std::cmp::min(self.config as usize, MAX_SLOTS);
+        let slot_id = match (1..=max_slots).find(|id| self.slots[*id].is_none()) {
+            Some(slot_id) => slot_id,
+            None => return (CC_NO_SLOTS_AVAILABLE, 0),
+        };
+
+        self.slots[slot_id] = Some(Slot {
+            port: 0,
+            context: 0,
+            state: SLOT_STATE_ENABLED,
+            endpoints: (0..MAX_ENDPOINTS).map(|_| None).collect(),
+        });
+        (CC_SUCCESS, slot_id)
+    }
+
+    fn disable_slot(&mut self, slot_id: usize) -> u32 {
+        if self.slot_mut(slot_id).is_none() {
+            return CC_SLOT_NOT_ENABLED;
+        }
+
+        for dci in 1..MAX_ENDPOINTS {
+            self.disable_endpoint(slot_id, dci);
+        }
+        self.slots[slot_id] = None;
+        CC_SUCCESS
+    }
+
+    fn address_device(&mut self, trb: &Trb) -> u32 {
+        let slot_id = trb.slot_id();
+        match self.slot_mut(slot_id) {
+            Some(slot) if slot.state < SLOT_STATE_ADDRESSED => (),
+            Some(_) => return CC_CONTEXT_STATE_ERROR,
+            None => return CC_SLOT_NOT_ENABLED,
+        }
+
+        let input = trb.parameter & !0xf;
+        let (control, slot_context, ep0_context) = match (
+            self.read_context(input),
+            self.read_context(input + CONTEXT_SIZE),
+            self.read_context(input + 2 * CONTEXT_SIZE),
+        ) {
+            (Some(control), Some(slot), Some(ep0)) => (control, slot, ep0),
+            _ => return CC_TRB_ERROR,
+        };
+        // The slot and the default control endpoint must both be added.
+        if control.dwords[1] & 0x3 != 0x3 {
+            return CC_PARAMETER_ERROR;
+        }
+
+        let port = ((slot_context.dwords[1] >> 16) & 0xff) as usize;
+        let connected = port > 0
+            && port <= NUM_PORTS
+            && self.ports[port - 1] & PORTSC_PED != 0
+            && self.port_devices[port - 1]
+                .and_then(|i| self.devices[i].as_ref())
+                .is_some();
+        if !connected {
+            return CC_USB_TRANSACTION_ERROR;
+        }
+
+        let context: u64 = match self
+            .memory
+            .memory()
+            .read_obj(GuestAddress(self.dcbaap + slot_id as u64 * 8))
+        {
+            Ok(context) => context,
+            Err(_) => return CC_TRB_ERROR,
+        };
+
+        // The host device already has an address, the one given to the
+        // guest is the slot identifier.
+        let (state, address) = if trb.control & TRB_BSR != 0 {
+            (SLOT_STATE_DEFAULT, 0)
+        } else {
+            (SLOT_STATE_ADDRESSED, slot_id as u32)
+        };
+        let mut ep0_context = ep0_context;
+        ep0_context.dwords[0] = (ep0_context.dwords[0] & !EP_STATE_MASK) | EP_STATE_RUNNING;
+        let ring = Ring::new(ep0_context.dwords[2] as u64 | (ep0_context.dwords[3] as u64) << 32);
+
+        if let Some(slot) = self.slot_mut(slot_id) {
+            slot.port = port;
+            slot.context = context;
+            slot.endpoints[1] = Some(Endpoint {
+                transfer_type: Some(TransferType::Control),
+                ring,
+                state: EP_STATE_RUNNING,
+                inflight: None,
+            });
+        }
+        self.write_context(context, slot_context);
+        self.write_context(context + CONTEXT_SIZE, ep0_context);
+        self.write_slot_state(slot_id, state, address);
+        CC_SUCCESS
+    }
+
+    fn configure_endpoint(&mut self, trb: &Trb) -> u32 {
+        let slot_id = trb.slot_id();
+        let context = match self.slot_mut(slot_id) {
+            Some(slot) if slot.state >= SLOT_STATE_ADDRESSED => slot.context,
+            Some(_) => return CC_CONTEXT_STATE_ERROR,
+            None => return CC_SLOT_NOT_ENABLED,
+        };
+
+        if trb.control & TRB_DC != 0 {
+            for dci in 2..MAX_ENDPOINTS {
+                self.disable_endpoint(slot_id, dci);
+            }
+            self.write_slot_state(slot_id, SLOT_STATE_ADDRESSED, slot_id as u32);
+            return CC_SUCCESS;
+        }
+
+        let input = trb.parameter & !0xf;
+        let control = match self.read_context(input) {
+            Some(control) => control,
+            None => return CC_TRB_ERROR,
+        };
+        let (drop_flags, add_flags) = (control.dwords[0], control.dwords[1]);
+
+        for dci in 2..MAX_ENDPOINTS {
+            if drop_flags & (1 << dci) != 0 {
+                self.disable_endpoint(slot_id, dci);
+            }
+            if add_flags & (1 << dci) == 0 {
+                continue;
+            }
+
+            let addr = input + (dci as u64 + 1) * CONTEXT_SIZE;
+            let mut ep_context = match self.read_context(addr) {
+                Some(ep_context) => ep_context,
+                None => return CC_TRB_ERROR,
+            };
+            let transfer_type = match (ep_context.dwords[1] >> 3) & 0x7 {
+                2 | 6 => Some(TransferType::Bulk),
+                3 | 7 => Some(TransferType::Interrupt),
+                4 => Some(TransferType::Control),
+                _ => None,
+            };
+            let ring = Ring::new(ep_context.dwords[2] as u64 | (ep_context.dwords[3] as u64) << 32);
+
+            self.disable_endpoint(slot_id, dci);
+            if let Some(slot) = self.slot_mut(slot_id) {
+                slot.endpoints[dci] = Some(Endpoint {
+                    transfer_type,
+                    ring,
+                    state: EP_STATE_RUNNING,
+                    inflight: None,
+                });
+            }
+            ep_context.dwords[0] = (ep_context.dwords[0] & !EP_STATE_MASK) | EP_STATE_RUNNING;
+            self.write_context(context + dci as u64 * CONTEXT_SIZE, ep_context);
+        }
+
+        let configured = self.slot_mut(slot_id).map_or(false, |slot| {
+            slot.endpoints[2..].iter().any(|ep| ep.is_some())
+        });
+        let state = if configured {
+            SLOT_STATE_CONFIGURED
+        } else {
+            SLOT_STATE_ADDRESSED
+        };
+        self.write_slot_state(slot_id, state, slot_id as u32);
+        CC_SUCCESS
+    }
+
+    fn evaluate_context(&mut self, trb: &Trb) -> u32 {
+        let slot_id = trb.slot_id();
+        let context = match self.slot_mut(slot_id) {
+            Some(slot) => slot.context,
+            None => return CC_SLOT_NOT_ENABLED,
+        };
+
+        let input = trb.parameter & !0xf;
+        let (control, slot_input, ep0_input) = match (
+            self.read_context(input),
+            self.read_context(input + CONTEXT_SIZE),
+            self.read_context(input + 2 * CONTEXT_SIZE),
+        ) {
+            (Some(control), Some(slot), Some(ep0)) => (control, slot, ep0),
+            _ => return CC_TRB_ERROR,
+        };
+
+        // Only the maximum exit latency, the interrupter target and the
+        // maximum packet size of the default control endpoint are updated.
+        if control.dwords[1] & 0x1 != 0 {
+            if let Some(mut slot_context) = self.read_context(context) {
+                slot_context.dwords[1] =
+                    (slot_context.dwords[1] & !0xffff) | (slot_input.dwords[1] & 0xffff);
+                slot_context.dwords[2] =
+                    (slot_context.dwords[2] & 0x3f_ffff) | (slot_input.dwords[2] & !0x3f_ffff);
+                self.write_context(context, slot_context);
+            }
+        }
+        if control.dwords[1] & 0x2 != 0 {
+            if let Some(mut ep0_context) = self.read_context(context + CONTEXT_SIZE) {
+                ep0_context.dwords[1] =
+                    (ep0_context.dwords[1] & 0xffff) | (ep0_input.dwords[1] & !0xffff);
+                self.write_context(context + CONTEXT_SIZE, ep0_context);
+            }
+        }
+
+        CC_SUCCESS
+    }
+
+    fn reset_endpoint(&mut self, trb: &Trb) -> u32 {
+        let (slot_id, dci) = (trb.slot_id(), trb.endpoint_id());
+        match self.endpoint_mut(slot_id, dci) {
+            Some(endpoint) if endpoint.state == EP_STATE_HALTED => {
+                endpoint.state = EP_STATE_STOPPED
+            }
+            Some(_) => return CC_CONTEXT_STATE_ERROR,
+            None => return CC_ENDPOINT_NOT_ENABLED,
+        }
+
+        self.write_endpoint_state(slot_id, dci);
+        CC_SUCCESS
+    }
+
+    fn stop_endpoint(&mut self, trb: &Trb) -> u32 {
+        let (slot_id, dci) = (trb.slot_id(), trb.endpoint_id());
+        let inflight = match self.endpoint_mut(slot_id, dci) {
+            Some(endpoint) if endpoint.state == EP_STATE_RUNNING => {
+                endpoint.state = EP_STATE_STOPPED;
+                // The endpoint stops on the first TRB of the transfer in
+                // flight, which the driver either skips or restarts.
+                endpoint.inflight.take().map(|(key, start)| {
+                    endpoint.ring = start;
+                    key
+                })
+            }
+            Some(_) => return CC_CONTEXT_STATE_ERROR,
+            None => return CC_ENDPOINT_NOT_ENABLED,
+        };
+        if let Some(key) = inflight {
+            self.cancel_transfer(key);
+        }
+
+        self.write_endpoint_state(slot_id, dci);
+        CC_SUCCESS
+    }
+
+    fn set_tr_dequeue(&mut self, trb: &Trb) -> u32 {
+        let (slot_id, dci) = (trb.slot_id(), trb.endpoint_id());
+        match self.endpoint_mut(slot_id, dci) {
+            Some(endpoint) if endpoint.state == EP_STATE_STOPPED => {
+                endpoint.ring = Ring::new(trb.parameter)
+            }
+            Some(_) => return CC_CONTEXT_STATE_ERROR,
+            None => return CC_ENDPOINT_NOT_ENABLED,
+        }
+
+        self.write_endpoint_state(slot_id, dci);
+        CC_SUCCESS
+    }
+
+    fn reset_device(&mut self, slot_id: usize) -> u32 {
+        match self.slot_mut(slot_id) {
+            Some(slot) if slot.state >= SLOT_STATE_ADDRESSED => (),
+            Some(_) => return CC_CONTEXT_STATE_ERROR,
+            None => return CC_SLOT_NOT_ENABLED,
+        }
+
+        for dci in 2..MAX_ENDPOINTS {
+            self.disable_endpoint(slot_id, dci);
+        }
+        self.write_slot_state(slot_id, SLOT_STATE_DEFAULT, 0);
+        CC_SUCCESS
+    }
+
+    fn disable_endpoint(&mut self, slot_id: usize, dci: usize) {
+        let endpoint = match self.slot_mut(slot_id) {
+            Some(slot) => slot.endpoints[dci].take(),
+            None => return,
+        };
+        if let Some((key, _)) = endpoint.and_then(|endpoint| endpoint.inflight) {
+            self.cancel_transfer(key);
+        }
+        self.write_endpoint_state(slot_id, dci);
+    }
+
+    // The transfer completes anyway, but is ignored.
+    fn cancel_transfer(&mut self, key: u64) {
+        if let Some(transfer) = self.transfers.remove(&key) {
+            if let Some(Some(device)) = self.devices.get_mut(transfer.device) {
+                device.discard(key);
+            }
+        }
+    }
+
+    fn process_endpoint(&mut self, slot_id: usize, dci: usize) {
+        let mem = self.memory.memory();
+        for _ in 0..MAX_RING_TRBS {
+            let endpoint = match self.endpoint_mut(slot_id, dci) {
+                Some(endpoint) => endpoint,
+                None => return,
+            };
+            if endpoint.state != EP_STATE_RUNNING || endpoint.inflight.is_some() {
+                return;
+            }
+
+            // A Transfer Descriptor is made of chained TRBs, except for the
+            // control transfers, going from the setup to the status stage.
+            let start = endpoint.ring;
+            let mut ring = endpoint.ring;
+            let mut trbs: Vec<(u64, Trb)> = Vec::new();
+            let complete = loop {
+                let (addr, trb) = match ring.next(&mem) {
+                    Some(trb) => trb,
+                    None => break false,
+                };
+                trbs.push((addr, trb));
+                let control = trbs[0].1.trb_type() == TRB_SETUP_STAGE;
+                if (control && trb.trb_type() == TRB_STATUS_STAGE)
+                    || (!control && trb.control & TRB_CHAIN == 0)
+                {
+                    break true;
+                }
+                if trbs.len() >= MAX_RING_TRBS {
+                    break false;
+                }
+            };
+            if !complete {
+                return;
+            }
+            endpoint.ring = ring;
+
+            match self.submit_transfer(slot_id, dci, &trbs) {
+                Ok(Some((key, device, segments, offset))) => {
+                    if let Some(endpoint) = self.endpoint_mut(slot_id, dci) {
+                        endpoint.inflight = Some((key, start));
+                    }
+                    self.transfers.insert(
+                        key,
+                        Transfer {
+                            device,
+                            slot_id,
+                            dci,
+                            trbs,
+                            segments,
+                            offset,
+                        },
+                    );
+                    return;
+                }
+                Ok(None) => self.complete_transfer_descriptor(slot_id, dci, &trbs, 0, CC_SUCCESS),
+                Err(code) => self.complete_transfer_descriptor(slot_id, dci, &trbs, 0, code),
+            }
+        }
+    }
+
+    // Submits the transfer described by the TRBs to the host device,
+    // returning its key, the device index, the guest buffers receiving the
+    // data and their offset in the transfer buffer. Nothing is submitted
+    // for the requests the controller handles itself.
+    #[allow(clippy::type_complexity)]
+    fn submit_transfer(
+        &mut self,
+        slot_id: usize,
+        dci: usize,
+        trbs: &[(u64, Trb)],
+    ) -> result::Result<Option<(u64, usize, Vec<(u64, u32)>, usize)>, u32> {
+        let port = self.slot_mut(slot_id).map_or(0, |slot| slot.port);
+        let device = match port.checked_sub(1).and_then(|port| self.port_devices[port]) {
+            Some(device) if self.devices[device].is_some() => device,
+            _ => return Err(CC_USB_TRANSACTION_ERROR),
+        };
+        // The isochronous transfers aren't supported.
+        let transfer_type = match self.endpoint_mut(slot_id, dci) {
+            Some(Endpoint {
+                transfer_type: Some(transfer_type),
+                ..
+            }) => *transfer_type,
+            _ => return Err(CC_TRB_ERROR),
+        };
+
+        let setup = match trbs.first() {
+            Some((_, trb)) if trb.trb_type() == TRB_SETUP_STAGE => {
+                Some(trb.parameter.to_le_bytes())
+            }
+            _ => None,
+        };
+        if setup.is_some() != (transfer_type == TransferType::Control) {
+            return Err(CC_TRB_ERROR);
+        }
+        let input = match setup {
+            Some(setup) => setup[0] & 0x80 != 0,
+            None => dci % 2 == 1,
+        };
+
+        let mem = self.memory.memory();
+        let mut buffer = setup.map_or_else(Vec::new, |setup| setup.to_vec());
+        let offset = buffer.len();
+        let mut segments = Vec::new();
+        for (_, trb) in trbs.iter().filter(|(_, trb)| trb.has_data()) {
+            let len = trb.transfer_length();
+            let start = buffer.len();
+            if (start - offset) as u32 + len > MAX_TRANSFER_SIZE {
+                return Err(CC_TRB_ERROR);
+            }
+            if input {
+                segments.push((trb.parameter, len));
+                buffer.resize(start + len as usize, 0);
+            } else if trb.control & TRB_IDT != 0 {
+                let data = trb.parameter.to_le_bytes();
+                buffer.extend_from_slice(&data[..std::cmp::min(len as usize, data.len())]);
+            } else {
+                buffer.resize(start + len as usize, 0);
+                mem.read_slice(&mut buffer[start..], GuestAddress(trb.parameter))
+                    .map_err(|_| CC_DATA_BUFFER_ERROR)?;
+            }
+        }
+
+        let address = match setup {
+            Some(setup) => {
+                // The kernel expects the buffer to match the setup packet.
+                let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+                buffer.resize(USB_SETUP_SIZE + length, 0);
+                if let Some(result) = self.standard_request(device, &setup) {
+                    return match result {
+                        Ok(()) => Ok(None),
+                        Err(e) => {
+                            warn!("{}: USB request failed: {}", self.id, e);
+                            Err(CC_STALL)
+                        }
+                    };
+                }
+                0
+            }
+            None => (dci / 2) as u8 | if input { 0x80 } else { 0 },
+        };
+
+        let key = self.next_key;
+        self.next_key = self.next_key.wrapping_add(1);
+        if let Some(host) = self.devices[device].as_mut() {
+            if let Err(e) = host.submit(key, transfer_type, address, buffer) {
+                warn!("{}: Failed submitting USB transfer: {}", self.id, e);
+                return Err(CC_USB_TRANSACTION_ERROR);
+            }
+        }
+
+        Ok(Some((key, device, segments, offset)))
+    }
+
+    // Handles the standard requests changing the state of the device, which
+    // usbfs needs to know about.
+    fn standard_request(&mut self, device: usize, setup: &[u8; 8]) -> Option<io::Result<()>> {
+        let host = self.devices[device].as_mut()?;
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+
+        match (setup[0], setup[1]) {
+            (0x00, USB_REQ_SET_ADDRESS) => Some(Ok(())),
+            (0x00, USB_REQ_SET_CONFIGURATION) => Some(host.set_configuration(value as u8)),
+            (0x01, USB_REQ_SET_INTERFACE) => Some(host.set_interface(index, value)),
+            (0x02, USB_REQ_CLEAR_FEATURE) if value == USB_ENDPOINT_HALT => {
+                Some(host.clear_halt(index as u8))
+            }
+            _ => None,
+        }
+    }
+
+    fn process_completions(&mut self, device: usize) {
+        loop {
+            let result = match self.devices[device].as_mut() {
+                Some(host) => host.reap(),
+                None => return,
+            };
+            match result {
+                Ok(Some(completion)) => self.complete_transfer(completion),
+                Ok(None) => return,
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                    self.disconnect(device);
+                    return;
+                }
+                Err(e) => {
+                    error!("{}: Failed reaping USB transfer: {}", self.id, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn complete_transfer(&mut self, completion: Completion) {
+        // Cancelled transfers are ignored.
+        let transfer = match self.transfers.remove(&completion.key) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let (slot_id, dci) = (transfer.slot_id, transfer.dci);
+        if let Some(endpoint) = self.endpoint_mut(slot_id, dci) {
+            endpoint.inflight = None;
+        }
+
+        let mut code = completion_code(completion.status);
+        let data = completion.buffer.get(transfer.offset..).unwrap_or(&[]);
+        let mut data = &data[..std::cmp::min(completion.actual_length, data.len())];
+        let actual = data.len() as u32;
+        let mem = self.memory.memory();
+        for (addr, len) in transfer.segments.iter() {
+            let len = std::cmp::min(*len as usize, data.len());
+            if len == 0 {
+                break;
+            }
+            if mem.write_slice(&data[..len], GuestAddress(*addr)).is_err() {
+                code = CC_DATA_BUFFER_ERROR;
+                break;
+            }
+            data = &data[len..];
+        }
+
+        self.complete_transfer_descriptor(slot_id, dci, &transfer.trbs, actual, code);
+        self.process_endpoint(slot_id, dci);
+    }
+
+    // Posts the transfer events of the TRBs asking for one, given the
+    // number of bytes transferred.
+    fn complete_transfer_descriptor(
+        &mut self,
+        slot_id: usize,
+        dci: usize,
+        trbs: &[(u64, Trb)],
+        actual: u32,
+        code: u32,
+    ) {
+        if code != CC_SUCCESS {
+            if let Some(endpoint) = self.endpoint_mut(slot_id, dci) {
+                endpoint.state = EP_STATE_HALTED;
+            }
+            self.write_endpoint_state(slot_id, dci);
+        }
+
+        let control = trbs
+            .first()
+            .map_or(false, |(_, trb)| trb.trb_type() == TRB_SETUP_STAGE);
+        let mut left = actual;
+        let mut transferred = 0;
+        let mut short = false;
+        for (i, (addr, trb)) in trbs.iter().enumerate() {
+            if trb.trb_type() == TRB_EVENT_DATA {
+                if trb.control & TRB_IOC != 0 {
+                    let code = if short { CC_SHORT_PACKET } else { code };
+                    self.post_transfer_event(slot_id, dci, trb.parameter, code, transferred, true);
+                    transferred = 0;
+                }
+                continue;
+            }
+            // A control transfer goes on with its status stage after a short
+            // data stage, while the others are over.
+            if short && control && trb.has_data() {
+                continue;
+            }
+
+            let length = if trb.has_data() {
+                trb.transfer_length()
+            } else {
+                0
+            };
+            let done = std::cmp::min(length, left);
+            left -= done;
+            transferred += done;
+            let residual = length - done;
+
+            if code != CC_SUCCESS {
+                if residual > 0 || i == trbs.len() - 1 {
+                    self.post_transfer_event(slot_id, dci, *addr, code, residual, false);
+                    return;
+                }
+                continue;
+            }
+
+            short |= residual > 0;
+            if trb.control & TRB_IOC != 0 || (residual > 0 && trb.control & TRB_ISP != 0) {
+                let code = if short && (trb.has_data() || !control) {
+                    CC_SHORT_PACKET
+                } else {
+                    CC_SUCCESS
+                };
+                self.post_transfer_event(slot_id, dci, *addr, code, residual, false);
+                if short && !control {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn disconnect(&mut self, device: usize) {
+        if let Some(host) = self.devices[device].take() {
+            info!(
+                "{}: {} was unplugged from the host",
+                self.id,
+                host.path().display()
+            );
+        }
+
+        let keys: Vec<u64> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.device == device)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            if let Some(transfer) = self.transfers.remove(&key) {
+                let (slot_id, dci) = (transfer.slot_id, transfer.dci);
+                if let Some(endpoint) = self.endpoint_mut(slot_id, dci) {
+                    endpoint.inflight = None;
+                }
+                let code = CC_USB_TRANSACTION_ERROR;
+                self.complete_transfer_descriptor(slot_id, dci, &transfer.trbs, 0, code);
+            }
+        }
+
+        if let Some(port) = self.port_devices.iter().position(|d| *d == Some(device)) {
+            let old = self.ports[port];
+            self.connect_port(port);
+            self.ports[port] |= PORTSC_CSC;
+            self.port_changed(port, old);
+        }
+    }
+}
+
+// Adds the file of the host device at `index` to the epoll set, or removes
+// it.
+fn watch_device(
+    epoll_fd: i32,
+    state: &XhciState,
+    index: usize,
+    op: epoll::ControlOptions,
+) -> io::Result<()> {
+    match &state.devices[index] {
+        Some(host) => epoll::ctl(
+            epoll_fd,
+            op,
+            host.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLOUT, DEVICE_EVENT + index as u64),
+        ),
+        None => Ok(()),
+    }
+}
+
+struct XhciWorker {
+    state: Arc<Mutex<XhciState>>,
+    kick_evt: EventFd,
+    kill_evt: EventFd,
+}
+
+impl XhciWorker {
+    fn run(&self) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        for (fd, token) in &[
+            (self.kick_evt.as_raw_fd(), KICK_EVENT),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+        ] {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, *token),
+            )?;
+        }
+        // The device files become writable once transfers are completed.
+        let num_devices = {
+            let state = self.state.lock().unwrap();
+            for index in 0..state.devices.len() {
+                watch_device(
+                    epoll_fd,
+                    &state,
+                    index,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                )?;
+            }
+            state.devices.len()
+        };
+        // Devices no longer watched while the VM is paused, as their
+        // completed transfers would keep waking the worker up.
+        let mut unwatched = vec![false; num_devices];
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2 + num_devices];
+        loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    KICK_EVENT => {
+                        self.kick_evt.read().ok();
+                        let mut state = self.state.lock().unwrap();
+                        // The controller is kicked again once resumed.
+                        if state.paused {
+                            continue;
+                        }
+                        for (index, unwatched) in unwatched.iter_mut().enumerate() {
+                            if *unwatched {
+                                watch_device(
+                                    epoll_fd,
+                                    &state,
+                                    index,
+                                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                                )?;
+                                *unwatched = false;
+                            }
+                        }
+                        state.process_doorbells();
+                    }
+                    KILL_EVENT => return Ok(()),
+                    token => {
+                        let device = (token - DEVICE_EVENT) as usize;
+                        let mut state = self.state.lock().unwrap();
+                        if state.paused {
+                            watch_device(
+                                epoll_fd,
+                                &state,
+                                device,
+                                epoll::ControlOptions::EPOLL_CTL_DEL,
+                            )?;
+                            unwatched[device] = true;
+                            continue;
+                        }
+                        let hangup = epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR;
+                        if epoll::Events::from_bits_truncate(event.events).intersects(hangup) {
+                            state.disconnect(device);
+                        } else {
+                            state.process_completions(device);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_structure_sizes() {
+        assert_eq!(std::mem::size_of::<Trb>(), TRB_SIZE as usize);
+        assert_eq!(std::mem::size_of::<Context>(), CONTEXT_SIZE as usize);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(read_capability(REG_CAPLENGTH), 0x0100_0040);
+        assert_eq!(read_capability(REG_HCSPARAMS1), 0x0800_0108);
+        assert_eq!(read_capability(REG_HCCPARAMS1) >> 16, 0x200);
+
+        // USB 2 ports first, then the USB 3 ones, up to the end of the
+        // capability list.
+        assert_eq!(read_extended_capability(0x00), 0x0200_0402);
+        assert_eq!(read_extended_capability(0x08), 0x0000_0401);
+        assert_eq!(read_extended_capability(0x10), 0x0300_0002);
+        assert_eq!(read_extended_capability(0x18), 0x0000_0405);
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulated Intel VT-d DMA remapping hardware unit.
+//!
+//! Provides an IOMMU to the guests lacking a virtio-iommu driver. Only the
+//! legacy translation mode is emulated, with 4-level second-level tables and
+//! register based invalidations. The caching mode is advertised, which makes
+//! the guest report any change of its tables through an invalidation, even
+//! though the tables are currently walked on each translation.
+//!
+//! The translation itself happens from the threads of the devices, through
+//! the `VtdMapping` shared with the register interface.
+
+use std::convert::TryInto;
+use std::io;
+use std::sync::{Arc, Barrier, RwLock};
+use vm_device::BusDevice;
+use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+/// Size of the register set of the remapping hardware unit.
+pub const VTD_SIZE: u64 = 0x1000;
+
+/// Width of the guest addresses which can be translated, as reported in the
+/// DMAR table.
+pub const VTD_HOST_ADDRESS_WIDTH: u8 = 48;
+
+const VER_REG: u64 = 0x0;
+const CAP_REG: u64 = 0x8;
+const ECAP_REG: u64 = 0x10;
+const GCMD_REG: u64 = 0x18;
+const GSTS_REG: u64 = 0x1c;
+const RTADDR_REG: u64 = 0x20;
+const CCMD_REG: u64 = 0x28;
+const FECTL_REG: u64 = 0x38;
+const FEDATA_REG: u64 = 0x3c;
+const FEADDR_REG: u64 = 0x40;
+const FEUADDR_REG: u64 = 0x44;
+// Offset of the IOTLB registers, reported through ECAP_REG.
+const IVA_REG: u64 = 0x100;
+const IOTLB_REG: u64 = 0x108;
+// Offset of the single fault recording register, reported through CAP_REG.
+const FRCD_REG: u64 = 0x200;
+
+// Version 1.0 of the specification.
+const VERSION: u64 = 0x10;
+// 256 domains, caching mode, 4-level tables, 48-bit guest addresses and the
+// offset of the fault recording register.
+const CAPABILITIES: u64 = 2
+    | (1 << 7)
+    | (1 << 10)
+    | ((VTD_HOST_ADDRESS_WIDTH as u64 - 1) << 16)
+    | ((FRCD_REG / 16) << 24);
+// Coherent accesses to the tables and the offset of the IOTLB registers.
+const EXTENDED_CAPABILITIES: u64 = 1 | ((IVA_REG / 16) << 8);
+
+// Global command and status bits.
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GSTS_TES: u32 = 1 << 31;
+const GSTS_RTPS: u32 = 1 << 30;
+
+// Bits of the upper half of the context and IOTLB command registers, the
+// requested granularity being reported back as the actual one.
+const CCMD_ICC: u32 = 1 << 31;
+const CCMD_CIRG_SHIFT: u32 = 29;
+const CCMD_CAIG_SHIFT: u32 = 27;
+const IOTLB_IVT: u32 = 1 << 31;
+const IOTLB_IIRG_SHIFT: u32 = 28;
+const IOTLB_IAIG_SHIFT: u32 = 25;
+
+const FECTL_IM: u32 = 1 << 31;
+
+// Fields of the root, context and second-level paging entries.
+const ENTRY_PRESENT: u64 = 1;
+const ENTRY_READ_WRITE: u64 = 0x3;
+const ENTRY_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const CONTEXT_TRANSLATION_TYPE_SHIFT: u64 = 2;
+const CONTEXT_ADDRESS_WIDTH_MASK: u64 = 0x7;
+// Address width encoding of the 4-level tables.
+const CONTEXT_ADDRESS_WIDTH_48: u64 = 2;
+const ROOT_ENTRY_SIZE: u64 = 16;
+const CONTEXT_ENTRY_SIZE: u64 = 16;
+const PAGE_TABLE_LEVELS: u64 = 4;
+const PAGE_SHIFT: u64 = 12;
+const PAGE_TABLE_INDEX_BITS: u64 = 9;
+
+fn translation_fault(bdf: u32, iova: u64, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "VT-d fault for device {:02x}:{:02x}.{} at 0x{:x}: {}",
+            bdf >> 8,
+            (bdf >> 3) & 0x1f,
+            bdf & 0x7,
+            iova,
+            reason
+        ),
+    )
+}
+
+/// Translation of the DMA addresses, following the tables programmed by the
+/// guest through the registers of the `Vtd` device.
+pub struct VtdMapping {
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    // Root table in use, only set while the translation is enabled.
+    root_table: RwLock<Option<u64>>,
+}
+
+impl VtdMapping {
+    fn read_entry(&self, addr: u64, bdf: u32, iova: u64) -> io::Result<u64> {
+        self.memory
+            .memory()
+            .read_obj(GuestAddress(addr))
+            .map_err(|e| translation_fault(bdf, iova, &e.to_string()))
+    }
+
+    /// Translates the IOVA the device identified by its BDF accessed into a
+    /// guest physical address. The addresses are left untouched as long as
+    /// the guest didn't enable the translation.
+    pub fn translate(&self, bdf: u32, iova: u64) -> io::Result<u64> {
+        let root_table = match *self.root_table.read().unwrap() {
+            Some(root_table) => root_table,
+            None => return Ok(iova),
+        };
+
+        if iova >> VTD_HOST_ADDRESS_WIDTH != 0 {
+            return Err(translation_fault(bdf, iova, "address too wide"));
+        }
+
+        let bus = u64::from((bdf >> 8) & 0xff);
+        let devfn = u64::from(bdf & 0xff);
+
+        let root_entry = self.read_entry(root_table + bus * ROOT_ENTRY_SIZE, bdf, iova)?;
+        if root_entry & ENTRY_PRESENT == 0 {
+            return Err(translation_fault(bdf, iova, "root entry not present"));
+        }
+
+        let context_entry = (root_entry & ENTRY_ADDR_MASK) + devfn * CONTEXT_ENTRY_SIZE;
+        let context_lo = self.read_entry(context_entry, bdf, iova)?;
+        let context_hi = self.read_entry(context_entry + 8, bdf, iova)?;
+        if context_lo & ENTRY_PRESENT == 0 {
+            return Err(translation_fault(bdf, iova, "context entry not present"));
+        }
+        if (context_lo >> CONTEXT_TRANSLATION_TYPE_SHIFT) & 0x3 != 0 {
+            return Err(translation_fault(bdf, iova, "unsupported translation type"));
+        }
+        if context_hi & CONTEXT_ADDRESS_WIDTH_MASK != CONTEXT_ADDRESS_WIDTH_48 {
+            return Err(translation_fault(bdf, iova, "unsupported address width"));
+        }
+
+        let mut table = context_lo & ENTRY_ADDR_MASK;
+        for level in (0..PAGE_TABLE_LEVELS).rev() {
+            let shift = PAGE_SHIFT + level * PAGE_TABLE_INDEX_BITS;
+            let index = (iova >> shift) & ((1 << PAGE_TABLE_INDEX_BITS) - 1);
+            let entry = self.read_entry(table + index * 8, bdf, iova)?;
+            if entry & ENTRY_READ_WRITE == 0 {
+                return Err(translation_fault(bdf, iova, "page not present"));
+            }
+            table = entry & ENTRY_ADDR_MASK;
+        }
+
+        Ok(table | (iova & ((1 << PAGE_SHIFT) - 1)))
+    }
+}
+
+/// Register interface of the DMA remapping hardware unit.
+pub struct Vtd {
+    regs: Vec<u32>,
+    // Root table latched by the last root table pointer command.
+    root_table: u64,
+    mapping: Arc<VtdMapping>,
+}
+
+impl Vtd {
+    pub fn new(memory: GuestMemoryAtomic<GuestMemoryMmap>) -> Self {
+        let mut vtd = Vtd {
+            regs: vec![0; (VTD_SIZE / 4) as usize],
+            root_table: 0,
+            mapping: Arc::new(VtdMapping {
+                memory,
+                root_table: RwLock::new(None),
+            }),
+        };
+        vtd.set_reg64(VER_REG, VERSION);
+        vtd.set_reg64(CAP_REG, CAPABILITIES);
+        vtd.set_reg64(ECAP_REG, EXTENDED_CAPABILITIES);
+        vtd.set_reg(FECTL_REG, FECTL_IM);
+        vtd
+    }
+
+    pub fn mapping(&self) -> Arc<VtdMapping> {
+        self.mapping.clone()
+    }
+
+    fn reg(&self, offset: u64) -> u32 {
+        self.regs[(offset / 4) as usize]
+    }
+
+    fn set_reg(&mut self, offset: u64, value: u32) {
+        self.regs[(offset / 4) as usize] = value;
+    }
+
+    fn reg64(&self, offset: u64) -> u64 {
+        u64::from(self.reg(offset)) | (u64::from(self.reg(offset + 4)) << 32)
+    }
+
+    fn set_reg64(&mut self, offset: u64, value: u64) {
+        self.set_reg(offset, value as u32);
+        self.set_reg(offset + 4, (value >> 32) as u32);
+    }
+
+    fn global_command(&mut self, value: u32) {
+        let mut status = self.reg(GSTS_REG);
+        if value & GCMD_SRTP != 0 {
+            self.root_table = self.reg64(RTADDR_REG) & !0xfff;
+            status |= GSTS_RTPS;
+        }
+        if value & GCMD_TE != 0 {
+            status |= GSTS_TES;
+        } else {
+            status &= !GSTS_TES;
+        }
+        self.set_reg(GSTS_REG, status);
+
+        let mut root_table = self.mapping.root_table.write().unwrap();
+        *root_table = if status & GSTS_TES != 0 {
+            Some(self.root_table)
+        } else {
+            None
+        };
+    }
+
+    // Writes to read-only or unsupported registers are dropped. The 64-bit
+    // registers being accessed through their halves, the commands only take
+    // effect once their upper half is written.
+    fn write_reg(&mut self, offset: u64, value: u32) -> bool {
+        match offset {
+            GCMD_REG => self.global_command(value),
+            RTADDR_REG | FEDATA_REG | FEADDR_REG | FEUADDR_REG | IVA_REG => {
+                self.set_reg(offset, value)
+            }
+            o if o == RTADDR_REG + 4 || o == IVA_REG + 4 || o == CCMD_REG || o == IOTLB_REG => {
+                self.set_reg(offset, value)
+            }
+            o if o == CCMD_REG + 4 => {
+                let granularity = (value >> CCMD_CIRG_SHIFT) & 0x3;
+                let value = value & !CCMD_ICC & !(0x3 << CCMD_CAIG_SHIFT);
+                self.set_reg(offset, value | (granularity << CCMD_CAIG_SHIFT));
+            }
+            o if o == IOTLB_REG + 4 => {
+                let granularity = (value >> IOTLB_IIRG_SHIFT) & 0x3;
+                let value = value & !IOTLB_IVT & !(0x3 << IOTLB_IAIG_SHIFT);
+                self.set_reg(offset, value | (granularity << IOTLB_IAIG_SHIFT));
+            }
+            FECTL_REG => self.set_reg(offset, value & FECTL_IM),
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl BusDevice for Vtd {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset + data.len() as u64 > VTD_SIZE
+            || offset % 4 != 0
+            || (data.len() != 4 && data.len() != 8)
+        {
+            warn!(
+                "Invalid VT-d read: offset 0x{:x}, data length {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        for (i, chunk) in data.chunks_mut(4).enumerate() {
+            chunk.copy_from_slice(&self.reg(offset + i as u64 * 4).to_le_bytes());
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset + data.len() as u64 > VTD_SIZE
+            || offset % 4 != 0
+            || (data.len() != 4 && data.len() != 8)
+        {
+            warn!(
+                "Invalid VT-d write: offset 0x{:x}, data length {}",
+                offset,
+                data.len()
+            );
+            return None;
+        }
+
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let reg_offset = offset + i as u64 * 4;
+            if !self.write_reg(reg_offset, u32::from_le_bytes(chunk.try_into().unwrap())) {
+                debug!("Ignored VT-d write: offset 0x{:x}", reg_offset);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write64(vtd: &mut Vtd, offset: u64, value: u64) {
+        vtd.write(0, offset, &value.to_le_bytes());
+    }
+
+    fn read64(vtd: &mut Vtd, offset: u64) -> u64 {
+        let mut data = [0u8; 8];
+        vtd.read(0, offset, &mut data);
+        u64::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_vtd_translate() {
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let memory = GuestMemoryAtomic::new(memory);
+        let mut vtd = Vtd::new(memory.clone());
+        let mapping = vtd.mapping();
+
+        // Root table at 0x1000, context table at 0x2000 and the page tables
+        // from 0x3000 to 0x6000, mapping the IOVA 0x4000_1000 of the device
+        // 00:03.0 to the page 0x8000.
+        let mem = memory.memory();
+        let bdf = 3 << 3;
+        let iova = 0x4000_1000;
+        mem.write_obj(0x2000u64 | ENTRY_PRESENT, GuestAddress(0x1000))
+            .unwrap();
+        mem.write_obj(0x3000u64 | ENTRY_PRESENT, GuestAddress(0x2000 + bdf * 16))
+            .unwrap();
+        mem.write_obj(
+            CONTEXT_ADDRESS_WIDTH_48,
+            GuestAddress(0x2000 + bdf * 16 + 8),
+        )
+        .unwrap();
+        mem.write_obj(0x4000u64 | ENTRY_READ_WRITE, GuestAddress(0x3000))
+            .unwrap();
+        mem.write_obj(0x5000u64 | ENTRY_READ_WRITE, GuestAddress(0x4000 + 8))
+            .unwrap();
+        mem.write_obj(0x6000u64 | ENTRY_READ_WRITE, GuestAddress(0x5000))
+            .unwrap();
+        mem.write_obj(0x8000u64 | ENTRY_READ_WRITE, GuestAddress(0x6000 + 8))
+            .unwrap();
+
+        // The addresses are left untouched until the translation is enabled.
+        assert_eq!(
+            mapping.translate(bdf as u32, iova + 0x10).unwrap(),
+            iova + 0x10
+        );
+
+        write64(&mut vtd, RTADDR_REG, 0x1000);
+        vtd.write(0, GCMD_REG, &GCMD_SRTP.to_le_bytes());
+        assert_eq!(read64(&mut vtd, GCMD_REG) >> 32, u64::from(GSTS_RTPS));
+        vtd.write(0, GCMD_REG, &GCMD_TE.to_le_bytes());
+        assert_eq!(
+            read64(&mut vtd, GCMD_REG) >> 32,
+            u64::from(GSTS_TES | GSTS_RTPS)
+        );
+
+        assert_eq!(mapping.translate(bdf as u32, iova + 0x10).unwrap(), 0x8010);
+        assert!(mapping.translate(bdf as u32, iova + 0x1000).is_err());
+        assert!(mapping.translate(4 << 3, iova).is_err());
+
+        // The invalidations complete immediately, with the granularity that
+        // was requested.
+        write64(&mut vtd, CCMD_REG, (1 << 63) | (1 << 61));
+        assert_eq!(read64(&mut vtd, CCMD_REG), (1 << 61) | (1 << 59));
+        write64(&mut vtd, IOTLB_REG, (1 << 63) | (1 << 60));
+        assert_eq!(read64(&mut vtd, IOTLB_REG), (1 << 60) | (1 << 57));
+
+        vtd.write(0, GCMD_REG, &0u32.to_le_bytes());
+        assert_eq!(mapping.translate(bdf as u32, iova).unwrap(), iova);
+    }
+}
@@ -12,12 +12,13 @@ use crate::arch::x86::{Exception, SegmentRegisterOps};
 use crate::x86_64::{SegmentRegister, SpecialRegisters, StandardRegisters};
 use anyhow::Context;
 use iced_x86::*;
+use std::collections::HashMap;
 
 #[macro_use]
 mod instructions;
 
 /// x86 CPU modes
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CpuMode {
     /// Real mode
     Real,
@@ -471,8 +472,92 @@ impl CpuStateManager for EmulatorCpuState {
     }
 }
 
+/// Number of decoded instructions an `InstructionCache` holds.
+const INSN_CACHE_SIZE: usize = 64;
+
+struct CachedInstruction {
+    bytes: Vec<u8>,
+    insn: Instruction,
+}
+
+// What the instructions depend on besides their bytes: the address space
+// they're mapped in, and the CPU mode along with the code segment, which
+// set the default operand and address sizes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DecodeContext {
+    cr3: u64,
+    mode: Option<CpuMode>,
+    cs_long: u8,
+    cs_db: u8,
+}
+
+impl DecodeContext {
+    fn new<T: CpuStateManager>(state: &T) -> Result<Self, PlatformError> {
+        let cs = state.read_segment(Register::CS)?;
+        Ok(DecodeContext {
+            cr3: state.read_reg(Register::CR3)?,
+            // An inconsistent mode is reported when emulating.
+            mode: state.mode().ok(),
+            cs_long: cs.long(),
+            cs_db: cs.db(),
+        })
+    }
+}
+
+/// Instructions decoded for a vCPU.
+///
+/// A guest polling a device keeps exiting on the same instruction, which
+/// then only needs to be decoded once. The instructions are looked up by
+/// their virtual address, which doesn't cost a translation on every exit,
+/// and are only hit if the instruction stream at that address still starts
+/// with the cached bytes, so that code modified in place, or another page
+/// mapped at the same address, gets decoded again. Since a new CR3 maps
+/// other code altogether, and a new CPU mode or code segment decodes the
+/// same bytes differently, the cache is flushed whenever any of them
+/// changes.
+#[derive(Default)]
+pub struct InstructionCache {
+    context: Option<DecodeContext>,
+    entries: HashMap<u64, CachedInstruction>,
+}
+
+impl InstructionCache {
+    fn set_context(&mut self, context: DecodeContext) {
+        if self.context != Some(context) {
+            self.entries.clear();
+            self.context = Some(context);
+        }
+    }
+
+    fn get(&mut self, context: DecodeContext, ip: u64, insn_stream: &[u8]) -> Option<Instruction> {
+        self.set_context(context);
+        self.entries
+            .get(&ip)
+            .filter(|entry| insn_stream.starts_with(&entry.bytes))
+            .map(|entry| entry.insn)
+    }
+
+    fn insert(&mut self, context: DecodeContext, ip: u64, bytes: &[u8], insn: Instruction) {
+        self.set_context(context);
+        // The instructions a guest exits on are few, so starting over is
+        // good enough when the cache is full.
+        if self.entries.len() >= INSN_CACHE_SIZE && !self.entries.contains_key(&ip) {
+            self.entries.clear();
+        }
+
+        self.entries.insert(
+            ip,
+            CachedInstruction {
+                bytes: bytes.to_vec(),
+                insn,
+            },
+        );
+    }
+}
+
 pub struct Emulator<'a, T: CpuStateManager> {
     platform: &'a mut dyn PlatformEmulator<CpuState = T>,
+    cache: Option<&'a mut InstructionCache>,
 }
 
 // Reduce repetition, see its invocation in get_handler().
@@ -489,7 +574,17 @@ macro_rules! gen_handler_match {
 
 impl<'a, T: CpuStateManager> Emulator<'a, T> {
     pub fn new(platform: &mut dyn PlatformEmulator<CpuState = T>) -> Emulator<T> {
-        Emulator { platform }
+        Emulator {
+            platform,
+            cache: None,
+        }
+    }
+
+    /// Looks the first instruction emulated up in `cache`, and keeps it
+    /// there once decoded.
+    pub fn with_cache(mut self, cache: &'a mut InstructionCache) -> Self {
+        self.cache = Some(cache);
+        self
     }
 
     fn get_handler(code: Code) -> Option<Box<dyn InstructionHandler<T>>> {
@@ -532,6 +627,26 @@ impl<'a, T: CpuStateManager> Emulator<'a, T> {
         handler
     }
 
+    fn emulate_decoded_insn(
+        &mut self,
+        insn: &Instruction,
+        state: &mut T,
+    ) -> EmulationResult<(), Exception> {
+        Emulator::get_handler(insn.code())
+            .ok_or_else(|| {
+                EmulationError::UnsupportedInstruction(anyhow!(
+                    "{:#x?} {:?} {:?}",
+                    insn_format!(insn),
+                    insn.mnemonic(),
+                    insn.code()
+                ))
+            })?
+            .emulate(insn, state, self.platform)
+            .context(anyhow!("Failed to emulate {:#x?}", insn_format!(insn)))?;
+
+        Ok(())
+    }
+
     fn emulate_insn_stream(
         &mut self,
         cpu_id: usize,
@@ -549,6 +664,19 @@ impl<'a, T: CpuStateManager> Emulator<'a, T> {
         let mut last_decoded_ip: u64 = state.ip();
         let mut stop_emulation: bool = false;
 
+        let context = DecodeContext::new(&state).map_err(EmulationError::PlatformEmulationError)?;
+        if num_insn == Some(1) {
+            let cached = self
+                .cache
+                .as_mut()
+                .and_then(|cache| cache.get(context, last_decoded_ip, insn_stream));
+            if let Some(insn) = cached {
+                self.emulate_decoded_insn(&insn, &mut state)?;
+                state.set_ip(insn.next_ip());
+                return Ok(state);
+            }
+        }
+
         decoder.set_ip(state.ip());
 
         while decoder.can_decode() && !stop_emulation {
@@ -590,17 +718,15 @@ impl<'a, T: CpuStateManager> Emulator<'a, T> {
             }
 
             // Emulate the decoded instruction
-            Emulator::get_handler(insn.code())
-                .ok_or_else(|| {
-                    EmulationError::UnsupportedInstruction(anyhow!(
-                        "{:#x?} {:?} {:?}",
-                        insn_format!(insn),
-                        insn.mnemonic(),
-                        insn.code()
-                    ))
-                })?
-                .emulate(&insn, &mut state, self.platform)
-                .context(anyhow!("Failed to emulate {:#x?}", insn_format!(insn)))?;
+            self.emulate_decoded_insn(&insn, &mut state)?;
+
+            // Only the instructions decoded from the stream as given are
+            // cached, as the stream is what the lookups compare against.
+            if num_insn_emulated == 0 && !stop_emulation {
+                if let Some(cache) = self.cache.as_mut() {
+                    cache.insert(context, last_decoded_ip, &insn_stream[..insn.len()], insn);
+                }
+            }
 
             last_decoded_ip = decoder.ip();
             num_insn_emulated += 1;
@@ -858,4 +984,71 @@ mod tests {
         let mut vmm = MockVmm::new(ip, vec![], Some((ip, &memory)));
         assert!(vmm.emulate_first_insn(cpu_id, &insn).is_err());
     }
+
+    #[test]
+    // Emulate the same instruction twice through a cache, then another one
+    // at the same address, the same one once CR3 changed, and once the CPU
+    // mode changed.
+    //
+    // mov rax, 0x1000
+    // mov rax, 0x2000
+    fn test_insn_cache() {
+        let ip: u64 = 0x1000;
+        let cpu_id = 0;
+        let insn = [
+            0x48, 0xc7, 0xc0, 0x00, 0x10, 0x00, 0x00, // mov rax, 0x1000
+            0x90, // Read ahead
+        ];
+        let other_insn = [
+            0x48, 0xc7, 0xc0, 0x00, 0x20, 0x00, 0x00, // mov rax, 0x2000
+        ];
+
+        let mut cache = InstructionCache::default();
+        let mut vmm = MockVmm::new(ip, vec![], None);
+        for _ in 0..2 {
+            let state = Emulator::new(&mut vmm)
+                .with_cache(&mut cache)
+                .emulate_first_insn(cpu_id, &insn)
+                .unwrap();
+            assert_eq!(state.read_reg(Register::RAX).unwrap(), 0x1000);
+            assert_eq!(state.ip(), ip + 7);
+            assert_eq!(cache.entries.len(), 1);
+        }
+
+        let state = Emulator::new(&mut vmm)
+            .with_cache(&mut cache)
+            .emulate_first_insn(cpu_id, &other_insn)
+            .unwrap();
+        assert_eq!(state.read_reg(Register::RAX).unwrap(), 0x2000);
+        assert_eq!(cache.entries[&ip].bytes, other_insn.to_vec());
+
+        let mut vmm = MockVmm::new(ip, vec![(Register::CR3, 0x4000)], None);
+        let state = Emulator::new(&mut vmm)
+            .with_cache(&mut cache)
+            .emulate_first_insn(cpu_id, &insn)
+            .unwrap();
+        assert_eq!(state.read_reg(Register::RAX).unwrap(), 0x1000);
+        assert_eq!(cache.context.unwrap().cr3, 0x4000);
+        assert_eq!(cache.entries[&ip].bytes, insn[..7].to_vec());
+
+        let other_ip = ip + 0x100;
+        let mut vmm = MockVmm::new(other_ip, vec![(Register::CR3, 0x4000)], None);
+        Emulator::new(&mut vmm)
+            .with_cache(&mut cache)
+            .emulate_first_insn(cpu_id, &insn)
+            .unwrap();
+        assert_eq!(cache.entries.len(), 2);
+
+        let mut vmm = MockVmm::new(
+            ip,
+            vec![(Register::CR3, 0x4000), (Register::CR0, CR0_PE)],
+            None,
+        );
+        Emulator::new(&mut vmm)
+            .with_cache(&mut cache)
+            .emulate_first_insn(cpu_id, &insn)
+            .unwrap();
+        assert_eq!(cache.context.unwrap().mode, Some(CpuMode::Protected));
+        assert_eq!(cache.entries.len(), 1);
+    }
 }
@@ -149,6 +149,16 @@ pub enum HypervisorCpuError {
     #[error("Failed to init vcpu: {0}")]
     VcpuInit(#[source] anyhow::Error),
     ///
+    /// Vcpu features finalization error
+    ///
+    #[error("Failed to finalize vcpu features: {0}")]
+    VcpuFinalize(#[source] anyhow::Error),
+    ///
+    /// PMU initialization error
+    ///
+    #[error("Failed to initialize PMU: {0}")]
+    InitializePmu(#[source] anyhow::Error),
+    ///
     /// Setting one reg error
     ///
     #[error("Failed to init vcpu: {0}")]
@@ -183,6 +193,11 @@ pub enum HypervisorCpuError {
     #[error("Failed to enable HyperV SynIC")]
     EnableHyperVSyncIc(#[source] anyhow::Error),
     ///
+    /// Enabling the paravirtualized features enforcement error
+    ///
+    #[error("Failed to enable the paravirtualized features enforcement: {0}")]
+    EnablePvFeatureEnforcement(#[source] anyhow::Error),
+    ///
     /// Getting AArch64 core register error
     ///
     #[error("Failed to get core register: {0}")]
@@ -287,6 +302,12 @@ pub trait Vcpu: Send + Sync {
     fn enable_hyperv_synic(&self) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     ///
+    /// X86 specific call restricting the paravirtualized features, and
+    /// their MSRs, to the ones advertised through CPUID
+    ///
+    fn enable_pv_feature_enforcement(&self) -> Result<()>;
+    #[cfg(target_arch = "x86_64")]
+    ///
     /// X86 specific call to retrieve the CPUID registers.
     ///
     fn get_cpuid2(&self, num_entries: usize) -> Result<CpuId>;
@@ -364,6 +385,18 @@ pub trait Vcpu: Send + Sync {
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     fn vcpu_init(&self, kvi: &VcpuInit) -> Result<()>;
     ///
+    /// Finalizes the configuration of a feature enabled at init time, which
+    /// is required before the vCPU can run for features such as SVE.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn vcpu_finalize(&self, feature: i32) -> Result<()>;
+    ///
+    /// Initializes the PMU of the vCPU, signaling its overflows through the
+    /// given interrupt. This must be called after the GIC has been created.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn init_pmu(&self, irq: u32) -> Result<()>;
+    ///
     /// Sets the value of one register for this vCPU.
     ///
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
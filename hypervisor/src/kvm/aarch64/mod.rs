@@ -102,6 +102,17 @@ macro_rules! arm64_sys_reg {
 // https://elixir.bootlin.com/linux/v4.20.17/source/arch/arm64/include/asm/sysreg.h#L135
 arm64_sys_reg!(MPIDR_EL1, 3, 0, 0, 0, 5);
 
+// Constants imported from the Linux kernel:
+// https://elixir.bootlin.com/linux/v5.19/source/arch/arm64/include/uapi/asm/kvm.h#L356
+const KVM_REG_ARM_FW_FEAT_BMAP: u64 = 0x0016 << 16;
+/// Firmware pseudo-register holding the bitmap of the KVM vendor hypervisor
+/// services offered to the guest. It can only be written before the vCPU
+/// first runs.
+pub const KVM_REG_ARM_VENDOR_HYP_BMAP: u64 =
+    KVM_REG_ARM64 as u64 | KVM_REG_SIZE_U64 as u64 | KVM_REG_ARM_FW_FEAT_BMAP | 2;
+/// Bit of the PTP_KVM service in `KVM_REG_ARM_VENDOR_HYP_BMAP`.
+pub const KVM_REG_ARM_VENDOR_HYP_BIT_PTP: u8 = 1;
+
 /// Specifies whether a particular register is a system register or not.
 /// The kernel splits the registers on aarch64 in core registers and system registers.
 /// So, below we get the system registers by checking that they are not core registers.
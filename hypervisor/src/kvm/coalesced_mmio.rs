@@ -0,0 +1,173 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Coalesced MMIO and PIO, letting the guest write to some ranges without
+//! exiting. KVM queues the writes to these ranges in a ring shared by all the
+//! vCPUs, which replay them on their next exit, ahead of the access they
+//! exited on, so that the devices still see the accesses in order. The ring
+//! is also flushed outside of the exits, as a vCPU waiting for an interrupt
+//! the queued writes would raise doesn't exit.
+
+use crate::vm::{self, VmmOps};
+use kvm_bindings::{
+    kvm_coalesced_mmio, kvm_coalesced_mmio_ring, kvm_coalesced_mmio_zone, KVMIO,
+    KVM_CAP_COALESCED_MMIO, KVM_CAP_COALESCED_PIO,
+};
+use kvm_ioctls::{VcpuFd, VmFd};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{null_mut, read_volatile, write_volatile};
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Mutex;
+use vmm_sys_util::ioctl::{ioctl_with_ref, ioctl_with_val};
+use vmm_sys_util::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_iow_nr};
+
+// kvm-ioctls only tells whether a capability is supported, while the one of
+// coalesced MMIO is the page the ring is mapped at, and doesn't wrap the
+// registration of the ranges.
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_iow_nr!(
+    KVM_REGISTER_COALESCED_MMIO,
+    KVMIO,
+    0x67,
+    kvm_coalesced_mmio_zone
+);
+
+fn check_extension(vm: &VmFd, cap: u32) -> i32 {
+    // Safe because the ioctl doesn't touch the memory of the process.
+    unsafe { ioctl_with_val(vm, KVM_CHECK_EXTENSION(), cap.into()) }
+}
+
+/// Returns the page of the vCPU mappings the ring is found at, if KVM
+/// supports coalesced MMIO.
+pub(super) fn ring_page_offset(vm: &VmFd) -> Option<u64> {
+    match check_extension(vm, KVM_CAP_COALESCED_MMIO) {
+        offset if offset > 0 => Some(offset as u64),
+        _ => None,
+    }
+}
+
+/// Registers the range of `size` bytes at `addr`, in the port I/O space if
+/// `pio` is set, for the writes of the guest to it to be coalesced.
+pub(super) fn register(vm: &VmFd, addr: u64, size: u32, pio: bool) -> io::Result<()> {
+    let cap = if pio {
+        KVM_CAP_COALESCED_PIO
+    } else {
+        KVM_CAP_COALESCED_MMIO
+    };
+    if check_extension(vm, cap) <= 0 {
+        return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+    }
+
+    let mut zone = kvm_coalesced_mmio_zone {
+        addr,
+        size,
+        ..Default::default()
+    };
+    zone.__bindgen_anon_1.pio = pio as u32;
+    // Safe because the kernel only reads the zone, which outlives the call.
+    let ret = unsafe { ioctl_with_ref(vm, KVM_REGISTER_COALESCED_MMIO(), &zone) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// The ring of the coalesced writes, as mapped by a vCPU.
+pub(super) struct CoalescedMmioRing {
+    addr: *mut libc::c_void,
+    size: usize,
+    entries: u32,
+    // Serializes the vCPUs and the VM replaying the writes.
+    lock: Mutex<()>,
+}
+
+// Safe because the mapping is only accessed with the lock held.
+unsafe impl Send for CoalescedMmioRing {}
+unsafe impl Sync for CoalescedMmioRing {}
+
+impl CoalescedMmioRing {
+    pub(super) fn new(vcpu: &VcpuFd, page_offset: u64) -> io::Result<Self> {
+        // Safe because sysconf() has no side effect.
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // Safe because a new mapping is created, which is checked below.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                (page_offset as usize * size) as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(CoalescedMmioRing {
+            addr,
+            size,
+            entries: ((size - std::mem::size_of::<kvm_coalesced_mmio_ring>())
+                / std::mem::size_of::<kvm_coalesced_mmio>()) as u32,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Replays the writes queued in the ring through `vmmops`.
+    pub(super) fn replay(&self, vmmops: &dyn VmmOps) -> vm::Result<()> {
+        self.drain(|addr, pio, data| {
+            #[cfg(target_arch = "x86_64")]
+            if pio {
+                return vmmops.pio_write(addr, data);
+            }
+            #[cfg(not(target_arch = "x86_64"))]
+            let _ = pio;
+
+            vmmops.mmio_write(addr, data)
+        })
+    }
+
+    /// Passes the writes queued in the ring to `replay`, in order, as their
+    /// address, whether it's a port and their data.
+    fn drain<E>(&self, mut replay: impl FnMut(u64, bool, &[u8]) -> Result<(), E>) -> Result<(), E> {
+        let _lock = self.lock.lock().unwrap();
+        let header = self.addr as *mut kvm_coalesced_mmio_ring;
+        // Safe because the header and the entries are within the page
+        // mapped, which KVM only appends entries to.
+        unsafe {
+            let ring = (*header).coalesced_mmio.as_ptr();
+            let mut first = read_volatile(&(*header).first);
+            while first != read_volatile(&(*header).last) {
+                // Pairs with KVM writing the entry before moving last.
+                fence(Ordering::Acquire);
+                let entry = read_volatile(ring.add(first as usize));
+                let len = std::cmp::min(entry.len as usize, entry.data.len());
+                first = (first + 1) % self.entries;
+                // The entry is copied, KVM can reuse it.
+                fence(Ordering::Release);
+                write_volatile(&mut (*header).first, first);
+
+                replay(
+                    entry.phys_addr,
+                    entry.__bindgen_anon_1.pio != 0,
+                    &entry.data[..len],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for CoalescedMmioRing {
+    fn drop(&mut self) {
+        // Safe because the mapping was created in new().
+        unsafe {
+            libc::munmap(self.addr, self.size);
+        }
+    }
+}
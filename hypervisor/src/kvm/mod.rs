@@ -11,7 +11,7 @@
 #[cfg(target_arch = "aarch64")]
 pub use crate::aarch64::{
     check_required_kvm_extensions, is_system_register, VcpuInit, VcpuKvmState as CpuState,
-    MPIDR_EL1,
+    KVM_REG_ARM_VENDOR_HYP_BIT_PTP, KVM_REG_ARM_VENDOR_HYP_BMAP, MPIDR_EL1,
 };
 use crate::cpu;
 use crate::device;
@@ -30,10 +30,13 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 #[cfg(target_arch = "x86_64")]
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
 #[cfg(target_arch = "x86_64")]
 use vm_memory::Address;
 use vmm_sys_util::eventfd::EventFd;
+
+mod coalesced_mmio;
+use coalesced_mmio::CoalescedMmioRing;
 // x86_64 dependencies
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
@@ -89,6 +92,8 @@ pub use {
     kvm_ioctls::VcpuExit,
 };
 
+#[cfg(target_arch = "x86_64")]
+const KVM_CAP_ENFORCE_PV_FEATURE_CPUID: u32 = 190;
 #[cfg(target_arch = "x86_64")]
 const KVM_CAP_SGX_ATTRIBUTE: u32 = 196;
 
@@ -116,6 +121,11 @@ pub struct KvmVm {
     #[cfg(target_arch = "x86_64")]
     msrs: MsrEntries,
     state: KvmVmState,
+    // The ring of the coalesced writes, shared with the vCPUs, along with the
+    // operations replaying them, which the vCPUs own as they refer to the
+    // devices, which refer to the VM.
+    coalesced_mmio_ring: Mutex<Option<Arc<CoalescedMmioRing>>>,
+    vmmops: Mutex<Option<Weak<Box<dyn VmmOps>>>>,
 }
 
 ///
@@ -174,6 +184,26 @@ impl vm::Vm for KvmVm {
             .fd
             .create_vcpu(id as u64)
             .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+        // The ring of the coalesced writes is mapped through the first vCPU,
+        // and shared with the others, which replay the writes on their exits.
+        let coalesced_mmio_ring = {
+            let mut ring = self.coalesced_mmio_ring.lock().unwrap();
+            if ring.is_none() {
+                if let Some(page_offset) = coalesced_mmio::ring_page_offset(&self.fd) {
+                    *ring = Some(Arc::new(
+                        CoalescedMmioRing::new(&vc, page_offset)
+                            .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?,
+                    ));
+                }
+            }
+            ring.clone()
+        };
+        if let Some(vmmops) = &vmmops {
+            self.vmmops
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| Arc::downgrade(vmmops));
+        }
         let vcpu = KvmVcpu {
             fd: vc,
             #[cfg(target_arch = "x86_64")]
@@ -181,6 +211,7 @@ impl vm::Vm for KvmVm {
             vmmops,
             #[cfg(target_arch = "x86_64")]
             hyperv_synic: AtomicBool::new(false),
+            coalesced_mmio_ring,
         };
         Ok(Arc::new(vcpu))
     }
@@ -219,6 +250,33 @@ impl vm::Vm for KvmVm {
             .map_err(|e| vm::HypervisorVmError::UnregisterIoEvent(e.into()))
     }
     ///
+    /// Registers a range the guest writes to without exiting.
+    ///
+    fn register_coalesced_mmio(&self, addr: &IoEventAddress, size: u32) -> vm::Result<()> {
+        let (addr, pio) = match *addr {
+            IoEventAddress::Pio(addr) => (addr, true),
+            IoEventAddress::Mmio(addr) => (addr, false),
+        };
+        coalesced_mmio::register(&self.fd, addr, size, pio)
+            .map_err(|e| vm::HypervisorVmError::RegisterCoalescedMmio(e.into()))
+    }
+    ///
+    /// Replays the writes queued in the coalesced ranges.
+    ///
+    fn flush_coalesced_mmio(&self) -> vm::Result<()> {
+        let ring = self.coalesced_mmio_ring.lock().unwrap().clone();
+        let vmmops = self
+            .vmmops
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|vmmops| vmmops.upgrade());
+        match (ring, vmmops) {
+            (Some(ring), Some(vmmops)) => ring.replay(vmmops.as_ref().as_ref()),
+            _ => Ok(()),
+        }
+    }
+    ///
     /// Sets the GSI routing table entries, overwriting any previously set
     /// entries, as per the `KVM_SET_GSI_ROUTING` ioctl.
     ///
@@ -571,6 +629,8 @@ impl hypervisor::Hypervisor for KvmHypervisor {
                 fd: vm_fd,
                 msrs,
                 state: VmState {},
+                coalesced_mmio_ring: Mutex::new(None),
+                vmmops: Mutex::new(None),
             }))
         }
 
@@ -579,6 +639,8 @@ impl hypervisor::Hypervisor for KvmHypervisor {
             Ok(Arc::new(KvmVm {
                 fd: vm_fd,
                 state: VmState {},
+                coalesced_mmio_ring: Mutex::new(None),
+                vmmops: Mutex::new(None),
             }))
         }
     }
@@ -646,7 +708,20 @@ pub struct KvmVcpu {
     vmmops: Option<Arc<Box<dyn vm::VmmOps>>>,
     #[cfg(target_arch = "x86_64")]
     hyperv_synic: AtomicBool,
+    coalesced_mmio_ring: Option<Arc<CoalescedMmioRing>>,
 }
+
+impl KvmVcpu {
+    fn replay_coalesced_mmio(&self) -> cpu::Result<()> {
+        match (&self.coalesced_mmio_ring, &self.vmmops) {
+            (Some(ring), Some(vmmops)) => ring
+                .replay(vmmops.as_ref().as_ref())
+                .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into())),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// Implementation of Vcpu trait for KVM
 /// Example:
 /// #[cfg(feature = "kvm")]
@@ -738,6 +813,21 @@ impl cpu::Vcpu for KvmVcpu {
             .enable_cap(&cap)
             .map_err(|e| cpu::HypervisorCpuError::EnableHyperVSyncIc(e.into()))
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call restricting the paravirtualized features, and
+    /// their MSRs, to the ones advertised through CPUID
+    ///
+    fn enable_pv_feature_enforcement(&self) -> cpu::Result<()> {
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_ENFORCE_PV_FEATURE_CPUID,
+            ..Default::default()
+        };
+        cap.args[0] = 1;
+        self.fd
+            .enable_cap(&cap)
+            .map_err(|e| cpu::HypervisorCpuError::EnablePvFeatureEnforcement(e.into()))
+    }
     ///
     /// X86 specific call to retrieve the CPUID registers.
     ///
@@ -840,7 +930,12 @@ impl cpu::Vcpu for KvmVcpu {
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
-        match self.fd.run() {
+        let exit = self.fd.run();
+        // The writes the guest coalesced come before the access it exited
+        // on, if any.
+        self.replay_coalesced_mmio()?;
+
+        match exit {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
@@ -961,6 +1056,39 @@ impl cpu::Vcpu for KvmVcpu {
             .map_err(|e| cpu::HypervisorCpuError::VcpuInit(e.into()))
     }
     ///
+    /// Finalizes the configuration of a feature enabled at init time.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn vcpu_finalize(&self, feature: i32) -> cpu::Result<()> {
+        self.fd
+            .vcpu_finalize(&feature)
+            .map_err(|e| cpu::HypervisorCpuError::VcpuFinalize(e.into()))
+    }
+    ///
+    /// Initializes the PMU of the vCPU.
+    ///
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn init_pmu(&self, irq: u32) -> cpu::Result<()> {
+        let irq_attr = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_IRQ),
+            addr: &irq as *const u32 as u64,
+            flags: 0,
+        };
+        let init_attr = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT),
+            addr: 0,
+            flags: 0,
+        };
+        self.fd
+            .set_device_attr(&irq_attr)
+            .map_err(|e| cpu::HypervisorCpuError::InitializePmu(e.into()))?;
+        self.fd
+            .set_device_attr(&init_attr)
+            .map_err(|e| cpu::HypervisorCpuError::InitializePmu(e.into()))
+    }
+    ///
     /// Sets the value of one register for this vCPU.
     ///
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
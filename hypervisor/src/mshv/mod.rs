@@ -6,7 +6,7 @@
 use crate::arch::emulator::{PlatformEmulator, PlatformError};
 
 #[cfg(target_arch = "x86_64")]
-use crate::arch::x86::emulator::{Emulator, EmulatorCpuState};
+use crate::arch::x86::emulator::{Emulator, EmulatorCpuState, InstructionCache};
 use crate::cpu;
 use crate::cpu::Vcpu;
 use crate::hypervisor;
@@ -31,7 +31,7 @@ pub use x86_64::*;
 #[cfg(target_arch = "x86_64")]
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 
 pub const PAGE_SHIFT: usize = 12;
 
@@ -134,6 +134,7 @@ pub struct MshvVcpu {
     msrs: MsrEntries,
     hv_state: Arc<RwLock<HvState>>, // Mshv State
     vmmops: Option<Arc<Box<dyn vm::VmmOps>>>,
+    insn_cache: Mutex<InstructionCache>,
 }
 
 /// Implementation of Vcpu trait for Microsoft Hypervisor
@@ -268,6 +269,15 @@ impl cpu::Vcpu for MshvVcpu {
         /* We always have SynIC enabled on MSHV */
         Ok(())
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call restricting the paravirtualized features, and
+    /// their MSRs, to the ones advertised through CPUID
+    ///
+    fn enable_pv_feature_enforcement(&self) -> cpu::Result<()> {
+        /* MSHV doesn't offer the KVM paravirtualized features */
+        Ok(())
+    }
     #[allow(non_upper_case_globals)]
     fn run(&self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
         // Safe because this is just only done during initialization.
@@ -378,8 +388,10 @@ impl cpu::Vcpu for MshvVcpu {
                         map: (info.guest_virtual_address, info.guest_physical_address),
                     };
 
-                    // Create a new emulator.
-                    let mut emul = Emulator::new(&mut context);
+                    // Create a new emulator, remembering the instructions
+                    // it decodes for the next exits.
+                    let mut insn_cache = self.insn_cache.lock().unwrap();
+                    let mut emul = Emulator::new(&mut context).with_cache(&mut insn_cache);
 
                     // Emulate the trapped instruction, and only the first one.
                     let new_state = emul
@@ -755,6 +767,7 @@ impl vm::Vm for MshvVm {
             msrs: self.msrs.clone(),
             hv_state: self.hv_state.clone(),
             vmmops,
+            insn_cache: Mutex::new(InstructionCache::default()),
         };
         Ok(Arc::new(vcpu))
     }
@@ -804,6 +817,16 @@ impl vm::Vm for MshvVm {
             .map_err(|e| vm::HypervisorVmError::UnregisterIoEvent(e.into()))
     }
 
+    fn register_coalesced_mmio(&self, _addr: &IoEventAddress, _size: u32) -> vm::Result<()> {
+        Err(vm::HypervisorVmError::RegisterCoalescedMmio(anyhow!(
+            "Coalesced MMIO is not supported by MSHV"
+        )))
+    }
+
+    fn flush_coalesced_mmio(&self) -> vm::Result<()> {
+        Ok(())
+    }
+
     /// Creates a guest physical memory region.
     fn create_user_memory_region(&self, user_memory_region: MemoryRegion) -> vm::Result<()> {
         self.fd
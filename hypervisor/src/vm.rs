@@ -84,6 +84,11 @@ pub enum HypervisorVmError {
     #[error("Failed to register IO event: {0}")]
     RegisterIoEvent(#[source] anyhow::Error),
     ///
+    /// Register coalesced MMIO error
+    ///
+    #[error("Failed to register coalesced MMIO: {0}")]
+    RegisterCoalescedMmio(#[source] anyhow::Error),
+    ///
     /// Unregister IO event error
     ///
     #[error("Failed to unregister IO event: {0}")]
@@ -228,6 +233,12 @@ pub trait Vm: Send + Sync {
     ) -> Result<()>;
     /// Unregister an event from a certain address it has been previously registered to.
     fn unregister_ioevent(&self, fd: &EventFd, addr: &IoEventAddress) -> Result<()>;
+    /// Registers a range the guest writes to without exiting, the writes being
+    /// replayed through the `VmmOps` of the vCPUs on their next exit.
+    fn register_coalesced_mmio(&self, addr: &IoEventAddress, size: u32) -> Result<()>;
+    /// Replays the writes queued in the coalesced ranges through the `VmmOps`
+    /// of the vCPUs, without waiting for one of them to exit.
+    fn flush_coalesced_mmio(&self) -> Result<()>;
     /// Sets the GSI routing table entries, overwriting any previously set
     fn set_gsi_routing(&self, entries: &[IrqRoutingEntry]) -> Result<()>;
     /// Creates a memory region structure that can be used with {create/remove}_user_memory_region
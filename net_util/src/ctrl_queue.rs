@@ -36,13 +36,26 @@ pub struct ControlHeader {
 
 unsafe impl ByteValued for ControlHeader {}
 
+type MqHandler = Box<dyn FnMut(u16) -> bool + Send>;
+
 pub struct CtrlQueue {
     pub taps: Vec<Tap>,
+    mq_handler: Option<MqHandler>,
 }
 
 impl CtrlQueue {
     pub fn new(taps: Vec<Tap>) -> Self {
-        CtrlQueue { taps }
+        CtrlQueue {
+            taps,
+            mq_handler: None,
+        }
+    }
+
+    /// Registers a handler invoked with the number of queue pairs requested
+    /// by the driver, returning whether the request could be applied.
+    pub fn with_mq_handler(mut self, handler: MqHandler) -> Self {
+        self.mq_handler = Some(handler);
+        self
     }
 
     pub fn process(&mut self, mem: &GuestMemoryMmap, queue: &mut Queue) -> Result<bool> {
@@ -72,7 +85,10 @@ impl CtrlQueue {
                         false
                     } else {
                         info!("Number of MQ pairs requested: {}", queue_pairs);
-                        true
+                        match self.mq_handler.as_mut() {
+                            Some(handler) => handler(queue_pairs),
+                            None => true,
+                        }
                     }
                 }
                 VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
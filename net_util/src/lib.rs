@@ -17,6 +17,7 @@ mod ctrl_queue;
 mod mac;
 mod open_tap;
 mod queue_pair;
+mod rx_filter;
 mod tap;
 
 use std::io::Error as IoError;
@@ -37,7 +38,10 @@ type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 pub use ctrl_queue::{CtrlQueue, Error as CtrlQueueError};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use open_tap::{open_tap, Error as OpenTapError};
-pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
+pub use queue_pair::{
+    drop_tap_frames, NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio,
+};
+pub use rx_filter::RxFilter;
 pub use tap::{Error as TapError, Tap};
 
 #[derive(Debug)]
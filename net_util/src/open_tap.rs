@@ -25,6 +25,8 @@ pub enum Error {
     TapSetNetmask(TapError),
     /// Setting MAC address failed
     TapSetMac(TapError),
+    /// Setting MTU failed.
+    TapSetMtu(TapError),
     /// Getting MAC address failed
     TapGetMac(TapError),
     /// Setting vnet header size failed.
@@ -62,6 +64,7 @@ pub fn open_tap(
     ip_addr: Option<Ipv4Addr>,
     netmask: Option<Ipv4Addr>,
     host_mac: &mut Option<MacAddr>,
+    mtu: Option<u16>,
     num_rx_q: usize,
     flags: Option<i32>,
 ) -> Result<Vec<Tap>> {
@@ -94,6 +97,9 @@ pub fn open_tap(
             } else {
                 *host_mac = Some(tap.get_mac_addr().map_err(Error::TapGetMac)?)
             }
+            if let Some(mtu) = mtu {
+                tap.set_mtu(mtu as i32).map_err(Error::TapSetMtu)?;
+            }
             tap.enable().map_err(Error::TapEnable)?;
 
             tap.set_vnet_hdr_size(vnet_hdr_size)
@@ -7,16 +7,76 @@ use crate::GuestMemoryMmap;
 use rate_limiter::{RateLimiter, TokenType};
 use std::io;
 use std::num::Wrapping;
+use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use vm_memory::{Bytes, GuestAddressSpace, GuestMemory, GuestMemoryAtomic};
-use vm_virtio::Queue;
+use vm_memory::{
+    Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryError,
+    GuestMemoryRegion,
+};
+use vm_virtio::{DescriptorChain, NotificationSuppression, Queue};
+
+// Largest frame the tap can hand over, a 64KiB GSO frame along with its
+// Ethernet and virtio-net headers, as the virtio specification sizes it.
+const MAX_FRAME_LEN: usize = 65562;
+
+// Offset of the num_buffers field in the virtio-net header.
+const NUM_BUFFERS_OFFSET: usize = 10;
+
+// Appends the iovecs covering a guest memory range, one per memory region
+// the range spans, for the frames to be exchanged with the tap straight
+// from and to guest memory. Descriptors crossing memory regions don't have
+// to be copied this way either.
+fn push_iovecs(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: usize,
+    iovecs: &mut Vec<libc::iovec>,
+) -> Result<(), GuestMemoryError> {
+    let completed = mem.try_access(len, addr, |_, count, region_addr, region| {
+        let slice = region.get_slice(region_addr, count)?;
+        iovecs.push(libc::iovec {
+            iov_base: slice.as_ptr() as *mut libc::c_void,
+            iov_len: count as libc::size_t,
+        });
+        Ok(count)
+    })?;
+
+    if completed != len {
+        return Err(GuestMemoryError::PartialBuffer {
+            expected: len,
+            completed,
+        });
+    }
+
+    Ok(())
+}
+
+// Gathers the iovecs of the frame a transmit chain holds, from its readable
+// descriptors, the chain possibly going through an indirect table.
+fn tx_iovecs(
+    mem: &GuestMemoryMmap,
+    head: DescriptorChain,
+) -> Result<Vec<libc::iovec>, GuestMemoryError> {
+    let mut iovecs = Vec::new();
+    let mut next_desc = Some(head);
+    while let Some(desc) = next_desc {
+        if !desc.is_write_only() && desc.len > 0 {
+            push_iovecs(mem, desc.addr, desc.len as usize, &mut iovecs)?;
+        }
+        next_desc = desc.next_descriptor();
+    }
+
+    Ok(iovecs)
+}
 
 #[derive(Clone)]
 pub struct TxVirtio {
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    pub counter_dropped: Wrapping<u64>,
+    notification: NotificationSuppression,
 }
 
 impl Default for TxVirtio {
@@ -30,6 +90,8 @@ impl TxVirtio {
         TxVirtio {
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            counter_dropped: Wrapping(0),
+            notification: NotificationSuppression::new(),
         }
     }
 
@@ -39,32 +101,32 @@ impl TxVirtio {
         tap: &mut Tap,
         queue: &mut Queue,
         rate_limiter: &mut Option<RateLimiter>,
+        link_up: bool,
     ) -> Result<bool, NetQueuePairError> {
         let mut retry_write = false;
         let mut rate_limit_reached = false;
-        while let Some(avail_desc) = queue.iter(mem).next() {
+        loop {
+            let avail_desc = match queue.iter(mem).next() {
+                Some(avail_desc) => avail_desc,
+                None => {
+                    if self.notification.enable_notification(queue, mem) {
+                        continue;
+                    }
+                    break;
+                }
+            };
             if rate_limit_reached {
                 queue.go_to_previous_position();
                 break;
             }
 
             let head_index = avail_desc.index;
-            let mut next_desc = Some(avail_desc);
-
-            let mut iovecs = Vec::new();
-            while let Some(desc) = next_desc {
-                if !desc.is_write_only() && desc.len > 0 {
-                    let buf = mem
-                        .get_slice(desc.addr, desc.len as usize)
-                        .map_err(NetQueuePairError::GuestMemory)?
-                        .as_ptr();
-                    let iovec = libc::iovec {
-                        iov_base: buf as *mut libc::c_void,
-                        iov_len: desc.len as libc::size_t,
-                    };
-                    iovecs.push(iovec);
-                }
-                next_desc = desc.next_descriptor();
+            let mut iovecs = tx_iovecs(mem, avail_desc).map_err(NetQueuePairError::GuestMemory)?;
+
+            // The frames sent while the link is down go nowhere.
+            if !link_up {
+                iovecs.clear();
+                self.counter_dropped += Wrapping(1);
             }
 
             let len = if !iovecs.is_empty() {
@@ -97,8 +159,7 @@ impl TxVirtio {
                 0
             };
 
-            queue.add_used(mem, head_index, 0);
-            queue.update_avail_event(mem);
+            self.notification.add_used(queue, mem, head_index, 0);
 
             // For the sake of simplicity (similar to the RX rate limiting), we always
             // let the 'last' descriptor chain go-through even if it was over the rate
@@ -113,10 +174,49 @@ impl TxVirtio {
     }
 }
 
+/// Reads and drops the frames queued on a non-blocking tap until there are
+/// none left, returning how many were dropped.
+pub fn drop_tap_frames(fd: RawFd) -> io::Result<u64> {
+    // Each read consumes a whole frame, even when it doesn't fit the buffer.
+    let mut buf = [0u8; 1];
+    let mut dropped = 0;
+    loop {
+        // Safe because the buffer is valid for its length.
+        let result = unsafe {
+            libc::read(
+                fd as libc::c_int,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+
+            /* EAGAIN */
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(dropped);
+            }
+
+            return Err(e);
+        }
+
+        dropped += 1;
+    }
+}
+
 #[derive(Clone)]
 pub struct RxVirtio {
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    pub counter_dropped: Wrapping<u64>,
+    // Whether the driver negotiated VIRTIO_NET_F_MRG_RXBUF, letting a frame
+    // spread over several descriptor chains.
+    pub mergeable: bool,
+    notification: NotificationSuppression,
+    // Receives the part of a frame not fitting in the first descriptor chain.
+    spill: Vec<u8>,
+    // A frame read while the driver hadn't provided enough buffers for it.
+    pending: Option<Vec<u8>>,
 }
 
 impl Default for RxVirtio {
@@ -130,7 +230,89 @@ impl RxVirtio {
         RxVirtio {
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            counter_dropped: Wrapping(0),
+            mergeable: false,
+            notification: NotificationSuppression::new(),
+            spill: Vec::new(),
+            pending: None,
+        }
+    }
+
+    // Reads and drops the frames received by the tap, until there are none
+    // left, for the guest not to get any while the link is down.
+    fn drop_frames(&mut self, tap: &mut Tap) -> Result<(), NetQueuePairError> {
+        self.drop_pending();
+
+        let dropped = drop_tap_frames(tap.as_raw_fd()).map_err(|e| {
+            error!("net: rx: failed reading from tap: {}", e);
+            NetQueuePairError::ReadTap(e)
+        })?;
+        self.counter_dropped += Wrapping(dropped);
+
+        Ok(())
+    }
+
+    // Drops the frame read before the link went down, which the guest
+    // mustn't get once it's back up.
+    fn drop_pending(&mut self) {
+        if self.pending.take().is_some() {
+            self.counter_dropped += Wrapping(1);
+        }
+    }
+
+    // Hands a frame held in host memory over to the guest, which requires
+    // enough buffers for all of it. Returns false, giving the descriptor
+    // chains back, if the driver hasn't provided them yet. A frame the whole
+    // queue can't hold is dropped.
+    fn deliver_pending(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+        chains: &mut RxChains,
+        frame: &[u8],
+    ) -> Result<bool, NetQueuePairError> {
+        if !chains.gather(mem, queue, frame.len())? {
+            chains.rewind(queue);
+            if chains.descs < queue.actual_size() as usize {
+                return Ok(false);
+            }
+            // The driver has no more descriptors to provide buffers with.
+            debug!("net: rx: dropping frame larger than the whole queue");
+            self.counter_dropped += Wrapping(1);
+            return Ok(true);
         }
+        write_iovecs(&chains.iovecs, 0, frame);
+        self.add_used_frame(mem, queue, chains, frame.len());
+
+        Ok(true)
+    }
+
+    // Returns the chains a frame of `len` bytes was written to back to the
+    // driver, reporting how many of them it spreads over.
+    fn add_used_frame(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+        chains: &RxChains,
+        len: usize,
+    ) {
+        let used_lens = split_frame(&chains.chains, len);
+
+        // The number of chains the frame spreads over is reported in the
+        // num_buffers field of the header, at the start of the frame.
+        let num_buffers = used_lens.len() as u16;
+        write_iovecs(
+            &chains.iovecs,
+            NUM_BUFFERS_OFFSET,
+            &num_buffers.to_le_bytes(),
+        );
+
+        for (&(head_index, _), &used_len) in chains.chains.iter().zip(used_lens.iter()) {
+            self.notification.add_used(queue, mem, head_index, used_len);
+        }
+
+        self.counter_bytes += Wrapping((len - vnet_hdr_len()) as u64);
+        self.counter_frames += Wrapping(1);
     }
 
     pub fn process_desc_chain(
@@ -143,74 +325,131 @@ impl RxVirtio {
         let mut exhausted_descs = true;
         let mut rate_limit_reached = false;
 
-        while let Some(avail_desc) = queue.iter(mem).next() {
+        loop {
+            let avail_desc = match queue.iter(mem).next() {
+                Some(avail_desc) => avail_desc,
+                None => {
+                    if self.notification.enable_notification(queue, mem) {
+                        continue;
+                    }
+                    break;
+                }
+            };
             if rate_limit_reached {
                 exhausted_descs = false;
                 queue.go_to_previous_position();
                 break;
             }
 
-            let head_index = avail_desc.index;
-            let num_buffers_addr = mem.checked_offset(avail_desc.addr, 10).unwrap();
-            let mut next_desc = Some(avail_desc);
-
-            let mut iovecs = Vec::new();
-            while let Some(desc) = next_desc {
-                if desc.is_write_only() && desc.len > 0 {
-                    let buf = mem
-                        .get_slice(desc.addr, desc.len as usize)
-                        .map_err(NetQueuePairError::GuestMemory)?
-                        .as_ptr();
-                    let iovec = libc::iovec {
-                        iov_base: buf as *mut libc::c_void,
-                        iov_len: desc.len as libc::size_t,
-                    };
-                    iovecs.push(iovec);
+            let mut chains = RxChains::default();
+            chains.push(mem, avail_desc)?;
+
+            // A frame which didn't fit in the buffers available when it was
+            // read goes first.
+            if let Some(frame) = self.pending.take() {
+                if !self.deliver_pending(mem, queue, &mut chains, &frame)? {
+                    self.pending = Some(frame);
+                    queue.update_avail_event(mem);
+                    break;
                 }
-                next_desc = desc.next_descriptor();
+                let len = frame.len();
+                if let Some(rate_limiter) = rate_limiter {
+                    rate_limit_reached = !rate_limiter.consume(1, TokenType::Ops)
+                        || !rate_limiter.consume(len as u64, TokenType::Bytes);
+                }
+                continue;
             }
 
-            let len = if !iovecs.is_empty() {
-                let result = unsafe {
-                    libc::readv(
-                        tap.as_raw_fd() as libc::c_int,
-                        iovecs.as_ptr() as *const libc::iovec,
-                        iovecs.len() as libc::c_int,
-                    )
-                };
-                if result < 0 {
-                    let e = std::io::Error::last_os_error();
-                    exhausted_descs = false;
-                    queue.go_to_previous_position();
+            if chains.iovecs.is_empty() {
+                self.notification
+                    .add_used(queue, mem, chains.chains[0].0, 0);
+                continue;
+            }
 
-                    /* EAGAIN */
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        break;
-                    }
+            // The frame is read straight into the first descriptor chain.
+            // With mergeable buffers, what doesn't fit is read into the spill
+            // buffer, and copied to as many more chains as it takes once the
+            // length of the frame is known. Either way, the tap silently
+            // truncates the frames not fitting in the buffers. An extra byte
+            // is appended to detect this case, since a truncated frame can't
+            // be handed over to the guest.
+            let capacity = chains.capacity;
+            let spill_len = if self.mergeable {
+                MAX_FRAME_LEN.saturating_sub(capacity)
+            } else {
+                0
+            } + 1;
+            self.spill.resize(spill_len.max(self.spill.len()), 0);
+            let mut iovecs = chains.iovecs.clone();
+            iovecs.push(libc::iovec {
+                iov_base: self.spill.as_mut_ptr() as *mut libc::c_void,
+                iov_len: spill_len,
+            });
+
+            let result = unsafe {
+                libc::readv(
+                    tap.as_raw_fd() as libc::c_int,
+                    iovecs.as_ptr() as *const libc::iovec,
+                    iovecs.len() as libc::c_int,
+                )
+            };
+            if result < 0 {
+                let e = std::io::Error::last_os_error();
+                exhausted_descs = false;
+                chains.rewind(queue);
 
-                    error!("net: rx: failed reading from tap: {}", e);
-                    return Err(NetQueuePairError::ReadTap(e));
+                /* EAGAIN */
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    break;
                 }
 
-                // Write num_buffers to guest memory. We simply write 1 as we
-                // never spread the frame over more than one descriptor chain.
-                mem.write_obj(1u16, num_buffers_addr)
-                    .map_err(NetQueuePairError::GuestMemory)?;
+                error!("net: rx: failed reading from tap: {}", e);
+                return Err(NetQueuePairError::ReadTap(e));
+            }
 
-                self.counter_bytes += Wrapping(result as u64 - vnet_hdr_len() as u64);
-                self.counter_frames += Wrapping(1);
+            let len = result as usize;
+            if len >= capacity + spill_len || len < vnet_hdr_len() {
+                // Drop the frame and reuse the descriptor chain for the next
+                // one.
+                debug!(
+                    "net: rx: dropping frame larger than the {} bytes buffer",
+                    capacity + spill_len - 1
+                );
+                chains.rewind(queue);
+                self.counter_dropped += Wrapping(1);
+                continue;
+            }
 
-                result as u32
-            } else {
-                0
-            };
+            if len > capacity {
+                let head_iovecs = chains.iovecs.len();
+                if !chains.gather(mem, queue, len)? {
+                    if chains.descs >= queue.actual_size() as usize {
+                        // The driver has no more descriptors to provide
+                        // buffers with.
+                        debug!("net: rx: dropping frame larger than the whole queue");
+                        chains.rewind(queue);
+                        self.counter_dropped += Wrapping(1);
+                        continue;
+                    }
 
-            queue.add_used(mem, head_index, len);
-            queue.update_avail_event(mem);
+                    // Rather than dropping the frame, keep it until the
+                    // driver provides more buffers.
+                    let mut frame = vec![0u8; len];
+                    read_iovecs(&chains.iovecs[..head_iovecs], &mut frame[..capacity]);
+                    frame[capacity..].copy_from_slice(&self.spill[..len - capacity]);
+                    self.pending = Some(frame);
+                    chains.rewind(queue);
+                    queue.update_avail_event(mem);
+                    break;
+                }
+                write_iovecs(&chains.iovecs, capacity, &self.spill[..len - capacity]);
+            }
+
+            self.add_used_frame(mem, queue, &chains, len);
 
             // For the sake of simplicity (keeping the handling of RX_QUEUE_EVENT and
-            // RX_TAP_EVENT totally asynchronous), we always let the 'last' descriptor
-            // chain go-through even if it was over the rate limit, and simply stop
+            // RX_TAP_EVENT totally asynchronous), we always let the 'last' frame
+            // go-through even if it was over the rate limit, and simply stop
             // processing oncoming `avail_desc` if any.
             if let Some(rate_limiter) = rate_limiter {
                 rate_limit_reached = !rate_limiter.consume(1, TokenType::Ops)
@@ -222,12 +461,133 @@ impl RxVirtio {
     }
 }
 
+// The descriptor chains a frame is received into, all of them popped from
+// the available ring.
+#[derive(Default)]
+struct RxChains {
+    // The head index and the capacity of each chain.
+    chains: Vec<(u16, usize)>,
+    iovecs: Vec<libc::iovec>,
+    capacity: usize,
+    descs: usize,
+}
+
+impl RxChains {
+    fn push(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain<GuestMemoryMmap>,
+    ) -> Result<(), NetQueuePairError> {
+        let head_index = head.index;
+        let mut chain_capacity = 0;
+        let mut next_desc = Some(head);
+        while let Some(desc) = next_desc {
+            if desc.is_write_only() && desc.len > 0 {
+                push_iovecs(mem, desc.addr, desc.len as usize, &mut self.iovecs)
+                    .map_err(NetQueuePairError::GuestMemory)?;
+                chain_capacity += desc.len as usize;
+            }
+            self.descs += 1;
+            next_desc = desc.next_descriptor();
+        }
+        self.chains.push((head_index, chain_capacity));
+        self.capacity += chain_capacity;
+
+        Ok(())
+    }
+
+    // Pops descriptor chains until they can hold `len` bytes. Returns false
+    // if the available ring runs out first.
+    fn gather(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+        len: usize,
+    ) -> Result<bool, NetQueuePairError> {
+        while self.capacity < len {
+            match queue.iter(mem).next() {
+                Some(head) => self.push(mem, head)?,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    // Gives all the chains back to the driver.
+    fn rewind(&self, queue: &mut Queue) {
+        for _ in 0..self.chains.len() {
+            queue.go_to_previous_position();
+        }
+    }
+}
+
+// Splits a frame over the descriptor chains it was read into, returning how
+// many bytes each of them received, up to the last one the frame reached.
+fn split_frame(chains: &[(u16, usize)], len: usize) -> Vec<u32> {
+    let mut used_lens = Vec::new();
+    let mut remaining = len;
+    for &(_, capacity) in chains {
+        let used_len = remaining.min(capacity);
+        used_lens.push(used_len as u32);
+        remaining -= used_len;
+        if remaining == 0 {
+            break;
+        }
+    }
+    used_lens
+}
+
+// Reads the start of the buffers the iovecs point to into `data`. The
+// iovecs must be valid, and cover the range being read.
+fn read_iovecs(iovecs: &[libc::iovec], mut data: &mut [u8]) {
+    for iovec in iovecs {
+        if data.is_empty() {
+            break;
+        }
+
+        let count = data.len().min(iovec.iov_len);
+        // Safe because the iovec points to `iov_len` bytes of valid memory.
+        unsafe {
+            std::ptr::copy_nonoverlapping(iovec.iov_base as *const u8, data.as_mut_ptr(), count);
+        }
+        data = &mut data[count..];
+    }
+}
+
+// Writes `data` at `offset` in the buffers the iovecs point to, which might
+// split it. The iovecs must be valid, and cover the range being written.
+fn write_iovecs(iovecs: &[libc::iovec], mut offset: usize, mut data: &[u8]) {
+    for iovec in iovecs {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= iovec.iov_len {
+            offset -= iovec.iov_len;
+            continue;
+        }
+
+        let count = data.len().min(iovec.iov_len - offset);
+        // Safe because the iovec points to `iov_len` bytes of valid memory.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (iovec.iov_base as *mut u8).add(offset),
+                count,
+            );
+        }
+        data = &data[count..];
+        offset = 0;
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct NetCounters {
     pub tx_bytes: Arc<AtomicU64>,
     pub tx_frames: Arc<AtomicU64>,
+    pub tx_dropped: Arc<AtomicU64>,
     pub rx_bytes: Arc<AtomicU64>,
     pub rx_frames: Arc<AtomicU64>,
+    pub rx_dropped: Arc<AtomicU64>,
 }
 
 #[derive(Debug)]
@@ -265,6 +625,11 @@ pub struct NetQueuePair {
     pub rx_desc_avail: bool,
     pub rx_rate_limiter: Option<RateLimiter>,
     pub tx_rate_limiter: Option<RateLimiter>,
+    // Whether the link is up, the frames being dropped otherwise.
+    pub link_up: Arc<AtomicBool>,
+    // Whether the link was up the last time frames were received, for the
+    // ones held from before it went down to be dropped.
+    pub link_was_up: bool,
 }
 
 impl NetQueuePair {
@@ -280,6 +645,7 @@ impl NetQueuePair {
             &mut self.tap,
             &mut queue,
             &mut self.tx_rate_limiter,
+            self.link_up.load(Ordering::Acquire),
         )?;
 
         // We got told to try again when writing to the tap. Wait for the TAP to be writable
@@ -311,10 +677,14 @@ impl NetQueuePair {
         self.counters
             .tx_frames
             .fetch_add(self.tx.counter_frames.0, Ordering::AcqRel);
+        self.counters
+            .tx_dropped
+            .fetch_add(self.tx.counter_dropped.0, Ordering::AcqRel);
         self.tx.counter_bytes = Wrapping(0);
         self.tx.counter_frames = Wrapping(0);
+        self.tx.counter_dropped = Wrapping(0);
 
-        Ok(queue.needs_notification(&mem, queue.next_used))
+        Ok(self.tx.notification.needs_notification(queue, mem.deref()))
     }
 
     pub fn process_rx(&mut self, mut queue: &mut Queue) -> Result<bool, NetQueuePairError> {
@@ -324,6 +694,23 @@ impl NetQueuePair {
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
 
+        if !self.link_up.load(Ordering::Acquire) {
+            self.link_was_up = false;
+            self.rx.drop_frames(&mut self.tap)?;
+            self.counters
+                .rx_dropped
+                .fetch_add(self.rx.counter_dropped.0, Ordering::AcqRel);
+            self.rx.counter_dropped = Wrapping(0);
+            return Ok(false);
+        }
+
+        // The frames queued on the tap while the link was down are dropped
+        // when it's brought up, but one may have been read already.
+        if !self.link_was_up {
+            self.link_was_up = true;
+            self.rx.drop_pending();
+        }
+
         self.rx_desc_avail = !self.rx.process_desc_chain(
             &mem,
             &mut self.tap,
@@ -355,9 +742,174 @@ impl NetQueuePair {
         self.counters
             .rx_frames
             .fetch_add(self.rx.counter_frames.0, Ordering::AcqRel);
+        self.counters
+            .rx_dropped
+            .fetch_add(self.rx.counter_dropped.0, Ordering::AcqRel);
         self.rx.counter_bytes = Wrapping(0);
         self.rx.counter_frames = Wrapping(0);
+        self.rx.counter_dropped = Wrapping(0);
+
+        Ok(self.rx.notification.needs_notification(queue, mem.deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_virtio::queue::testing::{VirtQueue, VirtqDesc};
+    use vm_virtio::queue::{VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    #[test]
+    fn test_tx_iovecs_indirect() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+
+        // The header is followed by an indirect descriptor holding the
+        // frame in two pieces, and a write only descriptor to skip.
+        let indirect_table = [
+            VirtqDesc::new(GuestAddress(0x2000), &mem),
+            VirtqDesc::new(GuestAddress(0x2010), &mem),
+            VirtqDesc::new(GuestAddress(0x2020), &mem),
+        ];
+        indirect_table[0].set(0x3000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        indirect_table[1].set(0x4000, 0x200, VIRTQ_DESC_F_NEXT, 2);
+        indirect_table[2].set(0x5000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable[0].set(0x1000, 12, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 48, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        let mut queue = vq.create_queue();
+        let head = queue.iter(&mem).next().unwrap();
+        let iovecs = tx_iovecs(&mem, head).unwrap();
+        let iovecs: Vec<(*mut u8, usize)> = iovecs
+            .iter()
+            .map(|iovec| (iovec.iov_base as *mut u8, iovec.iov_len))
+            .collect();
+        assert_eq!(
+            iovecs,
+            vec![
+                (mem.get_host_address(GuestAddress(0x1000)).unwrap(), 12),
+                (mem.get_host_address(GuestAddress(0x3000)).unwrap(), 0x100),
+                (mem.get_host_address(GuestAddress(0x4000)).unwrap(), 0x200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_push_iovecs() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+        let mut iovecs = Vec::new();
+
+        push_iovecs(&mem, GuestAddress(0x100), 0x200, &mut iovecs).unwrap();
+        assert_eq!(iovecs.len(), 1);
+        assert_eq!(iovecs[0].iov_len, 0x200);
+
+        // A range crossing regions gets one iovec per region.
+        iovecs.clear();
+        push_iovecs(&mem, GuestAddress(0xf00), 0x200, &mut iovecs).unwrap();
+        assert_eq!(iovecs.len(), 2);
+        assert_eq!(iovecs[0].iov_len, 0x100);
+        assert_eq!(iovecs[1].iov_len, 0x100);
+        assert_eq!(
+            iovecs[1].iov_base as *mut u8,
+            mem.get_host_address(GuestAddress(0x1000)).unwrap()
+        );
+
+        // A range going past the end of the guest memory is rejected.
+        assert!(push_iovecs(&mem, GuestAddress(0x1f00), 0x200, &mut iovecs).is_err());
+        assert!(push_iovecs(&mem, GuestAddress(0x2000), 0x100, &mut iovecs).is_err());
+    }
+
+    #[test]
+    fn test_split_frame() {
+        let chains = [(0, 0x600), (3, 0x600), (7, 0x600)];
+
+        assert_eq!(split_frame(&chains, 0x100), vec![0x100]);
+        assert_eq!(split_frame(&chains, 0x600), vec![0x600]);
+        assert_eq!(split_frame(&chains, 0x700), vec![0x600, 0x100]);
+        assert_eq!(split_frame(&chains, 0x1200), vec![0x600, 0x600, 0x600]);
+    }
+
+    #[test]
+    fn test_write_iovecs() {
+        let mut first = [0u8; 11];
+        let mut second = [0u8; 4];
+        let iovecs = [
+            libc::iovec {
+                iov_base: first.as_mut_ptr() as *mut libc::c_void,
+                iov_len: first.len(),
+            },
+            libc::iovec {
+                iov_base: second.as_mut_ptr() as *mut libc::c_void,
+                iov_len: second.len(),
+            },
+        ];
+
+        // The num_buffers field is split when the header is.
+        write_iovecs(&iovecs, NUM_BUFFERS_OFFSET, &0x0302u16.to_le_bytes());
+        assert_eq!(first[NUM_BUFFERS_OFFSET], 0x02);
+        assert_eq!(second, [0x03, 0, 0, 0]);
+
+        write_iovecs(&iovecs, 12, &[0xff]);
+        assert_eq!(second, [0x03, 0xff, 0, 0]);
+
+        let mut data = [0u8; 13];
+        read_iovecs(&iovecs, &mut data);
+        assert_eq!(data[NUM_BUFFERS_OFFSET..], [0x02, 0x03, 0xff]);
+    }
+
+    #[test]
+    fn test_drop_tap_frames() {
+        // A datagram socket keeps the frame boundaries, like a tap.
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe {
+                libc::socketpair(
+                    libc::AF_UNIX,
+                    libc::SOCK_DGRAM | libc::SOCK_NONBLOCK,
+                    0,
+                    fds.as_mut_ptr(),
+                )
+            },
+            0
+        );
+
+        assert_eq!(drop_tap_frames(fds[0]).unwrap(), 0);
+
+        for len in [1, 64, 1500].iter() {
+            let frame = vec![0xaau8; *len];
+            assert_eq!(
+                unsafe { libc::write(fds[1], frame.as_ptr() as *const libc::c_void, *len) },
+                *len as isize
+            );
+        }
+        assert_eq!(drop_tap_frames(fds[0]).unwrap(), 3);
+        assert_eq!(drop_tap_frames(fds[0]).unwrap(), 0);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+
+        // Any other error than EAGAIN is reported.
+        assert!(drop_tap_frames(-1).is_err());
+    }
+
+    #[test]
+    fn test_drop_pending() {
+        let mut rx = RxVirtio::new();
+
+        rx.drop_pending();
+        assert_eq!(rx.counter_dropped, Wrapping(0));
 
-        Ok(queue.needs_notification(&mem, queue.next_used))
+        rx.pending = Some(vec![0u8; 64]);
+        rx.drop_pending();
+        assert!(rx.pending.is_none());
+        assert_eq!(rx.counter_dropped, Wrapping(1));
     }
 }
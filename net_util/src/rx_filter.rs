@@ -0,0 +1,127 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+// Maximum number of instructions of a classic BPF program (BPF_MAXINSNS).
+const MAX_CLASSIC_INSTRUCTIONS: usize = 4096;
+
+// Command retrieving a pinned object from the BPF filesystem.
+const BPF_OBJ_GET: libc::c_long = 7;
+
+// Attributes of the BPF_OBJ_GET command.
+#[repr(C)]
+#[derive(Default)]
+struct BpfObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+/// Filter run on the frames the TAP interface receives from the host, the
+/// frames it rejects being dropped before reaching the guest.
+#[derive(Debug)]
+pub enum RxFilter {
+    /// Classic BPF program.
+    Classic(Vec<net_gen::sock_filter>),
+    /// eBPF socket filter program.
+    Ebpf(File),
+}
+
+impl RxFilter {
+    /// Reads a classic BPF program from `path`, as printed by `tcpdump -ddd`:
+    /// the number of instructions, followed by one instruction per line,
+    /// made of its code, jumps and constant as decimal numbers.
+    pub fn classic(path: &Path) -> io::Result<Self> {
+        parse_classic(&fs::read_to_string(path)?).map(RxFilter::Classic)
+    }
+
+    /// Gets the eBPF program pinned at `path` in the BPF filesystem.
+    pub fn ebpf(path: &Path) -> io::Result<Self> {
+        let pathname = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let attr = BpfObjGetAttr {
+            pathname: pathname.as_ptr() as u64,
+            ..Default::default()
+        };
+
+        // Safe because the attributes are properly sized and outlive the
+        // call, and we check the return value.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_OBJ_GET,
+                &attr as *const BpfObjGetAttr,
+                std::mem::size_of::<BpfObjGetAttr>(),
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because the file descriptor was just returned, and nothing
+        // else owns it.
+        Ok(RxFilter::Ebpf(unsafe { File::from_raw_fd(fd as i32) }))
+    }
+}
+
+fn parse_classic(program: &str) -> io::Result<Vec<net_gen::sock_filter>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut lines = program.lines().map(str::trim).filter(|l| !l.is_empty());
+    let len: usize = lines
+        .next()
+        .and_then(|l| l.parse().ok())
+        .ok_or_else(|| invalid("Missing BPF instruction count"))?;
+    if len == 0 || len > MAX_CLASSIC_INSTRUCTIONS {
+        return Err(invalid("Invalid BPF instruction count"));
+    }
+
+    let mut instructions = Vec::with_capacity(len);
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(invalid("Invalid BPF instruction"));
+        }
+        let invalid_field = |_| invalid("Invalid BPF instruction");
+        instructions.push(net_gen::sock_filter {
+            code: fields[0].parse().map_err(invalid_field)?,
+            jt: fields[1].parse().map_err(invalid_field)?,
+            jf: fields[2].parse().map_err(invalid_field)?,
+            k: fields[3].parse().map_err(invalid_field)?,
+        });
+    }
+    if instructions.len() != len {
+        return Err(invalid("BPF instruction count mismatch"));
+    }
+
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_classic() {
+        // tcpdump -ddd arp
+        let program = parse_classic("4\n40 0 0 12\n21 0 1 2054\n6 0 0 262144\n6 0 0 0\n").unwrap();
+        assert_eq!(program.len(), 4);
+        assert_eq!(program[1].code, 21);
+        assert_eq!(program[1].jt, 0);
+        assert_eq!(program[1].jf, 1);
+        assert_eq!(program[1].k, 2054);
+
+        assert!(parse_classic("").is_err());
+        assert!(parse_classic("0\n").is_err());
+        assert!(parse_classic("2\n6 0 0 0\n").is_err());
+        assert!(parse_classic("1\n6 0 0\n").is_err());
+        assert!(parse_classic("1\n6 0 256 0\n").is_err());
+    }
+}
@@ -5,7 +5,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use super::{create_sockaddr, create_socket, vnet_hdr_len, Error as NetUtilError, MacAddr};
+use super::{
+    create_sockaddr, create_socket, vnet_hdr_len, Error as NetUtilError, MacAddr, RxFilter,
+};
 use crate::mac::MAC_ADDR_LEN;
 use std::fs::File;
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
@@ -297,6 +299,43 @@ impl Tap {
         Ok(())
     }
 
+    /// Set the MTU of the tap interface.
+    pub fn set_mtu(&self, mtu: c_int) -> Result<()> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        ifreq.ifr_ifru.ifru_mtu = mtu;
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFMTU as c_ulong, &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Get the MTU of the tap interface.
+    pub fn mtu(&self) -> Result<c_int> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCGIFMTU as c_ulong, &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        // We only access one field of the ifru union, hence this is safe.
+        let mtu = unsafe { ifreq.ifr_ifru.ifru_mtu };
+
+        Ok(mtu)
+    }
+
     /// Set the offload flags for the tap interface.
     pub fn set_offload(&self, flags: c_uint) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -346,6 +385,32 @@ impl Tap {
         Ok(())
     }
 
+    /// Attach a filter to the frames received by the tap interface from the
+    /// host, the frames it rejects being dropped.
+    pub fn set_rx_filter(&self, filter: &RxFilter) -> Result<()> {
+        let ret = match filter {
+            RxFilter::Classic(instructions) => {
+                let prog = net_gen::sock_fprog {
+                    len: instructions.len() as c_ushort,
+                    filter: instructions.as_ptr() as *mut net_gen::sock_filter,
+                };
+                // ioctl is safe. Called with a valid tap fd, the program
+                // isn't modified by the kernel, and we check the return.
+                unsafe { ioctl_with_ref(&self.tap_file, net_gen::TUNATTACHFILTER(), &prog) }
+            }
+            RxFilter::Ebpf(prog) => {
+                let prog_fd: c_int = prog.as_raw_fd();
+                // ioctl is safe. Called with a valid tap fd, and we check the return.
+                unsafe { ioctl_with_ref(&self.tap_file, net_gen::TUNSETFILTEREBPF(), &prog_fd) }
+            }
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Set the size of the vnet hdr.
     pub fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -591,6 +656,9 @@ mod tests {
         assert!(ret.is_ok());
         let ret = tap.set_netmask(netmask);
         assert!(ret.is_ok());
+        let ret = tap.set_mtu(9000);
+        assert!(ret.is_ok());
+        assert_eq!(tap.mtu().unwrap(), 9000);
     }
 
     #[test]
@@ -37,6 +37,73 @@ impl fmt::Display for OptionParserError {
 }
 type OptionParserResult<T> = std::result::Result<T, OptionParserError>;
 
+// Splits `s` on the separators found outside of any brackets or quotes, for
+// the values to be lists or to contain separators themselves, as in
+// cpus=[0,2],path="a,b".
+fn split_outside(s: &str, separator: char) -> OptionParserResult<Vec<String>> {
+    let mut list = Vec::new();
+    let mut item = String::new();
+    let mut opened_brackets = 0usize;
+    let mut in_quotes = false;
+
+    for c in s.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '[' if !in_quotes => opened_brackets += 1,
+            ']' if !in_quotes => {
+                opened_brackets = opened_brackets
+                    .checked_sub(1)
+                    .ok_or_else(|| OptionParserError::InvalidSyntax(s.to_owned()))?
+            }
+            c if c == separator && !in_quotes && opened_brackets == 0 => {
+                list.push(item);
+                item = String::new();
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    if in_quotes || opened_brackets != 0 {
+        return Err(OptionParserError::InvalidSyntax(s.to_owned()));
+    }
+    list.push(item);
+
+    Ok(list)
+}
+
+// Removes the quotes around `s`, if any.
+fn unquote(s: &str) -> &str {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        &s[1..s.len() - 1]
+    } else {
+        s
+    }
+}
+
+/// Splits a list into its items. Lists are given as "[a,b,c]", where the
+/// items can be quoted or be lists themselves, or as "a:b:c", the syntax of
+/// the lists before brackets were supported.
+pub fn split_list(s: &str) -> OptionParserResult<Vec<String>> {
+    let s = s.trim();
+    if !s.starts_with('[') {
+        return Ok(s.split(':').map(|item| item.to_owned()).collect());
+    }
+    if !s.ends_with(']') {
+        return Err(OptionParserError::InvalidSyntax(s.to_owned()));
+    }
+
+    let items = &s[1..s.len() - 1];
+    if items.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(split_outside(items, ',')?
+        .iter()
+        .map(|item| unquote(item.trim()).to_owned())
+        .collect())
+}
+
 impl OptionParser {
     pub fn new() -> Self {
         Self {
@@ -49,19 +116,19 @@ impl OptionParser {
             return Ok(());
         }
 
-        let options_list: Vec<&str> = input.trim().split(',').collect();
+        let options_list = split_outside(input.trim(), ',')?;
 
         for option in options_list.iter() {
-            let parts: Vec<&str> = option.split('=').collect();
+            let parts = split_outside(option, '=')?;
 
-            match self.options.get_mut(parts[0]) {
-                None => return Err(OptionParserError::UnknownOption(parts[0].to_owned())),
+            match self.options.get_mut(parts[0].as_str()) {
+                None => return Err(OptionParserError::UnknownOption(parts[0].clone())),
                 Some(value) => {
                     if value.requires_value {
                         if parts.len() != 2 {
-                            return Err(OptionParserError::InvalidSyntax((*option).to_owned()));
+                            return Err(OptionParserError::InvalidSyntax(option.clone()));
                         }
-                        value.value = Some(parts[1].trim().to_owned());
+                        value.value = Some(unquote(parts[1].trim()).to_owned());
                     } else {
                         value.value = Some(String::new());
                     }
@@ -183,13 +250,14 @@ impl FromStr for IntegerList {
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         let mut integer_list = Vec::new();
-        let ranges_list: Vec<&str> = s.trim().split(':').collect();
+        let ranges_list =
+            split_list(s).map_err(|_| IntegerListParseError::InvalidValue(s.to_owned()))?;
 
         for range in ranges_list.iter() {
             let items: Vec<&str> = range.split('-').collect();
 
             if items.len() > 2 {
-                return Err(IntegerListParseError::InvalidValue((*range).to_string()));
+                return Err(IntegerListParseError::InvalidValue(range.to_string()));
             }
 
             let start_range = items[0]
@@ -203,7 +271,7 @@ impl FromStr for IntegerList {
                     .parse::<u64>()
                     .map_err(|_| IntegerListParseError::InvalidValue(items[1].to_owned()))?;
                 if start_range >= end_range {
-                    return Err(IntegerListParseError::InvalidValue((*range).to_string()));
+                    return Err(IntegerListParseError::InvalidValue(range.to_string()));
                 }
 
                 for i in start_range..end_range {
@@ -227,15 +295,14 @@ impl FromStr for TupleTwoIntegers {
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         let mut list = Vec::new();
-        let tuples_list: Vec<&str> = s.trim().split(':').collect();
+        let tuples_list =
+            split_list(s).map_err(|_| TupleTwoIntegersParseError::InvalidValue(s.to_owned()))?;
 
         for tuple in tuples_list.iter() {
             let items: Vec<&str> = tuple.split('@').collect();
 
             if items.len() != 2 {
-                return Err(TupleTwoIntegersParseError::InvalidValue(
-                    (*tuple).to_string(),
-                ));
+                return Err(TupleTwoIntegersParseError::InvalidValue(tuple.to_string()));
             }
 
             let item1 = items[0]
@@ -262,7 +329,8 @@ impl FromStr for StringList {
     type Err = StringListParseError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let string_list: Vec<String> = s.trim().split(':').map(|e| e.to_owned()).collect();
+        let string_list =
+            split_list(s).map_err(|_| StringListParseError::InvalidValue(s.to_owned()))?;
 
         Ok(StringList(string_list))
     }
@@ -0,0 +1,86 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::configuration::{
+    PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType, PciProgrammingInterface,
+};
+use crate::device::PciDevice;
+use std::any::Any;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+
+const VENDOR_ID_REDHAT: u16 = 0x1b36;
+const DEVICE_ID_REDHAT_PCI_BRIDGE: u16 = 0x0001;
+
+/// Programming interfaces of the PCI-to-PCI bridges.
+#[derive(Clone, Copy)]
+pub enum PciBridgeProgrammingInterface {
+    /// Forwards the accesses to the ranges it doesn't claim, such as a
+    /// transparent bridge.
+    SubtractiveDecode = 0x01,
+}
+
+impl PciProgrammingInterface for PciBridgeProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// Emulates a transparent PCI-to-PCI bridge, adding a secondary bus behind
+/// the bus it sits on. The bridge doesn't claim any address range, and
+/// forwards every access it receives to its secondary bus, which means the
+/// devices behind it can be given addresses from any range of the host
+/// bridge, without having to manage the windows of the bridge.
+///
+/// The bus numbers are fixed, as the devices are looked up through them.
+pub struct PciBridge {
+    config: PciConfiguration,
+}
+
+impl PciBridge {
+    pub fn new(primary_bus: u8, secondary_bus: u8) -> Self {
+        let mut config = PciConfiguration::new(
+            VENDOR_ID_REDHAT,
+            DEVICE_ID_REDHAT_PCI_BRIDGE,
+            0,
+            PciClassCode::BridgeDevice,
+            &PciBridgeSubclass::PciToPciBridge,
+            Some(&PciBridgeProgrammingInterface::SubtractiveDecode),
+            PciHeaderType::Bridge,
+            0,
+            0,
+            None,
+        );
+
+        // The bridges are chained, each of them being the last device of the
+        // bus behind the previous one, which is why any bus above the
+        // secondary one is reachable through the bridge.
+        config.set_bus_numbers(primary_bus, secondary_bus, 0xff);
+
+        PciBridge { config }
+    }
+}
+
+impl BusDevice for PciBridge {}
+
+impl PciDevice for PciBridge {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.config.write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.config.read_reg(reg_idx)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
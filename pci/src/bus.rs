@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use crate::bridge::PciBridge;
 use crate::configuration::{
     PciBarRegionType, PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType,
 };
@@ -13,10 +14,14 @@ use std::ops::DerefMut;
 use std::sync::{Arc, Barrier, Mutex};
 use vm_device::{Bus, BusDevice};
 use vm_memory::{Address, GuestAddress, GuestUsize};
+use vmm_sys_util::eventfd::EventFd;
 
 const VENDOR_ID_INTEL: u16 = 0x8086;
 const DEVICE_ID_INTEL_VIRT_PCIE_HOST: u16 = 0x0d57;
 const NUM_DEVICE_IDS: usize = 32;
+const NUM_BUSES: usize = 256;
+/// The last device of each bus is kept for the bridge to the next bus.
+pub const BRIDGE_DEVICE_ID: usize = NUM_DEVICE_IDS - 1;
 
 /// Errors for device manager.
 #[derive(Debug)]
@@ -90,12 +95,18 @@ impl PciDevice for PciRoot {
     }
 }
 
+/// Devices reachable from the host bridge, either directly on the root bus,
+/// or on the secondary buses of the PCI-to-PCI bridges chained behind it.
+/// The devices are identified by their bus and device numbers, the same way
+/// as in their b/d/f, meaning the device 0x21 is the device 1 of the bus 1.
 pub struct PciBus {
-    /// Devices attached to this bus.
+    /// Functions attached to this bus, by b/d/f. Each device has at least
+    /// its function 0.
     /// Device 0 is host bridge.
     devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>>,
     device_reloc: Arc<dyn DeviceRelocation>,
     device_ids: Vec<bool>,
+    root_bus_only: bool,
 }
 
 impl PciBus {
@@ -110,12 +121,61 @@ impl PciBus {
             devices,
             device_reloc,
             device_ids,
+            root_bus_only: false,
         }
     }
 
+    /// Allocates the next devices on the root bus only, without adding any
+    /// bridge, as the devices behind a bridge can't be hot plugged.
+    pub fn restrict_to_root_bus(&mut self) {
+        self.root_bus_only = true;
+    }
+
+    /// Returns the devices of the root bus which are not used yet.
+    pub fn free_root_device_ids(&self) -> Vec<u32> {
+        (0..NUM_DEVICE_IDS)
+            .filter(|id| !self.device_ids[*id])
+            .map(|id| id as u32)
+            .collect()
+    }
+
+    /// Returns the device of the root bus the given device is reached
+    /// through, which is the first bridge for the devices behind it.
+    pub fn root_device_id(&self, id: u32) -> u32 {
+        if (id as usize) < NUM_DEVICE_IDS {
+            id
+        } else {
+            BRIDGE_DEVICE_ID as u32
+        }
+    }
+
+    // Adds a bridge on the last device of the last bus, behind which a new
+    // bus is created.
+    fn add_bridge(&mut self) -> Result<()> {
+        let secondary_bus = self.device_ids.len() / NUM_DEVICE_IDS;
+        if self.root_bus_only || secondary_bus >= NUM_BUSES {
+            return Err(PciRootError::NoPciDeviceSlotAvailable);
+        }
+
+        let bridge_id = self.device_ids.len() - NUM_DEVICE_IDS + BRIDGE_DEVICE_ID;
+        if self.device_ids[bridge_id] {
+            return Err(PciRootError::AlreadyInUsePciDeviceSlot(bridge_id));
+        }
+        self.device_ids[bridge_id] = true;
+        self.device_ids
+            .resize(self.device_ids.len() + NUM_DEVICE_IDS, false);
+
+        let bridge = PciBridge::new(secondary_bus as u8 - 1, secondary_bus as u8);
+        self.devices
+            .insert((bridge_id as u32) << 3, Arc::new(Mutex::new(bridge)));
+
+        Ok(())
+    }
+
     pub fn register_mapping(
         &self,
         dev: Arc<Mutex<dyn BusDevice>>,
+        id: &str,
         #[cfg(target_arch = "x86_64")] io_bus: &Bus,
         mmio_bus: &Bus,
         bars: Vec<(GuestAddress, GuestUsize, PciBarRegionType)>,
@@ -125,14 +185,14 @@ impl PciBus {
                 PciBarRegionType::IoRegion => {
                     #[cfg(target_arch = "x86_64")]
                     io_bus
-                        .insert(dev.clone(), address.raw_value(), size)
+                        .insert(dev.clone(), address.raw_value(), size, id)
                         .map_err(PciRootError::PioInsert)?;
                     #[cfg(target_arch = "aarch64")]
                     error!("I/O region is not supported");
                 }
                 PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
                     mmio_bus
-                        .insert(dev.clone(), address.raw_value(), size)
+                        .insert(dev.clone(), address.raw_value(), size, id)
                         .map_err(PciRootError::MmioInsert)?;
                 }
             }
@@ -145,7 +205,7 @@ impl PciBus {
         pci_device_bdf: u32,
         device: Arc<Mutex<dyn PciDevice>>,
     ) -> Result<()> {
-        self.devices.insert(pci_device_bdf >> 3, device);
+        self.devices.insert(pci_device_bdf, device);
         Ok(())
     }
 
@@ -155,18 +215,34 @@ impl PciBus {
     }
 
     pub fn next_device_id(&mut self) -> Result<u32> {
+        let last_bus_start = self.device_ids.len() - NUM_DEVICE_IDS;
         for (idx, device_id) in self.device_ids.iter_mut().enumerate() {
+            // Unless the bridges are disabled, the last device of the last
+            // bus is kept for the bridge to the next one.
+            if !self.root_bus_only && idx == last_bus_start + BRIDGE_DEVICE_ID {
+                break;
+            }
+            if self.root_bus_only && idx >= NUM_DEVICE_IDS {
+                break;
+            }
             if !(*device_id) {
                 *device_id = true;
                 return Ok(idx as u32);
             }
         }
 
-        Err(PciRootError::NoPciDeviceSlotAvailable)
+        self.add_bridge()?;
+        self.next_device_id()
     }
 
     pub fn get_device_id(&mut self, id: usize) -> Result<()> {
-        if id < NUM_DEVICE_IDS {
+        // The bridges the device is behind are added first, when restoring
+        // a device from a secondary bus.
+        while id >= self.device_ids.len() && id < NUM_BUSES * NUM_DEVICE_IDS {
+            self.add_bridge()?;
+        }
+
+        if id < self.device_ids.len() {
             if !self.device_ids[id] {
                 self.device_ids[id] = true;
                 Ok(())
@@ -179,7 +255,7 @@ impl PciBus {
     }
 
     pub fn put_device_id(&mut self, id: usize) -> Result<()> {
-        if id < NUM_DEVICE_IDS {
+        if id < self.device_ids.len() {
             self.device_ids[id] = false;
             Ok(())
         } else {
@@ -188,17 +264,31 @@ impl PciBus {
     }
 }
 
+// The reset control register, at 0xcf9, overlaps the config address
+// register, which is only ever accessed 32 bits at a time. Setting the reset
+// CPU bit resets the machine, the other bits only tell what kind of reset it
+// is, which makes no difference to a VM.
+const RESET_CONTROL_OFFSET: u64 = 1;
+const RESET_CONTROL_SYS_RESET: u8 = 1 << 1;
+const RESET_CONTROL_RESET_CPU: u8 = 1 << 2;
+const RESET_CONTROL_FULL_RESET: u8 = 1 << 3;
+
 pub struct PciConfigIo {
     /// Config space register.
     config_address: u32,
     pci_bus: Arc<Mutex<PciBus>>,
+    /// Reset control register.
+    reset_control: u8,
+    reset_evt: EventFd,
 }
 
 impl PciConfigIo {
-    pub fn new(pci_bus: Arc<Mutex<PciBus>>) -> Self {
+    pub fn new(pci_bus: Arc<Mutex<PciBus>>, reset_evt: EventFd) -> Self {
         PciConfigIo {
             pci_bus,
             config_address: 0,
+            reset_control: 0,
+            reset_evt,
         }
     }
 
@@ -211,21 +301,11 @@ impl PciConfigIo {
         let (bus, device, function, register) =
             parse_io_config_address(self.config_address & !0x8000_0000);
 
-        // Only support one bus.
-        if bus != 0 {
-            return 0xffff_ffff;
-        }
-
-        // Don't support multi-function devices.
-        if function > 0 {
-            return 0xffff_ffff;
-        }
-
         self.pci_bus
             .lock()
             .unwrap()
             .devices
-            .get(&(device as u32))
+            .get(&bdf(bus, device, function))
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -241,16 +321,11 @@ impl PciConfigIo {
             return None;
         }
 
-        let (bus, device, _function, register) =
+        let (bus, device, function, register) =
             parse_io_config_address(self.config_address & !0x8000_0000);
 
-        // Only support one bus.
-        if bus != 0 {
-            return None;
-        }
-
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
+        if let Some(d) = pci_bus.devices.get(&bdf(bus, device, function)) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -295,10 +370,25 @@ impl PciConfigIo {
         };
         self.config_address = (self.config_address & !mask) | value;
     }
+
+    fn set_reset_control(&mut self, value: u8) {
+        self.reset_control = value & (RESET_CONTROL_SYS_RESET | RESET_CONTROL_FULL_RESET);
+        if value & RESET_CONTROL_RESET_CPU != 0 {
+            debug!("PCI reset signalled");
+            if let Err(e) = self.reset_evt.write(1) {
+                error!("Error triggering PCI reset event: {}", e);
+            }
+        }
+    }
 }
 
 impl BusDevice for PciConfigIo {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset == RESET_CONTROL_OFFSET && data.len() == 1 {
+            data[0] = self.reset_control;
+            return;
+        }
+
         // `offset` is relative to 0xcf8
         let value = match offset {
             0..=3 => self.config_address,
@@ -321,6 +411,11 @@ impl BusDevice for PciConfigIo {
     }
 
     fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset == RESET_CONTROL_OFFSET && data.len() == 1 {
+            self.set_reset_control(data[0]);
+            return None;
+        }
+
         // `offset` is relative to 0xcf8
         match offset {
             o @ 0..=3 => {
@@ -344,18 +439,13 @@ impl PciConfigMmio {
     }
 
     fn config_space_read(&self, config_address: u32) -> u32 {
-        let (bus, device, _function, register) = parse_mmio_config_address(config_address);
-
-        // Only support one bus.
-        if bus != 0 {
-            return 0xffff_ffff;
-        }
+        let (bus, device, function, register) = parse_mmio_config_address(config_address);
 
         self.pci_bus
             .lock()
             .unwrap()
             .devices
-            .get(&(device as u32))
+            .get(&bdf(bus, device, function))
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -366,15 +456,10 @@ impl PciConfigMmio {
             return;
         }
 
-        let (bus, device, _function, register) = parse_mmio_config_address(config_address);
-
-        // Only support one bus.
-        if bus != 0 {
-            return;
-        }
+        let (bus, device, function, register) = parse_mmio_config_address(config_address);
 
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
+        if let Some(d) = pci_bus.devices.get(&bdf(bus, device, function)) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -428,6 +513,11 @@ impl BusDevice for PciConfigMmio {
     }
 }
 
+// Identifies a function by its bus, device and function numbers.
+fn bdf(bus: usize, device: usize, function: usize) -> u32 {
+    ((bus * NUM_DEVICE_IDS + device) << 3 | function) as u32
+}
+
 fn shift_and_mask(value: u32, offset: usize, mask: u32) -> usize {
     ((value >> offset) & mask) as usize
 }
@@ -471,3 +561,113 @@ fn parse_io_config_address(config_address: u32) -> (usize, usize, usize, usize)
         shift_and_mask(config_address, REGISTER_NUMBER_OFFSET, REGISTER_NUMBER_MASK),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoRelocation;
+
+    impl DeviceRelocation for NoRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_device_ids_across_buses() {
+        let mut pci_bus = PciBus::new(PciRoot::new(None), Arc::new(NoRelocation));
+
+        // The host bridge is device 0, and the last device of the root bus
+        // is kept for the bridge.
+        for id in 1..31 {
+            assert_eq!(pci_bus.next_device_id().unwrap(), id);
+        }
+        assert_eq!(pci_bus.next_device_id().unwrap(), 0x20);
+        assert!(pci_bus.devices.contains_key(&(31 << 3)));
+        assert_eq!(pci_bus.root_device_id(0x20), 31);
+        assert_eq!(pci_bus.root_device_id(3), 3);
+
+        // A freed device is reused first.
+        pci_bus.put_device_id(3).unwrap();
+        assert_eq!(pci_bus.next_device_id().unwrap(), 3);
+
+        // Restoring a device adds the bridges it is behind.
+        pci_bus.get_device_id(0x45).unwrap();
+        assert!(pci_bus.devices.contains_key(&(0x3f << 3)));
+        assert!(pci_bus.get_device_id(0x45).is_err());
+
+        // Only the root bus is used once restricted to it.
+        pci_bus.restrict_to_root_bus();
+        assert!(pci_bus.next_device_id().is_err());
+        assert!(pci_bus.free_root_device_ids().is_empty());
+        pci_bus.put_device_id(5).unwrap();
+        pci_bus.put_device_id(7).unwrap();
+        assert_eq!(pci_bus.free_root_device_ids(), vec![5, 7]);
+        assert_eq!(pci_bus.next_device_id().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_multifunction_config_access() {
+        let pci_bus = Arc::new(Mutex::new(PciBus::new(
+            PciRoot::new(None),
+            Arc::new(NoRelocation),
+        )));
+        // Functions 0 and 1 of the device 2.
+        for function in 0..2 {
+            pci_bus
+                .lock()
+                .unwrap()
+                .add_device(2 << 3 | function, Arc::new(Mutex::new(PciRoot::new(None))))
+                .unwrap();
+        }
+
+        let config_mmio = PciConfigMmio::new(pci_bus.clone());
+        let read_mmio = |function: u32| config_mmio.config_space_read(2 << 15 | function << 12);
+        assert_eq!(read_mmio(0) & 0xffff, VENDOR_ID_INTEL as u32);
+        assert_eq!(read_mmio(1) & 0xffff, VENDOR_ID_INTEL as u32);
+        assert_eq!(read_mmio(2), 0xffff_ffff);
+
+        let mut config_io = PciConfigIo::new(pci_bus, EventFd::new(0).unwrap());
+        let mut read_io = |function: u32| {
+            config_io.config_address = 0x8000_0000 | 2 << 11 | function << 8;
+            config_io.config_space_read()
+        };
+        assert_eq!(read_io(1) & 0xffff, VENDOR_ID_INTEL as u32);
+        assert_eq!(read_io(2), 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_reset_control() {
+        let pci_bus = Arc::new(Mutex::new(PciBus::new(
+            PciRoot::new(None),
+            Arc::new(NoRelocation),
+        )));
+        let reset_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut config_io = PciConfigIo::new(pci_bus, reset_evt.try_clone().unwrap());
+
+        // Selecting the kind of reset doesn't reset yet.
+        config_io.write(0xcf8, 1, &[0x2]);
+        assert!(reset_evt.read().is_err());
+        let mut data = [0u8];
+        config_io.read(0xcf8, 1, &mut data);
+        assert_eq!(data[0], 0x2);
+
+        config_io.write(0xcf8, 1, &[0x6]);
+        assert_eq!(reset_evt.read().unwrap(), 1);
+        config_io.read(0xcf8, 1, &mut data);
+        assert_eq!(data[0], 0x2);
+
+        // The config address is still reached through 32 bits accesses.
+        config_io.write(0xcf8, 0, &0x8000_1000u32.to_le_bytes());
+        assert_eq!(config_io.config_address, 0x8000_1000);
+        assert!(reset_evt.read().is_err());
+    }
+}
@@ -17,6 +17,7 @@ const NUM_CONFIGURATION_REGISTERS: usize = 1024;
 const STATUS_REG: usize = 1;
 const STATUS_REG_CAPABILITIES_USED_MASK: u32 = 0x0010_0000;
 const BAR0_REG: usize = 4;
+const BUS_NUMBERS_REG: usize = 6;
 const ROM_BAR_REG: usize = 12;
 const BAR_IO_ADDR_MASK: u32 = 0xffff_fffc;
 const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
@@ -27,6 +28,7 @@ const FIRST_CAPABILITY_OFFSET: usize = 0x40;
 const CAPABILITY_MAX_OFFSET: usize = 192;
 
 const INTERRUPT_LINE_PIN_REG: usize = 15;
+pub const SUBSYSTEM_ID_REG: usize = 11;
 
 /// Represents the types of PCI headers allowed in the configuration registers.
 #[derive(Copy, Clone)]
@@ -246,6 +248,12 @@ pub trait PciCapability {
     fn id(&self) -> PciCapabilityId;
 }
 
+/// Splits the value of the subsystem ID register into the subsystem vendor
+/// and subsystem device IDs.
+pub fn subsystem_id_from_register(register: u32) -> (u16, u16) {
+    ((register & 0xffff) as u16, (register >> 16) as u16)
+}
+
 fn encode_32_bits_bar_size(bar_size: u32) -> Option<u32> {
     if bar_size > 0 {
         return Some(!(bar_size - 1));
@@ -429,11 +437,15 @@ impl PciConfiguration {
             }
             PciHeaderType::Bridge => {
                 registers[3] = 0x0001_0000; // Header type 1 (bridge)
+                registers[7] = 0x0000_00f0; // I/O window disabled (base > limit)
+                registers[8] = 0x0000_fff0; // Memory window disabled
+                registers[9] = 0x0000_fff0; // Prefetchable memory window disabled
                 writable_bits[9] = 0xfff0_fff0; // Memory base and limit
                 writable_bits[15] = 0xffff_00ff; // Bridge control (r/w), interrupt line (r/w)
             }
         };
-        registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+        registers[SUBSYSTEM_ID_REG] =
+            u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
 
         let bars = [PciBar::default(); NUM_BAR_REGS];
 
@@ -685,6 +697,26 @@ impl PciConfiguration {
             | u32::from(line);
     }
 
+    /// Configures the primary, secondary and subordinate bus numbers of a
+    /// bridge.
+    pub fn set_bus_numbers(&mut self, primary: u8, secondary: u8, subordinate: u8) {
+        self.registers[BUS_NUMBERS_REG] = (self.registers[BUS_NUMBERS_REG] & 0xff00_0000)
+            | u32::from(subordinate) << 16
+            | u32::from(secondary) << 8
+            | u32::from(primary);
+    }
+
+    /// Overrides the subsystem vendor and device IDs set at creation time.
+    pub fn set_subsystem_id(&mut self, subsystem_vendor_id: u16, subsystem_id: u16) {
+        self.registers[SUBSYSTEM_ID_REG] =
+            u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+    }
+
+    /// Returns the subsystem vendor and device IDs.
+    pub fn subsystem_id(&self) -> (u16, u16) {
+        subsystem_id_from_register(self.registers[SUBSYSTEM_ID_REG])
+    }
+
     /// Adds the capability `cap_data` to the list of capabilities.
     /// `cap_data` should include the two-byte PCI capability header (type, next),
     /// but not populate it. Correct values will be generated automatically based
@@ -1053,4 +1085,36 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn subsystem_id() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+        );
+        assert_eq!(cfg.subsystem_id(), (0xABCD, 0x2468));
+
+        cfg.set_subsystem_id(0x8086, 0x1357);
+        assert_eq!(cfg.read_reg(SUBSYSTEM_ID_REG), 0x1357_8086);
+        assert_eq!(cfg.subsystem_id(), (0x8086, 0x1357));
+
+        // The register is read-only for the guest.
+        cfg.write_config_register(SUBSYSTEM_ID_REG, 0, &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(cfg.subsystem_id(), (0x8086, 0x1357));
+    }
+
+    #[test]
+    fn subsystem_id_register_split() {
+        assert_eq!(subsystem_id_from_register(0x1357_8086), (0x8086, 0x1357));
+        assert_eq!(subsystem_id_from_register(0xffff_0000), (0, 0xffff));
+        assert_eq!(subsystem_id_from_register(0), (0, 0));
+    }
 }
@@ -19,6 +19,8 @@ pub enum Error {
     IoAllocationFailed(u64),
     /// Registering an IO BAR failed.
     IoRegistrationFailed(u64, configuration::Error),
+    /// Shared memory region doesn't fit in its BAR or reuses an identifier.
+    SharedMemoryRegionInvalid(u8, u64, u64),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -34,6 +36,11 @@ impl Display for Error {
             IoRegistrationFailed(addr, e) => {
                 write!(f, "failed to register an IO BAR, addr={} err={}", addr, e)
             }
+            SharedMemoryRegionInvalid(id, offset, len) => write!(
+                f,
+                "invalid shared memory region, id={} offset=0x{:x} len=0x{:x}",
+                id, offset, len
+            ),
         }
     }
 }
@@ -6,6 +6,7 @@
 #[macro_use]
 extern crate log;
 
+mod bridge;
 mod bus;
 mod configuration;
 mod device;
@@ -13,18 +14,20 @@ mod msi;
 mod msix;
 mod vfio;
 
-pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
+pub use self::bridge::PciBridge;
+pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError, BRIDGE_DEVICE_ID};
 pub use self::configuration::{
-    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability, PciCapabilityId,
-    PciClassCode, PciConfiguration, PciHeaderType, PciMassStorageSubclass,
-    PciNetworkControllerSubclass, PciProgrammingInterface, PciSerialBusSubClass, PciSubclass,
+    subsystem_id_from_register, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType,
+    PciCapability, PciCapabilityId, PciClassCode, PciConfiguration, PciHeaderType,
+    PciMassStorageSubclass, PciNetworkControllerSubclass, PciProgrammingInterface,
+    PciSerialBusSubClass, PciSubclass, SUBSYSTEM_ID_REG,
 };
 pub use self::device::{
     BarReprogrammingParams, DeviceRelocation, Error as PciDeviceError, PciDevice,
 };
 pub use self::msi::{msi_num_enabled_vectors, MsiCap, MsiConfig};
 pub use self::msix::{MsixCap, MsixConfig, MsixTableEntry, MSIX_TABLE_ENTRY_SIZE};
-pub use self::vfio::{VfioPciDevice, VfioPciError};
+pub use self::vfio::{vfio_iova_ranges, VfioPciDevice, VfioPciError};
 
 /// PCI has four interrupt pins A->D.
 #[derive(Copy, Clone)]
@@ -12,7 +12,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use std::any::Any;
 use std::os::unix::io::AsRawFd;
 use std::ptr::null_mut;
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Weak};
 use std::{fmt, io, result};
 use vfio_bindings::bindings::vfio::*;
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioError};
@@ -81,6 +81,110 @@ impl PciSubclass for PciVfioSubclass {
     }
 }
 
+// VFIO_IOMMU_GET_INFO, _IO(VFIO_TYPE, VFIO_BASE + 12).
+const VFIO_IOMMU_GET_INFO: u64 = 0x3b70;
+// The info is followed by a capability chain.
+const VFIO_IOMMU_INFO_CAPS: u32 = 1 << 1;
+const VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE: u16 = 1;
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct VfioIommuType1Info {
+    argsz: u32,
+    flags: u32,
+    iova_pgsizes: u64,
+    cap_offset: u32,
+    pad: u32,
+}
+
+// Parses the IOVA ranges capability out of the VFIO_IOMMU_GET_INFO reply,
+// whose capabilities are chained through their offset in the reply.
+fn parse_iova_ranges(info: &[u8]) -> Vec<(u64, u64)> {
+    let read_u16 = |offset: usize| info.get(offset..offset + 2).map(LittleEndian::read_u16);
+    let read_u32 = |offset: usize| info.get(offset..offset + 4).map(LittleEndian::read_u32);
+    let read_u64 = |offset: usize| info.get(offset..offset + 8).map(LittleEndian::read_u64);
+
+    let mut ranges = Vec::new();
+    let mut offset = read_u32(16).unwrap_or(0) as usize;
+    // The chain goes forward, which bounds the walk.
+    while offset >= std::mem::size_of::<VfioIommuType1Info>() {
+        // struct vfio_info_cap_header { u16 id; u16 version; u32 next; }
+        let (id, next) = match (read_u16(offset), read_u32(offset + 4)) {
+            (Some(id), Some(next)) => (id, next as usize),
+            _ => break,
+        };
+        if id == VFIO_IOMMU_TYPE1_INFO_CAP_IOVA_RANGE {
+            // The header is followed by u32 nr_iovas, u32 reserved and the
+            // array of struct vfio_iova_range { u64 start; u64 end; }.
+            let count = read_u32(offset + 8).unwrap_or(0) as usize;
+            for i in 0..count {
+                let range = offset + 16 + i * 16;
+                match (read_u64(range), read_u64(range + 8)) {
+                    (Some(start), Some(end)) => ranges.push((start, end)),
+                    _ => break,
+                }
+            }
+            break;
+        }
+        if next <= offset {
+            break;
+        }
+        offset = next;
+    }
+
+    ranges
+}
+
+/// Gets the IOVA ranges, bounds included, the host IOMMU lets the devices
+/// of `container` use. The addresses in between are reserved by the host,
+/// for the MSI doorbells or the RMRRs. The ranges are empty when the kernel
+/// doesn't report them.
+pub fn vfio_iova_ranges(container: &VfioContainer) -> io::Result<Vec<(u64, u64)>> {
+    let mut info = VfioIommuType1Info {
+        argsz: std::mem::size_of::<VfioIommuType1Info>() as u32,
+        ..Default::default()
+    };
+    // Safe because the kernel writes at most argsz bytes to info, and the
+    // return value is checked.
+    let ret = unsafe { libc::ioctl(container.as_raw_fd(), VFIO_IOMMU_GET_INFO as _, &mut info) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if info.flags & VFIO_IOMMU_INFO_CAPS == 0 {
+        return Ok(Vec::new());
+    }
+
+    // The kernel updated argsz to the size the capabilities need.
+    let argsz = std::cmp::max(
+        info.argsz as usize,
+        std::mem::size_of::<VfioIommuType1Info>(),
+    );
+    let mut buf = vec![0u64; (argsz + 7) / 8];
+    // Safe because the buffer is large enough and aligned for the info.
+    unsafe {
+        *(buf.as_mut_ptr() as *mut VfioIommuType1Info) = VfioIommuType1Info {
+            argsz: argsz as u32,
+            ..Default::default()
+        };
+    }
+    // Safe because the kernel writes at most argsz bytes to the buffer, and
+    // the return value is checked.
+    let ret = unsafe {
+        libc::ioctl(
+            container.as_raw_fd(),
+            VFIO_IOMMU_GET_INFO as _,
+            buf.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the buffer holds argsz initialized bytes.
+    let info = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, argsz) };
+    Ok(parse_iova_ranges(info))
+}
+
 enum InterruptUpdateAction {
     EnableMsi,
     DisableMsi,
@@ -124,6 +228,21 @@ struct VfioMsix {
     cap: MsixCap,
     cap_offset: u32,
     interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    // The eventfds the device signals the vectors through.
+    irq_fds: Vec<EventFd>,
+}
+
+// Whether the eventfd has been signaled, without consuming its counter.
+fn eventfd_signaled(eventfd: &EventFd) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: eventfd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Safe because pollfd outlives the call and the count is 1.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+
+    ret > 0 && (pollfd.revents & libc::POLLIN) != 0
 }
 
 impl VfioMsix {
@@ -155,6 +274,43 @@ impl VfioMsix {
 
         bar_index == table_bir && offset >= table_offset && offset < table_offset + table_size
     }
+
+    fn pba_accessed(&self, bar_index: u32, offset: u64) -> bool {
+        let pba_offset: u64 = u64::from(self.cap.pba_offset());
+        let pba_size: u64 = ((u64::from(self.cap.table_size()) + 63) / 64) * 8;
+        let pba_bir: u32 = self.cap.pba_bir();
+
+        bar_index == pba_bir && offset >= pba_offset && offset < pba_offset + pba_size
+    }
+
+    // A vector is pending when the device signaled it while it was masked,
+    // as the interrupt is only delivered once it gets unmasked. The bits are
+    // emulated, since the device only sets them in hardware when the mask
+    // bits are in hardware too, while the guest ones are only cached here.
+    fn read_pba(&self, offset: u64, data: &mut [u8]) {
+        let offset = offset - u64::from(self.cap.pba_offset());
+        let mut bits: u64 = 0;
+
+        if self.bar.enabled() {
+            let first = (offset * 8) as usize;
+            for bit in 0..(data.len() * 8) {
+                let index = first + bit;
+                let masked = match self.bar.table_entries.get(index) {
+                    Some(entry) => self.bar.masked() || entry.masked(),
+                    None => break,
+                };
+                if masked && self.irq_fds.get(index).map_or(false, eventfd_signaled) {
+                    bits |= 1 << bit;
+                }
+            }
+        }
+
+        match data.len() {
+            4 => LittleEndian::write_u32(data, bits as u32),
+            8 => LittleEndian::write_u64(data, bits),
+            _ => error!("invalid data length {} for MSI-X PBA read", data.len()),
+        }
+    }
 }
 
 struct Interrupt {
@@ -225,6 +381,20 @@ impl Interrupt {
         }
     }
 
+    fn msix_pba_accessed(&self, bar_index: u32, offset: u64) -> bool {
+        if let Some(msix) = &self.msix {
+            return msix.pba_accessed(bar_index, offset);
+        }
+
+        false
+    }
+
+    fn msix_read_pba(&self, offset: u64, data: &mut [u8]) {
+        if let Some(msix) = &self.msix {
+            msix.read_pba(offset, data)
+        }
+    }
+
     fn intx_in_use(&self) -> bool {
         if let Some(intx) = &self.intx {
             return intx.enabled;
@@ -300,6 +470,7 @@ pub struct VfioPciDevice {
     mmio_regions: Vec<MmioRegion>,
     interrupt: Interrupt,
     iommu_attached: bool,
+    multifunction: bool,
 }
 
 impl VfioPciDevice {
@@ -343,6 +514,7 @@ impl VfioPciDevice {
                 msix: None,
             },
             iommu_attached,
+            multifunction: false,
         };
 
         vfio_pci_device.parse_capabilities(msi_interrupt_manager);
@@ -356,6 +528,18 @@ impl VfioPciDevice {
         self.iommu_attached
     }
 
+    /// Returns the VFIO device, which can't be upgraded anymore once the
+    /// device is closed.
+    pub fn vfio_device(&self) -> Weak<VfioDevice> {
+        Arc::downgrade(&self.device)
+    }
+
+    /// Exposes the device as multifunction to the guest, for its other
+    /// functions to be discovered, when they are assigned along with it.
+    pub fn set_multifunction(&mut self, multifunction: bool) {
+        self.multifunction = multifunction;
+    }
+
     fn enable_intx(&mut self) -> Result<()> {
         if let Some(intx) = &mut self.interrupt.intx {
             if !intx.enabled {
@@ -413,17 +597,12 @@ impl VfioPciDevice {
 
     fn enable_msix(&self) -> Result<()> {
         if let Some(msix) = &self.interrupt.msix {
-            let mut irq_fds: Vec<EventFd> = Vec::new();
-            for i in 0..msix.bar.table_entries.len() {
-                if let Some(eventfd) = msix.interrupt_source_group.notifier(i as InterruptIndex) {
-                    irq_fds.push(eventfd);
-                } else {
-                    return Err(VfioPciError::MissingNotifier);
-                }
+            if msix.irq_fds.len() != msix.bar.table_entries.len() {
+                return Err(VfioPciError::MissingNotifier);
             }
 
             self.device
-                .enable_msix(irq_fds.iter().collect())
+                .enable_msix(msix.irq_fds.iter().collect())
                 .map_err(VfioPciError::EnableMsix)?;
         }
 
@@ -492,11 +671,21 @@ impl VfioPciDevice {
 
         let msix_config = MsixConfig::new(msix_cap.table_size(), interrupt_source_group.clone(), 0);
 
+        // Stop at the first missing notifier, enable_msix() reports it.
+        let mut irq_fds: Vec<EventFd> = Vec::new();
+        for i in 0..msix_cap.table_size() {
+            match interrupt_source_group.notifier(i as InterruptIndex) {
+                Some(eventfd) => irq_fds.push(eventfd),
+                None => break,
+            }
+        }
+
         self.interrupt.msix = Some(VfioMsix {
             bar: msix_config,
             cap: msix_cap,
             cap_offset: cap.into(),
             interrupt_source_group,
+            irq_fds,
         });
     }
 
@@ -1059,10 +1248,10 @@ impl PciDevice for VfioPciDevice {
             return self.configuration.read_reg(reg_idx);
         }
 
-        // Since we don't support passing multi-functions devices, we should
-        // mask the multi-function bit, bit 7 of the Header Type byte on the
-        // register 3.
-        let mask = if reg_idx == PCI_HEADER_TYPE_REG_INDEX {
+        // Unless the other functions of the device are assigned as well, we
+        // should mask the multi-function bit, bit 7 of the Header Type byte
+        // on the register 3.
+        let mask = if reg_idx == PCI_HEADER_TYPE_REG_INDEX && !self.multifunction {
             0xff7f_ffff
         } else {
             0xffff_ffff
@@ -1089,6 +1278,8 @@ impl PciDevice for VfioPciDevice {
 
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.interrupt.msix_read_table(offset, data);
+            } else if self.interrupt.msix_pba_accessed(region.index, offset) {
+                self.interrupt.msix_read_pba(offset, data);
             } else {
                 self.device.region_read(region.index, data, offset);
             }
@@ -1112,6 +1303,8 @@ impl PciDevice for VfioPciDevice {
             // If the MSI-X table is written to, we need to update our cache.
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.interrupt.msix_write_table(offset, data);
+            } else if self.interrupt.msix_pba_accessed(region.index, offset) {
+                // The PBA is read-only.
             } else {
                 self.device.region_write(region.index, data, offset);
             }
@@ -1177,3 +1370,33 @@ impl PciDevice for VfioPciDevice {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_iova_ranges() {
+        let mut info = vec![0u8; 24];
+        // No capability.
+        assert!(parse_iova_ranges(&info).is_empty());
+
+        // A migration capability chained to the IOVA ranges one.
+        LittleEndian::write_u32(&mut info[16..20], 24);
+        info.extend_from_slice(&[2, 0, 1, 0, 40, 0, 0, 0]);
+        info.extend_from_slice(&[0; 8]);
+        info.extend_from_slice(&[1, 0, 1, 0, 0, 0, 0, 0]);
+        info.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        for value in &[0u64, 0xfedf_ffff, 0xfef0_0000, 0xffff_ffff_ffff] {
+            info.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(
+            parse_iova_ranges(&info),
+            vec![(0, 0xfedf_ffff), (0xfef0_0000, 0xffff_ffff_ffff)]
+        );
+
+        // A truncated capability is ignored.
+        info.truncate(info.len() - 8);
+        assert_eq!(parse_iova_ranges(&info), vec![(0, 0xfedf_ffff)]);
+    }
+}
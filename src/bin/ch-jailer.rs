@@ -0,0 +1,408 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#[macro_use(crate_authors)]
+extern crate clap;
+
+use clap::{App, Arg, ArgMatches};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use vmm::config::{DiskConfig, NetConfig};
+
+const DEFAULT_CHROOT_BASE_DIR: &str = "/srv/jailer";
+const MAX_ID_LEN: usize = 64;
+
+// The device nodes the VMM needs, with their major and minor numbers. The
+// TAP interfaces and the disks are opened before entering the jail.
+const DEVICE_NODES: [(&str, u64, u64); 2] = [("dev/kvm", 10, 232), ("dev/urandom", 1, 9)];
+
+#[derive(Debug)]
+enum Error {
+    InvalidId(String),
+    InvalidUser,
+    InvalidExecFile(String),
+    ParseDisk(vmm::config::Error),
+    DiskWithoutPath(String),
+    OpenDisk(PathBuf, io::Error),
+    ParseNet(vmm::config::Error),
+    NetWithoutTap(String),
+    OpenTap(String, net_util::OpenTapError),
+    InheritFd(io::Error),
+    CreateJail(PathBuf, io::Error),
+    CopyExecFile(io::Error),
+    CreateDeviceNode(&'static str, io::Error),
+    Chroot(io::Error),
+    DropCapabilities(io::Error),
+    DropPrivileges(io::Error),
+    Exec(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            InvalidId(id) => write!(
+                f,
+                "Invalid id {}: up to {} alphanumeric characters or dashes",
+                id, MAX_ID_LEN
+            ),
+            InvalidUser => write!(f, "Invalid uid or gid, the VMM can't run as root"),
+            InvalidExecFile(path) => write!(f, "Invalid VMM executable: {}", path),
+            ParseDisk(e) => write!(f, "Error parsing disk syntax: {}", e),
+            DiskWithoutPath(disk) => write!(f, "Disk without a path: {}", disk),
+            OpenDisk(path, e) => write!(f, "Error opening disk {:?}: {}", path, e),
+            ParseNet(e) => write!(f, "Error parsing network syntax: {}", e),
+            NetWithoutTap(net) => write!(f, "Network interface without a TAP: {}", net),
+            OpenTap(tap, e) => write!(f, "Error opening TAP {}: {:?}", tap, e),
+            InheritFd(e) => write!(f, "Error passing a file descriptor to the VMM: {}", e),
+            CreateJail(path, e) => write!(f, "Error creating the jail {:?}: {}", path, e),
+            CopyExecFile(e) => write!(f, "Error copying the VMM executable: {}", e),
+            CreateDeviceNode(node, e) => write!(f, "Error creating /{}: {}", node, e),
+            Chroot(e) => write!(f, "Error entering the jail: {}", e),
+            DropCapabilities(e) => write!(f, "Error dropping the capabilities: {}", e),
+            DropPrivileges(e) => write!(f, "Error dropping the privileges: {}", e),
+            Exec(e) => write!(f, "Error executing the VMM: {}", e),
+        }
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Lets the VMM inherit the file, which is still closed on drop.
+fn inherit(file: &File) -> Result<(), Error> {
+    // Safe because the fd is valid and the return value is checked.
+    check(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, 0) }).map_err(Error::InheritFd)
+}
+
+fn cstring(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Opens the disk image with the rights the VMM would have asked for, and
+// forwards the disk with the file descriptor it can use in the jail.
+fn open_disk(disk: &str) -> Result<(File, String), Error> {
+    let config = DiskConfig::parse(disk).map_err(Error::ParseDisk)?;
+    let path = config
+        .path
+        .ok_or_else(|| Error::DiskWithoutPath(disk.to_owned()))?;
+
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(!config.readonly && config.overlay.is_none());
+    if config.direct {
+        options.custom_flags(libc::O_DIRECT);
+    }
+    let file = options
+        .open(&path)
+        .map_err(|e| Error::OpenDisk(path.clone(), e))?;
+    inherit(&file)?;
+
+    let disk = format!("{},fd={}", disk, file.as_raw_fd());
+    Ok((file, disk))
+}
+
+// Creates the TAP interface and configures it as the VMM would have, the
+// VMM only getting the file descriptors of its queues.
+fn open_tap(net: &str) -> Result<(Vec<net_util::Tap>, String), Error> {
+    let config = NetConfig::parse(net).map_err(Error::ParseNet)?;
+    let tap = config
+        .tap
+        .ok_or_else(|| Error::NetWithoutTap(net.to_owned()))?;
+
+    let mut host_mac = config.host_mac;
+    let taps = net_util::open_tap(
+        Some(&tap),
+        Some(config.ip),
+        Some(config.mask),
+        &mut host_mac,
+        config.mtu,
+        config.num_queues / 2,
+        Some(libc::O_RDWR | libc::O_NONBLOCK),
+    )
+    .map_err(|e| Error::OpenTap(tap.clone(), e))?;
+
+    // The parameters the TAP interface was configured with are left out,
+    // the VMM using the TAP rather than the file descriptors otherwise.
+    let mut params: Vec<String> = net
+        .split(',')
+        .filter(|param| {
+            let key = param.split('=').next().unwrap_or_default();
+            !matches!(key, "tap" | "ip" | "mask" | "host_mac")
+        })
+        .map(str::to_owned)
+        .collect();
+    let fds: Vec<String> = taps.iter().map(|tap| tap.as_raw_fd().to_string()).collect();
+    params.push(format!("fd={}", fds.join(":")));
+
+    Ok((taps, params.join(",")))
+}
+
+fn makedev(major: u64, minor: u64) -> libc::dev_t {
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0xff)
+}
+
+// Creates <chroot-base-dir>/<exec-file-name>/<id>/root, holding a copy of
+// the VMM executable and the device nodes it needs.
+fn create_jail(
+    matches: &ArgMatches,
+    exec_name: &str,
+    uid: u32,
+    gid: u32,
+) -> Result<PathBuf, Error> {
+    let root = Path::new(
+        matches
+            .value_of("chroot-base-dir")
+            .unwrap_or(DEFAULT_CHROOT_BASE_DIR),
+    )
+    .join(exec_name)
+    .join(matches.value_of("id").unwrap())
+    .join("root");
+
+    fs::create_dir_all(root.join("dev")).map_err(|e| Error::CreateJail(root.clone(), e))?;
+    let root_path = cstring(&root);
+    // Safe because the path is a valid C string and the return value is
+    // checked.
+    check(unsafe { libc::chown(root_path.as_ptr(), uid, gid) })
+        .map_err(|e| Error::CreateJail(root.clone(), e))?;
+
+    fs::copy(matches.value_of("exec-file").unwrap(), root.join(exec_name))
+        .map_err(Error::CopyExecFile)?;
+
+    for (node, major, minor) in DEVICE_NODES.iter() {
+        let path = cstring(&root.join(node));
+        fs::remove_file(root.join(node)).unwrap_or_default();
+        // Safe because the path is a valid C string and the return values
+        // are checked.
+        check(unsafe {
+            libc::mknod(
+                path.as_ptr(),
+                libc::S_IFCHR | libc::S_IRUSR | libc::S_IWUSR,
+                makedev(*major, *minor),
+            )
+        })
+        .and_then(|_| check(unsafe { libc::chown(path.as_ptr(), uid, gid) }))
+        .map_err(|e| Error::CreateDeviceNode(*node, e))?;
+    }
+
+    Ok(root)
+}
+
+fn enter_jail(root: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+    let root = cstring(root);
+    // Safe because the paths are valid C strings and the return values are
+    // checked.
+    check(unsafe { libc::chroot(root.as_ptr()) })
+        .and_then(|_| check(unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) }))
+        .map_err(Error::Chroot)?;
+
+    // Empty the bounding set, prctl() failing with EINVAL past the last
+    // capability the kernel knows about.
+    let mut cap: libc::c_ulong = 0;
+    loop {
+        // Safe because the return value is checked.
+        if let Err(e) = check(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap) }) {
+            if e.raw_os_error() == Some(libc::EINVAL) && cap > 0 {
+                break;
+            }
+            return Err(Error::DropCapabilities(e));
+        }
+        cap += 1;
+    }
+
+    // Switching to a user other than root clears the permitted and the
+    // effective capabilities.
+    // Safe because the arguments are valid and the return values are
+    // checked.
+    check(unsafe { libc::setgroups(0, std::ptr::null()) })
+        .and_then(|_| check(unsafe { libc::setgid(gid) }))
+        .and_then(|_| check(unsafe { libc::setuid(uid) }))
+        .and_then(|_| {
+            let (set, unused): (libc::c_ulong, libc::c_ulong) = (1, 0);
+            check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, set, unused, unused, unused) })
+        })
+        .map_err(Error::DropPrivileges)
+}
+
+fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let id = matches.value_of("id").unwrap();
+    if !valid_id(id) {
+        return Err(Error::InvalidId(id.to_owned()));
+    }
+
+    let uid = matches
+        .value_of("uid")
+        .unwrap()
+        .parse::<u32>()
+        .map_err(|_| Error::InvalidUser)?;
+    let gid = matches
+        .value_of("gid")
+        .unwrap()
+        .parse::<u32>()
+        .map_err(|_| Error::InvalidUser)?;
+    if uid == 0 || gid == 0 {
+        return Err(Error::InvalidUser);
+    }
+
+    let exec_file = matches.value_of("exec-file").unwrap();
+    let exec_name = Path::new(exec_file)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::InvalidExecFile(exec_file.to_owned()))?
+        .to_owned();
+
+    // The resources are opened with the privileges of the jailer, and are
+    // kept open until the VMM is executed.
+    let mut args = Vec::new();
+    let mut disk_files = Vec::new();
+    if let Some(disks) = matches.values_of("disk") {
+        args.push("--disk".to_owned());
+        for disk in disks {
+            let (file, disk) = open_disk(disk)?;
+            disk_files.push(file);
+            args.push(disk);
+        }
+    }
+    let mut taps = Vec::new();
+    if let Some(nets) = matches.values_of("net") {
+        args.push("--net".to_owned());
+        for net in nets {
+            let (net_taps, net) = open_tap(net)?;
+            taps.extend(net_taps);
+            args.push(net);
+        }
+    }
+    if let Some(vmm_args) = matches.values_of("vmm-args") {
+        args.extend(vmm_args.map(str::to_owned));
+    }
+
+    let root = create_jail(matches, &exec_name, uid, gid)?;
+    enter_jail(&root, uid, gid)?;
+
+    Err(Error::Exec(
+        Command::new(Path::new("/").join(&exec_name))
+            .args(&args)
+            .exec(),
+    ))
+}
+
+fn main() {
+    let app = App::new("ch-jailer")
+        .author(crate_authors!())
+        .about(
+            "Run cloud-hypervisor in a chroot, without capabilities, \
+             giving it the disks and the TAP interfaces opened beforehand.",
+        )
+        .arg(
+            Arg::with_name("id")
+                .long("id")
+                .help("VM identifier, naming the jail")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("exec-file")
+                .long("exec-file")
+                .help("Path to the cloud-hypervisor executable")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("uid")
+                .long("uid")
+                .help("User the VMM runs as")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("gid")
+                .long("gid")
+                .help("Group the VMM runs as")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("chroot-base-dir")
+                .long("chroot-base-dir")
+                .help("Directory the jails are created in, defaults to /srv/jailer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("disk")
+                .long("disk")
+                .help(
+                    "Disks opened before entering the jail, forwarded to the VMM: \
+                     path=<disk_image_path>,... as accepted by cloud-hypervisor",
+                )
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("net")
+                .long("net")
+                .help(
+                    "TAP interfaces opened before entering the jail, forwarded to the VMM: \
+                     tap=<if_name>,... as accepted by cloud-hypervisor",
+                )
+                .takes_value(true)
+                .min_values(1),
+        )
+        .arg(
+            Arg::with_name("vmm-args")
+                .help("Arguments passed to the VMM, after --")
+                .multiple(true)
+                .last(true),
+        );
+
+    let matches = app.get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("Error running the jailer: {}", e);
+        process::exit(1)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_id() {
+        assert!(valid_id("vm-0"));
+        assert!(!valid_id(""));
+        assert!(!valid_id("../vm0"));
+        assert!(!valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+
+    #[test]
+    fn test_makedev() {
+        assert_eq!(makedev(10, 232), 0xae8);
+        assert_eq!(makedev(1, 9), 0x109);
+        assert_eq!(makedev(259, 0x1234), 0x0001_2103_34);
+    }
+}
@@ -7,12 +7,14 @@
 extern crate clap;
 
 use api_client::simple_api_command;
+use api_client::simple_api_full_command;
 use api_client::Error as ApiClientError;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use option_parser::{ByteSized, ByteSizedParseError};
+use option_parser::{ByteSized, ByteSizedParseError, OptionParserError};
 use std::fmt;
 use std::os::unix::net::UnixStream;
 use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
 enum Error {
@@ -21,13 +23,19 @@ enum Error {
     InvalidCpuCount(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
     InvalidBalloonSize(ByteSizedParseError),
+    InvalidResumeDelay(std::num::ParseIntError),
+    InvalidBatteryLevel(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
     AddPmemConfig(vmm::config::Error),
     AddNetConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    AddBalloonConfig(vmm::config::Error),
     Restore(vmm::config::Error),
+    DiskSnapshot(vmm::config::Error),
+    Checkpoint(vmm::config::Error),
+    InvalidTrace(OptionParserError),
 }
 
 impl fmt::Display for Error {
@@ -39,13 +47,19 @@ impl fmt::Display for Error {
             InvalidCpuCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {:?}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {:?}", e),
+            InvalidResumeDelay(e) => write!(f, "Error parsing resume delay: {}", e),
+            InvalidBatteryLevel(e) => write!(f, "Error parsing battery level: {}", e),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
             AddPmemConfig(e) => write!(f, "Error parsing persistent memory syntax: {}", e),
             AddNetConfig(e) => write!(f, "Error parsing network syntax: {}", e),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {}", e),
+            AddBalloonConfig(e) => write!(f, "Error parsing balloon syntax: {}", e),
             Restore(e) => write!(f, "Error parsing restore syntax: {}", e),
+            DiskSnapshot(e) => write!(f, "Error parsing disk snapshot syntax: {}", e),
+            Checkpoint(e) => write!(f, "Error parsing checkpoint syntax: {}", e),
+            InvalidTrace(e) => write!(f, "Error parsing trace syntax: {}", e),
         }
     }
 }
@@ -117,6 +131,59 @@ fn resize_zone_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Res
     .map_err(Error::ApiClient)
 }
 
+fn mirror_disk_api_command(socket: &mut UnixStream, id: &str, path: &str) -> Result<(), Error> {
+    let mirror_disk_data = vmm::api::VmMirrorDiskData {
+        id: id.to_owned(),
+        path: path.into(),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "mirror-disk",
+        Some(&serde_json::to_string(&mirror_disk_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn pivot_disk_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let pivot_disk_data = vmm::api::VmPivotDiskData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "pivot-disk",
+        Some(&serde_json::to_string(&pivot_disk_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn disk_snapshot_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let disk_snapshot_config =
+        vmm::config::DiskSnapshotConfig::parse(config).map_err(Error::DiskSnapshot)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "disk-snapshot",
+        Some(&serde_json::to_string(&disk_snapshot_config).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn checkpoint_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let checkpoint_config =
+        vmm::config::CheckpointConfig::parse(config).map_err(Error::Checkpoint)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "checkpoint",
+        Some(&serde_json::to_string(&checkpoint_config).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
 
@@ -201,9 +268,51 @@ fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Er
     .map_err(Error::ApiClient)
 }
 
-fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+fn add_balloon_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let balloon_config =
+        vmm::config::BalloonConfig::parse(config).map_err(Error::AddBalloonConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-balloon",
+        Some(&serde_json::to_string(&balloon_config).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn schedule_resume_api_command(socket: &mut UnixStream, delay: Option<&str>) -> Result<(), Error> {
+    // The delay is turned into an absolute time, so that it doesn't depend
+    // on when the request is handled.
+    let time = if let Some(delay) = delay {
+        let delay: u64 = delay.parse().map_err(Error::InvalidResumeDelay)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Some(now + delay)
+    } else {
+        None
+    };
+    let schedule_resume_data = vmm::api::VmScheduleResumeData { time };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "schedule-resume",
+        Some(&serde_json::to_string(&schedule_resume_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn snapshot_api_command(
+    socket: &mut UnixStream,
+    url: &str,
+    parent_url: Option<&str>,
+) -> Result<(), Error> {
     let snapshot_config = vmm::api::VmSnapshotConfig {
         destination_url: String::from(url),
+        parent_url: parent_url.map(String::from),
     };
 
     simple_api_command(
@@ -253,6 +362,69 @@ fn send_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(),
     .map_err(Error::ApiClient)
 }
 
+fn set_log_level_api_command(
+    socket: &mut UnixStream,
+    level: Option<&str>,
+    traces: Option<clap::Values>,
+) -> Result<(), Error> {
+    let set_log_level_data = vmm::api::VmmSetLogLevelData {
+        level: level.map(|level| level.to_owned()),
+        traces: traces
+            .into_iter()
+            .flatten()
+            .map(vmm::logger::TraceConfig::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::InvalidTrace)?,
+    };
+
+    simple_api_full_command(
+        socket,
+        "PUT",
+        "vmm.set-log-level",
+        Some(&serde_json::to_string(&set_log_level_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn power_supply_api_command(
+    socket: &mut UnixStream,
+    ac_online: Option<&str>,
+    battery_level: Option<&str>,
+) -> Result<(), Error> {
+    let battery_level: Option<u8> = if let Some(battery_level) = battery_level {
+        Some(battery_level.parse().map_err(Error::InvalidBatteryLevel)?)
+    } else {
+        None
+    };
+    let power_supply_data = vmm::api::VmPowerSupplyData {
+        ac_online: ac_online.map(|ac_online| ac_online == "on"),
+        battery_level,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "power-supply",
+        Some(&serde_json::to_string(&power_supply_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
+fn set_net_link_api_command(socket: &mut UnixStream, id: &str, link: &str) -> Result<(), Error> {
+    let set_net_link_data = vmm::api::VmSetNetLinkData {
+        id: id.to_owned(),
+        up: link == "up",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-net-link",
+        Some(&serde_json::to_string(&set_net_link_data).unwrap()),
+    )
+    .map_err(Error::ApiClient)
+}
+
 fn do_command(matches: &ArgMatches) -> Result<(), Error> {
     let mut socket =
         UnixStream::connect(matches.value_of("api-socket").unwrap()).map_err(Error::Connect)?;
@@ -264,6 +436,23 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
         Some("counters") => {
             simple_api_command(&mut socket, "GET", "counters", None).map_err(Error::ApiClient)
         }
+        Some("config") => {
+            simple_api_command(&mut socket, "GET", "config", None).map_err(Error::ApiClient)
+        }
+        Some("memory-regions") => {
+            simple_api_command(&mut socket, "GET", "memory-regions", None).map_err(Error::ApiClient)
+        }
+        Some("power-supply") => power_supply_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("power-supply")
+                .unwrap()
+                .value_of("ac_online"),
+            matches
+                .subcommand_matches("power-supply")
+                .unwrap()
+                .value_of("battery_level"),
+        ),
         Some("resize") => resize_api_command(
             &mut socket,
             matches
@@ -348,6 +537,21 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("vsock_config")
                 .unwrap(),
         ),
+        Some("add-balloon") => add_balloon_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-balloon")
+                .unwrap()
+                .value_of("balloon_config")
+                .unwrap(),
+        ),
+        Some("schedule-resume") => schedule_resume_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("schedule-resume")
+                .unwrap()
+                .value_of("delay"),
+        ),
         Some("snapshot") => snapshot_api_command(
             &mut socket,
             matches
@@ -355,6 +559,10 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("snapshot_config")
                 .unwrap(),
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("parent"),
         ),
         Some("restore") => restore_api_command(
             &mut socket,
@@ -372,6 +580,70 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("send_migration_config")
                 .unwrap(),
         ),
+        Some("set-log-level") => set_log_level_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-log-level")
+                .unwrap()
+                .value_of("level"),
+            matches
+                .subcommand_matches("set-log-level")
+                .unwrap()
+                .values_of("trace"),
+        ),
+        Some("mirror-disk") => mirror_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("mirror-disk")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("mirror-disk")
+                .unwrap()
+                .value_of("path")
+                .unwrap(),
+        ),
+        Some("pivot-disk") => pivot_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("pivot-disk")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
+        Some("disk-snapshot") => disk_snapshot_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("disk-snapshot")
+                .unwrap()
+                .value_of("disk_snapshot_config")
+                .unwrap(),
+        ),
+        Some("checkpoint") => checkpoint_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("checkpoint")
+                .unwrap()
+                .value_of("checkpoint_config")
+                .unwrap(),
+        ),
+        Some("checkpoint-info") => {
+            simple_api_command(&mut socket, "GET", "checkpoint", None).map_err(Error::ApiClient)
+        }
+        Some("set-net-link") => set_net_link_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-net-link")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-net-link")
+                .unwrap()
+                .value_of("link")
+                .unwrap(),
+        ),
         Some("receive-migration") => receive_migration_api_command(
             &mut socket,
             matches
@@ -452,6 +724,15 @@ fn main() {
                         .help(vmm::config::VsockConfig::SYNTAX),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("add-balloon")
+                .about("Add balloon device")
+                .arg(
+                    Arg::with_name("balloon_config")
+                        .index(1)
+                        .help(vmm::config::BalloonConfig::SYNTAX),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("remove-device")
                 .about("Remove VFIO device")
@@ -459,9 +740,82 @@ fn main() {
         )
         .subcommand(SubCommand::with_name("info").about("Info on the VM"))
         .subcommand(SubCommand::with_name("counters").about("Counters from the VM"))
+        .subcommand(SubCommand::with_name("config").about("Effective configuration of the VM"))
+        .subcommand(SubCommand::with_name("memory-regions").about("Guest RAM regions of the VM"))
         .subcommand(SubCommand::with_name("pause").about("Pause the VM"))
         .subcommand(SubCommand::with_name("reboot").about("Reboot the VM"))
         .subcommand(SubCommand::with_name("power-button").about("Trigger a power button in the VM"))
+        .subcommand(SubCommand::with_name("sleep-button").about("Trigger a sleep button in the VM"))
+        .subcommand(SubCommand::with_name("nmi").about("Inject an NMI into the VM"))
+        .subcommand(
+            SubCommand::with_name("mirror-disk")
+                .about("Start mirroring a disk onto a new backing file")
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<disk_id>"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .index(2)
+                        .required(true)
+                        .help("<target_path>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pivot-disk")
+                .about("Switch a disk to the backing file it is mirrored onto")
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<disk_id>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disk-snapshot")
+                .about("Redirect the writes to a disk onto a new copy-on-write overlay")
+                .arg(
+                    Arg::with_name("disk_snapshot_config")
+                        .index(1)
+                        .required(true)
+                        .help(vmm::config::DiskSnapshotConfig::SYNTAX),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("checkpoint")
+                .about("Back the running VM up, along with its disks")
+                .arg(
+                    Arg::with_name("checkpoint_config")
+                        .index(1)
+                        .required(true)
+                        .help(vmm::config::CheckpointConfig::SYNTAX),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("checkpoint-info")
+                .about("Progress of the checkpoint being taken, or outcome of the last one"),
+        )
+        .subcommand(
+            SubCommand::with_name("power-supply")
+                .about("Update the AC adapter and battery of the VM")
+                .arg(
+                    Arg::with_name("ac_online")
+                        .long("ac-online")
+                        .help("Whether the AC adapter is plugged in")
+                        .possible_values(&["on", "off"])
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("battery_level")
+                        .long("battery-level")
+                        .help("New battery charge level in percent")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("resize")
                 .about("Resize the VM")
@@ -506,6 +860,47 @@ fn main() {
                 ),
         )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
+        .subcommand(
+            SubCommand::with_name("schedule-resume")
+                .about("Boot or resume the VM after a delay, cancel it without any delay")
+                .arg(Arg::with_name("delay").index(1).help("<delay_in_seconds>")),
+        )
+        .subcommand(
+            SubCommand::with_name("set-log-level")
+                .about("Change the log level of the VMM, or the records of some components")
+                .arg(
+                    Arg::with_name("level")
+                        .long("level")
+                        .help("off|error|warn|info|debug|trace")
+                        .takes_value(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .help(vmm::logger::TraceConfig::SYNTAX)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-net-link")
+                .about("Set the link state of a network device")
+                .arg(
+                    Arg::with_name("id")
+                        .index(1)
+                        .required(true)
+                        .help("<net_id>"),
+                )
+                .arg(
+                    Arg::with_name("link")
+                        .index(2)
+                        .required(true)
+                        .possible_values(&["up", "down"])
+                        .help("up|down"),
+                ),
+        )
         .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"))
         .subcommand(
             SubCommand::with_name("snapshot")
@@ -514,6 +909,13 @@ fn main() {
                     Arg::with_name("snapshot_config")
                         .index(1)
                         .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::with_name("parent")
+                        .long("parent")
+                        .help("URL of the snapshot last taken or restored from, for the snapshot to be incremental")
+                        .takes_value(true)
+                        .number_of_values(1),
                 ),
         )
         .subcommand(
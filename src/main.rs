@@ -20,7 +20,8 @@ use signal_hook::{
 use std::env;
 use std::fs::File;
 use std::os::unix::io::{FromRawFd, RawFd};
-use std::sync::mpsc::channel;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use thiserror::Error;
@@ -44,6 +45,8 @@ enum Error {
     StartVmmThread(#[source] vmm::Error),
     #[error("Error parsing config: {0}")]
     ParsingConfig(vmm::config::Error),
+    #[error("Error dumping config: {0}")]
+    DumpConfig(serde_json::Error),
     #[error("Error creating VM: {0:?}")]
     VmCreate(vmm::api::ApiError),
     #[error("Error booting VM: {0:?}")]
@@ -52,18 +55,46 @@ enum Error {
     VmRestore(vmm::api::ApiError),
     #[error("Error parsing restore: {0}")]
     ParsingRestore(vmm::config::Error),
+    #[error("Error parsing hooks: {0}")]
+    ParsingHooks(vmm::config::Error),
+    #[error("Error parsing --on-sigterm: {0}")]
+    ParsingSigterm(vmm::config::Error),
+    #[error("Error parsing --memory-pressure: {0}")]
+    ParsingMemoryPressure(vmm::config::Error),
+    #[error("Error reading the VM state: {0}")]
+    LoadState(std::io::Error),
+    #[error("No VM state to resume from")]
+    MissingState,
+    #[error("Cannot resume the VM: its devices were created from file descriptors")]
+    UnresumableState,
+    #[error("Error pausing VM: {0:?}")]
+    VmPause(vmm::api::ApiError),
+    #[error("Error deleting VM: {0:?}")]
+    VmDelete(vmm::api::ApiError),
     #[error("Failed to join on VMM thread: {0:?}")]
     ThreadJoin(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
     #[error("VMM thread exited with error: {0}")]
     VmmThread(#[source] vmm::Error),
     #[error("Error parsing --api-socket: {0}")]
     ParsingApiSocket(std::num::ParseIntError),
+    #[error("Error parsing --api-vsock: {0}")]
+    ParsingApiVsock(vmm::config::Error),
     #[error("Error parsing --event-monitor: {0}")]
     ParsingEventMonitor(option_parser::OptionParserError),
     #[error("Error parsing --event-monitor: path or fd required")]
     BareEventMonitor,
     #[error("Error doing event monitor I/O: {0}")]
     EventMonitorIo(std::io::Error),
+    #[error("Error parsing --api-audit-log: {0}")]
+    ParsingApiAuditLog(option_parser::OptionParserError),
+    #[error("Error parsing --api-audit-log: path or fd required")]
+    BareApiAuditLog,
+    #[error("Error opening API audit log: {0}")]
+    ApiAuditLogIo(std::io::Error),
+    #[error("Error opening --panic-dump file: {0}")]
+    PanicDumpIo(std::io::Error),
+    #[error("Error installing the panic dump: {0}")]
+    PanicDump(#[source] vmm::panic_dump::Error),
     #[error("Error creating log file: {0}")]
     LogFileCreation(std::io::Error),
     #[error("Error setting up logger: {0}")]
@@ -76,8 +107,8 @@ struct Logger {
 }
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        vmm::logger::enabled(metadata)
     }
 
     fn log(&self, record: &log::Record) {
@@ -148,7 +179,11 @@ fn create_app<'a, 'b>(
                 .help(
                     "boot=<boot_vcpus>,max=<max_vcpus>,\
                     topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
-                    kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>",
+                    kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>,\
+                    quota=<cpu_time_per_period_in_us>,period=<period_in_us>,\
+                    pmu=on|off,sve=on|off,ptrauth=on|off,\
+                    affinity=[<vcpus>@<host_cpus>,...],core_types=[<vcpus>@core|atom,...],\
+                    kvmclock=on|off,ptp_kvm=on|off",
                 )
                 .default_value(default_vcpus)
                 .group("vm-config"),
@@ -162,7 +197,8 @@ fn create_app<'a, 'b>(
                      hugepages=on|off,hugepage_size=<hugepage_size>,\
                      hotplug_method=acpi|virtio-mem,\
                      hotplug_size=<hotpluggable_memory_size>,\
-                     hotplugged_size=<hotplugged_memory_size>\"",
+                     hotplugged_size=<hotplugged_memory_size>,\
+                     cold_page_offload=<idle_seconds>\"",
                 )
                 .default_value(default_memory)
                 .group("vm-config"),
@@ -183,6 +219,25 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help(
+                    "Path to a JSON or TOML file holding the VM configuration, whose settings \
+                are replaced by the ones given on the command line",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("dump-config")
+                .long("dump-config")
+                .help(
+                    "Print the configuration of the VM resolved from the other options, \
+                defaults included, and exit",
+                )
+                .takes_value(false),
+        )
         .arg(
             Arg::with_name("kernel")
                 .long("kernel")
@@ -227,7 +282,7 @@ fn create_app<'a, 'b>(
             Arg::with_name("rng")
                 .long("rng")
                 .help(
-                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off\"",
+                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off|bypass\"",
                 )
                 .default_value(default_rng)
                 .group("vm-config"),
@@ -258,7 +313,7 @@ fn create_app<'a, 'b>(
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .help("Control serial port: off|null|pty|tty|file=/path/to/a/file")
+                .help("Control serial port: off|null|pty|tty|api|file=/path/to/a/file, file=/path/to/a/file can be combined with pty or tty, coalesce=on|off")
                 .default_value("null")
                 .group("vm-config"),
         )
@@ -266,7 +321,7 @@ fn create_app<'a, 'b>(
             Arg::with_name("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file,iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|api|file=/path/to/a/file,iommu=on|off|bypass\", file=/path/to/a/file can be combined with pty or tty",
                 )
                 .default_value("tty")
                 .group("vm-config"),
@@ -295,6 +350,38 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("numa-policy")
+                .long("numa-policy")
+                .help("Host NUMA placement policy of the vCPUs, the I/O threads and the memory zones. \"auto\" binds them to the same host nodes automatically, \"manual\" relies on the host_numa_node and affinity settings: manual|auto")
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("on-worker-failure")
+                .long("on-worker-failure")
+                .help("Action taken when a worker thread of a virtio device fails: ignore|pause|reset")
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("pci-subsystem")
+                .long("pci-subsystem")
+                .help(config::PciSubsystemConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("virtio-features")
+                .long("virtio-features")
+                .help(config::VirtioFeaturesConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("watchdog")
                 .long("watchdog")
@@ -302,6 +389,77 @@ fn create_app<'a, 'b>(
                 .takes_value(false)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("shared-event-loop")
+                .long("shared-event-loop")
+                .help("Run low traffic virtio devices (balloon, rng, watchdog) from a single shared thread")
+                .takes_value(false)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("suspend")
+                .long("suspend")
+                .help(config::SuspendConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("power-supply")
+                .long("power-supply")
+                .help(config::PowerSupplyConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("cgroup")
+                .long("cgroup")
+                .help(config::CgroupConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("platform")
+                .long("platform")
+                .help(config::PlatformConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("input-log")
+                .long("input-log")
+                .help(config::InputLogConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("usb")
+                .long("usb")
+                .help(config::UsbConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("tpm")
+                .long("tpm")
+                .help(config::TpmConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("identity")
+                .long("identity")
+                .help(config::IdentityConfig::SYNTAX)
+                .takes_value(true)
+                .number_of_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -325,6 +483,15 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("api-vsock")
+                .long("api-vsock")
+                .help(config::ApiVsockConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .requires("api-socket")
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("event-monitor")
                 .long("event-monitor")
@@ -333,6 +500,14 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("api-audit-log")
+                .long("api-audit-log")
+                .help("File to record the HTTP API requests on: path=</path/to/a/file> or fd=<fd>")
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("restore")
                 .long("restore")
@@ -341,6 +516,55 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .help("Directory to persist the VM configuration and state to")
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("resume-from-state")
+                .long("resume-from-state")
+                .help("Re-create the VM persisted in the state directory")
+                .takes_value(false)
+                .requires("state-dir")
+                .conflicts_with_all(&["config", "kernel", "restore"])
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("on-sigterm")
+                .long("on-sigterm")
+                .help(config::SigtermConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("memory-pressure")
+                .long("memory-pressure")
+                .help(config::MemoryPressureConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("hook")
+                .long("hook")
+                .help(config::HooksConfig::SYNTAX)
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("panic-dump")
+                .long("panic-dump")
+                .help("File to append an emergency dump of the VMM to, should it panic")
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("seccomp")
                 .long("seccomp")
@@ -375,6 +599,26 @@ fn create_app<'a, 'b>(
     app
 }
 
+fn parse_vm_config(cmd_arguments: &ArgMatches) -> Result<config::VmConfig, Error> {
+    if let Some(path) = cmd_arguments.value_of("config") {
+        config::VmConfig::parse_with_file(Path::new(path), cmd_arguments)
+    } else {
+        config::VmConfig::parse(config::VmParams::from_arg_matches(cmd_arguments))
+    }
+    .map_err(Error::ParsingConfig)
+}
+
+// Prints the configuration the VM would be created with, in the format
+// --config and vm.create take, without starting anything.
+fn dump_config(cmd_arguments: &ArgMatches) -> Result<(), Error> {
+    let vm_config = parse_vm_config(cmd_arguments)?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&vm_config).map_err(Error::DumpConfig)?
+    );
+    Ok(())
+}
+
 fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     let log_level = match cmd_arguments.occurrences_of("v") {
         0 => LevelFilter::Warn,
@@ -395,7 +639,7 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         output: Mutex::new(log_file),
         start: std::time::Instant::now(),
     }))
-    .map(|()| log::set_max_level(log_level))
+    .map(|()| vmm::logger::set_level(log_level))
     .map_err(Error::LoggerSetup)?;
 
     let (api_socket_path, api_socket_fd) =
@@ -421,6 +665,12 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
             (None, None)
         };
 
+    let api_vsock = cmd_arguments
+        .value_of("api-vsock")
+        .map(config::ApiVsockConfig::parse)
+        .transpose()
+        .map_err(Error::ParsingApiVsock)?;
+
     if let Some(monitor_config) = cmd_arguments.value_of("event-monitor") {
         let mut parser = OptionParser::new();
         parser.add("path").add("fd");
@@ -446,6 +696,31 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         event_monitor::set_monitor(file).map_err(Error::EventMonitorIo)?;
     }
 
+    if let Some(audit_log_config) = cmd_arguments.value_of("api-audit-log") {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("fd");
+        parser
+            .parse(audit_log_config)
+            .map_err(Error::ParsingApiAuditLog)?;
+
+        let file = if parser.is_set("fd") {
+            let fd = parser
+                .convert("fd")
+                .map_err(Error::ParsingApiAuditLog)?
+                .unwrap();
+            unsafe { File::from_raw_fd(fd) }
+        } else if parser.is_set("path") {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(parser.get("path").unwrap())
+                .map_err(Error::ApiAuditLogIo)?
+        } else {
+            return Err(Error::BareApiAuditLog);
+        };
+        vmm::api::audit::set_audit_log(file);
+    }
+
     let (api_request_sender, api_request_receiver) = channel();
     let api_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateApiEventFd)?;
 
@@ -464,6 +739,15 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         SeccompAction::Trap
     };
 
+    if let Some(path) = cmd_arguments.value_of("panic-dump") {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(Error::PanicDumpIo)?;
+        vmm::panic_dump::install(file, &seccomp_action).map_err(Error::PanicDump)?;
+    }
+
     // See https://github.com/rust-lang/libc/issues/716 why we can't get the details from siginfo_t
     if seccomp_action == SeccompAction::Trap {
         thread::Builder::new()
@@ -490,24 +774,70 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
 
     event!("vmm", "starting");
 
+    let state_dir = cmd_arguments.value_of("state-dir").map(PathBuf::from);
+    let persisted_vms = if cmd_arguments.is_present("resume-from-state") {
+        // Read before the VMM thread starts overwriting it.
+        let persisted_state_dir = vmm::persistence::StateDir::new(state_dir.clone().unwrap());
+        let persisted_vm = persisted_state_dir.load().map_err(Error::LoadState)?;
+        let persisted_hosted_vms = persisted_state_dir
+            .load_hosted()
+            .map_err(Error::LoadState)?;
+        if persisted_vm.is_none() && persisted_hosted_vms.is_empty() {
+            return Err(Error::MissingState);
+        }
+        if persisted_vm.as_ref().map(|vm| vm.resumable()) == Some(false) {
+            return Err(Error::UnresumableState);
+        }
+        Some((persisted_vm, persisted_hosted_vms))
+    } else {
+        None
+    };
+
+    let hooks = cmd_arguments
+        .value_of("hook")
+        .map(config::HooksConfig::parse)
+        .transpose()
+        .map_err(Error::ParsingHooks)?;
+
+    let on_sigterm = cmd_arguments
+        .value_of("on-sigterm")
+        .map(config::SigtermConfig::parse)
+        .transpose()
+        .map_err(Error::ParsingSigterm)?
+        .unwrap_or_default();
+
+    let memory_pressure = cmd_arguments
+        .value_of("memory-pressure")
+        .map(config::MemoryPressureConfig::parse)
+        .transpose()
+        .map_err(Error::ParsingMemoryPressure)?;
+
     let hypervisor = hypervisor::new().map_err(Error::CreateHypervisor)?;
     let vmm_thread = vmm::start_vmm_thread(
         env!("CARGO_PKG_VERSION").to_string(),
         &api_socket_path,
         api_socket_fd,
+        api_vsock,
         api_evt.try_clone().unwrap(),
         http_sender,
         api_request_receiver,
         &seccomp_action,
         hypervisor,
+        state_dir,
+        hooks,
+        Some(on_sigterm),
+        memory_pressure,
     )
     .map_err(Error::StartVmmThread)?;
 
     // Can't test for "vm-config" group as some have default values. The kernel
-    // is the only required option for booting the VM.
-    if cmd_arguments.is_present("kernel") || cmd_arguments.is_present("tdx") {
-        let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
-        let vm_config = config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?;
+    // is the only required option for booting the VM, unless it comes from
+    // the configuration file.
+    if cmd_arguments.is_present("config")
+        || cmd_arguments.is_present("kernel")
+        || cmd_arguments.is_present("tdx")
+    {
+        let vm_config = parse_vm_config(&cmd_arguments)?;
 
         // Create and boot the VM based off the VM config we just built.
         let sender = api_request_sender.clone();
@@ -525,6 +855,33 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
             Arc::new(config::RestoreConfig::parse(restore_params).map_err(Error::ParsingRestore)?),
         )
         .map_err(Error::VmRestore)?;
+    } else if let Some((persisted_vm, persisted_hosted_vms)) = persisted_vms {
+        if let Some(persisted_vm) = persisted_vm {
+            resume_vm(&api_evt, &api_request_sender, persisted_vm)?;
+        }
+
+        // A hosted VM which can't be resumed doesn't take the others down.
+        for (id, persisted_vm) in persisted_hosted_vms {
+            if !persisted_vm.resumable() {
+                log::error!("Cannot resume VM {}: {}", id, Error::UnresumableState);
+                continue;
+            }
+            let (vm_evt, vm_sender, _) = vmm::api::vmm_add_vm(
+                api_evt.try_clone().unwrap(),
+                api_request_sender.clone(),
+                id.clone(),
+            )
+            .map_err(Error::VmCreate)?;
+            if let Err(e) = resume_vm(&vm_evt, &vm_sender, persisted_vm) {
+                log::error!("Cannot resume VM {}: {}", id, e);
+                vmm::api::vmm_remove_vm(
+                    api_evt.try_clone().unwrap(),
+                    api_request_sender.clone(),
+                    id,
+                )
+                .map_err(Error::VmDelete)?;
+            }
+        }
     }
 
     vmm_thread
@@ -535,13 +892,43 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     Ok(api_socket_path)
 }
 
+// Re-creates the VM as it was persisted, through the API channel of the VMM
+// thread driving it.
+fn resume_vm(
+    api_evt: &EventFd,
+    api_sender: &Sender<vmm::api::ApiRequest>,
+    persisted_vm: vmm::persistence::PersistedVm,
+) -> Result<(), Error> {
+    vmm::api::vm_create(
+        api_evt.try_clone().unwrap(),
+        api_sender.clone(),
+        Arc::new(Mutex::new(persisted_vm.config)),
+    )
+    .map_err(Error::VmCreate)?;
+    if persisted_vm.state != vmm::vm::VmState::Created {
+        vmm::api::vm_boot(api_evt.try_clone().unwrap(), api_sender.clone())
+            .map_err(Error::VmBoot)?;
+    }
+    if persisted_vm.state == vmm::vm::VmState::Paused {
+        vmm::api::vm_pause(api_evt.try_clone().unwrap(), api_sender.clone())
+            .map_err(Error::VmPause)?;
+    }
+
+    Ok(())
+}
+
 fn main() {
     // Ensure all created files (.e.g sockets) are only accessible by this user
     let _ = unsafe { libc::umask(0o077) };
 
     let (default_vcpus, default_memory, default_rng) = prepare_default_values();
     let cmd_arguments = create_app(&default_vcpus, &default_memory, &default_rng).get_matches();
-    let exit_code = match start_vmm(cmd_arguments) {
+    let result = if cmd_arguments.is_present("dump-config") {
+        dump_config(&cmd_arguments).map(|()| None)
+    } else {
+        start_vmm(cmd_arguments)
+    };
+    let exit_code = match result {
         Ok(path) => {
             path.map(|s| std::fs::remove_file(s).ok());
             0
@@ -565,8 +952,8 @@ mod unit_tests {
     use crate::{create_app, prepare_default_values};
     use std::path::PathBuf;
     use vmm::config::{
-        CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, KernelConfig, MemoryConfig,
-        RngConfig, VmConfig, VmParams,
+        CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, IdentityConfig, IommuMode,
+        KernelConfig, MemoryConfig, NumaPolicy, RngConfig, VmConfig, VmParams, WorkerFailurePolicy,
     };
 
     fn get_vm_config_from_vec(args: &[&str]) -> VmConfig {
@@ -617,6 +1004,15 @@ mod unit_tests {
                     topology: None,
                     kvm_hyperv: false,
                     max_phys_bits: None,
+                    quota: None,
+                    period: None,
+                    pmu: false,
+                    sve: false,
+                    ptrauth: false,
+                    affinity: None,
+                    core_types: None,
+                    kvmclock: true,
+                    ptp_kvm: None,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
@@ -628,6 +1024,7 @@ mod unit_tests {
                     hugepages: false,
                     zones: None,
                     hugepage_size: None,
+                    cold_page_offload: None,
                 },
                 kernel: Some(KernelConfig {
                     path: PathBuf::from("/path/to/kernel"),
@@ -640,7 +1037,7 @@ mod unit_tests {
                 net: None,
                 rng: RngConfig {
                     src: PathBuf::from("/dev/urandom"),
-                    iommu: false,
+                    iommu: IommuMode::Off,
                 },
                 balloon: None,
                 fs: None,
@@ -648,12 +1045,16 @@ mod unit_tests {
                 serial: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Null,
-                    iommu: false,
+                    iommu: IommuMode::Off,
+                    log_file: None,
+                    coalesce: false,
                 },
                 console: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Tty,
-                    iommu: false,
+                    iommu: IommuMode::Off,
+                    log_file: None,
+                    coalesce: false,
                 },
                 devices: None,
                 vsock: None,
@@ -661,7 +1062,20 @@ mod unit_tests {
                 #[cfg(target_arch = "x86_64")]
                 sgx_epc: None,
                 numa: None,
+                numa_policy: NumaPolicy::Manual,
+                on_worker_failure: WorkerFailurePolicy::Ignore,
+                pci_subsystems: None,
+                virtio_features: None,
                 watchdog: false,
+                shared_event_loop: false,
+                suspend: None,
+                power_supply: None,
+                cgroup: None,
+                platform: None,
+                input_log: None,
+                usb: None,
+                tpm: None,
+                identity: IdentityConfig::default(),
                 #[cfg(feature = "tdx")]
                 tdx: None,
             };
@@ -17,8 +17,6 @@ extern crate test_infra;
 mod tests {
     use net_util::MacAddr;
     use std::collections::HashMap;
-    use std::env;
-    use std::ffi::OsStr;
     use std::fs;
     use std::io;
     use std::io::BufRead;
@@ -26,7 +24,7 @@ mod tests {
     use std::io::Write;
     use std::os::unix::io::AsRawFd;
     use std::path::PathBuf;
-    use std::process::{Child, Command, Stdio};
+    use std::process::{Command, Stdio};
     use std::string::String;
     use std::sync::mpsc::Receiver;
     use std::sync::{mpsc, Mutex};
@@ -36,20 +34,6 @@ mod tests {
     #[cfg_attr(target_arch = "aarch64", allow(unused_imports))]
     use wait_timeout::ChildExt;
 
-    lazy_static! {
-        static ref NEXT_VM_ID: Mutex<u8> = Mutex::new(1);
-    }
-
-    struct Guest {
-        tmp_dir: TempDir,
-        disk_config: Box<dyn DiskConfig>,
-        fw_path: String,
-        network: GuestNetworkConfig,
-    }
-
-    // Safe to implement as we know we have no interior mutability
-    impl std::panic::RefUnwindSafe for Guest {}
-
     #[cfg(target_arch = "x86_64")]
     const BIONIC_IMAGE_NAME: &str = "bionic-server-cloudimg-amd64.raw";
     #[cfg(target_arch = "x86_64")]
@@ -75,25 +59,13 @@ mod tests {
     #[cfg(target_arch = "x86_64")]
     const OVMF_NAME: &str = "OVMF-4b47d0c6c8.fd";
 
-    const DIRECT_KERNEL_BOOT_CMDLINE: &str =
-        "root=/dev/vda1 console=hvc0 rw systemd.journald.forward_to_console=1";
-
     #[cfg(target_arch = "x86_64")]
     const GREP_SERIAL_IRQ_CMD: &str = "grep -c 'IO-APIC.*ttyS0' /proc/interrupts || true";
     #[cfg(target_arch = "aarch64")]
     const GREP_SERIAL_IRQ_CMD: &str = "grep -c 'GICv3.*uart-pl011' /proc/interrupts || true";
 
-    const PIPE_SIZE: i32 = 32 << 20;
-
     const CONSOLE_TEST_STRING: &str = "Started OpenBSD Secure Shell server";
 
-    fn clh_command(cmd: &str) -> String {
-        env::var("BUILD_TARGET").map_or(
-            format!("target/x86_64-unknown-linux-gnu/release/{}", cmd),
-            |target| format!("target/{}/release/{}", target, cmd),
-        )
-    }
-
     fn prepare_virtiofsd(
         tmp_dir: &TempDir,
         shared_dir: &str,
@@ -204,22 +176,6 @@ mod tests {
         snapshot_dir
     }
 
-    // Creates the path for direct kernel boot and return the path.
-    // For x86_64, this function returns the vmlinux kernel path.
-    // For AArch64, this function returns the PE kernel path.
-    fn direct_kernel_boot_path() -> PathBuf {
-        let mut workload_path = dirs::home_dir().unwrap();
-        workload_path.push("workloads");
-
-        let mut kernel_path = workload_path;
-        #[cfg(target_arch = "x86_64")]
-        kernel_path.push("vmlinux");
-        #[cfg(target_arch = "aarch64")]
-        kernel_path.push("Image");
-
-        kernel_path
-    }
-
     #[cfg(target_arch = "aarch64")]
     fn edk2_path() -> PathBuf {
         let mut workload_path = dirs::home_dir().unwrap();
@@ -344,521 +300,6 @@ mod tests {
         cmd.status().expect("Failed to launch ch-remote").success()
     }
 
-    #[derive(Debug)]
-    enum Error {
-        Parsing(std::num::ParseIntError),
-        SshCommand(SshCommandError),
-        WaitForBoot(WaitForBootError),
-    }
-
-    impl From<SshCommandError> for Error {
-        fn from(e: SshCommandError) -> Self {
-            Self::SshCommand(e)
-        }
-    }
-
-    impl Guest {
-        fn new_from_ip_range(mut disk_config: Box<dyn DiskConfig>, class: &str, id: u8) -> Self {
-            let tmp_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
-
-            let mut workload_path = dirs::home_dir().unwrap();
-            workload_path.push("workloads");
-
-            let mut fw_path = workload_path;
-            #[cfg(target_arch = "aarch64")]
-            fw_path.push("Image");
-            #[cfg(target_arch = "x86_64")]
-            fw_path.push("hypervisor-fw");
-            let fw_path = String::from(fw_path.to_str().unwrap());
-            let network = GuestNetworkConfig {
-                guest_ip: format!("{}.{}.2", class, id),
-                l2_guest_ip1: format!("{}.{}.3", class, id),
-                l2_guest_ip2: format!("{}.{}.4", class, id),
-                l2_guest_ip3: format!("{}.{}.5", class, id),
-                host_ip: format!("{}.{}.1", class, id),
-                guest_mac: format!("12:34:56:78:90:{:02x}", id),
-                l2_guest_mac1: format!("de:ad:be:ef:12:{:02x}", id),
-                l2_guest_mac2: format!("de:ad:be:ef:34:{:02x}", id),
-                l2_guest_mac3: format!("de:ad:be:ef:56:{:02x}", id),
-                tcp_listener_port: DEFAULT_TCP_LISTENER_PORT + id as u16,
-            };
-
-            disk_config.prepare_files(&tmp_dir, &network);
-
-            Guest {
-                tmp_dir,
-                disk_config,
-                fw_path,
-                network,
-            }
-        }
-
-        fn new(disk_config: Box<dyn DiskConfig>) -> Self {
-            let mut guard = NEXT_VM_ID.lock().unwrap();
-            let id = *guard;
-            *guard = id + 1;
-
-            Self::new_from_ip_range(disk_config, "192.168", id)
-        }
-
-        fn default_net_string(&self) -> String {
-            format!(
-                "tap=,mac={},ip={},mask=255.255.255.0",
-                self.network.guest_mac, self.network.host_ip
-            )
-        }
-
-        fn default_net_string_w_iommu(&self) -> String {
-            format!(
-                "tap=,mac={},ip={},mask=255.255.255.0,iommu=on",
-                self.network.guest_mac, self.network.host_ip
-            )
-        }
-
-        fn ssh_command(&self, command: &str) -> Result<String, SshCommandError> {
-            ssh_command_ip(
-                command,
-                &self.network.guest_ip,
-                DEFAULT_SSH_RETRIES,
-                DEFAULT_SSH_TIMEOUT,
-            )
-        }
-
-        fn ssh_command_l1(&self, command: &str) -> Result<String, SshCommandError> {
-            ssh_command_ip(
-                command,
-                &self.network.guest_ip,
-                DEFAULT_SSH_RETRIES,
-                DEFAULT_SSH_TIMEOUT,
-            )
-        }
-
-        fn ssh_command_l2_1(&self, command: &str) -> Result<String, SshCommandError> {
-            ssh_command_ip(
-                command,
-                &self.network.l2_guest_ip1,
-                DEFAULT_SSH_RETRIES,
-                DEFAULT_SSH_TIMEOUT,
-            )
-        }
-
-        fn ssh_command_l2_2(&self, command: &str) -> Result<String, SshCommandError> {
-            ssh_command_ip(
-                command,
-                &self.network.l2_guest_ip2,
-                DEFAULT_SSH_RETRIES,
-                DEFAULT_SSH_TIMEOUT,
-            )
-        }
-
-        fn ssh_command_l2_3(&self, command: &str) -> Result<String, SshCommandError> {
-            ssh_command_ip(
-                command,
-                &self.network.l2_guest_ip3,
-                DEFAULT_SSH_RETRIES,
-                DEFAULT_SSH_TIMEOUT,
-            )
-        }
-
-        fn api_create_body(&self, cpu_count: u8) -> String {
-            #[cfg(target_arch = "x86_64")]
-            format! {"{{\"cpus\":{{\"boot_vcpus\":{},\"max_vcpus\":{}}},\"kernel\":{{\"path\":\"{}\"}},\"cmdline\":{{\"args\": \"\"}},\"net\":[{{\"ip\":\"{}\", \"mask\":\"255.255.255.0\", \"mac\":\"{}\"}}], \"disks\":[{{\"path\":\"{}\"}}, {{\"path\":\"{}\"}}]}}",
-                     cpu_count,
-                     cpu_count,
-                     self.fw_path.as_str(),
-                     self.network.host_ip,
-                     self.network.guest_mac,
-                     self.disk_config.disk(DiskType::OperatingSystem).unwrap().as_str(),
-                     self.disk_config.disk(DiskType::CloudInit).unwrap().as_str(),
-            }
-            #[cfg(target_arch = "aarch64")]
-            format! {"{{\"cpus\":{{\"boot_vcpus\":{},\"max_vcpus\":{}}},\"kernel\":{{\"path\":\"{}\"}},\"cmdline\":{{\"args\": \"{}\"}},\"net\":[{{\"ip\":\"{}\", \"mask\":\"255.255.255.0\", \"mac\":\"{}\"}}], \"disks\":[{{\"path\":\"{}\"}}, {{\"path\":\"{}\"}}]}}",
-                     cpu_count,
-                     cpu_count,
-                     direct_kernel_boot_path().to_str().unwrap(),
-                     DIRECT_KERNEL_BOOT_CMDLINE,
-                     self.network.host_ip,
-                     self.network.guest_mac,
-                     self.disk_config.disk(DiskType::OperatingSystem).unwrap().as_str(),
-                     self.disk_config.disk(DiskType::CloudInit).unwrap().as_str(),
-            }
-        }
-
-        fn get_cpu_count(&self) -> Result<u32, Error> {
-            self.ssh_command("grep -c processor /proc/cpuinfo")?
-                .trim()
-                .parse()
-                .map_err(Error::Parsing)
-        }
-
-        fn get_initial_apicid(&self) -> Result<u32, Error> {
-            self.ssh_command("grep \"initial apicid\" /proc/cpuinfo | grep -o \"[0-9]*\"")?
-                .trim()
-                .parse()
-                .map_err(Error::Parsing)
-        }
-
-        fn get_total_memory(&self) -> Result<u32, Error> {
-            self.ssh_command("grep MemTotal /proc/meminfo | grep -o \"[0-9]*\"")?
-                .trim()
-                .parse()
-                .map_err(Error::Parsing)
-        }
-
-        fn get_total_memory_l2(&self) -> Result<u32, Error> {
-            self.ssh_command_l2_1("grep MemTotal /proc/meminfo | grep -o \"[0-9]*\"")?
-                .trim()
-                .parse()
-                .map_err(Error::Parsing)
-        }
-
-        fn get_numa_node_memory(&self, node_id: usize) -> Result<u32, Error> {
-            self.ssh_command(
-                format!(
-                    "grep MemTotal /sys/devices/system/node/node{}/meminfo \
-                        | cut -d \":\" -f 2 | grep -o \"[0-9]*\"",
-                    node_id
-                )
-                .as_str(),
-            )?
-            .trim()
-            .parse()
-            .map_err(Error::Parsing)
-        }
-
-        fn wait_vm_boot(&self, custom_timeout: Option<i32>) -> Result<(), Error> {
-            self.network
-                .wait_vm_boot(custom_timeout)
-                .map_err(Error::WaitForBoot)
-        }
-
-        fn check_numa_node_cpus(&self, node_id: usize, cpus: Vec<usize>) -> Result<(), Error> {
-            for cpu in cpus.iter() {
-                let cmd = format!(
-                    "[ -d \"/sys/devices/system/node/node{}/cpu{}\" ]",
-                    node_id, cpu
-                );
-                self.ssh_command(cmd.as_str())?;
-            }
-
-            Ok(())
-        }
-
-        fn check_numa_node_distances(
-            &self,
-            node_id: usize,
-            distances: &str,
-        ) -> Result<bool, Error> {
-            let cmd = format!("cat /sys/devices/system/node/node{}/distance", node_id);
-            if self.ssh_command(cmd.as_str())?.trim() == distances {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        }
-
-        fn check_sgx_support(&self) -> Result<(), Error> {
-            self.ssh_command(
-                "cpuid -l 0x7 -s 0 | tr -s [:space:] | grep -q 'SGX: \
-                    Software Guard Extensions supported = true'",
-            )?;
-            self.ssh_command(
-                "cpuid -l 0x7 -s 0 | tr -s [:space:] | grep -q 'SGX_LC: \
-                    SGX launch config supported = true'",
-            )?;
-            self.ssh_command(
-                "cpuid -l 0x12 -s 0 | tr -s [:space:] | grep -q 'SGX1 \
-                    supported = true'",
-            )?;
-
-            Ok(())
-        }
-
-        fn get_entropy(&self) -> Result<u32, Error> {
-            self.ssh_command("cat /proc/sys/kernel/random/entropy_avail")?
-                .trim()
-                .parse()
-                .map_err(Error::Parsing)
-        }
-
-        fn get_pci_bridge_class(&self) -> Result<String, Error> {
-            Ok(self
-                .ssh_command("cat /sys/bus/pci/devices/0000:00:00.0/class")?
-                .trim()
-                .to_string())
-        }
-
-        fn get_pci_device_ids(&self) -> Result<String, Error> {
-            Ok(self
-                .ssh_command("cat /sys/bus/pci/devices/*/device")?
-                .trim()
-                .to_string())
-        }
-
-        fn get_pci_vendor_ids(&self) -> Result<String, Error> {
-            Ok(self
-                .ssh_command("cat /sys/bus/pci/devices/*/vendor")?
-                .trim()
-                .to_string())
-        }
-
-        fn does_device_vendor_pair_match(
-            &self,
-            device_id: &str,
-            vendor_id: &str,
-        ) -> Result<bool, Error> {
-            // We are checking if console device's device id and vendor id pair matches
-            let devices = self.get_pci_device_ids()?;
-            let devices: Vec<&str> = devices.split('\n').collect();
-            let vendors = self.get_pci_vendor_ids()?;
-            let vendors: Vec<&str> = vendors.split('\n').collect();
-
-            for (index, d_id) in devices.iter().enumerate() {
-                if *d_id == device_id {
-                    if let Some(v_id) = vendors.get(index) {
-                        if *v_id == vendor_id {
-                            return Ok(true);
-                        }
-                    }
-                }
-            }
-
-            Ok(false)
-        }
-
-        fn valid_virtio_fs_cache_size(
-            &self,
-            dax: bool,
-            cache_size: Option<u64>,
-        ) -> Result<bool, Error> {
-            // SHM region is called different things depending on kernel
-            let shm_region = self
-                .ssh_command("sudo grep 'virtio[0-9]\\|virtio-pci-shm' /proc/iomem || true")?
-                .trim()
-                .to_string();
-
-            if shm_region.is_empty() {
-                return Ok(!dax);
-            }
-
-            // From this point, the region is not empty, hence it is an error
-            // if DAX is off.
-            if !dax {
-                return Ok(false);
-            }
-
-            let cache = if let Some(cache) = cache_size {
-                cache
-            } else {
-                // 8Gib by default
-                0x0002_0000_0000
-            };
-
-            let args: Vec<&str> = shm_region.split(':').collect();
-            if args.is_empty() {
-                return Ok(false);
-            }
-
-            let args: Vec<&str> = args[0].trim().split('-').collect();
-            if args.len() != 2 {
-                return Ok(false);
-            }
-
-            let start_addr = u64::from_str_radix(args[0], 16).map_err(Error::Parsing)?;
-            let end_addr = u64::from_str_radix(args[1], 16).map_err(Error::Parsing)?;
-
-            Ok(cache == (end_addr - start_addr + 1))
-        }
-
-        fn check_vsock(&self, socket: &str) {
-            // Listen from guest on vsock CID=3 PORT=16
-            // SOCKET-LISTEN:<domain>:<protocol>:<local-address>
-            let guest_ip = self.network.guest_ip.clone();
-            let listen_socat = thread::spawn(move || {
-                ssh_command_ip("sudo socat - SOCKET-LISTEN:40:0:x00x00x10x00x00x00x03x00x00x00x00x00x00x00 > vsock_log", &guest_ip, DEFAULT_SSH_RETRIES, DEFAULT_SSH_TIMEOUT).unwrap();
-            });
-
-            // Make sure socat is listening, which might take a few second on slow systems
-            thread::sleep(std::time::Duration::new(10, 0));
-
-            // Write something to vsock from the host
-            assert!(exec_host_command_status(&format!(
-                "echo -e \"CONNECT 16\\nHelloWorld!\" | socat - UNIX-CONNECT:{}",
-                socket
-            ))
-            .success());
-
-            // Wait for the thread to terminate.
-            listen_socat.join().unwrap();
-
-            assert_eq!(
-                self.ssh_command("cat vsock_log").unwrap().trim(),
-                "HelloWorld!"
-            );
-        }
-
-        fn check_nvidia_gpu(&self) {
-            // Run CUDA sample to validate it can find the device
-            let device_query_result = self
-                .ssh_command(
-                    "sudo /root/NVIDIA_CUDA-11.3_Samples/bin/x86_64/linux/release/deviceQuery",
-                )
-                .unwrap();
-            assert!(device_query_result.contains("Detected 1 CUDA Capable device"));
-            assert!(device_query_result.contains("Device 0: \"NVIDIA Tesla T4\""));
-            assert!(device_query_result.contains("Result = PASS"));
-
-            // Run NVIDIA DCGM Diagnostics to validate the device is functional
-            self.ssh_command("sudo nv-hostengine").unwrap();
-
-            assert!(self
-                .ssh_command("sudo dcgmi discovery -l")
-                .unwrap()
-                .contains("Name: NVIDIA Tesla T4"));
-            assert_eq!(
-                self.ssh_command("sudo dcgmi diag -r 'diagnostic' | grep Pass | wc -l")
-                    .unwrap()
-                    .trim(),
-                "10"
-            );
-        }
-
-        fn reboot_linux(&self, current_reboot_count: u32, custom_timeout: Option<i32>) {
-            let list_boots_cmd = "sudo journalctl --list-boots | wc -l";
-            let boot_count = self
-                .ssh_command(list_boots_cmd)
-                .unwrap()
-                .trim()
-                .parse::<u32>()
-                .unwrap_or_default();
-
-            assert_eq!(boot_count, current_reboot_count + 1);
-            self.ssh_command("sudo reboot").unwrap();
-
-            self.wait_vm_boot(custom_timeout).unwrap();
-            let boot_count = self
-                .ssh_command(list_boots_cmd)
-                .unwrap()
-                .trim()
-                .parse::<u32>()
-                .unwrap_or_default();
-            assert_eq!(boot_count, current_reboot_count + 2);
-        }
-
-        fn enable_memory_hotplug(&self) {
-            self.ssh_command(
-                "echo online | sudo tee /sys/devices/system/memory/auto_online_blocks",
-            )
-            .unwrap();
-        }
-    }
-
-    struct GuestCommand<'a> {
-        command: Command,
-        guest: &'a Guest,
-        capture_output: bool,
-    }
-
-    impl<'a> GuestCommand<'a> {
-        fn new(guest: &'a Guest) -> Self {
-            Self::new_with_binary_name(guest, "cloud-hypervisor")
-        }
-
-        fn new_with_binary_name(guest: &'a Guest, binary_name: &str) -> Self {
-            Self {
-                command: Command::new(clh_command(binary_name)),
-                guest,
-                capture_output: false,
-            }
-        }
-
-        fn capture_output(&mut self) -> &mut Self {
-            self.capture_output = true;
-            self
-        }
-
-        fn spawn(&mut self) -> io::Result<Child> {
-            println!(
-                "\n\n==== Start cloud-hypervisor command-line ====\n\n\
-                 {:?}\n\
-                 \n==== End cloud-hypervisor command-line ====\n\n",
-                self.command
-            );
-
-            if self.capture_output {
-                let child = self
-                    .command
-                    .arg("-v")
-                    .stderr(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .unwrap();
-
-                let fd = child.stdout.as_ref().unwrap().as_raw_fd();
-                let pipesize = unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, PIPE_SIZE) };
-                let fd = child.stderr.as_ref().unwrap().as_raw_fd();
-                let pipesize1 = unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, PIPE_SIZE) };
-
-                if pipesize >= PIPE_SIZE && pipesize1 >= PIPE_SIZE {
-                    Ok(child)
-                } else {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "resizing pipe w/ 'fnctl' failed!",
-                    ))
-                }
-            } else {
-                self.command.arg("-v").spawn()
-            }
-        }
-
-        fn args<I, S>(&mut self, args: I) -> &mut Self
-        where
-            I: IntoIterator<Item = S>,
-            S: AsRef<OsStr>,
-        {
-            self.command.args(args);
-            self
-        }
-
-        fn default_disks(&mut self) -> &mut Self {
-            if self.guest.disk_config.disk(DiskType::CloudInit).is_some() {
-                self.args(&[
-                    "--disk",
-                    format!(
-                        "path={}",
-                        self.guest
-                            .disk_config
-                            .disk(DiskType::OperatingSystem)
-                            .unwrap()
-                    )
-                    .as_str(),
-                    format!(
-                        "path={}",
-                        self.guest.disk_config.disk(DiskType::CloudInit).unwrap()
-                    )
-                    .as_str(),
-                ])
-            } else {
-                self.args(&[
-                    "--disk",
-                    format!(
-                        "path={}",
-                        self.guest
-                            .disk_config
-                            .disk(DiskType::OperatingSystem)
-                            .unwrap()
-                    )
-                    .as_str(),
-                ])
-            }
-        }
-
-        fn default_net(&mut self) -> &mut Self {
-            self.args(&["--net", self.guest.default_net_string().as_str()])
-        }
-    }
-
     fn test_cpu_topology(threads_per_core: u8, cores_per_package: u8, packages: u8) {
         let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
         let guest = Guest::new(Box::new(focal));
@@ -2228,9 +1669,8 @@ mod tests {
             handle_child_output(r, &output);
         }
 
-        #[test]
         #[cfg(feature = "acpi")]
-        fn test_guest_numa_nodes() {
+        fn _test_guest_numa_nodes(numa: &[&str]) {
             let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
             let guest = Guest::new(Box::new(focal));
             let api_socket = temp_api_path(&guest.tmp_dir);
@@ -2249,12 +1689,8 @@ mod tests {
                     "id=mem1,size=2G,hotplug_size=3G",
                     "id=mem2,size=3G,hotplug_size=3G",
                 ])
-                .args(&[
-                    "--numa",
-                    "guest_numa_id=0,cpus=0-2:9,distances=1@15:2@20,memory_zones=mem0",
-                    "guest_numa_id=1,cpus=3-4:6-8,distances=0@20:2@25,memory_zones=mem1",
-                    "guest_numa_id=2,cpus=5:10-11,distances=0@25:1@30,memory_zones=mem2",
-                ])
+                .args(&["--numa"])
+                .args(numa)
                 .args(&["--kernel", kernel_path.to_str().unwrap()])
                 .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
                 .args(&["--api-socket", &api_socket])
@@ -2316,6 +1752,26 @@ mod tests {
             handle_child_output(r, &output);
         }
 
+        #[test]
+        #[cfg(feature = "acpi")]
+        fn test_guest_numa_nodes() {
+            _test_guest_numa_nodes(&[
+                "guest_numa_id=0,cpus=0-2:9,distances=1@15:2@20,memory_zones=mem0",
+                "guest_numa_id=1,cpus=3-4:6-8,distances=0@20:2@25,memory_zones=mem1",
+                "guest_numa_id=2,cpus=5:10-11,distances=0@25:1@30,memory_zones=mem2",
+            ])
+        }
+
+        #[test]
+        #[cfg(feature = "acpi")]
+        fn test_guest_numa_nodes_bracketed_lists() {
+            _test_guest_numa_nodes(&[
+                "guest_numa_id=0,cpus=[0-2,9],distances=[1@15,2@20],memory_zones=mem0",
+                "guest_numa_id=1,cpus=[3-4,6-8],distances=[0@20,2@25],memory_zones=mem1",
+                "guest_numa_id=2,cpus=[5,10-11],distances=[0@25,1@30],memory_zones=mem2",
+            ])
+        }
+
         #[test]
         fn test_pci_msi() {
             let focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
@@ -5032,6 +4488,7 @@ mod tests {
                 Some(std::net::Ipv4Addr::from_str(&guest.network.host_ip).unwrap()),
                 None,
                 &mut None,
+                None,
                 num_queue_pairs,
                 Some(libc::O_RDWR | libc::O_NONBLOCK),
             )
@@ -14,15 +14,18 @@
 
 use super::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    SharedEpollLoop, VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::GuestMemoryMmap;
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
+use std::fs::File;
 use std::io;
 use std::mem::size_of;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
@@ -32,14 +35,18 @@ use std::thread;
 use vm_memory::GuestMemory;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
-    GuestMemoryError,
+    GuestMemoryError, GuestMemoryRegion,
 };
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
+use vm_virtio::VirtioConfig;
 use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 128;
 const NUM_QUEUES: usize = 2;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// The free page reporting queue comes after the inflate and deflate queues,
+// since neither the statistics nor the free page hinting are supported.
+const REPORTING_QUEUE_INDEX: usize = 2;
 
 // Get resize event.
 const RESIZE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
@@ -47,12 +54,21 @@ const RESIZE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const INFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New descriptors are pending on the virtio queue.
 const DEFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// New descriptors are pending on the virtio queue.
+const REPORTING_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
 // Size of a PFN in the balloon interface.
 const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
 
 // Deflate balloon on OOM
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+// Guest is poisoning its free pages
+const VIRTIO_BALLOON_F_PAGE_POISON: u64 = 4;
+// Guest is reporting its free pages
+const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
+
+// Filesystem type of hugetlbfs, from include/uapi/linux/magic.h
+const HUGETLBFS_MAGIC: i64 = 0x9584_58f6;
 
 #[derive(Debug)]
 pub enum Error {
@@ -66,6 +82,8 @@ pub enum Error {
     FallocateFail(std::io::Error),
     // Madvise fail.
     MadviseFail(std::io::Error),
+    // Fstatfs fail.
+    FstatfsFail(std::io::Error),
     // Failed to EventFd write.
     EventFdWriteFail(std::io::Error),
     // Failed to EventFd try_clone.
@@ -88,10 +106,17 @@ struct VirtioBalloonConfig {
     num_pages: u32,
     // Number of pages we've actually got in balloon.
     actual: u32,
+    // Free page hinting command ID, unused.
+    free_page_hint_cmd_id: u32,
+    // Value the guest fills its free pages with.
+    poison_val: u32,
 }
 
+// The "actual" and "poison_val" fields are the only mutable fields
 const CONFIG_ACTUAL_OFFSET: u64 = 4;
 const CONFIG_ACTUAL_SIZE: usize = 4;
+const CONFIG_POISON_VAL_OFFSET: u64 = 12;
+const CONFIG_POISON_VAL_SIZE: usize = 4;
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBalloonConfig {}
@@ -147,13 +172,14 @@ impl VirtioBalloonResize {
 }
 
 struct BalloonEpollHandler {
-    config: Arc<Mutex<VirtioBalloonConfig>>,
+    config: Arc<Mutex<VirtioConfig<VirtioBalloonConfig>>>,
     resize_receiver: VirtioBalloonResizeReceiver,
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     inflate_queue_evt: EventFd,
     deflate_queue_evt: EventFd,
+    reporting_queue_evt: Option<EventFd>,
     kill_evt: EventFd,
     pause_evt: EventFd,
 }
@@ -180,7 +206,7 @@ impl BalloonEpollHandler {
         let mut used_desc_heads = [0; QUEUE_SIZE as usize];
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in self.queues[queue_index].iter(&mem) {
+        for avail_desc in self.queues[queue_index].iter(mem.deref()) {
             used_desc_heads[used_count] = avail_desc.index;
             used_count += 1;
 
@@ -204,43 +230,26 @@ impl BalloonEpollHandler {
 
                 let gpa = (pfn as u64) << VIRTIO_BALLOON_PFN_SHIFT;
                 if let Ok(hva) = mem.get_host_address(GuestAddress(gpa)) {
-                    let advice = match ev_type {
-                        INFLATE_QUEUE_EVENT => {
-                            let region =
-                                mem.find_region(GuestAddress(gpa))
-                                    .ok_or(Error::GuestMemory(
-                                        GuestMemoryError::InvalidGuestAddress(GuestAddress(gpa)),
-                                    ))?;
-                            if let Some(f_off) = region.file_offset() {
-                                let offset = hva as usize - region.as_ptr() as usize;
-                                let res = unsafe {
-                                    libc::fallocate64(
-                                        f_off.file().as_raw_fd(),
-                                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
-                                        (offset as u64 + f_off.start()) as libc::off64_t,
-                                        (1 << VIRTIO_BALLOON_PFN_SHIFT) as libc::off64_t,
-                                    )
-                                };
-
-                                if res != 0 {
-                                    return Err(Error::FallocateFail(io::Error::last_os_error()));
-                                }
+                    match ev_type {
+                        INFLATE_QUEUE_EVENT => Self::release_memory_range(
+                            &mem,
+                            GuestAddress(gpa),
+                            1 << VIRTIO_BALLOON_PFN_SHIFT,
+                        )?,
+                        DEFLATE_QUEUE_EVENT => {
+                            // Need unsafe to do syscall madvise
+                            let res = unsafe {
+                                libc::madvise(
+                                    hva as *mut libc::c_void,
+                                    (1 << VIRTIO_BALLOON_PFN_SHIFT) as libc::size_t,
+                                    libc::MADV_WILLNEED,
+                                )
+                            };
+                            if res != 0 {
+                                return Err(Error::MadviseFail(io::Error::last_os_error()));
                             }
-                            libc::MADV_DONTNEED
                         }
-                        DEFLATE_QUEUE_EVENT => libc::MADV_WILLNEED,
                         _ => return Err(Error::ProcessQueueWrongEvType(ev_type)),
-                    };
-                    // Need unsafe to do syscall madvise
-                    let res = unsafe {
-                        libc::madvise(
-                            hva as *mut libc::c_void,
-                            (1 << VIRTIO_BALLOON_PFN_SHIFT) as libc::size_t,
-                            advice,
-                        )
-                    };
-                    if res != 0 {
-                        return Err(Error::MadviseFail(io::Error::last_os_error()));
                     }
                 } else {
                     error!("Address 0x{:x} is not available", gpa);
@@ -250,7 +259,7 @@ impl BalloonEpollHandler {
         }
 
         for &desc_index in &used_desc_heads[..used_count] {
-            self.queues[queue_index].add_used(&mem, desc_index, 0);
+            self.queues[queue_index].add_used(mem.deref(), desc_index, 0);
         }
         if used_count > 0 {
             self.signal(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))?;
@@ -259,18 +268,150 @@ impl BalloonEpollHandler {
         Ok(())
     }
 
+    fn process_reporting_queue(&mut self) -> result::Result<(), Error> {
+        // The guest expects its free pages to keep the poison value, which
+        // wouldn't be the case anymore once they are given back to the host.
+        let release = self.config.lock().unwrap().get().poison_val == 0;
+
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in self.queues[REPORTING_QUEUE_INDEX].iter(mem.deref()) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+
+            if !release {
+                continue;
+            }
+
+            // Each descriptor of the chain describes a range of free pages.
+            for desc in avail_desc {
+                Self::release_memory_range(&mem, desc.addr, desc.len as u64)?;
+            }
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            self.queues[REPORTING_QUEUE_INDEX].add_used(mem.deref(), desc_index, 0);
+        }
+        if used_count > 0 {
+            self.signal(
+                &VirtioInterruptType::Queue,
+                Some(&self.queues[REPORTING_QUEUE_INDEX]),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Gives the memory backing a guest range back to the host, the range
+    // reading as zeros afterwards.
+    fn release_memory_range(
+        mem: &GuestMemoryMmap,
+        range_base: GuestAddress,
+        range_len: u64,
+    ) -> result::Result<(), Error> {
+        let region = mem.find_region(range_base).ok_or(Error::GuestMemory(
+            GuestMemoryError::InvalidGuestAddress(range_base),
+        ))?;
+        let mut offset = range_base.unchecked_offset_from(region.start_addr());
+        let mut len = range_len;
+        if len == 0 || offset + len > region.len() {
+            error!(
+                "Range 0x{:x}-0x{:x} is not contained in a single memory region",
+                range_base.0,
+                range_base.0 + range_len
+            );
+            return Err(Error::InvalidRequest);
+        }
+
+        if let Some(f_off) = region.file_offset() {
+            let hugepage_size = Self::hugetlbfs_page_size(f_off.file())?;
+            if let Some(hugepage_size) = hugepage_size {
+                // Huge pages can only be released as a whole.
+                let start = (offset + hugepage_size - 1) / hugepage_size * hugepage_size;
+                let end = (offset + len) / hugepage_size * hugepage_size;
+                if start >= end {
+                    return Ok(());
+                }
+                offset = start;
+                len = end - start;
+            }
+
+            let res = unsafe {
+                libc::fallocate64(
+                    f_off.file().as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    (offset + f_off.start()) as libc::off64_t,
+                    len as libc::off64_t,
+                )
+            };
+            if res != 0 {
+                return Err(Error::FallocateFail(io::Error::last_os_error()));
+            }
+
+            // Punching the hole already unmapped the huge pages, which can't
+            // be passed to MADV_DONTNEED before Linux 5.18. As the memory is
+            // never backed by an anonymous mapping, MADV_FREE isn't an option
+            // either.
+            if hugepage_size.is_some() {
+                return Ok(());
+            }
+        }
+
+        // Need unsafe to do syscall madvise
+        let res = unsafe {
+            libc::madvise(
+                region.as_ptr().add(offset as usize) as *mut libc::c_void,
+                len as libc::size_t,
+                libc::MADV_DONTNEED,
+            )
+        };
+        if res != 0 {
+            return Err(Error::MadviseFail(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    // Returns the size of the huge pages backing the file, if it belongs to
+    // hugetlbfs.
+    fn hugetlbfs_page_size(file: &File) -> result::Result<Option<u64>, Error> {
+        let mut buf = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+        // Need unsafe to do syscall fstatfs
+        let res = unsafe { libc::fstatfs(file.as_raw_fd(), buf.as_mut_ptr()) };
+        if res != 0 {
+            return Err(Error::FstatfsFail(io::Error::last_os_error()));
+        }
+        // Safe because the kernel filled the structure.
+        let buf = unsafe { buf.assume_init() };
+
+        if buf.f_type as i64 == HUGETLBFS_MAGIC {
+            Ok(Some(buf.f_bsize as u64))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
+        let mut helper = self.epoll_helper()?;
+        helper.run(paused, paused_sync, self)?;
+
+        Ok(())
+    }
+
+    fn epoll_helper(&self) -> result::Result<EpollHelper, EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.resize_receiver.evt.as_raw_fd(), RESIZE_EVENT)?;
         helper.add_event(self.inflate_queue_evt.as_raw_fd(), INFLATE_QUEUE_EVENT)?;
         helper.add_event(self.deflate_queue_evt.as_raw_fd(), DEFLATE_QUEUE_EVENT)?;
-        helper.run(paused, paused_sync, self)?;
-
-        Ok(())
+        if let Some(reporting_queue_evt) = &self.reporting_queue_evt {
+            helper.add_event(reporting_queue_evt.as_raw_fd(), REPORTING_QUEUE_EVENT)?;
+        }
+        Ok(helper)
     }
 }
 
@@ -285,10 +426,18 @@ impl EpollHelperHandler for BalloonEpollHandler {
                 }
                 let mut signal_error = false;
                 let r = {
-                    let mut config = self.config.lock().unwrap();
-                    config.num_pages =
+                    let num_pages =
                         (self.resize_receiver.get_size() >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
-                    if let Err(e) = self.signal(&VirtioInterruptType::Config, None) {
+                    let changed = self
+                        .config
+                        .lock()
+                        .unwrap()
+                        .update(|config| config.num_pages = num_pages);
+                    // Only notify the driver if the request changes the
+                    // balloon target size.
+                    if !changed {
+                        Ok(())
+                    } else if let Err(e) = self.signal(&VirtioInterruptType::Config, None) {
                         signal_error = true;
                         Err(e)
                     } else {
@@ -325,6 +474,17 @@ impl EpollHelperHandler for BalloonEpollHandler {
                     return true;
                 }
             }
+            REPORTING_QUEUE_EVENT => {
+                if let Some(reporting_queue_evt) = &self.reporting_queue_evt {
+                    if let Err(e) = reporting_queue_evt.read() {
+                        error!("Failed to get reporting queue event: {:?}", e);
+                        return true;
+                    } else if let Err(e) = self.process_reporting_queue() {
+                        error!("Failed to signal used reporting queue: {:?}", e);
+                        return true;
+                    }
+                }
+            }
             _ => {
                 error!("Unknown event for virtio-balloon");
                 return true;
@@ -340,8 +500,9 @@ pub struct Balloon {
     common: VirtioCommon,
     id: String,
     resize: VirtioBalloonResize,
-    config: Arc<Mutex<VirtioBalloonConfig>>,
+    config: Arc<Mutex<VirtioConfig<VirtioBalloonConfig>>>,
     seccomp_action: SeccompAction,
+    shared_event_loop: Option<Arc<SharedEpollLoop>>,
 }
 
 impl Balloon {
@@ -350,12 +511,21 @@ impl Balloon {
         id: String,
         size: u64,
         deflate_on_oom: bool,
+        free_page_reporting: bool,
         seccomp_action: SeccompAction,
     ) -> io::Result<Self> {
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut queue_sizes = QUEUE_SIZES.to_vec();
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_INDIRECT_DESC;
         if deflate_on_oom {
             avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
         }
+        if free_page_reporting {
+            // A guest poisoning its free pages only reports them when the
+            // device knows about the poison value.
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_PAGE_POISON;
+            queue_sizes.push(QUEUE_SIZE);
+        }
 
         let config = VirtioBalloonConfig {
             num_pages: (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
@@ -367,24 +537,35 @@ impl Balloon {
                 device_type: VirtioDeviceType::Balloon as u32,
                 avail_features,
                 paused_sync: Some(Arc::new(Barrier::new(2))),
-                queue_sizes: QUEUE_SIZES.to_vec(),
+                queue_sizes,
                 min_queues: NUM_QUEUES as u16,
                 ..Default::default()
             },
             id,
             resize: VirtioBalloonResize::new()?,
-            config: Arc::new(Mutex::new(config)),
+            config: Arc::new(Mutex::new(
+                VirtioConfig::new(config)
+                    .with_writable_field(CONFIG_ACTUAL_OFFSET, CONFIG_ACTUAL_SIZE)
+                    .with_writable_field(CONFIG_POISON_VAL_OFFSET, CONFIG_POISON_VAL_SIZE),
+            )),
             seccomp_action,
+            shared_event_loop: None,
         })
     }
 
+    /// Runs the device handler from the shared event loop rather than from
+    /// a dedicated thread.
+    pub fn set_shared_event_loop(&mut self, shared_event_loop: Arc<SharedEpollLoop>) {
+        self.shared_event_loop = Some(shared_event_loop);
+    }
+
     pub fn resize(&self, size: u64) -> Result<(), Error> {
         self.resize.work(size)
     }
 
     // Get the actual size of the virtio-balloon.
     pub fn get_actual(&self) -> u64 {
-        (self.config.lock().unwrap().actual as u64) << VIRTIO_BALLOON_PFN_SHIFT
+        (self.config.lock().unwrap().get().actual as u64) << VIRTIO_BALLOON_PFN_SHIFT
     }
 }
 
@@ -414,22 +595,24 @@ impl VirtioDevice for Balloon {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.lock().unwrap().as_slice(), offset, data);
+        if let Err(e) = self.config.lock().unwrap().read(offset, data) {
+            error!("Failed reading balloon configuration: {}", e);
+        }
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        // The "actual" field is the only mutable field
-        if offset != CONFIG_ACTUAL_OFFSET || data.len() != CONFIG_ACTUAL_SIZE {
-            error!(
-                "Attempt to write to read-only field: offset {:x} length {}",
-                offset,
-                data.len()
-            );
-            return;
+        if let Err(e) = self.config.lock().unwrap().write(offset, data) {
+            error!("Failed writing balloon configuration: {}", e);
         }
+    }
 
-        self.write_config_helper(self.config.lock().unwrap().as_mut_slice(), offset, data);
+    fn config_generation(&self) -> u8 {
+        self.config.lock().unwrap().generation()
     }
 
     fn activate(
@@ -453,23 +636,53 @@ impl VirtioDevice for Balloon {
             interrupt_cb,
             inflate_queue_evt: queue_evts.remove(0),
             deflate_queue_evt: queue_evts.remove(0),
+            reporting_queue_evt: queue_evts.pop(),
             kill_evt,
             pause_evt,
         };
 
         let paused = self.common.paused.clone();
         let paused_sync = self.common.paused_sync.clone();
+
+        if let Some(shared_event_loop) = &self.shared_event_loop {
+            let helper = handler.epoll_helper().map_err(|e| {
+                error!("failed creating virtio-balloon epoll helper: {:?}", e);
+                ActivateError::BadActivate
+            })?;
+            let worker = shared_event_loop
+                .add(
+                    self.id.clone(),
+                    self.common.watch_worker(&self.id),
+                    helper,
+                    Box::new(handler),
+                    paused,
+                    paused_sync.unwrap(),
+                )
+                .map_err(|e| {
+                    error!(
+                        "failed adding virtio-balloon to the shared event loop: {:?}",
+                        e
+                    );
+                    ActivateError::BadActivate
+                })?;
+            self.common.shared_epoll_workers = Some(vec![worker]);
+
+            event!("virtio-device", "activated", "id", &self.id);
+            return Ok(());
+        }
+
         let mut epoll_threads = Vec::new();
         let virtio_balloon_seccomp_filter =
             get_seccomp_filter(&self.seccomp_action, Thread::VirtioBalloon)
                 .map_err(ActivateError::CreateSeccompFilter)?;
+        let watch = self.common.watch_worker(&self.id);
         thread::Builder::new()
             .name(self.id.clone())
             .spawn(move || {
                 if let Err(e) = SeccompFilter::apply(virtio_balloon_seccomp_filter) {
-                    error!("Error applying seccomp filter: {:?}", e);
+                    watch.failed(format!("Error applying seccomp filter: {:?}", e));
                 } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                    error!("Error running worker: {:?}", e);
+                    watch.failed(format!("Error running worker: {:?}", e));
                 }
             })
             .map(|thread| epoll_threads.push(thread))
@@ -507,3 +720,27 @@ impl Snapshottable for Balloon {
 }
 impl Transportable for Balloon {}
 impl Migratable for Balloon {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_memory_range() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_obj(0xdead_beefu32, GuestAddress(0x2000)).unwrap();
+        mem.write_obj(0xdead_beefu32, GuestAddress(0x3000)).unwrap();
+
+        BalloonEpollHandler::release_memory_range(&mem, GuestAddress(0x2000), 0x1000).unwrap();
+        assert_eq!(mem.read_obj::<u32>(GuestAddress(0x2000)).unwrap(), 0);
+        assert_eq!(
+            mem.read_obj::<u32>(GuestAddress(0x3000)).unwrap(),
+            0xdead_beef
+        );
+
+        // Ranges must be contained in a single memory region.
+        assert!(
+            BalloonEpollHandler::release_memory_range(&mem, GuestAddress(0xf000), 0x2000).is_err()
+        );
+    }
+}
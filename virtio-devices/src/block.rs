@@ -17,27 +17,38 @@ use super::{
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::GuestMemoryMmap;
 use crate::VirtioInterrupt;
+use block_util::mirror::{Mirror, MirrorDiskSync};
+use block_util::overlay::OverlayDiskSync;
 use block_util::{
-    async_io::AsyncIo, async_io::AsyncIoError, async_io::DiskFile, build_disk_image_id, Request,
-    RequestType, VirtioBlockConfig,
+    async_io::AsyncIo, async_io::AsyncIoError, async_io::DiskFile, async_io::DiskTopology,
+    build_disk_image_id, build_serial, Request, RequestType, VirtioBlockConfig,
 };
 use rate_limiter::{RateLimiter, TokenType};
 use seccomp::{SeccompAction, SeccompFilter};
+use std::cmp;
+use std::fs::File;
 use std::io;
 use std::num::Wrapping;
-use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, convert::TryInto};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic};
+use virtio_bindings::bindings::virtio_ring::{
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic};
 use vm_migration::VersionMapped;
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::{NotificationSuppression, VirtioConfig};
 use vmm_sys_util::eventfd::EventFd;
 
 const SECTOR_SHIFT: u8 = 9;
@@ -49,33 +60,118 @@ const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New 'wake up' event from the rate limiter
 const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The disk image is about to be switched.
+const DISK_SWITCH_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+
+// How long the requests in flight may take to complete before a disk switch
+// is given up.
+const DISK_SWITCH_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the requests in flight may take to complete once the queues are
+// about to be released or the disk image switched.
+const IN_FLIGHT_REQUESTS_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to parse the request.
     RequestParsing(block_util::Error),
-    /// Failed to execute the request.
-    RequestExecuting(block_util::ExecuteError),
     /// Missing the expected entry in the list of requests.
     MissingEntryRequestList,
-    /// The asynchronous request returned with failure.
-    AsyncRequestFailure,
     /// Failed synchronizing the file
     Fsync(AsyncIoError),
+    /// Failed signaling the used queue.
+    SignalUsedQueue(DeviceError),
+    /// A mirror of the disk is already in progress.
+    MirrorInProgress,
+    /// No mirror of the disk is in progress.
+    NoMirror,
+    /// Failed setting up the mirror of the disk.
+    CreateMirror(io::Error),
+    /// Failed creating the async I/O of the new disk image.
+    SwitchAsyncIo(block_util::async_io::DiskFileError),
+    /// Failed spawning the thread copying the disk to the mirror.
+    SpawnMirrorThread(io::Error),
+    /// Failed pivoting to the mirror of the disk.
+    PivotMirror(io::Error),
+    /// Failed taking a snapshot of the disk.
+    SnapshotDisk(io::Error),
+    /// Timed out waiting for the requests in flight before a disk switch.
+    DiskSwitchTimeout,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+// Upper bounds, in microseconds, of the request latency histogram buckets.
+// An extra bucket accounts for the requests slower than the last bound.
+const LATENCY_BUCKETS_US: [u64; 4] = [100, 1_000, 10_000, 100_000];
+const LATENCY_BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+const READ_LATENCY_COUNTERS: [&str; LATENCY_BUCKETS] = [
+    "read_latency_us_lt_100",
+    "read_latency_us_lt_1000",
+    "read_latency_us_lt_10000",
+    "read_latency_us_lt_100000",
+    "read_latency_us_ge_100000",
+];
+const WRITE_LATENCY_COUNTERS: [&str; LATENCY_BUCKETS] = [
+    "write_latency_us_lt_100",
+    "write_latency_us_lt_1000",
+    "write_latency_us_lt_10000",
+    "write_latency_us_lt_100000",
+    "write_latency_us_ge_100000",
+];
+const FLUSH_LATENCY_COUNTERS: [&str; LATENCY_BUCKETS] = [
+    "flush_latency_us_lt_100",
+    "flush_latency_us_lt_1000",
+    "flush_latency_us_lt_10000",
+    "flush_latency_us_lt_100000",
+    "flush_latency_us_ge_100000",
+];
+
+#[derive(Default, Clone)]
+struct LatencyHistogram {
+    buckets: Arc<[AtomicU64; LATENCY_BUCKETS]>,
+}
+
+impl LatencyHistogram {
+    fn bucket(latency: Duration) -> usize {
+        let latency_us = latency.as_micros();
+        LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| latency_us < u128::from(*bound))
+            .unwrap_or(LATENCY_BUCKETS - 1)
+    }
+
+    fn record(&self, latency: Duration) {
+        self.buckets[Self::bucket(latency)].fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn export(
+        &self,
+        names: &[&'static str; LATENCY_BUCKETS],
+        counters: &mut HashMap<&'static str, Wrapping<u64>>,
+    ) {
+        for (name, bucket) in names.iter().zip(self.buckets.iter()) {
+            counters.insert(*name, Wrapping(bucket.load(Ordering::Acquire)));
+        }
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct BlockCounters {
     read_bytes: Arc<AtomicU64>,
     read_ops: Arc<AtomicU64>,
     write_bytes: Arc<AtomicU64>,
     write_ops: Arc<AtomicU64>,
+    flush_ops: Arc<AtomicU64>,
+    errors: Arc<AtomicU64>,
+    read_latency: LatencyHistogram,
+    write_latency: LatencyHistogram,
+    flush_latency: LatencyHistogram,
 }
 
 struct BlockEpollHandler {
     queue: Queue,
+    notification: NotificationSuppression,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Box<dyn AsyncIo>,
     disk_nsectors: u64,
@@ -86,8 +182,54 @@ struct BlockEpollHandler {
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     queue_evt: EventFd,
-    request_list: HashMap<u16, Request>,
+    request_list: HashMap<u16, (Request, Instant)>,
     rate_limiter: Option<RateLimiter>,
+    poll_us: u64,
+    disk_switch_evt: EventFd,
+    disk_switch: Receiver<DiskSwitch>,
+    quiesce_failed: Arc<AtomicBool>,
+}
+
+// Request for an epoll handler to switch to a new disk image. The handler
+// acknowledges it has no request in flight anymore, and waits for the new
+// disk image, keeping the current one if the channel gets closed instead.
+// The new disk image is provided upfront while the device is paused, as
+// nothing is waiting for the acknowledgement then.
+struct DiskSwitch {
+    drained: Sender<()>,
+    disk_image: Receiver<Box<dyn AsyncIo>>,
+}
+
+/// Switch of a block device to a new disk image, requested to its epoll
+/// handlers. The handlers complete the requests they have in flight, and
+/// wait for the new disk image, keeping the current one if the request is
+/// dropped instead.
+pub struct DiskSwitchRequest {
+    handlers: Vec<(Receiver<()>, Sender<Box<dyn AsyncIo>>, u32)>,
+    paused: bool,
+}
+
+impl DiskSwitchRequest {
+    /// Waits for the handlers to complete the requests in flight, which
+    /// shouldn't be done with the device locked, as the vCPUs may need it
+    /// meanwhile. While the device is paused, the handlers switch once
+    /// resumed, hence there is nothing to wait for.
+    pub fn wait_drained(&self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + DISK_SWITCH_TIMEOUT;
+        for (drained, _, _) in self.handlers.iter() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            // The channel is closed if the handler is gone.
+            if let Err(RecvTimeoutError::Timeout) = drained.recv_timeout(timeout) {
+                return Err(Error::DiskSwitchTimeout);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl BlockEpollHandler {
@@ -95,10 +237,16 @@ impl BlockEpollHandler {
         let queue = &mut self.queue;
         let mem = self.mem.memory();
 
-        let mut used_desc_heads = Vec::new();
-        let mut used_count = 0;
-
-        for avail_desc in queue.iter(&mem) {
+        loop {
+            let avail_desc = match queue.iter(mem.deref()).next() {
+                Some(avail_desc) => avail_desc,
+                None => {
+                    if self.notification.enable_notification(queue, mem.deref()) {
+                        continue;
+                    }
+                    break;
+                }
+            };
             let mut request = Request::parse(&avail_desc, &mem).map_err(Error::RequestParsing)?;
 
             if let Some(rate_limiter) = &mut self.rate_limiter {
@@ -134,54 +282,62 @@ impl BlockEpollHandler {
 
             request.set_writeback(self.writeback.load(Ordering::Acquire));
 
-            if request
-                .execute_async(
-                    &mem,
-                    self.disk_nsectors,
-                    self.disk_image.as_mut(),
-                    &self.disk_image_id,
-                    avail_desc.index as u64,
-                )
-                .map_err(Error::RequestExecuting)?
-            {
-                self.request_list.insert(avail_desc.index, request);
-            } else {
-                // We use unwrap because the request parsing process already
-                // checked that the status_addr was valid.
-                mem.write_obj(VIRTIO_BLK_S_OK, request.status_addr).unwrap();
-
-                // If no asynchronous operation has been submitted, we can
-                // simply return the used descriptor.
-                used_desc_heads.push((avail_desc.index, 0));
-                used_count += 1;
+            let start = Instant::now();
+            match request.execute_async(
+                &mem,
+                self.disk_nsectors,
+                self.disk_image.as_mut(),
+                &self.disk_image_id,
+                avail_desc.index as u64,
+            ) {
+                Ok(true) => {
+                    self.request_list.insert(avail_desc.index, (request, start));
+                }
+                Ok(false) => {
+                    // We use unwrap because the request parsing process already
+                    // checked that the status_addr was valid.
+                    mem.write_obj(VIRTIO_BLK_S_OK, request.status_addr).unwrap();
+
+                    // If no asynchronous operation has been submitted, we can
+                    // simply return the used descriptor.
+                    self.notification
+                        .add_used(queue, mem.deref(), avail_desc.index, 0);
+                }
+                Err(e) => {
+                    error!("Failed to execute request: {:?}", e);
+                    self.counters.errors.fetch_add(1, Ordering::AcqRel);
+
+                    // Report the failure to the guest instead of stopping the
+                    // processing of the queue.
+                    mem.write_obj(e.status(), request.status_addr).unwrap();
+                    self.notification
+                        .add_used(queue, mem.deref(), avail_desc.index, 0);
+                }
             }
         }
 
-        for &(desc_index, len) in used_desc_heads.iter() {
-            queue.add_used(&mem, desc_index, len);
-        }
-
-        Ok(used_count > 0)
+        Ok(self.notification.needs_notification(queue, mem.deref()))
     }
 
     fn process_queue_complete(&mut self) -> Result<bool> {
         let queue = &mut self.queue;
 
-        let mut used_desc_heads = Vec::new();
-        let mut used_count = 0;
         let mem = self.mem.memory();
         let mut read_bytes = Wrapping(0);
         let mut write_bytes = Wrapping(0);
         let mut read_ops = Wrapping(0);
         let mut write_ops = Wrapping(0);
+        let mut flush_ops = Wrapping(0);
+        let mut errors = Wrapping(0);
 
         let completion_list = self.disk_image.complete();
         for (user_data, result) in completion_list {
             let desc_index = user_data as u16;
-            let request = self
+            let (request, start) = self
                 .request_list
                 .remove(&desc_index)
                 .ok_or(Error::MissingEntryRequestList)?;
+            let latency = start.elapsed();
 
             let (status, len) = if result >= 0 {
                 match request.request_type {
@@ -190,6 +346,7 @@ impl BlockEpollHandler {
                             read_bytes += Wrapping(*data_len as u64);
                         }
                         read_ops += Wrapping(1);
+                        self.counters.read_latency.record(latency);
                     }
                     RequestType::Out => {
                         if !request.writeback {
@@ -199,6 +356,11 @@ impl BlockEpollHandler {
                             write_bytes += Wrapping(*data_len as u64);
                         }
                         write_ops += Wrapping(1);
+                        self.counters.write_latency.record(latency);
+                    }
+                    RequestType::Flush => {
+                        flush_ops += Wrapping(1);
+                        self.counters.flush_latency.record(latency);
                     }
                     _ => {}
                 }
@@ -209,19 +371,17 @@ impl BlockEpollHandler {
                     "Request failed: {:?}",
                     io::Error::from_raw_os_error(-result)
                 );
-                return Err(Error::AsyncRequestFailure);
+                errors += Wrapping(1);
+
+                (VIRTIO_BLK_S_IOERR, 0)
             };
 
             // We use unwrap because the request parsing process already
             // checked that the status_addr was valid.
             mem.write_obj(status, request.status_addr).unwrap();
 
-            used_desc_heads.push((desc_index as u16, len));
-            used_count += 1;
-        }
-
-        for &(desc_index, len) in used_desc_heads.iter() {
-            queue.add_used(&mem, desc_index, len);
+            self.notification
+                .add_used(queue, mem.deref(), desc_index, len);
         }
 
         self.counters
@@ -238,7 +398,12 @@ impl BlockEpollHandler {
             .read_ops
             .fetch_add(read_ops.0, Ordering::AcqRel);
 
-        Ok(used_count > 0)
+        self.counters
+            .flush_ops
+            .fetch_add(flush_ops.0, Ordering::AcqRel);
+        self.counters.errors.fetch_add(errors.0, Ordering::AcqRel);
+
+        Ok(self.notification.needs_notification(queue, mem.deref()))
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -250,6 +415,162 @@ impl BlockEpollHandler {
             })
     }
 
+    // Busy polls the queue for new requests, and the disk image for the
+    // completion of the submitted ones, for `poll_us` before going back to
+    // waiting for events. This saves the guest notification and the wake up
+    // latencies, at the cost of a spinning thread. Polling stops as soon as
+    // the rate limit is reached, or the device is being paused or killed.
+    fn poll_queue(&mut self) -> Result<()> {
+        let budget = Duration::from_micros(self.poll_us);
+        let start = Instant::now();
+
+        while start.elapsed() < budget {
+            if self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked())
+                || self.pause_or_kill_pending()
+            {
+                break;
+            }
+
+            let submit_notification = self.process_queue_submit()?;
+            let complete_notification = self.process_queue_complete()?;
+            if submit_notification || complete_notification {
+                self.signal_used_queue().map_err(Error::SignalUsedQueue)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whether the pause or the kill event fired, without consuming it so that
+    // the epoll loop handles it once polling stops. An error stops polling as
+    // well.
+    fn pause_or_kill_pending(&self) -> bool {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.kill_evt.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.pause_evt.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safe because the file descriptors and the timeout outlive the
+        // call, which doesn't block.
+        let ret = unsafe {
+            libc::ppoll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                &timeout,
+                std::ptr::null(),
+            )
+        };
+        ret != 0
+    }
+
+    // Waits for the completion of the requests submitted to the backend.
+    // Once the device has been reset, the guest memory they point to must
+    // not be accessed anymore, and the completions are dropped since the
+    // queue is about to be reinitialized by the driver. Otherwise they are
+    // handed back to the guest. Gives up on requests the backend doesn't
+    // complete in time.
+    fn wait_in_flight_requests(&mut self, complete: bool) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        epoll::ctl(
+            epoll_file.as_raw_fd(),
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.disk_image.notifier().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(COMPLETION_EVENT)),
+        )?;
+
+        let mut events = [epoll::Event::new(epoll::Events::empty(), 0)];
+        let deadline = Instant::now() + IN_FLIGHT_REQUESTS_TIMEOUT;
+        while !self.request_list.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.as_millis() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} requests still in flight", self.request_list.len()),
+                ));
+            }
+
+            match epoll::wait(
+                epoll_file.as_raw_fd(),
+                timeout.as_millis() as i32,
+                &mut events,
+            ) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            let _ = self.disk_image.notifier().read();
+            if complete {
+                if self
+                    .process_queue_complete()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?
+                {
+                    self.signal_used_queue()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+                }
+            } else {
+                for (user_data, _) in self.disk_image.complete() {
+                    self.request_list.remove(&(user_data as u16));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Switches to a new disk image once the requests submitted to the
+    // current one completed, as their completions would be lost otherwise.
+    fn switch_disk_image(
+        &mut self,
+        helper: &mut EpollHelper,
+        switch: DiskSwitch,
+    ) -> result::Result<(), EpollHelperError> {
+        self.wait_in_flight_requests(true)
+            .map_err(EpollHelperError::IoError)?;
+
+        let _ = switch.drained.send(());
+        let disk_image = match switch.disk_image.recv() {
+            Ok(disk_image) => disk_image,
+            Err(_) => return Ok(()),
+        };
+
+        helper.del_event_custom(
+            self.disk_image.notifier().as_raw_fd(),
+            COMPLETION_EVENT,
+            epoll::Events::EPOLLIN,
+        )?;
+        self.disk_image = disk_image;
+        helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)
+    }
+
+    // Applies the pending disk switches, returning whether there was any.
+    fn switch_disk_images(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> result::Result<bool, EpollHelperError> {
+        let mut switched = false;
+        while let Ok(switch) = self.disk_switch.try_recv() {
+            self.switch_disk_image(helper, switch)?;
+            switched = true;
+        }
+
+        Ok(switched)
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -258,6 +579,7 @@ impl BlockEpollHandler {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
         helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
+        helper.add_event(self.disk_switch_evt.as_raw_fd(), DISK_SWITCH_EVENT)?;
         if let Some(rate_limiter) = &self.rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
         }
@@ -268,8 +590,23 @@ impl BlockEpollHandler {
 }
 
 impl EpollHelperHandler for BlockEpollHandler {
-    fn handle_event(&mut self, _helper: &mut EpollHelper, event: &epoll::Event) -> bool {
+    fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool {
         let ev_type = event.data as u16;
+
+        // A disk switch requested while the device was paused must be applied
+        // before anything else once resumed, as the requests would otherwise
+        // keep going to the previous disk image until its event is handled.
+        match self.switch_disk_images(helper) {
+            // The completions of the previous disk image have all been
+            // processed already.
+            Ok(true) if ev_type == COMPLETION_EVENT => return false,
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to switch disk image: {:?}", e);
+                return true;
+            }
+        }
+
         match ev_type {
             QUEUE_AVAIL_EVENT => {
                 if let Err(e) = self.queue_evt.read() {
@@ -297,6 +634,13 @@ impl EpollHelperHandler for BlockEpollHandler {
                         }
                     }
                 }
+
+                if self.poll_us > 0 {
+                    if let Err(e) = self.poll_queue() {
+                        error!("Failed to poll queue: {:?}", e);
+                        return true;
+                    }
+                }
             }
             COMPLETION_EVENT => {
                 if let Err(e) = self.disk_image.notifier().read() {
@@ -318,6 +662,13 @@ impl EpollHelperHandler for BlockEpollHandler {
                         return true;
                     }
                 }
+
+                if self.poll_us > 0 {
+                    if let Err(e) = self.poll_queue() {
+                        error!("Failed to poll queue: {:?}", e);
+                        return true;
+                    }
+                }
             }
             RATE_LIMITER_EVENT => {
                 if let Some(rate_limiter) = &mut self.rate_limiter {
@@ -344,6 +695,13 @@ impl EpollHelperHandler for BlockEpollHandler {
                     return true;
                 }
             }
+            DISK_SWITCH_EVENT => {
+                // The pending disk switches have been applied already.
+                if let Err(e) = self.disk_switch_evt.read() {
+                    error!("Failed to get disk switch event: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unexpected event: {}", ev_type);
                 return true;
@@ -351,6 +709,13 @@ impl EpollHelperHandler for BlockEpollHandler {
         }
         false
     }
+
+    fn quiesce(&mut self) -> result::Result<(), EpollHelperError> {
+        self.wait_in_flight_requests(false).map_err(|e| {
+            self.quiesce_failed.store(true, Ordering::SeqCst);
+            EpollHelperError::IoError(e)
+        })
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
@@ -360,11 +725,20 @@ pub struct Block {
     disk_image: Box<dyn DiskFile>,
     disk_path: PathBuf,
     disk_nsectors: u64,
-    config: VirtioBlockConfig,
+    serial: Option<String>,
+    config: VirtioConfig<VirtioBlockConfig>,
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
+    poll_us: u64,
+    // Lets the epoll handlers switch to a new disk image, along with the
+    // depth of their queue.
+    disk_switches: Vec<(EventFd, Sender<DiskSwitch>, u32)>,
+    mirror: Option<Arc<Mirror>>,
+    // Whether an epoll handler stopped with requests still in flight, which
+    // might still access the guest memory.
+    quiesce_failed: Arc<AtomicBool>,
 }
 
 #[derive(Versionize)]
@@ -378,6 +752,27 @@ pub struct BlockState {
 
 impl VersionMapped for BlockState {}
 
+// Fills the topology fields of the configuration space, expressed in logical
+// blocks, falling back to plain sectors if the topology doesn't make sense.
+fn set_topology(config: &mut VirtioBlockConfig, topology: &DiskTopology) {
+    config.blk_size = SECTOR_SIZE as u32;
+
+    let logical = topology.logical_block_size;
+    if logical < SECTOR_SIZE
+        || !logical.is_power_of_two()
+        || topology.physical_block_size < logical
+        || !topology.physical_block_size.is_power_of_two()
+    {
+        warn!("Ignoring invalid disk topology {:?}", topology);
+        return;
+    }
+
+    config.blk_size = logical as u32;
+    config.physical_block_exp = (topology.physical_block_size / logical).trailing_zeros() as u8;
+    config.min_io_size = cmp::min(topology.minimum_io_size / logical, u16::MAX as u64) as u16;
+    config.opt_io_size = cmp::min(topology.optimal_io_size / logical, u32::MAX as u64) as u32;
+}
+
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     #[allow(clippy::too_many_arguments)]
@@ -385,12 +780,14 @@ impl Block {
         id: String,
         mut disk_image: Box<dyn DiskFile>,
         disk_path: PathBuf,
+        serial: Option<String>,
         is_disk_read_only: bool,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
+        poll_us: u64,
     ) -> io::Result<Self> {
         let disk_size = disk_image.size().map_err(|e| {
             io::Error::new(
@@ -407,8 +804,12 @@ impl Block {
         }
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
             | (1u64 << VIRTIO_BLK_F_FLUSH)
-            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE);
+            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE)
+            | (1u64 << VIRTIO_BLK_F_BLK_SIZE)
+            | (1u64 << VIRTIO_BLK_F_TOPOLOGY);
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -424,6 +825,7 @@ impl Block {
             writeback: 1,
             ..Default::default()
         };
+        set_topology(&mut config, &disk_image.topology());
 
         if num_queues > 1 {
             avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
@@ -443,21 +845,199 @@ impl Block {
             disk_image,
             disk_path,
             disk_nsectors,
-            config,
+            serial,
+            config: Self::config_space(config),
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
             seccomp_action,
             rate_limiter_config,
+            poll_us,
+            disk_switches: Vec::new(),
+            mirror: None,
+            quiesce_failed: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Requests the epoll handlers to switch to a new disk image. They are
+    /// held once the requests submitted to the current disk image completed,
+    /// so that none of them accesses the new one while another might still
+    /// be writing to the current one. The new disk image must be provided
+    /// once the request has been waited for.
+    pub fn request_disk_switch(&self) -> DiskSwitchRequest {
+        let mut handlers = Vec::new();
+        for (disk_switch_evt, disk_switch, queue_size) in self.disk_switches.iter() {
+            let (drained_sender, drained) = channel();
+            let (disk_image_sender, disk_image) = channel();
+            let switch = DiskSwitch {
+                drained: drained_sender,
+                disk_image,
+            };
+            if disk_switch.send(switch).is_ok() && disk_switch_evt.write(1).is_ok() {
+                handlers.push((drained, disk_image_sender, *queue_size));
+            }
+        }
+
+        DiskSwitchRequest {
+            handlers,
+            paused: self.common.paused.load(Ordering::SeqCst),
+        }
+    }
+
+    // Switches the epoll handlers held by `request` to the disk image
+    // `new_disk_image` returns. They keep using the current disk image if
+    // no new one can be provided.
+    fn switch_disk_image<F>(&mut self, request: DiskSwitchRequest, new_disk_image: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Box<dyn DiskFile>>,
+    {
+        let handlers = request.handlers;
+        let disk_image = new_disk_image()?;
+        let mut async_ios = Vec::new();
+        for (_, _, queue_size) in handlers.iter() {
+            async_ios.push(
+                disk_image
+                    .new_async_io(*queue_size)
+                    .map_err(Error::SwitchAsyncIo)?,
+            );
+        }
+        for ((_, disk_image_sender, _), async_io) in handlers.into_iter().zip(async_ios) {
+            let _ = disk_image_sender.send(async_io);
+        }
+        self.disk_image = disk_image;
+
+        Ok(())
+    }
+
+    /// Starts mirroring the disk onto `target`, `source` being the raw image
+    /// currently backing the disk. The content is copied in the background,
+    /// the writes of the guest being tracked until the mirror is pivoted.
+    pub fn start_mirror(
+        &mut self,
+        request: DiskSwitchRequest,
+        source: File,
+        target: File,
+    ) -> Result<()> {
+        if self.mirror.is_some() {
+            return Err(Error::MirrorInProgress);
+        }
+
+        // The copy only needs the same syscalls as the epoll handlers.
+        let virtio_block_seccomp_filter =
+            get_seccomp_filter(&self.seccomp_action, Thread::VirtioBlock).map_err(|e| {
+                Error::SpawnMirrorThread(io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+            })?;
+
+        // Nothing is copied before the copy thread starts, hence the writes
+        // issued before the switch don't need to be tracked.
+        let mirror = Arc::new(Mirror::new(source, target).map_err(Error::CreateMirror)?);
+        let disk_image: Box<dyn DiskFile> = Box::new(MirrorDiskSync::new(mirror.clone()));
+        self.switch_disk_image(request, || Ok(disk_image))?;
+        // The copy starts once the device is resumed.
+        if self.common.paused.load(Ordering::SeqCst) {
+            mirror.pause();
+        }
+
+        let copy_mirror = mirror.clone();
+        let id = self.id.clone();
+        thread::Builder::new()
+            .name(format!("{}_mirror", self.id))
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(virtio_block_seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    copy_mirror.stop();
+                } else if let Err(e) = copy_mirror.run() {
+                    error!("Failed mirroring disk {}: {}", id, e);
+                }
+            })
+            .map_err(Error::SpawnMirrorThread)?;
+
+        self.mirror = Some(mirror);
+        event!("virtio-device", "mirror-started", "id", &self.id);
+
+        Ok(())
+    }
+
+    /// Switches the disk to the target of the mirror in progress, which must
+    /// have caught up with the disk already. The disk keeps being served
+    /// through the mirror until switched to a new disk image.
+    pub fn pivot_mirror(&mut self) -> Result<()> {
+        self.mirror
+            .as_ref()
+            .ok_or(Error::NoMirror)?
+            .pivot()
+            .map_err(Error::PivotMirror)?;
+        self.mirror = None;
+        event!("virtio-device", "mirror-pivoted", "id", &self.id);
+
+        Ok(())
+    }
+
+    /// Switches the disk to `disk_image`, which must have the same content
+    /// as the current one.
+    pub fn switch_disk(
+        &mut self,
+        request: DiskSwitchRequest,
+        disk_image: Box<dyn DiskFile>,
+    ) -> Result<()> {
+        self.switch_disk_image(request, || Ok(disk_image))
+    }
+
+    /// Takes a snapshot of the disk, `base` being the raw image backing it,
+    /// layered with the read-only `backing_overlays`, the last one being
+    /// the overlay the disk currently writes to if any. Once the image and
+    /// this overlay have been flushed, the writes are redirected to a new
+    /// copy-on-write overlay created at `overlay`, the lower layers being
+    /// only read from then on. While the device is paused, the requests
+    /// still in flight complete onto the former layers once resumed.
+    pub fn snapshot_disk(
+        &mut self,
+        request: DiskSwitchRequest,
+        base: File,
+        backing_overlays: &[PathBuf],
+        overlay: &Path,
+    ) -> Result<()> {
+        if self.mirror.is_some() {
+            return Err(Error::MirrorInProgress);
+        }
+        if overlay.exists() {
+            return Err(Error::SnapshotDisk(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("overlay {:?} already exists", overlay),
+            )));
+        }
+
+        self.switch_disk_image(request, || {
+            base.sync_all().map_err(Error::SnapshotDisk)?;
+            if let Some(top) = backing_overlays.last() {
+                File::open(top)
+                    .and_then(|top| top.sync_all())
+                    .map_err(Error::SnapshotDisk)?;
+            }
+            Ok(Box::new(
+                OverlayDiskSync::new(base, backing_overlays, overlay)
+                    .map_err(Error::SnapshotDisk)?,
+            ) as Box<dyn DiskFile>)
+        })?;
+        event!("virtio-device", "disk-snapshot", "id", &self.id);
+
+        Ok(())
+    }
+
+    fn config_space(config: VirtioBlockConfig) -> VirtioConfig<VirtioBlockConfig> {
+        // The "writeback" field is the only mutable field
+        let writeback_offset =
+            (&config.writeback as *const _ as u64) - (&config as *const _ as u64);
+        VirtioConfig::new(config)
+            .with_writable_field(writeback_offset, std::mem::size_of_val(&config.writeback))
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.to_str().unwrap().to_owned(),
             disk_nsectors: self.disk_nsectors,
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
-            config: self.config,
+            config: *self.config.get(),
         }
     }
 
@@ -466,13 +1046,13 @@ impl Block {
         self.disk_nsectors = state.disk_nsectors;
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
-        self.config = state.config;
+        self.config = Self::config_space(state.config);
     }
 
     fn update_writeback(&mut self) {
         // Use writeback from config if VIRTIO_BLK_F_CONFIG_WCE
         let writeback = if self.common.feature_acked(VIRTIO_BLK_F_CONFIG_WCE.into()) {
-            self.config.writeback == 1
+            self.config.get().writeback == 1
         } else {
             // Else check if VIRTIO_BLK_F_FLUSH negotiated
             self.common.feature_acked(VIRTIO_BLK_F_FLUSH.into())
@@ -492,6 +1072,10 @@ impl Block {
 
 impl Drop for Block {
     fn drop(&mut self) {
+        if let Some(mirror) = self.mirror.take() {
+            mirror.stop();
+        }
+
         if let Some(kill_evt) = self.common.kill_evt.take() {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
@@ -516,28 +1100,29 @@ impl VirtioDevice for Block {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        if let Err(e) = self.config.read(offset, data) {
+            error!("Failed reading block configuration: {}", e);
+        }
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        // The "writeback" field is the only mutable field
-        let writeback_offset =
-            (&self.config.writeback as *const _ as u64) - (&self.config as *const _ as u64);
-        if offset != writeback_offset || data.len() != std::mem::size_of_val(&self.config.writeback)
-        {
-            error!(
-                "Attempt to write to read-only field: offset {:x} length {}",
-                offset,
-                data.len()
-            );
+        if let Err(e) = self.config.write(offset, data) {
+            error!("Failed writing block configuration: {}", e);
             return;
         }
 
-        self.config.writeback = data[0];
         self.update_writeback();
     }
 
+    fn config_generation(&self) -> u8 {
+        self.config.generation()
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -547,13 +1132,19 @@ impl VirtioDevice for Block {
     ) -> ActivateResult {
         self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
 
-        let disk_image_id = build_disk_image_id(&self.disk_path);
+        let disk_image_id = match &self.serial {
+            Some(serial) => build_serial(serial),
+            None => build_disk_image_id(&self.disk_path),
+        };
         self.update_writeback();
 
+        let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
         let mut epoll_threads = Vec::new();
+        self.disk_switches.clear();
         for i in 0..queues.len() {
             let queue_evt = queue_evts.remove(0);
-            let queue = queues.remove(0);
+            let mut queue = queues.remove(0);
+            queue.set_event_idx(event_idx);
             let queue_size = queue.size;
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
@@ -563,8 +1154,23 @@ impl VirtioDevice for Block {
                 .transpose()
                 .map_err(ActivateError::CreateRateLimiter)?;
 
+            let disk_switch_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                error!("failed creating disk switch EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+            let (disk_switch_sender, disk_switch) = channel();
+            self.disk_switches.push((
+                disk_switch_evt.try_clone().map_err(|e| {
+                    error!("failed cloning disk switch EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?,
+                disk_switch_sender,
+                queue_size as u32,
+            ));
+
             let mut handler = BlockEpollHandler {
                 queue,
+                notification: NotificationSuppression::new(),
                 mem: mem.clone(),
                 disk_image: self
                     .disk_image
@@ -583,6 +1189,10 @@ impl VirtioDevice for Block {
                 queue_evt,
                 request_list: HashMap::with_capacity(queue_size.into()),
                 rate_limiter,
+                poll_us: self.poll_us,
+                disk_switch_evt,
+                disk_switch,
+                quiesce_failed: self.quiesce_failed.clone(),
             };
 
             let paused = self.common.paused.clone();
@@ -593,13 +1203,14 @@ impl VirtioDevice for Block {
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioBlock)
                     .map_err(ActivateError::CreateSeccompFilter)?;
 
+            let watch = self.common.watch_worker(&self.id);
             thread::Builder::new()
                 .name(format!("{}_q{}", self.id.clone(), i))
                 .spawn(move || {
                     if let Err(e) = SeccompFilter::apply(virtio_block_seccomp_filter) {
-                        error!("Error applying seccomp filter: {:?}", e);
+                        watch.failed(format!("Error applying seccomp filter: {:?}", e));
                     } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                        error!("Error running worker: {:?}", e);
+                        watch.failed(format!("Error running worker: {:?}", e));
                     }
                 })
                 .map(|thread| epoll_threads.push(thread))
@@ -617,6 +1228,11 @@ impl VirtioDevice for Block {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        self.disk_switches.clear();
+        if self.quiesce_failed.swap(false, Ordering::SeqCst) {
+            error!("Failed to reset {}: requests still in flight", self.id);
+            return None;
+        }
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
@@ -640,6 +1256,28 @@ impl VirtioDevice for Block {
             "write_ops",
             Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
         );
+        counters.insert(
+            "flush_ops",
+            Wrapping(self.counters.flush_ops.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "errors",
+            Wrapping(self.counters.errors.load(Ordering::Acquire)),
+        );
+
+        self.counters
+            .read_latency
+            .export(&READ_LATENCY_COUNTERS, &mut counters);
+        self.counters
+            .write_latency
+            .export(&WRITE_LATENCY_COUNTERS, &mut counters);
+        self.counters
+            .flush_latency
+            .export(&FLUSH_LATENCY_COUNTERS, &mut counters);
+
+        if let Some(mirror) = &self.mirror {
+            counters.insert("mirror_remaining_bytes", Wrapping(mirror.remaining()));
+        }
 
         Some(counters)
     }
@@ -647,10 +1285,20 @@ impl VirtioDevice for Block {
 
 impl Pausable for Block {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        self.common.pause()
+        self.common.pause()?;
+        // The disk isn't accessed anymore once paused, by the guest nor by
+        // the mirror in progress.
+        if let Some(mirror) = &self.mirror {
+            mirror.pause();
+        }
+
+        Ok(())
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
+        if let Some(mirror) = &self.mirror {
+            mirror.resume();
+        }
         self.common.resume()
     }
 }
@@ -671,3 +1319,93 @@ impl Snapshottable for Block {
 }
 impl Transportable for Block {}
 impl Migratable for Block {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::{VirtQueue, VirtqDesc};
+    use vm_virtio::queue::{VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    #[test]
+    fn test_indirect_request() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        mem.write_obj(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+            .unwrap();
+        mem.write_obj(8u64, GuestAddress(0x1008)).unwrap();
+
+        // The header is followed by an indirect descriptor holding the data
+        // and status descriptors.
+        let indirect_table = [
+            VirtqDesc::new(GuestAddress(0x2000), &mem),
+            VirtqDesc::new(GuestAddress(0x2010), &mem),
+        ];
+        indirect_table[0].set(0x3000, 0x200, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        indirect_table[1].set(0x4000, 1, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable[0].set(0x1000, 16, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        let mut queue = vq.create_queue();
+        let head = queue.iter(&mem).next().unwrap();
+        let request = Request::parse(&head, &mem).unwrap();
+        assert_eq!(request.request_type, RequestType::In);
+        assert_eq!(request.sector, 8);
+        assert_eq!(
+            request.data_descriptors,
+            vec![(GuestAddress(0x3000), 0x200)]
+        );
+        assert_eq!(request.status_addr, GuestAddress(0x4000));
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(0));
+        histogram.record(Duration::from_micros(99));
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_millis(50));
+        histogram.record(Duration::from_secs(2));
+
+        let mut counters = HashMap::new();
+        histogram.export(&READ_LATENCY_COUNTERS, &mut counters);
+        assert_eq!(counters.len(), LATENCY_BUCKETS);
+        assert_eq!(counters["read_latency_us_lt_100"], Wrapping(2));
+        assert_eq!(counters["read_latency_us_lt_1000"], Wrapping(1));
+        assert_eq!(counters["read_latency_us_lt_10000"], Wrapping(0));
+        assert_eq!(counters["read_latency_us_lt_100000"], Wrapping(1));
+        assert_eq!(counters["read_latency_us_ge_100000"], Wrapping(1));
+    }
+
+    #[test]
+    fn test_set_topology() {
+        let mut config = VirtioBlockConfig::default();
+        set_topology(
+            &mut config,
+            &DiskTopology {
+                logical_block_size: 512,
+                physical_block_size: 4096,
+                minimum_io_size: 4096,
+                optimal_io_size: 1 << 20,
+            },
+        );
+        assert_eq!({ config.blk_size }, 512);
+        assert_eq!(config.physical_block_exp, 3);
+        assert_eq!({ config.min_io_size }, 8);
+        assert_eq!({ config.opt_io_size }, 2048);
+
+        let mut config = VirtioBlockConfig::default();
+        set_topology(
+            &mut config,
+            &DiskTopology {
+                logical_block_size: 4096,
+                physical_block_size: 512,
+                ..Default::default()
+            },
+        );
+        assert_eq!({ config.blk_size }, 512);
+        assert_eq!(config.physical_block_exp, 0);
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bounce buffers for the guests whose memory is encrypted.
+//!
+//! The VMM can't access the memory of a confidential guest, but for the
+//! regions the guest shares with it. Like swiotlb does, the guest copies its
+//! DMA buffers to and from a shared region, and the virtio devices are only
+//! given access to this region: their queues and the buffers they point to
+//! must lie within it, the descriptors pointing anywhere else being invalid.
+
+use crate::{GuestMemoryMmap, GuestRegionMmap};
+use std::sync::Arc;
+use vm_memory::{Error as MmapError, GuestAddress, GuestMemoryAtomic, GuestMemoryRegion};
+
+pub struct BounceBuffer {
+    region: Arc<GuestRegionMmap>,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+}
+
+impl BounceBuffer {
+    /// Creates the bounce buffers from the region reserved for them, which
+    /// must be shared with the host.
+    pub fn new(region: Arc<GuestRegionMmap>) -> Result<Self, MmapError> {
+        let memory = GuestMemoryMmap::from_arc_regions(vec![region.clone()])?;
+        Ok(BounceBuffer {
+            region,
+            memory: GuestMemoryAtomic::new(memory),
+        })
+    }
+
+    /// Gets the guest address and the size of the shared region.
+    pub fn range(&self) -> (GuestAddress, u64) {
+        (self.region.start_addr(), self.region.len())
+    }
+
+    /// Gets the only guest memory the virtio devices can access.
+    pub fn memory(&self) -> GuestMemoryAtomic<GuestMemoryMmap> {
+        self.memory.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MmapRegion;
+    use std::ops::Deref;
+    use vm_memory::{Bytes, GuestAddressSpace, GuestMemory};
+    use vm_virtio::queue::{testing::VirtQueue, VIRTQ_DESC_F_NEXT};
+
+    #[test]
+    fn test_bounce_buffer_memory() {
+        let region =
+            GuestRegionMmap::new(MmapRegion::new(0x10000).unwrap(), GuestAddress(0x100000))
+                .unwrap();
+        let bounce_buffer = BounceBuffer::new(Arc::new(region)).unwrap();
+        assert_eq!(bounce_buffer.range(), (GuestAddress(0x100000), 0x10000));
+
+        let memory = bounce_buffer.memory();
+        let mem = memory.memory();
+        assert!(mem.check_range(GuestAddress(0x100000), 0x10000));
+        assert!(!mem.check_range(GuestAddress(0x1000), 0x100));
+        assert!(mem.write_obj(0u64, GuestAddress(0x1000)).is_err());
+
+        // A chain pointing to the private memory is rejected.
+        let vq = VirtQueue::new(GuestAddress(0x100000), &mem, 16);
+        vq.dtable[0].set(0x108000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x1000, 0x100, 0, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        let mut queue = vq.create_queue();
+        let head = queue.iter(mem.deref()).next().unwrap();
+        assert_eq!(head.addr, GuestAddress(0x108000));
+        assert!(head.next_descriptor().is_none());
+    }
+}
@@ -5,7 +5,7 @@ use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
     VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
-    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::GuestMemoryMmap;
@@ -16,7 +16,7 @@ use std::cmp;
 use std::collections::VecDeque;
 use std::io;
 use std::io::Write;
-use std::ops::DerefMut;
+use std::ops::{Deref, DerefMut};
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
@@ -88,7 +88,7 @@ impl ConsoleEpollHandler {
         }
 
         let mem = self.mem.memory();
-        for avail_desc in recv_queue.iter(&mem) {
+        for avail_desc in recv_queue.iter(mem.deref()) {
             let len = cmp::min(avail_desc.len as u32, in_buffer.len() as u32);
             let source_slice = in_buffer.drain(..len as usize).collect::<Vec<u8>>();
             if let Err(e) = mem.write_slice(&source_slice[..], avail_desc.addr) {
@@ -106,7 +106,7 @@ impl ConsoleEpollHandler {
         }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
-            recv_queue.add_used(&mem, desc_index, len);
+            recv_queue.add_used(mem.deref(), desc_index, len);
         }
 
         used_count > 0
@@ -125,7 +125,7 @@ impl ConsoleEpollHandler {
         let mut used_count = 0;
 
         let mem = self.mem.memory();
-        for avail_desc in trans_queue.iter(&mem) {
+        for avail_desc in trans_queue.iter(mem.deref()) {
             let len;
             let mut out = self.out.lock().unwrap();
             let _ = mem.write_to(
@@ -141,7 +141,7 @@ impl ConsoleEpollHandler {
         }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
-            trans_queue.add_used(&mem, desc_index, len);
+            trans_queue.add_used(mem.deref(), desc_index, len);
         }
         used_count > 0
     }
@@ -301,7 +301,9 @@ impl Console {
         iommu: bool,
         seccomp_action: SeccompAction,
     ) -> io::Result<(Console, Arc<ConsoleInput>)> {
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_RING_INDIRECT_DESC
+            | 1u64 << VIRTIO_CONSOLE_F_SIZE;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -381,6 +383,10 @@ impl VirtioDevice for Console {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         self.read_config_from_slice(self.config.lock().unwrap().as_slice(), offset, data);
     }
@@ -426,13 +432,14 @@ impl VirtioDevice for Console {
         let virtio_console_seccomp_filter =
             get_seccomp_filter(&self.seccomp_action, Thread::VirtioConsole)
                 .map_err(ActivateError::CreateSeccompFilter)?;
+        let watch = self.common.watch_worker(&self.id);
         thread::Builder::new()
             .name(self.id.clone())
             .spawn(move || {
                 if let Err(e) = SeccompFilter::apply(virtio_console_seccomp_filter) {
-                    error!("Error applying seccomp filter: {:?}", e);
+                    watch.failed(format!("Error applying seccomp filter: {:?}", e));
                 } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                    error!("Error running worker: {:?}", e);
+                    watch.failed(format!("Error running worker: {:?}", e));
                 }
             })
             .map(|thread| epoll_threads.push(thread))
@@ -480,3 +487,83 @@ impl Snapshottable for Console {
 }
 impl Transportable for Console {}
 impl Migratable for Console {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::{VirtQueue, VirtqDesc};
+    use vm_virtio::queue::{VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_WRITE};
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_indirect_buffers() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x20000)]).unwrap();
+        let receiveq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let transmitq = VirtQueue::new(GuestAddress(0x8000), &mem, 16);
+
+        // Both buffers are posted through an indirect descriptor.
+        let receive_table = VirtqDesc::new(GuestAddress(0x10000), &mem);
+        receive_table.set(0x11000, 0x100, VIRTQ_DESC_F_WRITE, 0);
+        receiveq.dtable[0].set(0x10000, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        receiveq.avail.ring[0].set(0);
+        receiveq.avail.idx.set(1);
+
+        let transmit_table = VirtqDesc::new(GuestAddress(0x12000), &mem);
+        transmit_table.set(0x13000, 5, 0, 0);
+        mem.write_slice(b"hello", GuestAddress(0x13000)).unwrap();
+        transmitq.dtable[2].set(0x12000, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        transmitq.avail.ring[0].set(2);
+        transmitq.avail.idx.set(1);
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut handler = ConsoleEpollHandler {
+            queues: vec![receiveq.create_queue(), transmitq.create_queue()],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            in_buffer: Arc::new(Mutex::new(b"world".iter().copied().collect())),
+            out: Arc::new(Mutex::new(Box::new(SharedOutput(output.clone())))),
+            input_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            output_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            input_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            config_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        };
+
+        assert!(handler.process_input_queue());
+        let mut received = [0u8; 5];
+        mem.read_slice(&mut received, GuestAddress(0x11000))
+            .unwrap();
+        assert_eq!(&received, b"world");
+        assert_eq!(receiveq.used.ring[0].get().id, 0);
+        assert_eq!(receiveq.used.ring[0].get().len, 5);
+
+        assert!(handler.process_output_queue());
+        assert_eq!(&output.lock().unwrap()[..], b"hello");
+        assert_eq!(transmitq.used.ring[0].get().id, 2);
+    }
+}
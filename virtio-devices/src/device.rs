@@ -6,7 +6,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::{ActivateError, ActivateResult, Error, Queue};
+use crate::{
+    ActivateError, ActivateResult, Error, Queue, SharedEpollWorker, WorkerSupervisor, WorkerWatch,
+};
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 use libc::EFD_NONBLOCK;
 use std::collections::HashMap;
@@ -47,12 +49,21 @@ pub struct UserspaceMapping {
     pub mergeable: bool,
 }
 
+/// Shared memory region exposed by a device, as defined by the virtio
+/// specification.
 #[derive(Clone)]
 pub struct VirtioSharedMemory {
+    /// Device specific identifier of the region (shmid).
+    pub id: u8,
+    /// Offset of the region inside the shared memory window.
     pub offset: u64,
     pub len: u64,
 }
 
+/// Window of guest memory backing all shared memory regions of a device.
+///
+/// The transport exposes the whole window to the guest, and describes each
+/// region it contains.
 #[derive(Clone)]
 pub struct VirtioSharedMemoryList {
     pub host_addr: u64,
@@ -62,6 +73,25 @@ pub struct VirtioSharedMemoryList {
     pub region_list: Vec<VirtioSharedMemory>,
 }
 
+impl VirtioSharedMemoryList {
+    /// Returns the first region which does not fit inside the window or
+    /// which reuses the identifier of a previous region.
+    pub fn invalid_region(&self) -> Option<&VirtioSharedMemory> {
+        self.region_list.iter().enumerate().find_map(|(idx, shm)| {
+            let out_of_range = shm
+                .offset
+                .checked_add(shm.len)
+                .map_or(true, |end| end > self.len);
+            let duplicated = self.region_list[..idx].iter().any(|r| r.id == shm.id);
+            if out_of_range || duplicated {
+                Some(shm)
+            } else {
+                None
+            }
+        })
+    }
+}
+
 /// Trait for virtio devices to be driven by a virtio transport.
 ///
 /// The lifecycle of a virtio device is to be moved to a virtio transport, which will then query the
@@ -102,6 +132,12 @@ pub trait VirtioDevice: Send {
         );
     }
 
+    /// Returns the generation of the device configuration space, which must
+    /// change every time the device updates its configuration.
+    fn config_generation(&self) -> u8 {
+        0
+    }
+
     /// Activates this device for real usage.
     fn activate(
         &mut self,
@@ -157,6 +193,21 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Returns the state common to the virtio devices, for the operations
+    /// only depending on it to be implemented once by the trait.
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        None
+    }
+
+    /// Sets the supervisor the worker threads of the device report their
+    /// failures to. It must be set before the device is activated, and is
+    /// ignored by the devices without common state.
+    fn set_worker_supervisor(&mut self, supervisor: WorkerSupervisor) {
+        if let Some(common) = self.common_mut() {
+            common.worker_supervisor = Some(supervisor);
+        }
+    }
+
     /// Helper to allow common implementation of read_config
     fn read_config_from_slice(&self, config: &[u8], offset: u64, mut data: &mut [u8]) {
         let config_len = config.len() as u64;
@@ -222,6 +273,8 @@ pub struct VirtioCommon {
     pub paused: Arc<AtomicBool>,
     pub paused_sync: Option<Arc<Barrier>>,
     pub epoll_threads: Option<Vec<thread::JoinHandle<()>>>,
+    pub shared_epoll_workers: Option<Vec<SharedEpollWorker>>,
+    pub worker_supervisor: Option<WorkerSupervisor>,
     pub queue_sizes: Vec<u16>,
     pub device_type: u32,
     pub min_queues: u16,
@@ -317,10 +370,24 @@ impl VirtioCommon {
             }
         }
 
+        // The workers running on the shared event loop are waited for as
+        // well, so that none of them is still accessing the queues once the
+        // reset is acknowledged to the driver.
+        if let Some(workers) = self.shared_epoll_workers.take() {
+            for worker in workers.iter() {
+                worker.wait();
+            }
+        }
+
         // Return the interrupt
         Some(self.interrupt_cb.take().unwrap())
     }
 
+    /// Returns the watch to move into a worker thread of the device.
+    pub fn watch_worker(&self, device_id: &str) -> WorkerWatch {
+        WorkerWatch::new(self.worker_supervisor.clone(), device_id)
+    }
+
     pub fn dup_eventfds(&self) -> (EventFd, EventFd) {
         (
             self.kill_evt.as_ref().unwrap().try_clone().unwrap(),
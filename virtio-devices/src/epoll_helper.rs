@@ -8,10 +8,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::WorkerWatch;
+use seccomp::{SeccompAction, SeccompFilter};
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Condvar, Mutex};
 use std::thread;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -26,6 +29,20 @@ pub enum EpollHelperError {
     Ctl(std::io::Error),
     IoError(std::io::Error),
     Wait(std::io::Error),
+    CreateSeccompFilter(seccomp::SeccompError),
+    SpawnThread(std::io::Error),
+}
+
+/// Outcome of `EpollHelper::process_pending()`.
+#[derive(Debug, PartialEq)]
+pub enum EpollHelperStatus {
+    /// Events have been handled, the helper can be polled again.
+    Running,
+    /// A pause has been acknowledged, the helper must not be polled before
+    /// the device is resumed.
+    Paused,
+    /// The loop has been killed or the handler asked for it to stop.
+    Stopped,
 }
 
 pub const EPOLL_HELPER_EVENT_PAUSE: u16 = 0;
@@ -35,6 +52,14 @@ pub const EPOLL_HELPER_EVENT_LAST: u16 = 15;
 pub trait EpollHelperHandler {
     // Return true if execution of the loop should be stopped
     fn handle_event(&mut self, helper: &mut EpollHelper, event: &epoll::Event) -> bool;
+
+    // Called once the loop has been killed, before it returns. This is the
+    // place to wait for the requests still in flight, as the queues are
+    // released by the device right after the loop returns. An error makes
+    // the loop fail, as the queues may still be accessed.
+    fn quiesce(&mut self) -> std::result::Result<(), EpollHelperError> {
+        Ok(())
+    }
 }
 
 impl EpollHelper {
@@ -132,6 +157,7 @@ impl EpollHelper {
                 match ev_type {
                     EPOLL_HELPER_EVENT_KILL => {
                         debug!("KILL_EVENT received, stopping epoll loop");
+                        handler.quiesce()?;
                         return Ok(());
                     }
                     EPOLL_HELPER_EVENT_PAUSE => {
@@ -162,6 +188,62 @@ impl EpollHelper {
             }
         }
     }
+
+    /// Handles the events already pending on the helper without blocking.
+    ///
+    /// This is the building block for sharing one thread across several
+    /// helpers. Contrary to `run()`, a pause request doesn't park the calling
+    /// thread. The pause is acknowledged and `Paused` is returned, the caller
+    /// being responsible for calling `resumed()` before polling the helper
+    /// again.
+    pub fn process_pending(
+        &mut self,
+        paused_sync: &Barrier,
+        handler: &mut dyn EpollHelperHandler,
+    ) -> std::result::Result<EpollHelperStatus, EpollHelperError> {
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        let num_events = loop {
+            match epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[..]) {
+                Ok(res) => break res,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(EpollHelperError::Wait(e)),
+            }
+        };
+
+        for event in events.iter().take(num_events) {
+            let ev_type = event.data as u16;
+
+            match ev_type {
+                EPOLL_HELPER_EVENT_KILL => {
+                    debug!("KILL_EVENT received, stopping epoll loop");
+                    handler.quiesce()?;
+                    return Ok(EpollHelperStatus::Stopped);
+                }
+                EPOLL_HELPER_EVENT_PAUSE => {
+                    debug!("PAUSE_EVENT received, pausing epoll loop");
+                    // Events left unprocessed are level triggered, hence
+                    // they will be reported again once resumed.
+                    paused_sync.wait();
+                    return Ok(EpollHelperStatus::Paused);
+                }
+                _ => {
+                    if handler.handle_event(self, event) {
+                        return Ok(EpollHelperStatus::Stopped);
+                    }
+                }
+            }
+        }
+
+        Ok(EpollHelperStatus::Running)
+    }
+
+    /// Drains the pause event after the device has been resumed. Must be
+    /// called after `process_pending()` returned `Paused`.
+    pub fn resumed(&mut self) {
+        let _ = self.pause_evt.read();
+    }
 }
 
 impl AsRawFd for EpollHelper {
@@ -169,3 +251,349 @@ impl AsRawFd for EpollHelper {
         self.epoll_file.as_raw_fd()
     }
 }
+
+/// Handle on a worker running on a `SharedEpollLoop`.
+///
+/// Contrary to a worker running on its own thread, there is no thread to
+/// join, hence the device relies on this handle to know when the worker has
+/// stopped and won't access the queues anymore.
+#[derive(Clone, Default)]
+pub struct SharedEpollWorker(Arc<(Mutex<bool>, Condvar)>);
+
+impl SharedEpollWorker {
+    fn stopped(&self) {
+        let (lock, cvar) = &*self.0;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    /// Blocks until the worker has been removed from the loop.
+    pub fn wait(&self) {
+        let (lock, cvar) = &*self.0;
+        let mut stopped = lock.lock().unwrap();
+        while !*stopped {
+            stopped = cvar.wait(stopped).unwrap();
+        }
+    }
+}
+
+struct SharedEpollEntry {
+    id: String,
+    watch: WorkerWatch,
+    helper: EpollHelper,
+    handler: Box<dyn EpollHelperHandler + Send>,
+    paused: Arc<AtomicBool>,
+    paused_sync: Arc<Barrier>,
+    registered: bool,
+    worker: SharedEpollWorker,
+}
+
+impl Drop for SharedEpollEntry {
+    fn drop(&mut self) {
+        self.worker.stopped();
+    }
+}
+
+const SHARED_EPOLL_EVENT_KILL: u64 = 0;
+const SHARED_EPOLL_EVENT_ADD: u64 = 1;
+const SHARED_EPOLL_EVENT_FIRST_ENTRY: u64 = 2;
+// Interval at which paused entries are checked for resumption, as resuming
+// a device only unparks the threads it owns.
+const SHARED_EPOLL_RESUME_POLL_MS: i32 = 100;
+
+/// Event loop shared across several low traffic virtio devices.
+///
+/// Instead of spawning one thread per device, each device hands its
+/// `EpollHelper` and handler over to the loop. The epoll file descriptor of
+/// every helper is registered on the loop's own epoll file descriptor, and
+/// the events are processed by the helpers without blocking from the single
+/// thread running the loop.
+pub struct SharedEpollLoop {
+    kill_evt: EventFd,
+    add_evt: EventFd,
+    new_entries: Arc<Mutex<Vec<SharedEpollEntry>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SharedEpollLoop {
+    pub fn new(seccomp_action: &SeccompAction) -> std::result::Result<Self, EpollHelperError> {
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(EpollHelperError::IoError)?;
+        let add_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(EpollHelperError::IoError)?;
+        let new_entries = Arc::new(Mutex::new(Vec::new()));
+
+        let epoll_fd = epoll::create(true).map_err(EpollHelperError::CreateFd)?;
+        // Use 'File' to enforce closing on 'epoll_fd'
+        let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+        for (fd, token) in [
+            (kill_evt.as_raw_fd(), SHARED_EPOLL_EVENT_KILL),
+            (add_evt.as_raw_fd(), SHARED_EPOLL_EVENT_ADD),
+        ]
+        .iter()
+        {
+            epoll::ctl(
+                epoll_file.as_raw_fd(),
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, *token),
+            )
+            .map_err(EpollHelperError::Ctl)?;
+        }
+
+        let seccomp_filter = get_seccomp_filter(seccomp_action, Thread::VirtioSharedEventLoop)
+            .map_err(EpollHelperError::CreateSeccompFilter)?;
+        let loop_add_evt = add_evt.try_clone().map_err(EpollHelperError::IoError)?;
+        let loop_new_entries = new_entries.clone();
+        let thread = thread::Builder::new()
+            .name("virtio-shared".to_string())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                } else if let Err(e) = Self::run(epoll_file, loop_add_evt, loop_new_entries.clone())
+                {
+                    error!("Error running shared event loop: {:?}", e);
+                }
+                // Release the entries which never made it to the loop so
+                // that nobody waits for them.
+                loop_new_entries.lock().unwrap().clear();
+            })
+            .map_err(EpollHelperError::SpawnThread)?;
+
+        Ok(SharedEpollLoop {
+            kill_evt,
+            add_evt,
+            new_entries,
+            thread: Some(thread),
+        })
+    }
+
+    /// Hands the helper and the handler of a device over to the loop. This
+    /// replaces spawning a dedicated thread running `EpollHelper::run()`,
+    /// the returned handle replacing the thread handle.
+    pub fn add(
+        &self,
+        id: String,
+        watch: WorkerWatch,
+        helper: EpollHelper,
+        handler: Box<dyn EpollHelperHandler + Send>,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> std::result::Result<SharedEpollWorker, EpollHelperError> {
+        let worker = SharedEpollWorker::default();
+        self.new_entries.lock().unwrap().push(SharedEpollEntry {
+            id,
+            watch,
+            helper,
+            handler,
+            paused,
+            paused_sync,
+            registered: false,
+            worker: worker.clone(),
+        });
+        self.add_evt.write(1).map_err(EpollHelperError::IoError)?;
+        Ok(worker)
+    }
+
+    fn set_registered(
+        epoll_fd: RawFd,
+        entry: &mut SharedEpollEntry,
+        slot: usize,
+        registered: bool,
+    ) -> std::result::Result<(), EpollHelperError> {
+        let op = if registered {
+            epoll::ControlOptions::EPOLL_CTL_ADD
+        } else {
+            epoll::ControlOptions::EPOLL_CTL_DEL
+        };
+        epoll::ctl(
+            epoll_fd,
+            op,
+            entry.helper.as_raw_fd(),
+            epoll::Event::new(
+                epoll::Events::EPOLLIN,
+                SHARED_EPOLL_EVENT_FIRST_ENTRY + slot as u64,
+            ),
+        )
+        .map_err(EpollHelperError::Ctl)?;
+        entry.registered = registered;
+        Ok(())
+    }
+
+    fn run(
+        epoll_file: File,
+        add_evt: EventFd,
+        new_entries: Arc<Mutex<Vec<SharedEpollEntry>>>,
+    ) -> std::result::Result<(), EpollHelperError> {
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+        let epoll_fd = epoll_file.as_raw_fd();
+        let mut entries: Vec<Option<SharedEpollEntry>> = Vec::new();
+
+        loop {
+            // Entries which are not registered are waiting for their device
+            // to be resumed, hence the need for a timeout.
+            let timeout = if entries.iter().flatten().any(|e| !e.registered) {
+                SHARED_EPOLL_RESUME_POLL_MS
+            } else {
+                -1
+            };
+
+            let num_events = match epoll::wait(epoll_fd, timeout, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(EpollHelperError::Wait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    SHARED_EPOLL_EVENT_KILL => {
+                        debug!("KILL_EVENT received, stopping shared event loop");
+                        return Ok(());
+                    }
+                    SHARED_EPOLL_EVENT_ADD => {
+                        let _ = add_evt.read();
+                        for entry in new_entries.lock().unwrap().drain(..) {
+                            match entries.iter().position(Option::is_none) {
+                                Some(slot) => entries[slot] = Some(entry),
+                                None => entries.push(Some(entry)),
+                            }
+                        }
+                    }
+                    token => {
+                        let slot = (token - SHARED_EPOLL_EVENT_FIRST_ENTRY) as usize;
+                        let entry = match entries.get_mut(slot) {
+                            // Skip events left over from a removed entry.
+                            Some(Some(entry)) if entry.registered => entry,
+                            _ => continue,
+                        };
+
+                        let status = entry
+                            .helper
+                            .process_pending(&entry.paused_sync, entry.handler.as_mut());
+                        match status {
+                            Ok(EpollHelperStatus::Running) => {}
+                            Ok(EpollHelperStatus::Paused) => {
+                                Self::set_registered(epoll_fd, entry, slot, false)?;
+                            }
+                            Ok(EpollHelperStatus::Stopped) | Err(_) => {
+                                if let Err(e) = status {
+                                    entry.watch.failed(format!(
+                                        "Error running worker for {}: {:?}",
+                                        entry.id, e
+                                    ));
+                                }
+                                Self::set_registered(epoll_fd, entry, slot, false)?;
+                                entries[slot] = None;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Register the entries which are new or have been resumed. A new
+            // entry is not expected to start processing anything before its
+            // device has been resumed, which matters for the restore path.
+            for (slot, entry) in entries.iter_mut().enumerate() {
+                if let Some(entry) = entry {
+                    if !entry.registered && !entry.paused.load(Ordering::SeqCst) {
+                        entry.helper.resumed();
+                        Self::set_registered(epoll_fd, entry, slot, true)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SharedEpollLoop {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            if let Err(e) = thread.join() {
+                error!("Error joining thread: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const TEST_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+
+    struct TestHandler {
+        evt: EventFd,
+        sender: mpsc::Sender<()>,
+    }
+
+    impl EpollHelperHandler for TestHandler {
+        fn handle_event(&mut self, _helper: &mut EpollHelper, _event: &epoll::Event) -> bool {
+            let _ = self.evt.read();
+            self.sender.send(()).unwrap();
+            false
+        }
+
+        fn quiesce(&mut self) -> std::result::Result<(), EpollHelperError> {
+            self.sender.send(()).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shared_epoll_loop() {
+        let shared_loop = SharedEpollLoop::new(&SeccompAction::Allow).unwrap();
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let pause_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let paused = Arc::new(AtomicBool::new(false));
+        let paused_sync = Arc::new(Barrier::new(2));
+        let (sender, receiver) = mpsc::channel();
+
+        let mut helper = EpollHelper::new(&kill_evt, &pause_evt).unwrap();
+        helper.add_event(evt.as_raw_fd(), TEST_EVENT).unwrap();
+        let handler = TestHandler {
+            evt: evt.try_clone().unwrap(),
+            sender,
+        };
+        let worker = shared_loop
+            .add(
+                "test".to_string(),
+                WorkerWatch::new(None, "test"),
+                helper,
+                Box::new(handler),
+                paused.clone(),
+                paused_sync.clone(),
+            )
+            .unwrap();
+
+        evt.write(1).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Events are not handled while the device is paused.
+        paused.store(true, Ordering::SeqCst);
+        pause_evt.write(1).unwrap();
+        paused_sync.wait();
+        evt.write(1).unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+
+        paused.store(false, Ordering::SeqCst);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The handler is quiesced and dropped once the device has been
+        // killed, which the worker handle lets the device wait for.
+        kill_evt.write(1).unwrap();
+        worker.wait();
+        receiver.try_recv().unwrap();
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        ));
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Record and replay of the non-deterministic inputs of the devices, for a
+//! run of the guest to be reproduced from the same snapshot. The inputs are
+//! logged along with the time they were received at, counted while the VM
+//! is running. The inputs pushed to the guest are replayed at the same time,
+//! while those the guest pulls, such as random bytes, are replayed in the
+//! same order.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Each record starts with the time in nanoseconds, the kind of input and
+// the length of the data, all little endian.
+const RECORD_HEADER_SIZE: usize = 13;
+
+/// The kinds of inputs logged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputKind {
+    /// Random bytes read by the virtio-rng device.
+    Rng,
+    /// Bytes received by the serial port.
+    Serial,
+    /// Bytes received by the virtio-console device.
+    Console,
+}
+
+impl InputKind {
+    fn from_u8(kind: u8) -> io::Result<Self> {
+        match kind {
+            0 => Ok(InputKind::Rng),
+            1 => Ok(InputKind::Serial),
+            2 => Ok(InputKind::Console),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown input kind {}", kind),
+            )),
+        }
+    }
+}
+
+/// An input, along with the time it was received at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputRecord {
+    pub time: Duration,
+    pub kind: InputKind,
+    pub data: Vec<u8>,
+}
+
+impl InputRecord {
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header[0..8].copy_from_slice(&(self.time.as_nanos() as u64).to_le_bytes());
+        header[8] = self.kind as u8;
+        header[9..13].copy_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.write_all(&header)?;
+        out.write_all(&self.data)
+    }
+
+    // Returns None once the end of the log is reached.
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&header[0..8]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[9..13]);
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        input.read_exact(&mut data)?;
+
+        Ok(Some(InputRecord {
+            time: Duration::from_nanos(u64::from_le_bytes(time)),
+            kind: InputKind::from_u8(header[8])?,
+            data,
+        }))
+    }
+}
+
+enum Mode {
+    Record(BufWriter<File>),
+    Replay {
+        // Random bytes, replayed in order as the guest reads them.
+        rng: VecDeque<u8>,
+        // Inputs replayed at the time they were received at.
+        timed: VecDeque<InputRecord>,
+    },
+}
+
+struct State {
+    mode: Mode,
+    // Time the VM ran for before it was last resumed.
+    elapsed: Duration,
+    running_since: Option<Instant>,
+    stopped: bool,
+}
+
+impl State {
+    fn now(&self) -> Duration {
+        let running = self
+            .running_since
+            .map_or(Duration::default(), |t| t.elapsed());
+        self.elapsed + running
+    }
+}
+
+/// The inputs of a VM, either being recorded to a file or replayed from it.
+pub struct InputLog {
+    state: Mutex<State>,
+    // Wakes up the replay of the timed inputs when the clock is resumed or
+    // paused, or when the replay is stopped.
+    changed: Condvar,
+}
+
+impl InputLog {
+    fn new(mode: Mode, elapsed: Duration) -> Self {
+        InputLog {
+            state: Mutex::new(State {
+                mode,
+                elapsed,
+                running_since: None,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Records the inputs to `path`, appending them to the ones recorded
+    /// already, such as before the guest rebooted. The clock goes on from
+    /// the time of the last input recorded.
+    pub fn record(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut elapsed = Duration::default();
+        let mut input = BufReader::new(&mut file);
+        while let Some(record) = InputRecord::read_from(&mut input)? {
+            elapsed = record.time;
+        }
+
+        Ok(InputLog::new(Mode::Record(BufWriter::new(file)), elapsed))
+    }
+
+    /// Replays the inputs recorded to `path`.
+    pub fn replay(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut rng = VecDeque::new();
+        let mut timed = VecDeque::new();
+        while let Some(record) = InputRecord::read_from(&mut input)? {
+            match record.kind {
+                InputKind::Rng => rng.extend(record.data),
+                _ => timed.push_back(record),
+            }
+        }
+
+        Ok(InputLog::new(
+            Mode::Replay { rng, timed },
+            Duration::default(),
+        ))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.state.lock().unwrap().mode, Mode::Replay { .. })
+    }
+
+    /// Runs the clock the inputs are timed with, while the VM runs.
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.running_since.is_none() {
+            state.running_since = Some(Instant::now());
+            self.changed.notify_all();
+        }
+    }
+
+    /// Stops the clock the inputs are timed with, while the VM is paused.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(running_since) = state.running_since.take() {
+            state.elapsed += running_since.elapsed();
+            self.changed.notify_all();
+        }
+    }
+
+    /// Stops replaying the timed inputs, for the VM to be shut down.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+    }
+
+    /// Records an input received, unless replaying. A failure to record is
+    /// only reported, the input being handled anyway.
+    pub fn record_input(&self, kind: InputKind, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let time = state.now();
+        if let Mode::Record(out) = &mut state.mode {
+            let record = InputRecord {
+                time,
+                kind,
+                data: data.to_vec(),
+            };
+            if let Err(e) = record.write_to(out).and_then(|_| out.flush()) {
+                warn!("Failed recording {:?} input: {}", kind, e);
+            }
+        }
+    }
+
+    /// Fills `buf` with random bytes, read from `source` and recorded, or
+    /// replayed. Once the bytes recorded run out, they are read from
+    /// `source` again.
+    pub fn read_random(&self, source: &mut dyn Read, buf: &mut [u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Mode::Replay { rng, .. } = &mut state.mode {
+            let replayed = std::cmp::min(rng.len(), buf.len());
+            for (byte, replayed_byte) in buf.iter_mut().zip(rng.drain(..replayed)) {
+                *byte = replayed_byte;
+            }
+            if replayed < buf.len() {
+                warn!("No random bytes left to replay");
+                source.read_exact(&mut buf[replayed..])?;
+            }
+            return Ok(());
+        }
+        drop(state);
+
+        source.read_exact(buf)?;
+        self.record_input(InputKind::Rng, buf);
+
+        Ok(())
+    }
+
+    /// Waits for the next timed input to replay to be due, and returns it.
+    /// Returns None once all the inputs have been replayed, or when the
+    /// replay is stopped.
+    pub fn next_replayed_input(&self) -> Option<InputRecord> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return None;
+            }
+            let due = match &state.mode {
+                Mode::Replay { timed, .. } => timed.front()?.time,
+                Mode::Record(_) => return None,
+            };
+
+            let now = state.now();
+            if state.running_since.is_some() && due <= now {
+                break;
+            }
+            state = if state.running_since.is_some() {
+                self.changed.wait_timeout(state, due - now).unwrap().0
+            } else {
+                self.changed.wait(state).unwrap()
+            };
+        }
+
+        match &mut state.mode {
+            Mode::Replay { timed, .. } => timed.pop_front(),
+            Mode::Record(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_input_log() {
+        let file = TempFile::new().unwrap();
+        let mut source: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+        let input_log = InputLog::record(file.as_path()).unwrap();
+        assert!(!input_log.is_replaying());
+        input_log.resume();
+        let mut buf = [0u8; 3];
+        input_log.read_random(&mut source, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        input_log.record_input(InputKind::Serial, b"ls\r");
+        input_log.pause();
+        // The inputs received while paused are timed when the VM resumes.
+        input_log.record_input(InputKind::Console, b"q");
+        input_log.read_random(&mut source, &mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6]);
+        drop(input_log);
+
+        let input_log = InputLog::replay(file.as_path()).unwrap();
+        assert!(input_log.is_replaying());
+        let mut source: &[u8] = &[9, 10, 11, 12];
+        let mut buf = [0u8; 4];
+        input_log.read_random(&mut source, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        // Once the bytes recorded run out, the source is read.
+        input_log.read_random(&mut source, &mut buf).unwrap();
+        assert_eq!(buf, [5, 6, 9, 10]);
+
+        input_log.resume();
+        let serial = input_log.next_replayed_input().unwrap();
+        assert_eq!(serial.kind, InputKind::Serial);
+        assert_eq!(serial.data, b"ls\r");
+        let console = input_log.next_replayed_input().unwrap();
+        assert_eq!(console.kind, InputKind::Console);
+        assert_eq!(console.data, b"q");
+        assert!(console.time >= serial.time);
+        assert!(input_log.next_replayed_input().is_none());
+
+        let input_log = InputLog::replay(file.as_path()).unwrap();
+        input_log.stop();
+        assert!(input_log.next_replayed_input().is_none());
+    }
+
+    #[test]
+    fn test_input_log_append() {
+        let file = TempFile::new().unwrap();
+
+        let input_log = InputLog::record(file.as_path()).unwrap();
+        input_log.resume();
+        std::thread::sleep(Duration::from_millis(10));
+        input_log.record_input(InputKind::Serial, b"reboot\r");
+        drop(input_log);
+
+        // Recording again, as after a reboot, keeps the inputs recorded and
+        // goes on with the clock.
+        let input_log = InputLog::record(file.as_path()).unwrap();
+        input_log.record_input(InputKind::Serial, b"ls\r");
+        drop(input_log);
+
+        let input_log = InputLog::replay(file.as_path()).unwrap();
+        input_log.resume();
+        let before = input_log.next_replayed_input().unwrap();
+        assert_eq!(before.data, b"reboot\r");
+        assert!(before.time >= Duration::from_millis(10));
+        let after = input_log.next_replayed_input().unwrap();
+        assert_eq!(after.data, b"ls\r");
+        assert!(after.time >= before.time);
+        input_log.stop();
+    }
+}
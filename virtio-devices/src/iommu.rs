@@ -6,21 +6,22 @@ use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, DescriptorChain, EpollHelper, EpollHelperError,
     EpollHelperHandler, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType,
-    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::GuestMemoryMmap;
 use crate::{DmaRemapping, VirtioInterrupt, VirtioInterruptType};
 use seccomp::{SeccompAction, SeccompFilter};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Display};
 use std::io;
 use std::mem::size_of;
 use std::ops::Bound::Included;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex, RwLock};
 use std::thread;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -55,11 +56,15 @@ const EVENT_Q_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 /// will conflict with x86.
 const PROBE_PROP_SIZE: u32 =
     (size_of::<VirtioIommuProbeProperty>() + size_of::<VirtioIommuProbeResvMem>()) as u32;
+/// Maximum number of RESV_MEM properties reported for an endpoint, the MSI
+/// one included. The PROBE properties size advertised to the driver leaves
+/// room for all of them.
+const MAX_RESV_MEM_PROPS: u32 = 16;
+const PROBE_SIZE: u32 = PROBE_PROP_SIZE * MAX_RESV_MEM_PROPS;
 const MSI_IOVA_START: u64 = 0xfee0_0000;
 const MSI_IOVA_END: u64 = 0xfeef_ffff;
 
 /// Virtio IOMMU features
-#[allow(unused)]
 const VIRTIO_IOMMU_F_INPUT_RANGE: u32 = 0;
 #[allow(unused)]
 const VIRTIO_IOMMU_F_DOMAIN_RANGE: u32 = 1;
@@ -236,7 +241,6 @@ struct VirtioIommuProbeProperty {
 unsafe impl ByteValued for VirtioIommuProbeProperty {}
 
 /// Virtio IOMMU request PROBE property RESV_MEM subtypes
-#[allow(unused)]
 const VIRTIO_IOMMU_RESV_MEM_T_RESERVED: u8 = 0;
 const VIRTIO_IOMMU_RESV_MEM_T_MSI: u8 = 1;
 
@@ -508,21 +512,36 @@ impl Request {
                     .map_err(Error::GuestMemory)?;
                 debug!("Probe request {:?}", req);
 
-                let probe_prop = VirtioIommuProbeProperty {
-                    type_: VIRTIO_IOMMU_PROBE_T_RESV_MEM,
-                    length: size_of::<VirtioIommuProbeResvMem>() as u16,
-                };
-                reply.extend_from_slice(probe_prop.as_slice());
+                let endpoint = req.endpoint;
+                let reserved_regions = mapping.reserved_regions.read().unwrap();
+                let regions =
+                    std::iter::once((VIRTIO_IOMMU_RESV_MEM_T_MSI, MSI_IOVA_START, MSI_IOVA_END))
+                        .chain(
+                            reserved_regions.get(&endpoint).into_iter().flatten().map(
+                                |(start, end)| (VIRTIO_IOMMU_RESV_MEM_T_RESERVED, *start, *end),
+                            ),
+                        );
+                for (subtype, start, end) in regions {
+                    let probe_prop = VirtioIommuProbeProperty {
+                        type_: VIRTIO_IOMMU_PROBE_T_RESV_MEM,
+                        length: size_of::<VirtioIommuProbeResvMem>() as u16,
+                    };
+                    reply.extend_from_slice(probe_prop.as_slice());
+
+                    let resv_mem = VirtioIommuProbeResvMem {
+                        subtype,
+                        start,
+                        end,
+                        ..Default::default()
+                    };
+                    reply.extend_from_slice(resv_mem.as_slice());
+                }
 
-                let resv_mem = VirtioIommuProbeResvMem {
-                    subtype: VIRTIO_IOMMU_RESV_MEM_T_MSI,
-                    start: MSI_IOVA_START,
-                    end: MSI_IOVA_END,
-                    ..Default::default()
-                };
-                reply.extend_from_slice(resv_mem.as_slice());
+                // The properties are terminated by a NONE one, the driver
+                // expects the tail right after the PROBE properties size.
+                reply.resize(PROBE_SIZE as usize, 0);
 
-                PROBE_PROP_SIZE
+                PROBE_SIZE
             }
             _ => return Err(Error::InvalidRequest),
         };
@@ -561,7 +580,7 @@ struct IommuEpollHandler {
     kill_evt: EventFd,
     pause_evt: EventFd,
     mapping: Arc<IommuMapping>,
-    ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    ext_mapping: Arc<Mutex<BTreeMap<u32, Arc<dyn ExternalDmaMapping>>>>,
     ext_domain_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
 }
 
@@ -570,12 +589,12 @@ impl IommuEpollHandler {
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in self.queues[0].iter(&mem) {
+        for avail_desc in self.queues[0].iter(mem.deref()) {
             let len = match Request::parse(
                 &avail_desc,
                 &mem,
                 &self.mapping,
-                &self.ext_mapping,
+                &self.ext_mapping.lock().unwrap(),
                 &mut self.ext_domain_mapping,
             ) {
                 Ok(len) => len as u32,
@@ -590,7 +609,7 @@ impl IommuEpollHandler {
         }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queues[0].add_used(&mem, desc_index, len);
+            self.queues[0].add_used(mem.deref(), desc_index, len);
         }
         used_count > 0
     }
@@ -668,24 +687,65 @@ pub struct IommuMapping {
     endpoints: Arc<RwLock<BTreeMap<u32, u32>>>,
     // List of mappings per domain.
     mappings: Arc<RwLock<BTreeMap<u32, BTreeMap<u64, Mapping>>>>,
+    // Endpoints whose DMA bypasses the translation as long as they are not
+    // attached to any domain.
+    bypass_endpoints: RwLock<BTreeSet<u32>>,
+    // Whether the DMA of the other endpoints is blocked until they are
+    // attached to a domain. This is only the case once the driver is ready,
+    // so that the firmware can still rely on the devices to boot.
+    blocking: AtomicBool,
+    // IOVA ranges the host IOMMU reserves, per endpoint, on top of the MSI
+    // one every endpoint has.
+    reserved_regions: RwLock<BTreeMap<u32, Vec<(u64, u64)>>>,
+}
+
+impl IommuMapping {
+    /// Lets the DMA of the endpoint bypass the translation until the guest
+    /// attaches it to a domain.
+    pub fn add_bypass_endpoint(&self, id: u32) {
+        self.bypass_endpoints.write().unwrap().insert(id);
+    }
+
+    /// Forgets about the endpoint, when the device behind it is removed.
+    pub fn remove_endpoint(&self, id: u32) {
+        self.bypass_endpoints.write().unwrap().remove(&id);
+        self.endpoints.write().unwrap().remove(&id);
+        self.reserved_regions.write().unwrap().remove(&id);
+    }
 }
 
 impl DmaRemapping for IommuMapping {
     fn translate(&self, id: u32, addr: u64) -> std::result::Result<u64, std::io::Error> {
         debug!("Translate addr 0x{:x}", addr);
-        if let Some(domain) = self.endpoints.read().unwrap().get(&id) {
-            if let Some(mapping) = self.mappings.read().unwrap().get(domain) {
-                let range_start = if VIRTIO_IOMMU_PAGE_SIZE_MASK > addr {
-                    0
-                } else {
-                    addr - VIRTIO_IOMMU_PAGE_SIZE_MASK
-                };
-                for (&key, &value) in mapping.range((Included(&range_start), Included(&addr))) {
-                    if addr >= key && addr < key + value.size {
-                        let new_addr = addr - key + value.gpa;
-                        debug!("Into new_addr 0x{:x}", new_addr);
-                        return Ok(new_addr);
-                    }
+        let endpoints = self.endpoints.read().unwrap();
+        let domain = match endpoints.get(&id) {
+            Some(domain) => domain,
+            None => {
+                if self.blocking.load(Ordering::Acquire)
+                    && !self.bypass_endpoints.read().unwrap().contains(&id)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("endpoint {} isn't attached to any domain", id),
+                    ));
+                }
+
+                debug!("Into same addr...");
+                return Ok(addr);
+            }
+        };
+
+        if let Some(mapping) = self.mappings.read().unwrap().get(domain) {
+            let range_start = if VIRTIO_IOMMU_PAGE_SIZE_MASK > addr {
+                0
+            } else {
+                addr - VIRTIO_IOMMU_PAGE_SIZE_MASK
+            };
+            for (&key, &value) in mapping.range((Included(&range_start), Included(&addr))) {
+                if addr >= key && addr < key + value.size {
+                    let new_addr = addr - key + value.gpa;
+                    debug!("Into new_addr 0x{:x}", new_addr);
+                    return Ok(new_addr);
                 }
             }
         }
@@ -700,7 +760,7 @@ pub struct Iommu {
     id: String,
     config: VirtioIommuConfig,
     mapping: Arc<IommuMapping>,
-    ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    ext_mapping: Arc<Mutex<BTreeMap<u32, Arc<dyn ExternalDmaMapping>>>>,
     seccomp_action: SeccompAction,
 }
 
@@ -718,13 +778,16 @@ impl Iommu {
     pub fn new(id: String, seccomp_action: SeccompAction) -> io::Result<(Self, Arc<IommuMapping>)> {
         let config = VirtioIommuConfig {
             page_size_mask: VIRTIO_IOMMU_PAGE_SIZE_MASK,
-            probe_size: PROBE_PROP_SIZE,
+            probe_size: PROBE_SIZE,
             ..Default::default()
         };
 
         let mapping = Arc::new(IommuMapping {
             endpoints: Arc::new(RwLock::new(BTreeMap::new())),
             mappings: Arc::new(RwLock::new(BTreeMap::new())),
+            bypass_endpoints: RwLock::new(BTreeSet::new()),
+            blocking: AtomicBool::new(false),
+            reserved_regions: RwLock::new(BTreeMap::new()),
         });
 
         Ok((
@@ -734,6 +797,7 @@ impl Iommu {
                     device_type: VirtioDeviceType::Iommu as u32,
                     queue_sizes: QUEUE_SIZES.to_vec(),
                     avail_features: 1u64 << VIRTIO_F_VERSION_1
+                        | 1u64 << VIRTIO_F_RING_INDIRECT_DESC
                         | 1u64 << VIRTIO_IOMMU_F_MAP_UNMAP
                         | 1u64 << VIRTIO_IOMMU_F_PROBE,
                     paused_sync: Some(Arc::new(Barrier::new(2))),
@@ -741,7 +805,7 @@ impl Iommu {
                 },
                 config,
                 mapping: mapping.clone(),
-                ext_mapping: BTreeMap::new(),
+                ext_mapping: Arc::new(Mutex::new(BTreeMap::new())),
                 seccomp_action,
             },
             mapping,
@@ -785,10 +849,92 @@ impl Iommu {
     }
 
     pub fn add_external_mapping(&mut self, device_id: u32, mapping: Arc<dyn ExternalDmaMapping>) {
-        self.ext_mapping.insert(device_id, mapping);
+        self.ext_mapping.lock().unwrap().insert(device_id, mapping);
+    }
+
+    pub fn remove_external_mapping(&mut self, device_id: u32) {
+        self.ext_mapping.lock().unwrap().remove(&device_id);
+    }
+
+    /// Restricts the endpoint to the IOVA ranges, bounds included, the host
+    /// IOMMU lets the device behind it use. The holes between the ranges are
+    /// reported to the driver as reserved regions of the endpoint, and the
+    /// input range advertised to the driver ends with the lowest last IOVA
+    /// of all the endpoints. The input range is only taken into account if
+    /// the device is restricted before the driver is initialized.
+    pub fn add_host_iova_ranges(&mut self, device_id: u32, ranges: &[(u64, u64)]) {
+        let mut ranges = ranges.to_vec();
+        ranges.sort_unstable();
+        let last = match ranges.last() {
+            Some((_, end)) => *end,
+            None => return,
+        };
+
+        // The MSI reserved region takes one of the properties.
+        let max_regions = MAX_RESV_MEM_PROPS as usize - 1;
+        let regions = reserved_regions(&ranges);
+        if regions.len() > max_regions {
+            warn!(
+                "Only reporting {} of the {} reserved regions of endpoint {:x}",
+                max_regions,
+                regions.len(),
+                device_id
+            );
+        }
+        self.mapping
+            .reserved_regions
+            .write()
+            .unwrap()
+            .insert(device_id, regions.into_iter().take(max_regions).collect());
+
+        // The features and the configuration the driver already read can't
+        // change anymore.
+        if self.common.acked_features != 0 {
+            if last < { self.config.input_range.end }
+                || !self.common.feature_acked(VIRTIO_IOMMU_F_INPUT_RANGE.into())
+            {
+                warn!(
+                    "Endpoint {:x} restricted to IOVAs up to {:#x} after the driver initialized the device",
+                    device_id, last
+                );
+            }
+            return;
+        }
+
+        let input_range_end =
+            if self.common.avail_features & (1u64 << VIRTIO_IOMMU_F_INPUT_RANGE) != 0 {
+                std::cmp::min(self.config.input_range.end, last)
+            } else {
+                last
+            };
+        self.config.input_range = VirtioIommuRange64 {
+            start: 0,
+            end: input_range_end,
+        };
+        self.common.avail_features |= 1u64 << VIRTIO_IOMMU_F_INPUT_RANGE;
     }
 }
 
+// Gets the holes between the sorted IOVA ranges, besides the ones the MSI
+// reserved region already covers.
+fn reserved_regions(ranges: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut regions = Vec::new();
+    let mut next = 0;
+    for (start, end) in ranges {
+        if *start > next {
+            let hole = (next, *start - 1);
+            if hole.0 < MSI_IOVA_START || hole.1 > MSI_IOVA_END {
+                regions.push(hole);
+            }
+        }
+        match end.checked_add(1) {
+            Some(end) => next = std::cmp::max(next, end),
+            None => break,
+        }
+    }
+    regions
+}
+
 impl Drop for Iommu {
     fn drop(&mut self) {
         if let Some(kill_evt) = self.common.kill_evt.take() {
@@ -815,6 +961,10 @@ impl VirtioDevice for Iommu {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         self.read_config_from_slice(self.config.as_slice(), offset, data);
     }
@@ -828,6 +978,13 @@ impl VirtioDevice for Iommu {
     ) -> ActivateResult {
         self.common.activate(&queues, &queue_evts, &interrupt_cb)?;
         let (kill_evt, pause_evt) = self.common.dup_eventfds();
+
+        // The driver negotiated the features without VIRTIO_IOMMU_F_BYPASS,
+        // hence the endpoints which are not attached can't access the guest
+        // memory anymore, unless they are explicitly allowed to bypass the
+        // translation.
+        self.mapping.blocking.store(true, Ordering::Release);
+
         let mut handler = IommuEpollHandler {
             queues,
             mem,
@@ -847,13 +1004,14 @@ impl VirtioDevice for Iommu {
         let virtio_iommu_seccomp_filter =
             get_seccomp_filter(&self.seccomp_action, Thread::VirtioIommu)
                 .map_err(ActivateError::CreateSeccompFilter)?;
+        let watch = self.common.watch_worker(&self.id);
         thread::Builder::new()
             .name(self.id.clone())
             .spawn(move || {
                 if let Err(e) = SeccompFilter::apply(virtio_iommu_seccomp_filter) {
-                    error!("Error applying seccomp filter: {:?}", e);
+                    watch.failed(format!("Error applying seccomp filter: {:?}", e));
                 } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                    error!("Error running worker: {:?}", e);
+                    watch.failed(format!("Error running worker: {:?}", e));
                 }
             })
             .map(|thread| epoll_threads.push(thread))
@@ -870,6 +1028,7 @@ impl VirtioDevice for Iommu {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        self.mapping.blocking.store(false, Ordering::Release);
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
@@ -901,3 +1060,72 @@ impl Snapshottable for Iommu {
 }
 impl Transportable for Iommu {}
 impl Migratable for Iommu {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_unattached_endpoints() {
+        let (_iommu, mapping) = Iommu::new("_iommu".to_string(), SeccompAction::Allow).unwrap();
+
+        // The firmware relies on the devices before the driver is ready.
+        assert_eq!(mapping.translate(0x8, 0x1000).unwrap(), 0x1000);
+
+        mapping.blocking.store(true, Ordering::Release);
+        assert!(mapping.translate(0x8, 0x1000).is_err());
+
+        mapping.add_bypass_endpoint(0x8);
+        assert_eq!(mapping.translate(0x8, 0x1000).unwrap(), 0x1000);
+        mapping.remove_endpoint(0x8);
+        assert!(mapping.translate(0x8, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_host_iova_ranges() {
+        let (mut iommu, mapping) = Iommu::new("_iommu".to_string(), SeccompAction::Allow).unwrap();
+        assert_eq!(iommu.features() & (1u64 << VIRTIO_IOMMU_F_INPUT_RANGE), 0);
+
+        // The MSI hole is already reported to the driver.
+        iommu.add_host_iova_ranges(0x8, &[(0xfef0_0000, 0xffff_ffff_ffff), (0, 0xfedf_ffff)]);
+        assert!(mapping.reserved_regions.read().unwrap()[&0x8].is_empty());
+        assert_ne!(iommu.features() & (1u64 << VIRTIO_IOMMU_F_INPUT_RANGE), 0);
+        assert_eq!({ iommu.config.input_range.end }, 0xffff_ffff_ffff);
+
+        iommu.add_host_iova_ranges(
+            0x10,
+            &[
+                (0, 0x7a3f_ffff),
+                (0x7a80_0000, 0xfedf_ffff),
+                (0xfef0_0000, 0x7f_ffff_ffff),
+            ],
+        );
+        assert_eq!(
+            mapping.reserved_regions.read().unwrap()[&0x10],
+            vec![(0x7a40_0000, 0x7a7f_ffff)]
+        );
+        assert_eq!({ iommu.config.input_range.end }, 0x7f_ffff_ffff);
+
+        // The driver already read the input range.
+        iommu.ack_features(iommu.features());
+        iommu.add_host_iova_ranges(0x18, &[(0, 0x3f_ffff_ffff)]);
+        assert_eq!({ iommu.config.input_range.end }, 0x7f_ffff_ffff);
+
+        mapping.remove_endpoint(0x10);
+        assert!(!mapping.reserved_regions.read().unwrap().contains_key(&0x10));
+
+        // Once attached, the DMA of a bypass endpoint is translated.
+        mapping.add_bypass_endpoint(0x10);
+        mapping.endpoints.write().unwrap().insert(0x10, 1);
+        let mut domain_mappings = BTreeMap::new();
+        domain_mappings.insert(
+            0x1000,
+            Mapping {
+                gpa: 0x8000,
+                size: 0x1000,
+            },
+        );
+        mapping.mappings.write().unwrap().insert(1, domain_mappings);
+        assert_eq!(mapping.translate(0x10, 0x1800).unwrap(), 0x8800);
+    }
+}
@@ -24,14 +24,17 @@ use std::io;
 mod device;
 pub mod balloon;
 pub mod block;
+pub mod bounce_buffer;
 mod console;
 pub mod epoll_helper;
+pub mod input_log;
 mod iommu;
 pub mod mem;
 pub mod net;
 mod pmem;
 mod rng;
 pub mod seccomp_filters;
+pub mod supervisor;
 pub mod transport;
 pub mod vhost_user;
 pub mod vsock;
@@ -39,6 +42,7 @@ pub mod watchdog;
 
 pub use self::balloon::*;
 pub use self::block::*;
+pub use self::bounce_buffer::*;
 pub use self::console::*;
 pub use self::device::*;
 pub use self::epoll_helper::*;
@@ -47,6 +51,7 @@ pub use self::mem::*;
 pub use self::net::*;
 pub use self::pmem::*;
 pub use self::rng::*;
+pub use self::supervisor::*;
 pub use self::vsock::*;
 pub use self::watchdog::*;
 use vm_memory::{bitmap::AtomicBitmap, GuestAddress, GuestMemory};
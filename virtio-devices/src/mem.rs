@@ -16,7 +16,7 @@ use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, DescriptorChain, EpollHelper, EpollHelperError,
     EpollHelperHandler, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType,
-    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{GuestMemoryMmap, GuestRegionMmap};
@@ -27,6 +27,7 @@ use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::BTreeMap;
 use std::io;
 use std::mem::size_of;
+use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
@@ -623,7 +624,7 @@ impl MemEpollHandler {
         let mut request_list = Vec::new();
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in self.queue.iter(&mem) {
+        for avail_desc in self.queue.iter(mem.deref()) {
             request_list.push((avail_desc.index, Request::parse(&avail_desc, &mem)));
         }
 
@@ -660,7 +661,7 @@ impl MemEpollHandler {
                 },
             };
 
-            self.queue.add_used(&mem, *desc_index, len);
+            self.queue.add_used(mem.deref(), *desc_index, len);
 
             used_count += 1;
         }
@@ -771,7 +772,7 @@ impl Mem {
             ));
         }
 
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_INDIRECT_DESC;
 
         let mut config = VirtioMemConfig {
             block_size: VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
@@ -915,6 +916,10 @@ impl VirtioDevice for Mem {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         self.read_config_from_slice(self.config.lock().unwrap().as_slice(), offset, data);
     }
@@ -958,13 +963,14 @@ impl VirtioDevice for Mem {
         // Retrieve seccomp filter for virtio_mem thread
         let virtio_mem_seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::VirtioMem)
             .map_err(ActivateError::CreateSeccompFilter)?;
+        let watch = self.common.watch_worker(&self.id);
         thread::Builder::new()
             .name(self.id.clone())
             .spawn(move || {
                 if let Err(e) = SeccompFilter::apply(virtio_mem_seccomp_filter) {
-                    error!("Error applying seccomp filter: {:?}", e);
+                    watch.failed(format!("Error applying seccomp filter: {:?}", e));
                 } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                    error!("Error running worker: {:?}", e);
+                    watch.failed(format!("Error running worker: {:?}", e));
                 }
             })
             .map(|thread| epoll_threads.push(thread))
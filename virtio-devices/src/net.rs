@@ -16,13 +16,14 @@ use crate::GuestMemoryMmap;
 use crate::VirtioInterrupt;
 use net_util::CtrlQueue;
 use net_util::{
-    build_net_config_space, build_net_config_space_with_mq, open_tap,
-    virtio_features_to_tap_offload, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio,
-    Tap, TapError, TxVirtio, VirtioNetConfig,
+    build_net_config_space, build_net_config_space_with_mq, drop_tap_frames, open_tap,
+    virtio_features_to_tap_offload, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxFilter,
+    RxVirtio, Tap, TapError, TxVirtio, VirtioNetConfig,
 };
 use seccomp::{SeccompAction, SeccompFilter};
 use std::net::Ipv4Addr;
 use std::num::Wrapping;
+use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -33,16 +34,22 @@ use std::{collections::HashMap, convert::TryInto};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_bindings::bindings::virtio_net::*;
-use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic};
+use virtio_bindings::bindings::virtio_ring::{
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
+use vm_memory::{GuestAddressSpace, GuestMemoryAtomic};
 use vm_migration::VersionMapped;
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vm_virtio::VirtioConfig;
 use vmm_sys_util::eventfd::EventFd;
 
 /// Control queue
 // Event available on the control queue.
 const CTRL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
+// The device stands by for an assigned device sharing its MAC address.
+const VIRTIO_NET_F_STANDBY: u64 = 62;
+
 pub struct NetCtrlEpollHandler {
     pub mem: GuestMemoryAtomic<GuestMemoryMmap>,
     pub kill_evt: EventFd,
@@ -115,6 +122,15 @@ pub enum Error {
 
     // Error calling dup() on tap fd
     DuplicateTapFd(std::io::Error),
+
+    /// Failed to attach the RX filter to the taps.
+    SetRxFilter(TapError),
+
+    /// Failed to notify the driver of the link state change.
+    LinkStateNotification(std::io::Error),
+
+    /// Failed to drop the frames queued while the link was down.
+    DropFrames(std::io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -234,7 +250,7 @@ impl NetEpollHandler {
         // If there are some already available descriptors on the RX queue,
         // then we can start the thread while listening onto the TAP.
         if self.queue_pair[0]
-            .available_descriptors(&self.net.mem.as_ref().unwrap().memory())
+            .available_descriptors(self.net.mem.as_ref().unwrap().memory().deref())
             .unwrap()
         {
             helper.add_event(self.net.tap.as_raw_fd(), RX_TAP_EVENT)?;
@@ -349,11 +365,14 @@ pub struct Net {
     common: VirtioCommon,
     id: String,
     taps: Vec<Tap>,
-    config: VirtioNetConfig,
+    config: VirtioConfig<VirtioNetConfig>,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     counters: NetCounters,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
+    // Mirrors the link state reported through the configuration space, for
+    // the queue pairs to drop the frames while the link is down.
+    link_up: Arc<AtomicBool>,
 }
 
 #[derive(Versionize)]
@@ -373,6 +392,8 @@ impl Net {
         id: String,
         taps: Vec<Tap>,
         guest_mac: Option<MacAddr>,
+        mtu: Option<u16>,
+        standby: bool,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
@@ -390,7 +411,9 @@ impl Net {
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_TSO6
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_RING_F_INDIRECT_DESC
             | 1 << VIRTIO_F_VERSION_1;
 
         if iommu {
@@ -407,6 +430,23 @@ impl Net {
             build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
         }
 
+        // Advertising the MTU lets the driver size its receive buffers
+        // accordingly, as frames larger than the buffers get dropped.
+        if let Some(mtu) = mtu {
+            config.mtu = mtu;
+            avail_features |= 1 << VIRTIO_NET_F_MTU;
+        }
+
+        // The guest pairs the device with the one sharing its MAC address,
+        // using the latter while it's there.
+        if standby {
+            avail_features |= 1 << VIRTIO_NET_F_STANDBY;
+        }
+
+        // The link state can be changed through the API.
+        config.status = VIRTIO_NET_S_LINK_UP as u16;
+        avail_features |= 1 << VIRTIO_NET_F_STATUS;
+
         Ok(Net {
             common: VirtioCommon {
                 device_type: VirtioDeviceType::Net as u32,
@@ -418,11 +458,12 @@ impl Net {
             },
             id,
             taps,
-            config,
+            config: VirtioConfig::new(config),
             ctrl_queue_epoll_thread: None,
             counters: NetCounters::default(),
             seccomp_action,
             rate_limiter_config,
+            link_up: Arc::new(AtomicBool::new(true)),
         })
     }
 
@@ -436,19 +477,31 @@ impl Net {
         netmask: Option<Ipv4Addr>,
         guest_mac: Option<MacAddr>,
         host_mac: &mut Option<MacAddr>,
+        mtu: Option<u16>,
+        standby: bool,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
     ) -> Result<Self> {
-        let taps = open_tap(if_name, ip_addr, netmask, host_mac, num_queues / 2, None)
-            .map_err(Error::OpenTap)?;
+        let taps = open_tap(
+            if_name,
+            ip_addr,
+            netmask,
+            host_mac,
+            mtu,
+            num_queues / 2,
+            None,
+        )
+        .map_err(Error::OpenTap)?;
 
         Self::new_with_tap(
             id,
             taps,
             guest_mac,
+            mtu,
+            standby,
             iommu,
             num_queues,
             queue_size,
@@ -457,10 +510,16 @@ impl Net {
         )
     }
 
+    /// Create a new virtio network device from already opened TAP file
+    /// descriptors. The MTU of such TAP interfaces is expected to be set
+    /// by the caller, it is only advertised to the guest.
+    #[allow(clippy::too_many_arguments)]
     pub fn from_tap_fds(
         id: String,
         fds: &[RawFd],
         guest_mac: Option<MacAddr>,
+        mtu: Option<u16>,
+        standby: bool,
         iommu: bool,
         queue_size: u16,
         seccomp_action: SeccompAction,
@@ -483,6 +542,8 @@ impl Net {
             id,
             taps,
             guest_mac,
+            mtu,
+            standby,
             iommu,
             num_queue_pairs * 2,
             queue_size,
@@ -491,11 +552,53 @@ impl Net {
         )
     }
 
+    /// Drops the frames received by the taps that `filter` rejects, before
+    /// they reach the guest.
+    pub fn set_rx_filter(&self, filter: &RxFilter) -> Result<()> {
+        for tap in self.taps.iter() {
+            tap.set_rx_filter(filter).map_err(Error::SetRxFilter)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the link up or down, notifying the driver if the device is
+    /// active. The frames are dropped both ways while the link is down,
+    /// including the ones still queued on the taps when it's brought up.
+    pub fn set_link_up(&mut self, up: bool) -> Result<()> {
+        if up && !self.link_up.load(Ordering::Acquire) {
+            for tap in self.taps.iter() {
+                let dropped = drop_tap_frames(tap.as_raw_fd()).map_err(Error::DropFrames)?;
+                self.counters
+                    .rx_dropped
+                    .fetch_add(dropped, Ordering::AcqRel);
+            }
+        }
+
+        let status = if up { VIRTIO_NET_S_LINK_UP as u16 } else { 0 };
+        let changed = self.config.update(|config| config.status = status);
+        self.link_up.store(up, Ordering::Release);
+
+        if changed {
+            if let Some(interrupt_cb) = &self.common.interrupt_cb {
+                interrupt_cb
+                    .trigger(&VirtioInterruptType::Config, None)
+                    .map_err(Error::LinkStateNotification)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::Acquire)
+    }
+
     fn state(&self) -> NetState {
         NetState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
-            config: self.config,
+            config: *self.config.get(),
             queue_size: self.common.queue_sizes.clone(),
         }
     }
@@ -503,8 +606,11 @@ impl Net {
     fn set_state(&mut self, state: &NetState) {
         self.common.avail_features = state.avail_features;
         self.common.acked_features = state.acked_features;
-        self.config = state.config;
+        self.config = VirtioConfig::new(state.config);
         self.common.queue_sizes = state.queue_size.clone();
+        let status = state.config.status;
+        self.link_up
+            .store(status & VIRTIO_NET_S_LINK_UP as u16 != 0, Ordering::Release);
     }
 }
 
@@ -534,8 +640,18 @@ impl VirtioDevice for Net {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        if let Err(e) = self.config.read(offset, data) {
+            error!("Failed reading net configuration: {}", e);
+        }
+    }
+
+    fn config_generation(&self) -> u8 {
+        self.config.generation()
     }
 
     fn activate(
@@ -573,13 +689,14 @@ impl VirtioDevice for Net {
             let virtio_net_ctl_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioNetCtl)
                     .map_err(ActivateError::CreateSeccompFilter)?;
+            let watch = self.common.watch_worker(&self.id);
             thread::Builder::new()
                 .name(format!("{}_ctrl", self.id))
                 .spawn(move || {
                     if let Err(e) = SeccompFilter::apply(virtio_net_ctl_seccomp_filter) {
-                        error!("Error applying seccomp filter: {:?}", e);
+                        watch.failed(format!("Error applying seccomp filter: {:?}", e));
                     } else if let Err(e) = ctrl_handler.run_ctrl(paused, paused_sync.unwrap()) {
-                        error!("Error running worker: {:?}", e);
+                        watch.failed(format!("Error running worker: {:?}", e));
                     }
                 })
                 .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
@@ -590,11 +707,13 @@ impl VirtioDevice for Net {
         }
 
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
+        let mergeable = self.common.feature_acked(VIRTIO_NET_F_MRG_RXBUF.into());
 
         let mut epoll_threads = Vec::new();
         let mut taps = self.taps.clone();
         for i in 0..queues.len() / 2 {
-            let rx = RxVirtio::new();
+            let mut rx = RxVirtio::new();
+            rx.mergeable = mergeable;
             let tx = TxVirtio::new();
             let rx_tap_listening = false;
 
@@ -641,6 +760,8 @@ impl VirtioDevice for Net {
                     rx_desc_avail: false,
                     rx_rate_limiter,
                     tx_rate_limiter,
+                    link_up: self.link_up.clone(),
+                    link_was_up: true,
                 },
                 queue_pair,
                 queue_evt_pair,
@@ -656,13 +777,14 @@ impl VirtioDevice for Net {
             let virtio_net_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioNet)
                     .map_err(ActivateError::CreateSeccompFilter)?;
+            let watch = self.common.watch_worker(&self.id);
             thread::Builder::new()
                 .name(format!("{}_qp{}", self.id.clone(), i))
                 .spawn(move || {
                     if let Err(e) = SeccompFilter::apply(virtio_net_seccomp_filter) {
-                        error!("Error applying seccomp filter: {:?}", e);
+                        watch.failed(format!("Error applying seccomp filter: {:?}", e));
                     } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                        error!("Error running worker: {:?}", e);
+                        watch.failed(format!("Error running worker: {:?}", e));
                     }
                 })
                 .map(|thread| epoll_threads.push(thread))
@@ -703,6 +825,14 @@ impl VirtioDevice for Net {
             "tx_frames",
             Wrapping(self.counters.tx_frames.load(Ordering::Acquire)),
         );
+        counters.insert(
+            "rx_dropped",
+            Wrapping(self.counters.rx_dropped.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "tx_dropped",
+            Wrapping(self.counters.tx_dropped.load(Ordering::Acquire)),
+        );
 
         Some(counters)
     }
@@ -10,7 +10,8 @@ use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, DescriptorChain, EpollHelper, EpollHelperError,
     EpollHelperHandler, Queue, UserspaceMapping, VirtioCommon, VirtioDevice, VirtioDeviceType,
-    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+    EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_INDIRECT_DESC,
+    VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{GuestMemoryMmap, MmapRegion};
@@ -20,6 +21,7 @@ use std::fmt::{self, Display};
 use std::fs::File;
 use std::io;
 use std::mem::size_of;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
@@ -170,7 +172,7 @@ impl PmemEpollHandler {
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in self.queue.iter(&mem) {
+        for avail_desc in self.queue.iter(mem.deref()) {
             let len = match Request::parse(&avail_desc, &mem) {
                 Ok(ref req) if (req.type_ == RequestType::Flush) => {
                     let status_code = match self.disk.sync_all() {
@@ -206,7 +208,7 @@ impl PmemEpollHandler {
         }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queue.add_used(&mem, desc_index, len);
+            self.queue.add_used(mem.deref(), desc_index, len);
         }
         used_count > 0
     }
@@ -294,7 +296,7 @@ impl Pmem {
             size: (_region.size() as u64).to_le(),
         };
 
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_INDIRECT_DESC;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -359,6 +361,10 @@ impl VirtioDevice for Pmem {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         self.read_config_from_slice(self.config.as_slice(), offset, data);
     }
@@ -394,13 +400,14 @@ impl VirtioDevice for Pmem {
             let virtio_pmem_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioPmem)
                     .map_err(ActivateError::CreateSeccompFilter)?;
+            let watch = self.common.watch_worker(&self.id);
             thread::Builder::new()
                 .name(self.id.clone())
                 .spawn(move || {
                     if let Err(e) = SeccompFilter::apply(virtio_pmem_seccomp_filter) {
-                        error!("Error applying seccomp filter: {:?}", e);
+                        watch.failed(format!("Error applying seccomp filter: {:?}", e));
                     } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                        error!("Error running worker: {:?}", e);
+                        watch.failed(format!("Error running worker: {:?}", e));
                     }
                 })
                 .map(|thread| epoll_threads.push(thread))
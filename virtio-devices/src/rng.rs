@@ -5,15 +5,17 @@
 use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
-    VIRTIO_F_VERSION_1,
+    SharedEpollLoop, VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
+use crate::input_log::InputLog;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::GuestMemoryMmap;
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use seccomp::{SeccompAction, SeccompFilter};
 use std::fs::File;
 use std::io;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
@@ -32,10 +34,15 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
+// The random bytes going through the input log are read in chunks, for the
+// size of the buffer not to be up to the guest.
+const INPUT_LOG_CHUNK_SIZE: usize = 4096;
+
 struct RngEpollHandler {
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     random_file: File,
+    input_log: Option<Arc<InputLog>>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -49,20 +56,39 @@ impl RngEpollHandler {
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in queue.iter(&mem) {
+        for avail_desc in queue.iter(mem.deref()) {
             let mut len = 0;
 
             // Drivers can only read from the random device.
             if avail_desc.is_write_only() {
-                // Fill the read with data from the random device on the host.
-                if mem
-                    .read_from(
+                // Fill the read with data from the random device on the host,
+                // going through the input log when there's one.
+                let filled = if let Some(input_log) = &self.input_log {
+                    let mut data = [0u8; INPUT_LOG_CHUNK_SIZE];
+                    let mut offset = 0;
+                    let mut filled = true;
+                    while filled && offset < avail_desc.len as usize {
+                        let chunk = &mut data[..std::cmp::min(
+                            INPUT_LOG_CHUNK_SIZE,
+                            avail_desc.len as usize - offset,
+                        )];
+                        filled = input_log.read_random(&mut self.random_file, chunk).is_ok()
+                            && avail_desc
+                                .addr
+                                .checked_add(offset as u64)
+                                .map_or(false, |addr| mem.write_slice(chunk, addr).is_ok());
+                        offset += chunk.len();
+                    }
+                    filled
+                } else {
+                    mem.read_from(
                         avail_desc.addr,
                         &mut self.random_file,
                         avail_desc.len as usize,
                     )
                     .is_ok()
-                {
+                };
+                if filled {
                     len = avail_desc.len;
                 }
             }
@@ -72,7 +98,7 @@ impl RngEpollHandler {
         }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
-            queue.add_used(&mem, desc_index, len);
+            queue.add_used(mem.deref(), desc_index, len);
         }
         used_count > 0
     }
@@ -91,12 +117,17 @@ impl RngEpollHandler {
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
-        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
-        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        let mut helper = self.epoll_helper()?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
     }
+
+    fn epoll_helper(&self) -> result::Result<EpollHelper, EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        Ok(helper)
+    }
 }
 
 impl EpollHelperHandler for RngEpollHandler {
@@ -128,7 +159,9 @@ pub struct Rng {
     common: VirtioCommon,
     id: String,
     random_file: Option<File>,
+    input_log: Option<Arc<InputLog>>,
     seccomp_action: SeccompAction,
+    shared_event_loop: Option<Arc<SharedEpollLoop>>,
 }
 
 #[derive(Versionize)]
@@ -148,7 +181,7 @@ impl Rng {
         seccomp_action: SeccompAction,
     ) -> io::Result<Rng> {
         let random_file = File::open(path)?;
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_INDIRECT_DESC;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -165,10 +198,24 @@ impl Rng {
             },
             id,
             random_file: Some(random_file),
+            input_log: None,
             seccomp_action,
+            shared_event_loop: None,
         })
     }
 
+    /// Records the random bytes read by the guest to the input log, or
+    /// replays them from it.
+    pub fn set_input_log(&mut self, input_log: Arc<InputLog>) {
+        self.input_log = Some(input_log);
+    }
+
+    /// Runs the device handler from the shared event loop rather than from
+    /// a dedicated thread.
+    pub fn set_shared_event_loop(&mut self, shared_event_loop: Arc<SharedEpollLoop>) {
+        self.shared_event_loop = Some(shared_event_loop);
+    }
+
     fn state(&self) -> RngState {
         RngState {
             avail_features: self.common.avail_features,
@@ -208,6 +255,10 @@ impl VirtioDevice for Rng {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -227,6 +278,7 @@ impl VirtioDevice for Rng {
                 queues,
                 mem,
                 random_file,
+                input_log: self.input_log.clone(),
                 interrupt_cb,
                 queue_evt: queue_evts.remove(0),
                 kill_evt,
@@ -235,18 +287,44 @@ impl VirtioDevice for Rng {
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
+
+            if let Some(shared_event_loop) = &self.shared_event_loop {
+                let helper = handler.epoll_helper().map_err(|e| {
+                    error!("failed creating virtio-rng epoll helper: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+                let worker = shared_event_loop
+                    .add(
+                        self.id.clone(),
+                        self.common.watch_worker(&self.id),
+                        helper,
+                        Box::new(handler),
+                        paused,
+                        paused_sync.unwrap(),
+                    )
+                    .map_err(|e| {
+                        error!("failed adding virtio-rng to the shared event loop: {:?}", e);
+                        ActivateError::BadActivate
+                    })?;
+                self.common.shared_epoll_workers = Some(vec![worker]);
+
+                event!("virtio-device", "activated", "id", &self.id);
+                return Ok(());
+            }
+
             let mut epoll_threads = Vec::new();
             // Retrieve seccomp filter for virtio_rng thread
             let virtio_rng_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioRng)
                     .map_err(ActivateError::CreateSeccompFilter)?;
+            let watch = self.common.watch_worker(&self.id);
             thread::Builder::new()
                 .name(self.id.clone())
                 .spawn(move || {
                     if let Err(e) = SeccompFilter::apply(virtio_rng_seccomp_filter) {
-                        error!("Error applying seccomp filter: {:?}", e);
+                        watch.failed(format!("Error applying seccomp filter: {:?}", e));
                     } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                        error!("Error running worker: {:?}", e);
+                        watch.failed(format!("Error running worker: {:?}", e));
                     }
                 })
                 .map(|thread| epoll_threads.push(thread))
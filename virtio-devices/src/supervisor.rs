@@ -0,0 +1,135 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Worker thread of a virtio device which stopped processing the queues on
+/// an error, leaving the device unable to make any progress.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkerFailure {
+    pub device_id: String,
+    pub thread: String,
+    pub error: String,
+}
+
+/// Collects the failures of the worker threads of the virtio devices, and
+/// signals them through an EventFd the VMM waits on.
+#[derive(Clone)]
+pub struct WorkerSupervisor {
+    failures: Arc<Mutex<Vec<WorkerFailure>>>,
+    evt: Arc<EventFd>,
+}
+
+impl WorkerSupervisor {
+    pub fn new() -> io::Result<Self> {
+        Ok(WorkerSupervisor {
+            failures: Arc::new(Mutex::new(Vec::new())),
+            evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+        })
+    }
+
+    fn report(&self, failure: WorkerFailure) {
+        self.failures.lock().unwrap().push(failure);
+        if let Err(e) = self.evt.write(1) {
+            error!("Error signalling worker failure: {}", e);
+        }
+    }
+
+    /// Returns the failures reported since the last call, consuming the
+    /// event signalling them.
+    pub fn take_failures(&self) -> Vec<WorkerFailure> {
+        let _ = self.evt.read();
+        self.failures.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl AsRawFd for WorkerSupervisor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.evt.as_raw_fd()
+    }
+}
+
+/// Moved into a worker thread, reporting the thread to the supervisor when
+/// it fails or panics. The failures of a device without supervisor are only
+/// logged.
+pub struct WorkerWatch {
+    supervisor: Option<WorkerSupervisor>,
+    device_id: String,
+}
+
+impl WorkerWatch {
+    pub fn new(supervisor: Option<WorkerSupervisor>, device_id: &str) -> Self {
+        WorkerWatch {
+            supervisor,
+            device_id: device_id.to_string(),
+        }
+    }
+
+    /// Reports the worker running on the current thread as failed.
+    pub fn failed(&self, error: String) {
+        error!("{}", error);
+        if let Some(supervisor) = &self.supervisor {
+            supervisor.report(WorkerFailure {
+                device_id: self.device_id.clone(),
+                thread: thread::current().name().unwrap_or("").to_string(),
+                error,
+            });
+        }
+    }
+}
+
+impl Drop for WorkerWatch {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.failed("Worker panicked".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_watch() {
+        let supervisor = WorkerSupervisor::new().unwrap();
+        assert!(supervisor.take_failures().is_empty());
+
+        let watch = WorkerWatch::new(Some(supervisor.clone()), "_disk0");
+        thread::Builder::new()
+            .name("_disk0_q0".to_string())
+            .spawn(move || watch.failed("bad descriptor".to_string()))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let watch = WorkerWatch::new(Some(supervisor.clone()), "_net1");
+        assert!(thread::Builder::new()
+            .name("_net1_q0".to_string())
+            .spawn(move || {
+                let _watch = watch;
+                panic!("worker bug");
+            })
+            .unwrap()
+            .join()
+            .is_err());
+
+        let failures = supervisor.take_failures();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].device_id, "_disk0");
+        assert_eq!(failures[0].thread, "_disk0_q0");
+        assert_eq!(failures[0].error, "bad descriptor");
+        assert_eq!(failures[1].device_id, "_net1");
+        assert_eq!(failures[1].error, "Worker panicked");
+
+        // A worker stopping cleanly isn't reported.
+        let watch = WorkerWatch::new(Some(supervisor.clone()), "_rng");
+        thread::spawn(move || drop(watch)).join().unwrap();
+        assert!(supervisor.take_failures().is_empty());
+    }
+}
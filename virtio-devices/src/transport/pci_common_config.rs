@@ -56,6 +56,11 @@ pub struct VirtioPciCommonConfig {
     pub driver_feature_select: u32,
     pub queue_select: u16,
     pub msix_config: Arc<AtomicU16>,
+    // Features the device is allowed to offer and the driver to acknowledge.
+    pub features_mask: u64,
+    // Features offered by the transport on behalf of the device, which the
+    // device doesn't need to know about.
+    pub transport_features: u64,
 }
 
 impl VirtioPciCommonConfig {
@@ -90,7 +95,7 @@ impl VirtioPciCommonConfig {
 
         match data.len() {
             1 => {
-                let v = self.read_common_config_byte(offset);
+                let v = self.read_common_config_byte(offset, device);
                 data[0] = v;
             }
             2 => {
@@ -129,12 +134,16 @@ impl VirtioPciCommonConfig {
         }
     }
 
-    fn read_common_config_byte(&self, offset: u64) -> u8 {
+    fn read_common_config_byte(&self, offset: u64, device: Arc<Mutex<dyn VirtioDevice>>) -> u8 {
         debug!("read_common_config_byte: offset 0x{:x}", offset);
         // The driver is only allowed to do aligned, properly sized access.
         match offset {
             0x14 => self.driver_status,
-            0x15 => self.config_generation,
+            // The generation is offset by the device one, so that any update
+            // of the device configuration is visible to the driver.
+            0x15 => self
+                .config_generation
+                .wrapping_add(device.lock().unwrap().config_generation()),
             _ => {
                 warn!("invalid virtio config byte read: 0x{:x}", offset);
                 0
@@ -198,7 +207,8 @@ impl VirtioPciCommonConfig {
                 // Only 64 bits of features (2 pages) are defined for now, so limit
                 // device_feature_select to avoid shifting by 64 or more bits.
                 if self.device_feature_select < 2 {
-                    (locked_device.features() >> (self.device_feature_select * 32)) as u32
+                    (((locked_device.features() | self.transport_features) & self.features_mask)
+                        >> (self.device_feature_select * 32)) as u32
                 } else {
                     0
                 }
@@ -233,8 +243,12 @@ impl VirtioPciCommonConfig {
             0x0c => {
                 if self.driver_feature_select < 2 {
                     let mut locked_device = device.lock().unwrap();
-                    locked_device
-                        .ack_features(u64::from(value) << (self.driver_feature_select * 32));
+                    let transport_only = self.transport_features & !locked_device.features();
+                    locked_device.ack_features(
+                        (u64::from(value) << (self.driver_feature_select * 32))
+                            & self.features_mask
+                            & !transport_only,
+                    );
                 } else {
                     warn!(
                         "invalid ack_features (page {}, value 0x{:x})",
@@ -352,6 +366,8 @@ mod tests {
             driver_feature_select: 0x0,
             queue_select: 0xff,
             msix_config: Arc::new(AtomicU16::new(0)),
+            features_mask: !0,
+            transport_features: 0,
         };
 
         let dev = Arc::new(Mutex::new(DummyDevice(0)));
@@ -392,4 +408,82 @@ mod tests {
         assert_eq!(read_back[0], 0xaa);
         assert_eq!(read_back[1], 0x55);
     }
+
+    #[test]
+    fn features_mask() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            features_mask: !0xff,
+            transport_features: 0,
+        };
+
+        let dev = Arc::new(Mutex::new(DummyDevice(0)));
+        let mut queues = Vec::new();
+
+        // The features filtered out aren't offered by the device.
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev);
+        assert_eq!(
+            LittleEndian::read_u32(&read_back),
+            DUMMY_FEATURES as u32 & !0xff
+        );
+    }
+
+    struct AckingDevice(u64);
+    impl VirtioDevice for AckingDevice {
+        fn device_type(&self) -> u32 {
+            0
+        }
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<Queue>,
+            _queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            Ok(())
+        }
+
+        fn features(&self) -> u64 {
+            DUMMY_FEATURES
+        }
+
+        fn ack_features(&mut self, value: u64) {
+            self.0 |= value;
+        }
+    }
+
+    #[test]
+    fn transport_features() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 1,
+            driver_feature_select: 1,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+            features_mask: !0,
+            transport_features: 1 << 33,
+        };
+
+        let dev = Arc::new(Mutex::new(AckingDevice(0)));
+        let mut queues = Vec::new();
+
+        // The transport offers the feature on behalf of the device.
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read(0x04, &mut read_back, &mut queues, dev.clone());
+        assert_eq!(LittleEndian::read_u32(&read_back), 1 << 1);
+
+        // The device doesn't get to see it acknowledged.
+        regs.write(0x0c, &[0x2, 0, 0, 0], &mut queues, dev.clone());
+        assert_eq!(dev.lock().unwrap().0, 0);
+    }
 }
@@ -10,9 +10,9 @@ use super::VirtioPciCommonConfig;
 use crate::transport::VirtioTransport;
 use crate::GuestMemoryMmap;
 use crate::{
-    ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
-    DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK,
-    DEVICE_INIT,
+    ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt,
+    VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
+    DEVICE_FEATURES_OK, DEVICE_INIT, VIRTIO_F_IOMMU_PLATFORM,
 };
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
@@ -25,6 +25,7 @@ use std::any::Any;
 use std::cmp;
 use std::io::Write;
 use std::num::Wrapping;
+use std::ops::Deref;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
@@ -259,6 +260,11 @@ const MSIX_PBA_SIZE: u64 = 0x800;
 // The BAR size must be a power of 2.
 const CAPABILITY_BAR_SIZE: u64 = 0x80000;
 
+// BAR holding the virtio-pci capabilities structures.
+const VIRTIO_COMMON_BAR_INDEX: usize = 0;
+// BAR holding the shared memory regions of the device, if any.
+const VIRTIO_SHM_BAR_INDEX: usize = 2;
+
 const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
@@ -336,8 +342,13 @@ pub struct VirtioPciDevice {
     // EventFd to signal on to request activation
     activate_evt: EventFd,
 
-    // Barrier that is used to wait on for activation
-    activate_barrier: Arc<Barrier>,
+    // Whether an activation has been requested through activate_evt and not
+    // yet carried out by the VMM thread.
+    activation_pending: bool,
+
+    // Queue notifications which trapped to the VMM rather than being caught
+    // by the ioeventfds.
+    notification_traps: Wrapping<u64>,
 }
 
 impl VirtioPciDevice {
@@ -433,6 +444,8 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
                 msix_config: Arc::new(AtomicU16::new(VIRTIO_MSI_NO_VECTOR)),
+                features_mask: !0,
+                transport_features: 0,
             },
             msix_config,
             msix_num,
@@ -450,7 +463,8 @@ impl VirtioPciDevice {
             cap_pci_cfg_info: VirtioPciCfgCapInfo::default(),
             bar_regions: vec![],
             activate_evt,
-            activate_barrier: Arc::new(Barrier::new(2)),
+            activation_pending: false,
+            notification_traps: Wrapping(0),
         };
 
         if let Some(msix_config) = &virtio_pci_device.msix_config {
@@ -503,12 +517,12 @@ impl VirtioPciDevice {
                 queue.used_ring = GuestAddress(state.queues[i].used_ring);
                 queue.next_avail = Wrapping(
                     queue
-                        .used_index_from_memory(&mem)
+                        .used_index_from_memory(mem.deref())
                         .map_err(Error::QueueRingIndex)?,
                 );
                 queue.next_used = Wrapping(
                     queue
-                        .used_index_from_memory(&mem)
+                        .used_index_from_memory(mem.deref())
                         .map_err(Error::QueueRingIndex)?,
                 );
             }
@@ -542,6 +556,26 @@ impl VirtioPciDevice {
         self.settings_bar_addr = Some(GuestAddress(bar_addr));
     }
 
+    // This function is used by the caller to override the subsystem IDs,
+    // which default to the virtio vendor and device IDs.
+    pub fn set_subsystem_id(&mut self, subsystem_vendor_id: u16, subsystem_id: u16) {
+        self.configuration
+            .set_subsystem_id(subsystem_vendor_id, subsystem_id);
+    }
+
+    // This function is used by the caller to filter the features offered by
+    // the device, all of them being offered by default.
+    pub fn set_features_mask(&mut self, features_mask: u64) {
+        self.common_config.features_mask = features_mask;
+    }
+
+    // This function is used by the caller to have the driver go through the
+    // DMA API of the guest, for its buffers to be bounced through the memory
+    // the guest shares with the device.
+    pub fn set_access_platform(&mut self) {
+        self.common_config.transport_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+    }
+
     pub fn config_bar_addr(&self) -> u64 {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
@@ -676,7 +710,7 @@ impl VirtioPciDevice {
                 queues.retain(|q| q.ready);
                 for (i, queue) in queues.iter().enumerate() {
                     queue_evts.push(self.queue_evts[i].try_clone().unwrap());
-                    if !queue.is_valid(&mem.memory()) {
+                    if !queue.is_valid(mem.memory().deref()) {
                         error!("Queue {} is not valid", i);
                     }
                 }
@@ -687,20 +721,81 @@ impl VirtioPciDevice {
     }
 
     pub fn maybe_activate(&mut self) {
+        self.activation_pending = false;
         if self.needs_activation() {
             self.activate().expect("Failed to activate device");
             self.device_activated.store(true, Ordering::SeqCst);
-            info!("{}: Waiting for barrier", self.id);
-            self.activate_barrier.wait();
-            info!("{}: Barrier released", self.id);
+            info!("{}: Device activated", self.id);
         } else {
             info!("{}: Device does not need activation", self.id)
         }
     }
 
+    /// Restarts the device whose workers stopped processing the queues,
+    /// without involving the driver. The device is reset, then activated
+    /// again with its queues resuming from the used rings, meaning the
+    /// requests the workers left in flight are processed again.
+    pub fn restart(&mut self) -> ActivateResult {
+        if !self.device_activated.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let virtio_interrupt = self
+            .device
+            .lock()
+            .unwrap()
+            .reset()
+            .ok_or(ActivateError::BadActivate)?;
+        self.virtio_interrupt = Some(virtio_interrupt);
+        self.device_activated.store(false, Ordering::SeqCst);
+
+        if let Some(mem) = self.memory.as_ref() {
+            let mem = mem.memory();
+            for queue in self.queues.iter_mut().filter(|q| q.ready) {
+                let index = queue.used_index_from_memory(mem.deref()).map_err(|e| {
+                    error!("failed reading the used ring index: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+                queue.next_avail = Wrapping(index);
+                queue.next_used = Wrapping(index);
+            }
+        }
+
+        self.activate()?;
+        self.device_activated.store(true, Ordering::SeqCst);
+        info!("{}: Device restarted", self.id);
+
+        Ok(())
+    }
+
     fn needs_activation(&self) -> bool {
         !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
     }
+
+    /// Number of queue notifications which trapped to the VMM because no
+    /// ioeventfd caught them.
+    pub fn notification_traps(&self) -> Wrapping<u64> {
+        self.notification_traps
+    }
+
+    // Handles a queue notification which wasn't caught by the ioeventfd of
+    // the queue, which is much slower than it should be.
+    fn trapped_notification(&mut self, offset: u64) {
+        if self.notification_traps.0 == 0 {
+            warn!(
+                "{}: Queue notifications are trapping, their ioeventfds are missing",
+                self.id
+            );
+        }
+        self.notification_traps += Wrapping(1);
+
+        let queue_index = (offset / u64::from(NOTIFY_OFF_MULTIPLIER)) as usize;
+        if let Some(queue_evt) = self.queue_evts.get(queue_index) {
+            if let Err(e) = queue_evt.write(1) {
+                error!("{}: Failed to notify queue {}: {}", self.id, queue_index, e);
+            }
+        }
+    }
 }
 
 impl VirtioTransport for VirtioPciDevice {
@@ -880,7 +975,7 @@ impl PciDevice for VirtioPciDevice {
             .push((virtio_pci_bar_addr, CAPABILITY_BAR_SIZE, region_type));
 
         let config = PciBarConfiguration::default()
-            .set_register_index(0)
+            .set_register_index(VIRTIO_COMMON_BAR_INDEX)
             .set_address(virtio_pci_bar_addr.raw_value())
             .set_size(CAPABILITY_BAR_SIZE)
             .set_region_type(region_type);
@@ -893,26 +988,35 @@ impl PciDevice for VirtioPciDevice {
         self.add_pci_capabilities(virtio_pci_bar)?;
 
         // Allocate a dedicated BAR if there are some shared memory regions.
+        // Each region is described to the guest through its own shared memory
+        // capability, pointing at the region's offset inside this BAR.
         if let Some(shm_list) = device.get_shm_regions() {
+            if let Some(shm) = shm_list.invalid_region() {
+                return Err(PciDeviceError::SharedMemoryRegionInvalid(
+                    shm.id, shm.offset, shm.len,
+                ));
+            }
+
+            let region_type = PciBarRegionType::Memory64BitRegion;
             let config = PciBarConfiguration::default()
-                .set_register_index(2)
+                .set_register_index(VIRTIO_SHM_BAR_INDEX)
                 .set_address(shm_list.addr.raw_value())
-                .set_size(shm_list.len);
+                .set_size(shm_list.len)
+                .set_region_type(region_type);
             let virtio_pci_shm_bar =
                 self.configuration.add_pci_bar(&config).map_err(|e| {
                     PciDeviceError::IoRegistrationFailed(shm_list.addr.raw_value(), e)
                 })? as u8;
 
-            let region_type = PciBarRegionType::Memory64BitRegion;
             ranges.push((shm_list.addr, shm_list.len, region_type));
             self.bar_regions
                 .push((shm_list.addr, shm_list.len, region_type));
 
-            for (idx, shm) in shm_list.region_list.iter().enumerate() {
+            for shm in shm_list.region_list.iter() {
                 let shm_cap = VirtioPciCap64::new(
                     PciCapabilityType::SharedMemoryConfig,
                     virtio_pci_shm_bar,
-                    idx as u8,
+                    shm.id,
                     shm.offset,
                     shm.len,
                 );
@@ -1023,7 +1127,8 @@ impl PciDevice for VirtioPciDevice {
             o if NOTIFICATION_BAR_OFFSET <= o
                 && o < NOTIFICATION_BAR_OFFSET + NOTIFICATION_SIZE =>
             {
-                // Handled with ioeventfds.
+                // Handled with ioeventfds, unless they are missing.
+                self.trapped_notification(o - NOTIFICATION_BAR_OFFSET);
             }
             o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
                 if let Some(msix_config) = &self.msix_config {
@@ -1044,18 +1149,36 @@ impl PciDevice for VirtioPciDevice {
             _ => (),
         };
 
-        // Try and activate the device if the driver status has changed
+        // Try and activate the device if the driver status has changed.
+        // The activation is deferred to the VMM thread so that the vCPU
+        // doesn't have to wait for the device threads to be spawned. Any
+        // queue notification happening in the meantime is latched by the
+        // ioeventfds and will be handled once the device is activated.
         if self.needs_activation() {
-            info!(
-                "{}: Needs activation; writing to activate event fd",
-                self.id
-            );
-            self.activate_evt.write(1).ok();
-            info!("{}: Needs activation; returning barrier", self.id);
-            return Some(self.activate_barrier.clone());
+            if !self.activation_pending {
+                info!(
+                    "{}: Needs activation; writing to activate event fd",
+                    self.id
+                );
+                self.activation_pending = true;
+                self.activate_evt.write(1).ok();
+            }
+            return None;
+        }
+
+        // Device has been reset by the driver before the pending activation
+        // could be handled. There's nothing to tear down, only the transport
+        // state needs to be reset.
+        if self.activation_pending && self.is_driver_init() {
+            self.activation_pending = false;
+            self.queues.iter_mut().for_each(Queue::reset);
+            self.common_config.queue_select = 0;
         }
 
-        // Device has been reset by the driver
+        // Device has been reset by the driver. The reset only returns once
+        // the workers have stopped and the in-flight requests have completed,
+        // which guarantees the queues won't be accessed anymore by the time
+        // the driver reads the status back.
         if self.device_activated.load(Ordering::SeqCst) && self.is_driver_init() {
             let mut device = self.device.lock().unwrap();
             if let Some(virtio_interrupt) = device.reset() {
@@ -1151,7 +1274,7 @@ impl Snapshottable for VirtioPciDevice {
             }
 
             // First restore the status of the virtqueues.
-            self.set_state(&virtio_pci_dev_section.to_versioned_state()?)
+            self.set_state(&virtio_pci_dev_section.to_versioned_state(snapshot.version)?)
                 .map_err(|e| {
                     MigratableError::Restore(anyhow!(
                         "Could not restore VIRTIO_PCI_DEVICE state {:?}",
@@ -1162,10 +1285,13 @@ impl Snapshottable for VirtioPciDevice {
             // Then we can activate the device, as we know at this point that
             // the virtqueues are in the right state and the device is ready
             // to be activated, which will spawn each virtio worker thread.
-            if self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready() {
+            // This also covers the case where the snapshot was taken while
+            // an activation was still pending.
+            if self.is_driver_ready() {
                 self.activate().map_err(|e| {
                     MigratableError::Restore(anyhow!("Failed activating the device: {:?}", e))
                 })?;
+                self.device_activated.store(true, Ordering::SeqCst);
             }
 
             return Ok(());
@@ -5,8 +5,9 @@ use super::super::{
     ActivateError, ActivateResult, Queue, VirtioCommon, VirtioDevice, VirtioDeviceType,
 };
 use super::vu_common_ctrl::{
-    add_memory_region, connect_vhost_user, negotiate_features_vhost_user, reset_vhost_user,
-    setup_vhost_user, update_mem_table, VhostUserConfig,
+    add_memory_region, connect_vhost_user, negotiate_features_vhost_user,
+    negotiate_queue_num_vhost_user, reset_vhost_user, setup_vhost_user, update_mem_table,
+    VhostUserConfig,
 };
 use super::{Error, Result, DEFAULT_VIRTIO_FEATURES};
 use crate::vhost_user::{Inflight, VhostUserEpollHandler};
@@ -51,7 +52,10 @@ pub struct Blk {
 }
 
 impl Blk {
-    /// Create a new vhost-user-blk device
+    /// Create a new vhost-user-blk device.
+    ///
+    /// The number of queues is reduced if the backend doesn't support as
+    /// many as requested, see `num_queues()`.
     pub fn new(id: String, vu_cfg: VhostUserConfig) -> Result<Blk> {
         let num_queues = vu_cfg.num_queues;
 
@@ -81,25 +85,21 @@ impl Blk {
             | VhostUserProtocolFeatures::REPLY_ACK
             | VhostUserProtocolFeatures::INFLIGHT_SHMFD;
 
-        let (acked_features, acked_protocol_features) = negotiate_features_vhost_user(
+        let (mut acked_features, acked_protocol_features) = negotiate_features_vhost_user(
             &mut vhost_user_blk,
             avail_features,
             avail_protocol_features,
         )?;
 
-        let backend_num_queues =
-            if acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0 {
-                vhost_user_blk
-                    .get_queue_num()
-                    .map_err(Error::VhostUserGetQueueMaxNum)? as usize
-            } else {
-                DEFAULT_QUEUE_NUMBER
-            };
-
-        if num_queues > backend_num_queues {
-            error!("vhost-user-blk requested too many queues ({}) since the backend only supports {}\n",
-                num_queues, backend_num_queues);
-            return Err(Error::BadQueueNum);
+        let num_queues = negotiate_queue_num_vhost_user(
+            &mut vhost_user_blk,
+            acked_protocol_features,
+            num_queues,
+            DEFAULT_QUEUE_NUMBER,
+            DEFAULT_QUEUE_NUMBER,
+        )?;
+        if num_queues == 1 {
+            acked_features &= !(1 << VIRTIO_BLK_F_MQ);
         }
 
         let config_len = mem::size_of::<VirtioBlockConfig>();
@@ -146,6 +146,11 @@ impl Blk {
             epoll_thread: None,
         })
     }
+
+    /// Number of queues actually exposed to the guest.
+    pub fn num_queues(&self) -> usize {
+        self.common.queue_sizes.len()
+    }
 }
 
 impl Drop for Blk {
@@ -2,8 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::vu_common_ctrl::{
-    add_memory_region, connect_vhost_user, negotiate_features_vhost_user, reset_vhost_user,
-    setup_vhost_user, update_mem_table,
+    add_memory_region, connect_vhost_user, negotiate_features_vhost_user,
+    negotiate_queue_num_vhost_user, reset_vhost_user, setup_vhost_user, update_mem_table,
 };
 use super::{Error, Result, DEFAULT_VIRTIO_FEATURES};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
@@ -37,6 +37,9 @@ use vmm_sys_util::eventfd::EventFd;
 const NUM_QUEUE_OFFSET: usize = 1;
 const DEFAULT_QUEUE_NUMBER: usize = 2;
 
+// Identifier of the DAX cache window, as defined by the virtio-fs specification.
+pub const VIRTIO_FS_SHM_CACHE_ID: u8 = 0;
+
 struct SlaveReqHandler {
     cache_offset: GuestAddress,
     cache_size: u64,
@@ -301,6 +304,9 @@ pub struct Fs {
 
 impl Fs {
     /// Create a new virtio-fs device.
+    ///
+    /// The number of request queues is reduced if the backend doesn't
+    /// support as many as requested, see `num_request_queues()`.
     pub fn new(
         id: String,
         path: &str,
@@ -337,22 +343,15 @@ impl Fs {
             avail_protocol_features,
         )?;
 
-        let backend_num_queues =
-            if acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0 {
-                vhost_user_fs
-                    .get_queue_num()
-                    .map_err(Error::VhostUserGetQueueMaxNum)? as usize
-            } else {
-                DEFAULT_QUEUE_NUMBER
-            };
-
-        if num_queues > backend_num_queues {
-            error!(
-                "vhost-user-fs requested too many queues ({}) since the backend only supports {}\n",
-                num_queues, backend_num_queues
-            );
-            return Err(Error::BadQueueNum);
-        }
+        // At least one request queue is needed besides the high priority one.
+        let num_queues = negotiate_queue_num_vhost_user(
+            &mut vhost_user_fs,
+            acked_protocol_features,
+            num_queues,
+            DEFAULT_QUEUE_NUMBER,
+            NUM_QUEUE_OFFSET + 1,
+        )?;
+        let req_num_queues = num_queues - NUM_QUEUE_OFFSET;
 
         if acked_protocol_features & slave_protocol_features.bits()
             == slave_protocol_features.bits()
@@ -388,6 +387,11 @@ impl Fs {
             epoll_thread: None,
         })
     }
+
+    /// Number of request queues actually exposed to the guest.
+    pub fn num_request_queues(&self) -> usize {
+        self.config.num_request_queues as usize
+    }
 }
 
 impl Drop for Fs {
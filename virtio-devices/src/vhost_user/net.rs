@@ -3,8 +3,9 @@
 
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vhost_user::vu_common_ctrl::{
-    add_memory_region, connect_vhost_user, negotiate_features_vhost_user, reset_vhost_user,
-    setup_vhost_user, update_mem_table, VhostUserConfig,
+    add_memory_region, connect_vhost_user, negotiate_features_vhost_user,
+    negotiate_queue_num_vhost_user, reset_vhost_user, setup_vhost_user, update_mem_table,
+    VhostUserConfig,
 };
 use crate::vhost_user::{Error, Inflight, Result, VhostUserEpollHandler};
 use crate::{
@@ -28,7 +29,7 @@ use virtio_bindings::bindings::virtio_net::{
     VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN,
     VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO,
     VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MRG_RXBUF,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_MTU,
 };
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic};
 use vm_migration::{Migratable, MigratableError, Pausable, Snapshottable, Transportable};
@@ -39,6 +40,29 @@ const DEFAULT_QUEUE_NUMBER: usize = 2;
 struct SlaveReqHandler {}
 impl VhostUserMasterReqHandler for SlaveReqHandler {}
 
+// Enables the vrings of the first `queue_pairs` queue pairs and disables the
+// other ones, so that the backend only processes the queues the driver uses.
+fn enable_queue_pairs(vu: &mut Master, num_queues: usize, queue_pairs: u16) -> bool {
+    let num_enabled = queue_pairs as usize * 2;
+    if num_enabled > num_queues {
+        warn!(
+            "Number of MQ pairs {} beyond the {} pairs available",
+            queue_pairs,
+            num_queues / 2
+        );
+        return false;
+    }
+
+    for queue_index in 0..num_queues {
+        if let Err(e) = vu.set_vring_enable(queue_index, queue_index < num_enabled) {
+            error!("Failed setting vring {} enable state: {:?}", queue_index, e);
+            return false;
+        }
+    }
+
+    true
+}
+
 /// Control queue
 // Event available on the control queue.
 const CTRL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
@@ -98,6 +122,9 @@ pub struct Net {
     config: VirtioNetConfig,
     guest_memory: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
     acked_protocol_features: u64,
+    // Features emulated by the VMM, which must not be forwarded to the
+    // backend.
+    vmm_features: u64,
     socket_path: String,
     server: bool,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
@@ -106,10 +133,14 @@ pub struct Net {
 }
 
 impl Net {
-    /// Create a new vhost-user-net device
+    /// Create a new vhost-user-net device.
+    ///
+    /// The number of queues is reduced if the backend doesn't support as
+    /// many as requested, see `num_queues()`.
     pub fn new(
         id: String,
         mac_addr: MacAddr,
+        mtu: Option<u16>,
         vu_cfg: VhostUserConfig,
         server: bool,
         seccomp_action: SeccompAction,
@@ -136,6 +167,11 @@ impl Net {
         let mut config = VirtioNetConfig::default();
         build_net_config_space(&mut config, mac_addr, num_queues, &mut avail_features);
 
+        if let Some(mtu) = mtu {
+            config.mtu = mtu;
+            avail_features |= 1 << VIRTIO_NET_F_MTU;
+        }
+
         let mut vhost_user_net =
             connect_vhost_user(server, &vu_cfg.socket, num_queues as u64, false)?;
 
@@ -150,26 +186,37 @@ impl Net {
             avail_protocol_features,
         )?;
 
-        let backend_num_queues =
-            if acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0 {
-                vhost_user_net
-                    .get_queue_num()
-                    .map_err(Error::VhostUserGetQueueMaxNum)? as usize
-            } else {
-                DEFAULT_QUEUE_NUMBER
-            };
+        // Only full queue pairs can be used.
+        let negotiated_num_queues = negotiate_queue_num_vhost_user(
+            &mut vhost_user_net,
+            acked_protocol_features,
+            num_queues,
+            DEFAULT_QUEUE_NUMBER,
+            DEFAULT_QUEUE_NUMBER,
+        )? & !1;
+        if negotiated_num_queues != num_queues {
+            num_queues = negotiated_num_queues;
+            config.max_virtqueue_pairs = (num_queues / 2) as u16;
+        }
 
-        if num_queues > backend_num_queues {
-            error!("vhost-user-net requested too many queues ({}) since the backend only supports {}\n",
-                num_queues, backend_num_queues);
-            return Err(Error::BadQueueNum);
+        if mtu.is_some() && acked_features & (1 << VIRTIO_NET_F_MTU) == 0 {
+            warn!("vhost-user-net backend doesn't support setting the MTU");
         }
 
-        // If the control queue feature has been negotiated, let's increase
-        // the number of queues.
-        if acked_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
-            num_queues += 1;
+        // The control queue is handled by the VMM, which takes care of
+        // enabling the vrings of the queue pairs selected by the driver.
+        // This means both the control queue and the multiqueue features can
+        // be exposed to the guest even if the backend doesn't know about
+        // them, as long as it supports several queues.
+        let mut vmm_features = 1 << VIRTIO_NET_F_CTRL_VQ;
+        if num_queues > DEFAULT_QUEUE_NUMBER {
+            vmm_features |= 1 << VIRTIO_NET_F_MQ;
         }
+        vmm_features &= !acked_features;
+        acked_features |= vmm_features;
+
+        // The control queue comes after the data queues.
+        num_queues += 1;
 
         // Make sure the virtio feature to set the MAC address is exposed to
         // the guest, even if it hasn't been negotiated with the backend.
@@ -190,6 +237,7 @@ impl Net {
             config,
             guest_memory: None,
             acked_protocol_features,
+            vmm_features,
             socket_path: vu_cfg.socket,
             server,
             ctrl_queue_epoll_thread: None,
@@ -197,6 +245,12 @@ impl Net {
             seccomp_action,
         })
     }
+
+    /// Number of data queues actually exposed to the guest, the control
+    /// queue excluded.
+    pub fn num_queues(&self) -> usize {
+        self.common.queue_sizes.len() - 1
+    }
 }
 
 impl Drop for Net {
@@ -226,6 +280,10 @@ impl VirtioDevice for Net {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         self.read_config_from_slice(self.config.as_slice(), offset, data);
     }
@@ -248,11 +306,20 @@ impl VirtioDevice for Net {
 
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
+            let mut ctrl_q = CtrlQueue::new(Vec::new());
+            if self.acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0 {
+                let vu = self.vhost_user_net.clone();
+                let num_data_queues = num_queues - 1;
+                ctrl_q = ctrl_q.with_mq_handler(Box::new(move |queue_pairs| {
+                    enable_queue_pairs(&mut vu.lock().unwrap(), num_data_queues, queue_pairs)
+                }));
+            }
+
             let mut ctrl_handler = NetCtrlEpollHandler {
                 mem: mem.clone(),
                 kill_evt,
                 pause_evt,
-                ctrl_q: CtrlQueue::new(Vec::new()),
+                ctrl_q,
                 queue: cvq_queue,
                 queue_evt: cvq_queue_evt,
             };
@@ -268,13 +335,14 @@ impl VirtioDevice for Net {
             let virtio_vhost_net_ctl_seccomp_filter =
                 get_seccomp_filter(&self.seccomp_action, Thread::VirtioVhostNetCtl)
                     .map_err(ActivateError::CreateSeccompFilter)?;
+            let watch = self.common.watch_worker(&self.id);
             thread::Builder::new()
                 .name(format!("{}_ctrl", self.id))
                 .spawn(move || {
                     if let Err(e) = SeccompFilter::apply(virtio_vhost_net_ctl_seccomp_filter) {
-                        error!("Error applying seccomp filter: {:?}", e);
+                        watch.failed(format!("Error applying seccomp filter: {:?}", e));
                     } else if let Err(e) = ctrl_handler.run_ctrl(paused, paused_sync.unwrap()) {
-                        error!("Error running worker: {:?}", e);
+                        watch.failed(format!("Error running worker: {:?}", e));
                     }
                 })
                 .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
@@ -289,8 +357,10 @@ impl VirtioDevice for Net {
         // The backend acknowledged features must contain the protocol feature
         // bit in case it was initially set but lost through the features
         // negotiation with the guest. Additionally, it must not contain
-        // VIRTIO_NET_F_MAC since we don't expect the backend to handle it.
-        let backend_acked_features = self.common.acked_features & !(1 << VIRTIO_NET_F_MAC)
+        // VIRTIO_NET_F_MAC or the features emulated by the VMM since we don't
+        // expect the backend to handle them.
+        let backend_acked_features = self.common.acked_features
+            & !(1 << VIRTIO_NET_F_MAC | self.vmm_features)
             | (self.common.avail_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits());
 
         let mut inflight: Option<Inflight> =
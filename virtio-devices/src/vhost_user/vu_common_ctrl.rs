@@ -109,6 +109,44 @@ pub fn negotiate_features_vhost_user(
     Ok((acked_features, acked_protocol_features.bits()))
 }
 
+/// Returns the number of queues the device can use, which is the number of
+/// queues requested, reduced to the maximum supported by the backend if it
+/// can't handle that many. `default_num_queues` is the number of queues
+/// assumed when the backend doesn't support the MQ protocol feature, and the
+/// backend must support at least `min_num_queues`.
+pub fn negotiate_queue_num_vhost_user(
+    vu: &mut Master,
+    acked_protocol_features: u64,
+    num_queues: usize,
+    default_num_queues: usize,
+    min_num_queues: usize,
+) -> Result<usize> {
+    let mq_supported = acked_protocol_features & VhostUserProtocolFeatures::MQ.bits() != 0;
+    let backend_num_queues = if mq_supported {
+        vu.get_queue_num().map_err(Error::VhostUserGetQueueMaxNum)? as usize
+    } else {
+        default_num_queues
+    };
+
+    if backend_num_queues < min_num_queues {
+        error!(
+            "vhost-user backend only supports {} queues, at least {} are needed",
+            backend_num_queues, min_num_queues
+        );
+        return Err(Error::BadQueueNum);
+    }
+
+    if num_queues > backend_num_queues {
+        warn!(
+            "Requested {} queues while the vhost-user backend only supports {}, using {}",
+            num_queues, backend_num_queues, backend_num_queues
+        );
+        return Ok(backend_num_queues);
+    }
+
+    Ok(num_queues)
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn setup_vhost_user<S: VhostUserMasterReqHandler>(
     vu: &mut Master,
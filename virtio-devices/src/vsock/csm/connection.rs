@@ -156,6 +156,11 @@ where
         // source, destination, credit, etc).
         self.init_pkt(pkt);
 
+        // Any packet yielded from now on lets the peer know about our current `fwd_cnt`, so this
+        // is the value it last received from us, unless we end up with no packet to yield.
+        let last_fwd_cnt_to_peer = self.last_fwd_cnt_to_peer;
+        self.last_fwd_cnt_to_peer = self.fwd_cnt;
+
         // If forceful termination is pending, there's no point in checking for anything else.
         // It's dead, Jim.
         if self.pending_rx.remove(PendingRx::Rst) {
@@ -199,12 +204,17 @@ where
             // Oh wait, before we start bringing in the big data, can our peer handle receiving so
             // much bytes goodness?
             if self.need_credit_update_from_peer() {
-                self.last_fwd_cnt_to_peer = self.fwd_cnt;
                 pkt.set_op(uapi::VSOCK_OP_CREDIT_REQUEST);
                 return Ok(());
             }
 
-            let buf = pkt.buf_mut().ok_or(VsockError::PktBufMissing)?;
+            let buf = match pkt.buf_mut() {
+                Some(buf) => buf,
+                None => {
+                    self.last_fwd_cnt_to_peer = last_fwd_cnt_to_peer;
+                    return Err(VsockError::PktBufMissing);
+                }
+            };
 
             // The maximum amount of data we can read in is limited by both the RX buffer size and
             // the peer available buffer space.
@@ -230,7 +240,6 @@ where
                         pkt.set_op(uapi::VSOCK_OP_RW).set_len(read_cnt as u32);
                     }
                     self.rx_cnt += Wrapping(pkt.len());
-                    self.last_fwd_cnt_to_peer = self.fwd_cnt;
                     return Ok(());
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
@@ -250,7 +259,6 @@ where
                         self.local_port, self.peer_port, err
                     );
                     pkt.set_op(uapi::VSOCK_OP_RST);
-                    self.last_fwd_cnt_to_peer = self.fwd_cnt;
                     return Ok(());
                 }
             };
@@ -261,12 +269,12 @@ where
         // indication last.
         if self.pending_rx.remove(PendingRx::CreditUpdate) && !self.has_pending_rx() {
             pkt.set_op(uapi::VSOCK_OP_CREDIT_UPDATE);
-            self.last_fwd_cnt_to_peer = self.fwd_cnt;
             return Ok(());
         }
 
         // We've already checked for all conditions that would have produced a packet, so
         // if we got to here, we don't know how to yield one.
+        self.last_fwd_cnt_to_peer = last_fwd_cnt_to_peer;
         Err(VsockError::NoData)
     }
 
@@ -590,41 +598,16 @@ where
 
     /// Send some raw data (a byte-slice) to the host stream.
     ///
-    /// Raw data can either be sent straight to the host stream, or to our TX buffer, if the
-    /// former fails.
+    /// Raw data is sent straight to the host stream, right after any data still waiting in our
+    /// TX buffer. Only the data the stream can't take is copied to the TX buffer, which
+    /// `self.notify()` will drain when EPOLLOUT arrives.
     ///
     fn send_bytes(&mut self, buf: &[u8]) -> Result<()> {
-        // If there is data in the TX buffer, that means we're already registered for EPOLLOUT
-        // events on the underlying stream. Therefore, there's no point in attempting a write
-        // at this point. `self.notify()` will get called when EPOLLOUT arrives, and it will
-        // attempt to drain the TX buffer then.
-        if !self.tx_buf.is_empty() {
-            return self.tx_buf.push(buf);
-        }
+        let written = self.tx_buf.write_through(&mut self.stream, buf)?;
 
-        // The TX buffer is empty, so we can try to write straight to the host stream.
-        let written = match self.stream.write(buf) {
-            Ok(cnt) => cnt,
-            Err(e) => {
-                // Absorb any would-block errors, since we can always try again later.
-                if e.kind() == ErrorKind::WouldBlock {
-                    0
-                } else {
-                    // We don't know how to handle any other write error, so we'll send it up
-                    // the call chain.
-                    return Err(Error::StreamWrite(e));
-                }
-            }
-        };
         // Move the "forwarded bytes" counter ahead by how much we were able to send out.
         self.fwd_cnt += Wrapping(written as u32);
 
-        // If we couldn't write the whole slice, we'll need to push the remaining data to our
-        // buffer.
-        if written < buf.len() {
-            self.tx_buf.push(&buf[written..])?;
-        }
-
         Ok(())
     }
 
@@ -646,8 +629,12 @@ where
     /// Get the maximum number of bytes that we can send to our peer, without overflowing its
     /// buffer.
     ///
+    /// The peer may shrink its buffer below the amount of data it has yet to consume, in which
+    /// case it has no credit left, rather than the wrapped-around difference.
+    ///
     fn peer_avail_credit(&self) -> usize {
-        (Wrapping(self.peer_buf_alloc as u32) - (self.rx_cnt - self.peer_fwd_cnt)).0 as usize
+        let in_flight = (self.rx_cnt - self.peer_fwd_cnt).0;
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
     }
 
     /// Prepare a packet header for transmission to our peer.
@@ -674,7 +661,7 @@ where
 mod tests {
     use libc::EFD_NONBLOCK;
 
-    use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+    use std::io::{Error as IoError, ErrorKind, IoSlice, Read, Result as IoResult, Write};
     use std::os::unix::io::RawFd;
     use std::time::{Duration, Instant};
     use vmm_sys_util::eventfd::EventFd;
@@ -759,6 +746,13 @@ mod tests {
                 StreamState::WouldBlock => Err(IoError::new(ErrorKind::WouldBlock, "EAGAIN")),
             }
         }
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> IoResult<usize> {
+            let mut written = 0;
+            for buf in bufs {
+                written += self.write(buf)?;
+            }
+            Ok(written)
+        }
         fn flush(&mut self) -> IoResult<()> {
             Ok(())
         }
@@ -1092,6 +1086,24 @@ mod tests {
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_REQUEST);
     }
 
+    #[test]
+    fn test_peer_buf_shrink() {
+        let mut ctx = CsmTestContext::new_established();
+        ctx.set_peer_credit(16);
+
+        // The peer shrinks its buffer below the amount of data it has yet to consume.
+        ctx.init_pkt(uapi::VSOCK_OP_CREDIT_UPDATE, 0)
+            .set_buf_alloc(PEER_BUF_ALLOC / 2);
+        ctx.send();
+        assert_eq!(ctx.conn.peer_avail_credit(), 0);
+
+        // No data can be sent until the peer grants us some more credit.
+        assert!(!ctx.conn.get_polled_evset().contains(epoll::Events::EPOLLIN));
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_REQUEST);
+    }
+
     #[test]
     fn test_credit_request_from_peer() {
         let mut ctx = CsmTestContext::new_established();
@@ -1174,6 +1186,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tx_write_through() {
+        let mut ctx = CsmTestContext::new_established();
+
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::WouldBlock;
+        ctx.set_stream(stream);
+
+        let data = &[1, 2, 3, 4];
+        ctx.init_data_pkt(data);
+        ctx.send();
+        assert_eq!(ctx.conn.tx_buf.len(), data.len());
+
+        // Once the backing stream can be written to again, the next data packet goes straight
+        // to it, right after the buffered data.
+        ctx.set_stream(TestStream::new());
+        let more_data = &[5, 6];
+        ctx.init_data_pkt(more_data);
+        ctx.send();
+        assert!(ctx.conn.tx_buf.is_empty());
+        assert_eq!(ctx.conn.stream.write_buf, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(ctx.conn.fwd_cnt.0, 6);
+        assert!(!ctx
+            .conn
+            .get_polled_evset()
+            .contains(epoll::Events::EPOLLOUT));
+    }
+
     #[test]
     fn test_stream_write_error() {
         // Test case: sending a data packet to a broken / closed backing stream should kill it.
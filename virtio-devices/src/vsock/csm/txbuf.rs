@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::io::Write;
+use std::io::{ErrorKind, IoSlice, Write};
 use std::num::Wrapping;
 
 use super::defs;
@@ -82,6 +82,27 @@ impl TxBuf {
         Ok(())
     }
 
+    /// Get the buffered data, as the (up to) two slices it spans in the ring-buffer, in the
+    /// order they need to be flushed out: tail to slice end (or to head), then slice start to
+    /// head, if the buffer wraps around.
+    ///
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        let data = match self.data.as_ref() {
+            Some(data) if !self.is_empty() => data,
+            _ => return (&[], &[]),
+        };
+
+        // Buffer tail, as an offset into the buffer data slice.
+        let tail_ofs = self.tail.0 as usize % Self::SIZE;
+        // First slice length: the lesser of tail to slice end, or tail to head.
+        let len = std::cmp::min(Self::SIZE - tail_ofs, self.len());
+
+        (
+            &data[tail_ofs..(tail_ofs + len)],
+            &data[..(self.len() - len)],
+        )
+    }
+
     /// Flush the contents of the ring-buffer to a writable stream.
     ///
     /// Return the number of bytes that have been transferred out of the ring-buffer and into
@@ -96,43 +117,58 @@ impl TxBuf {
             return Ok(0);
         }
 
-        // Buffer tail, as an offset into the buffer data slice.
-        let tail_ofs = self.tail.0 as usize % Self::SIZE;
-
-        // Flushing the buffer can take either one or two writes:
-        // - one write, if the tail doesn't need to wrap around to reach the head; or
-        // - two writes, if the tail would wrap around: tail to slice end, then slice end to
-        //   head.
-
-        // First write length: the lesser of tail to slice end, or tail to head.
-        let len_to_write = std::cmp::min(Self::SIZE - tail_ofs, self.len());
-
-        // It's safe to unwrap here, since we've already checked if the buffer was empty.
-        let data = self.data.as_ref().unwrap();
-
-        // Issue the first write and absorb any `WouldBlock` error (we can just try again
-        // later).
+        // Even if the buffered data wraps around, a single vectored write is enough to flush
+        // it all out.
+        let (first, second) = self.as_slices();
         let written = sink
-            .write(&data[tail_ofs..(tail_ofs + len_to_write)])
+            .write_vectored(&[IoSlice::new(first), IoSlice::new(second)])
             .map_err(Error::TxBufFlush)?;
 
         // Move the buffer tail ahead by the amount (of bytes) we were able to flush out.
         self.tail += Wrapping(written as u32);
 
-        // If we weren't able to flush out as much as we tried, there's no point in attempting
-        // our second write.
-        if written < len_to_write {
-            return Ok(written);
+        Ok(written)
+    }
+
+    /// Write the contents of the ring-buffer, followed by a byte slice, to a writable stream.
+    ///
+    /// This takes a single vectored write, keeping the stream ordering while only copying to
+    /// the ring-buffer the part of the slice the stream couldn't take. A `WouldBlock` error is
+    /// absorbed, the whole slice being pushed then. Same as with `push()`, nothing is written
+    /// if there isn't enough room to push the entire slice, in which case
+    /// `Err(Error::TxBufFull)` is returned.
+    ///
+    /// Return the number of bytes that have been transferred to the writable stream, from both
+    /// the ring-buffer and the slice.
+    ///
+    pub fn write_through<W>(&mut self, sink: &mut W, src: &[u8]) -> Result<usize>
+    where
+        W: Write,
+    {
+        if self.len() + src.len() > Self::SIZE {
+            return Err(Error::TxBufFull);
         }
 
-        // Attempt our second write. This will return immediately if a second write isn't
-        // needed, since checking for an empty buffer is the first thing we do in this
-        // function.
-        //
-        // Interesting corner case: if we've already written some data in the first pass,
-        // and then the second write fails, we will consider the flush action a success
-        // and return the number of bytes written in the first pass.
-        Ok(written + self.flush_to(sink).unwrap_or(0))
+        let buffered = self.len();
+        let (first, second) = self.as_slices();
+        let written = match sink.write_vectored(&[
+            IoSlice::new(first),
+            IoSlice::new(second),
+            IoSlice::new(src),
+        ]) {
+            Ok(cnt) => cnt,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(Error::StreamWrite(e)),
+        };
+
+        // The buffered data goes out first, and only then the slice.
+        let src_written = written.saturating_sub(buffered);
+        self.tail += Wrapping((written - src_written) as u32);
+        if src_written < src.len() {
+            self.push(&src[src_written..])?;
+        }
+
+        Ok(written)
     }
 
     /// Check if the buffer holds any data that hasn't yet been flushed out.
@@ -147,7 +183,7 @@ mod tests {
     use super::*;
     use std::io::Error as IoError;
     use std::io::Result as IoResult;
-    use std::io::{ErrorKind, Write};
+    use std::io::{ErrorKind, IoSlice, Write};
 
     struct TestSink {
         data: Vec<u8>,
@@ -175,6 +211,17 @@ mod tests {
             self.data.extend_from_slice(&src[..len_to_push]);
             Ok(len_to_push)
         }
+        fn write_vectored(&mut self, srcs: &[IoSlice]) -> IoResult<usize> {
+            let mut written = 0;
+            for src in srcs {
+                let len = self.write(src)?;
+                written += len;
+                if len < src.len() {
+                    break;
+                }
+            }
+            Ok(written)
+        }
         fn flush(&mut self) -> IoResult<()> {
             Ok(())
         }
@@ -255,6 +302,44 @@ mod tests {
         assert_eq!(sink.data, [1, 2, 3, 4]);
     }
 
+    #[test]
+    fn test_write_through() {
+        let mut txbuf = TxBuf::new();
+        let mut sink = TestSink::new();
+
+        // Nothing gets buffered if the sink takes the whole slice.
+        assert_eq!(txbuf.write_through(&mut sink, &[1, 2]).unwrap(), 2);
+        assert!(txbuf.data.is_none());
+        assert_eq!(sink.data, [1, 2]);
+
+        // Only the part of the slice the sink can't take is buffered.
+        sink.set_capacity(3);
+        assert_eq!(txbuf.write_through(&mut sink, &[3, 4, 5]).unwrap(), 1);
+        assert_eq!(txbuf.len(), 2);
+
+        // The buffered data is written out before the slice.
+        sink.set_capacity(5);
+        assert_eq!(txbuf.write_through(&mut sink, &[6, 7]).unwrap(), 2);
+        assert_eq!(txbuf.len(), 2);
+        sink.set_capacity(16);
+        assert_eq!(txbuf.write_through(&mut sink, &[8]).unwrap(), 3);
+        assert!(txbuf.is_empty());
+        assert_eq!(sink.data, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // The whole slice is buffered when writing would block.
+        sink.set_err(IoError::new(ErrorKind::WouldBlock, "EAGAIN"));
+        assert_eq!(txbuf.write_through(&mut sink, &[9]).unwrap(), 0);
+        assert_eq!(txbuf.len(), 1);
+
+        // Nothing is written when the slice can't be buffered.
+        let tmp = vec![0u8; TxBuf::SIZE];
+        match txbuf.write_through(&mut sink, tmp.as_slice()) {
+            Err(Error::TxBufFull) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(sink.data, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
     #[test]
     fn test_flush_error() {
         const EACCESS: i32 = 13;
@@ -35,11 +35,12 @@ use crate::VirtioInterrupt;
 use crate::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
     VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
-    VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+    VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use seccomp::{SeccompAction, SeccompFilter};
 use std::io;
+use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
@@ -54,9 +55,7 @@ use vm_migration::{
 };
 use vmm_sys_util::eventfd::EventFd;
 
-const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
 // New descriptors are pending on the rx queue.
 pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
@@ -119,10 +118,9 @@ where
     fn process_rx(&mut self) -> result::Result<(), DeviceError> {
         debug!("vsock: epoll_handler::process_rx()");
 
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
+        let mut used_desc_heads = Vec::new();
         let mem = self.mem.memory();
-        for avail_desc in self.queues[0].iter(&mem) {
+        for avail_desc in self.queues[0].iter(mem.deref()) {
             let used_len = match VsockPacket::from_rx_virtq_head(&avail_desc) {
                 Ok(mut pkt) => {
                     if self.backend.write().unwrap().recv_pkt(&mut pkt).is_ok() {
@@ -140,15 +138,14 @@ where
                 }
             };
 
-            used_desc_heads[used_count] = (avail_desc.index, used_len);
-            used_count += 1;
+            used_desc_heads.push((avail_desc.index, used_len));
         }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queues[0].add_used(&mem, desc_index, len);
+        for &(desc_index, len) in used_desc_heads.iter() {
+            self.queues[0].add_used(mem.deref(), desc_index, len);
         }
 
-        if used_count > 0 {
+        if !used_desc_heads.is_empty() {
             self.signal_used_queue(&self.queues[0])
         } else {
             Ok(())
@@ -161,16 +158,14 @@ where
     fn process_tx(&mut self) -> result::Result<(), DeviceError> {
         debug!("vsock: epoll_handler::process_tx()");
 
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
+        let mut used_desc_heads = Vec::new();
         let mem = self.mem.memory();
-        for avail_desc in self.queues[1].iter(&mem) {
+        for avail_desc in self.queues[1].iter(mem.deref()) {
             let pkt = match VsockPacket::from_tx_virtq_head(&avail_desc) {
                 Ok(pkt) => pkt,
                 Err(e) => {
                     error!("vsock: error reading TX packet: {:?}", e);
-                    used_desc_heads[used_count] = (avail_desc.index, 0);
-                    used_count += 1;
+                    used_desc_heads.push((avail_desc.index, 0));
                     continue;
                 }
             };
@@ -180,15 +175,14 @@ where
                 break;
             }
 
-            used_desc_heads[used_count] = (avail_desc.index, 0);
-            used_count += 1;
+            used_desc_heads.push((avail_desc.index, 0));
         }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queues[1].add_used(&mem, desc_index, len);
+        for &(desc_index, len) in used_desc_heads.iter() {
+            self.queues[1].add_used(mem.deref(), desc_index, len);
         }
 
-        if used_count > 0 {
+        if !used_desc_heads.is_empty() {
             self.signal_used_queue(&self.queues[1])
         } else {
             Ok(())
@@ -326,9 +320,12 @@ where
         path: PathBuf,
         backend: B,
         iommu: bool,
+        queue_size: u16,
         seccomp_action: SeccompAction,
     ) -> io::Result<Vsock<B>> {
-        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_RING_INDIRECT_DESC
+            | 1u64 << VIRTIO_F_IN_ORDER;
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -339,7 +336,7 @@ where
                 device_type: VirtioDeviceType::Vsock as u32,
                 avail_features,
                 paused_sync: Some(Arc::new(Barrier::new(2))),
-                queue_sizes: QUEUE_SIZES.to_vec(),
+                queue_sizes: vec![queue_size; NUM_QUEUES],
                 min_queues: NUM_QUEUES as u16,
                 ..Default::default()
             },
@@ -396,6 +393,10 @@ where
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         match offset {
             0 if data.len() == 8 => LittleEndian::write_u64(data, self.cid),
@@ -438,13 +439,14 @@ where
         let virtio_vsock_seccomp_filter =
             get_seccomp_filter(&self.seccomp_action, Thread::VirtioVsock)
                 .map_err(ActivateError::CreateSeccompFilter)?;
+        let watch = self.common.watch_worker(&self.id);
         thread::Builder::new()
             .name(self.id.clone())
             .spawn(move || {
                 if let Err(e) = SeccompFilter::apply(virtio_vsock_seccomp_filter) {
-                    error!("Error applying seccomp filter: {:?}", e);
+                    watch.failed(format!("Error applying seccomp filter: {:?}", e));
                 } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                    error!("Error running worker: {:?}", e);
+                    watch.failed(format!("Error running worker: {:?}", e));
                 }
             })
             .map(|thread| epoll_threads.push(thread))
@@ -514,7 +516,9 @@ mod tests {
     #[test]
     fn test_virtio_device() {
         let mut ctx = TestContext::new();
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
+        let avail_features = 1u64 << VIRTIO_F_VERSION_1
+            | 1u64 << VIRTIO_F_RING_INDIRECT_DESC
+            | 1u64 << VIRTIO_F_IN_ORDER;
         let device_features = avail_features;
         let driver_features: u64 = avail_features | 1 | (1 << 32);
         let device_pages = [
@@ -526,7 +530,7 @@ mod tests {
             (driver_features >> 32) as u32,
         ];
         assert_eq!(ctx.device.device_type(), VirtioDeviceType::Vsock as u32);
-        assert_eq!(ctx.device.queue_max_sizes(), QUEUE_SIZES);
+        assert_eq!(ctx.device.queue_max_sizes(), &[256; NUM_QUEUES]);
         assert_eq!(ctx.device.features() as u32, device_pages[0]);
         assert_eq!((ctx.device.features() >> 32) as u32, device_pages[1]);
 
@@ -274,6 +274,7 @@ mod tests {
                     PathBuf::from("/test/sock"),
                     TestBackend::new(),
                     false,
+                    256,
                     seccomp::SeccompAction::Trap,
                 )
                 .unwrap(),
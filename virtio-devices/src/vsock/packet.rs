@@ -345,7 +345,7 @@ mod tests {
     use crate::GuestMemoryMmap;
     use vm_memory::GuestAddress;
     use vm_virtio::queue::testing::VirtqDesc as GuestQDesc;
-    use vm_virtio::queue::VIRTQ_DESC_F_WRITE;
+    use vm_virtio::queue::{VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
 
     macro_rules! create_context {
         ($test_ctx:ident, $handler_ctx:ident) => {
@@ -407,6 +407,37 @@ mod tests {
             );
         }
 
+        // Test case: successful TX packet assembly from an indirect table.
+        {
+            create_context!(test_ctx, handler_ctx);
+            let indirect_table = [
+                GuestQDesc::new(GuestAddress(0x0060_0000), &test_ctx.mem),
+                GuestQDesc::new(GuestAddress(0x0060_0010), &test_ctx.mem),
+            ];
+            let dtable = &handler_ctx.guest_txvq.dtable;
+            indirect_table[0].set(
+                dtable[0].addr.get(),
+                dtable[0].len.get(),
+                VIRTQ_DESC_F_NEXT,
+                1,
+            );
+            indirect_table[1].set(dtable[1].addr.get(), dtable[1].len.get(), 0, 0);
+            dtable[0].set(0x0060_0000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+
+            let pkt = VsockPacket::from_tx_virtq_head(
+                &handler_ctx.handler.queues[1]
+                    .iter(&test_ctx.mem)
+                    .next()
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(pkt.hdr().len(), VSOCK_PKT_HDR_SIZE);
+            assert_eq!(
+                pkt.buf().unwrap().len(),
+                indirect_table[1].len.get() as usize
+            );
+        }
+
         // Test case: error on write-only hdr descriptor.
         {
             create_context!(test_ctx, handler_ctx);
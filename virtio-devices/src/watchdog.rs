@@ -8,7 +8,8 @@
 use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
-    VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    SharedEpollLoop, VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::GuestMemoryMmap;
@@ -17,6 +18,7 @@ use anyhow::anyhow;
 use seccomp::{SeccompAction, SeccompFilter};
 use std::fs::File;
 use std::io::{self, Read};
+use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
 use std::sync::atomic::AtomicBool;
@@ -65,7 +67,7 @@ impl WatchdogEpollHandler {
         let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
         let mut used_count = 0;
         let mem = self.mem.memory();
-        for avail_desc in queue.iter(&mem) {
+        for avail_desc in queue.iter(mem.deref()) {
             let mut len = 0;
 
             if avail_desc.is_write_only() && mem.write_obj(1u8, avail_desc.addr).is_ok() {
@@ -88,7 +90,7 @@ impl WatchdogEpollHandler {
         }
 
         for &(desc_index, len) in &used_desc_heads[..used_count] {
-            queue.add_used(&mem, desc_index, len);
+            queue.add_used(mem.deref(), desc_index, len);
         }
         used_count > 0
     }
@@ -107,13 +109,18 @@ impl WatchdogEpollHandler {
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
-        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
-        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
-        helper.add_event(self.timer.as_raw_fd(), TIMER_EXPIRED_EVENT)?;
+        let mut helper = self.epoll_helper()?;
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
     }
+
+    fn epoll_helper(&self) -> result::Result<EpollHelper, EpollHelperError> {
+        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        helper.add_event(self.timer.as_raw_fd(), TIMER_EXPIRED_EVENT)?;
+        Ok(helper)
+    }
 }
 
 impl EpollHelperHandler for WatchdogEpollHandler {
@@ -166,6 +173,7 @@ pub struct Watchdog {
     reset_evt: EventFd,
     last_ping_time: Arc<Mutex<Option<Instant>>>,
     timer: File,
+    shared_event_loop: Option<Arc<SharedEpollLoop>>,
 }
 
 #[derive(Versionize)]
@@ -184,7 +192,7 @@ impl Watchdog {
         reset_evt: EventFd,
         seccomp_action: SeccompAction,
     ) -> io::Result<Watchdog> {
-        let avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_INDIRECT_DESC;
         let timer_fd = timerfd_create().map_err(|e| {
             error!("Failed to create timer fd {}", e);
             e
@@ -204,9 +212,16 @@ impl Watchdog {
             reset_evt,
             last_ping_time: Arc::new(Mutex::new(None)),
             timer,
+            shared_event_loop: None,
         })
     }
 
+    /// Runs the device handler from the shared event loop rather than from
+    /// a dedicated thread.
+    pub fn set_shared_event_loop(&mut self, shared_event_loop: Arc<SharedEpollLoop>) {
+        self.shared_event_loop = Some(shared_event_loop);
+    }
+
     fn state(&self) -> WatchdogState {
         WatchdogState {
             avail_features: self.common.avail_features,
@@ -283,6 +298,10 @@ impl VirtioDevice for Watchdog {
         self.common.ack_features(value)
     }
 
+    fn common_mut(&mut self) -> Option<&mut VirtioCommon> {
+        Some(&mut self.common)
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -317,18 +336,47 @@ impl VirtioDevice for Watchdog {
 
         let paused = self.common.paused.clone();
         let paused_sync = self.common.paused_sync.clone();
+
+        if let Some(shared_event_loop) = &self.shared_event_loop {
+            let helper = handler.epoll_helper().map_err(|e| {
+                error!("failed creating virtio-watchdog epoll helper: {:?}", e);
+                ActivateError::BadActivate
+            })?;
+            let worker = shared_event_loop
+                .add(
+                    self.id.clone(),
+                    self.common.watch_worker(&self.id),
+                    helper,
+                    Box::new(handler),
+                    paused,
+                    paused_sync.unwrap(),
+                )
+                .map_err(|e| {
+                    error!(
+                        "failed adding virtio-watchdog to the shared event loop: {:?}",
+                        e
+                    );
+                    ActivateError::BadActivate
+                })?;
+            self.common.shared_epoll_workers = Some(vec![worker]);
+
+            event!("virtio-device", "activated", "id", &self.id);
+            return Ok(());
+        }
+
         let mut epoll_threads = Vec::new();
         // Retrieve seccomp filter for virtio_watchdog thread
         let virtio_watchdog_seccomp_filter =
             get_seccomp_filter(&self.seccomp_action, Thread::VirtioWatchdog)
                 .map_err(ActivateError::CreateSeccompFilter)?;
+        let watch = self.common.watch_worker(&self.id);
         thread::Builder::new()
             .name(self.id.clone())
             .spawn(move || {
                 if let Err(e) = SeccompFilter::apply(virtio_watchdog_seccomp_filter) {
-                    error!("Error applying seccomp filter: {:?}", e);
+                    watch.failed(format!("Error applying seccomp filter: {:?}", e));
                 } else if let Err(e) = handler.run(paused, paused_sync.unwrap()) {
-                    error!("Error running worker: {:?}", e);
+                    watch.failed(format!("Error running worker: {:?}", e));
                 }
             })
             .map(|thread| epoll_threads.push(thread))
@@ -29,7 +29,16 @@ pub trait BusDevice: Send {
 #[derive(Debug)]
 pub enum Error {
     /// The insertion failed because the new device overlapped with an old device.
-    Overlap,
+    Overlap {
+        /// Device which could not be inserted.
+        id: String,
+        /// Range which could not be inserted.
+        range: BusRange,
+        /// Device already registered on the bus conflicting with the new one.
+        existing_id: String,
+        /// Range already registered on the bus conflicting with the new one.
+        existing: BusRange,
+    },
     /// Failed to operate on zero sized range.
     ZeroSizedRange,
     /// Failed to find address range.
@@ -40,7 +49,24 @@ pub type Result<T> = result::Result<T, Error>;
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "bus_error: {:?}", self)
+        match self {
+            Error::Overlap {
+                id,
+                range,
+                existing_id,
+                existing,
+            } => write!(
+                f,
+                "bus_error: range [0x{:x}-0x{:x}] of {} overlaps with range [0x{:x}-0x{:x}] of {}",
+                range.base,
+                range.end(),
+                id,
+                existing.base,
+                existing.end(),
+                existing_id
+            ),
+            _ => write!(f, "bus_error: {:?}", self),
+        }
     }
 }
 
@@ -67,6 +93,11 @@ impl BusRange {
     pub fn overlaps(&self, base: u64, len: u64) -> bool {
         self.base < (base + len) && base < self.base + self.len
     }
+
+    /// Returns the last address covered by the range.
+    pub fn end(&self) -> u64 {
+        self.base + self.len - 1
+    }
 }
 
 impl Eq for BusRange {}
@@ -89,13 +120,20 @@ impl PartialOrd for BusRange {
     }
 }
 
+// Device registered on a bus, along with its identifier for the errors to
+// name it.
+struct BusEntry {
+    id: String,
+    device: Weak<Mutex<dyn BusDevice>>,
+}
+
 /// A device container for routing reads and writes over some address space.
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
 /// only restriction is that no two devices can overlap in this address space.
 #[derive(Default)]
 pub struct Bus {
-    devices: RwLock<BTreeMap<BusRange, Weak<Mutex<dyn BusDevice>>>>,
+    devices: RwLock<BTreeMap<BusRange, BusEntry>>,
 }
 
 impl Bus {
@@ -108,11 +146,11 @@ impl Bus {
 
     fn first_before(&self, addr: u64) -> Option<(BusRange, Arc<Mutex<dyn BusDevice>>)> {
         let devices = self.devices.read().unwrap();
-        let (range, dev) = devices
+        let (range, entry) = devices
             .range(..=BusRange { base: addr, len: 1 })
             .rev()
             .next()?;
-        dev.upgrade().map(|d| (*range, d.clone()))
+        entry.device.upgrade().map(|d| (*range, d))
     }
 
     #[allow(clippy::type_complexity)]
@@ -126,33 +164,51 @@ impl Bus {
         None
     }
 
-    /// Puts the given device at the given address space.
-    pub fn insert(&self, device: Arc<Mutex<dyn BusDevice>>, base: u64, len: u64) -> Result<()> {
-        if len == 0 {
-            return Err(Error::ZeroSizedRange);
-        }
-
-        // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
-            .read()
-            .unwrap()
-            .iter()
-            .any(|(range, _dev)| range.overlaps(base, len))
+    // Reject all cases where the range overlaps with an existing device.
+    fn check_overlap(
+        devices: &BTreeMap<BusRange, BusEntry>,
+        id: &str,
+        base: u64,
+        len: u64,
+    ) -> Result<()> {
+        if let Some((existing, entry)) = devices.iter().find(|(range, _)| range.overlaps(base, len))
         {
-            return Err(Error::Overlap);
+            return Err(Error::Overlap {
+                id: id.to_owned(),
+                range: BusRange { base, len },
+                existing_id: entry.id.clone(),
+                existing: *existing,
+            });
         }
 
-        if self
-            .devices
-            .write()
-            .unwrap()
-            .insert(BusRange { base, len }, Arc::downgrade(&device))
-            .is_some()
-        {
-            return Err(Error::Overlap);
+        Ok(())
+    }
+
+    /// Puts the given device at the given address space, `id` naming the
+    /// device in the errors.
+    pub fn insert(
+        &self,
+        device: Arc<Mutex<dyn BusDevice>>,
+        base: u64,
+        len: u64,
+        id: &str,
+    ) -> Result<()> {
+        if len == 0 {
+            return Err(Error::ZeroSizedRange);
         }
 
+        // Hold the write lock across the check and the insertion so that
+        // concurrent insertions can't end up overlapping.
+        let mut devices = self.devices.write().unwrap();
+        Self::check_overlap(&devices, id, base, len)?;
+        devices.insert(
+            BusRange { base, len },
+            BusEntry {
+                id: id.to_owned(),
+                device: Arc::downgrade(&device),
+            },
+        );
+
         Ok(())
     }
 
@@ -176,9 +232,12 @@ impl Bus {
         let mut device_list = self.devices.write().unwrap();
         let mut remove_key_list = Vec::new();
 
-        for (key, value) in device_list.iter() {
-            if Arc::ptr_eq(&value.upgrade().unwrap(), device) {
-                remove_key_list.push(*key);
+        for (key, entry) in device_list.iter() {
+            // Entries whose device has already been dropped are stale and
+            // can be cleaned up at the same time.
+            match entry.device.upgrade() {
+                Some(dev) if !Arc::ptr_eq(&dev, device) => {}
+                _ => remove_key_list.push(*key),
             }
         }
 
@@ -190,6 +249,9 @@ impl Bus {
     }
 
     /// Updates the address range for an existing device.
+    ///
+    /// The update is atomic: if the new range overlaps with another device,
+    /// the device stays registered at its previous address.
     pub fn update_range(
         &self,
         old_base: u64,
@@ -197,18 +259,35 @@ impl Bus {
         new_base: u64,
         new_len: u64,
     ) -> Result<()> {
-        // Retrieve the device corresponding to the range
-        let device = if let Some((_, _, dev)) = self.resolve(old_base) {
-            dev.clone()
-        } else {
-            return Err(Error::MissingAddressRange);
-        };
+        if old_len == 0 || new_len == 0 {
+            return Err(Error::ZeroSizedRange);
+        }
+
+        let mut devices = self.devices.write().unwrap();
 
         // Remove the old address range
-        self.remove(old_base, old_len)?;
+        let old_range = BusRange {
+            base: old_base,
+            len: old_len,
+        };
+        let entry = devices
+            .remove(&old_range)
+            .ok_or(Error::MissingAddressRange)?;
+
+        // Insert the new address range, restoring the old one on failure
+        if let Err(e) = Self::check_overlap(&devices, &entry.id, new_base, new_len) {
+            devices.insert(old_range, entry);
+            return Err(e);
+        }
+        devices.insert(
+            BusRange {
+                base: new_base,
+                len: new_len,
+            },
+            entry,
+        );
 
-        // Insert the new address range
-        self.insert(device, new_base, new_len)
+        Ok(())
     }
 
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
@@ -270,21 +349,24 @@ mod tests {
     fn bus_insert() {
         let bus = Bus::new();
         let dummy = Arc::new(Mutex::new(DummyDevice));
-        assert!(bus.insert(dummy.clone(), 0x10, 0).is_err());
-        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(dummy.clone(), 0x10, 0, "dummy").is_err());
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10, "dummy").is_ok());
 
-        let result = bus.insert(dummy.clone(), 0x0f, 0x10);
+        let result = bus.insert(dummy.clone(), 0x0f, 0x10, "dummy");
         assert!(result.is_err());
-        assert_eq!(format!("{:?}", result), "Err(Overlap)");
-
-        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_err());
-        assert!(bus.insert(dummy.clone(), 0x10, 0x15).is_err());
-        assert!(bus.insert(dummy.clone(), 0x12, 0x15).is_err());
-        assert!(bus.insert(dummy.clone(), 0x12, 0x01).is_err());
-        assert!(bus.insert(dummy.clone(), 0x0, 0x20).is_err());
-        assert!(bus.insert(dummy.clone(), 0x20, 0x05).is_ok());
-        assert!(bus.insert(dummy.clone(), 0x25, 0x05).is_ok());
-        assert!(bus.insert(dummy, 0x0, 0x10).is_ok());
+        assert_eq!(
+            format!("{}", result.unwrap_err()),
+            "bus_error: range [0xf-0x1e] of dummy overlaps with range [0x10-0x1f] of dummy"
+        );
+
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10, "dummy").is_err());
+        assert!(bus.insert(dummy.clone(), 0x10, 0x15, "dummy").is_err());
+        assert!(bus.insert(dummy.clone(), 0x12, 0x15, "dummy").is_err());
+        assert!(bus.insert(dummy.clone(), 0x12, 0x01, "dummy").is_err());
+        assert!(bus.insert(dummy.clone(), 0x0, 0x20, "dummy").is_err());
+        assert!(bus.insert(dummy.clone(), 0x20, 0x05, "dummy").is_ok());
+        assert!(bus.insert(dummy.clone(), 0x25, 0x05, "dummy").is_ok());
+        assert!(bus.insert(dummy, 0x0, 0x10, "dummy").is_ok());
     }
 
     #[test]
@@ -292,7 +374,7 @@ mod tests {
     fn bus_read_write() {
         let bus = Bus::new();
         let dummy = Arc::new(Mutex::new(DummyDevice));
-        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10, "dummy").is_ok());
         assert!(bus.read(0x10, &mut [0, 0, 0, 0]).is_ok());
         assert!(bus.write(0x10, &[0, 0, 0, 0]).is_ok());
         assert!(bus.read(0x11, &mut [0, 0, 0, 0]).is_ok());
@@ -310,7 +392,7 @@ mod tests {
     fn bus_read_write_values() {
         let bus = Bus::new();
         let dummy = Arc::new(Mutex::new(ConstantDevice));
-        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10, "dummy").is_ok());
 
         let mut values = [0, 1, 2, 3];
         assert!(bus.read(0x10, &mut values).is_ok());
@@ -336,12 +418,75 @@ mod tests {
         let bus = Bus::new();
         let mut data = [1, 2, 3, 4];
         let device = Arc::new(Mutex::new(DummyDevice));
-        assert!(bus.insert(device.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(device.clone(), 0x10, 0x10, "device").is_ok());
         assert!(bus.write(0x10, &data).is_ok());
         assert!(bus.read(0x10, &mut data).is_ok());
         assert_eq!(data, [1, 2, 3, 4]);
     }
 
+    #[test]
+    #[allow(clippy::redundant_clone)]
+    fn bus_update_range() {
+        let bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(ConstantDevice));
+        let other = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10, "dummy").is_ok());
+        assert!(bus.insert(other, 0x40, 0x10, "other").is_ok());
+
+        // Moving a BAR on top of another device must fail and leave the
+        // device reachable at its previous address.
+        let result = bus.update_range(0x10, 0x10, 0x38, 0x10);
+        assert!(matches!(
+            result,
+            Err(Error::Overlap { ref id, ref existing_id, .. }) if id == "dummy" && existing_id == "other"
+        ));
+        let mut values = [0, 0];
+        assert!(bus.read(0x12, &mut values).is_ok());
+        assert_eq!(values, [2, 3]);
+
+        // Moving a BAR to an address range partially overlapping with its
+        // current one is valid.
+        assert!(bus.update_range(0x10, 0x10, 0x18, 0x10).is_ok());
+        assert!(bus.read(0x10, &mut values).is_err());
+        assert!(bus.read(0x1a, &mut values).is_ok());
+        assert_eq!(values, [2, 3]);
+
+        assert!(matches!(
+            bus.update_range(0x10, 0x10, 0x60, 0x10),
+            Err(Error::MissingAddressRange)
+        ));
+        assert!(matches!(
+            bus.update_range(0x18, 0x10, 0x60, 0),
+            Err(Error::ZeroSizedRange)
+        ));
+    }
+
+    #[test]
+    fn bus_remove_by_device() {
+        let bus = Bus::new();
+        let dummy: Arc<Mutex<dyn BusDevice>> = Arc::new(Mutex::new(DummyDevice));
+        let other: Arc<Mutex<dyn BusDevice>> = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10, "dummy").is_ok());
+        assert!(bus.insert(dummy.clone(), 0x30, 0x10, "dummy").is_ok());
+        assert!(bus.insert(other.clone(), 0x50, 0x10, "other").is_ok());
+
+        assert!(bus.remove_by_device(&dummy).is_ok());
+        assert!(bus.read(0x10, &mut [0, 0]).is_err());
+        assert!(bus.read(0x30, &mut [0, 0]).is_err());
+        assert!(bus.read(0x50, &mut [0, 0]).is_ok());
+
+        // The freed ranges can be reused right away.
+        assert!(bus.insert(other.clone(), 0x10, 0x30, "other").is_ok());
+
+        // Stale entries left behind by a dropped device don't prevent the
+        // removal of other devices.
+        let stale: Arc<Mutex<dyn BusDevice>> = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(stale.clone(), 0x80, 0x10, "stale").is_ok());
+        drop(stale);
+        assert!(bus.remove_by_device(&other).is_ok());
+        assert!(bus.devices.read().unwrap().is_empty());
+    }
+
     #[test]
     fn bus_range_overlap() {
         let a = BusRange {
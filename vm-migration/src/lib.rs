@@ -13,14 +13,33 @@ use versionize::{VersionMap, Versionize};
 
 pub mod protocol;
 
-/// Global VMM version for versioning
-const MAJOR_VERSION: u16 = 16;
-const MINOR_VERSION: u16 = 0;
-const VMM_VERSION: u16 = MAJOR_VERSION << 12 | MINOR_VERSION & 0b1111;
+/// Version of the snapshot format, to be bumped whenever the state of a
+/// component changes. It is recorded in every snapshot so that the snapshots
+/// taken by previous releases can be converted when restored.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Oldest snapshot version that can still be restored.
+pub const MIN_SNAPSHOT_VERSION: u16 = 1;
+
+// Snapshots taken before the version was recorded.
+fn legacy_snapshot_version() -> u16 {
+    1
+}
+
+/// Builds a version map with one root version per snapshot version, which
+/// the components changing their versioned state extend with their own
+/// type versions.
+pub fn snapshot_version_map() -> VersionMap {
+    let mut version_map = VersionMap::new();
+    for _ in 1..SNAPSHOT_VERSION {
+        version_map.new_version();
+    }
+    version_map
+}
 
 pub trait VersionMapped {
     fn version_map() -> VersionMap {
-        VersionMap::new()
+        snapshot_version_map()
     }
 }
 
@@ -86,17 +105,30 @@ impl SnapshotDataSection {
         })
     }
 
-    /// Generate versioned state
-    pub fn to_versioned_state<T>(&self) -> Result<T, MigratableError>
+    /// Generate versioned state from the snapshot data, serialized by the
+    /// snapshot `version`.
+    pub fn to_versioned_state<T>(&self, version: u16) -> Result<T, MigratableError>
     where
         T: Versionize + VersionMapped,
     {
-        T::deserialize(
-            &mut self.snapshot.as_slice(),
-            &T::version_map(),
-            VMM_VERSION,
-        )
-        .map_err(|e| MigratableError::Restore(anyhow!("Error deserialising: {} {}", self.id, e)))
+        T::deserialize(&mut self.snapshot.as_slice(), &T::version_map(), version).map_err(|e| {
+            MigratableError::Restore(anyhow!("Error deserialising: {} {}", self.id, e))
+        })
+    }
+
+    /// Update the serialized state in place, letting a snapshot taken by a
+    /// previous release be converted before being restored.
+    pub fn update_state<F>(&mut self, f: F) -> Result<(), MigratableError>
+    where
+        F: FnOnce(&mut serde_json::Value),
+    {
+        let mut state: serde_json::Value = self.to_state()?;
+        f(&mut state);
+        self.snapshot = serde_json::to_vec(&state).map_err(|e| {
+            MigratableError::Restore(anyhow!("Error serialising: {} {}", self.id, e))
+        })?;
+
+        Ok(())
     }
 
     /// Create from state that can be serialized
@@ -122,7 +154,7 @@ impl SnapshotDataSection {
     {
         let mut snapshot = Vec::new();
         state
-            .serialize(&mut snapshot, &T::version_map(), VMM_VERSION)
+            .serialize(&mut snapshot, &T::version_map(), SNAPSHOT_VERSION)
             .map_err(|e| MigratableError::Snapshot(anyhow!("Error serialising: {} {}", id, e)))?;
 
         let snapshot_data = SnapshotDataSection {
@@ -150,6 +182,10 @@ pub struct Snapshot {
     /// The Snapshottable component id.
     pub id: String,
 
+    /// The version of the snapshot format.
+    #[serde(default = "legacy_snapshot_version")]
+    pub version: u16,
+
     /// The Snapshottable component snapshots.
     pub snapshots: std::collections::BTreeMap<String, Box<Snapshot>>,
 
@@ -163,6 +199,7 @@ impl Snapshot {
     pub fn new(id: &str) -> Self {
         Snapshot {
             id: id.to_string(),
+            version: SNAPSHOT_VERSION,
             ..Default::default()
         }
     }
@@ -219,7 +256,7 @@ impl Snapshot {
         self.snapshot_data
             .get(&format!("{}-section", id))
             .ok_or_else(|| MigratableError::Restore(anyhow!("Missing section for {}", id)))?
-            .to_versioned_state()
+            .to_versioned_state(self.version)
     }
 }
 
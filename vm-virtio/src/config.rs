@@ -0,0 +1,213 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a helper for virtio device specific configuration spaces
+
+use std::fmt::{self, Display};
+use std::ops::Range;
+use std::result;
+use vm_memory::ByteValued;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Access going beyond the end of the configuration space.
+    OutOfBounds(u64, usize),
+    /// Driver write to a field that is read-only.
+    ReadOnlyField(u64, usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            OutOfBounds(offset, len) => write!(
+                f,
+                "out-of-bound access to configuration: offset 0x{:x} length {}",
+                offset, len
+            ),
+            ReadOnlyField(offset, len) => write!(
+                f,
+                "attempt to write to read-only field: offset 0x{:x} length {}",
+                offset, len
+            ),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Device specific configuration space.
+///
+/// Wraps the configuration structure of a device so that bound checking and
+/// the list of fields the driver is allowed to write don't have to be handled
+/// by each device. Multi-byte fields are exposed with the byte order of the
+/// host, which matches the little endian layout required by virtio 1.0 on all
+/// supported architectures.
+///
+/// Updates performed by the device itself must go through `update()`, which
+/// bumps the configuration generation whenever the content changes, so that
+/// the driver can detect it raced with the device while reading the fields.
+pub struct VirtioConfig<T: ByteValued> {
+    config: T,
+    writable: Vec<Range<u64>>,
+    generation: u8,
+}
+
+impl<T: ByteValued> VirtioConfig<T> {
+    /// Creates a configuration space where every field is read-only for the
+    /// driver.
+    pub fn new(config: T) -> Self {
+        VirtioConfig {
+            config,
+            writable: Vec::new(),
+            generation: 0,
+        }
+    }
+
+    /// Allows the driver to write the field located at `offset` and spanning
+    /// over `len` bytes.
+    pub fn with_writable_field(mut self, offset: u64, len: usize) -> Self {
+        self.writable.push(offset..offset + len as u64);
+        self
+    }
+
+    /// Returns the typed configuration.
+    pub fn get(&self) -> &T {
+        &self.config
+    }
+
+    /// Returns the current configuration generation.
+    pub fn generation(&self) -> u8 {
+        self.generation
+    }
+
+    /// Reads `data` from the configuration space at `offset`.
+    pub fn read(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let range = self.check_range(offset, data.len())?;
+        data.copy_from_slice(&self.config.as_slice()[range]);
+        Ok(())
+    }
+
+    /// Writes `data` coming from the driver at `offset`.
+    ///
+    /// The access must fit entirely inside one of the writable fields.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let range = self.check_range(offset, data.len())?;
+        let end = offset + data.len() as u64;
+        if !self
+            .writable
+            .iter()
+            .any(|field| field.start <= offset && end <= field.end)
+        {
+            return Err(Error::ReadOnlyField(offset, data.len()));
+        }
+
+        self.config.as_mut_slice()[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Applies an update coming from the device.
+    ///
+    /// Returns true if the configuration content changed, meaning the driver
+    /// must be notified through a configuration change interrupt.
+    pub fn update<F: FnOnce(&mut T)>(&mut self, f: F) -> bool {
+        let previous = self.config;
+        f(&mut self.config);
+
+        let changed = previous.as_slice() != self.config.as_slice();
+        if changed {
+            self.generation = self.generation.wrapping_add(1);
+        }
+
+        changed
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<Range<usize>> {
+        let config_len = std::mem::size_of::<T>() as u64;
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= config_len => Ok(offset as usize..end as usize),
+            _ => Err(Error::OutOfBounds(offset, len)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default)]
+    struct TestConfig {
+        ro: u32,
+        rw: u32,
+    }
+
+    // Safe because it only has data and has no implicit padding.
+    unsafe impl ByteValued for TestConfig {}
+
+    fn create_config() -> VirtioConfig<TestConfig> {
+        VirtioConfig::new(TestConfig { ro: 0x1234, rw: 0 }).with_writable_field(4, 4)
+    }
+
+    #[test]
+    fn test_config_read() {
+        let config = create_config();
+
+        let mut data = [0u8; 4];
+        config.read(0, &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x1234);
+
+        let mut data = [0u8; 2];
+        config.read(1, &mut data).unwrap();
+        assert_eq!(data, [0x12, 0]);
+
+        assert!(config.read(6, &mut [0u8; 4]).is_err());
+        assert!(config.read(u64::MAX, &mut [0u8; 1]).is_err());
+    }
+
+    #[test]
+    fn test_config_write() {
+        let mut config = create_config();
+
+        config.write(4, &0x5678u32.to_le_bytes()).unwrap();
+        assert_eq!(config.get().rw, 0x5678);
+        config.write(5, &[0xaa]).unwrap();
+        assert_eq!(config.get().rw, 0xaa78);
+
+        assert!(matches!(
+            config.write(0, &[0u8; 4]),
+            Err(Error::ReadOnlyField(0, 4))
+        ));
+        assert!(matches!(
+            config.write(2, &[0u8; 4]),
+            Err(Error::ReadOnlyField(2, 4))
+        ));
+        assert!(matches!(
+            config.write(6, &[0u8; 4]),
+            Err(Error::OutOfBounds(6, 4))
+        ));
+        assert_eq!(config.get().ro, 0x1234);
+
+        // Driver writes don't change the generation.
+        assert_eq!(config.generation(), 0);
+    }
+
+    #[test]
+    fn test_config_update() {
+        let mut config = create_config();
+
+        assert!(config.update(|c| c.ro = 0x4321));
+        assert_eq!(config.get().ro, 0x4321);
+        assert_eq!(config.generation(), 1);
+
+        assert!(!config.update(|c| c.ro = 0x4321));
+        assert_eq!(config.generation(), 1);
+
+        for _ in 0..255 {
+            config.update(|c| c.ro += 1);
+        }
+        assert_eq!(config.generation(), 0);
+    }
+}
@@ -15,7 +15,11 @@ extern crate log;
 
 use std::fmt;
 
+pub mod config;
+pub mod notification;
 pub mod queue;
+pub use config::VirtioConfig;
+pub use notification::NotificationSuppression;
 pub use queue::*;
 
 pub type VirtioIommuRemapping =
@@ -0,0 +1,256 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Suppression of the notifications between the driver and the device.
+//!
+//! With VIRTIO_F_RING_EVENT_IDX, each side tells the other which index of
+//! its ring it wants to be notified at. A device has to follow two rules for
+//! this to neither lose a notification nor send spurious ones:
+//!
+//! - the driver is signalled once per batch of used buffers, if the batch
+//!   went past the used_event the driver published,
+//! - once the available ring is exhausted, the driver is asked to notify the
+//!   next buffer through avail_event, and the ring is looked at again, as the
+//!   buffers made available before the driver could see the new avail_event
+//!   won't be notified.
+
+use crate::queue::Queue;
+use std::num::Wrapping;
+use vm_memory::GuestMemory;
+
+/// Tracks a batch of used buffers, processing a queue as:
+///
+/// ```ignore
+/// loop {
+///     while let Some(desc) = queue.iter(mem).next() {
+///         notification.add_used(queue, mem, desc.index, len);
+///     }
+///     if !notification.enable_notification(queue, mem) {
+///         break;
+///     }
+/// }
+/// if notification.needs_notification(queue, mem) {
+///     // Signal the used queue
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct NotificationSuppression {
+    // Whether buffers were used since the driver was last signalled.
+    used: bool,
+}
+
+impl NotificationSuppression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts a buffer into the used ring, the driver being signalled once the
+    /// batch is over.
+    pub fn add_used<M: GuestMemory>(
+        &mut self,
+        queue: &mut Queue,
+        mem: &M,
+        desc_index: u16,
+        len: u32,
+    ) {
+        if queue.add_used(mem, desc_index, len).is_some() {
+            self.used = true;
+        }
+    }
+
+    /// Asks the driver to notify the next buffer it makes available, once
+    /// the available ring has been exhausted. Returns whether buffers were
+    /// made available in the meantime, which have to be processed since the
+    /// driver may not notify them.
+    pub fn enable_notification<M: GuestMemory>(&self, queue: &mut Queue, mem: &M) -> bool {
+        if queue.event_idx() {
+            queue.set_avail_event(mem, queue.next_avail);
+        }
+
+        match queue.avail_index_from_memory(mem) {
+            Ok(avail_index) => Wrapping(avail_index) != queue.next_avail,
+            Err(e) => {
+                warn!("Can't read the available ring index: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Ends the batch, returning whether the driver must be signalled.
+    pub fn needs_notification<M: GuestMemory>(&mut self, queue: &mut Queue, mem: &M) -> bool {
+        if !self.used {
+            return false;
+        }
+        self.used = false;
+
+        let next_used = queue.next_used;
+        queue.needs_notification(mem, next_used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::testing::VirtQueue;
+    use std::sync::atomic::{fence, Ordering};
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::thread;
+    use std::time::Duration;
+    use vm_memory::{bitmap::AtomicBitmap, GuestAddress};
+
+    type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+    const QUEUE_SIZE: u16 = 16;
+    const BUFFERS: u32 = 100_000;
+    const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Tells whether an index going from `old` to `new` went past `event`,
+    // as vring_need_event() does.
+    fn need_event(event: u16, new: u16, old: u16) -> bool {
+        new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+    }
+
+    // A driver and a device exchange buffers from two threads, notifying
+    // each other through EVENT_IDX only. A lost notification stalls them.
+    fn run_stress(event_idx: bool) -> (u32, u32) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        for (i, desc) in vq.dtable.iter().enumerate() {
+            desc.set(0x8000 + i as u64 * 0x100, 0x100, 0, 0);
+        }
+        let mut queue = vq.create_queue();
+        queue.set_event_idx(event_idx);
+
+        let (kick_sender, kick_receiver) = channel::<()>();
+        let (irq_sender, irq_receiver) = channel::<()>();
+        let device_mem = mem.clone();
+        let device = thread::spawn(move || {
+            let mem = device_mem;
+            let mut notification = NotificationSuppression::new();
+            let mut interrupts = 0;
+            while kick_receiver.recv().is_ok() {
+                loop {
+                    while let Some(desc) = queue.iter(&mem).next() {
+                        notification.add_used(&mut queue, &mem, desc.index, 0);
+                    }
+                    if !notification.enable_notification(&mut queue, &mem) {
+                        break;
+                    }
+                }
+                if notification.needs_notification(&mut queue, &mem) {
+                    interrupts += 1;
+                    if irq_sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            }
+            interrupts
+        });
+
+        let mut free: Vec<u16> = (0..QUEUE_SIZE).collect();
+        let mut avail_idx = 0u16;
+        let mut last_used = 0u16;
+        let (mut submitted, mut completed, mut kicks) = (0, 0, 0);
+        let mut seed = 0x2545_f491u32;
+        while completed < BUFFERS {
+            let used_idx = vq.used.idx.get();
+            fence(Ordering::Acquire);
+            while last_used != used_idx {
+                let elem = vq.used.ring[(last_used % QUEUE_SIZE) as usize].get();
+                free.push(elem.id as u16);
+                last_used = last_used.wrapping_add(1);
+                completed += 1;
+            }
+
+            if !free.is_empty() && submitted < BUFFERS {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let burst = 1 + seed as usize % free.len();
+                let burst = std::cmp::min(burst, (BUFFERS - submitted) as usize);
+                let old = avail_idx;
+                for id in free.drain(..burst) {
+                    vq.avail.ring[(avail_idx % QUEUE_SIZE) as usize].set(id);
+                    avail_idx = avail_idx.wrapping_add(1);
+                }
+                submitted += burst as u32;
+                fence(Ordering::SeqCst);
+                vq.avail.idx.set(avail_idx);
+                fence(Ordering::SeqCst);
+                if !event_idx || need_event(vq.used.event.get(), avail_idx, old) {
+                    kicks += 1;
+                    kick_sender.send(()).unwrap();
+                }
+                continue;
+            }
+
+            // Nothing left to submit, ask to be signalled at the next used
+            // buffer and look at the used ring again before waiting.
+            vq.avail.event.set(last_used);
+            fence(Ordering::SeqCst);
+            if vq.used.idx.get() != last_used {
+                continue;
+            }
+            match irq_receiver.recv_timeout(STALL_TIMEOUT) {
+                Ok(()) => {}
+                Err(RecvTimeoutError::Timeout) => panic!(
+                    "Stalled with {} buffers submitted and {} completed",
+                    submitted, completed
+                ),
+                Err(RecvTimeoutError::Disconnected) => panic!("Device thread is gone"),
+            }
+        }
+
+        drop(kick_sender);
+        let interrupts = device.join().unwrap();
+        (kicks, interrupts)
+    }
+
+    #[test]
+    fn test_need_event() {
+        assert!(need_event(5, 6, 5));
+        assert!(!need_event(6, 6, 5));
+        assert!(need_event(5, 8, 2));
+        assert!(!need_event(1, 8, 2));
+        // Wrapping indexes
+        assert!(need_event(0xffff, 2, 0xfffe));
+        assert!(!need_event(3, 2, 0xfffe));
+    }
+
+    #[test]
+    fn test_notification_suppression_stress() {
+        let (kicks, interrupts) = run_stress(true);
+        // Every buffer would be notified both ways otherwise.
+        assert!(kicks < BUFFERS);
+        assert!(interrupts < BUFFERS);
+    }
+
+    #[test]
+    fn test_notification_without_event_idx() {
+        let (kicks, interrupts) = run_stress(false);
+        assert!(kicks > 0);
+        assert!(interrupts > 0);
+    }
+
+    #[test]
+    fn test_enable_notification() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let mut queue = vq.create_queue();
+        queue.set_event_idx(true);
+        let notification = NotificationSuppression::new();
+
+        assert!(!notification.enable_notification(&mut queue, &mem));
+        assert_eq!(vq.used.event.get(), 0);
+
+        // A buffer made available after the ring was exhausted, before the
+        // driver could see the avail_event.
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        assert!(notification.enable_notification(&mut queue, &mem));
+        assert_eq!(queue.iter(&mem).count(), 1);
+        assert!(!notification.enable_notification(&mut queue, &mem));
+        assert_eq!(vq.used.event.get(), 1);
+    }
+}
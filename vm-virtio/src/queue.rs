@@ -59,24 +59,24 @@ impl Display for Error {
 
 /// An iterator over a single descriptor chain.  Not to be confused with AvailIter,
 /// which iterates over the descriptor chain heads in a queue.
-pub struct DescIter<'a> {
-    next: Option<DescriptorChain<'a>>,
+pub struct DescIter<'a, M: GuestMemory = GuestMemoryMmap> {
+    next: Option<DescriptorChain<'a, M>>,
 }
 
-impl<'a> DescIter<'a> {
+impl<'a, M: GuestMemory> DescIter<'a, M> {
     /// Returns an iterator that only yields the readable descriptors in the chain.
-    pub fn readable(self) -> impl Iterator<Item = DescriptorChain<'a>> {
+    pub fn readable(self) -> impl Iterator<Item = DescriptorChain<'a, M>> {
         self.filter(|d| !d.is_write_only())
     }
 
     /// Returns an iterator that only yields the writable descriptors in the chain.
-    pub fn writable(self) -> impl Iterator<Item = DescriptorChain<'a>> {
+    pub fn writable(self) -> impl Iterator<Item = DescriptorChain<'a, M>> {
         self.filter(DescriptorChain::is_write_only)
     }
 }
 
-impl<'a> Iterator for DescIter<'a> {
-    type Item = DescriptorChain<'a>;
+impl<'a, M: GuestMemory> Iterator for DescIter<'a, M> {
+    type Item = DescriptorChain<'a, M>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(current) = self.next.take() {
@@ -100,7 +100,7 @@ pub struct Descriptor {
 
 unsafe impl ByteValued for Descriptor {}
 
-/// A virtio descriptor head, not tied to a GuestMemoryMmap.
+/// A virtio descriptor head, not tied to any guest memory.
 pub struct DescriptorHead {
     desc_table: GuestAddress,
     table_size: u16,
@@ -108,18 +108,23 @@ pub struct DescriptorHead {
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
 }
 
-/// A virtio descriptor chain.
-#[derive(Clone)]
-pub struct DescriptorChain<'a> {
+/// A virtio descriptor chain, accessed through any implementation of the
+/// `GuestMemory` trait. Besides the guest RAM mmapped by the VMM, this can be
+/// a view of the memory the guest shares with the device, such as the bounce
+/// buffers of an encrypted guest.
+pub struct DescriptorChain<'a, M: GuestMemory = GuestMemoryMmap> {
     desc_table: GuestAddress,
     table_size: u16,
-    ttl: u16, // used to prevent infinite chain cycles
+    ttl: u16,       // used to prevent infinite chain cycles
+    indirect: bool, // whether desc_table is an indirect table
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
 
     /// Reference to guest memory
-    pub mem: &'a GuestMemoryMmap,
+    pub mem: &'a M,
 
-    /// Index into the descriptor table
+    /// Index into the descriptor table. For a chain head returned by
+    /// `AvailIter`, this is the index the used ring refers to the chain with,
+    /// even when the chain is held by an indirect table.
     pub index: u16,
 
     /// Guest physical address of device specific data
@@ -136,14 +141,33 @@ pub struct DescriptorChain<'a> {
     pub next: u16,
 }
 
-impl<'a> DescriptorChain<'a> {
+// Not derived, the memory itself doesn't have to be Clone.
+impl<'a, M: GuestMemory> Clone for DescriptorChain<'a, M> {
+    fn clone(&self) -> Self {
+        DescriptorChain {
+            desc_table: self.desc_table,
+            table_size: self.table_size,
+            ttl: self.ttl,
+            indirect: self.indirect,
+            iommu_mapping_cb: self.iommu_mapping_cb.clone(),
+            mem: self.mem,
+            index: self.index,
+            addr: self.addr,
+            len: self.len,
+            flags: self.flags,
+            next: self.next,
+        }
+    }
+}
+
+impl<'a, M: GuestMemory> DescriptorChain<'a, M> {
     pub fn checked_new(
-        mem: &GuestMemoryMmap,
+        mem: &'a M,
         desc_table: GuestAddress,
         table_size: u16,
         index: u16,
         iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
-    ) -> Option<DescriptorChain> {
+    ) -> Option<DescriptorChain<'a, M>> {
         if index >= table_size {
             return None;
         }
@@ -176,6 +200,7 @@ impl<'a> DescriptorChain<'a> {
             desc_table,
             table_size,
             ttl: table_size,
+            indirect: false,
             index,
             addr: GuestAddress(desc_addr),
             len: desc.len,
@@ -191,10 +216,20 @@ impl<'a> DescriptorChain<'a> {
         }
     }
 
-    pub fn new_from_indirect(&self) -> Result<DescriptorChain, Error> {
-        if !self.is_indirect() {
+    /// Returns the chain held by the table this indirect descriptor points to.
+    pub fn new_from_indirect(&self) -> Result<DescriptorChain<'a, M>, Error> {
+        // An indirect descriptor can't be chained, and its table must hold
+        // a whole number of descriptors.
+        if !self.is_indirect()
+            || self.flags & VIRTQ_DESC_F_NEXT != 0
+            || self.len == 0
+            || self.len % 16 != 0
+        {
             return Err(Error::InvalidIndirectDescriptor);
         }
+        let table_size: u16 = (self.len / 16)
+            .try_into()
+            .map_err(|_| Error::InvalidIndirectDescriptor)?;
 
         let desc_head = self.addr;
         self.mem
@@ -221,8 +256,9 @@ impl<'a> DescriptorChain<'a> {
         let chain = DescriptorChain {
             mem: self.mem,
             desc_table: self.addr,
-            table_size: (self.len / 16).try_into().unwrap(),
-            ttl: (self.len / 16).try_into().unwrap(),
+            table_size,
+            ttl: table_size,
+            indirect: true,
             index: 0,
             addr: GuestAddress(desc_addr),
             len: desc.len,
@@ -231,6 +267,11 @@ impl<'a> DescriptorChain<'a> {
             iommu_mapping_cb,
         };
 
+        // An indirect table can't point to another one.
+        if chain.is_indirect() {
+            return Err(Error::InvalidIndirectDescriptor);
+        }
+
         if !chain.is_valid() {
             return Err(Error::InvalidChain);
         }
@@ -238,11 +279,11 @@ impl<'a> DescriptorChain<'a> {
         Ok(chain)
     }
 
-    /// Returns a copy of a descriptor referencing a different GuestMemoryMmap object.
+    /// Returns a copy of a descriptor referencing a different guest memory object.
     pub fn new_from_head(
-        mem: &'a GuestMemoryMmap,
+        mem: &'a M,
         head: DescriptorHead,
-    ) -> Result<DescriptorChain<'a>, Error> {
+    ) -> Result<DescriptorChain<'a, M>, Error> {
         match DescriptorChain::checked_new(
             mem,
             head.desc_table,
@@ -256,7 +297,7 @@ impl<'a> DescriptorChain<'a> {
     }
 
     /// Returns a DescriptorHead that can be used to build a copy of a descriptor
-    /// referencing a different GuestMemoryMmap.
+    /// referencing a different guest memory object.
     pub fn get_head(&self) -> DescriptorHead {
         DescriptorHead {
             desc_table: self.desc_table,
@@ -290,30 +331,53 @@ impl<'a> DescriptorChain<'a> {
 
     /// Gets the next descriptor in this descriptor chain, if there is one.
     ///
+    /// The last descriptor of a chain may point to an indirect table, in
+    /// which case the first descriptor of the table is returned in its place.
+    ///
     /// Note that this is distinct from the next descriptor chain returned by `AvailIter`, which is
     /// the head of the next _available_ descriptor chain.
-    pub fn next_descriptor(&self) -> Option<DescriptorChain<'a>> {
-        if self.has_next() {
-            DescriptorChain::checked_new(
-                self.mem,
-                self.desc_table,
-                self.table_size,
-                self.next,
-                self.iommu_mapping_cb.clone(),
-            )
-            .map(|mut c| {
-                c.ttl = self.ttl - 1;
-                c
-            })
-        } else {
-            None
+    pub fn next_descriptor(&self) -> Option<DescriptorChain<'a, M>> {
+        if !self.has_next() {
+            return None;
+        }
+
+        let next = DescriptorChain::checked_new(
+            self.mem,
+            self.desc_table,
+            self.table_size,
+            self.next,
+            self.iommu_mapping_cb.clone(),
+        )
+        .map(|mut c| {
+            c.ttl = self.ttl - 1;
+            c.indirect = self.indirect;
+            c
+        })?;
+        if !next.is_indirect() {
+            return Some(next);
+        }
+
+        // A chain can only go through a single indirect table.
+        if self.indirect {
+            error!(
+                "Indirect descriptor {} within an indirect table",
+                next.index
+            );
+            return None;
+        }
+        match next.new_from_indirect() {
+            Ok(chain) => Some(chain),
+            Err(e) => {
+                error!("Invalid indirect descriptor {}: {}", next.index, e);
+                None
+            }
         }
     }
 }
 
-impl<'a> IntoIterator for DescriptorChain<'a> {
-    type Item = DescriptorChain<'a>;
-    type IntoIter = DescIter<'a>;
+impl<'a, M: GuestMemory> IntoIterator for DescriptorChain<'a, M> {
+    type Item = DescriptorChain<'a, M>;
+    type IntoIter = DescIter<'a, M>;
 
     fn into_iter(self) -> Self::IntoIter {
         DescIter { next: Some(self) }
@@ -321,8 +385,8 @@ impl<'a> IntoIterator for DescriptorChain<'a> {
 }
 
 /// Consuming iterator over all available descriptor chain heads in the queue.
-pub struct AvailIter<'a, 'b> {
-    mem: &'a GuestMemoryMmap,
+pub struct AvailIter<'a, 'b, M: GuestMemory = GuestMemoryMmap> {
+    mem: &'a M,
     desc_table: GuestAddress,
     avail_ring: GuestAddress,
     next_index: Wrapping<u16>,
@@ -332,8 +396,8 @@ pub struct AvailIter<'a, 'b> {
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
 }
 
-impl<'a, 'b> AvailIter<'a, 'b> {
-    pub fn new(mem: &'a GuestMemoryMmap, q_next_avail: &'b mut Wrapping<u16>) -> AvailIter<'a, 'b> {
+impl<'a, 'b, M: GuestMemory> AvailIter<'a, 'b, M> {
+    pub fn new(mem: &'a M, q_next_avail: &'b mut Wrapping<u16>) -> AvailIter<'a, 'b, M> {
         AvailIter {
             mem,
             desc_table: GuestAddress(0),
@@ -347,8 +411,8 @@ impl<'a, 'b> AvailIter<'a, 'b> {
     }
 }
 
-impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
-    type Item = DescriptorChain<'a>;
+impl<'a, 'b, M: GuestMemory> Iterator for AvailIter<'a, 'b, M> {
+    type Item = DescriptorChain<'a, M>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_index == self.last_index {
@@ -378,7 +442,24 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
             self.queue_size,
             desc_index,
             self.iommu_mapping_cb.clone(),
-        );
+        )
+        .and_then(|head| {
+            if !head.is_indirect() {
+                return Some(head);
+            }
+            // The chain is returned in place of the descriptor pointing to
+            // it, so that devices don't have to care about indirect tables.
+            match head.new_from_indirect() {
+                Ok(mut chain) => {
+                    chain.index = head.index;
+                    Some(chain)
+                }
+                Err(e) => {
+                    error!("Invalid indirect descriptor {}: {}", head.index, e);
+                    None
+                }
+            }
+        });
         if ret.is_some() {
             *self.next_avail += Wrapping(1);
         }
@@ -485,7 +566,7 @@ impl Queue {
         self.signalled_used = None;
     }
 
-    pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
+    pub fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
         let queue_size = self.actual_size() as usize;
         let desc_table = self.desc_table;
         let desc_table_size = 16 * queue_size;
@@ -545,7 +626,7 @@ impl Queue {
     }
 
     /// A consuming iterator over all available descriptor chain heads offered by the driver.
-    pub fn iter<'a, 'b>(&'b mut self, mem: &'a GuestMemoryMmap) -> AvailIter<'a, 'b> {
+    pub fn iter<'a, 'b, M: GuestMemory>(&'b mut self, mem: &'a M) -> AvailIter<'a, 'b, M> {
         let queue_size = self.actual_size();
         let avail_ring = self.avail_ring;
 
@@ -576,7 +657,7 @@ impl Queue {
     }
 
     /// Update avail_event on the used ring with the last index in the avail ring.
-    pub fn update_avail_event(&mut self, mem: &GuestMemoryMmap) {
+    pub fn update_avail_event<M: GuestMemory>(&mut self, mem: &M) {
         let index_addr = match mem.checked_offset(self.avail_ring, 2) {
             Some(ret) => ret,
             None => {
@@ -591,9 +672,15 @@ impl Queue {
             Err(_) => return,
         };
 
+        self.set_avail_event(mem, Wrapping(last_index));
+    }
+
+    /// Asks the driver to notify the device once the available ring goes
+    /// past `avail_event`, when using EVENT_IDX.
+    pub fn set_avail_event<M: GuestMemory>(&self, mem: &M, avail_event: Wrapping<u16>) {
         match mem.checked_offset(self.used_ring, (4 + self.actual_size() * 8) as usize) {
             Some(a) => {
-                mem.write_obj(last_index, a).unwrap();
+                mem.write_obj(avail_event.0, a).unwrap();
             }
             None => warn!("Can't update avail_event"),
         }
@@ -604,7 +691,7 @@ impl Queue {
 
     /// Return the value present in the used_event field of the avail ring.
     #[inline(always)]
-    pub fn get_used_event(&self, mem: &GuestMemoryMmap) -> Option<Wrapping<u16>> {
+    pub fn get_used_event<M: GuestMemory>(&self, mem: &M) -> Option<Wrapping<u16>> {
         let avail_ring = self.avail_ring;
         let used_event_addr =
             match mem.checked_offset(avail_ring, (4 + self.actual_size() * 2) as usize) {
@@ -624,7 +711,7 @@ impl Queue {
     }
 
     /// Puts an available descriptor head into the used ring for use by the guest.
-    pub fn add_used(&mut self, mem: &GuestMemoryMmap, desc_index: u16, len: u32) -> Option<u16> {
+    pub fn add_used<M: GuestMemory>(&mut self, mem: &M, desc_index: u16, len: u32) -> Option<u16> {
         if desc_index >= self.actual_size() {
             error!(
                 "attempted to add out of bounds descriptor to used ring: {}",
@@ -661,7 +748,7 @@ impl Queue {
     }
 
     /// Get ring's index from memory.
-    fn index_from_memory(&self, ring: GuestAddress, mem: &GuestMemoryMmap) -> Result<u16, Error> {
+    fn index_from_memory<M: GuestMemory>(&self, ring: GuestAddress, mem: &M) -> Result<u16, Error> {
         mem.read_obj::<u16>(
             mem.checked_offset(ring, 2)
                 .ok_or_else(|| Error::InvalidOffset(ring.raw_value() + 2))?,
@@ -670,26 +757,30 @@ impl Queue {
     }
 
     /// Get latest index from available ring.
-    pub fn avail_index_from_memory(&self, mem: &GuestMemoryMmap) -> Result<u16, Error> {
+    pub fn avail_index_from_memory<M: GuestMemory>(&self, mem: &M) -> Result<u16, Error> {
         self.index_from_memory(self.avail_ring, mem)
     }
 
     /// Get latest index from used ring.
-    pub fn used_index_from_memory(&self, mem: &GuestMemoryMmap) -> Result<u16, Error> {
+    pub fn used_index_from_memory<M: GuestMemory>(&self, mem: &M) -> Result<u16, Error> {
         self.index_from_memory(self.used_ring, mem)
     }
 
-    pub fn available_descriptors(&self, mem: &GuestMemoryMmap) -> Result<bool, Error> {
+    pub fn available_descriptors<M: GuestMemory>(&self, mem: &M) -> Result<bool, Error> {
         Ok(self.used_index_from_memory(mem)? < self.avail_index_from_memory(mem)?)
     }
 
+    pub fn event_idx(&self) -> bool {
+        self.event_idx
+    }
+
     pub fn set_event_idx(&mut self, enabled: bool) {
         /* Also reset the last signalled event */
         self.signalled_used = None;
         self.event_idx = enabled;
     }
 
-    pub fn needs_notification(&mut self, mem: &GuestMemoryMmap, used_idx: Wrapping<u16>) -> bool {
+    pub fn needs_notification<M: GuestMemory>(&mut self, mem: &M, used_idx: Wrapping<u16>) -> bool {
         if !self.event_idx {
             return true;
         }
@@ -1029,6 +1120,44 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_descriptor_chain_generic_memory() {
+        // Any GuestMemory implementation, here one not tracking dirty pages.
+        let m =
+            &vm_memory::GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        let descs = [
+            Descriptor {
+                addr: 0x1000,
+                len: 0x100,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: 1,
+            },
+            Descriptor {
+                addr: 0x2000,
+                len: 0x200,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            m.write_obj(*desc, GuestAddress(i as u64 * 16)).unwrap();
+        }
+
+        let c = DescriptorChain::checked_new(m, GuestAddress(0), 16, 0, None).unwrap();
+        let chain: Vec<(GuestAddress, u32, bool)> = c
+            .into_iter()
+            .map(|d| (d.addr, d.len, d.is_write_only()))
+            .collect();
+        assert_eq!(
+            chain,
+            vec![
+                (GuestAddress(0x1000), 0x100, false),
+                (GuestAddress(0x2000), 0x200, true)
+            ]
+        );
+    }
+
     #[test]
     fn test_new_from_descriptor_chain() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1165,6 +1294,86 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_indirect_chain_iterator() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        // the chain (0, 1) is held by an indirect table at 0x2000, pointed
+        // to by descriptor 3
+        let indirect_table = [
+            VirtqDesc::new(GuestAddress(0x2000), m),
+            VirtqDesc::new(GuestAddress(0x2010), m),
+        ];
+        indirect_table[0].set(0x3000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        indirect_table[1].set(0x4000, 0x1000, 0, 0);
+        vq.dtable[3].set(0x2000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.avail.ring[0].set(3);
+        vq.avail.idx.set(1);
+
+        {
+            let c = q.iter(m).next().unwrap();
+            // the head is the one of the indirect chain, but the used ring
+            // still refers to the chain through descriptor 3
+            assert_eq!(c.index, 3);
+            assert_eq!(c.addr, GuestAddress(0x3000));
+            let c = c.next_descriptor().unwrap();
+            assert_eq!(c.addr, GuestAddress(0x4000));
+            assert!(!c.has_next());
+        }
+
+        // an indirect table holding no descriptor is invalid
+        q.go_to_previous_position();
+        vq.dtable[3].len.set(0);
+        assert!(q.iter(m).next().is_none());
+
+        // and so is an indirect table pointing to another one
+        vq.dtable[3].len.set(32);
+        indirect_table[0].flags.set(VIRTQ_DESC_F_INDIRECT);
+        assert!(q.iter(m).next().is_none());
+    }
+
+    #[test]
+    fn test_indirect_chain_tail() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        // descriptor 0 is followed by descriptor 1, the last of the chain,
+        // which points to an indirect table at 0x2000 holding (0, 1)
+        let indirect_table = [
+            VirtqDesc::new(GuestAddress(0x2000), m),
+            VirtqDesc::new(GuestAddress(0x2010), m),
+        ];
+        indirect_table[0].set(0x3000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        indirect_table[1].set(0x4000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        vq.dtable[0].set(0x1000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        {
+            let c = q.iter(m).next().unwrap();
+            assert_eq!(c.index, 0);
+            assert_eq!(c.addr, GuestAddress(0x1000));
+            let c = c.next_descriptor().unwrap();
+            assert_eq!(c.addr, GuestAddress(0x3000));
+            let c = c.next_descriptor().unwrap();
+            assert_eq!(c.addr, GuestAddress(0x4000));
+            assert!(c.is_write_only());
+            assert!(c.next_descriptor().is_none());
+        }
+
+        // an indirect table within an indirect table is rejected
+        indirect_table[0].set(0x2000, 32, VIRTQ_DESC_F_NEXT, 1);
+        indirect_table[1].set(0x2000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        q.go_to_previous_position();
+        let c = q.iter(m).next().unwrap();
+        let c = c.next_descriptor().unwrap();
+        assert!(c.next_descriptor().is_none());
+    }
+
     #[test]
     fn test_add_used() {
         let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
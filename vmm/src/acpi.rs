@@ -164,6 +164,31 @@ struct ViotPciRangeNode {
     _reserved2: [u8; 6],
 }
 
+#[cfg(target_arch = "x86_64")]
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default)]
+struct DmarHardwareUnit {
+    pub type_: u16,
+    pub length: u16,
+    pub flags: u8,
+    _reserved: u8,
+    pub segment: u16,
+    pub register_base_address: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default)]
+struct DmarDeviceScope {
+    pub type_: u8,
+    pub length: u8,
+    _reserved: u16,
+    pub enumeration_id: u8,
+    pub start_bus_number: u8,
+}
+
 pub fn create_dsdt_table(
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
@@ -471,6 +496,72 @@ fn create_viot_table(iommu_bdf: u32, devices_bdf: &[u32]) -> Sdt {
     viot
 }
 
+#[cfg(target_arch = "x86_64")]
+fn create_dmar_table(vtd_address: GuestAddress, devices_bdf: &[u32]) -> Sdt {
+    // DMAR
+    let mut dmar = Sdt::new(*b"DMAR", 36, 1, *b"CLOUDH", *b"CHDMAR  ", 1);
+    // Host address width, minus one
+    dmar.append(devices::vtd::VTD_HOST_ADDRESS_WIDTH - 1);
+    // No interrupt remapping
+    dmar.append(0u8);
+    // DMAR reserved 10 bytes
+    dmar.append_slice(&[0u8; 10]);
+
+    // Each device is reached through the bridges chaining the buses, the
+    // path starting from the root bus.
+    let scopes: Vec<Vec<u8>> = devices_bdf
+        .iter()
+        .map(|bdf| {
+            let bus = (bdf >> 8) as u8;
+            let mut path = vec![pci::BRIDGE_DEVICE_ID as u8, 0].repeat(bus as usize);
+            path.extend_from_slice(&[((bdf >> 3) & 0x1f) as u8, (bdf & 0x7) as u8]);
+            path
+        })
+        .collect();
+    let scopes_length: usize = scopes
+        .iter()
+        .map(|path| std::mem::size_of::<DmarDeviceScope>() + path.len())
+        .sum();
+
+    dmar.append(DmarHardwareUnit {
+        type_: 0,
+        length: (std::mem::size_of::<DmarHardwareUnit>() + scopes_length) as u16,
+        segment: 0,
+        register_base_address: vtd_address.0,
+        ..Default::default()
+    });
+
+    for path in scopes {
+        // PCI endpoint device
+        dmar.append(DmarDeviceScope {
+            type_: 1,
+            length: (std::mem::size_of::<DmarDeviceScope>() + path.len()) as u8,
+            enumeration_id: 0,
+            start_bus_number: 0,
+            ..Default::default()
+        });
+        dmar.append_slice(&path);
+    }
+
+    dmar
+}
+
+fn create_tpm2_table(tpm_address: GuestAddress) -> Sdt {
+    // TPM2
+    let mut tpm2 = Sdt::new(*b"TPM2", 64, 4, *b"CLOUDH", *b"CHTPM2  ", 1);
+    // Platform Class: Client
+    tpm2.write(36, 0u16);
+    // Address of the CRB Control Area
+    tpm2.write(
+        40,
+        (tpm_address.0 + devices::tpm::TPM_CRB_CONTROL_AREA_OFFSET).to_le(),
+    );
+    // Start Method: Command Response Buffer
+    tpm2.write(48, 7u32.to_le());
+
+    tpm2
+}
+
 pub fn create_acpi_tables(
     guest_mem: &GuestMemoryMmap,
     device_manager: &Arc<Mutex<DeviceManager>>,
@@ -614,6 +705,34 @@ pub fn create_acpi_tables(
         prev_tbl_off = viot_offset;
     }
 
+    // DMAR
+    #[cfg(target_arch = "x86_64")]
+    if let Some((vtd_address, devices_bdf)) = device_manager.lock().unwrap().vtd_attached_devices()
+    {
+        let dmar = create_dmar_table(*vtd_address, devices_bdf);
+
+        let dmar_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(dmar.as_slice(), dmar_offset)
+            .expect("Error writing DMAR table");
+        tables.push(dmar_offset.0);
+        prev_tbl_len = dmar.len() as u64;
+        prev_tbl_off = dmar_offset;
+    }
+
+    // TPM2
+    if let Some(tpm_address) = device_manager.lock().unwrap().tpm_address() {
+        let tpm2 = create_tpm2_table(tpm_address);
+
+        let tpm2_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(tpm2.as_slice(), tpm2_offset)
+            .expect("Error writing TPM2 table");
+        tables.push(tpm2_offset.0);
+        prev_tbl_len = tpm2.len() as u64;
+        prev_tbl_off = tpm2_offset;
+    }
+
     // XSDT
     let mut xsdt = Sdt::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
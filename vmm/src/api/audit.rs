@@ -0,0 +1,193 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit log of the REST API requests.
+//!
+//! Each request handled by the API server, together with the response sent
+//! back, is recorded as one JSON object per line. The values of the fields
+//! which might hold secrets are redacted before being written.
+//!
+//! The API server does not tell which connection a request comes from. When
+//! the audit log is set, the connections to the API socket and to the API
+//! vsock port are relayed to the API server one request at a time, so that
+//! each request is recorded with the credentials of the peer of its
+//! connection, or with its CID for a vsock one.
+
+use micro_http::{Body, Request, Response};
+use serde_json::Value;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Replaces the value of the redacted fields.
+pub const REDACTED: &str = "<redacted>";
+
+// The kernel command line is commonly used to pass credentials to the guest.
+const REDACTED_FIELDS: &[&str] = &["args", "password", "secret", "token"];
+
+/// Credentials of the peer connected to the API.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum PeerCredentials {
+    /// Process connected to the API socket
+    Unix { pid: i32, uid: u32, gid: u32 },
+    /// VM connected to the API vsock port
+    Vsock { cid: u32 },
+}
+
+struct AuditLog {
+    file: File,
+    enabled: bool,
+    peer: Option<PeerCredentials>,
+}
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+    // Serializes the requests relayed to the API server, so that the peer
+    // of the request being handled is known.
+    static ref EXCHANGE: Mutex<()> = Mutex::new(());
+}
+
+/// Sets the file the audit records are appended to, and enables the audit
+/// log. This must be called before the API server is started.
+pub fn set_audit_log(file: File) {
+    *AUDIT_LOG.lock().unwrap() = Some(AuditLog {
+        file,
+        enabled: true,
+        peer: None,
+    });
+}
+
+/// Returns whether an audit log file has been set.
+pub fn audit_log_set() -> bool {
+    AUDIT_LOG.lock().unwrap().is_some()
+}
+
+fn set_peer(peer: Option<PeerCredentials>) {
+    if let Some(audit_log) = AUDIT_LOG.lock().unwrap().as_mut() {
+        audit_log.peer = peer;
+    }
+}
+
+/// Runs `exchange`, which relays one request of `peer` to the API server and
+/// waits for the response. The request is recorded as coming from `peer`, the
+/// exchanges of the other peers waiting for this one to complete.
+pub fn with_peer<T>(peer: PeerCredentials, exchange: impl FnOnce() -> T) -> T {
+    let _exchange = EXCHANGE.lock().unwrap();
+    set_peer(Some(peer));
+    let result = exchange();
+    set_peer(None);
+    result
+}
+
+/// Enables or disables the audit log at runtime. Returns false if no audit
+/// log file has been set.
+pub fn enable_audit_log(enabled: bool) -> bool {
+    match AUDIT_LOG.lock().unwrap().as_mut() {
+        Some(audit_log) => {
+            audit_log.enabled = enabled;
+            true
+        }
+        None => false,
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: f64,
+    peer: Option<PeerCredentials>,
+    method: String,
+    path: &'a str,
+    request: Option<Value>,
+    status: String,
+    response: Option<Value>,
+}
+
+/// Records the request and the response sent back. Requests toggling the
+/// audit log are recorded even when the audit log is disabled, so that the
+/// periods without any record can be accounted for.
+pub fn audit(request: &Request, response: &Response, force: bool) {
+    let mut audit_log = AUDIT_LOG.lock().unwrap();
+    let audit_log = match audit_log.as_mut() {
+        Some(audit_log) if audit_log.enabled || force => audit_log,
+        _ => return,
+    };
+
+    let record = AuditRecord {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64())
+            .unwrap_or_default(),
+        peer: audit_log.peer,
+        method: format!("{:?}", request.method()),
+        path: request.uri().get_abs_path(),
+        request: request.body.as_ref().map(redacted_body),
+        status: format!("{:?}", response.status()),
+        response: response.body().as_ref().map(redacted_body),
+    };
+
+    let mut line = serde_json::to_vec(&record).unwrap();
+    line.push(b'\n');
+    if let Err(e) = audit_log.file.write_all(&line) {
+        error!("Failed writing API audit record: {}", e);
+    }
+}
+
+// Bodies which are not JSON, such as the metrics, are not recorded, only
+// their size is.
+fn redacted_body(body: &Body) -> Value {
+    match serde_json::from_slice(body.raw()) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value
+        }
+        Err(_) => Value::from(format!("<{} bytes>", body.raw().len())),
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if REDACTED_FIELDS.iter().any(|field| key.contains(field)) {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_body() {
+        let body = Body::new(
+            r#"{
+                "cmdline": {"args": "console=ttyS0 luks.key=foo"},
+                "disks": [{"path": "/path/to/disk", "auth_token": "bar"}],
+                "memory": {"size": 1073741824}
+            }"#,
+        );
+        assert_eq!(
+            redacted_body(&body),
+            serde_json::json!({
+                "cmdline": {"args": REDACTED},
+                "disks": [{"path": "/path/to/disk", "auth_token": REDACTED}],
+                "memory": {"size": 1073741824u64}
+            })
+        );
+
+        let body = Body::new("# TYPE cloud_hypervisor_vcpu_exits_total counter\n");
+        assert_eq!(redacted_body(&body), Value::from("<49 bytes>"));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Backward compatibility for the REST API payloads.
+//!
+//! Payloads sent by controllers written against an older release might still
+//! rely on fields which have been renamed or moved since then. Instead of
+//! rejecting them, the payloads are first deserialized into a generic JSON
+//! value which is upgraded to the current layout, before being converted
+//! into the actual configuration structure.
+
+use crate::config::VmConfig;
+use serde_json::{Map, Value};
+
+/// Deserializes a `VmConfig` from a payload which might have been built
+/// against an older version of the API.
+pub fn vm_config_from_slice(data: &[u8]) -> serde_json::Result<VmConfig> {
+    let mut value: Value = serde_json::from_slice(data)?;
+    upgrade_vm_config(&mut value);
+    serde_json::from_value(value)
+}
+
+fn upgrade_vm_config(value: &mut Value) {
+    if let Some(config) = value.as_object_mut() {
+        upgrade_memory_balloon(config);
+    }
+}
+
+// The balloon used to be enabled through the "balloon" and "balloon_size"
+// fields of the memory configuration, before getting its own configuration.
+fn upgrade_memory_balloon(config: &mut Map<String, Value>) {
+    let (balloon, balloon_size) = match config.get_mut("memory").and_then(Value::as_object_mut) {
+        Some(memory) => (memory.remove("balloon"), memory.remove("balloon_size")),
+        None => return,
+    };
+
+    if balloon.as_ref().and_then(Value::as_bool) != Some(true) {
+        return;
+    }
+
+    if config.get("balloon").map_or(false, |b| !b.is_null()) {
+        warn!("Ignoring deprecated memory balloon as a balloon is already configured");
+        return;
+    }
+
+    warn!("The memory \"balloon\" and \"balloon_size\" fields are deprecated, use \"balloon\"");
+    let mut balloon = Map::new();
+    balloon.insert(
+        "size".to_string(),
+        balloon_size.unwrap_or_else(|| Value::from(0)),
+    );
+    config.insert("balloon".to_string(), Value::Object(balloon));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_memory_balloon() {
+        let config = vm_config_from_slice(
+            br#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "memory": {"size": 1073741824, "balloon": true, "balloon_size": 536870912}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.memory.size, 1 << 30);
+        let balloon = config.balloon.unwrap();
+        assert_eq!(balloon.size, 1 << 29);
+        assert!(!balloon.deflate_on_oom);
+
+        // A disabled legacy balloon doesn't create any device.
+        let config = vm_config_from_slice(
+            br#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "memory": {"size": 1073741824, "balloon": false, "balloon_size": 536870912}
+            }"#,
+        )
+        .unwrap();
+        assert!(config.balloon.is_none());
+
+        // The current layout takes precedence over the legacy one.
+        let config = vm_config_from_slice(
+            br#"{
+                "kernel": {"path": "/path/to/kernel"},
+                "memory": {"size": 1073741824, "balloon": true, "balloon_size": 536870912},
+                "balloon": {"size": 1073741824, "deflate_on_oom": true}
+            }"#,
+        )
+        .unwrap();
+        let balloon = config.balloon.unwrap();
+        assert_eq!(balloon.size, 1 << 30);
+        assert!(balloon.deflate_on_oom);
+    }
+
+    #[test]
+    fn test_current_vm_config() {
+        let data = br#"{
+            "kernel": {"path": "/path/to/kernel"},
+            "memory": {"size": 1073741824, "shared": true},
+            "balloon": {"size": 536870912}
+        }"#;
+        let config = vm_config_from_slice(data).unwrap();
+        assert_eq!(config, serde_json::from_slice::<VmConfig>(data).unwrap());
+    }
+}
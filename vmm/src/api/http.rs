@@ -3,20 +3,32 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdown};
-use crate::api::{ApiError, ApiRequest, VmAction};
+use crate::api::audit::{self, audit, PeerCredentials};
+use crate::api::http_endpoint::{
+    ApiVersions, VmActionHandler, VmCreate, VmInfo, VmmAuditLog, VmmMetrics, VmmPing,
+    VmmSetLogLevel, VmmShutdown, VmmVms,
+};
+use crate::api::{vmm_add_vm, vmm_get_vm, vmm_remove_vm, ApiError, ApiRequest, VmAction};
+use crate::config::ApiVsockConfig;
+use crate::metrics::{Metric, MetricType};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error, Result};
 use micro_http::{Body, HttpServer, MediaType, Method, Request, Response, StatusCode, Version};
 use seccomp::{SeccompAction, SeccompFilter};
 use serde_json::Error as SerdeError;
-use std::collections::HashMap;
-use std::os::unix::io::{IntoRawFd, RawFd};
-use std::os::unix::net::UnixListener;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use vmm_sys_util::eventfd::EventFd;
 
 /// Errors associated with VMM management
@@ -31,6 +43,9 @@ pub enum HttpError {
     /// Undefined endpoints
     NotFound,
 
+    /// Endpoints from an API version not supported by this VMM
+    UnsupportedApiVersion(String),
+
     /// Internal Server Error
     InternalServerError,
 
@@ -52,6 +67,9 @@ pub enum HttpError {
     /// Could not pause the VM
     VmResume(ApiError),
 
+    /// Could not schedule the VM resume
+    VmScheduleResume(ApiError),
+
     /// Could not shut a VM down
     VmShutdown(ApiError),
 
@@ -85,6 +103,12 @@ pub enum HttpError {
     /// Could not handle VMM ping
     VmmPing(ApiError),
 
+    /// Could not get the VMM metrics
+    VmmMetrics(ApiError),
+
+    /// No audit log file was given when starting the VMM
+    AuditLogNotConfigured,
+
     /// Could not add a disk to a VM
     VmAddDisk(ApiError),
 
@@ -100,9 +124,18 @@ pub enum HttpError {
     /// Could not add a vsock device to a VM
     VmAddVsock(ApiError),
 
+    /// Could not add a balloon device to a VM
+    VmAddBalloon(ApiError),
+
     /// Could not get counters from VM
     VmCounters(ApiError),
 
+    /// Could not get the guest RAM regions from VM
+    VmMemoryRegions(ApiError),
+
+    /// Could not get the configuration from VM
+    VmConfig(ApiError),
+
     /// Error setting up migration received
     VmReceiveMigration(ApiError),
 
@@ -111,6 +144,42 @@ pub enum HttpError {
 
     /// Error activating power button
     VmPowerButton(ApiError),
+
+    /// Error activating sleep button
+    VmSleepButton(ApiError),
+
+    /// Could not inject an NMI
+    VmNmi(ApiError),
+
+    /// Could not update the power supply
+    VmPowerSupply(ApiError),
+
+    /// Could not set the link state of the network device
+    VmSetNetLink(ApiError),
+
+    /// Could not mirror the disk
+    VmMirrorDisk(ApiError),
+
+    /// Could not pivot the disk to its mirror
+    VmPivotDisk(ApiError),
+
+    /// Could not take the disk snapshot
+    VmDiskSnapshot(ApiError),
+
+    /// Could not checkpoint the VM
+    VmCheckpoint(ApiError),
+
+    /// Could not get the checkpoint progress
+    VmCheckpointInfo(ApiError),
+
+    /// Could not reach the VM console
+    VmConsole(ApiError),
+
+    /// Could not reach a hosted VM
+    HostedVm(ApiError),
+
+    /// Could not list the hosted VMs
+    VmmVms(ApiError),
 }
 
 impl From<serde_json::Error> for HttpError {
@@ -121,6 +190,16 @@ impl From<serde_json::Error> for HttpError {
 
 const HTTP_ROOT: &str = "/api/v1";
 
+/// Prefix shared by all the versions of the API.
+pub const HTTP_API_PREFIX: &str = "/api";
+
+/// Current version of the API, the one HTTP_ROOT points to.
+pub const HTTP_API_VERSION: &str = "v1";
+
+/// Versions of the API this VMM is able to serve, from the oldest to the
+/// most recent one.
+pub const HTTP_API_VERSIONS: &[&str] = &[HTTP_API_VERSION];
+
 pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     let mut response = Response::new(Version::Http11, status);
     response.set_body(Body::new(format!("{:?}", error)));
@@ -182,6 +261,11 @@ pub trait EndpointHandler: Sync + Send {
     ) -> std::result::Result<Option<Body>, HttpError> {
         Err(HttpError::BadRequest)
     }
+
+    /// Media type of the responses sent by the handler.
+    fn media_type(&self) -> MediaType {
+        MediaType::ApplicationJson
+    }
 }
 
 /// An HTTP routes structure.
@@ -203,6 +287,7 @@ lazy_static! {
             routes: HashMap::new(),
         };
 
+        r.routes.insert(endpoint!("/vm.add-balloon"), Box::new(VmActionHandler::new(VmAction::AddBalloon(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-device"), Box::new(VmActionHandler::new(VmAction::AddDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-disk"), Box::new(VmActionHandler::new(VmAction::AddDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-fs"), Box::new(VmActionHandler::new(VmAction::AddFs(Arc::default()))));
@@ -210,12 +295,21 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.add-pmem"), Box::new(VmActionHandler::new(VmAction::AddPmem(Arc::default()))));
         r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmActionHandler::new(VmAction::AddVsock(Arc::default()))));
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
+        r.routes.insert(endpoint!("/vm.checkpoint"), Box::new(VmActionHandler::new(VmAction::Checkpoint(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.config"), Box::new(VmActionHandler::new(VmAction::Config)));
+        r.routes.insert(endpoint!("/vm.console"), Box::new(VmActionHandler::new(VmAction::Console(Arc::default()))));
         r.routes.insert(endpoint!("/vm.counters"), Box::new(VmActionHandler::new(VmAction::Counters)));
         r.routes.insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
+        r.routes.insert(endpoint!("/vm.disk-snapshot"), Box::new(VmActionHandler::new(VmAction::DiskSnapshot(Arc::default()))));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+        r.routes.insert(endpoint!("/vm.memory-regions"), Box::new(VmActionHandler::new(VmAction::MemoryRegions)));
+        r.routes.insert(endpoint!("/vm.mirror-disk"), Box::new(VmActionHandler::new(VmAction::MirrorDisk(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.nmi"), Box::new(VmActionHandler::new(VmAction::Nmi)));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
+        r.routes.insert(endpoint!("/vm.pivot-disk"), Box::new(VmActionHandler::new(VmAction::PivotDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vm.power-button"), Box::new(VmActionHandler::new(VmAction::PowerButton)));
+        r.routes.insert(endpoint!("/vm.power-supply"), Box::new(VmActionHandler::new(VmAction::PowerSupply(Arc::default()))));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
         r.routes.insert(endpoint!("/vm.receive-migration"), Box::new(VmActionHandler::new(VmAction::ReceiveMigration(Arc::default()))));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))));
@@ -223,38 +317,212 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.resize-zone"), Box::new(VmActionHandler::new(VmAction::ResizeZone(Arc::default()))));
         r.routes.insert(endpoint!("/vm.restore"), Box::new(VmActionHandler::new(VmAction::Restore(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
+        r.routes.insert(endpoint!("/vm.schedule-resume"), Box::new(VmActionHandler::new(VmAction::ScheduleResume(Arc::default()))));
         r.routes.insert(endpoint!("/vm.send-migration"), Box::new(VmActionHandler::new(VmAction::SendMigration(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.set-net-link"), Box::new(VmActionHandler::new(VmAction::SetNetLink(Arc::default()))));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
+        r.routes.insert(endpoint!("/vm.sleep-button"), Box::new(VmActionHandler::new(VmAction::SleepButton)));
         r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))));
+        r.routes.insert(endpoint!("/vmm.audit-log"), Box::new(VmmAuditLog {}));
+        r.routes.insert(endpoint!("/vmm.metrics"), Box::new(VmmMetrics {}));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+        r.routes.insert(endpoint!("/vmm.set-log-level"), Box::new(VmmSetLogLevel {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
+        r.routes.insert(endpoint!("/vms"), Box::new(VmmVms {}));
+
+        // Not versioned, so that clients can find out which versions of the
+        // API are supported before issuing any other request.
+        r.routes.insert(format!("{}/versions", HTTP_API_PREFIX), Box::new(ApiVersions {}));
 
         r
     };
 }
 
+lazy_static! {
+    /// Number of requests and total time spent handling them, per endpoint.
+    static ref HTTP_REQUEST_DURATIONS: Mutex<BTreeMap<String, (u64, Duration)>> =
+        Mutex::new(BTreeMap::new());
+}
+
+fn record_request_duration(path: &str, duration: Duration) {
+    let mut durations = HTTP_REQUEST_DURATIONS.lock().unwrap();
+    let entry = durations
+        .entry(path.to_string())
+        .or_insert((0, Duration::default()));
+    entry.0 += 1;
+    entry.1 += duration;
+}
+
+/// Returns the metric describing the time spent handling the API requests.
+pub fn request_duration_metric() -> Metric {
+    let mut metric = Metric::new(
+        "api_request_duration_seconds",
+        MetricType::Summary,
+        "Time spent handling the API requests, per endpoint.",
+    );
+    for (path, (count, duration)) in HTTP_REQUEST_DURATIONS.lock().unwrap().iter() {
+        metric.add_sample("_sum", &[("endpoint", path)], duration.as_secs_f64());
+        metric.add_sample("_count", &[("endpoint", path)], *count as f64);
+    }
+    metric
+}
+
+// Returns the identifier of the hosted VM and the endpoint a request for a
+// hosted VM, sent to /api/v1/vms/<id>/<endpoint>, refers to.
+fn hosted_vm_endpoint(path: &str) -> Option<(&str, &str)> {
+    path.strip_prefix(HTTP_ROOT)?
+        .strip_prefix("/vms/")?
+        .split_once('/')
+}
+
+// Endpoints which start hosting the VM they act on, if it's not yet.
+const HOSTED_VM_CREATE_ENDPOINTS: &[&str] = &["vm.create", "vm.restore", "vm.receive-migration"];
+
+fn hosted_vm_error_response(e: ApiError) -> Response {
+    let status = match e {
+        ApiError::InvalidVmId(_) => StatusCode::BadRequest,
+        ApiError::UnknownVm(_) => StatusCode::NotFound,
+        ApiError::VmBeingRemoved(_) => StatusCode::BadRequest,
+        _ => StatusCode::InternalServerError,
+    };
+    error_response(HttpError::HostedVm(e), status)
+}
+
+// The requests for a hosted VM are served by the same handlers as the ones
+// for the default VM, through the API channel of the hosted VM. Deleting a
+// hosted VM stops hosting it, and so does failing to create the VM the
+// request started hosting.
+fn handle_hosted_vm_request(
+    request: &Request,
+    id: &str,
+    endpoint: &str,
+    api_notifier: EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> Response {
+    // The VMM endpoints apply to the whole process.
+    let route = match HTTP_ROUTES.routes.get(&endpoint!(format!("/{}", endpoint))) {
+        Some(route) if endpoint.starts_with("vm.") => route,
+        _ => return error_response(HttpError::NotFound, StatusCode::NotFound),
+    };
+
+    let start = Instant::now();
+    let response = if endpoint == "vm.delete" {
+        if request.method() != Method::Put {
+            return Response::new(Version::Http11, StatusCode::BadRequest);
+        }
+        match vmm_remove_vm(api_notifier, api_sender.clone(), id.to_string()) {
+            Ok(()) => Response::new(Version::Http11, StatusCode::NoContent),
+            Err(e) => hosted_vm_error_response(e),
+        }
+    } else if HOSTED_VM_CREATE_ENDPOINTS.contains(&endpoint) {
+        let remove_notifier = match api_notifier.try_clone() {
+            Ok(notifier) => notifier,
+            Err(_) => {
+                return error_response(
+                    HttpError::InternalServerError,
+                    StatusCode::InternalServerError,
+                )
+            }
+        };
+        match vmm_add_vm(api_notifier, api_sender.clone(), id.to_string()) {
+            Ok((vm_notifier, vm_sender, added)) => {
+                let response = route.handle_request(request, vm_notifier, vm_sender);
+                // The VMM thread would be left without a VM to drive.
+                if added && !matches!(response.status(), StatusCode::OK | StatusCode::NoContent) {
+                    if let Err(e) =
+                        vmm_remove_vm(remove_notifier, api_sender.clone(), id.to_string())
+                    {
+                        error!(
+                            "Error removing VM {} after failing to create it: {:?}",
+                            id, e
+                        );
+                    }
+                }
+                response
+            }
+            Err(e) => hosted_vm_error_response(e),
+        }
+    } else {
+        match vmm_get_vm(api_notifier, api_sender.clone(), id.to_string()) {
+            Ok((vm_notifier, vm_sender)) => route.handle_request(request, vm_notifier, vm_sender),
+            Err(e) => hosted_vm_error_response(e),
+        }
+    };
+    // All the hosted VMs share the samples of an endpoint.
+    record_request_duration(
+        &endpoint!(format!("/vms/{{id}}/{}", endpoint)),
+        start.elapsed(),
+    );
+
+    response
+}
+
 fn handle_http_request(
     request: &Request,
     api_notifier: &EventFd,
     api_sender: &Sender<ApiRequest>,
 ) -> Response {
     let path = request.uri().get_abs_path().to_string();
-    let mut response = match HTTP_ROUTES.routes.get(&path) {
-        Some(route) => match api_notifier.try_clone() {
-            Ok(notifier) => route.handle_request(request, notifier, api_sender.clone()),
+    let mut media_type = MediaType::ApplicationJson;
+    let mut response = if let Some((id, endpoint)) = hosted_vm_endpoint(&path) {
+        match api_notifier.try_clone() {
+            Ok(notifier) => handle_hosted_vm_request(request, id, endpoint, notifier, api_sender),
             Err(_) => error_response(
                 HttpError::InternalServerError,
                 StatusCode::InternalServerError,
             ),
-        },
-        None => error_response(HttpError::NotFound, StatusCode::NotFound),
+        }
+    } else {
+        match HTTP_ROUTES.routes.get(&path) {
+            Some(route) => match api_notifier.try_clone() {
+                Ok(notifier) => {
+                    let start = Instant::now();
+                    let response = route.handle_request(request, notifier, api_sender.clone());
+                    // Only known endpoints are recorded, to keep the number of
+                    // samples bounded.
+                    record_request_duration(&path, start.elapsed());
+                    media_type = route.media_type();
+                    response
+                }
+                Err(_) => error_response(
+                    HttpError::InternalServerError,
+                    StatusCode::InternalServerError,
+                ),
+            },
+            None => match unsupported_api_version(&path) {
+                Some(version) => error_response(
+                    HttpError::UnsupportedApiVersion(version),
+                    StatusCode::NotFound,
+                ),
+                None => error_response(HttpError::NotFound, StatusCode::NotFound),
+            },
+        }
     };
 
     response.set_server("Cloud Hypervisor API");
-    response.set_content_type(MediaType::ApplicationJson);
+    response.set_content_type(media_type);
+
+    audit(request, &response, path == endpoint!("/vmm.audit-log"));
+
     response
 }
 
+// Returns the version an unknown endpoint refers to, if this version is not
+// supported. This allows telling clients apart from typos in the endpoint.
+fn unsupported_api_version(path: &str) -> Option<String> {
+    let version = path
+        .strip_prefix(HTTP_API_PREFIX)?
+        .strip_prefix('/')?
+        .split('/')
+        .next()?;
+
+    if version.starts_with('v') && !HTTP_API_VERSIONS.contains(&version) {
+        Some(version.to_string())
+    } else {
+        None
+    }
+}
+
 fn start_http_thread(
     mut server: HttpServer,
     api_notifier: EventFd,
@@ -306,10 +574,14 @@ pub fn start_http_path_thread(
 ) -> Result<thread::JoinHandle<Result<()>>> {
     std::fs::remove_file(path).unwrap_or_default();
     let socket_path = PathBuf::from(path);
-    let socket_fd = UnixListener::bind(socket_path).map_err(Error::CreateApiServerSocket)?;
-    let server =
-        HttpServer::new_from_fd(socket_fd.into_raw_fd()).map_err(Error::CreateApiServer)?;
-    start_http_thread(server, api_notifier, api_sender, seccomp_action)
+    let listener = UnixListener::bind(&socket_path).map_err(Error::CreateApiServerSocket)?;
+    start_http_listener_thread(
+        listener,
+        &socket_path,
+        api_notifier,
+        api_sender,
+        seccomp_action,
+    )
 }
 
 pub fn start_http_fd_thread(
@@ -318,6 +590,490 @@ pub fn start_http_fd_thread(
     api_sender: Sender<ApiRequest>,
     seccomp_action: &SeccompAction,
 ) -> Result<thread::JoinHandle<Result<()>>> {
-    let server = HttpServer::new_from_fd(fd).map_err(Error::CreateApiServer)?;
+    if !audit::audit_log_set() {
+        let server = HttpServer::new_from_fd(fd).map_err(Error::CreateApiServer)?;
+        return start_http_thread(server, api_notifier, api_sender, seccomp_action);
+    }
+
+    let socket_path = http_fd_path(fd).map_err(Error::CreateApiServerSocket)?;
+    // Safe because the fd is given to us to serve the API on.
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    start_http_listener_thread(
+        listener,
+        &socket_path,
+        api_notifier,
+        api_sender,
+        seccomp_action,
+    )
+}
+
+// Serves the API on `listener`, bound to `path`. When the audit log is set,
+// the connections are relayed to the API server by the "http-audit" thread,
+// which knows the credentials of their peer.
+fn start_http_listener_thread(
+    listener: UnixListener,
+    path: &Path,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    let listener = if audit::audit_log_set() {
+        let (api_listener, api_path) =
+            bind_audited_api_socket(path).map_err(Error::CreateApiServerSocket)?;
+        start_http_audit_thread(listener, api_path, seccomp_action)?;
+        api_listener
+    } else {
+        listener
+    };
+
+    let server = HttpServer::new_from_fd(listener.into_raw_fd()).map_err(Error::CreateApiServer)?;
     start_http_thread(server, api_notifier, api_sender, seccomp_action)
 }
+
+// Binds the socket the API server listens on when the audit log is set. Only
+// the "http-audit" thread connects to it, so that no request escapes the
+// audit log: the socket lives in a directory only the VMM's user can access.
+fn bind_audited_api_socket(path: &Path) -> io::Result<(UnixListener, PathBuf)> {
+    let api_path = audited_api_path(path);
+    let dir = api_path.parent().unwrap();
+    std::fs::remove_dir_all(dir).unwrap_or_default();
+    DirBuilder::new().mode(0o700).create(dir)?;
+
+    let listener = UnixListener::bind(&api_path)?;
+    Ok((listener, api_path))
+}
+
+// Path of the socket the API server listens on when the audit log is set, and
+// the API socket is bound to `path`.
+fn audited_api_path(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".audit");
+    PathBuf::from(dir).join("api.sock")
+}
+
+// Size limit of the head of the relayed HTTP messages.
+const MAX_HTTP_HEAD_SIZE: usize = 8192;
+
+fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    // Safe because all zeros is a valid ucred.
+    let mut ucred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safe because ucred and len are valid, the size of ucred is passed along
+    // and the return value is checked.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCredentials::Unix {
+        pid: ucred.pid,
+        uid: ucred.uid,
+        gid: ucred.gid,
+    })
+}
+
+// Reads the HTTP messages received on a connection, one after the other.
+struct HttpMessageReader<R: Read> {
+    stream: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> HttpMessageReader<R> {
+    fn new(stream: R) -> Self {
+        HttpMessageReader {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let count = self.stream.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..count]);
+        Ok(count)
+    }
+
+    // Reads the head of the next message, up to and including the empty
+    // line. Returns None if the connection is closed before the next message.
+    fn read_head(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok(Some(self.buf.drain(..end + 4).collect()));
+            }
+            if self.buf.len() > MAX_HTTP_HEAD_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HTTP message head too large",
+                ));
+            }
+            if self.fill()? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                };
+            }
+        }
+    }
+
+    // Copies the `len` bytes of the body of the message to `out`.
+    fn copy_body<W: Write>(&mut self, mut len: usize, out: &mut W) -> io::Result<()> {
+        while len > 0 {
+            if self.buf.is_empty() && self.fill()? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let count = std::cmp::min(len, self.buf.len());
+            out.write_all(&self.buf[..count])?;
+            self.buf.drain(..count);
+            len -= count;
+        }
+        Ok(())
+    }
+}
+
+// Returns the length of the body following the HTTP message `head`.
+fn http_content_length(head: &[u8]) -> io::Result<usize> {
+    let head =
+        std::str::from_utf8(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    for line in head.split("\r\n").skip(1) {
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                return value
+                    .trim()
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+    }
+    Ok(0)
+}
+
+// An interim response, such as "100 Continue", is followed by the final one.
+fn http_interim_response(head: &[u8]) -> bool {
+    head.split(|b| *b == b' ')
+        .nth(1)
+        .map_or(false, |status| status.starts_with(b"1"))
+}
+
+// Relays the requests received on an API connection to the API server,
+// recording them as coming from `peer`.
+fn relay_audited<C>(client: &C, peer: PeerCredentials, api_path: &Path) -> io::Result<()>
+where
+    for<'a> &'a C: Read + Write,
+{
+    let api = UnixStream::connect(api_path)?;
+    let mut out = client;
+    let mut requests = HttpMessageReader::new(client);
+    let mut responses = HttpMessageReader::new(&api);
+
+    while let Some(head) = requests.read_head()? {
+        let len = http_content_length(&head)?;
+        audit::with_peer(peer, || -> io::Result<()> {
+            (&api).write_all(&head)?;
+            requests.copy_body(len, &mut &api)?;
+            loop {
+                let head = responses
+                    .read_head()?
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                out.write_all(&head)?;
+                let len = http_content_length(&head)?;
+                responses.copy_body(len, &mut out)?;
+                if !http_interim_response(&head) {
+                    return Ok(());
+                }
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+fn start_http_audit_thread(
+    listener: UnixListener,
+    api_path: PathBuf,
+    seccomp_action: &SeccompAction,
+) -> Result<()> {
+    let api_audit_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::ApiAudit).map_err(Error::CreateSeccompFilter)?;
+
+    thread::Builder::new()
+        .name("http-audit".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for API audit thread, the connection
+            // threads inherit it.
+            if let Err(e) = SeccompFilter::apply(api_audit_seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Error accepting API connection: {}", e);
+                        continue;
+                    }
+                };
+                let api_path = api_path.clone();
+                let result = thread::Builder::new()
+                    .name("http-audit-conn".to_string())
+                    .spawn(move || {
+                        let result = peer_credentials(&client)
+                            .and_then(|peer| relay_audited(&client, peer, &api_path));
+                        if let Err(e) = result {
+                            error!("Error relaying API connection: {}", e);
+                        }
+                    });
+                if let Err(e) = result {
+                    error!("Error spawning API connection thread: {}", e);
+                }
+            }
+        })
+        .map(|_| ())
+        .map_err(Error::HttpThreadSpawn)
+}
+
+/// Gets the path the API socket `fd` is bound to.
+pub fn http_fd_path(fd: RawFd) -> io::Result<PathBuf> {
+    // Safe because the listener is only borrowed, it is never dropped and
+    // the HTTP server keeps owning the fd.
+    let listener = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd) });
+    listener
+        .local_addr()?
+        .as_pathname()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unnamed API socket"))
+}
+
+fn vsock_listen(port: u32) -> io::Result<File> {
+    // Safe because the arguments are valid and the return value is checked.
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the fd we just created.
+    let socket = unsafe { File::from_raw_fd(fd) };
+
+    // Safe because all zeros is a valid sockaddr_vm.
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_port = port;
+    addr.svm_cid = libc::VMADDR_CID_ANY;
+    // Safe because addr is a valid sockaddr_vm whose size is passed along,
+    // and the return value is checked.
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the fd is a bound socket and the return value is checked.
+    if unsafe { libc::listen(socket.as_raw_fd(), 10) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(socket)
+}
+
+// Accepts a connection on the vsock `listener`, returning it along with the
+// CID of its peer.
+fn vsock_accept(listener: &File) -> io::Result<(File, u32)> {
+    // Safe because all zeros is a valid sockaddr_vm.
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    // Safe because addr and len are valid, the size of addr is passed along
+    // and the return value is checked.
+    let fd = unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut len,
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the fd accept4 just returned.
+    Ok((unsafe { File::from_raw_fd(fd) }, addr.svm_cid))
+}
+
+// Copies the bytes in both directions until either end closes the
+// connection.
+fn relay(vsock: &mut File, api: &mut UnixStream, buf: &mut [u8]) -> io::Result<()> {
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: vsock.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: api.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // Safe because fds is a valid array of pollfd whose length is
+        // passed along, and the return value is checked.
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if fds[0].revents != 0 {
+            let count = vsock.read(buf)?;
+            if count == 0 {
+                return Ok(());
+            }
+            api.write_all(&buf[..count])?;
+        }
+        if fds[1].revents != 0 {
+            let count = api.read(buf)?;
+            if count == 0 {
+                return Ok(());
+            }
+            vsock.write_all(&buf[..count])?;
+        }
+    }
+}
+
+/// Serves the API on the vsock port of `config` too, by relaying the
+/// connections of the allowed CIDs to the API server behind the API socket
+/// bound to `api_path`. micro_http only accepting connections on UNIX
+/// sockets, each vsock connection is relayed by a thread of its own.
+pub fn start_http_vsock_thread(
+    config: ApiVsockConfig,
+    api_path: &Path,
+    seccomp_action: &SeccompAction,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    let listener = vsock_listen(config.port).map_err(Error::CreateApiVsockSocket)?;
+    let api_vsock_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::ApiVsock).map_err(Error::CreateSeccompFilter)?;
+    // When the audit log is set, the connections are relayed straight to the
+    // API server so that they are recorded as coming from their CID.
+    let audited = audit::audit_log_set();
+    let api_path = if audited {
+        audited_api_path(api_path)
+    } else {
+        api_path.to_path_buf()
+    };
+
+    thread::Builder::new()
+        .name("http-vsock".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for API vsock thread, the connection
+            // threads inherit it.
+            SeccompFilter::apply(api_vsock_seccomp_filter).map_err(Error::ApplySeccompFilter)?;
+
+            loop {
+                let (mut vsock, cid) = match vsock_accept(&listener) {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        error!("Error accepting API vsock connection: {}", e);
+                        continue;
+                    }
+                };
+                if !config.allowed_cids.contains(&cid) {
+                    warn!("Refusing API vsock connection from CID {}", cid);
+                    continue;
+                }
+
+                let api_path = api_path.clone();
+                let result = thread::Builder::new()
+                    .name("http-vsock-conn".to_string())
+                    .spawn(move || {
+                        let result = if audited {
+                            relay_audited(&vsock, PeerCredentials::Vsock { cid }, &api_path)
+                        } else {
+                            let mut buf = vec![0u8; 4096];
+                            UnixStream::connect(&api_path)
+                                .and_then(|mut api| relay(&mut vsock, &mut api, &mut buf))
+                        };
+                        if let Err(e) = result {
+                            error!("Error relaying API vsock connection: {}", e);
+                        }
+                    });
+                if let Err(e) = result {
+                    error!("Error spawning API vsock connection thread: {}", e);
+                }
+            }
+        })
+        .map_err(Error::HttpThreadSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_api_version() {
+        assert_eq!(
+            unsupported_api_version("/api/v2/vm.create"),
+            Some("v2".to_string())
+        );
+        assert_eq!(unsupported_api_version("/api/v1/vm.foo"), None);
+        assert_eq!(unsupported_api_version("/api/foo"), None);
+        assert_eq!(unsupported_api_version("/foo/v2/vm.create"), None);
+    }
+
+    #[test]
+    fn test_http_message_reader() {
+        let messages: &[u8] = b"PUT /api/v1/vm.resize HTTP/1.1\r\nContent-Length: 4\r\n\r\n\
+            {..}GET /api/v1/vm.info HTTP/1.1\r\n\r\n";
+        let mut reader = HttpMessageReader::new(messages);
+
+        let head = reader.read_head().unwrap().unwrap();
+        assert_eq!(http_content_length(&head).unwrap(), 4);
+        let mut body = Vec::new();
+        reader.copy_body(4, &mut body).unwrap();
+        assert_eq!(body, b"{..}");
+
+        let head = reader.read_head().unwrap().unwrap();
+        assert_eq!(head, b"GET /api/v1/vm.info HTTP/1.1\r\n\r\n");
+        assert_eq!(http_content_length(&head).unwrap(), 0);
+        assert!(reader.read_head().unwrap().is_none());
+
+        assert!(http_interim_response(b"HTTP/1.1 100 Continue\r\n\r\n"));
+        assert!(!http_interim_response(b"HTTP/1.1 204 No Content\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_hosted_vm_endpoint() {
+        assert_eq!(
+            hosted_vm_endpoint("/api/v1/vms/vm0/vm.boot"),
+            Some(("vm0", "vm.boot"))
+        );
+        assert_eq!(hosted_vm_endpoint("/api/v1/vms/vm0"), None);
+        assert_eq!(hosted_vm_endpoint("/api/v1/vms"), None);
+        assert_eq!(hosted_vm_endpoint("/api/v1/vm.boot"), None);
+        assert_eq!(hosted_vm_endpoint("/api/v2/vms/vm0/vm.boot"), None);
+    }
+
+    #[test]
+    fn test_http_fd_path() {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = tmp_dir.as_path().join("api.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(http_fd_path(listener.as_raw_fd()).unwrap(), path);
+        // The listener is still usable.
+        assert!(UnixStream::connect(&path).is_ok());
+        assert!(listener.accept().is_ok());
+    }
+}
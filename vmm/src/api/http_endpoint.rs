@@ -3,15 +3,22 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http::{error_response, EndpointHandler, HttpError};
+use crate::api::compat::vm_config_from_slice;
+use crate::api::http::{
+    error_response, request_duration_metric, EndpointHandler, HttpError, HTTP_API_VERSION,
+    HTTP_API_VERSIONS,
+};
 use crate::api::{
-    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
-    vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_power_button, vm_reboot,
-    vm_receive_migration, vm_remove_device, vm_resize, vm_resize_zone, vm_restore, vm_resume,
-    vm_send_migration, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction,
-    VmConfig,
+    vm_add_balloon, vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock,
+    vm_boot, vm_checkpoint, vm_checkpoint_info, vm_config, vm_console, vm_counters, vm_create,
+    vm_delete, vm_disk_snapshot, vm_info, vm_memory_regions, vm_mirror_disk, vm_nmi, vm_pause,
+    vm_pivot_disk, vm_power_button, vm_power_supply, vm_reboot, vm_receive_migration,
+    vm_remove_device, vm_resize, vm_resize_zone, vm_restore, vm_resume, vm_schedule_resume,
+    vm_send_migration, vm_set_net_link, vm_shutdown, vm_sleep_button, vm_snapshot, vmm_audit_log,
+    vmm_metrics, vmm_ping, vmm_set_log_level, vmm_shutdown, vmm_vms, ApiRequest, VmAction,
+    VmConfig, VmmAuditLogData, VmmSetLogLevelData,
 };
-use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use micro_http::{Body, MediaType, Method, Request, Response, StatusCode, Version};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
@@ -30,8 +37,8 @@ impl EndpointHandler for VmCreate {
             Method::Put => {
                 match &req.body {
                     Some(body) => {
-                        // Deserialize into a VmConfig
-                        let vm_config: VmConfig = match serde_json::from_slice(body.raw())
+                        // Deserialize into a VmConfig, accepting older payloads
+                        let vm_config: VmConfig = match vm_config_from_slice(body.raw())
                             .map_err(HttpError::SerdeJsonDeserialize)
                         {
                             Ok(config) => config,
@@ -119,6 +126,13 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmAddVsock),
 
+                AddBalloon(_) => vm_add_balloon(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmAddBalloon),
+
                 RemoveDevice(_) => vm_remove_device(
                     api_notifier,
                     api_sender,
@@ -147,6 +161,13 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmRestore),
 
+                ScheduleResume(_) => vm_schedule_resume(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmScheduleResume),
+
                 Snapshot(_) => vm_snapshot(
                     api_notifier,
                     api_sender,
@@ -168,6 +189,55 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmSendMigration),
 
+                PowerSupply(_) => vm_power_supply(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmPowerSupply),
+
+                SetNetLink(_) => vm_set_net_link(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetNetLink),
+
+                MirrorDisk(_) => vm_mirror_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmMirrorDisk),
+
+                PivotDisk(_) => vm_pivot_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmPivotDisk),
+
+                DiskSnapshot(_) => vm_disk_snapshot(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmDiskSnapshot),
+
+                Checkpoint(_) => vm_checkpoint(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmCheckpoint),
+
+                Console(_) => vm_console(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmConsole),
+
                 _ => Err(HttpError::BadRequest),
             }
         } else {
@@ -181,6 +251,10 @@ impl EndpointHandler for VmActionHandler {
                 PowerButton => {
                     vm_power_button(api_notifier, api_sender).map_err(HttpError::VmPowerButton)
                 }
+                SleepButton => {
+                    vm_sleep_button(api_notifier, api_sender).map_err(HttpError::VmSleepButton)
+                }
+                Nmi => vm_nmi(api_notifier, api_sender).map_err(HttpError::VmNmi),
                 _ => Err(HttpError::BadRequest),
             }
         }
@@ -195,6 +269,13 @@ impl EndpointHandler for VmActionHandler {
         use VmAction::*;
         match self.action {
             Counters => vm_counters(api_notifier, api_sender).map_err(HttpError::VmCounters),
+            MemoryRegions => {
+                vm_memory_regions(api_notifier, api_sender).map_err(HttpError::VmMemoryRegions)
+            }
+            Config => vm_config(api_notifier, api_sender).map_err(HttpError::VmConfig),
+            Checkpoint(_) => {
+                vm_checkpoint_info(api_notifier, api_sender).map_err(HttpError::VmCheckpointInfo)
+            }
             _ => Err(HttpError::BadRequest),
         }
     }
@@ -252,6 +333,87 @@ impl EndpointHandler for VmmPing {
     }
 }
 
+// /api/v1/vms handler
+pub struct VmmVms {}
+
+impl EndpointHandler for VmmVms {
+    fn get_handler(
+        &self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        _body: &Option<Body>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let ids = vmm_vms(api_notifier, api_sender).map_err(HttpError::VmmVms)?;
+        Ok(Some(Body::new(serde_json::to_string(&ids).unwrap())))
+    }
+}
+
+// /api/v1/vmm.metrics handler
+pub struct VmmMetrics {}
+
+impl EndpointHandler for VmmMetrics {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                match vmm_metrics(api_notifier, api_sender).map_err(HttpError::VmmMetrics) {
+                    Ok(mut metrics) => {
+                        request_duration_metric().encode(&mut metrics);
+
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        response.set_body(Body::new(metrics));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+
+    fn media_type(&self) -> MediaType {
+        MediaType::PlainText
+    }
+}
+
+// /api/v1/vmm.audit-log handler
+pub struct VmmAuditLog {}
+
+impl EndpointHandler for VmmAuditLog {
+    fn put_handler(
+        &self,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+        let audit_log_data: VmmAuditLogData = serde_json::from_slice(body.raw())?;
+        vmm_audit_log(&audit_log_data).map_err(|_| HttpError::AuditLogNotConfigured)?;
+        Ok(None)
+    }
+}
+
+// /api/v1/vmm.set-log-level handler
+pub struct VmmSetLogLevel {}
+
+impl EndpointHandler for VmmSetLogLevel {
+    fn put_handler(
+        &self,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let body = body.as_ref().ok_or(HttpError::BadRequest)?;
+        let log_level_data: VmmSetLogLevelData = serde_json::from_slice(body.raw())?;
+        vmm_set_log_level(&log_level_data).map_err(|_| HttpError::BadRequest)?;
+        Ok(None)
+    }
+}
+
 // /api/v1/vmm.shutdown handler
 pub struct VmmShutdown {}
 
@@ -273,3 +435,36 @@ impl EndpointHandler for VmmShutdown {
         }
     }
 }
+
+#[derive(Serialize)]
+struct ApiVersionsResponse {
+    current: &'static str,
+    supported: &'static [&'static str],
+}
+
+// /api/versions handler
+pub struct ApiVersions {}
+
+impl EndpointHandler for ApiVersions {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                let versions_serialized = serde_json::to_string(&ApiVersionsResponse {
+                    current: HTTP_API_VERSION,
+                    supported: HTTP_API_VERSIONS,
+                })
+                .unwrap();
+
+                response.set_body(Body::new(versions_serialized));
+                response
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
@@ -28,21 +28,32 @@
 //!    response channel Receiver.
 //! 5. The thread handles the response and forwards potential errors.
 
+pub use self::http::http_fd_path;
 pub use self::http::start_http_fd_thread;
 pub use self::http::start_http_path_thread;
+pub use self::http::start_http_vsock_thread;
 
+pub mod audit;
+pub mod compat;
 pub mod http;
 pub mod http_endpoint;
 
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+    BalloonConfig, CheckpointConfig, DeviceConfig, DiskConfig, DiskSnapshotConfig, FsConfig,
+    NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
 };
+use crate::console_buffer::ConsoleBuffer;
 use crate::device_tree::DeviceTree;
+use crate::logger::{self, TraceConfig};
 use crate::vm::{Error as VmError, VmState};
+use log::LevelFilter;
 use micro_http::Body;
 use std::io;
-use std::sync::mpsc::{channel, RecvError, SendError, Sender};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use vm_migration::MigratableError;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -73,12 +84,24 @@ pub enum ApiError {
     /// The VM info is not available.
     VmInfo(VmError),
 
+    /// The VM counters are not available.
+    VmCounters(VmError),
+
+    /// The guest RAM regions of the VM are not available.
+    VmMemoryRegions(VmError),
+
+    /// The VM config is not available.
+    VmConfig(VmError),
+
     /// The VM could not be paused.
     VmPause(VmError),
 
     /// The VM could not resume.
     VmResume(VmError),
 
+    /// The VM resume could not be scheduled.
+    VmScheduleResume(VmError),
+
     /// The VM is not booted.
     VmNotBooted,
 
@@ -133,6 +156,9 @@ pub enum ApiError {
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
+    /// The balloon device could not be added to the VM.
+    VmAddBalloon(VmError),
+
     /// Error starting migration receiever
     VmReceiveMigration(MigratableError),
 
@@ -141,6 +167,54 @@ pub enum ApiError {
 
     /// Error triggering power button
     VmPowerButton(VmError),
+
+    /// Error triggering sleep button
+    VmSleepButton(VmError),
+
+    /// The NMI could not be injected.
+    VmNmi(VmError),
+
+    /// The VM power supply could not be updated.
+    VmPowerSupply(VmError),
+
+    /// The link state of the network device could not be set.
+    VmSetNetLink(VmError),
+
+    /// The disk could not be mirrored.
+    VmMirrorDisk(VmError),
+
+    /// The disk could not be pivoted to its mirror.
+    VmPivotDisk(VmError),
+
+    /// The disk snapshot could not be taken.
+    VmDiskSnapshot(VmError),
+
+    /// The VM could not be checkpointed.
+    VmCheckpoint(VmError),
+
+    /// The checkpoint progress is not available.
+    VmCheckpointInfo(VmError),
+
+    /// The VM console could not be reached.
+    VmConsole(VmError),
+
+    /// The identifier can't name a hosted VM.
+    InvalidVmId(String),
+
+    /// No hosted VM has this identifier.
+    UnknownVm(String),
+
+    /// The hosted VM with this identifier is still being shut down.
+    VmBeingRemoved(String),
+
+    /// The hosted VM could not be started.
+    VmmAddVm(io::Error),
+
+    /// The log level or one of the traces is invalid.
+    InvalidLogLevel,
+
+    /// No audit log file was given when starting the VMM.
+    AuditLogNotConfigured,
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -152,6 +226,21 @@ pub struct VmInfo {
     pub device_tree: Option<Arc<Mutex<DeviceTree>>>,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmAuditLogData {
+    pub enabled: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmSetLogLevelData {
+    /// Level of the records being emitted, from "off" to "trace". No level
+    /// keeps the current one.
+    pub level: Option<String>,
+    /// Records of the components to enable or disable on top of the level
+    #[serde(default)]
+    pub traces: Vec<TraceConfig>,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct VmmPingResponse {
     pub version: String,
@@ -179,6 +268,103 @@ pub struct VmRemoveDeviceData {
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
     pub destination_url: String,
+    /// The URL of the snapshot last taken or restored from, for the snapshot
+    /// to be incremental
+    #[serde(default)]
+    pub parent_url: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmScheduleResumeData {
+    /// Time the VM is booted or resumed at, in seconds since the Unix epoch.
+    /// No time cancels the scheduled resume.
+    pub time: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmPowerSupplyData {
+    /// Whether the AC adapter is plugged in, unchanged if not given
+    #[serde(default)]
+    pub ac_online: Option<bool>,
+    /// Charge level of the battery in percent, unchanged if not given
+    #[serde(default)]
+    pub battery_level: Option<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSetNetLinkData {
+    /// Identifier of the network device
+    pub id: String,
+    /// Whether the link is up
+    pub up: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmMirrorDiskData {
+    /// Identifier of the disk
+    pub id: String,
+    /// New backing file of the disk, created if it doesn't exist
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmPivotDiskData {
+    /// Identifier of the disk
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmConsoleData {
+    /// Bytes to send to the console
+    #[serde(default)]
+    pub input: Vec<u8>,
+    /// Offset of the console output to return, as returned by the previous
+    /// request
+    #[serde(default)]
+    pub offset: u64,
+    /// How long to wait for output from the offset on if there's none yet,
+    /// in milliseconds, up to MAX_CONSOLE_WAIT_MS
+    #[serde(default)]
+    pub wait_ms: u64,
+}
+
+/// Longest a `vm.console` request waits for the console output.
+pub const MAX_CONSOLE_WAIT_MS: u64 = 1000;
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmConsoleOutput {
+    /// Console output from the requested offset on
+    pub output: Vec<u8>,
+    /// Offset of the following console output
+    pub offset: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum CheckpointState {
+    Copying,
+    Completed,
+    Failed,
+}
+
+impl Default for CheckpointState {
+    fn default() -> Self {
+        CheckpointState::Copying
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmCheckpointInfo {
+    /// Whether the checkpoint is still being taken, or how it ended
+    pub state: CheckpointState,
+    /// Passes copying the guest memory written while the VM was running
+    pub passes: u32,
+    /// Bytes of guest memory copied to the checkpoint
+    pub copied_bytes: u64,
+    /// Time the VM was paused for, in milliseconds
+    pub downtime_ms: u64,
+    /// Why the checkpoint failed
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
@@ -203,8 +389,21 @@ pub enum ApiResponsePayload {
     /// Vmm ping response
     VmmPing(VmmPingResponse),
 
-    /// Vm action response
-    VmAction(Vec<u8>),
+    /// Vmm metrics, in the Prometheus text format
+    VmmMetrics(String),
+
+    /// Vm action response, if the action has one
+    VmAction(Option<Vec<u8>>),
+
+    /// API event and channel of a hosted VM, and whether the request
+    /// started hosting it
+    VmChannel(EventFd, Sender<ApiRequest>, bool),
+
+    /// Identifiers of the hosted VMs
+    VmmVms(Vec<String>),
+
+    /// Output of the console driven through the API
+    VmConsole(ConsoleBuffer),
 }
 
 /// This is the response sent by the VMM API server through the mpsc channel.
@@ -236,6 +435,9 @@ pub enum ApiRequest {
     /// Request the VMM API server status
     VmmPing(Sender<ApiResponse>),
 
+    /// Request the VMM metrics.
+    VmmMetrics(Sender<ApiResponse>),
+
     /// Pause a VM.
     VmPause(Sender<ApiResponse>),
 
@@ -245,6 +447,12 @@ pub enum ApiRequest {
     /// Get counters for a VM.
     VmCounters(Sender<ApiResponse>),
 
+    /// Get the guest RAM regions of a VM.
+    VmMemoryRegions(Sender<ApiResponse>),
+
+    /// Get the effective configuration of a VM.
+    VmConfig(Sender<ApiResponse>),
+
     /// Shut the previously booted virtual machine down.
     /// If the VM was not previously booted or created, the VMM API server
     /// will send a VmShutdown error back.
@@ -287,6 +495,12 @@ pub enum ApiRequest {
     /// Add a vsock device to the VM.
     VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
 
+    /// Add a balloon device to the VM.
+    VmAddBalloon(Arc<BalloonConfig>, Sender<ApiResponse>),
+
+    /// Schedule the VM to boot or resume at a given time
+    VmScheduleResume(Arc<VmScheduleResumeData>, Sender<ApiResponse>),
+
     /// Take a VM snapshot
     VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
 
@@ -301,6 +515,102 @@ pub enum ApiRequest {
 
     // Trigger power button
     VmPowerButton(Sender<ApiResponse>),
+
+    // Trigger sleep button
+    VmSleepButton(Sender<ApiResponse>),
+
+    /// Inject an NMI into the vCPUs
+    VmNmi(Sender<ApiResponse>),
+
+    /// Update the state of the AC adapter and battery
+    VmPowerSupply(Arc<VmPowerSupplyData>, Sender<ApiResponse>),
+
+    /// Set the link state of a network device
+    VmSetNetLink(Arc<VmSetNetLinkData>, Sender<ApiResponse>),
+
+    /// Start mirroring a disk onto a new backing file
+    VmMirrorDisk(Arc<VmMirrorDiskData>, Sender<ApiResponse>),
+
+    /// Switch a disk to the backing file it is mirrored onto
+    VmPivotDisk(Arc<VmPivotDiskData>, Sender<ApiResponse>),
+
+    /// Redirect the writes to a disk onto a new copy-on-write overlay
+    VmDiskSnapshot(Arc<DiskSnapshotConfig>, Sender<ApiResponse>),
+
+    /// Back the running VM up, along with its disks
+    VmCheckpoint(Arc<CheckpointConfig>, Sender<ApiResponse>),
+
+    /// Get the progress of the checkpoint being taken, or the outcome of
+    /// the last one
+    VmCheckpointInfo(Sender<ApiResponse>),
+
+    /// Send input to the console driven through the API, and get its output.
+    VmConsole(Arc<VmConsoleData>, Sender<ApiResponse>),
+
+    /// Start hosting a VM with the given identifier, unless it's already
+    /// hosted, and return the API event and channel to drive it.
+    VmmAddVm(String, Sender<ApiResponse>),
+
+    /// Return the API event and channel of a hosted VM.
+    VmmGetVm(String, Sender<ApiResponse>),
+
+    /// Shut a hosted VM down and stop hosting it.
+    VmmRemoveVm(String, Sender<ApiResponse>),
+
+    /// List the hosted VMs.
+    VmmVms(Sender<ApiResponse>),
+}
+
+impl ApiRequest {
+    /// Name of the request, cheap to get and free of any of its content.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiRequest::VmCreate(..) => "VmCreate",
+            ApiRequest::VmBoot(..) => "VmBoot",
+            ApiRequest::VmDelete(..) => "VmDelete",
+            ApiRequest::VmInfo(..) => "VmInfo",
+            ApiRequest::VmmPing(..) => "VmmPing",
+            ApiRequest::VmmMetrics(..) => "VmmMetrics",
+            ApiRequest::VmPause(..) => "VmPause",
+            ApiRequest::VmResume(..) => "VmResume",
+            ApiRequest::VmCounters(..) => "VmCounters",
+            ApiRequest::VmMemoryRegions(..) => "VmMemoryRegions",
+            ApiRequest::VmConfig(..) => "VmConfig",
+            ApiRequest::VmShutdown(..) => "VmShutdown",
+            ApiRequest::VmReboot(..) => "VmReboot",
+            ApiRequest::VmmShutdown(..) => "VmmShutdown",
+            ApiRequest::VmResize(..) => "VmResize",
+            ApiRequest::VmResizeZone(..) => "VmResizeZone",
+            ApiRequest::VmAddDevice(..) => "VmAddDevice",
+            ApiRequest::VmRemoveDevice(..) => "VmRemoveDevice",
+            ApiRequest::VmAddDisk(..) => "VmAddDisk",
+            ApiRequest::VmAddFs(..) => "VmAddFs",
+            ApiRequest::VmAddPmem(..) => "VmAddPmem",
+            ApiRequest::VmAddNet(..) => "VmAddNet",
+            ApiRequest::VmAddVsock(..) => "VmAddVsock",
+            ApiRequest::VmAddBalloon(..) => "VmAddBalloon",
+            ApiRequest::VmScheduleResume(..) => "VmScheduleResume",
+            ApiRequest::VmSnapshot(..) => "VmSnapshot",
+            ApiRequest::VmRestore(..) => "VmRestore",
+            ApiRequest::VmReceiveMigration(..) => "VmReceiveMigration",
+            ApiRequest::VmSendMigration(..) => "VmSendMigration",
+            ApiRequest::VmPowerButton(..) => "VmPowerButton",
+            ApiRequest::VmSleepButton(..) => "VmSleepButton",
+            ApiRequest::VmNmi(..) => "VmNmi",
+            ApiRequest::VmPowerSupply(..) => "VmPowerSupply",
+            ApiRequest::VmSetNetLink(..) => "VmSetNetLink",
+            ApiRequest::VmMirrorDisk(..) => "VmMirrorDisk",
+            ApiRequest::VmPivotDisk(..) => "VmPivotDisk",
+            ApiRequest::VmDiskSnapshot(..) => "VmDiskSnapshot",
+            ApiRequest::VmCheckpoint(..) => "VmCheckpoint",
+            ApiRequest::VmCheckpointInfo(..) => "VmCheckpointInfo",
+            ApiRequest::VmConsole(..) => "VmConsole",
+            ApiRequest::VmmAddVm(..) => "VmmAddVm",
+            ApiRequest::VmmGetVm(..) => "VmmGetVm",
+            ApiRequest::VmmRemoveVm(..) => "VmmRemoveVm",
+            ApiRequest::VmmVms(..) => "VmmVms",
+        }
+    }
 }
 
 pub fn vm_create(
@@ -346,6 +656,12 @@ pub enum VmAction {
     /// Return VM counters
     Counters,
 
+    /// Return VM guest RAM regions
+    MemoryRegions,
+
+    /// Return VM effective configuration
+    Config,
+
     /// Add VFIO device
     AddDevice(Arc<DeviceConfig>),
 
@@ -364,6 +680,9 @@ pub enum VmAction {
     /// Add vsock
     AddVsock(Arc<VsockConfig>),
 
+    /// Add balloon
+    AddBalloon(Arc<BalloonConfig>),
+
     /// Remove VFIO device
     RemoveDevice(Arc<VmRemoveDeviceData>),
 
@@ -376,6 +695,9 @@ pub enum VmAction {
     /// Restore VM
     Restore(Arc<RestoreConfig>),
 
+    /// Schedule VM resume
+    ScheduleResume(Arc<VmScheduleResumeData>),
+
     /// Snapshot VM
     Snapshot(Arc<VmSnapshotConfig>),
 
@@ -387,6 +709,36 @@ pub enum VmAction {
 
     /// Power Button for clean shutdown
     PowerButton,
+
+    /// Sleep Button for guest suspend
+    SleepButton,
+
+    /// NMI for guest diagnostics
+    Nmi,
+
+    /// Update the power supply state
+    PowerSupply(Arc<VmPowerSupplyData>),
+
+    /// Set the link state of a network device
+    SetNetLink(Arc<VmSetNetLinkData>),
+
+    /// Start mirroring a disk
+    MirrorDisk(Arc<VmMirrorDiskData>),
+
+    /// Pivot a disk to its mirror
+    PivotDisk(Arc<VmPivotDiskData>),
+
+    /// Take a snapshot of a disk
+    DiskSnapshot(Arc<DiskSnapshotConfig>),
+
+    /// Checkpoint the running VM
+    Checkpoint(Arc<CheckpointConfig>),
+
+    /// Return the checkpoint progress
+    CheckpointInfo,
+
+    /// Exchange with the console
+    Console(Arc<VmConsoleData>),
 }
 
 fn vm_action(
@@ -394,8 +746,24 @@ fn vm_action(
     api_sender: Sender<ApiRequest>,
     action: VmAction,
 ) -> ApiResult<Option<Body>> {
+    vm_request(&api_evt, &api_sender, action).map(|response| response.map(Body::new))
+}
+
+/// Sends a VM action to the VMM thread and returns the raw JSON response
+/// the action may come with.
+pub(crate) fn vm_request(
+    api_evt: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+    action: VmAction,
+) -> ApiResult<Option<Vec<u8>>> {
     let (response_sender, response_receiver) = channel();
 
+    // The console output is waited for here, not to hold the VMM thread.
+    let console_data = match &action {
+        VmAction::Console(data) => Some(data.clone()),
+        _ => None,
+    };
+
     use VmAction::*;
     let request = match action {
         Boot => ApiRequest::VmBoot(response_sender),
@@ -405,33 +773,55 @@ fn vm_action(
         Pause => ApiRequest::VmPause(response_sender),
         Resume => ApiRequest::VmResume(response_sender),
         Counters => ApiRequest::VmCounters(response_sender),
+        MemoryRegions => ApiRequest::VmMemoryRegions(response_sender),
+        Config => ApiRequest::VmConfig(response_sender),
         AddDevice(v) => ApiRequest::VmAddDevice(v, response_sender),
         AddDisk(v) => ApiRequest::VmAddDisk(v, response_sender),
         AddFs(v) => ApiRequest::VmAddFs(v, response_sender),
         AddPmem(v) => ApiRequest::VmAddPmem(v, response_sender),
         AddNet(v) => ApiRequest::VmAddNet(v, response_sender),
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
+        AddBalloon(v) => ApiRequest::VmAddBalloon(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
         ResizeZone(v) => ApiRequest::VmResizeZone(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
+        ScheduleResume(v) => ApiRequest::VmScheduleResume(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
         ReceiveMigration(v) => ApiRequest::VmReceiveMigration(v, response_sender),
         SendMigration(v) => ApiRequest::VmSendMigration(v, response_sender),
         PowerButton => ApiRequest::VmPowerButton(response_sender),
+        SleepButton => ApiRequest::VmSleepButton(response_sender),
+        Nmi => ApiRequest::VmNmi(response_sender),
+        PowerSupply(v) => ApiRequest::VmPowerSupply(v, response_sender),
+        SetNetLink(v) => ApiRequest::VmSetNetLink(v, response_sender),
+        MirrorDisk(v) => ApiRequest::VmMirrorDisk(v, response_sender),
+        PivotDisk(v) => ApiRequest::VmPivotDisk(v, response_sender),
+        DiskSnapshot(v) => ApiRequest::VmDiskSnapshot(v, response_sender),
+        Checkpoint(v) => ApiRequest::VmCheckpoint(v, response_sender),
+        CheckpointInfo => ApiRequest::VmCheckpointInfo(response_sender),
+        Console(v) => ApiRequest::VmConsole(v, response_sender),
     };
 
     // Send the VM request.
     api_sender.send(request).map_err(ApiError::RequestSend)?;
     api_evt.write(1).map_err(ApiError::EventFdWrite)?;
 
-    let body = match response_receiver.recv().map_err(ApiError::ResponseRecv)?? {
-        ApiResponsePayload::VmAction(response) => Some(Body::new(response)),
-        ApiResponsePayload::Empty => None,
-        _ => return Err(ApiError::ResponsePayloadType),
-    };
-
-    Ok(body)
+    match (
+        response_receiver.recv().map_err(ApiError::ResponseRecv)??,
+        console_data,
+    ) {
+        (ApiResponsePayload::VmAction(response), _) => Ok(response),
+        (ApiResponsePayload::Empty, _) => Ok(None),
+        (ApiResponsePayload::VmConsole(buffer), Some(data)) => {
+            let wait = Duration::from_millis(data.wait_ms.min(MAX_CONSOLE_WAIT_MS));
+            let (output, offset) = buffer.wait(data.offset, wait);
+            serde_json::to_vec(&VmConsoleOutput { output, offset })
+                .map(Some)
+                .map_err(|e| ApiError::VmConsole(VmError::SerializeJson(e)))
+        }
+        _ => Err(ApiError::ResponsePayloadType),
+    }
 }
 
 pub fn vm_boot(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
@@ -458,10 +848,29 @@ pub fn vm_resume(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<
     vm_action(api_evt, api_sender, VmAction::Resume)
 }
 
+pub fn vm_schedule_resume(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmScheduleResumeData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ScheduleResume(data))
+}
+
 pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::Counters)
 }
 
+pub fn vm_memory_regions(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MemoryRegions)
+}
+
+pub fn vm_config(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Config)
+}
+
 pub fn vm_power_button(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -469,6 +878,80 @@ pub fn vm_power_button(
     vm_action(api_evt, api_sender, VmAction::PowerButton)
 }
 
+pub fn vm_sleep_button(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SleepButton)
+}
+
+pub fn vm_nmi(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Nmi)
+}
+
+pub fn vm_power_supply(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmPowerSupplyData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::PowerSupply(data))
+}
+
+pub fn vm_set_net_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetNetLinkData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetNetLink(data))
+}
+
+pub fn vm_mirror_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmMirrorDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MirrorDisk(data))
+}
+
+pub fn vm_pivot_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmPivotDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::PivotDisk(data))
+}
+
+pub fn vm_disk_snapshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<DiskSnapshotConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::DiskSnapshot(data))
+}
+
+pub fn vm_checkpoint(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<CheckpointConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Checkpoint(data))
+}
+
+pub fn vm_checkpoint_info(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::CheckpointInfo)
+}
+
+pub fn vm_console(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmConsoleData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::Console(data))
+}
+
 pub fn vm_receive_migration(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -534,6 +1017,22 @@ pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<V
     }
 }
 
+pub fn vmm_metrics(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<String> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmMetrics(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let metrics = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match metrics {
+        ApiResponsePayload::VmmMetrics(metrics) => Ok(metrics),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
 pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
@@ -548,6 +1047,107 @@ pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResu
     Ok(())
 }
 
+fn vmm_vm_channel(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    request: ApiRequest,
+    response_receiver: Receiver<ApiResponse>,
+) -> ApiResult<(EventFd, Sender<ApiRequest>, bool)> {
+    api_sender.send(request).map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    match response_receiver.recv().map_err(ApiError::ResponseRecv)?? {
+        ApiResponsePayload::VmChannel(evt, sender, added) => Ok((evt, sender, added)),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vmm_add_vm(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    id: String,
+) -> ApiResult<(EventFd, Sender<ApiRequest>, bool)> {
+    let (response_sender, response_receiver) = channel();
+    let request = ApiRequest::VmmAddVm(id, response_sender);
+
+    vmm_vm_channel(api_evt, api_sender, request, response_receiver)
+}
+
+pub fn vmm_get_vm(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    id: String,
+) -> ApiResult<(EventFd, Sender<ApiRequest>)> {
+    let (response_sender, response_receiver) = channel();
+    let request = ApiRequest::VmmGetVm(id, response_sender);
+
+    vmm_vm_channel(api_evt, api_sender, request, response_receiver)
+        .map(|(evt, sender, _)| (evt, sender))
+}
+
+pub fn vmm_remove_vm(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    id: String,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmRemoveVm(id, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+/// Changes the log level of the whole process. The request is checked as a
+/// whole before anything gets applied.
+pub fn vmm_set_log_level(data: &VmmSetLogLevelData) -> ApiResult<()> {
+    let level = data
+        .level
+        .as_deref()
+        .map(LevelFilter::from_str)
+        .transpose()
+        .map_err(|_| ApiError::InvalidLogLevel)?;
+    if data.traces.iter().any(|trace| trace.target.is_empty()) {
+        return Err(ApiError::InvalidLogLevel);
+    }
+
+    if let Some(level) = level {
+        logger::set_level(level);
+    }
+    for trace in data.traces.iter() {
+        logger::set_trace(trace);
+    }
+
+    Ok(())
+}
+
+/// Enables or disables the API audit log.
+pub fn vmm_audit_log(data: &VmmAuditLogData) -> ApiResult<()> {
+    if audit::enable_audit_log(data.enabled) {
+        Ok(())
+    } else {
+        Err(ApiError::AuditLogNotConfigured)
+    }
+}
+
+pub fn vmm_vms(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Vec<String>> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmVms(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    match response_receiver.recv().map_err(ApiError::ResponseRecv)?? {
+        ApiResponsePayload::VmmVms(ids) => Ok(ids),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
 pub fn vm_resize(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -619,3 +1219,11 @@ pub fn vm_add_vsock(
 ) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::AddVsock(data))
 }
+
+pub fn vm_add_balloon(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<BalloonConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddBalloon(data))
+}
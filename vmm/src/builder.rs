@@ -0,0 +1,538 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Embedding Cloud Hypervisor in a Rust process.
+//!
+//! [`VmmBuilder`] starts a VMM thread in the calling process and returns a
+//! [`VmmHandle`], whose methods mirror the REST API endpoints of the VMM and
+//! of its default VM, the hosted VMs being only reachable through the REST
+//! API. The REST API server is only started when an API socket path or file
+//! descriptor is given to the builder, so an orchestrator can drive the VMM
+//! entirely through the handle:
+//!
+//! ```ignore
+//! let hypervisor = hypervisor::new()?;
+//! let vmm = VmmBuilder::new(hypervisor)
+//!     .version(env!("CARGO_PKG_VERSION"))
+//!     .build()?;
+//!
+//! vmm.vm_create(vm_config)?;
+//! vmm.vm_boot()?;
+//! ...
+//! vmm.vmm_shutdown()?;
+//! vmm.join();
+//! ```
+
+use crate::api::{
+    vm_request, vmm_audit_log, vmm_set_log_level, ApiError, ApiRequest, ApiResponse,
+    ApiResponsePayload, ApiResult, VmAction, VmConsoleData, VmInfo, VmMirrorDiskData,
+    VmPivotDiskData, VmPowerSupplyData, VmReceiveMigrationData, VmRemoveDeviceData, VmResizeData,
+    VmResizeZoneData, VmScheduleResumeData, VmSendMigrationData, VmSetNetLinkData,
+    VmSnapshotConfig, VmmAuditLogData, VmmPingResponse, VmmSetLogLevelData,
+};
+use crate::config::{
+    ApiVsockConfig, BalloonConfig, CheckpointConfig, DeviceConfig, DiskConfig, DiskSnapshotConfig,
+    FsConfig, HooksConfig, MemoryPressureConfig, NetConfig, PmemConfig, RestoreConfig,
+    SigtermConfig, VmConfig, VsockConfig,
+};
+use crate::{start_vmm_thread, Error, Result};
+use libc::EFD_NONBLOCK;
+use seccomp::SeccompAction;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Configures and starts a VMM thread.
+pub struct VmmBuilder {
+    hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    version: String,
+    api_socket_path: Option<String>,
+    api_socket_fd: Option<RawFd>,
+    api_vsock: Option<ApiVsockConfig>,
+    seccomp_action: SeccompAction,
+    state_dir: Option<PathBuf>,
+    hooks: Option<HooksConfig>,
+    on_sigterm: Option<SigtermConfig>,
+    memory_pressure: Option<MemoryPressureConfig>,
+}
+
+impl VmmBuilder {
+    /// Creates a builder for a VMM running on top of `hypervisor`, with no
+    /// REST API server and the default seccomp filters.
+    pub fn new(hypervisor: Arc<dyn hypervisor::Hypervisor>) -> Self {
+        VmmBuilder {
+            hypervisor,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            api_socket_path: None,
+            api_socket_fd: None,
+            api_vsock: None,
+            seccomp_action: SeccompAction::Trap,
+            state_dir: None,
+            hooks: None,
+            on_sigterm: None,
+            memory_pressure: None,
+        }
+    }
+
+    /// Sets the version reported by `vmm.ping`.
+    pub fn version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Serves the REST API on a UNIX socket created at `path`.
+    pub fn api_socket_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.api_socket_path = Some(path.into());
+        self
+    }
+
+    /// Serves the REST API on an already bound UNIX socket. It is ignored
+    /// when an API socket path is set.
+    pub fn api_socket_fd(mut self, fd: RawFd) -> Self {
+        self.api_socket_fd = Some(fd);
+        self
+    }
+
+    /// Serves the REST API on a vsock port too, to the allowed CIDs only,
+    /// see `--api-vsock`. An API socket must be set.
+    pub fn api_vsock(mut self, api_vsock: ApiVsockConfig) -> Self {
+        self.api_vsock = Some(api_vsock);
+        self
+    }
+
+    /// Sets the action taken when a VMM thread makes a forbidden syscall.
+    pub fn seccomp_action(mut self, seccomp_action: SeccompAction) -> Self {
+        self.seccomp_action = seccomp_action;
+        self
+    }
+
+    /// Persists the VM state under `state_dir`, see `--state-dir`.
+    pub fn state_dir<P: Into<PathBuf>>(mut self, state_dir: P) -> Self {
+        self.state_dir = Some(state_dir.into());
+        self
+    }
+
+    /// Runs executables on the VM lifecycle events, see `--hook`.
+    pub fn hooks(mut self, hooks: HooksConfig) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Handles SIGTERM and SIGINT, see `--on-sigterm`. The signals are left
+    /// to the calling process otherwise.
+    pub fn on_sigterm(mut self, on_sigterm: SigtermConfig) -> Self {
+        self.on_sigterm = Some(on_sigterm);
+        self
+    }
+
+    /// Inflates the balloons under host memory pressure, see
+    /// `--memory-pressure`.
+    pub fn memory_pressure(mut self, memory_pressure: MemoryPressureConfig) -> Self {
+        self.memory_pressure = Some(memory_pressure);
+        self
+    }
+
+    /// Starts the VMM thread, and the REST API server if one was configured.
+    pub fn build(self) -> Result<VmmHandle> {
+        let api_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let (api_sender, api_receiver) = channel();
+
+        let thread = start_vmm_thread(
+            self.version,
+            &self.api_socket_path,
+            self.api_socket_fd,
+            self.api_vsock,
+            api_evt.try_clone().map_err(Error::EventFdClone)?,
+            api_sender.clone(),
+            api_receiver,
+            &self.seccomp_action,
+            self.hypervisor,
+            self.state_dir,
+            self.hooks,
+            self.on_sigterm,
+            self.memory_pressure,
+        )?;
+
+        Ok(VmmHandle {
+            api_evt,
+            api_sender,
+            thread,
+        })
+    }
+}
+
+/// Controls a running VMM thread.
+///
+/// Each method sends the matching internal API request and waits for the
+/// VMM to reply. Methods returning `Option<Vec<u8>>` forward the JSON body
+/// the REST API would send back, if any.
+pub struct VmmHandle {
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl VmmHandle {
+    fn request<F>(&self, request: F) -> ApiResult<ApiResponsePayload>
+    where
+        F: FnOnce(Sender<ApiResponse>) -> ApiRequest,
+    {
+        let (response_sender, response_receiver) = channel();
+
+        self.api_sender
+            .send(request(response_sender))
+            .map_err(ApiError::RequestSend)?;
+        self.api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+        response_receiver.recv().map_err(ApiError::ResponseRecv)?
+    }
+
+    fn action(&self, action: VmAction) -> ApiResult<Option<Vec<u8>>> {
+        vm_request(&self.api_evt, &self.api_sender, action)
+    }
+
+    /// Creates the VM, see `vm.create`.
+    pub fn vm_create(&self, config: VmConfig) -> ApiResult<()> {
+        self.request(|sender| ApiRequest::VmCreate(Arc::new(Mutex::new(config)), sender))?;
+        Ok(())
+    }
+
+    /// Boots the VM, see `vm.boot`.
+    pub fn vm_boot(&self) -> ApiResult<()> {
+        self.action(VmAction::Boot).map(|_| ())
+    }
+
+    /// Deletes the VM, shutting it down first if needed, see `vm.delete`.
+    pub fn vm_delete(&self) -> ApiResult<()> {
+        self.action(VmAction::Delete).map(|_| ())
+    }
+
+    /// Shuts the VM down, see `vm.shutdown`.
+    pub fn vm_shutdown(&self) -> ApiResult<()> {
+        self.action(VmAction::Shutdown).map(|_| ())
+    }
+
+    /// Reboots the VM, see `vm.reboot`.
+    pub fn vm_reboot(&self) -> ApiResult<()> {
+        self.action(VmAction::Reboot).map(|_| ())
+    }
+
+    /// Pauses the VM, see `vm.pause`.
+    pub fn vm_pause(&self) -> ApiResult<()> {
+        self.action(VmAction::Pause).map(|_| ())
+    }
+
+    /// Resumes the VM, see `vm.resume`.
+    pub fn vm_resume(&self) -> ApiResult<()> {
+        self.action(VmAction::Resume).map(|_| ())
+    }
+
+    /// Triggers the ACPI power button, see `vm.power-button`.
+    pub fn vm_power_button(&self) -> ApiResult<()> {
+        self.action(VmAction::PowerButton).map(|_| ())
+    }
+
+    /// Triggers the ACPI sleep button, see `vm.sleep-button`.
+    pub fn vm_sleep_button(&self) -> ApiResult<()> {
+        self.action(VmAction::SleepButton).map(|_| ())
+    }
+
+    /// Injects an NMI into the vCPUs, see `vm.nmi`.
+    pub fn vm_nmi(&self) -> ApiResult<()> {
+        self.action(VmAction::Nmi).map(|_| ())
+    }
+
+    /// Updates the AC adapter and battery state, see `vm.power-supply`.
+    pub fn vm_power_supply(&self, data: VmPowerSupplyData) -> ApiResult<()> {
+        self.action(VmAction::PowerSupply(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Sends input to the console and returns its output, see `vm.console`.
+    pub fn vm_console(&self, data: VmConsoleData) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::Console(Arc::new(data)))
+    }
+
+    /// Schedules the VM boot or resume, see `vm.schedule-resume`.
+    pub fn vm_schedule_resume(&self, data: VmScheduleResumeData) -> ApiResult<()> {
+        self.action(VmAction::ScheduleResume(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Returns the VM configuration and state, see `vm.info`.
+    pub fn vm_info(&self) -> ApiResult<VmInfo> {
+        match self.request(ApiRequest::VmInfo)? {
+            ApiResponsePayload::VmInfo(info) => Ok(info),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+
+    /// Returns the device counters, see `vm.counters`.
+    pub fn vm_counters(&self) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::Counters)
+    }
+
+    /// Returns the guest RAM regions, see `vm.memory-regions`.
+    pub fn vm_memory_regions(&self) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::MemoryRegions)
+    }
+
+    /// Returns the effective configuration of the VM, see `vm.config`.
+    pub fn vm_config(&self) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::Config)
+    }
+
+    /// Resizes the VM, see `vm.resize`.
+    pub fn vm_resize(&self, data: VmResizeData) -> ApiResult<()> {
+        self.action(VmAction::Resize(Arc::new(data))).map(|_| ())
+    }
+
+    /// Resizes a memory zone, see `vm.resize-zone`.
+    pub fn vm_resize_zone(&self, data: VmResizeZoneData) -> ApiResult<()> {
+        self.action(VmAction::ResizeZone(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Adds a VFIO device, see `vm.add-device`.
+    pub fn vm_add_device(&self, config: DeviceConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddDevice(Arc::new(config)))
+    }
+
+    /// Adds a disk, see `vm.add-disk`.
+    pub fn vm_add_disk(&self, config: DiskConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddDisk(Arc::new(config)))
+    }
+
+    /// Adds a virtio-fs device, see `vm.add-fs`.
+    pub fn vm_add_fs(&self, config: FsConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddFs(Arc::new(config)))
+    }
+
+    /// Adds a pmem device, see `vm.add-pmem`.
+    pub fn vm_add_pmem(&self, config: PmemConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddPmem(Arc::new(config)))
+    }
+
+    /// Adds a network device, see `vm.add-net`.
+    pub fn vm_add_net(&self, config: NetConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddNet(Arc::new(config)))
+    }
+
+    /// Adds a vsock device, see `vm.add-vsock`.
+    pub fn vm_add_vsock(&self, config: VsockConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddVsock(Arc::new(config)))
+    }
+
+    /// Adds a balloon device, see `vm.add-balloon`.
+    pub fn vm_add_balloon(&self, config: BalloonConfig) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::AddBalloon(Arc::new(config)))
+    }
+
+    /// Removes a device, see `vm.remove-device`.
+    pub fn vm_remove_device<S: Into<String>>(&self, id: S) -> ApiResult<()> {
+        let data = VmRemoveDeviceData { id: id.into() };
+        self.action(VmAction::RemoveDevice(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Snapshots the paused VM to `destination_url`, see `vm.snapshot`.
+    pub fn vm_snapshot<S: Into<String>>(&self, destination_url: S) -> ApiResult<()> {
+        let data = VmSnapshotConfig {
+            destination_url: destination_url.into(),
+            parent_url: None,
+        };
+        self.action(VmAction::Snapshot(Arc::new(data))).map(|_| ())
+    }
+
+    /// Snapshots the paused VM to `destination_url`, only saving the guest
+    /// memory written since the snapshot at `parent_url`, see `vm.snapshot`.
+    pub fn vm_snapshot_incremental<S: Into<String>, P: Into<String>>(
+        &self,
+        destination_url: S,
+        parent_url: P,
+    ) -> ApiResult<()> {
+        let data = VmSnapshotConfig {
+            destination_url: destination_url.into(),
+            parent_url: Some(parent_url.into()),
+        };
+        self.action(VmAction::Snapshot(Arc::new(data))).map(|_| ())
+    }
+
+    /// Restores a VM from a snapshot, see `vm.restore`.
+    pub fn vm_restore(&self, config: RestoreConfig) -> ApiResult<()> {
+        self.action(VmAction::Restore(Arc::new(config))).map(|_| ())
+    }
+
+    /// Receives a migrated VM, see `vm.receive-migration`.
+    pub fn vm_receive_migration<S: Into<String>>(&self, receiver_url: S) -> ApiResult<()> {
+        let data = VmReceiveMigrationData {
+            receiver_url: receiver_url.into(),
+        };
+        self.action(VmAction::ReceiveMigration(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Migrates the VM to `destination_url`, see `vm.send-migration`.
+    pub fn vm_send_migration<S: Into<String>>(&self, destination_url: S) -> ApiResult<()> {
+        let data = VmSendMigrationData {
+            destination_url: destination_url.into(),
+        };
+        self.action(VmAction::SendMigration(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Sets the link state of a network device, see `vm.set-net-link`.
+    pub fn vm_set_net_link(&self, data: VmSetNetLinkData) -> ApiResult<()> {
+        self.action(VmAction::SetNetLink(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Starts mirroring a disk, see `vm.mirror-disk`.
+    pub fn vm_mirror_disk(&self, data: VmMirrorDiskData) -> ApiResult<()> {
+        self.action(VmAction::MirrorDisk(Arc::new(data)))
+            .map(|_| ())
+    }
+
+    /// Switches a disk to its mirror, see `vm.pivot-disk`.
+    pub fn vm_pivot_disk(&self, data: VmPivotDiskData) -> ApiResult<()> {
+        self.action(VmAction::PivotDisk(Arc::new(data))).map(|_| ())
+    }
+
+    /// Snapshots a disk onto a new overlay, see `vm.disk-snapshot`.
+    pub fn vm_disk_snapshot(&self, config: DiskSnapshotConfig) -> ApiResult<()> {
+        self.action(VmAction::DiskSnapshot(Arc::new(config)))
+            .map(|_| ())
+    }
+
+    /// Checkpoints the running VM, see `PUT vm.checkpoint`.
+    pub fn vm_checkpoint(&self, config: CheckpointConfig) -> ApiResult<()> {
+        self.action(VmAction::Checkpoint(Arc::new(config)))
+            .map(|_| ())
+    }
+
+    /// Returns the progress of the last checkpoint, see `GET vm.checkpoint`.
+    pub fn vm_checkpoint_info(&self) -> ApiResult<Option<Vec<u8>>> {
+        self.action(VmAction::CheckpointInfo)
+    }
+
+    /// Returns the VMM version, see `vmm.ping`.
+    pub fn vmm_ping(&self) -> ApiResult<VmmPingResponse> {
+        match self.request(ApiRequest::VmmPing)? {
+            ApiResponsePayload::VmmPing(pong) => Ok(pong),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+
+    /// Returns the VMM metrics in the Prometheus text format, see
+    /// `vmm.metrics`.
+    pub fn vmm_metrics(&self) -> ApiResult<String> {
+        match self.request(ApiRequest::VmmMetrics)? {
+            ApiResponsePayload::VmmMetrics(metrics) => Ok(metrics),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+
+    /// Returns the identifiers of the hosted VMs, see `vms`.
+    pub fn vmm_vms(&self) -> ApiResult<Vec<String>> {
+        match self.request(ApiRequest::VmmVms)? {
+            ApiResponsePayload::VmmVms(ids) => Ok(ids),
+            _ => Err(ApiError::ResponsePayloadType),
+        }
+    }
+
+    /// Changes the log level of the whole process, see `vmm.set-log-level`.
+    pub fn vmm_set_log_level(&self, data: VmmSetLogLevelData) -> ApiResult<()> {
+        vmm_set_log_level(&data)
+    }
+
+    /// Enables or disables the API audit log, see `vmm.audit-log`.
+    pub fn vmm_audit_log(&self, enabled: bool) -> ApiResult<()> {
+        vmm_audit_log(&VmmAuditLogData { enabled })
+    }
+
+    /// Shuts the VM, if any, and the VMM thread down, see `vmm.shutdown`.
+    /// Use [`VmmHandle::join`] to wait for the thread to exit.
+    pub fn vmm_shutdown(&self) -> ApiResult<()> {
+        self.request(ApiRequest::VmmShutdown)?;
+        Ok(())
+    }
+
+    /// Waits for the VMM thread to exit, either after `vmm.shutdown` or
+    /// because the guest shut itself down.
+    pub fn join(self) -> thread::Result<Result<()>> {
+        self.thread.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serves the requests sent through a handle the way the VMM control
+    // loop would, without needing a hypervisor.
+    fn fake_vmm() -> VmmHandle {
+        let api_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (api_sender, api_receiver) = channel();
+
+        let thread = thread::spawn(move || loop {
+            let (sender, response) = match api_receiver.recv().unwrap() {
+                ApiRequest::VmmPing(sender) => (
+                    sender,
+                    ApiResponsePayload::VmmPing(VmmPingResponse {
+                        version: "1.0".to_string(),
+                    }),
+                ),
+                ApiRequest::VmCounters(sender) => {
+                    (sender, ApiResponsePayload::VmAction(Some(b"{}".to_vec())))
+                }
+                ApiRequest::VmBoot(sender) => (sender, ApiResponsePayload::Empty),
+                ApiRequest::VmCheckpointInfo(sender) => (
+                    sender,
+                    ApiResponsePayload::VmAction(Some(b"{\"state\":\"Copying\"}".to_vec())),
+                ),
+                ApiRequest::VmmVms(sender) => {
+                    (sender, ApiResponsePayload::VmmVms(vec!["vm0".to_string()]))
+                }
+                ApiRequest::VmPause(sender) => {
+                    (sender, ApiResponsePayload::VmmMetrics(String::new()))
+                }
+                ApiRequest::VmmShutdown(sender) => {
+                    sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                    return Ok(());
+                }
+                _ => unreachable!(),
+            };
+            sender.send(Ok(response)).unwrap();
+        });
+
+        VmmHandle {
+            api_evt,
+            api_sender,
+            thread,
+        }
+    }
+
+    #[test]
+    fn test_vmm_handle_requests() {
+        let vmm = fake_vmm();
+
+        assert_eq!(vmm.vmm_ping().unwrap().version, "1.0");
+        assert_eq!(vmm.vm_counters().unwrap(), Some(b"{}".to_vec()));
+        assert!(vmm.vm_boot().is_ok());
+        assert_eq!(
+            vmm.vm_checkpoint_info().unwrap(),
+            Some(b"{\"state\":\"Copying\"}".to_vec())
+        );
+        assert_eq!(vmm.vmm_vms().unwrap(), vec!["vm0".to_string()]);
+        // A reply not matching the request is reported as such.
+        assert!(matches!(vmm.vm_pause(), Err(ApiError::ResponsePayloadType)));
+
+        vmm.vmm_shutdown().unwrap();
+        assert!(vmm.join().unwrap().is_ok());
+    }
+}
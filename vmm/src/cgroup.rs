@@ -0,0 +1,296 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Minimal support for the cgroup v2 unified hierarchy.
+//!
+//! The cgroups are created below the one the VMM process has been started
+//! in, so that the VMM doesn't need to know about the way the host manages
+//! its resources, and only needs write access to its own cgroup.
+
+use crate::config::CgroupConfig;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const CGROUP2_FS_TYPE: &str = "cgroup2";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The cgroup v2 hierarchy is not mounted.
+    NotMounted,
+
+    /// The VMM process doesn't belong to any cgroup v2.
+    NoCurrentCgroup,
+
+    /// Cannot read a cgroup file.
+    Read(PathBuf, io::Error),
+
+    /// Cannot write a cgroup file.
+    Write(PathBuf, io::Error),
+
+    /// Cannot create a cgroup.
+    Create(PathBuf, io::Error),
+
+    /// Cannot find the block device the I/O limits apply to.
+    IoDevice(PathBuf, io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A cgroup of the unified hierarchy. The cgroups created through this
+/// structure are removed when it is dropped, which only succeeds once all the
+/// threads they contain have exited.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    owned: bool,
+}
+
+impl Cgroup {
+    /// Returns the cgroup the VMM process belongs to.
+    pub fn current() -> Result<Self> {
+        let cgroup_path = Path::new("/proc/self/cgroup");
+        let cgroup =
+            fs::read_to_string(cgroup_path).map_err(|e| Error::Read(cgroup_path.into(), e))?;
+        let relative_path = cgroup2_relative_path(&cgroup).ok_or(Error::NoCurrentCgroup)?;
+
+        Cgroup::from_path(relative_path)
+    }
+
+    /// Returns an existing cgroup, from its path relative to the root of the
+    /// hierarchy.
+    pub fn from_path(relative_path: &str) -> Result<Self> {
+        let mounts_path = Path::new("/proc/self/mounts");
+        let mounts =
+            fs::read_to_string(mounts_path).map_err(|e| Error::Read(mounts_path.into(), e))?;
+        let mount_point = cgroup2_mount_point(&mounts).ok_or(Error::NotMounted)?;
+
+        Ok(Cgroup {
+            path: Path::new(mount_point).join(relative_path.trim_start_matches('/')),
+            owned: false,
+        })
+    }
+
+    /// Creates a threaded child cgroup, enabling the given controllers for
+    /// it. Contrary to the domain cgroups, threads of the same process can be
+    /// spread across the threaded cgroups.
+    pub fn create_threaded_child(&self, name: &str, controllers: &[&str]) -> Result<Self> {
+        let child = self.create_child(name)?;
+        child.write("cgroup.type", "threaded")?;
+        self.enable_controllers(controllers)?;
+
+        Ok(child)
+    }
+
+    /// Lets the children of the cgroup use the given controllers. Unless the
+    /// cgroup is the root one, it must not contain any process for domain
+    /// controllers, such as "memory" or "io", to be enabled.
+    pub fn enable_controllers(&self, controllers: &[&str]) -> Result<()> {
+        for controller in controllers.iter() {
+            self.write("cgroup.subtree_control", &format!("+{}", controller))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn disable_controllers(&self, controllers: &[&str]) -> Result<()> {
+        for controller in controllers.iter() {
+            self.write("cgroup.subtree_control", &format!("-{}", controller))?;
+        }
+
+        Ok(())
+    }
+
+    fn create_child(&self, name: &str) -> Result<Self> {
+        let path = self.path.join(name);
+        match fs::create_dir(&path) {
+            Ok(()) => {}
+            // Left behind by a VM whose threads were still running when it
+            // got destroyed.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(Error::Create(path, e)),
+        }
+
+        Ok(Cgroup { path, owned: true })
+    }
+
+    /// Writes one of the cgroup interface files, such as "cpu.max".
+    pub fn write(&self, file: &str, value: &str) -> Result<()> {
+        let path = self.path.join(file);
+        fs::write(&path, value).map_err(|e| Error::Write(path, e))
+    }
+
+    /// Moves a thread, identified by its thread ID, to the cgroup.
+    pub fn add_thread(&self, tid: libc::pid_t) -> Result<()> {
+        self.write("cgroup.threads", &tid.to_string())
+    }
+
+    /// Moves a process, with all its threads, to the cgroup.
+    pub fn add_process(&self, pid: u32) -> Result<()> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+}
+
+/// Cgroup the VMM process is moved to for the lifetime of a VM. This must
+/// happen before the guest memory is allocated, as the memory is charged to
+/// the cgroup of the thread faulting it in, and before the vhost devices are
+/// created, as their kernel workers join the cgroup of their owner.
+pub struct VmCgroup {
+    cgroup: Cgroup,
+    previous: Cgroup,
+    // Controllers enabled on the previous cgroup for the one of the VM
+    controllers: Vec<&'static str>,
+}
+
+impl VmCgroup {
+    pub fn new(config: &CgroupConfig) -> Result<Self> {
+        let previous = Cgroup::current()?;
+        let pid = std::process::id();
+
+        let mut controllers = Vec::new();
+        if config.memory_high.is_some() {
+            controllers.push("memory");
+        }
+        let io_max_line = config
+            .io_device
+            .as_ref()
+            .map(|io_device| {
+                fs::metadata(io_device)
+                    .map(|metadata| io_max(metadata.rdev(), &config.io_limits()))
+                    .map_err(|e| Error::IoDevice(io_device.clone(), e))
+            })
+            .transpose()?;
+        if io_max_line.is_some() {
+            controllers.push("io");
+        }
+
+        let cgroup = match &config.path {
+            Some(path) => Cgroup::from_path(path)?,
+            None => previous.create_child(&format!("cloud-hypervisor-{}", pid))?,
+        };
+        cgroup.add_process(pid)?;
+
+        let mut vm_cgroup = VmCgroup {
+            cgroup,
+            previous,
+            controllers: Vec::new(),
+        };
+
+        // A cgroup provided by the caller is expected to have been set up
+        // with the needed controllers already. The controllers are recorded
+        // one by one, so that they can be disabled even if one of them can't
+        // be enabled.
+        if config.path.is_none() {
+            for controller in controllers {
+                vm_cgroup.previous.enable_controllers(&[controller])?;
+                vm_cgroup.controllers.push(controller);
+            }
+        }
+
+        if let Some(memory_high) = config.memory_high {
+            vm_cgroup
+                .cgroup
+                .write("memory.high", &memory_high.to_string())?;
+        }
+        if let Some(io_max_line) = io_max_line {
+            vm_cgroup.cgroup.write("io.max", &io_max_line)?;
+        }
+
+        Ok(vm_cgroup)
+    }
+}
+
+impl Drop for VmCgroup {
+    fn drop(&mut self) {
+        // The controllers must be disabled before the process can move back
+        // to the previous cgroup, and the process must have moved back for
+        // the cgroup of the VM to be removed.
+        if let Err(e) = self
+            .previous
+            .disable_controllers(&self.controllers)
+            .and_then(|_| self.previous.add_process(std::process::id()))
+        {
+            warn!("Error moving the VMM back to its cgroup: {:?}", e);
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if self.owned {
+            if let Err(e) = fs::remove_dir(&self.path) {
+                warn!("Error removing cgroup {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Returns the thread ID of the calling thread.
+pub fn gettid() -> libc::pid_t {
+    // Safe because the syscall has no argument and can't fail.
+    unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+}
+
+fn cgroup2_mount_point(mounts: &str) -> Option<&str> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount_point = fields.nth(1)?;
+        if fields.next()? == CGROUP2_FS_TYPE {
+            Some(mount_point)
+        } else {
+            None
+        }
+    })
+}
+
+// Formats the "io.max" line for a block device, from its device number.
+fn io_max(rdev: u64, limits: &[(&str, u64)]) -> String {
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    let mut io_max = format!("{}:{}", major, minor);
+    for (key, limit) in limits.iter() {
+        io_max.push_str(&format!(" {}={}", key, limit));
+    }
+
+    io_max
+}
+
+// The unified hierarchy is the one with the "0" ID and no controller.
+fn cgroup2_relative_path(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup2_paths() {
+        let mounts = "sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0\n\
+                      cgroup2 /sys/fs/cgroup cgroup2 rw,nosuid,nodev,noexec,relatime 0 0\n";
+        assert_eq!(cgroup2_mount_point(mounts), Some("/sys/fs/cgroup"));
+        assert_eq!(cgroup2_mount_point("sysfs /sys sysfs rw 0 0\n"), None);
+
+        let cgroup = "1:name=systemd:/user.slice\n\
+                      0::/user.slice/user-1000.slice/session-1.scope\n";
+        assert_eq!(
+            cgroup2_relative_path(cgroup),
+            Some("/user.slice/user-1000.slice/session-1.scope")
+        );
+        assert_eq!(cgroup2_relative_path("1:cpu:/\n"), None);
+    }
+
+    #[test]
+    fn test_io_max() {
+        // /dev/sda
+        assert_eq!(
+            io_max(0x800, &[("rbps", 1048576), ("wiops", 100)]),
+            "8:0 rbps=1048576 wiops=100"
+        );
+        // /dev/nvme0n1p1, with a minor number above 255
+        assert_eq!(io_max(0x101_0301, &[("wbps", 1)]), "259:4097 wbps=1");
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Offload of the guest pages left untouched for a while.
+//!
+//! The guest pages are tracked through the kernel idle page tracking: every
+//! scan, the pages found idle since the previous one are cold, and are paged
+//! out with `MADV_PAGEOUT`. The guest RAM being a memfd, the cold pages go to
+//! the host swap, compressed in memory by zswap when it's enabled. As the
+//! kernel may keep some of them in memory, they're only counted as offloaded
+//! once found out of memory at the next scan. Since the VM is overcommitted
+//! at the price of the guest faulting these pages back, the offloaded pages
+//! found back in memory are counted as refaulted.
+
+use crate::GuestMemoryMmap;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use vm_memory::{GuestMemory, GuestMemoryAtomic, GuestMemoryRegion};
+
+const PAGEMAP_PATH: &str = "/proc/self/pagemap";
+const PAGE_IDLE_BITMAP_PATH: &str = "/sys/kernel/mm/page_idle/bitmap";
+const PAGE_SIZE: u64 = 4096;
+// Pages whose pagemap entries are read at once.
+const SCAN_CHUNK_PAGES: u64 = 512;
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_PFN_MASK: u64 = (1 << 55) - 1;
+// Only defined by the most recent versions of the libc crate.
+const MADV_PAGEOUT: libc::c_int = 21;
+
+/// Gets the frame a pagemap entry maps its page to, if the page is present.
+/// The frame reads as 0 without CAP_SYS_ADMIN.
+pub fn pagemap_pfn(entry: u64) -> Option<u64> {
+    if entry & PAGEMAP_PRESENT == 0 {
+        return None;
+    }
+    Some(entry & PAGEMAP_PFN_MASK).filter(|pfn| *pfn != 0)
+}
+
+/// Merges the indexes of the cold pages, in increasing order, into ranges of
+/// consecutive pages, given as the first page and the number of pages.
+pub fn cold_ranges(pages: &[u64]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for page in pages {
+        match ranges.last_mut() {
+            Some((first, count)) if *first + *count == *page => *count += 1,
+            _ => ranges.push((*page, 1)),
+        }
+    }
+    ranges
+}
+
+// What a scan found about a page.
+#[derive(Debug, PartialEq)]
+enum PageChange {
+    // Idle since the previous scan, to be paged out.
+    Cold,
+    // Out of memory since it was paged out at the previous scan.
+    Offloaded,
+    // Back in memory since it was offloaded.
+    Refaulted,
+    Unchanged,
+}
+
+// Pages of a region, one bit per page.
+struct RegionPages {
+    // Paged out at the previous scan, which the kernel may have ignored.
+    advised: Vec<u64>,
+    // Out of memory since they were paged out.
+    offloaded: Vec<u64>,
+}
+
+impl RegionPages {
+    fn new(pages: u64) -> Self {
+        let words = ((pages + 63) / 64) as usize;
+        RegionPages {
+            advised: vec![0; words],
+            offloaded: vec![0; words],
+        }
+    }
+
+    fn resize(&mut self, pages: u64) {
+        let words = ((pages + 63) / 64) as usize;
+        self.advised.resize(words, 0);
+        self.offloaded.resize(words, 0);
+    }
+
+    // Records what the scan found about `page`, its idle state if it's in
+    // memory, compared to the `previous` scan.
+    fn update(&mut self, previous: &RegionPages, page: u64, idle: Option<bool>) -> PageChange {
+        let (word, bit) = ((page / 64) as usize, 1 << (page % 64));
+        match idle {
+            None if previous.offloaded[word] & bit != 0 => {
+                self.offloaded[word] |= bit;
+                PageChange::Unchanged
+            }
+            None if previous.advised[word] & bit != 0 => {
+                self.offloaded[word] |= bit;
+                PageChange::Offloaded
+            }
+            None => PageChange::Unchanged,
+            Some(true) => {
+                self.advised[word] |= bit;
+                PageChange::Cold
+            }
+            Some(false) if previous.offloaded[word] & bit != 0 => PageChange::Refaulted,
+            Some(false) => PageChange::Unchanged,
+        }
+    }
+}
+
+/// Bytes offloaded and faulted back in since the VM started.
+#[derive(Default)]
+pub struct ColdPageCounters {
+    pub offloaded_bytes: AtomicU64,
+    pub refaulted_bytes: AtomicU64,
+}
+
+// The idle page bitmap can only be accessed 8 bytes at a time. Consecutive
+// pages being often backed by consecutive frames, the last word read is kept
+// along with the bits to set in it.
+struct PageIdleBitmap {
+    file: File,
+    word: Option<(u64, u64, u64)>,
+}
+
+impl PageIdleBitmap {
+    // Tells whether the page in `pfn` is still idle, and marks it idle for
+    // the next scan.
+    fn check_and_mark(&mut self, pfn: u64) -> io::Result<bool> {
+        let index = pfn / 64;
+        let bit = 1 << (pfn % 64);
+        match self.word {
+            Some((current, _, _)) if current == index => {}
+            _ => {
+                self.flush()?;
+                let mut value = [0u8; 8];
+                self.file.read_exact_at(&mut value, index * 8)?;
+                self.word = Some((index, u64::from_ne_bytes(value), 0));
+            }
+        }
+
+        let (_, value, marks) = self.word.as_mut().unwrap();
+        *marks |= bit;
+        Ok(*value & bit != 0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some((index, _, marks)) = self.word.take() {
+            self.file.write_all_at(&marks.to_ne_bytes(), index * 8)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ColdPageScanner {
+    guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    pagemap: File,
+    idle: PageIdleBitmap,
+    // Pages paged out, or offloaded and not faulted back in yet, per region
+    // host address.
+    pages: BTreeMap<u64, RegionPages>,
+    counters: Arc<ColdPageCounters>,
+}
+
+impl ColdPageScanner {
+    /// Opens the files the scans rely on, before any seccomp filter is
+    /// applied.
+    pub fn new(
+        guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        counters: Arc<ColdPageCounters>,
+    ) -> io::Result<Self> {
+        let pagemap = File::open(PAGEMAP_PATH)?;
+        let idle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(PAGE_IDLE_BITMAP_PATH)?;
+
+        Ok(ColdPageScanner {
+            guest_memory,
+            pagemap,
+            idle: PageIdleBitmap {
+                file: idle,
+                word: None,
+            },
+            pages: BTreeMap::new(),
+            counters,
+        })
+    }
+
+    /// Scans the guest memory every `period`, until `stop` is dropped.
+    pub fn run(&mut self, period: Duration, stop: Receiver<()>) {
+        loop {
+            match stop.recv_timeout(period) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            if let Err(e) = self.scan() {
+                error!("Error scanning the guest memory for cold pages: {}", e);
+                return;
+            }
+        }
+    }
+
+    fn scan(&mut self) -> io::Result<()> {
+        let guest_memory = self.guest_memory.memory();
+        let mut offloaded = 0;
+        let mut refaulted = 0;
+
+        for region in guest_memory.iter() {
+            let addr = region.as_ptr() as u64;
+            let pages = region.len() / PAGE_SIZE;
+            let mut previous = self
+                .pages
+                .remove(&addr)
+                .unwrap_or_else(|| RegionPages::new(pages));
+            previous.resize(pages);
+            let mut current = RegionPages::new(pages);
+
+            let mut cold = Vec::new();
+            let mut first = 0;
+            while first < pages {
+                let count = std::cmp::min(SCAN_CHUNK_PAGES, pages - first);
+                let mut entries = vec![0u8; (count * 8) as usize];
+                self.pagemap
+                    .read_exact_at(&mut entries, (addr / PAGE_SIZE + first) * 8)?;
+
+                for (i, entry) in entries.chunks_exact(8).enumerate() {
+                    let page = first + i as u64;
+                    let mut value = [0u8; 8];
+                    value.copy_from_slice(entry);
+                    // A page the kernel kept in memory is still idle, while
+                    // the one faulted back in is a new, accessed, frame.
+                    let idle = match pagemap_pfn(u64::from_ne_bytes(value)) {
+                        Some(pfn) => Some(self.idle.check_and_mark(pfn)?),
+                        None => None,
+                    };
+
+                    match current.update(&previous, page, idle) {
+                        PageChange::Cold => cold.push(page),
+                        PageChange::Offloaded => offloaded += PAGE_SIZE,
+                        PageChange::Refaulted => refaulted += PAGE_SIZE,
+                        PageChange::Unchanged => {}
+                    }
+                }
+                first += count;
+            }
+            self.idle.flush()?;
+
+            for (first, count) in cold_ranges(&cold) {
+                // Safe because the range is within the region, which the
+                // guard on the guest memory keeps mapped.
+                let ret = unsafe {
+                    libc::madvise(
+                        (addr + first * PAGE_SIZE) as *mut libc::c_void,
+                        (count * PAGE_SIZE) as usize,
+                        MADV_PAGEOUT,
+                    )
+                };
+                if ret != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            self.pages.insert(addr, current);
+        }
+
+        self.counters
+            .offloaded_bytes
+            .fetch_add(offloaded, Ordering::Relaxed);
+        self.counters
+            .refaulted_bytes
+            .fetch_add(refaulted, Ordering::Relaxed);
+        if offloaded > 0 {
+            event!(
+                "vm",
+                "cold-pages-offloaded",
+                "bytes",
+                offloaded.to_string(),
+                "refaulted_bytes",
+                refaulted.to_string()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pagemap_pfn() {
+        assert_eq!(pagemap_pfn(0), None);
+        assert_eq!(pagemap_pfn(PAGEMAP_PRESENT | 0x1234), Some(0x1234));
+        // Soft-dirty and exclusive bits
+        assert_eq!(
+            pagemap_pfn(PAGEMAP_PRESENT | 3 << 55 | 0x1234),
+            Some(0x1234)
+        );
+        // Swapped out page
+        assert_eq!(pagemap_pfn(1 << 62 | 0x1234), None);
+        // Frame hidden without CAP_SYS_ADMIN
+        assert_eq!(pagemap_pfn(PAGEMAP_PRESENT), None);
+    }
+
+    #[test]
+    fn test_page_changes() {
+        let mut previous = RegionPages::new(64);
+        let mut current = RegionPages::new(64);
+        assert_eq!(current.update(&previous, 0, Some(true)), PageChange::Cold);
+        assert_eq!(current.update(&previous, 1, Some(true)), PageChange::Cold);
+        assert_eq!(current.update(&previous, 2, None), PageChange::Unchanged);
+
+        // Page 1 was kept in memory, it's only paged out again.
+        previous = current;
+        current = RegionPages::new(64);
+        assert_eq!(current.update(&previous, 0, None), PageChange::Offloaded);
+        assert_eq!(current.update(&previous, 1, Some(true)), PageChange::Cold);
+
+        // Page 0 is counted once, until faulted back in.
+        previous = current;
+        current = RegionPages::new(64);
+        assert_eq!(current.update(&previous, 0, None), PageChange::Unchanged);
+        assert_eq!(
+            current.update(&previous, 1, Some(false)),
+            PageChange::Unchanged
+        );
+
+        previous = current;
+        current = RegionPages::new(64);
+        assert_eq!(
+            current.update(&previous, 0, Some(false)),
+            PageChange::Refaulted
+        );
+        assert_eq!(current.update(&previous, 1, None), PageChange::Unchanged);
+    }
+
+    #[test]
+    fn test_cold_ranges() {
+        assert_eq!(cold_ranges(&[]), vec![]);
+        assert_eq!(
+            cold_ranges(&[0, 1, 2, 5, 7, 8]),
+            vec![(0, 3), (5, 1), (7, 2)]
+        );
+    }
+}
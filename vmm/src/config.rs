@@ -3,27 +3,62 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use block_util::nbd::NbdAddress;
+use block_util::rbd::RbdAddress;
 use clap::ArgMatches;
+use devices::usb::xhci::MAX_USB_DEVICES;
 use net_util::MacAddr;
 use option_parser::{
-    ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, TupleTwoIntegers,
+    split_list, ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle,
+    TupleTwoIntegers,
 };
-use std::convert::From;
+use serde::de::{self, Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::{From, TryFrom};
 use std::fmt;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
 
+use uuid::Uuid;
 use virtio_devices::{RateLimiterConfig, TokenBucketConfig};
 
 pub const DEFAULT_VCPUS: u8 = 1;
+pub const DEFAULT_CPUS_PERIOD: u64 = 100_000;
+// Bounds of the cgroup v2 CPU bandwidth control, in microseconds.
+const MIN_CPUS_QUOTA: u64 = 1_000;
+const MIN_CPUS_PERIOD: u64 = 1_000;
+const MAX_CPUS_PERIOD: u64 = 1_000_000;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
 pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
 pub const DEFAULT_NUM_QUEUES_VUBLK: usize = 1;
 pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
+pub const DEFAULT_QUEUE_SIZE_VSOCK: u16 = 256;
+// The RX, TX and event queues of virtio-vsock
+const NUM_QUEUES_VSOCK: usize = 3;
+// Largest queue size allowed by the virtio specification
+const MAX_QUEUE_SIZE: u16 = 32768;
+// Largest number of queues a virtio-pci device can have, each of them getting
+// one of its 2048 MSI-X vectors, plus one for the configuration changes.
+const MAX_QUEUES_PER_DEVICE: usize = 2047;
+// Length of the disk identifier reported by virtio-blk (VIRTIO_BLK_ID_BYTES)
+const MAX_DISK_SERIAL_LEN: usize = 20;
+// Longest a queue thread of a disk may busy poll for after each event
+const MAX_DISK_POLL_US: u64 = 1000;
+// Largest number of I/O worker threads for each queue of a disk
+const MAX_DISK_WORKERS: usize = 64;
+
+// SMBIOS counts the OEM strings on a byte, one of them holding the creation
+// time of the VM.
+const MAX_IDENTITY_METADATA: usize = 254;
+// Minimum MTU for an Ethernet interface
+pub const MIN_NET_MTU: u16 = 68;
+// Size of the blocks of free pages reported by a Linux guest, matching the
+// pageblock size with 4KiB pages.
+const FREE_PAGE_REPORTING_BLOCK_SIZE: u64 = 2 << 20;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
@@ -42,6 +77,8 @@ pub enum Error {
     ParseVsockCidMissing,
     /// Missing restore source_url parameter.
     ParseRestoreSourceUrlMissing,
+    /// Both recording and replaying the inputs of the restored VM.
+    ParseRestoreInputLogConflict,
     /// Error parsing CPU options
     ParseCpus(OptionParserError),
     /// Error parsing memory options
@@ -74,6 +111,18 @@ pub enum Error {
     ParseVsock(OptionParserError),
     /// Failed to parse restore parameters
     ParseRestore(OptionParserError),
+    /// Failed to parse hooks parameters
+    ParseHooks(OptionParserError),
+    /// Failed to parse the termination signals parameters
+    ParseSigterm(OptionParserError),
+    /// Failed to parse the memory pressure parameters
+    ParseMemoryPressure(OptionParserError),
+    /// Failed to parse the API vsock parameters
+    ParseApiVsock(OptionParserError),
+    /// Missing 'port' from the API vsock parameters
+    ParseApiVsockPortMissing,
+    /// Missing 'allowed_cids' from the API vsock parameters
+    ParseApiVsockAllowedCidsMissing,
     /// Failed to parse SGX EPC parameters
     #[cfg(target_arch = "x86_64")]
     ParseSgxEpc(OptionParserError),
@@ -82,6 +131,62 @@ pub enum Error {
     ParseSgxEpcIdMissing,
     /// Failed to parse NUMA parameters
     ParseNuma(OptionParserError),
+    /// Error parsing the NUMA placement policy
+    ParseNumaPolicy(ParseNumaPolicyError),
+    /// Error parsing the policy applied on device worker failures
+    ParseWorkerFailurePolicy(ParseWorkerFailurePolicyError),
+    /// Failed to parse PCI subsystem parameters
+    ParsePciSubsystem(OptionParserError),
+    /// Missing 'id' from PCI subsystem
+    ParsePciSubsystemIdMissing,
+    /// Missing 'vendor' from PCI subsystem
+    ParsePciSubsystemVendorMissing,
+    /// Missing 'device' from PCI subsystem
+    ParsePciSubsystemDeviceMissing,
+    /// Failed to parse virtio features parameters
+    ParseVirtioFeatures(OptionParserError),
+    /// Missing 'id' from virtio features
+    ParseVirtioFeaturesIdMissing,
+    /// Failed to parse suspend parameters
+    ParseSuspend(OptionParserError),
+    /// Failed to parse power supply parameters
+    ParsePowerSupply(OptionParserError),
+    /// Failed to parse cgroup parameters
+    ParseCgroup(OptionParserError),
+    /// Failed to parse platform parameters
+    ParsePlatform(OptionParserError),
+    /// Failed to parse input log parameters
+    ParseInputLog(OptionParserError),
+    /// Missing 'path' from input log
+    ParseInputLogPathMissing,
+    /// Failed to parse USB device parameters
+    ParseUsb(OptionParserError),
+    /// Missing 'path' from USB device
+    ParseUsbPathMissing,
+    /// Failed to parse TPM parameters
+    ParseTpm(OptionParserError),
+    /// Neither or both of 'socket' and 'device' given for the TPM
+    ParseTpmBackend,
+    /// TPM control channel given without an emulator socket
+    ParseTpmCtrl,
+    /// Failed to parse identity parameters
+    ParseIdentity(OptionParserError),
+    /// Failed to parse disk snapshot parameters
+    ParseDiskSnapshot(OptionParserError),
+    /// Missing 'id' from disk snapshot
+    ParseDiskSnapshotIdMissing,
+    /// Missing 'overlay' from disk snapshot
+    ParseDiskSnapshotOverlayMissing,
+    /// Failed to parse checkpoint parameters
+    ParseCheckpoint(OptionParserError),
+    /// Missing 'destination_url' from checkpoint
+    ParseCheckpointDestinationUrlMissing,
+    /// Failed to read the configuration file
+    ReadConfigFile(PathBuf, std::io::Error),
+    /// Failed to parse the JSON configuration file
+    ParseJsonConfigFile(PathBuf, serde_json::Error),
+    /// Failed to parse the TOML configuration file
+    ParseTomlConfigFile(PathBuf, toml::de::Error),
     /// Failed to validate configuration
     Validation(ValidationError),
     #[cfg(feature = "tdx")]
@@ -96,10 +201,16 @@ pub enum Error {
 pub enum ValidationError {
     /// Both console and serial are tty.
     DoubleTtyMode,
+    /// Both console and serial are driven through the API.
+    DoubleApiMode,
     /// No kernel specified
     KernelMissing,
     /// Missing file value for console
     ConsoleFileMissing,
+    /// Console log file used without tty or pty mode
+    ConsoleLogFileIncompatible,
+    /// Coalesced writes asked for the virtio console
+    ConsoleCoalesce,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
     /// Both socket and path specified
@@ -122,10 +233,16 @@ pub enum ValidationError {
     VnetQueueFdMismatch,
     /// Using reserved fd
     VnetReservedFd,
+    // MTU below the minimum for Ethernet
+    VnetMtuTooSmall(u16),
     // Hugepages not turned on
     HugePageSizeWithoutHugePages,
     // Huge page size is not power of 2
     InvalidHugePageSize(u64),
+    // Cold pages offloaded from huge pages, which can't be paged out
+    ColdPageOffloadHugePages,
+    // Cold pages offloaded without any delay
+    InvalidColdPageOffload,
     // CPU Hotplug not permitted with TDX
     #[cfg(feature = "tdx")]
     TdxNoCpuHotplug,
@@ -134,6 +251,118 @@ pub enum ValidationError {
     TdxKernelSpecified,
     // Insuffient vCPUs for queues
     TooManyQueues,
+    // More queues than MSI-X vectors for them
+    TooManyQueuesForVectors(usize),
+    // Queue size not allowed by the virtio specification
+    InvalidQueueSize(u16),
+    // Disk overlay used along with an incompatible option
+    DiskOverlayIncompatible,
+    // Backing overlays without an overlay on top of them
+    DiskBackingOverlaysWithoutOverlay,
+    // Disk path looking like an NBD URI but not a valid one
+    InvalidNbdUri(String),
+    // Disk path looking like an RBD image but not a valid one
+    InvalidRbdPath(String),
+    // RBD image used while the RBD support isn't built in
+    RbdUnsupported,
+    // NVMe disk used along with an incompatible option
+    DiskNvmeIncompatible,
+    // Disk serial number longer than what virtio-blk can report
+    DiskSerialTooLong(String),
+    // Disk serial number used along with an incompatible option
+    DiskSerialIncompatible,
+    // Disk polling used along with an incompatible option
+    DiskPollIncompatible,
+    // Disk polling for longer than allowed
+    DiskPollTooLong(u64),
+    // More I/O worker threads per disk queue than allowed
+    TooManyDiskWorkers(usize),
+    // Disk file descriptor used along with an incompatible option
+    DiskFdIncompatible,
+    // Disk file descriptor using a reserved number
+    DiskReservedFd,
+    // PCI subsystem IDs overridden more than once for the same device
+    DuplicatePciSubsystem(String),
+    // Invalid PCI subsystem vendor ID
+    InvalidPciSubsystemVendor(u16),
+    // Virtio features filtered more than once for the same device
+    DuplicateVirtioFeatures(String),
+    // Virtio feature bit out of the 64 bits a device can offer
+    InvalidVirtioFeature(u8),
+    // Virtio feature the transport can't do without being turned off
+    MandatoryVirtioFeatureOff(u8),
+    // Virtio feature both turned on and off
+    VirtioFeatureOnAndOff(u8),
+    // More USB devices than the xHCI controller has room for
+    TooManyUsbDevices(usize),
+    // Same host USB device passed through twice
+    DuplicateUsbDevice(PathBuf),
+    // TPM without a backend, or with both an emulator and a host device
+    InvalidTpmBackend,
+    // TPM described to the guest through ACPI, which is turned off
+    TpmWithoutAcpi,
+    // Control channel of the TPM emulator without its socket
+    TpmCtrlWithoutSocket,
+    // Identity UUID not in the 8-4-4-4-12 hexadecimal format
+    InvalidIdentityUuid(String),
+    // Identity metadata key empty, or not fitting in an SMBIOS string
+    InvalidIdentityMetadata(String),
+    // More identity metadata than SMBIOS OEM strings
+    TooManyIdentityMetadata(usize),
+    // CPU quota below the minimum
+    InvalidCpusQuota(u64),
+    // CPU period out of the supported range
+    InvalidCpusPeriod(u64),
+    // CPU period without any quota
+    CpusPeriodWithoutQuota,
+    // AArch64 vCPU features requested on another architecture
+    CpuFeaturesUnsupported,
+    // Per vCPU setting given to a vCPU beyond the maximum
+    InvalidVcpu(u8),
+    // vCPU pinned onto a host CPU which doesn't exist
+    InvalidHostCpu(usize),
+    // Core types given to some of the vCPUs only
+    CpuCoreTypeMissing(u8),
+    // Hybrid core types requested on another architecture
+    CpuCoreTypesUnsupported,
+    // kvmclock disabled on another architecture than x86_64
+    KvmClockUnsupported,
+    // PTP_KVM requested on x86_64 without kvmclock
+    PtpKvmWithoutKvmClock,
+    // I/O limits without any block device
+    CgroupIoDeviceMissing,
+    // Battery charge level above 100%
+    InvalidBatteryLevel(u8),
+    // Feature relying on ACPI while ACPI is disabled
+    AcpiDisabled(&'static str),
+    // Huge pages too large to be released through free page reporting
+    BalloonHugePageSizeTooLarge(u64),
+    // VFIO device asking to bypass the virtio-iommu
+    VfioIommuBypass,
+    // VFIO device assigned with all its functions behind an IOMMU
+    VfioAllFunctionsIommu(PathBuf),
+    // Failover for a VFIO device assigned with all its functions
+    VfioFailoverAllFunctions(PathBuf),
+    // Failover to a network device missing or not in standby
+    VfioFailoverNetMissing(String),
+    // Standby network device backed by vhost-user
+    VnetStandbyVhostUser,
+    // RX filter on a network device backed by vhost-user
+    VnetRxFilterVhostUser,
+    // Both a classic BPF and an eBPF RX filter on a network device
+    VnetRxFilterConflict,
+    // VT-d emulation requested on another architecture
+    VtdUnsupported,
+    // Device asking to bypass the emulated VT-d
+    VtdIommuBypass,
+    // VFIO device placed behind the emulated VT-d
+    VtdVfio,
+    // Bounce buffers size not a multiple of 2MiB
+    InvalidBounceBufferSize(u64),
+    // Bounce buffers along with a vhost-user device
+    BounceBufferVhostUser,
+    // Bounce buffers for a guest whose memory isn't encrypted
+    BounceBufferNotConfidential,
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -143,8 +372,14 @@ impl fmt::Display for ValidationError {
         use self::ValidationError::*;
         match self {
             DoubleTtyMode => write!(f, "Console mode tty specified for both serial and console"),
+            DoubleApiMode => write!(f, "Console mode api specified for both serial and console"),
             KernelMissing => write!(f, "No kernel specified"),
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
+            ConsoleLogFileIncompatible => write!(
+                f,
+                "Console output can only be logged to a file in tty or pty mode"
+            ),
+            ConsoleCoalesce => write!(f, "Only the serial port can coalesce its writes"),
             CpusMaxLowerThanBoot => write!(f, "Max CPUs greater than boot CPUs"),
             DiskSocketAndPath => write!(f, "Disk path and vhost socket both provided"),
             VhostUserRequiresSharedMemory => {
@@ -164,12 +399,23 @@ impl fmt::Display for ValidationError {
                 "Number of queues to virtio_net does not match the number of input FDs"
             ),
             VnetReservedFd => write!(f, "Reserved fd number (<= 2)"),
+            VnetMtuTooSmall(mtu) => write!(
+                f,
+                "MTU {} is lower than the minimum of {}",
+                mtu, MIN_NET_MTU
+            ),
             HugePageSizeWithoutHugePages => {
                 write!(f, "Huge page size specified but huge pages not enabled")
             }
             InvalidHugePageSize(s) => {
                 write!(f, "Huge page size is not power of 2: {}", s)
             }
+            ColdPageOffloadHugePages => {
+                write!(f, "Cold pages can't be offloaded from huge pages")
+            }
+            InvalidColdPageOffload => {
+                write!(f, "Cold pages must be left untouched at least a second")
+            }
             #[cfg(feature = "tdx")]
             TdxNoCpuHotplug => {
                 write!(f, "CPU hotplug not possible with TDX")
@@ -181,6 +427,185 @@ impl fmt::Display for ValidationError {
             TooManyQueues => {
                 write!(f, "Number of vCPUs is insufficient for number of queues")
             }
+            TooManyQueuesForVectors(num_queues) => write!(
+                f,
+                "Number of queues {} is higher than the maximum of {}",
+                num_queues, MAX_QUEUES_PER_DEVICE
+            ),
+            InvalidQueueSize(size) => write!(
+                f,
+                "Queue size {} is not a power of 2 lower than or equal to {}",
+                size, MAX_QUEUE_SIZE
+            ),
+            DiskOverlayIncompatible => write!(
+                f,
+                "Disk overlay can't be used with vhost-user, read-only, direct, NBD or RBD disks"
+            ),
+            DiskBackingOverlaysWithoutOverlay => {
+                write!(f, "Disk backing overlays require an overlay")
+            }
+            InvalidNbdUri(uri) => write!(f, "Invalid NBD URI: {}", uri),
+            InvalidRbdPath(path) => write!(f, "Invalid RBD path: {}", path),
+            RbdUnsupported => write!(f, "RBD disks require the \"rbd\" feature"),
+            DiskNvmeIncompatible => write!(f, "NVMe disk can't be used with vhost-user or IOMMU"),
+            DiskSerialTooLong(serial) => write!(
+                f,
+                "Disk serial number {} is longer than {} bytes",
+                serial, MAX_DISK_SERIAL_LEN
+            ),
+            DiskPollIncompatible => {
+                write!(f, "Disk polling can't be used with vhost-user or NVMe")
+            }
+            DiskPollTooLong(poll_us) => write!(
+                f,
+                "Disk polling for {}us is longer than {}us",
+                poll_us, MAX_DISK_POLL_US
+            ),
+            TooManyDiskWorkers(workers) => write!(
+                f,
+                "Disk using {} worker threads per queue, more than {}",
+                workers, MAX_DISK_WORKERS
+            ),
+            DiskFdIncompatible => write!(
+                f,
+                "Disk file descriptor requires a path and can't be used with vhost-user, NBD or RBD disks"
+            ),
+            DiskReservedFd => write!(f, "Reserved disk fd number (<= 2)"),
+            DiskSerialIncompatible => {
+                write!(
+                    f,
+                    "Disk serial number can't be used with vhost-user or NVMe"
+                )
+            }
+            DuplicatePciSubsystem(id) => {
+                write!(f, "PCI subsystem IDs specified twice for device {}", id)
+            }
+            InvalidPciSubsystemVendor(vendor) => {
+                write!(f, "Invalid PCI subsystem vendor ID: 0x{:04x}", vendor)
+            }
+            DuplicateVirtioFeatures(id) => {
+                write!(f, "Virtio features filtered twice for device {}", id)
+            }
+            InvalidVirtioFeature(bit) => write!(f, "Invalid virtio feature bit: {}", bit),
+            MandatoryVirtioFeatureOff(bit) => {
+                write!(f, "Virtio feature bit {} can't be turned off", bit)
+            }
+            VirtioFeatureOnAndOff(bit) => {
+                write!(f, "Virtio feature bit {} turned both on and off", bit)
+            }
+            TooManyUsbDevices(count) => write!(
+                f,
+                "{} USB devices requested, the controller supports at most {}",
+                count, MAX_USB_DEVICES
+            ),
+            DuplicateUsbDevice(path) => {
+                write!(f, "USB device {} passed through twice", path.display())
+            }
+            InvalidTpmBackend => write!(
+                f,
+                "TPM needs exactly one of an emulator socket or a host device"
+            ),
+            TpmWithoutAcpi => write!(f, "TPM can't be used without ACPI"),
+            TpmCtrlWithoutSocket => write!(
+                f,
+                "TPM control channel can't be used without an emulator socket"
+            ),
+            InvalidIdentityUuid(uuid) => write!(f, "Invalid identity UUID: {}", uuid),
+            InvalidIdentityMetadata(key) => write!(f, "Invalid identity metadata: {:?}", key),
+            TooManyIdentityMetadata(count) => write!(
+                f,
+                "{} identity metadata entries given, at most {} are supported",
+                count, MAX_IDENTITY_METADATA
+            ),
+            InvalidCpusQuota(quota) => write!(
+                f,
+                "CPU quota {}us is lower than the minimum of {}us",
+                quota, MIN_CPUS_QUOTA
+            ),
+            InvalidCpusPeriod(period) => write!(
+                f,
+                "CPU period {}us is not between {}us and {}us",
+                period, MIN_CPUS_PERIOD, MAX_CPUS_PERIOD
+            ),
+            CpusPeriodWithoutQuota => write!(f, "CPU period specified without any quota"),
+            CpuFeaturesUnsupported => write!(
+                f,
+                "The pmu, sve and ptrauth CPU features are only supported on AArch64"
+            ),
+            InvalidVcpu(vcpu) => write!(f, "vCPU {} is beyond the maximum vCPUs", vcpu),
+            InvalidHostCpu(cpu) => write!(f, "Host CPU {} does not exist", cpu),
+            CpuCoreTypeMissing(vcpu) => write!(
+                f,
+                "vCPU {} has no core type, while the other vCPUs have one",
+                vcpu
+            ),
+            CpuCoreTypesUnsupported => {
+                write!(f, "CPU core types are only supported on x86_64")
+            }
+            KvmClockUnsupported => write!(f, "kvmclock can only be disabled on x86_64"),
+            PtpKvmWithoutKvmClock => write!(
+                f,
+                "PTP_KVM requires kvmclock, which is disabled or hidden by kvm_hyperv"
+            ),
+            CgroupIoDeviceMissing => write!(f, "I/O limits specified without any device"),
+            InvalidBatteryLevel(level) => write!(f, "Battery level {}% is above 100%", level),
+            AcpiDisabled(feature) => {
+                write!(f, "{} can't be used with ACPI disabled", feature)
+            }
+            BalloonHugePageSizeTooLarge(s) => write!(
+                f,
+                "Free page reporting can't release huge pages of {} bytes, the guest reports \
+                blocks of {} bytes",
+                s, FREE_PAGE_REPORTING_BLOCK_SIZE
+            ),
+            VfioIommuBypass => write!(
+                f,
+                "VFIO devices can't bypass the virtio-iommu, use iommu=on instead"
+            ),
+            VfioAllFunctionsIommu(p) => write!(
+                f,
+                "VFIO device {} can't be assigned with all its functions behind an IOMMU",
+                p.display()
+            ),
+            VfioFailoverAllFunctions(p) => write!(
+                f,
+                "VFIO device {} can't be assigned with all its functions and fail over",
+                p.display()
+            ),
+            VfioFailoverNetMissing(id) => write!(
+                f,
+                "No network device {} with standby=on to fail over to",
+                id
+            ),
+            VnetStandbyVhostUser => {
+                write!(f, "vhost-user network devices don't support standby")
+            }
+            VnetRxFilterVhostUser => {
+                write!(f, "vhost-user network devices don't support RX filters")
+            }
+            VnetRxFilterConflict => write!(
+                f,
+                "Network devices take either a classic BPF or an eBPF RX filter"
+            ),
+            VtdUnsupported => write!(f, "The VT-d emulation is only supported on x86_64"),
+            VtdIommuBypass => write!(
+                f,
+                "Devices can't bypass the emulated VT-d, use iommu=on instead"
+            ),
+            VtdVfio => write!(f, "VFIO devices can't be placed behind the emulated VT-d"),
+            InvalidBounceBufferSize(s) => write!(
+                f,
+                "Bounce buffers size {} is not a non-zero multiple of 2MiB",
+                s
+            ),
+            BounceBufferVhostUser => write!(
+                f,
+                "The vhost-user devices access the whole guest memory, not only the bounce buffers"
+            ),
+            BounceBufferNotConfidential => write!(
+                f,
+                "Bounce buffers are only meant for confidential guests, such as TDX ones"
+            ),
         }
     }
 }
@@ -216,14 +641,70 @@ impl fmt::Display for Error {
             ParseRng(o) => write!(f, "Error parsing --rng: {}", o),
             ParseBalloon(o) => write!(f, "Error parsing --balloon: {}", o),
             ParseRestore(o) => write!(f, "Error parsing --restore: {}", o),
+            ParseHooks(o) => write!(f, "Error parsing --hook: {}", o),
+            ParseSigterm(o) => write!(f, "Error parsing --on-sigterm: {}", o),
+            ParseMemoryPressure(o) => write!(f, "Error parsing --memory-pressure: {}", o),
+            ParseApiVsock(o) => write!(f, "Error parsing --api-vsock: {}", o),
+            ParseApiVsockPortMissing => write!(f, "Error parsing --api-vsock: port required"),
+            ParseApiVsockAllowedCidsMissing => write!(
+                f,
+                "Error parsing --api-vsock: the CIDs allowed to connect are required"
+            ),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpc(o) => write!(f, "Error parsing --sgx-epc: {}", o),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpcIdMissing => write!(f, "Error parsing --sgx-epc: id missing"),
             ParseNuma(o) => write!(f, "Error parsing --numa: {}", o),
+            ParseNumaPolicy(o) => write!(f, "Error parsing --numa-policy: {:?}", o),
+            ParseWorkerFailurePolicy(o) => {
+                write!(f, "Error parsing --on-worker-failure: {:?}", o)
+            }
+            ParsePciSubsystem(o) => write!(f, "Error parsing --pci-subsystem: {}", o),
+            ParsePciSubsystemIdMissing => write!(f, "Error parsing --pci-subsystem: id missing"),
+            ParsePciSubsystemVendorMissing => {
+                write!(f, "Error parsing --pci-subsystem: vendor missing")
+            }
+            ParsePciSubsystemDeviceMissing => {
+                write!(f, "Error parsing --pci-subsystem: device missing")
+            }
+            ParseVirtioFeatures(o) => write!(f, "Error parsing --virtio-features: {}", o),
+            ParseVirtioFeaturesIdMissing => {
+                write!(f, "Error parsing --virtio-features: id missing")
+            }
+            ParseSuspend(o) => write!(f, "Error parsing --suspend: {}", o),
+            ParsePowerSupply(o) => write!(f, "Error parsing --power-supply: {}", o),
+            ParseCgroup(o) => write!(f, "Error parsing --cgroup: {}", o),
+            ParsePlatform(o) => write!(f, "Error parsing --platform: {}", o),
+            ParseInputLog(o) => write!(f, "Error parsing --input-log: {}", o),
+            ParseInputLogPathMissing => write!(f, "Error parsing --input-log: path missing"),
+            ParseUsb(o) => write!(f, "Error parsing --usb: {}", o),
+            ParseUsbPathMissing => write!(f, "Error parsing --usb: path missing"),
+            ParseTpm(o) => write!(f, "Error parsing --tpm: {}", o),
+            ParseIdentity(o) => write!(f, "Error parsing --identity: {}", o),
+            ParseTpmBackend => write!(
+                f,
+                "Error parsing --tpm: exactly one of socket or device is expected"
+            ),
+            ParseTpmCtrl => write!(f, "Error parsing --tpm: ctrl needs socket"),
+            ParseDiskSnapshot(o) => write!(f, "Error parsing disk snapshot: {}", o),
+            ParseDiskSnapshotIdMissing => write!(f, "Error parsing disk snapshot: id missing"),
+            ParseDiskSnapshotOverlayMissing => {
+                write!(f, "Error parsing disk snapshot: overlay missing")
+            }
+            ParseCheckpoint(o) => write!(f, "Error parsing checkpoint: {}", o),
+            ParseCheckpointDestinationUrlMissing => {
+                write!(f, "Error parsing checkpoint: destination_url missing")
+            }
+            ReadConfigFile(p, e) => write!(f, "Error reading {}: {}", p.display(), e),
+            ParseJsonConfigFile(p, e) => write!(f, "Error parsing {}: {}", p.display(), e),
+            ParseTomlConfigFile(p, e) => write!(f, "Error parsing {}: {}", p.display(), e),
             ParseRestoreSourceUrlMissing => {
                 write!(f, "Error parsing --restore: source_url missing")
             }
+            ParseRestoreInputLogConflict => write!(
+                f,
+                "Error parsing --restore: record_inputs and replay_inputs are mutually exclusive"
+            ),
             Validation(v) => write!(f, "Error validating configuration: {}", v),
             #[cfg(feature = "tdx")]
             ParseTdx(o) => write!(f, "Error parsing --tdx: {}", o),
@@ -255,7 +736,20 @@ pub struct VmParams<'a> {
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
     pub numa: Option<Vec<&'a str>>,
+    pub numa_policy: Option<&'a str>,
+    pub on_worker_failure: Option<&'a str>,
+    pub pci_subsystems: Option<Vec<&'a str>>,
+    pub virtio_features: Option<Vec<&'a str>>,
     pub watchdog: bool,
+    pub shared_event_loop: bool,
+    pub suspend: Option<&'a str>,
+    pub power_supply: Option<&'a str>,
+    pub cgroup: Option<&'a str>,
+    pub platform: Option<&'a str>,
+    pub input_log: Option<&'a str>,
+    pub usb: Option<Vec<&'a str>>,
+    pub tpm: Option<&'a str>,
+    pub identity: Option<&'a str>,
     #[cfg(feature = "tdx")]
     pub tdx: Option<&'a str>,
 }
@@ -284,7 +778,22 @@ impl<'a> VmParams<'a> {
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
         let numa: Option<Vec<&str>> = args.values_of("numa").map(|x| x.collect());
+        let numa_policy: Option<&str> = args.value_of("numa-policy");
+        let on_worker_failure: Option<&str> = args.value_of("on-worker-failure");
+        let pci_subsystems: Option<Vec<&str>> =
+            args.values_of("pci-subsystem").map(|x| x.collect());
+        let virtio_features: Option<Vec<&str>> =
+            args.values_of("virtio-features").map(|x| x.collect());
         let watchdog = args.is_present("watchdog");
+        let shared_event_loop = args.is_present("shared-event-loop");
+        let suspend: Option<&str> = args.value_of("suspend");
+        let power_supply: Option<&str> = args.value_of("power-supply");
+        let cgroup: Option<&str> = args.value_of("cgroup");
+        let platform: Option<&str> = args.value_of("platform");
+        let input_log: Option<&str> = args.value_of("input-log");
+        let usb: Option<Vec<&str>> = args.values_of("usb").map(|x| x.collect());
+        let tpm: Option<&str> = args.value_of("tpm");
+        let identity: Option<&str> = args.value_of("identity");
         #[cfg(feature = "tdx")]
         let tdx = args.value_of("tdx");
         VmParams {
@@ -307,7 +816,20 @@ impl<'a> VmParams<'a> {
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
             numa,
+            numa_policy,
+            on_worker_failure,
+            pci_subsystems,
+            virtio_features,
             watchdog,
+            shared_event_loop,
+            suspend,
+            power_supply,
+            cgroup,
+            platform,
+            input_log,
+            usb,
+            tpm,
+            identity,
             #[cfg(feature = "tdx")]
             tdx,
         }
@@ -343,6 +865,71 @@ impl FromStr for HotplugMethod {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum NumaPolicy {
+    Manual,
+    Auto,
+}
+
+impl Default for NumaPolicy {
+    fn default() -> Self {
+        NumaPolicy::Manual
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseNumaPolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for NumaPolicy {
+    type Err = ParseNumaPolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "manual" => Ok(NumaPolicy::Manual),
+            "auto" => Ok(NumaPolicy::Auto),
+            _ => Err(ParseNumaPolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Action taken when a worker thread of a virtio device fails, leaving the
+/// device unable to process its queues.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum WorkerFailurePolicy {
+    /// The failure is only reported.
+    Ignore,
+    /// The VM is paused, so that it can be inspected or migrated.
+    Pause,
+    /// The device is reset and its workers started again.
+    Reset,
+}
+
+impl Default for WorkerFailurePolicy {
+    fn default() -> Self {
+        WorkerFailurePolicy::Ignore
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseWorkerFailurePolicyError {
+    InvalidValue(String),
+}
+
+impl FromStr for WorkerFailurePolicy {
+    type Err = ParseWorkerFailurePolicyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Ok(WorkerFailurePolicy::Ignore),
+            "pause" => Ok(WorkerFailurePolicy::Pause),
+            "reset" => Ok(WorkerFailurePolicy::Reset),
+            _ => Err(ParseWorkerFailurePolicyError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 pub enum CpuTopologyParseError {
     InvalidValue(String),
 }
@@ -384,6 +971,95 @@ impl FromStr for CpuTopology {
     }
 }
 
+// Splits a range, such as "0-3", into its first and last items.
+fn parse_range<T: FromStr + PartialOrd + Copy>(s: &str) -> Option<(T, T)> {
+    let mut items = s.splitn(2, '-');
+    let first = items.next()?.parse().ok()?;
+    let last = match items.next() {
+        Some(last) => last.parse().ok()?,
+        None => first,
+    };
+    if first > last {
+        return None;
+    }
+
+    Some((first, last))
+}
+
+// Splits a list of per vCPU values, such as "[0-3@core,4@atom]", into the
+// vCPUs along with the value given to each of them.
+fn parse_vcpu_values(s: &str) -> Option<Vec<(u8, String)>> {
+    let mut list = Vec::new();
+    for item in split_list(s).ok()? {
+        let mut parts = item.splitn(2, '@');
+        let (first, last) = parse_range::<u8>(parts.next()?)?;
+        let value = parts.next()?;
+        for vcpu in first..=last {
+            list.push((vcpu, value.to_owned()));
+        }
+    }
+
+    Some(list)
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuAffinity {
+    pub vcpu: u8,
+    pub host_cpus: Vec<usize>,
+}
+
+impl CpuAffinity {
+    // The host CPUs are given as a range or as a list of ranges, as in
+    // "[0@[0-1,4],1@2]". The host CPUs given to the same vCPU more than once
+    // add up.
+    fn parse_list(s: &str) -> Option<Vec<Self>> {
+        let mut list: Vec<CpuAffinity> = Vec::new();
+        for (vcpu, host_cpus) in parse_vcpu_values(s)? {
+            for range in split_list(&host_cpus).ok()? {
+                let (first, last) = parse_range::<usize>(&range)?;
+                match list.iter_mut().find(|a| a.vcpu == vcpu) {
+                    Some(affinity) => affinity.host_cpus.extend(first..=last),
+                    None => list.push(CpuAffinity {
+                        vcpu,
+                        host_cpus: (first..=last).collect(),
+                    }),
+                }
+            }
+        }
+
+        Some(list)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CoreType {
+    Core,
+    Atom,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuCoreType {
+    pub vcpu: u8,
+    pub core_type: CoreType,
+}
+
+impl CpuCoreType {
+    fn parse_list(s: &str) -> Option<Vec<Self>> {
+        let mut list: Vec<CpuCoreType> = Vec::new();
+        for (vcpu, core_type) in parse_vcpu_values(s)? {
+            let core_type = match core_type.to_lowercase().as_str() {
+                "core" => CoreType::Core,
+                "atom" => CoreType::Atom,
+                _ => return None,
+            };
+            list.retain(|c| c.vcpu != vcpu);
+            list.push(CpuCoreType { vcpu, core_type });
+        }
+
+        Some(list)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
@@ -394,6 +1070,28 @@ pub struct CpusConfig {
     pub kvm_hyperv: bool,
     #[serde(default)]
     pub max_phys_bits: Option<u8>,
+    #[serde(default)]
+    pub quota: Option<u64>,
+    #[serde(default)]
+    pub period: Option<u64>,
+    #[serde(default)]
+    pub pmu: bool,
+    #[serde(default)]
+    pub sve: bool,
+    #[serde(default)]
+    pub ptrauth: bool,
+    #[serde(default)]
+    pub affinity: Option<Vec<CpuAffinity>>,
+    #[serde(default)]
+    pub core_types: Option<Vec<CpuCoreType>>,
+    #[serde(default = "default_cpusconfig_kvmclock")]
+    pub kvmclock: bool,
+    #[serde(default)]
+    pub ptp_kvm: Option<bool>,
+}
+
+fn default_cpusconfig_kvmclock() -> bool {
+    true
 }
 
 impl CpusConfig {
@@ -404,7 +1102,16 @@ impl CpusConfig {
             .add("max")
             .add("topology")
             .add("kvm_hyperv")
-            .add("max_phys_bits");
+            .add("max_phys_bits")
+            .add("quota")
+            .add("period")
+            .add("pmu")
+            .add("sve")
+            .add("ptrauth")
+            .add("affinity")
+            .add("core_types")
+            .add("kvmclock")
+            .add("ptp_kvm");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
         let boot_vcpus: u8 = parser
@@ -424,6 +1131,48 @@ impl CpusConfig {
         let max_phys_bits = parser
             .convert::<u8>("max_phys_bits")
             .map_err(Error::ParseCpus)?;
+        let quota = parser.convert("quota").map_err(Error::ParseCpus)?;
+        let period = parser.convert("period").map_err(Error::ParseCpus)?;
+        let pmu = parser
+            .convert::<Toggle>("pmu")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let sve = parser
+            .convert::<Toggle>("sve")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let ptrauth = parser
+            .convert::<Toggle>("ptrauth")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let affinity = parser
+            .get("affinity")
+            .map(|s| {
+                CpuAffinity::parse_list(&s).ok_or_else(|| {
+                    Error::ParseCpus(OptionParserError::Conversion("affinity".to_owned(), s))
+                })
+            })
+            .transpose()?;
+        let core_types = parser
+            .get("core_types")
+            .map(|s| {
+                CpuCoreType::parse_list(&s).ok_or_else(|| {
+                    Error::ParseCpus(OptionParserError::Conversion("core_types".to_owned(), s))
+                })
+            })
+            .transpose()?;
+        let kvmclock = parser
+            .convert::<Toggle>("kvmclock")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(true))
+            .0;
+        let ptp_kvm = parser
+            .convert::<Toggle>("ptp_kvm")
+            .map_err(Error::ParseCpus)?
+            .map(|toggle| toggle.0);
 
         Ok(CpusConfig {
             boot_vcpus,
@@ -431,8 +1180,94 @@ impl CpusConfig {
             topology,
             kvm_hyperv,
             max_phys_bits,
+            quota,
+            period,
+            pmu,
+            sve,
+            ptrauth,
+            affinity,
+            core_types,
+            kvmclock,
+            ptp_kvm,
         })
     }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        #[cfg(not(target_arch = "aarch64"))]
+        if self.pmu || self.sve || self.ptrauth {
+            return Err(ValidationError::CpuFeaturesUnsupported);
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        if self.core_types.is_some() {
+            return Err(ValidationError::CpuCoreTypesUnsupported);
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        if !self.kvmclock {
+            return Err(ValidationError::KvmClockUnsupported);
+        }
+
+        // The x86_64 PTP_KVM hypercall reads the host clock through the
+        // kvmclock of the guest.
+        #[cfg(target_arch = "x86_64")]
+        if self.ptp_kvm == Some(true) && (!self.kvmclock || self.kvm_hyperv) {
+            return Err(ValidationError::PtpKvmWithoutKvmClock);
+        }
+
+        let vcpus = self
+            .affinity
+            .iter()
+            .flatten()
+            .map(|a| a.vcpu)
+            .chain(self.core_types.iter().flatten().map(|c| c.vcpu));
+        for vcpu in vcpus {
+            if vcpu >= self.max_vcpus {
+                return Err(ValidationError::InvalidVcpu(vcpu));
+            }
+        }
+
+        // The vCPU threads would otherwise fail to start. The host CPUs are
+        // counted including the offline ones, which can be brought online.
+        // Safe because sysconf() doesn't touch any memory.
+        let host_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        let max_host_cpus = if host_cpus > 0 {
+            std::cmp::min(host_cpus as usize, libc::CPU_SETSIZE as usize)
+        } else {
+            libc::CPU_SETSIZE as usize
+        };
+        if let Some(cpu) = self
+            .affinity
+            .iter()
+            .flatten()
+            .flat_map(|a| a.host_cpus.iter())
+            .find(|cpu| **cpu >= max_host_cpus)
+        {
+            return Err(ValidationError::InvalidHostCpu(*cpu));
+        }
+
+        // A hybrid CPU tells the type of every core.
+        if let Some(core_types) = &self.core_types {
+            if let Some(vcpu) =
+                (0..self.max_vcpus).find(|vcpu| !core_types.iter().any(|c| c.vcpu == *vcpu))
+            {
+                return Err(ValidationError::CpuCoreTypeMissing(vcpu));
+            }
+        }
+
+        match (self.quota, self.period) {
+            (None, Some(_)) => return Err(ValidationError::CpusPeriodWithoutQuota),
+            (Some(quota), _) if quota < MIN_CPUS_QUOTA => {
+                return Err(ValidationError::InvalidCpusQuota(quota))
+            }
+            (_, Some(period)) if !(MIN_CPUS_PERIOD..=MAX_CPUS_PERIOD).contains(&period) => {
+                return Err(ValidationError::InvalidCpusPeriod(period))
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for CpusConfig {
@@ -443,6 +1278,15 @@ impl Default for CpusConfig {
             topology: None,
             kvm_hyperv: false,
             max_phys_bits: None,
+            quota: None,
+            period: None,
+            pmu: false,
+            sve: false,
+            ptrauth: false,
+            affinity: None,
+            core_types: None,
+            kvmclock: true,
+            ptp_kvm: None,
         }
     }
 }
@@ -486,21 +1330,34 @@ pub struct MemoryConfig {
     pub hugepage_size: Option<u64>,
     #[serde(default)]
     pub zones: Option<Vec<MemoryZoneConfig>>,
+    /// Seconds a guest page has to be left untouched before it's paged out
+    /// to the host swap.
+    #[serde(default)]
+    pub cold_page_offload: Option<u64>,
 }
 
 impl MemoryConfig {
-    pub fn parse(memory: &str, memory_zones: Option<Vec<&str>>) -> Result<Self> {
+    fn parser() -> OptionParser {
         let mut parser = OptionParser::new();
         parser
             .add("size")
-            .add("file")
             .add("mergeable")
             .add("hotplug_method")
             .add("hotplug_size")
             .add("hotplugged_size")
             .add("shared")
             .add("hugepages")
-            .add("hugepage_size");
+            .add("hugepage_size")
+            .add("cold_page_offload")
+            // Deprecated in favor of --balloon, see
+            // BalloonConfig::parse_legacy_memory_options()
+            .add("balloon")
+            .add("balloon_size");
+        parser
+    }
+
+    pub fn parse(memory: &str, memory_zones: Option<Vec<&str>>) -> Result<Self> {
+        let mut parser = Self::parser();
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -539,6 +1396,9 @@ impl MemoryConfig {
             .convert::<ByteSized>("hugepage_size")
             .map_err(Error::ParseMemory)?
             .map(|v| v.0);
+        let cold_page_offload = parser
+            .convert("cold_page_offload")
+            .map_err(Error::ParseMemory)?;
 
         let zones: Option<Vec<MemoryZoneConfig>> = if let Some(memory_zones) = &memory_zones {
             let mut zones = Vec::new();
@@ -617,6 +1477,7 @@ impl MemoryConfig {
             hugepages,
             hugepage_size,
             zones,
+            cold_page_offload,
         })
     }
 
@@ -651,6 +1512,7 @@ impl Default for MemoryConfig {
             hugepages: false,
             hugepage_size: None,
             zones: None,
+            cold_page_offload: None,
         }
     }
 }
@@ -680,6 +1542,87 @@ impl CmdlineConfig {
     }
 }
 
+/// How a device relates to the virtio-iommu.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum IommuMode {
+    /// The device isn't placed behind the virtio-iommu.
+    Off,
+    /// The device is placed behind the virtio-iommu, and its DMA is blocked
+    /// until the guest attaches it to a domain.
+    On,
+    /// The device is placed behind the virtio-iommu, but its DMA bypasses
+    /// the translation until the guest attaches it to a domain.
+    Bypass,
+}
+
+impl IommuMode {
+    /// Whether the device is placed behind the virtio-iommu.
+    pub fn enabled(self) -> bool {
+        self != IommuMode::Off
+    }
+}
+
+impl Default for IommuMode {
+    fn default() -> Self {
+        IommuMode::Off
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseIommuModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for IommuMode {
+    type Err = ParseIommuModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(IommuMode::Off),
+            "on" => Ok(IommuMode::On),
+            "bypass" => Ok(IommuMode::Bypass),
+            _ => Err(ParseIommuModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for IommuMode {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // The iommu field used to be a boolean, which is still accepted.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum IommuValue {
+            Toggle(bool),
+            Mode(String),
+        }
+
+        match IommuValue::deserialize(deserializer)? {
+            IommuValue::Toggle(true) => Ok(IommuMode::On),
+            IommuValue::Toggle(false) => Ok(IommuMode::Off),
+            IommuValue::Mode(mode) => mode
+                .parse()
+                .map_err(|_| de::Error::custom(format!("invalid iommu mode: {}", mode))),
+        }
+    }
+}
+
+// Checks the queues of a virtio device fit the transport and the virtio
+// specification.
+fn validate_queues(num_queues: usize, queue_size: u16) -> ValidationResult<()> {
+    if num_queues > MAX_QUEUES_PER_DEVICE {
+        return Err(ValidationError::TooManyQueuesForVectors(num_queues));
+    }
+
+    if !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+        return Err(ValidationError::InvalidQueueSize(queue_size));
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
@@ -688,7 +1631,7 @@ pub struct DiskConfig {
     #[serde(default)]
     pub direct: bool,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
     #[serde(default = "default_diskconfig_num_queues")]
     pub num_queues: usize,
     #[serde(default = "default_diskconfig_queue_size")]
@@ -699,12 +1642,28 @@ pub struct DiskConfig {
     #[serde(default = "default_diskconfig_poll_queue")]
     pub poll_queue: bool,
     #[serde(default)]
+    pub poll_us: u64,
+    #[serde(default)]
     pub rate_limiter_config: Option<RateLimiterConfig>,
     #[serde(default)]
     pub id: Option<String>,
     // For testing use only. Not exposed in API.
     #[serde(default)]
     pub disable_io_uring: bool,
+    #[serde(default)]
+    pub workers: usize,
+    #[serde(default)]
+    pub overlay: Option<PathBuf>,
+    // Read-only overlays between the image and the overlay, from the bottom
+    // up, left by the disk snapshots.
+    #[serde(default)]
+    pub backing_overlays: Vec<PathBuf>,
+    #[serde(default)]
+    pub model: DiskModel,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub fd: Option<i32>,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -725,27 +1684,37 @@ impl Default for DiskConfig {
             path: None,
             readonly: false,
             direct: false,
-            iommu: false,
+            iommu: IommuMode::Off,
             num_queues: default_diskconfig_num_queues(),
             queue_size: default_diskconfig_queue_size(),
             vhost_user: false,
             vhost_socket: None,
             poll_queue: default_diskconfig_poll_queue(),
+            poll_us: 0,
             id: None,
             disable_io_uring: false,
             rate_limiter_config: None,
+            workers: 0,
+            overlay: None,
+            backing_overlays: Vec::new(),
+            model: DiskModel::Virtio,
+            serial: None,
+            fd: None,
         }
     }
 }
 
 impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
-         \"path=<disk_image_path>,readonly=on|off,direct=on|off,iommu=on|off,\
+         \"path=<disk_image_path>,readonly=on|off,direct=on|off,iommu=on|off|bypass,\
          num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
          vhost_user=on|off,socket=<vhost_user_socket_path>,poll_queue=on|off,\
-         bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+         poll_us=<busy_poll_budget_in_us>,bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
          ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
-         id=<device_id>\"";
+         id=<device_id>,workers=<number_of_io_worker_threads>,\
+         overlay=<copy_on_write_overlay_path>,backing_overlays=[<overlay_path>,...],\
+         model=virtio|nvme,\
+         serial=<serial_number>,fd=<fd>\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -759,6 +1728,7 @@ impl DiskConfig {
             .add("vhost_user")
             .add("socket")
             .add("poll_queue")
+            .add("poll_us")
             .add("bw_size")
             .add("bw_one_time_burst")
             .add("bw_refill_time")
@@ -766,6 +1736,12 @@ impl DiskConfig {
             .add("ops_one_time_burst")
             .add("ops_refill_time")
             .add("id")
+            .add("workers")
+            .add("overlay")
+            .add("backing_overlays")
+            .add("model")
+            .add("serial")
+            .add("fd")
             .add("_disable_io_uring");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
@@ -781,10 +1757,9 @@ impl DiskConfig {
             .unwrap_or(Toggle(false))
             .0;
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
             .map_err(Error::ParseDisk)?
-            .unwrap_or(Toggle(false))
-            .0;
+            .unwrap_or_default();
         let queue_size = parser
             .convert("queue_size")
             .map_err(Error::ParseDisk)?
@@ -804,12 +1779,32 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
             .0;
-        let id = parser.get("id");
+        let poll_us = parser
+            .convert("poll_us")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let id = parser.get("id");
         let disable_io_uring = parser
             .convert::<Toggle>("_disable_io_uring")
             .map_err(Error::ParseDisk)?
             .unwrap_or(Toggle(false))
             .0;
+        let workers = parser
+            .convert("workers")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let overlay = parser.get("overlay").map(PathBuf::from);
+        let backing_overlays = parser
+            .convert::<StringList>("backing_overlays")
+            .map_err(Error::ParseDisk)?
+            .map(|StringList(paths)| paths.into_iter().map(PathBuf::from).collect())
+            .unwrap_or_default();
+        let model = parser
+            .convert("model")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
+        let serial = parser.get("serial");
+        let fd = parser.convert("fd").map_err(Error::ParseDisk)?;
         let bw_size = parser
             .convert("bw_size")
             .map_err(Error::ParseDisk)?
@@ -875,9 +1870,16 @@ impl DiskConfig {
             vhost_user,
             vhost_socket,
             poll_queue,
+            poll_us,
             rate_limiter_config,
             id,
             disable_io_uring,
+            workers,
+            overlay,
+            backing_overlays,
+            model,
+            serial,
+            fd,
         })
     }
 
@@ -885,11 +1887,109 @@ impl DiskConfig {
         if self.num_queues > vm_config.cpus.boot_vcpus as usize {
             return Err(ValidationError::TooManyQueues);
         }
+        validate_queues(self.num_queues, self.queue_size)?;
+
+        let nbd = self
+            .path
+            .as_ref()
+            .and_then(|path| path.to_str())
+            .filter(|path| NbdAddress::is_nbd_uri(path));
+        if let Some(uri) = nbd {
+            if NbdAddress::parse(uri).is_err() {
+                return Err(ValidationError::InvalidNbdUri(uri.to_owned()));
+            }
+        }
+
+        let rbd = self
+            .path
+            .as_ref()
+            .and_then(|path| path.to_str())
+            .filter(|path| RbdAddress::is_rbd_path(path));
+        if let Some(path) = rbd {
+            if RbdAddress::parse(path).is_err() {
+                return Err(ValidationError::InvalidRbdPath(path.to_owned()));
+            }
+            if !cfg!(feature = "rbd") {
+                return Err(ValidationError::RbdUnsupported);
+            }
+        }
+
+        if self.overlay.is_some()
+            && (self.vhost_user || self.readonly || self.direct || nbd.is_some() || rbd.is_some())
+        {
+            return Err(ValidationError::DiskOverlayIncompatible);
+        }
+
+        if !self.backing_overlays.is_empty() && self.overlay.is_none() {
+            return Err(ValidationError::DiskBackingOverlaysWithoutOverlay);
+        }
+
+        if self.model == DiskModel::Nvme && (self.vhost_user || self.iommu.enabled()) {
+            return Err(ValidationError::DiskNvmeIncompatible);
+        }
+
+        if let Some(serial) = &self.serial {
+            if serial.len() > MAX_DISK_SERIAL_LEN {
+                return Err(ValidationError::DiskSerialTooLong(serial.clone()));
+            }
+            if self.vhost_user || self.model == DiskModel::Nvme {
+                return Err(ValidationError::DiskSerialIncompatible);
+            }
+        }
+
+        if self.poll_us > 0 && (self.vhost_user || self.model == DiskModel::Nvme) {
+            return Err(ValidationError::DiskPollIncompatible);
+        }
+        if self.poll_us > MAX_DISK_POLL_US {
+            return Err(ValidationError::DiskPollTooLong(self.poll_us));
+        }
+
+        if self.workers > MAX_DISK_WORKERS {
+            return Err(ValidationError::TooManyDiskWorkers(self.workers));
+        }
+
+        if let Some(fd) = self.fd {
+            if self.path.is_none() || self.vhost_user || nbd.is_some() || rbd.is_some() {
+                return Err(ValidationError::DiskFdIncompatible);
+            }
+            if fd <= 2 {
+                return Err(ValidationError::DiskReservedFd);
+            }
+        }
 
         Ok(())
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DiskModel {
+    Virtio,
+    Nvme,
+}
+
+impl Default for DiskModel {
+    fn default() -> Self {
+        DiskModel::Virtio
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseDiskModelError {
+    InvalidValue(String),
+}
+
+impl FromStr for DiskModel {
+    type Err = ParseDiskModelError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "virtio" => Ok(DiskModel::Virtio),
+            "nvme" => Ok(DiskModel::Nvme),
+            _ => Err(ParseDiskModelError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum VhostMode {
     Client,
@@ -932,7 +2032,7 @@ pub struct NetConfig {
     #[serde(default)]
     pub host_mac: Option<MacAddr>,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
     #[serde(default = "default_netconfig_num_queues")]
     pub num_queues: usize,
     #[serde(default = "default_netconfig_queue_size")]
@@ -948,6 +2048,14 @@ pub struct NetConfig {
     pub fds: Option<Vec<i32>>,
     #[serde(default)]
     pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    #[serde(default)]
+    pub standby: bool,
+    #[serde(default)]
+    pub rx_filter: Option<PathBuf>,
+    #[serde(default)]
+    pub rx_filter_ebpf: Option<PathBuf>,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -982,7 +2090,7 @@ impl Default for NetConfig {
             mask: default_netconfig_mask(),
             mac: default_netconfig_mac(),
             host_mac: None,
-            iommu: false,
+            iommu: IommuMode::Off,
             num_queues: default_netconfig_num_queues(),
             queue_size: default_netconfig_queue_size(),
             vhost_user: false,
@@ -991,17 +2099,27 @@ impl Default for NetConfig {
             id: None,
             fds: None,
             rate_limiter_config: None,
+            mtu: None,
+            standby: false,
+            rx_filter: None,
+            rx_filter_ebpf: None,
         }
     }
 }
 
 impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
-    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,fd=<fd1:fd2...>,iommu=on|off,\
+    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,fd=<fd1:fd2...>,iommu=on|off|bypass,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,id=<device_id>,\
     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,vhost_mode=client|server,\
-    bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
-    ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>\"";
+    mtu=<mtu>,bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+    ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,standby=on|off,\
+    rx_filter=<bpf_program>,rx_filter_ebpf=<pinned_ebpf_program>\" \
+    \n`standby` lets the device take over from an assigned device sharing its MAC \
+    address, such as a VF unplugged for a migration (disabled by default) \
+    \n`rx_filter` gives a classic BPF program as printed by `tcpdump -ddd`, and \
+    `rx_filter_ebpf` an eBPF socket filter pinned in the BPF filesystem, dropping the \
+    frames they reject before they reach the guest";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1020,12 +2138,16 @@ impl NetConfig {
             .add("vhost_mode")
             .add("id")
             .add("fd")
+            .add("mtu")
             .add("bw_size")
             .add("bw_one_time_burst")
             .add("bw_refill_time")
             .add("ops_size")
             .add("ops_one_time_burst")
-            .add("ops_refill_time");
+            .add("ops_refill_time")
+            .add("standby")
+            .add("rx_filter")
+            .add("rx_filter_ebpf");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -1043,10 +2165,9 @@ impl NetConfig {
             .unwrap_or_else(default_netconfig_mac);
         let host_mac = parser.convert("host_mac").map_err(Error::ParseNetwork)?;
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
             .map_err(Error::ParseNetwork)?
-            .unwrap_or(Toggle(false))
-            .0;
+            .unwrap_or_default();
         let queue_size = parser
             .convert("queue_size")
             .map_err(Error::ParseNetwork)?
@@ -1070,6 +2191,14 @@ impl NetConfig {
             .convert::<IntegerList>("fd")
             .map_err(Error::ParseNetwork)?
             .map(|v| v.0.iter().map(|e| *e as i32).collect());
+        let mtu = parser.convert("mtu").map_err(Error::ParseNetwork)?;
+        let standby = parser
+            .convert::<Toggle>("standby")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let rx_filter = parser.get("rx_filter").map(PathBuf::from);
+        let rx_filter_ebpf = parser.get("rx_filter_ebpf").map(PathBuf::from);
 
         let bw_size = parser
             .convert("bw_size")
@@ -1137,7 +2266,12 @@ impl NetConfig {
             id,
             fds,
             rate_limiter_config,
+            mtu,
+            standby,
+            rx_filter,
+            rx_filter_ebpf,
         };
+
         Ok(config)
     }
 
@@ -1158,9 +2292,29 @@ impl NetConfig {
             }
         }
 
+        if let Some(mtu) = self.mtu {
+            if mtu < MIN_NET_MTU {
+                return Err(ValidationError::VnetMtuTooSmall(mtu));
+            }
+        }
+
+        if self.standby && self.vhost_user {
+            return Err(ValidationError::VnetStandbyVhostUser);
+        }
+
+        if self.rx_filter.is_some() && self.rx_filter_ebpf.is_some() {
+            return Err(ValidationError::VnetRxFilterConflict);
+        }
+
+        if (self.rx_filter.is_some() || self.rx_filter_ebpf.is_some()) && self.vhost_user {
+            return Err(ValidationError::VnetRxFilterVhostUser);
+        }
+
         if (self.num_queues / 2) > vm_config.cpus.boot_vcpus as usize {
             return Err(ValidationError::TooManyQueues);
         }
+        // The control queue comes on top of the data ones.
+        validate_queues(self.num_queues + 1, self.queue_size)?;
 
         Ok(())
     }
@@ -1170,7 +2324,7 @@ impl NetConfig {
 pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
 }
 
 impl RngConfig {
@@ -1185,10 +2339,9 @@ impl RngConfig {
                 .unwrap_or_else(|| DEFAULT_RNG_SOURCE.to_owned()),
         );
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
             .map_err(Error::ParseRng)?
-            .unwrap_or(Toggle(false))
-            .0;
+            .unwrap_or_default();
 
         Ok(RngConfig { src, iommu })
     }
@@ -1198,27 +2351,54 @@ impl Default for RngConfig {
     fn default() -> Self {
         RngConfig {
             src: PathBuf::from(DEFAULT_RNG_SOURCE),
-            iommu: false,
+            iommu: IommuMode::Off,
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct BalloonConfig {
     pub size: u64,
     /// Option to deflate the balloon in case the guest is out of memory.
     #[serde(default)]
     pub deflate_on_oom: bool,
+    /// Option to let the guest report its free pages, which are then given
+    /// back to the host.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Bytes the balloon can be inflated by, past its size, when the host is
+    /// under memory pressure, which is disabled with 0.
+    #[serde(default)]
+    pub pressure_max: u64,
+    /// Intervals the host memory pressure has to last before the balloon is
+    /// inflated, or be gone before it's deflated back.
+    #[serde(default = "default_balloonconfig_pressure_priority")]
+    pub pressure_priority: u32,
+}
+
+fn default_balloonconfig_pressure_priority() -> u32 {
+    DEFAULT_BALLOON_PRESSURE_PRIORITY
 }
 
+pub const DEFAULT_BALLOON_PRESSURE_PRIORITY: u32 = 1;
+
 impl BalloonConfig {
     pub const SYNTAX: &'static str =
-        "Balloon parameters \"size=<balloon_size>,deflate_on_oom=on|off\"";
+        "Balloon parameters \"size=<balloon_size>,deflate_on_oom=on|off,\
+        free_page_reporting=on|off,id=<device_id>,pressure_max=<inflated_size>,\
+        pressure_priority=<intervals>\"";
 
     pub fn parse(balloon: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("size");
-        parser.add("deflate_on_oom");
+        parser
+            .add("size")
+            .add("deflate_on_oom")
+            .add("free_page_reporting")
+            .add("id")
+            .add("pressure_max")
+            .add("pressure_priority");
         parser.parse(balloon).map_err(Error::ParseBalloon)?;
 
         let size = parser
@@ -1233,11 +2413,90 @@ impl BalloonConfig {
             .unwrap_or(Toggle(false))
             .0;
 
+        let free_page_reporting = parser
+            .convert::<Toggle>("free_page_reporting")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        let id = parser.get("id");
+
+        let pressure_max = parser
+            .convert::<ByteSized>("pressure_max")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0)
+            .unwrap_or(0);
+
+        let pressure_priority = parser
+            .convert("pressure_priority")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or(DEFAULT_BALLOON_PRESSURE_PRIORITY);
+
         Ok(BalloonConfig {
             size,
             deflate_on_oom,
+            free_page_reporting,
+            id,
+            pressure_max,
+            pressure_priority,
         })
     }
+
+    /// Creates the balloon from the "balloon" and "balloon_size" options of
+    /// --memory, which predate --balloon and are kept for compatibility.
+    pub fn parse_legacy_memory_options(memory: &str) -> Result<Option<Self>> {
+        let mut parser = MemoryConfig::parser();
+        parser.parse(memory).map_err(Error::ParseMemory)?;
+
+        let balloon = parser
+            .convert::<Toggle>("balloon")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        if !balloon {
+            return Ok(None);
+        }
+
+        warn!("--memory balloon and balloon_size options are deprecated, use --balloon instead");
+
+        let size = parser
+            .convert::<ByteSized>("balloon_size")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0)
+            .unwrap_or(0);
+
+        Ok(Some(BalloonConfig {
+            size,
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            id: None,
+            pressure_max: 0,
+            pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+        }))
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if !self.free_page_reporting {
+            return Ok(());
+        }
+
+        // Huge pages can only be released as a whole, which never happens
+        // when they are larger than the blocks of free pages reported.
+        let memory = &vm_config.memory;
+        let mut hugepage_sizes = vec![(memory.hugepages, memory.hugepage_size)];
+        if let Some(zones) = &memory.zones {
+            hugepage_sizes.extend(zones.iter().map(|z| (z.hugepages, z.hugepage_size)));
+        }
+        for (hugepages, hugepage_size) in hugepage_sizes {
+            if let (true, Some(size)) = (hugepages, hugepage_size) {
+                if size > FREE_PAGE_REPORTING_BLOCK_SIZE {
+                    return Err(ValidationError::BalloonHugePageSizeTooLarge(size));
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -1349,6 +2608,8 @@ impl FsConfig {
         if self.num_queues > vm_config.cpus.boot_vcpus as usize {
             return Err(ValidationError::TooManyQueues);
         }
+        // The high priority queue comes on top of the request ones.
+        validate_queues(self.num_queues + 1, self.queue_size)?;
 
         Ok(())
     }
@@ -1360,7 +2621,7 @@ pub struct PmemConfig {
     #[serde(default)]
     pub size: Option<u64>,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
     #[serde(default)]
     pub mergeable: bool,
     #[serde(default)]
@@ -1371,7 +2632,7 @@ pub struct PmemConfig {
 
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
-    \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
+    \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off|bypass,\
     mergeable=on|off,discard_writes=on|off,id=<device_id>\"";
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1395,10 +2656,9 @@ impl PmemConfig {
             .unwrap_or(Toggle(false))
             .0;
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
             .map_err(Error::ParsePersistentMemory)?
-            .unwrap_or(Toggle(false))
-            .0;
+            .unwrap_or_default();
         let discard_writes = parser
             .convert::<Toggle>("discard_writes")
             .map_err(Error::ParsePersistentMemory)?
@@ -1424,6 +2684,7 @@ pub enum ConsoleOutputMode {
     Tty,
     File,
     Null,
+    Api,
 }
 
 impl ConsoleOutputMode {
@@ -1438,7 +2699,13 @@ pub struct ConsoleConfig {
     pub file: Option<PathBuf>,
     pub mode: ConsoleOutputMode,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    /// Let the guest write to the serial port without exiting, the writes
+    /// being handled on the next exit of a vCPU.
+    #[serde(default)]
+    pub coalesce: bool,
 }
 
 fn default_consoleconfig_file() -> Option<PathBuf> {
@@ -1453,8 +2720,10 @@ impl ConsoleConfig {
             .add_valueless("pty")
             .add_valueless("tty")
             .add_valueless("null")
+            .add_valueless("api")
             .add("file")
-            .add("iommu");
+            .add("iommu")
+            .add("coalesce");
         parser.parse(console).map_err(Error::ParseConsole)?;
 
         let mut file: Option<PathBuf> = default_consoleconfig_file();
@@ -1467,6 +2736,8 @@ impl ConsoleConfig {
             mode = ConsoleOutputMode::Tty
         } else if parser.is_set("null") {
             mode = ConsoleOutputMode::Null
+        } else if parser.is_set("api") {
+            mode = ConsoleOutputMode::Api
         } else if parser.is_set("file") {
             mode = ConsoleOutputMode::File;
             file =
@@ -1476,20 +2747,46 @@ impl ConsoleConfig {
         } else {
             return Err(Error::ParseConsoleInvalidModeGiven);
         }
+        // Along with tty or pty, the output is logged to the file as well.
+        // The other modes have no output to log.
+        let log_file = match mode {
+            ConsoleOutputMode::Tty | ConsoleOutputMode::Pty => {
+                parser.get("file").map(PathBuf::from)
+            }
+            ConsoleOutputMode::File => None,
+            _ if parser.is_set("file") => {
+                return Err(Error::Validation(
+                    ValidationError::ConsoleLogFileIncompatible,
+                ))
+            }
+            _ => None,
+        };
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
+            .map_err(Error::ParseConsole)?
+            .unwrap_or_default();
+        let coalesce = parser
+            .convert::<Toggle>("coalesce")
             .map_err(Error::ParseConsole)?
             .unwrap_or(Toggle(false))
             .0;
 
-        Ok(Self { file, mode, iommu })
+        Ok(Self {
+            file,
+            mode,
+            iommu,
+            log_file,
+            coalesce,
+        })
     }
 
     pub fn default_serial() -> Self {
         ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Null,
-            iommu: false,
+            iommu: IommuMode::Off,
+            log_file: None,
+            coalesce: false,
         }
     }
 
@@ -1497,8 +2794,25 @@ impl ConsoleConfig {
         ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Tty,
-            iommu: false,
+            iommu: IommuMode::Off,
+            log_file: None,
+            coalesce: false,
+        }
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.mode == ConsoleOutputMode::File && self.file.is_none() {
+            return Err(ValidationError::ConsoleFileMissing);
+        }
+
+        if self.log_file.is_some()
+            && self.mode != ConsoleOutputMode::Tty
+            && self.mode != ConsoleOutputMode::Pty
+        {
+            return Err(ValidationError::ConsoleLogFileIncompatible);
         }
+
+        Ok(())
     }
 }
 
@@ -1506,17 +2820,35 @@ impl ConsoleConfig {
 pub struct DeviceConfig {
     pub path: PathBuf,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub all_functions: bool,
+    #[serde(default)]
+    pub managed: bool,
+    #[serde(default)]
+    pub failover: Option<String>,
 }
 
 impl DeviceConfig {
-    pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>\"";
+    pub const SYNTAX: &'static str = "Direct device assignment parameters \
+        \"path=<device_path>,iommu=on|off,id=<device_id>,all_functions=on|off,managed=on|off\" \
+        \n`all_functions` assigns every function of a multifunction device along with \
+        the one given, keeping their function numbers (disabled by default) \
+        \n`managed` binds the device to vfio-pci, and back to its driver on exit, \
+        the path can then be the PCI address of the device (disabled by default) \
+        \n`failover` gives the id of the virtio-net device with standby=on the device \
+        is unplugged in favor of when the VM is migrated";
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("path").add("id").add("iommu");
+        parser
+            .add("path")
+            .add("id")
+            .add("iommu")
+            .add("all_functions")
+            .add("managed")
+            .add("failover");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
         let path = parser
@@ -1524,31 +2856,72 @@ impl DeviceConfig {
             .map(PathBuf::from)
             .ok_or(Error::ParseDevicePathMissing)?;
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
+            .map_err(Error::ParseDevice)?
+            .unwrap_or_default();
+        let id = parser.get("id");
+        let all_functions = parser
+            .convert::<Toggle>("all_functions")
             .map_err(Error::ParseDevice)?
             .unwrap_or(Toggle(false))
             .0;
-        let id = parser.get("id");
-        Ok(DeviceConfig { path, iommu, id })
+        let managed = parser
+            .convert::<Toggle>("managed")
+            .map_err(Error::ParseDevice)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let failover = parser.get("failover");
+        Ok(DeviceConfig {
+            path,
+            iommu,
+            id,
+            all_functions,
+            managed,
+            failover,
+        })
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct VsockConfig {
     pub cid: u64,
     pub socket: PathBuf,
     #[serde(default)]
-    pub iommu: bool,
+    pub iommu: IommuMode,
+    #[serde(default = "default_vsockconfig_queue_size")]
+    pub queue_size: u16,
     #[serde(default)]
     pub id: Option<String>,
 }
 
+fn default_vsockconfig_queue_size() -> u16 {
+    DEFAULT_QUEUE_SIZE_VSOCK
+}
+
+impl Default for VsockConfig {
+    fn default() -> Self {
+        Self {
+            cid: 0,
+            socket: PathBuf::new(),
+            iommu: IommuMode::Off,
+            queue_size: default_vsockconfig_queue_size(),
+            id: None,
+        }
+    }
+}
+
 impl VsockConfig {
     pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
-        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>\"";
+        \"cid=<context_id>,socket=<socket_path>,iommu=on|off|bypass,\
+        queue_size=<size_of_each_queue>,id=<device_id>\"";
     pub fn parse(vsock: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("socket").add("cid").add("iommu").add("id");
+        parser
+            .add("socket")
+            .add("cid")
+            .add("iommu")
+            .add("queue_size")
+            .add("id");
         parser.parse(vsock).map_err(Error::ParseVsock)?;
 
         let socket = parser
@@ -1556,86 +2929,492 @@ impl VsockConfig {
             .map(PathBuf::from)
             .ok_or(Error::ParseVsockSockMissing)?;
         let iommu = parser
-            .convert::<Toggle>("iommu")
+            .convert::<IommuMode>("iommu")
             .map_err(Error::ParseVsock)?
-            .unwrap_or(Toggle(false))
-            .0;
+            .unwrap_or_default();
         let cid = parser
             .convert("cid")
             .map_err(Error::ParseVsock)?
             .ok_or(Error::ParseVsockCidMissing)?;
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or_else(default_vsockconfig_queue_size);
         let id = parser.get("id");
 
         Ok(VsockConfig {
             cid,
             socket,
             iommu,
+            queue_size,
             id,
         })
     }
-}
-
-#[cfg(feature = "tdx")]
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct TdxConfig {
-    pub firmware: PathBuf,
-}
 
-#[cfg(feature = "tdx")]
-impl TdxConfig {
-    pub fn parse(tdx: &str) -> Result<Self> {
-        let mut parser = OptionParser::new();
-        parser.add("firmware");
-        parser.parse(tdx).map_err(Error::ParseTdx)?;
-        let firmware = parser
-            .get("firmware")
-            .map(PathBuf::from)
-            .ok_or(Error::FirmwarePathMissing)?;
-        Ok(TdxConfig { firmware })
+    pub fn validate(&self) -> ValidationResult<()> {
+        validate_queues(NUM_QUEUES_VSOCK, self.queue_size)
     }
 }
 
-#[cfg(target_arch = "x86_64")]
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct SgxEpcConfig {
-    pub id: String,
+pub struct SuspendConfig {
+    /// Let the guest enter the S3 sleep state (suspend to RAM).
     #[serde(default)]
-    pub size: u64,
+    pub s3: bool,
+    /// Let the guest enter the S4 sleep state (suspend to disk), which is
+    /// turned into a snapshot of the VM sent to this URL.
     #[serde(default)]
-    pub prefault: bool,
+    pub s4_destination_url: Option<String>,
 }
 
-#[cfg(target_arch = "x86_64")]
-impl SgxEpcConfig {
-    pub const SYNTAX: &'static str = "SGX EPC parameters \
-        \"id=<epc_section_identifier>,size=<epc_section_size>,prefault=on|off\"";
-    pub fn parse(sgx_epc: &str) -> Result<Self> {
+impl SuspendConfig {
+    pub const SYNTAX: &'static str = "Guest sleep states parameters \
+        \"s3=on|off,s4_destination_url=<destination_url>\"";
+    pub fn parse(suspend: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("id").add("size").add("prefault");
-        parser.parse(sgx_epc).map_err(Error::ParseSgxEpc)?;
+        parser.add("s3").add("s4_destination_url");
+        parser.parse(suspend).map_err(Error::ParseSuspend)?;
 
-        let id = parser.get("id").ok_or(Error::ParseSgxEpcIdMissing)?;
-        let size = parser
-            .convert::<ByteSized>("size")
-            .map_err(Error::ParseSgxEpc)?
-            .unwrap_or(ByteSized(0))
-            .0;
-        let prefault = parser
-            .convert::<Toggle>("prefault")
-            .map_err(Error::ParseSgxEpc)?
+        let s3 = parser
+            .convert::<Toggle>("s3")
+            .map_err(Error::ParseSuspend)?
             .unwrap_or(Toggle(false))
             .0;
+        let s4_destination_url = parser.get("s4_destination_url");
 
-        Ok(SgxEpcConfig { id, size, prefault })
+        Ok(SuspendConfig {
+            s3,
+            s4_destination_url,
+        })
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct NumaDistance {
-    #[serde(default)]
-    pub destination: u32,
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PowerSupplyConfig {
+    /// Report the AC adapter as plugged in.
+    #[serde(default = "default_powersupplyconfig_ac_online")]
+    pub ac_online: bool,
+    /// Report a battery along with the AC adapter.
     #[serde(default)]
-    pub distance: u8,
+    pub battery: bool,
+    /// Charge level of the battery, in percent.
+    #[serde(default = "default_powersupplyconfig_battery_level")]
+    pub battery_level: u8,
+}
+
+fn default_powersupplyconfig_ac_online() -> bool {
+    true
+}
+
+fn default_powersupplyconfig_battery_level() -> u8 {
+    100
+}
+
+impl Default for PowerSupplyConfig {
+    fn default() -> Self {
+        PowerSupplyConfig {
+            ac_online: default_powersupplyconfig_ac_online(),
+            battery: false,
+            battery_level: default_powersupplyconfig_battery_level(),
+        }
+    }
+}
+
+impl PowerSupplyConfig {
+    pub const SYNTAX: &'static str = "ACPI power supply parameters \
+        \"ac_online=on|off,battery=on|off,battery_level=<percent>\"";
+    pub fn parse(power_supply: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("ac_online").add("battery").add("battery_level");
+        parser
+            .parse(power_supply)
+            .map_err(Error::ParsePowerSupply)?;
+
+        let ac_online = parser
+            .convert::<Toggle>("ac_online")
+            .map_err(Error::ParsePowerSupply)?
+            .unwrap_or(Toggle(default_powersupplyconfig_ac_online()))
+            .0;
+        let battery = parser
+            .convert::<Toggle>("battery")
+            .map_err(Error::ParsePowerSupply)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let battery_level = parser
+            .convert("battery_level")
+            .map_err(Error::ParsePowerSupply)?
+            .unwrap_or_else(default_powersupplyconfig_battery_level);
+
+        Ok(PowerSupplyConfig {
+            ac_online,
+            battery,
+            battery_level,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.battery_level > 100 {
+            return Err(ValidationError::InvalidBatteryLevel(self.battery_level));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct CgroupConfig {
+    /// Existing cgroup the VMM is moved to, relative to the root of the
+    /// cgroup v2 hierarchy. A cgroup is created for the VM if not provided.
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub memory_high: Option<u64>,
+    /// Block device the I/O limits apply to.
+    #[serde(default)]
+    pub io_device: Option<PathBuf>,
+    #[serde(default)]
+    pub io_rbps: Option<u64>,
+    #[serde(default)]
+    pub io_wbps: Option<u64>,
+    #[serde(default)]
+    pub io_riops: Option<u64>,
+    #[serde(default)]
+    pub io_wiops: Option<u64>,
+}
+
+impl CgroupConfig {
+    pub const SYNTAX: &'static str = "cgroup v2 parameters \
+        \"path=<cgroup_path>,memory_high=<memory_high_limit>,io_device=<block_device_path>,\
+        io_rbps=<read_bytes_per_second>,io_wbps=<write_bytes_per_second>,\
+        io_riops=<read_ops_per_second>,io_wiops=<write_ops_per_second>\"";
+    pub fn parse(cgroup: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("memory_high")
+            .add("io_device")
+            .add("io_rbps")
+            .add("io_wbps")
+            .add("io_riops")
+            .add("io_wiops");
+        parser.parse(cgroup).map_err(Error::ParseCgroup)?;
+
+        let path = parser.get("path");
+        let memory_high = parser
+            .convert::<ByteSized>("memory_high")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| v.0);
+        let io_device = parser.get("io_device").map(PathBuf::from);
+        let io_rbps = parser.convert("io_rbps").map_err(Error::ParseCgroup)?;
+        let io_wbps = parser.convert("io_wbps").map_err(Error::ParseCgroup)?;
+        let io_riops = parser.convert("io_riops").map_err(Error::ParseCgroup)?;
+        let io_wiops = parser.convert("io_wiops").map_err(Error::ParseCgroup)?;
+
+        Ok(CgroupConfig {
+            path,
+            memory_high,
+            io_device,
+            io_rbps,
+            io_wbps,
+            io_riops,
+            io_wiops,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.io_device.is_none() && !self.io_limits().is_empty() {
+            return Err(ValidationError::CgroupIoDeviceMissing);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the I/O limits with the keys of the "io.max" file.
+    pub fn io_limits(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("rbps", self.io_rbps),
+            ("wbps", self.io_wbps),
+            ("riops", self.io_riops),
+            ("wiops", self.io_wiops),
+        ]
+        .iter()
+        .filter_map(|(key, limit)| limit.map(|limit| (*key, limit)))
+        .collect()
+    }
+}
+
+/// IOMMU exposed to the guest when devices are placed behind one.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum IommuType {
+    /// Paravirtualized IOMMU, described through the VIOT table.
+    Virtio,
+    /// Emulated Intel VT-d, described through the DMAR table, for the guests
+    /// without any virtio-iommu driver.
+    Vtd,
+}
+
+impl Default for IommuType {
+    fn default() -> Self {
+        IommuType::Virtio
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseIommuTypeError {
+    InvalidValue(String),
+}
+
+impl FromStr for IommuType {
+    type Err = ParseIommuTypeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "virtio" => Ok(IommuType::Virtio),
+            "vtd" => Ok(IommuType::Vtd),
+            _ => Err(ParseIommuTypeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlatformConfig {
+    /// Expose the ACPI tables and devices to the guest. Without them, the
+    /// guest boots faster but loses hotplug and power management.
+    #[serde(default = "default_platformconfig_acpi")]
+    pub acpi: bool,
+    /// IOMMU the devices tagged with `iommu=on` are placed behind.
+    #[serde(default)]
+    pub iommu: IommuType,
+    /// Randomize the guest address layout on every boot.
+    #[serde(default)]
+    pub randomize_layout: bool,
+    /// Seed the randomized layout of the running VM is derived from, set by
+    /// the VMM on boot for the VM to be restored with the same layout.
+    #[serde(default)]
+    pub layout_seed: Option<u64>,
+    /// Size of the region shared with the host the virtio devices are
+    /// restricted to, for a confidential guest to bounce its DMA buffers
+    /// through.
+    #[serde(default)]
+    pub bounce_buffer_size: Option<u64>,
+}
+
+fn default_platformconfig_acpi() -> bool {
+    true
+}
+
+impl Default for PlatformConfig {
+    fn default() -> Self {
+        PlatformConfig {
+            acpi: default_platformconfig_acpi(),
+            iommu: IommuType::default(),
+            randomize_layout: false,
+            layout_seed: None,
+            bounce_buffer_size: None,
+        }
+    }
+}
+
+impl PlatformConfig {
+    pub const SYNTAX: &'static str = "Platform parameters \
+         \"acpi=on|off,iommu=virtio|vtd,randomize_layout=on|off,\
+         bounce_buffer_size=<bounce_buffers_size>\"";
+    pub fn parse(platform: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("acpi")
+            .add("iommu")
+            .add("randomize_layout")
+            .add("bounce_buffer_size");
+        parser.parse(platform).map_err(Error::ParsePlatform)?;
+
+        let acpi = parser
+            .convert::<Toggle>("acpi")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(default_platformconfig_acpi()))
+            .0;
+        let iommu = parser
+            .convert::<IommuType>("iommu")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_default();
+        let randomize_layout = parser
+            .convert::<Toggle>("randomize_layout")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let bounce_buffer_size = parser
+            .convert::<ByteSized>("bounce_buffer_size")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| v.0);
+
+        Ok(PlatformConfig {
+            acpi,
+            iommu,
+            randomize_layout,
+            layout_seed: None,
+            bounce_buffer_size,
+        })
+    }
+
+    fn validate_vtd(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if cfg!(not(target_arch = "x86_64")) {
+            return Err(ValidationError::VtdUnsupported);
+        }
+
+        let mut iommu_modes = vec![vm_config.rng.iommu, vm_config.console.iommu];
+        iommu_modes.extend(vm_config.disks.iter().flatten().map(|d| d.iommu));
+        iommu_modes.extend(vm_config.net.iter().flatten().map(|n| n.iommu));
+        iommu_modes.extend(vm_config.pmem.iter().flatten().map(|p| p.iommu));
+        iommu_modes.extend(vm_config.vsock.iter().map(|v| v.iommu));
+        if iommu_modes.contains(&IommuMode::Bypass) {
+            return Err(ValidationError::VtdIommuBypass);
+        }
+
+        // The mappings of the guest are not propagated to the physical
+        // IOMMU, which only the virtio-iommu supports.
+        if vm_config
+            .devices
+            .iter()
+            .flatten()
+            .any(|d| d.iommu.enabled())
+        {
+            return Err(ValidationError::VtdVfio);
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.iommu == IommuType::Vtd {
+            self.validate_vtd(vm_config)?;
+        }
+
+        if let Some(size) = self.bounce_buffer_size {
+            if size == 0 || size % (2 << 20) != 0 {
+                return Err(ValidationError::InvalidBounceBufferSize(size));
+            }
+            #[cfg(feature = "tdx")]
+            let confidential = vm_config.tdx.is_some();
+            #[cfg(not(feature = "tdx"))]
+            let confidential = false;
+            if !confidential {
+                return Err(ValidationError::BounceBufferNotConfidential);
+            }
+            if vm_config.disks.iter().flatten().any(|d| d.vhost_user)
+                || vm_config.net.iter().flatten().any(|n| n.vhost_user)
+                || vm_config.fs.is_some()
+            {
+                return Err(ValidationError::BounceBufferVhostUser);
+            }
+        }
+
+        if self.acpi {
+            return Ok(());
+        }
+
+        if vm_config.cpus.max_vcpus != vm_config.cpus.boot_vcpus {
+            return Err(ValidationError::AcpiDisabled("CPU hotplug"));
+        }
+
+        let memory = &vm_config.memory;
+        let zones_hotplug = memory.zones.as_ref().map_or(false, |zones| {
+            zones.iter().any(|z| z.hotplug_size.is_some())
+        });
+        if memory.hotplug_method == HotplugMethod::Acpi
+            && (memory.hotplug_size.is_some() || zones_hotplug)
+        {
+            return Err(ValidationError::AcpiDisabled("ACPI memory hotplug"));
+        }
+
+        if vm_config.numa.is_some() {
+            return Err(ValidationError::AcpiDisabled("NUMA"));
+        }
+
+        if vm_config.suspend.is_some() {
+            return Err(ValidationError::AcpiDisabled("Guest sleep states"));
+        }
+
+        if vm_config.power_supply.is_some() {
+            return Err(ValidationError::AcpiDisabled("Power supply"));
+        }
+
+        if vm_config.iommu {
+            return Err(ValidationError::AcpiDisabled(match self.iommu {
+                IommuType::Virtio => "virtio-iommu",
+                IommuType::Vtd => "VT-d",
+            }));
+        }
+
+        #[cfg(feature = "tdx")]
+        if vm_config.tdx.is_some() {
+            return Err(ValidationError::AcpiDisabled("TDX"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tdx")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct TdxConfig {
+    pub firmware: PathBuf,
+}
+
+#[cfg(feature = "tdx")]
+impl TdxConfig {
+    pub fn parse(tdx: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("firmware");
+        parser.parse(tdx).map_err(Error::ParseTdx)?;
+        let firmware = parser
+            .get("firmware")
+            .map(PathBuf::from)
+            .ok_or(Error::FirmwarePathMissing)?;
+        Ok(TdxConfig { firmware })
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct SgxEpcConfig {
+    pub id: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SgxEpcConfig {
+    pub const SYNTAX: &'static str = "SGX EPC parameters \
+        \"id=<epc_section_identifier>,size=<epc_section_size>,prefault=on|off\"";
+    pub fn parse(sgx_epc: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("id").add("size").add("prefault");
+        parser.parse(sgx_epc).map_err(Error::ParseSgxEpc)?;
+
+        let id = parser.get("id").ok_or(Error::ParseSgxEpcIdMissing)?;
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseSgxEpc)?
+            .unwrap_or(ByteSized(0))
+            .0;
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseSgxEpc)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(SgxEpcConfig { id, size, prefault })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct NumaDistance {
+    #[serde(default)]
+    pub destination: u32,
+    #[serde(default)]
+    pub distance: u8,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
@@ -1708,459 +3487,2366 @@ impl NumaConfig {
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
-pub struct RestoreConfig {
-    pub source_url: PathBuf,
-    #[serde(default)]
-    pub prefault: bool,
+pub struct PciSubsystemConfig {
+    pub id: String,
+    pub vendor: u16,
+    pub device: u16,
 }
 
-impl RestoreConfig {
-    pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
-        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
-        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
-        \n`prefault` brings memory pages in when enabled (disabled by default)";
-    pub fn parse(restore: &str) -> Result<Self> {
+impl PciSubsystemConfig {
+    pub const SYNTAX: &'static str = "Override the PCI subsystem IDs of a virtio device \
+        \"id=<device_id>,vendor=<subsystem_vendor_id>,device=<subsystem_device_id>\" \
+        \nIDs are given in hexadecimal, e.g. vendor=0x8086";
+    pub fn parse(pci_subsystem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault");
-        parser.parse(restore).map_err(Error::ParseRestore)?;
+        parser.add("id").add("vendor").add("device");
+        parser
+            .parse(pci_subsystem)
+            .map_err(Error::ParsePciSubsystem)?;
 
-        let source_url = parser
-            .get("source_url")
-            .map(PathBuf::from)
-            .ok_or(Error::ParseRestoreSourceUrlMissing)?;
-        let prefault = parser
-            .convert::<Toggle>("prefault")
-            .map_err(Error::ParseRestore)?
-            .unwrap_or(Toggle(false))
-            .0;
+        let id = parser.get("id").ok_or(Error::ParsePciSubsystemIdMissing)?;
+        let vendor =
+            Self::parse_pci_id(&parser, "vendor")?.ok_or(Error::ParsePciSubsystemVendorMissing)?;
+        let device =
+            Self::parse_pci_id(&parser, "device")?.ok_or(Error::ParsePciSubsystemDeviceMissing)?;
 
-        Ok(RestoreConfig {
-            source_url,
-            prefault,
-        })
+        Ok(PciSubsystemConfig { id, vendor, device })
+    }
+
+    fn parse_pci_id(parser: &OptionParser, option: &str) -> Result<Option<u16>> {
+        parser
+            .get(option)
+            .map(|v| {
+                u16::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| {
+                    Error::ParsePciSubsystem(OptionParserError::Conversion(
+                        option.to_owned(),
+                        v.to_owned(),
+                    ))
+                })
+            })
+            .transpose()
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
-pub struct VmConfig {
-    #[serde(default)]
-    pub cpus: CpusConfig,
-    #[serde(default)]
-    pub memory: MemoryConfig,
-    pub kernel: Option<KernelConfig>,
-    #[serde(default)]
-    pub initramfs: Option<InitramfsConfig>,
+// Feature bits the virtio-pci transport can't do without, VIRTIO_F_VERSION_1
+// and VIRTIO_F_ACCESS_PLATFORM.
+const MANDATORY_VIRTIO_FEATURES: [u8; 2] = [32, 33];
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct VirtioFeaturesConfig {
+    pub id: String,
     #[serde(default)]
-    pub cmdline: CmdlineConfig,
-    pub disks: Option<Vec<DiskConfig>>,
-    pub net: Option<Vec<NetConfig>>,
+    pub features_off: Vec<u8>,
     #[serde(default)]
-    pub rng: RngConfig,
-    pub balloon: Option<BalloonConfig>,
-    pub fs: Option<Vec<FsConfig>>,
-    pub pmem: Option<Vec<PmemConfig>>,
-    #[serde(default = "ConsoleConfig::default_serial")]
-    pub serial: ConsoleConfig,
-    #[serde(default = "ConsoleConfig::default_console")]
-    pub console: ConsoleConfig,
-    pub devices: Option<Vec<DeviceConfig>>,
-    pub vsock: Option<VsockConfig>,
-    #[serde(default)]
-    pub iommu: bool,
-    #[cfg(target_arch = "x86_64")]
-    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
-    pub numa: Option<Vec<NumaConfig>>,
-    #[serde(default)]
-    pub watchdog: bool,
-    #[cfg(feature = "tdx")]
-    pub tdx: Option<TdxConfig>,
+    pub features_on: Option<Vec<u8>>,
 }
 
-impl VmConfig {
-    pub fn validate(&self) -> ValidationResult<()> {
-        #[cfg(not(feature = "tdx"))]
-        self.kernel.as_ref().ok_or(ValidationError::KernelMissing)?;
+impl VirtioFeaturesConfig {
+    pub const SYNTAX: &'static str = "Filter the features offered by a virtio device \
+        \"id=<device_id>,features_off=<list_of_feature_bits>,features_on=<list_of_feature_bits>\" \
+        \nfeatures_on restricts the offered features to the listed ones, \
+        e.g. features_off=[28,29] turns indirect descriptors and event_idx off";
+    pub fn parse(virtio_features: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("id").add("features_off").add("features_on");
+        parser
+            .parse(virtio_features)
+            .map_err(Error::ParseVirtioFeatures)?;
 
-        #[cfg(feature = "tdx")]
+        let id = parser
+            .get("id")
+            .ok_or(Error::ParseVirtioFeaturesIdMissing)?;
+        let features_off = Self::parse_features(&parser, "features_off")?.unwrap_or_default();
+        let features_on = Self::parse_features(&parser, "features_on")?;
+
+        Ok(VirtioFeaturesConfig {
+            id,
+            features_off,
+            features_on,
+        })
+    }
+
+    fn parse_features(parser: &OptionParser, option: &str) -> Result<Option<Vec<u8>>> {
+        parser
+            .convert::<IntegerList>(option)
+            .map_err(Error::ParseVirtioFeatures)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|bit| {
+                        u8::try_from(*bit).map_err(|_| {
+                            Error::ParseVirtioFeatures(OptionParserError::Conversion(
+                                option.to_owned(),
+                                bit.to_string(),
+                            ))
+                        })
+                    })
+                    .collect()
+            })
+            .transpose()
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        for bit in self
+            .features_off
+            .iter()
+            .chain(self.features_on.iter().flatten())
         {
-            let tdx_enabled = self.tdx.is_some();
-            if !tdx_enabled && self.kernel.is_none() {
-                return Err(ValidationError::KernelMissing);
+            if *bit >= 64 {
+                return Err(ValidationError::InvalidVirtioFeature(*bit));
             }
-            if tdx_enabled && (self.cpus.max_vcpus != self.cpus.boot_vcpus) {
-                return Err(ValidationError::TdxNoCpuHotplug);
+        }
+
+        for bit in self.features_off.iter() {
+            if MANDATORY_VIRTIO_FEATURES.contains(bit) {
+                return Err(ValidationError::MandatoryVirtioFeatureOff(*bit));
             }
-            if tdx_enabled && self.kernel.is_some() {
-                return Err(ValidationError::TdxKernelSpecified);
+            if let Some(features_on) = &self.features_on {
+                if features_on.contains(bit) {
+                    return Err(ValidationError::VirtioFeatureOnAndOff(*bit));
+                }
             }
         }
 
-        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
-        {
-            return Err(ValidationError::DoubleTtyMode);
-        }
+        Ok(())
+    }
 
-        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+    /// Returns the mask to apply to the features offered by the device. The
+    /// mandatory features are kept even when not listed in `features_on`.
+    pub fn mask(&self) -> u64 {
+        let mask = |bits: &[u8]| bits.iter().fold(0u64, |mask, bit| mask | 1 << bit);
+        let on = self.features_on.as_ref().map_or(!0, |features_on| {
+            mask(features_on) | mask(&MANDATORY_VIRTIO_FEATURES)
+        });
 
-        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+        on & !mask(&self.features_off)
+    }
+}
 
-        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
-            return Err(ValidationError::CpusMaxLowerThanBoot);
-        }
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct RestoreConfig {
+    pub source_url: PathBuf,
+    #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    pub input_log: Option<InputLogConfig>,
+    #[serde(default)]
+    pub host_time: bool,
+}
 
-        if let Some(disks) = &self.disks {
-            for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
-                    return Err(ValidationError::DiskSocketAndPath);
-                }
-                if disk.vhost_user && !self.memory.shared {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
-                }
-                if disk.vhost_user && disk.vhost_socket.is_none() {
-                    return Err(ValidationError::VhostUserMissingSocket);
-                }
-                disk.validate(self)?;
-            }
-        }
+impl RestoreConfig {
+    pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
+        \nRestore parameters \"source_url=<source_url>,prefault=on|off,\
+        record_inputs=<file>,replay_inputs=<file>,host_time=on|off\" \
+        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
+        \n`prefault` brings memory pages in when enabled (disabled by default) \
+        \n`host_time` advances the guest clock by the time elapsed since the snapshot \
+        (disabled by default) \
+        \n`record_inputs` and `replay_inputs` record the inputs of the devices to a file, \
+        or replay them from it (experimental)";
+    pub fn parse(restore: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("source_url")
+            .add("prefault")
+            .add("record_inputs")
+            .add("replay_inputs")
+            .add("host_time");
+        parser.parse(restore).map_err(Error::ParseRestore)?;
 
-        if let Some(nets) = &self.net {
-            for net in nets {
-                if net.vhost_user && !self.memory.shared {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
-                }
-                net.validate(self)?;
-            }
-        }
+        let source_url = parser
+            .get("source_url")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseRestoreSourceUrlMissing)?;
+        let prefault = parser
+            .convert::<Toggle>("prefault")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
 
-        if let Some(fses) = &self.fs {
-            if !fses.is_empty() && !self.memory.shared {
-                return Err(ValidationError::VhostUserRequiresSharedMemory);
-            }
-            for fs in fses {
-                fs.validate(self)?;
-            }
-        }
+        let input_log = match (parser.get("record_inputs"), parser.get("replay_inputs")) {
+            (Some(_), Some(_)) => return Err(Error::ParseRestoreInputLogConflict),
+            (Some(path), None) => Some(InputLogConfig {
+                path: PathBuf::from(path),
+                replay: false,
+            }),
+            (None, Some(path)) => Some(InputLogConfig {
+                path: PathBuf::from(path),
+                replay: true,
+            }),
+            (None, None) => None,
+        };
+        let host_time = parser
+            .convert::<Toggle>("host_time")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
 
-        if let Some(t) = &self.cpus.topology {
-            if t.threads_per_core == 0
-                || t.cores_per_die == 0
-                || t.dies_per_package == 0
-                || t.packages == 0
-            {
-                return Err(ValidationError::CpuTopologyZeroPart);
-            }
+        Ok(RestoreConfig {
+            source_url,
+            prefault,
+            input_log,
+            host_time,
+        })
+    }
+}
 
-            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
-            if total != self.cpus.max_vcpus {
-                return Err(ValidationError::CpuTopologyCount);
-            }
-        }
+/// File the non-deterministic inputs of the devices are recorded to, or
+/// replayed from.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct InputLogConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub replay: bool,
+}
 
-        if let Some(hugepage_size) = &self.memory.hugepage_size {
-            if !self.memory.hugepages {
-                return Err(ValidationError::HugePageSizeWithoutHugePages);
-            }
-            if !hugepage_size.is_power_of_two() {
-                return Err(ValidationError::InvalidHugePageSize(*hugepage_size));
-            }
-        }
+impl InputLogConfig {
+    pub const SYNTAX: &'static str = "Record or replay the inputs of the devices (experimental) \
+        \"path=</path/to/input/log>,replay=on|off\"";
+    pub fn parse(input_log: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("replay");
+        parser.parse(input_log).map_err(Error::ParseInputLog)?;
 
-        Ok(())
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseInputLogPathMissing)?;
+        let replay = parser
+            .convert::<Toggle>("replay")
+            .map_err(Error::ParseInputLog)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(InputLogConfig { path, replay })
     }
+}
 
-    pub fn parse(vm_params: VmParams) -> Result<Self> {
-        let mut iommu = false;
+/// Host USB device passed through to the guest behind the xHCI controller.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct UsbConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub id: Option<String>,
+}
 
-        let mut disks: Option<Vec<DiskConfig>> = None;
-        if let Some(disk_list) = &vm_params.disks {
-            let mut disk_config_list = Vec::new();
-            for item in disk_list.iter() {
-                let disk_config = DiskConfig::parse(item)?;
-                if disk_config.iommu {
-                    iommu = true;
-                }
-                disk_config_list.push(disk_config);
-            }
-            disks = Some(disk_config_list);
-        }
+impl UsbConfig {
+    pub const SYNTAX: &'static str = "Host USB device passed through to the guest \
+        \"path=</dev/bus/usb/<bus>/<device>>,id=<device_id>\"";
+    pub fn parse(usb: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("id");
+        parser.parse(usb).map_err(Error::ParseUsb)?;
 
-        let mut net: Option<Vec<NetConfig>> = None;
-        if let Some(net_list) = &vm_params.net {
-            let mut net_config_list = Vec::new();
-            for item in net_list.iter() {
-                let net_config = NetConfig::parse(item)?;
-                if net_config.iommu {
-                    iommu = true;
-                }
-                net_config_list.push(net_config);
-            }
-            net = Some(net_config_list);
-        }
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseUsbPathMissing)?;
+        let id = parser.get("id");
 
-        let rng = RngConfig::parse(vm_params.rng)?;
-        if rng.iommu {
-            iommu = true;
-        }
+        Ok(UsbConfig { path, id })
+    }
+}
 
-        let mut balloon: Option<BalloonConfig> = None;
-        if let Some(balloon_params) = &vm_params.balloon {
-            balloon = Some(BalloonConfig::parse(balloon_params)?);
-        }
+/// TPM exposed to the guest, backed by either a TPM emulator or a host TPM.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TpmConfig {
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub device: Option<PathBuf>,
+    #[serde(default)]
+    pub ctrl: Option<PathBuf>,
+}
 
-        let mut fs: Option<Vec<FsConfig>> = None;
-        if let Some(fs_list) = &vm_params.fs {
-            let mut fs_config_list = Vec::new();
-            for item in fs_list.iter() {
-                fs_config_list.push(FsConfig::parse(item)?);
-            }
-            fs = Some(fs_config_list);
+impl TpmConfig {
+    pub const SYNTAX: &'static str = "TPM 2.0 backed by an emulator such as swtpm, or by a \
+        host TPM \"socket=</path/to/swtpm/socket>,device=</dev/tpmrm0>,\
+        ctrl=</path/to/swtpm/ctrl/socket>\"";
+    pub fn parse(tpm: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("socket").add("device").add("ctrl");
+        parser.parse(tpm).map_err(Error::ParseTpm)?;
+
+        let socket = parser.get("socket").map(PathBuf::from);
+        let device = parser.get("device").map(PathBuf::from);
+        let ctrl = parser.get("ctrl").map(PathBuf::from);
+        if socket.is_some() == device.is_some() {
+            return Err(Error::ParseTpmBackend);
+        }
+        if ctrl.is_some() && socket.is_none() {
+            return Err(Error::ParseTpmCtrl);
         }
 
-        let mut pmem: Option<Vec<PmemConfig>> = None;
-        if let Some(pmem_list) = &vm_params.pmem {
-            let mut pmem_config_list = Vec::new();
-            for item in pmem_list.iter() {
-                let pmem_config = PmemConfig::parse(item)?;
-                if pmem_config.iommu {
-                    iommu = true;
+        Ok(TpmConfig {
+            socket,
+            device,
+            ctrl,
+        })
+    }
+}
+
+/// Identity of the VM, exposed to the guest through the SMBIOS tables and to
+/// the management layer through `vm.info`, for an agent in the guest to find
+/// out which VM it runs in without reaching a metadata service.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct IdentityConfig {
+    /// Generated when the VM is created, if not given.
+    #[serde(default)]
+    pub uuid: Option<String>,
+    /// Serial number of the system, the UUID if not given.
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    /// Seconds since the UNIX epoch the VM was created at, set by the VMM.
+    #[serde(default)]
+    pub created: Option<u64>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl IdentityConfig {
+    pub const SYNTAX: &'static str = "Identity of the VM exposed to the guest through SMBIOS \
+        \"uuid=<uuid>,serial_number=<serial_number>,metadata=[<key>=<value>,...]\"";
+    pub fn parse(identity: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("uuid").add("serial_number").add("metadata");
+        parser.parse(identity).map_err(Error::ParseIdentity)?;
+
+        let uuid = parser.get("uuid");
+        let serial_number = parser.get("serial_number");
+        let mut metadata = BTreeMap::new();
+        if let Some(StringList(entries)) = parser
+            .convert::<StringList>("metadata")
+            .map_err(Error::ParseIdentity)?
+        {
+            for entry in entries {
+                let mut parts = entry.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        metadata.insert(key.to_owned(), value.to_owned());
+                    }
+                    _ => {
+                        return Err(Error::ParseIdentity(OptionParserError::InvalidSyntax(
+                            entry,
+                        )))
+                    }
                 }
-                pmem_config_list.push(pmem_config);
             }
-            pmem = Some(pmem_config_list);
         }
 
-        let console = ConsoleConfig::parse(vm_params.console)?;
-        if console.iommu {
-            iommu = true;
-        }
-        let serial = ConsoleConfig::parse(vm_params.serial)?;
+        Ok(IdentityConfig {
+            uuid,
+            serial_number,
+            created: None,
+            metadata,
+        })
+    }
 
-        let mut devices: Option<Vec<DeviceConfig>> = None;
-        if let Some(device_list) = &vm_params.devices {
-            let mut device_config_list = Vec::new();
-            for item in device_list.iter() {
-                let device_config = DeviceConfig::parse(item)?;
-                if device_config.iommu {
-                    iommu = true;
-                }
-                device_config_list.push(device_config);
+    pub fn validate(&self) -> ValidationResult<()> {
+        if let Some(uuid) = &self.uuid {
+            if Uuid::parse_str(uuid).is_err() {
+                return Err(ValidationError::InvalidIdentityUuid(uuid.clone()));
+            }
+        }
+        if self.metadata.len() > MAX_IDENTITY_METADATA {
+            return Err(ValidationError::TooManyIdentityMetadata(
+                self.metadata.len(),
+            ));
+        }
+        for (key, value) in self.metadata.iter() {
+            if key.is_empty() || key.contains('=') || key.contains('\0') || value.contains('\0') {
+                return Err(ValidationError::InvalidIdentityMetadata(key.clone()));
             }
-            devices = Some(device_config_list);
         }
 
-        let mut vsock: Option<VsockConfig> = None;
-        if let Some(vs) = &vm_params.vsock {
-            let vsock_config = VsockConfig::parse(vs)?;
-            if vsock_config.iommu {
-                iommu = true;
-            }
-            vsock = Some(vsock_config);
+        Ok(())
+    }
+
+    /// Strings the identity is reported through in the SMBIOS OEM strings,
+    /// as `key=value`.
+    pub fn oem_strings(&self) -> Vec<String> {
+        let mut oem_strings = Vec::new();
+        if let Some(created) = self.created {
+            oem_strings.push(format!("created={}", created));
         }
+        for (key, value) in self.metadata.iter() {
+            oem_strings.push(format!("{}={}", key, value));
+        }
+        oem_strings
+    }
+}
 
-        #[cfg(target_arch = "x86_64")]
-        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
-        #[cfg(target_arch = "x86_64")]
+/// Disk to redirect the writes of onto a new copy-on-write overlay.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
+pub struct DiskSnapshotConfig {
+    pub id: String,
+    pub overlay: PathBuf,
+}
+
+impl DiskSnapshotConfig {
+    pub const SYNTAX: &'static str = "Take a snapshot of a disk \
+        \"id=<disk_id>,overlay=</path/to/new/overlay>\"";
+    pub fn parse(disk_snapshot: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("id").add("overlay");
+        parser
+            .parse(disk_snapshot)
+            .map_err(Error::ParseDiskSnapshot)?;
+
+        let id = parser.get("id").ok_or(Error::ParseDiskSnapshotIdMissing)?;
+        let overlay = parser
+            .get("overlay")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseDiskSnapshotOverlayMissing)?;
+
+        Ok(DiskSnapshotConfig { id, overlay })
+    }
+}
+
+pub const DEFAULT_CHECKPOINT_PASSES: u32 = 5;
+
+fn default_checkpointconfig_passes() -> u32 {
+    DEFAULT_CHECKPOINT_PASSES
+}
+
+/// Consistent backup of the running VM, its guest memory being copied while
+/// the guest keeps running.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CheckpointConfig {
+    pub destination_url: String,
+    /// Directory the disks get their new overlay in, the disks not being
+    /// part of the checkpoint without it.
+    #[serde(default)]
+    pub overlay_dir: Option<PathBuf>,
+    /// Bandwidth of the copy while the VM is running, in MiB/s.
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// Maximum number of times the guest memory written during the copy is
+    /// copied again before pausing the VM.
+    #[serde(default = "default_checkpointconfig_passes")]
+    pub passes: u32,
+    /// Whether the guest is quiesced through the freeze and thaw hooks.
+    #[serde(default)]
+    pub quiesce: bool,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            destination_url: String::new(),
+            overlay_dir: None,
+            bandwidth: None,
+            passes: DEFAULT_CHECKPOINT_PASSES,
+            quiesce: false,
+        }
+    }
+}
+
+impl CheckpointConfig {
+    pub const SYNTAX: &'static str = "Checkpoint the running VM \
+        \"destination_url=<destination_url>,overlay_dir=<directory>,bandwidth=<MiB/s>,\
+        passes=<passes>,quiesce=on|off\" \
+        \n`overlay_dir` is where the disks get their new copy-on-write overlay, the disks \
+        not being part of the checkpoint without it \
+        \n`bandwidth` limits the copy of the guest memory while the VM is running \
+        \n`passes` bounds how many times the memory written meanwhile is copied again \
+        (default 5) \
+        \n`quiesce` runs the freeze and thaw hooks around the pause of the VM";
+    pub fn parse(checkpoint: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("destination_url")
+            .add("overlay_dir")
+            .add("bandwidth")
+            .add("passes")
+            .add("quiesce");
+        parser.parse(checkpoint).map_err(Error::ParseCheckpoint)?;
+
+        let destination_url = parser
+            .get("destination_url")
+            .ok_or(Error::ParseCheckpointDestinationUrlMissing)?;
+        let overlay_dir = parser.get("overlay_dir").map(PathBuf::from);
+        let bandwidth = parser
+            .convert("bandwidth")
+            .map_err(Error::ParseCheckpoint)?;
+        let passes = parser
+            .convert("passes")
+            .map_err(Error::ParseCheckpoint)?
+            .unwrap_or(DEFAULT_CHECKPOINT_PASSES);
+        let quiesce = parser
+            .convert::<Toggle>("quiesce")
+            .map_err(Error::ParseCheckpoint)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(CheckpointConfig {
+            destination_url,
+            overlay_dir,
+            bandwidth,
+            passes,
+            quiesce,
+        })
+    }
+}
+
+/// Executables the VMM runs when the VM goes through a lifecycle event.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct HooksConfig {
+    pub booted: Option<PathBuf>,
+    pub paused: Option<PathBuf>,
+    pub resumed: Option<PathBuf>,
+    pub stopped: Option<PathBuf>,
+    pub freeze: Option<PathBuf>,
+    pub thaw: Option<PathBuf>,
+}
+
+impl HooksConfig {
+    pub const SYNTAX: &'static str = "Executables run on VM lifecycle events \
+        \"booted=</path/to/hook>,paused=</path/to/hook>,resumed=</path/to/hook>,\
+        stopped=</path/to/hook>,freeze=</path/to/hook>,thaw=</path/to/hook>\"";
+    pub fn parse(hooks: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("booted")
+            .add("paused")
+            .add("resumed")
+            .add("stopped")
+            .add("freeze")
+            .add("thaw");
+        parser.parse(hooks).map_err(Error::ParseHooks)?;
+
+        Ok(HooksConfig {
+            booted: parser.get("booted").map(PathBuf::from),
+            paused: parser.get("paused").map(PathBuf::from),
+            resumed: parser.get("resumed").map(PathBuf::from),
+            stopped: parser.get("stopped").map(PathBuf::from),
+            freeze: parser.get("freeze").map(PathBuf::from),
+            thaw: parser.get("thaw").map(PathBuf::from),
+        })
+    }
+}
+
+/// What the VMM does when it receives SIGTERM or SIGINT.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SigtermAction {
+    /// Shut the VM down and exit right away.
+    Exit,
+    /// Press the ACPI power button of the VM, and exit once the guest has
+    /// powered off, or once the grace period is over.
+    PowerButton,
+}
+
+impl Default for SigtermAction {
+    fn default() -> Self {
+        SigtermAction::Exit
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseSigtermActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for SigtermAction {
+    type Err = ParseSigtermActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exit" => Ok(SigtermAction::Exit),
+            "power-button" => Ok(SigtermAction::PowerButton),
+            _ => Err(ParseSigtermActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+pub const DEFAULT_SIGTERM_GRACE_PERIOD: u64 = 30;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigtermConfig {
+    pub action: SigtermAction,
+    /// Seconds the guest is given to power off, with `power-button`.
+    pub grace_period: u64,
+}
+
+impl Default for SigtermConfig {
+    fn default() -> Self {
+        SigtermConfig {
+            action: SigtermAction::default(),
+            grace_period: DEFAULT_SIGTERM_GRACE_PERIOD,
+        }
+    }
+}
+
+impl SigtermConfig {
+    pub const SYNTAX: &'static str = "Action on SIGTERM and SIGINT, either exiting right away \
+        or pressing the ACPI power button and waiting for the guest to power off \
+        \"[action=]exit|power-button,grace_period=<seconds>\"";
+    pub fn parse(sigterm: &str) -> Result<Self> {
+        // The action can be given without its key, as in
+        // "--on-sigterm power-button,grace_period=60".
+        let first = sigterm.splitn(2, ',').next().unwrap_or_default();
+        let sigterm = if first.is_empty() || first.contains('=') {
+            sigterm.to_string()
+        } else {
+            format!("action={}", sigterm)
+        };
+
+        let mut parser = OptionParser::new();
+        parser.add("action").add("grace_period");
+        parser.parse(&sigterm).map_err(Error::ParseSigterm)?;
+
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParseSigterm)?
+            .unwrap_or_default();
+        let grace_period = parser
+            .convert("grace_period")
+            .map_err(Error::ParseSigterm)?
+            .unwrap_or(DEFAULT_SIGTERM_GRACE_PERIOD);
+
+        Ok(SigtermConfig {
+            action,
+            grace_period,
+        })
+    }
+}
+
+pub const DEFAULT_MEMORY_PRESSURE_THRESHOLD: f64 = 10.0;
+pub const DEFAULT_MEMORY_PRESSURE_INTERVAL: u64 = 1000;
+pub const DEFAULT_MEMORY_PRESSURE_STEP: u64 = 64 << 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryPressureConfig {
+    /// Share of time, in percent over the last 10 seconds, some host tasks
+    /// were stalled on memory above which the host is under pressure.
+    pub threshold: f64,
+    /// Milliseconds between two readings of the pressure.
+    pub interval: u64,
+    /// Bytes the balloons are inflated or deflated by at every interval.
+    pub step: u64,
+}
+
+impl Default for MemoryPressureConfig {
+    fn default() -> Self {
+        MemoryPressureConfig {
+            threshold: DEFAULT_MEMORY_PRESSURE_THRESHOLD,
+            interval: DEFAULT_MEMORY_PRESSURE_INTERVAL,
+            step: DEFAULT_MEMORY_PRESSURE_STEP,
+        }
+    }
+}
+
+impl MemoryPressureConfig {
+    pub const SYNTAX: &'static str = "Watch the host memory pressure, inflating the balloons \
+        configured with a pressure_max while it's too high \
+        \"threshold=<avg10_percent>,interval=<milliseconds>,step=<size>\"";
+    pub fn parse(memory_pressure: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("threshold").add("interval").add("step");
+        parser
+            .parse(memory_pressure)
+            .map_err(Error::ParseMemoryPressure)?;
+
+        let threshold = parser
+            .convert("threshold")
+            .map_err(Error::ParseMemoryPressure)?
+            .unwrap_or(DEFAULT_MEMORY_PRESSURE_THRESHOLD);
+        let interval = parser
+            .convert("interval")
+            .map_err(Error::ParseMemoryPressure)?
+            .unwrap_or(DEFAULT_MEMORY_PRESSURE_INTERVAL);
+        let step = parser
+            .convert::<ByteSized>("step")
+            .map_err(Error::ParseMemoryPressure)?
+            .map(|v| v.0)
+            .unwrap_or(DEFAULT_MEMORY_PRESSURE_STEP);
+
+        Ok(MemoryPressureConfig {
+            threshold,
+            interval,
+            step,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiVsockConfig {
+    /// Vsock port the API is served on.
+    pub port: u32,
+    /// CIDs of the VMs allowed to connect, the connections of any other one
+    /// being closed right away.
+    pub allowed_cids: Vec<u32>,
+}
+
+impl ApiVsockConfig {
+    pub const SYNTAX: &'static str = "Vsock port the HTTP API socket is also served on, \
+         to the VMs with the given CIDs only \
+         \"port=<port>,allowed_cids=<list_of_cids>\"";
+
+    pub fn parse(api_vsock: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("port").add("allowed_cids");
+        parser.parse(api_vsock).map_err(Error::ParseApiVsock)?;
+
+        let port = parser
+            .convert("port")
+            .map_err(Error::ParseApiVsock)?
+            .ok_or(Error::ParseApiVsockPortMissing)?;
+        let allowed_cids = parser
+            .convert::<IntegerList>("allowed_cids")
+            .map_err(Error::ParseApiVsock)?
+            .map(|IntegerList(cids)| cids.into_iter().map(|cid| cid as u32).collect::<Vec<_>>())
+            .filter(|cids| !cids.is_empty())
+            .ok_or(Error::ParseApiVsockAllowedCidsMissing)?;
+
+        Ok(ApiVsockConfig { port, allowed_cids })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct VmConfig {
+    #[serde(default)]
+    pub cpus: CpusConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    pub kernel: Option<KernelConfig>,
+    #[serde(default)]
+    pub initramfs: Option<InitramfsConfig>,
+    #[serde(default)]
+    pub cmdline: CmdlineConfig,
+    pub disks: Option<Vec<DiskConfig>>,
+    pub net: Option<Vec<NetConfig>>,
+    #[serde(default)]
+    pub rng: RngConfig,
+    pub balloon: Option<BalloonConfig>,
+    pub fs: Option<Vec<FsConfig>>,
+    pub pmem: Option<Vec<PmemConfig>>,
+    #[serde(default = "ConsoleConfig::default_serial")]
+    pub serial: ConsoleConfig,
+    #[serde(default = "ConsoleConfig::default_console")]
+    pub console: ConsoleConfig,
+    pub devices: Option<Vec<DeviceConfig>>,
+    pub vsock: Option<VsockConfig>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[cfg(target_arch = "x86_64")]
+    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    pub numa: Option<Vec<NumaConfig>>,
+    #[serde(default)]
+    pub numa_policy: NumaPolicy,
+    #[serde(default)]
+    pub on_worker_failure: WorkerFailurePolicy,
+    #[serde(default)]
+    pub pci_subsystems: Option<Vec<PciSubsystemConfig>>,
+    #[serde(default)]
+    pub virtio_features: Option<Vec<VirtioFeaturesConfig>>,
+    #[serde(default)]
+    pub watchdog: bool,
+    #[serde(default)]
+    pub shared_event_loop: bool,
+    pub suspend: Option<SuspendConfig>,
+    #[serde(default)]
+    pub power_supply: Option<PowerSupplyConfig>,
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+    #[serde(default)]
+    pub platform: Option<PlatformConfig>,
+    #[serde(default)]
+    pub input_log: Option<InputLogConfig>,
+    #[serde(default)]
+    pub usb: Option<Vec<UsbConfig>>,
+    #[serde(default)]
+    pub tpm: Option<TpmConfig>,
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    #[cfg(feature = "tdx")]
+    pub tdx: Option<TdxConfig>,
+}
+
+impl VmConfig {
+    /// Whether the ACPI tables and devices are exposed to the guest.
+    pub fn acpi_enabled(&self) -> bool {
+        self.platform
+            .as_ref()
+            .map_or(true, |platform| platform.acpi)
+    }
+
+    /// IOMMU the devices tagged with `iommu=on` are placed behind.
+    pub fn iommu_type(&self) -> IommuType {
+        self.platform
+            .as_ref()
+            .map_or_else(IommuType::default, |platform| platform.iommu)
+    }
+
+    /// Removes the device identified by `id`, returning whether it was found.
+    pub fn remove_device(&mut self, id: &str) -> bool {
+        let mut removed = false;
+
+        // Remove if VFIO device
+        if let Some(devices) = self.devices.as_mut() {
+            let len = devices.len();
+            devices.retain(|dev| dev.id.as_deref() != Some(id));
+            removed |= devices.len() != len;
+        }
+
+        // Remove if disk device
+        if let Some(disks) = self.disks.as_mut() {
+            let len = disks.len();
+            disks.retain(|dev| dev.id.as_deref() != Some(id));
+            removed |= disks.len() != len;
+        }
+
+        // Remove if fs device
+        if let Some(fs) = self.fs.as_mut() {
+            let len = fs.len();
+            fs.retain(|dev| dev.id.as_deref() != Some(id));
+            removed |= fs.len() != len;
+        }
+
+        // Remove if net device
+        if let Some(net) = self.net.as_mut() {
+            let len = net.len();
+            net.retain(|dev| dev.id.as_deref() != Some(id));
+            removed |= net.len() != len;
+        }
+
+        // Remove if pmem device
+        if let Some(pmem) = self.pmem.as_mut() {
+            let len = pmem.len();
+            pmem.retain(|dev| dev.id.as_deref() != Some(id));
+            removed |= pmem.len() != len;
+        }
+
+        // Remove if vsock device
+        if self
+            .vsock
+            .as_ref()
+            .map_or(false, |dev| dev.id.as_deref() == Some(id))
         {
-            if let Some(sgx_epc_list) = &vm_params.sgx_epc {
-                let mut sgx_epc_config_list = Vec::new();
-                for item in sgx_epc_list.iter() {
-                    let sgx_epc_config = SgxEpcConfig::parse(item)?;
-                    sgx_epc_config_list.push(sgx_epc_config);
-                }
-                sgx_epc = Some(sgx_epc_config_list);
-            }
+            self.vsock = None;
+            removed = true;
         }
 
-        let mut numa: Option<Vec<NumaConfig>> = None;
-        if let Some(numa_list) = &vm_params.numa {
-            let mut numa_config_list = Vec::new();
-            for item in numa_list.iter() {
-                let numa_config = NumaConfig::parse(item)?;
-                numa_config_list.push(numa_config);
+        // Remove if balloon device
+        if self
+            .balloon
+            .as_ref()
+            .map_or(false, |dev| dev.id.as_deref() == Some(id))
+        {
+            self.balloon = None;
+            removed = true;
+        }
+
+        removed
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        #[cfg(not(feature = "tdx"))]
+        self.kernel.as_ref().ok_or(ValidationError::KernelMissing)?;
+
+        #[cfg(feature = "tdx")]
+        {
+            let tdx_enabled = self.tdx.is_some();
+            if !tdx_enabled && self.kernel.is_none() {
+                return Err(ValidationError::KernelMissing);
+            }
+            if tdx_enabled && (self.cpus.max_vcpus != self.cpus.boot_vcpus) {
+                return Err(ValidationError::TdxNoCpuHotplug);
+            }
+            if tdx_enabled && self.kernel.is_some() {
+                return Err(ValidationError::TdxKernelSpecified);
             }
-            numa = Some(numa_config_list);
         }
 
-        let mut kernel: Option<KernelConfig> = None;
-        if let Some(k) = vm_params.kernel {
-            kernel = Some(KernelConfig {
-                path: PathBuf::from(k),
-            });
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(ValidationError::DoubleTtyMode);
         }
 
-        let mut initramfs: Option<InitramfsConfig> = None;
-        if let Some(k) = vm_params.initramfs {
-            initramfs = Some(InitramfsConfig {
-                path: PathBuf::from(k),
-            });
+        if self.console.mode == ConsoleOutputMode::Api && self.serial.mode == ConsoleOutputMode::Api
+        {
+            return Err(ValidationError::DoubleApiMode);
         }
 
-        #[cfg(feature = "tdx")]
-        let tdx = vm_params.tdx.map(TdxConfig::parse).transpose()?;
+        self.console.validate()?;
+        self.serial.validate()?;
+        if self.console.coalesce {
+            return Err(ValidationError::ConsoleCoalesce);
+        }
 
-        let config = VmConfig {
-            cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory, vm_params.memory_zones)?,
-            kernel,
-            initramfs,
-            cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
-            disks,
-            net,
-            rng,
-            balloon,
-            fs,
-            pmem,
-            serial,
-            console,
-            devices,
-            vsock,
-            iommu,
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc,
-            numa,
-            watchdog: vm_params.watchdog,
-            #[cfg(feature = "tdx")]
-            tdx,
-        };
-        config.validate().map_err(Error::Validation)?;
-        Ok(config)
+        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
+            return Err(ValidationError::CpusMaxLowerThanBoot);
+        }
+
+        self.cpus.validate()?;
+
+        if let Some(power_supply) = &self.power_supply {
+            power_supply.validate()?;
+        }
+
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.validate()?;
+        }
+
+        if let Some(platform) = &self.platform {
+            platform.validate(self)?;
+        }
+
+        if let Some(balloon) = &self.balloon {
+            balloon.validate(self)?;
+        }
+
+        if let Some(disks) = &self.disks {
+            for disk in disks {
+                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
+                    return Err(ValidationError::DiskSocketAndPath);
+                }
+                if disk.vhost_user && !self.memory.shared {
+                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+                }
+                if disk.vhost_user && disk.vhost_socket.is_none() {
+                    return Err(ValidationError::VhostUserMissingSocket);
+                }
+                disk.validate(self)?;
+            }
+        }
+
+        if let Some(nets) = &self.net {
+            for net in nets {
+                if net.vhost_user && !self.memory.shared {
+                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+                }
+                net.validate(self)?;
+            }
+        }
+
+        if let Some(fses) = &self.fs {
+            if !fses.is_empty() && !self.memory.shared {
+                return Err(ValidationError::VhostUserRequiresSharedMemory);
+            }
+            for fs in fses {
+                fs.validate(self)?;
+            }
+        }
+
+        if let Some(vsock) = &self.vsock {
+            vsock.validate()?;
+        }
+
+        if let Some(devices) = &self.devices {
+            for device in devices {
+                if device.iommu == IommuMode::Bypass {
+                    return Err(ValidationError::VfioIommuBypass);
+                }
+                if device.all_functions && device.iommu.enabled() {
+                    return Err(ValidationError::VfioAllFunctionsIommu(device.path.clone()));
+                }
+                if let Some(failover) = &device.failover {
+                    // The device is hot unplugged, which all_functions rules out.
+                    if device.all_functions {
+                        return Err(ValidationError::VfioFailoverAllFunctions(
+                            device.path.clone(),
+                        ));
+                    }
+                    let standby = self
+                        .net
+                        .iter()
+                        .flatten()
+                        .any(|net| net.standby && net.id.as_deref() == Some(failover.as_str()));
+                    if !standby {
+                        return Err(ValidationError::VfioFailoverNetMissing(failover.clone()));
+                    }
+                }
+            }
+        }
+
+        if let Some(t) = &self.cpus.topology {
+            if t.threads_per_core == 0
+                || t.cores_per_die == 0
+                || t.dies_per_package == 0
+                || t.packages == 0
+            {
+                return Err(ValidationError::CpuTopologyZeroPart);
+            }
+
+            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
+            if total != self.cpus.max_vcpus {
+                return Err(ValidationError::CpuTopologyCount);
+            }
+        }
+
+        if let Some(pci_subsystems) = &self.pci_subsystems {
+            let mut ids = BTreeSet::new();
+            for pci_subsystem in pci_subsystems {
+                if !ids.insert(&pci_subsystem.id) {
+                    return Err(ValidationError::DuplicatePciSubsystem(
+                        pci_subsystem.id.clone(),
+                    ));
+                }
+                // 0xffff is returned when reading from a non-existent device.
+                if pci_subsystem.vendor == 0xffff {
+                    return Err(ValidationError::InvalidPciSubsystemVendor(
+                        pci_subsystem.vendor,
+                    ));
+                }
+            }
+        }
+
+        if let Some(virtio_features) = &self.virtio_features {
+            let mut ids = BTreeSet::new();
+            for features in virtio_features {
+                if !ids.insert(&features.id) {
+                    return Err(ValidationError::DuplicateVirtioFeatures(
+                        features.id.clone(),
+                    ));
+                }
+                features.validate()?;
+            }
+        }
+
+        if let Some(usb_devices) = &self.usb {
+            if usb_devices.len() > MAX_USB_DEVICES {
+                return Err(ValidationError::TooManyUsbDevices(usb_devices.len()));
+            }
+            let mut paths = BTreeSet::new();
+            for usb in usb_devices {
+                if !paths.insert(&usb.path) {
+                    return Err(ValidationError::DuplicateUsbDevice(usb.path.clone()));
+                }
+            }
+        }
+
+        self.identity.validate()?;
+
+        if let Some(tpm) = &self.tpm {
+            if tpm.socket.is_some() == tpm.device.is_some() {
+                return Err(ValidationError::InvalidTpmBackend);
+            }
+            if tpm.ctrl.is_some() && tpm.socket.is_none() {
+                return Err(ValidationError::TpmCtrlWithoutSocket);
+            }
+            if !self.acpi_enabled() {
+                return Err(ValidationError::TpmWithoutAcpi);
+            }
+        }
+
+        if let Some(hugepage_size) = &self.memory.hugepage_size {
+            if !self.memory.hugepages {
+                return Err(ValidationError::HugePageSizeWithoutHugePages);
+            }
+            if !hugepage_size.is_power_of_two() {
+                return Err(ValidationError::InvalidHugePageSize(*hugepage_size));
+            }
+        }
+
+        if let Some(cold_page_offload) = self.memory.cold_page_offload {
+            if cold_page_offload == 0 {
+                return Err(ValidationError::InvalidColdPageOffload);
+            }
+            let zone_hugepages = self
+                .memory
+                .zones
+                .as_ref()
+                .map_or(false, |zones| zones.iter().any(|zone| zone.hugepages));
+            if self.memory.hugepages || zone_hugepages {
+                return Err(ValidationError::ColdPageOffloadHugePages);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn parse(vm_params: VmParams) -> Result<Self> {
+        let config = Self::from_params(vm_params)?;
+        config.validate().map_err(Error::Validation)?;
+        Ok(config)
+    }
+
+    /// Loads the VM configuration from `path`, as TOML if its extension is
+    /// `.toml` and as JSON otherwise, the options given on the command line
+    /// replacing the ones of the file. The options left to their default
+    /// value don't replace anything.
+    pub fn parse_with_file(path: &Path, args: &ArgMatches) -> Result<Self> {
+        let mut config = Self::from_file(path)?;
+        let cli = Self::from_params(VmParams::from_arg_matches(args))?;
+        config.override_with(cli, |name| args.occurrences_of(name) > 0);
+        config.validate().map_err(Error::Validation)?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| Error::ReadConfigFile(path.into(), e))?;
+        if path.extension().map_or(false, |ext| ext == "toml") {
+            toml::from_str(&content).map_err(|e| Error::ParseTomlConfigFile(path.into(), e))
+        } else {
+            serde_json::from_str(&content).map_err(|e| Error::ParseJsonConfigFile(path.into(), e))
+        }
+    }
+
+    // Replaces the settings of the options `given` returns true for with the
+    // ones parsed from the command line.
+    fn override_with(&mut self, cli: VmConfig, given: impl Fn(&str) -> bool) {
+        macro_rules! override_field {
+            ($($arg:expr),+ => $field:ident) => {
+                if $(given($arg))||+ {
+                    self.$field = cli.$field;
+                }
+            };
+        }
+
+        override_field!("cpus" => cpus);
+        override_field!("memory", "memory-zone" => memory);
+        override_field!("kernel" => kernel);
+        override_field!("initramfs" => initramfs);
+        override_field!("cmdline" => cmdline);
+        override_field!("disk" => disks);
+        override_field!("net" => net);
+        override_field!("rng" => rng);
+        override_field!("fs" => fs);
+        override_field!("pmem" => pmem);
+        override_field!("serial" => serial);
+        override_field!("console" => console);
+        override_field!("device" => devices);
+        override_field!("vsock" => vsock);
+        #[cfg(target_arch = "x86_64")]
+        override_field!("sgx-epc" => sgx_epc);
+        override_field!("numa" => numa);
+        override_field!("numa-policy" => numa_policy);
+        override_field!("on-worker-failure" => on_worker_failure);
+        override_field!("pci-subsystem" => pci_subsystems);
+        override_field!("virtio-features" => virtio_features);
+        override_field!("watchdog" => watchdog);
+        override_field!("shared-event-loop" => shared_event_loop);
+        override_field!("suspend" => suspend);
+        override_field!("power-supply" => power_supply);
+        override_field!("cgroup" => cgroup);
+        override_field!("platform" => platform);
+        override_field!("input-log" => input_log);
+        override_field!("usb" => usb);
+        override_field!("tpm" => tpm);
+        override_field!("identity" => identity);
+        #[cfg(feature = "tdx")]
+        override_field!("tdx" => tdx);
+
+        // The balloon may come from the legacy --memory options as well.
+        if cli.balloon.is_some() {
+            self.balloon = cli.balloon;
+        }
+        self.iommu |= cli.iommu;
+    }
+
+    // Builds the configuration from the command line, without validating it.
+    fn from_params(vm_params: VmParams) -> Result<Self> {
+        let mut iommu = false;
+
+        let mut disks: Option<Vec<DiskConfig>> = None;
+        if let Some(disk_list) = &vm_params.disks {
+            let mut disk_config_list = Vec::new();
+            for item in disk_list.iter() {
+                let disk_config = DiskConfig::parse(item)?;
+                if disk_config.iommu.enabled() {
+                    iommu = true;
+                }
+                disk_config_list.push(disk_config);
+            }
+            disks = Some(disk_config_list);
+        }
+
+        let mut net: Option<Vec<NetConfig>> = None;
+        if let Some(net_list) = &vm_params.net {
+            let mut net_config_list = Vec::new();
+            for item in net_list.iter() {
+                let net_config = NetConfig::parse(item)?;
+                if net_config.iommu.enabled() {
+                    iommu = true;
+                }
+                net_config_list.push(net_config);
+            }
+            net = Some(net_config_list);
+        }
+
+        let rng = RngConfig::parse(vm_params.rng)?;
+        if rng.iommu.enabled() {
+            iommu = true;
+        }
+
+        let balloon = if let Some(balloon_params) = &vm_params.balloon {
+            Some(BalloonConfig::parse(balloon_params)?)
+        } else {
+            BalloonConfig::parse_legacy_memory_options(vm_params.memory)?
+        };
+
+        let mut fs: Option<Vec<FsConfig>> = None;
+        if let Some(fs_list) = &vm_params.fs {
+            let mut fs_config_list = Vec::new();
+            for item in fs_list.iter() {
+                fs_config_list.push(FsConfig::parse(item)?);
+            }
+            fs = Some(fs_config_list);
+        }
+
+        let mut pmem: Option<Vec<PmemConfig>> = None;
+        if let Some(pmem_list) = &vm_params.pmem {
+            let mut pmem_config_list = Vec::new();
+            for item in pmem_list.iter() {
+                let pmem_config = PmemConfig::parse(item)?;
+                if pmem_config.iommu.enabled() {
+                    iommu = true;
+                }
+                pmem_config_list.push(pmem_config);
+            }
+            pmem = Some(pmem_config_list);
+        }
+
+        let console = ConsoleConfig::parse(vm_params.console)?;
+        if console.iommu.enabled() {
+            iommu = true;
+        }
+        let serial = ConsoleConfig::parse(vm_params.serial)?;
+
+        let mut devices: Option<Vec<DeviceConfig>> = None;
+        if let Some(device_list) = &vm_params.devices {
+            let mut device_config_list = Vec::new();
+            for item in device_list.iter() {
+                let device_config = DeviceConfig::parse(item)?;
+                if device_config.iommu.enabled() {
+                    iommu = true;
+                }
+                device_config_list.push(device_config);
+            }
+            devices = Some(device_config_list);
+        }
+
+        let mut vsock: Option<VsockConfig> = None;
+        if let Some(vs) = &vm_params.vsock {
+            let vsock_config = VsockConfig::parse(vs)?;
+            if vsock_config.iommu.enabled() {
+                iommu = true;
+            }
+            vsock = Some(vsock_config);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(sgx_epc_list) = &vm_params.sgx_epc {
+                let mut sgx_epc_config_list = Vec::new();
+                for item in sgx_epc_list.iter() {
+                    let sgx_epc_config = SgxEpcConfig::parse(item)?;
+                    sgx_epc_config_list.push(sgx_epc_config);
+                }
+                sgx_epc = Some(sgx_epc_config_list);
+            }
+        }
+
+        let mut numa: Option<Vec<NumaConfig>> = None;
+        if let Some(numa_list) = &vm_params.numa {
+            let mut numa_config_list = Vec::new();
+            for item in numa_list.iter() {
+                let numa_config = NumaConfig::parse(item)?;
+                numa_config_list.push(numa_config);
+            }
+            numa = Some(numa_config_list);
+        }
+
+        let mut pci_subsystems: Option<Vec<PciSubsystemConfig>> = None;
+        if let Some(pci_subsystem_list) = &vm_params.pci_subsystems {
+            let mut pci_subsystem_config_list = Vec::new();
+            for item in pci_subsystem_list.iter() {
+                let pci_subsystem_config = PciSubsystemConfig::parse(item)?;
+                pci_subsystem_config_list.push(pci_subsystem_config);
+            }
+            pci_subsystems = Some(pci_subsystem_config_list);
+        }
+
+        let mut virtio_features: Option<Vec<VirtioFeaturesConfig>> = None;
+        if let Some(virtio_features_list) = &vm_params.virtio_features {
+            let mut virtio_features_config_list = Vec::new();
+            for item in virtio_features_list.iter() {
+                let virtio_features_config = VirtioFeaturesConfig::parse(item)?;
+                virtio_features_config_list.push(virtio_features_config);
+            }
+            virtio_features = Some(virtio_features_config_list);
+        }
+
+        let mut kernel: Option<KernelConfig> = None;
+        if let Some(k) = vm_params.kernel {
+            kernel = Some(KernelConfig {
+                path: PathBuf::from(k),
+            });
+        }
+
+        let mut initramfs: Option<InitramfsConfig> = None;
+        if let Some(k) = vm_params.initramfs {
+            initramfs = Some(InitramfsConfig {
+                path: PathBuf::from(k),
+            });
+        }
+
+        let numa_policy = vm_params
+            .numa_policy
+            .map(NumaPolicy::from_str)
+            .transpose()
+            .map_err(Error::ParseNumaPolicy)?
+            .unwrap_or_default();
+
+        let on_worker_failure = vm_params
+            .on_worker_failure
+            .map(WorkerFailurePolicy::from_str)
+            .transpose()
+            .map_err(Error::ParseWorkerFailurePolicy)?
+            .unwrap_or_default();
+
+        let suspend = vm_params.suspend.map(SuspendConfig::parse).transpose()?;
+        let power_supply = vm_params
+            .power_supply
+            .map(PowerSupplyConfig::parse)
+            .transpose()?;
+        let cgroup = vm_params.cgroup.map(CgroupConfig::parse).transpose()?;
+        let platform = vm_params.platform.map(PlatformConfig::parse).transpose()?;
+        let input_log = vm_params.input_log.map(InputLogConfig::parse).transpose()?;
+
+        let mut usb: Option<Vec<UsbConfig>> = None;
+        if let Some(usb_list) = &vm_params.usb {
+            let mut usb_config_list = Vec::new();
+            for item in usb_list.iter() {
+                let usb_config = UsbConfig::parse(item)?;
+                usb_config_list.push(usb_config);
+            }
+            usb = Some(usb_config_list);
+        }
+
+        let tpm = vm_params.tpm.map(TpmConfig::parse).transpose()?;
+        let identity = vm_params
+            .identity
+            .map(IdentityConfig::parse)
+            .transpose()?
+            .unwrap_or_default();
+
+        #[cfg(feature = "tdx")]
+        let tdx = vm_params.tdx.map(TdxConfig::parse).transpose()?;
+
+        Ok(VmConfig {
+            cpus: CpusConfig::parse(vm_params.cpus)?,
+            memory: MemoryConfig::parse(vm_params.memory, vm_params.memory_zones)?,
+            kernel,
+            initramfs,
+            cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
+            disks,
+            net,
+            rng,
+            balloon,
+            fs,
+            pmem,
+            serial,
+            console,
+            devices,
+            vsock,
+            iommu,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc,
+            numa,
+            numa_policy,
+            on_worker_failure,
+            pci_subsystems,
+            virtio_features,
+            watchdog: vm_params.watchdog,
+            shared_event_loop: vm_params.shared_event_loop,
+            suspend,
+            power_supply,
+            cgroup,
+            platform,
+            input_log,
+            usb,
+            tpm,
+            identity,
+            #[cfg(feature = "tdx")]
+            tdx,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_option_parser() {
+        let mut parser = OptionParser::new();
+        parser
+            .add("size")
+            .add("mergeable")
+            .add("hotplug_method")
+            .add("hotplug_size");
+
+        assert!(parser.parse("size=128M,hanging_param").is_err());
+        assert!(parser.parse("size=128M,too_many_equals=foo=bar").is_err());
+        assert!(parser.parse("size=128M,file=/dev/shm").is_err());
+        assert!(parser.parse("size=128M").is_ok());
+
+        assert_eq!(parser.get("size"), Some("128M".to_owned()));
+        assert!(!parser.is_set("mergeable"));
+        assert!(parser.is_set("size"));
+
+        // Commas and equal signs are kept within brackets and quotes.
+        assert!(parser
+            .parse("size=128M,hotplug_method=[a,b=c],hotplug_size=\"1,2=3\"")
+            .is_ok());
+        assert_eq!(parser.get("hotplug_method"), Some("[a,b=c]".to_owned()));
+        assert_eq!(parser.get("hotplug_size"), Some("1,2=3".to_owned()));
+        assert!(parser.parse("size=[128M").is_err());
+        assert!(parser.parse("size=128M]").is_err());
+        assert!(parser.parse("size=\"128M").is_err());
+
+        assert_eq!(
+            split_list("[0-1,\"a,b\",[2,3]]").unwrap(),
+            vec!["0-1", "a,b", "[2,3]"]
+        );
+        assert_eq!(split_list("0-1:2").unwrap(), vec!["0-1", "2"]);
+        assert!(split_list("[]").unwrap().is_empty());
+        assert!(split_list("[0,1").is_err());
+    }
+
+    #[test]
+    fn test_cpu_parsing() -> Result<()> {
+        assert_eq!(CpusConfig::parse("")?, CpusConfig::default());
+
+        assert_eq!(
+            CpusConfig::parse("boot=1")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,max=2")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=8,topology=2:2:1:2")?,
+            CpusConfig {
+                boot_vcpus: 8,
+                max_vcpus: 8,
+                topology: Some(CpuTopology {
+                    threads_per_core: 2,
+                    cores_per_die: 2,
+                    dies_per_package: 1,
+                    packages: 2
+                }),
+                ..Default::default()
+            }
+        );
+
+        assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
+        assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=1,kvm_hyperv=on")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                kvm_hyperv: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=2,quota=50000,period=200000")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                quota: Some(50000),
+                period: Some(200000),
+                ..Default::default()
+            }
+        );
+        assert!(CpusConfig::parse("quota=half").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=1,pmu=on,sve=on,ptrauth=on")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                pmu: true,
+                sve: true,
+                ptrauth: true,
+                ..Default::default()
+            }
+        );
+        assert!(CpusConfig::parse("pmu=yes").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=4,affinity=0@0-1:0@4:1-3@2")?,
+            CpusConfig {
+                boot_vcpus: 4,
+                max_vcpus: 4,
+                affinity: Some(vec![
+                    CpuAffinity {
+                        vcpu: 0,
+                        host_cpus: vec![0, 1, 4],
+                    },
+                    CpuAffinity {
+                        vcpu: 1,
+                        host_cpus: vec![2],
+                    },
+                    CpuAffinity {
+                        vcpu: 2,
+                        host_cpus: vec![2],
+                    },
+                    CpuAffinity {
+                        vcpu: 3,
+                        host_cpus: vec![2],
+                    },
+                ]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=4,affinity=[0@[0-1,4],1-3@2]")?,
+            CpusConfig::parse("boot=4,affinity=0@0-1:0@4:1-3@2")?
+        );
+        assert!(CpusConfig::parse("affinity=0-1").is_err());
+        assert!(CpusConfig::parse("affinity=1-0@2").is_err());
+        assert!(CpusConfig::parse("affinity=[0@[0-1,4]").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=2,core_types=0@core:1@atom")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                core_types: Some(vec![
+                    CpuCoreType {
+                        vcpu: 0,
+                        core_type: CoreType::Core,
+                    },
+                    CpuCoreType {
+                        vcpu: 1,
+                        core_type: CoreType::Atom,
+                    },
+                ]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=2,core_types=[0@core,1@atom]")?,
+            CpusConfig::parse("boot=2,core_types=0@core:1@atom")?
+        );
+        assert!(CpusConfig::parse("core_types=0@big").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=1,kvmclock=off")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                kvmclock: false,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,ptp_kvm=on")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                ptp_kvm: Some(true),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,ptp_kvm=off")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                ptp_kvm: Some(false),
+                ..Default::default()
+            }
+        );
+        assert!(CpusConfig::parse("ptp_kvm=maybe").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_parsing() -> Result<()> {
+        assert_eq!(MemoryConfig::parse("", None)?, MemoryConfig::default());
+        // Default string
+        assert_eq!(
+            MemoryConfig::parse("size=512M", None)?,
+            MemoryConfig::default()
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=512M,mergeable=on", None)?,
+            MemoryConfig {
+                size: 512 << 20,
+                mergeable: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("mergeable=on", None)?,
+            MemoryConfig {
+                mergeable: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,mergeable=off", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                mergeable: false,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=acpi", None)?,
+            MemoryConfig {
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=acpi,hotplug_size=512M", None)?,
+            MemoryConfig {
+                hotplug_size: Some(512 << 20),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
+            MemoryConfig {
+                hotplug_size: Some(512 << 20),
+                hotplug_method: HotplugMethod::VirtioMem,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hugepages=on,size=1G,hugepage_size=2M", None)?,
+            MemoryConfig {
+                hugepage_size: Some(2 << 20),
+                size: 1 << 30,
+                hugepages: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,cold_page_offload=60", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                cold_page_offload: Some(60),
+                ..Default::default()
+            }
+        );
+        // The guest RAM is always backed by a memfd, a file can only back a
+        // memory zone.
+        assert!(MemoryConfig::parse("size=1G,file=/dev/shm", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_parsing() -> Result<()> {
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,id=mydisk0")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                id: Some("mydisk0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("vhost_user=true,socket=/tmp/sock")?,
+            DiskConfig {
+                vhost_socket: Some(String::from("/tmp/sock")),
+                vhost_user: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,iommu=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                iommu: IommuMode::On,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                iommu: IommuMode::On,
+                queue_size: 256,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256,num_queues=4")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                iommu: IommuMode::On,
+                queue_size: 256,
+                num_queues: 4,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,workers=4")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                workers: 4,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_base,overlay=/path/to_overlay")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_base")),
+                overlay: Some(PathBuf::from("/path/to_overlay")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_base,overlay=/path/to_overlay,backing_overlays=[/path/to_first,/path/to_second]"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_base")),
+                overlay: Some(PathBuf::from("/path/to_overlay")),
+                backing_overlays: vec![
+                    PathBuf::from("/path/to_first"),
+                    PathBuf::from("/path/to_second")
+                ],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,model=nvme")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                model: DiskModel::Nvme,
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,model=scsi").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,serial=DISK0")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                serial: Some("DISK0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,direct=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                direct: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,poll_queue=false")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                poll_queue: false,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,poll_queue=true")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                poll_queue: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,poll_us=50")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                poll_us: 50,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,fd=5")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                fd: Some(5),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_net_parsing() -> Result<()> {
+        // mac address is random
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                id: Some("mynet0".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                tap: Some("tap0".to_owned()),
+                ip: "192.168.100.1".parse().unwrap(),
+                mask: "255.255.255.128".parse().unwrap(),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,vhost_user=true,socket=/tmp/sock,num_queues=4,mtu=9000"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                num_queues: 4,
+                mtu: Some(9000),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                num_queues: 4,
+                queue_size: 1024,
+                iommu: IommuMode::On,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=3:7,num_queues=4")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                fds: Some(vec![3, 7]),
+                num_queues: 4,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,id=net0,standby=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                id: Some("net0".to_owned()),
+                standby: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("tap=tap0,rx_filter=/tmp/filter")?,
+            NetConfig {
+                tap: Some("tap0".to_owned()),
+                rx_filter: Some(PathBuf::from("/tmp/filter")),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rng() -> Result<()> {
+        assert_eq!(RngConfig::parse("")?, RngConfig::default());
+        assert_eq!(
+            RngConfig::parse("src=/dev/random")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("src=/dev/random,iommu=on")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                iommu: IommuMode::On,
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("iommu=on")?,
+            RngConfig {
+                iommu: IommuMode::On,
+                ..Default::default()
+            }
+        );
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn test_option_parser() {
-        let mut parser = OptionParser::new();
-        parser
-            .add("size")
-            .add("mergeable")
-            .add("hotplug_method")
-            .add("hotplug_size");
+    fn test_parse_fs() -> Result<()> {
+        // "tag" and "socket" must be supplied
+        assert!(FsConfig::parse("").is_err());
+        assert!(FsConfig::parse("tag=mytag").is_err());
+        assert!(FsConfig::parse("socket=/tmp/sock").is_err());
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,num_queues=4,queue_size=1024")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                num_queues: 4,
+                queue_size: 1024,
+                ..Default::default()
+            }
+        );
+        // DAX on -> default cache size
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=on")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                dax: true,
+                cache_size: default_fsconfig_cache_size(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=on,cache_size=4G")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                dax: true,
+                cache_size: 4 << 30,
+                ..Default::default()
+            }
+        );
+        // Cache size without DAX is an error
+        assert!(FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=off,cache_size=4G").is_err());
+        Ok(())
+    }
 
-        assert!(parser.parse("size=128M,hanging_param").is_err());
-        assert!(parser.parse("size=128M,too_many_equals=foo=bar").is_err());
-        assert!(parser.parse("size=128M,file=/dev/shm").is_err());
-        assert!(parser.parse("size=128M").is_ok());
+    #[test]
+    fn test_balloon_parsing() -> Result<()> {
+        assert_eq!(
+            BalloonConfig::parse("size=1G")?,
+            BalloonConfig {
+                size: 1 << 30,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+                id: None,
+                pressure_max: 0,
+                pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse("size=512M,deflate_on_oom=on,id=balloon0")?,
+            BalloonConfig {
+                size: 512 << 20,
+                deflate_on_oom: true,
+                free_page_reporting: false,
+                id: Some("balloon0".to_owned()),
+                pressure_max: 0,
+                pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse("size=0,free_page_reporting=on")?,
+            BalloonConfig {
+                size: 0,
+                deflate_on_oom: false,
+                free_page_reporting: true,
+                id: None,
+                pressure_max: 0,
+                pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+            }
+        );
+        assert_eq!(
+            BalloonConfig::parse("size=0,pressure_max=2G,pressure_priority=3")?,
+            BalloonConfig {
+                size: 0,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+                id: None,
+                pressure_max: 2 << 30,
+                pressure_priority: 3,
+            }
+        );
 
-        assert_eq!(parser.get("size"), Some("128M".to_owned()));
-        assert!(!parser.is_set("mergeable"));
-        assert!(parser.is_set("size"));
+        // The balloon used to be configured through --memory
+        assert_eq!(
+            BalloonConfig::parse_legacy_memory_options("size=1G,balloon=on,balloon_size=256M")?,
+            Some(BalloonConfig {
+                size: 256 << 20,
+                deflate_on_oom: false,
+                free_page_reporting: false,
+                id: None,
+                pressure_max: 0,
+                pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+            })
+        );
+        assert_eq!(
+            BalloonConfig::parse_legacy_memory_options("size=1G,balloon_size=256M")?,
+            None
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,balloon=on,balloon_size=256M", None)?.size,
+            1 << 30
+        );
+        Ok(())
     }
 
     #[test]
-    fn test_cpu_parsing() -> Result<()> {
-        assert_eq!(CpusConfig::parse("")?, CpusConfig::default());
-
+    fn test_pmem_parsing() -> Result<()> {
+        // Must always give a file and size
+        assert!(PmemConfig::parse("").is_err());
+        assert!(PmemConfig::parse("size=128M").is_err());
         assert_eq!(
-            CpusConfig::parse("boot=1")?,
-            CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
+            PmemConfig::parse("file=/tmp/pmem,size=128M")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
                 ..Default::default()
             }
         );
         assert_eq!(
-            CpusConfig::parse("boot=1,max=2")?,
-            CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 2,
+            PmemConfig::parse("file=/tmp/pmem,size=128M,id=mypmem0")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                id: Some("mypmem0".to_owned()),
                 ..Default::default()
             }
         );
         assert_eq!(
-            CpusConfig::parse("boot=8,topology=2:2:1:2")?,
-            CpusConfig {
-                boot_vcpus: 8,
-                max_vcpus: 8,
-                topology: Some(CpuTopology {
-                    threads_per_core: 2,
-                    cores_per_die: 2,
-                    dies_per_package: 1,
-                    packages: 2
-                }),
+            PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,mergeable=on,discard_writes=on")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                mergeable: true,
+                discard_writes: true,
+                iommu: IommuMode::On,
                 ..Default::default()
             }
         );
 
-        assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
-        assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_parsing() -> Result<()> {
+        assert!(ConsoleConfig::parse("").is_err());
+        assert!(ConsoleConfig::parse("badmode").is_err());
         assert_eq!(
-            CpusConfig::parse("boot=1,kvm_hyperv=on")?,
-            CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
-                kvm_hyperv: true,
-                ..Default::default()
+            ConsoleConfig::parse("off")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Off,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tty,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("null")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Null,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: IommuMode::Off,
+                file: Some(PathBuf::from("/tmp/console")),
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("null,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Null,
+                iommu: IommuMode::On,
+                file: None,
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: IommuMode::On,
+                file: Some(PathBuf::from("/tmp/console")),
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("api")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Api,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: None,
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tty,file=/tmp/console")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tty,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: Some(PathBuf::from("/tmp/console")),
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty,file=/tmp/console")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: Some(PathBuf::from("/tmp/console")),
+                coalesce: false,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tty,coalesce=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tty,
+                iommu: IommuMode::Off,
+                file: None,
+                log_file: None,
+                coalesce: true,
             }
         );
+        assert!(ConsoleConfig::parse("tty,coalesce=maybe").is_err());
+        assert!(ConsoleConfig::parse("off,file=/tmp/console").is_err());
+        assert!(ConsoleConfig::parse("null,file=/tmp/console").is_err());
+        assert!(ConsoleConfig::parse("api,file=/tmp/console").is_err());
         Ok(())
     }
 
     #[test]
-    fn test_mem_parsing() -> Result<()> {
-        assert_eq!(MemoryConfig::parse("", None)?, MemoryConfig::default());
-        // Default string
+    fn test_device_parsing() -> Result<()> {
+        // Device must have a path provided
+        assert!(DeviceConfig::parse("").is_err());
         assert_eq!(
-            MemoryConfig::parse("size=512M", None)?,
-            MemoryConfig::default()
+            DeviceConfig::parse("path=/path/to/device")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: IommuMode::Off,
+                all_functions: false,
+                managed: false,
+                failover: None,
+            }
         );
+
         assert_eq!(
-            MemoryConfig::parse("size=512M,mergeable=on", None)?,
-            MemoryConfig {
-                size: 512 << 20,
-                mergeable: true,
-                ..Default::default()
+            DeviceConfig::parse("path=/path/to/device,iommu=on")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: IommuMode::On,
+                all_functions: false,
+                managed: false,
+                failover: None,
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,iommu=on,id=mydevice0")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: Some("mydevice0".to_owned()),
+                iommu: IommuMode::On,
+                all_functions: false,
+                managed: false,
+                failover: None,
             }
         );
+
         assert_eq!(
-            MemoryConfig::parse("mergeable=on", None)?,
-            MemoryConfig {
-                mergeable: true,
-                ..Default::default()
+            DeviceConfig::parse("path=/path/to/device,iommu=bypass")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: IommuMode::Bypass,
+                all_functions: false,
+                managed: false,
+                failover: None,
             }
         );
+        assert!(DeviceConfig::parse("path=/path/to/device,iommu=maybe").is_err());
+
         assert_eq!(
-            MemoryConfig::parse("size=1G,mergeable=off", None)?,
-            MemoryConfig {
-                size: 1 << 30,
-                mergeable: false,
-                ..Default::default()
+            DeviceConfig::parse("path=/sys/bus/pci/devices/0000:01:00.0/,all_functions=on")?,
+            DeviceConfig {
+                path: PathBuf::from("/sys/bus/pci/devices/0000:01:00.0/"),
+                id: None,
+                iommu: IommuMode::Off,
+                all_functions: true,
+                managed: false,
+                failover: None,
             }
         );
+
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=acpi", None)?,
-            MemoryConfig {
-                ..Default::default()
+            DeviceConfig::parse("path=0000:af:00.0,managed=on")?,
+            DeviceConfig {
+                path: PathBuf::from("0000:af:00.0"),
+                id: None,
+                iommu: IommuMode::Off,
+                all_functions: false,
+                managed: true,
+                failover: None,
             }
         );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iommu_mode_deserialization() {
+        // Booleans are still accepted along with the modes.
+        let rng: RngConfig =
+            serde_json::from_str(r#"{"src": "/dev/urandom", "iommu": true}"#).unwrap();
+        assert_eq!(rng.iommu, IommuMode::On);
+        let rng: RngConfig =
+            serde_json::from_str(r#"{"src": "/dev/urandom", "iommu": false}"#).unwrap();
+        assert_eq!(rng.iommu, IommuMode::Off);
+        let rng: RngConfig =
+            serde_json::from_str(r#"{"src": "/dev/urandom", "iommu": "Bypass"}"#).unwrap();
+        assert_eq!(rng.iommu, IommuMode::Bypass);
+        let rng: RngConfig = serde_json::from_str(r#"{"src": "/dev/urandom"}"#).unwrap();
+        assert_eq!(rng.iommu, IommuMode::Off);
+        assert!(
+            serde_json::from_str::<RngConfig>(r#"{"src": "/dev/urandom", "iommu": "maybe"}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_vsock_parsing() -> Result<()> {
+        // socket and cid is required
+        assert!(VsockConfig::parse("").is_err());
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=acpi,hotplug_size=512M", None)?,
-            MemoryConfig {
-                hotplug_size: Some(512 << 20),
-                ..Default::default()
+            VsockConfig::parse("socket=/tmp/sock,cid=1")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: IommuMode::Off,
+                queue_size: DEFAULT_QUEUE_SIZE_VSOCK,
+                id: None,
             }
         );
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
-            MemoryConfig {
-                hotplug_size: Some(512 << 20),
-                hotplug_method: HotplugMethod::VirtioMem,
-                ..Default::default()
+            VsockConfig::parse("socket=/tmp/sock,cid=1,iommu=on")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: IommuMode::On,
+                queue_size: DEFAULT_QUEUE_SIZE_VSOCK,
+                id: None,
             }
         );
         assert_eq!(
-            MemoryConfig::parse("hugepages=on,size=1G,hugepage_size=2M", None)?,
-            MemoryConfig {
-                hugepage_size: Some(2 << 20),
-                size: 1 << 30,
-                hugepages: true,
+            VsockConfig::parse("socket=/tmp/sock,cid=1,queue_size=1024")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                queue_size: 1024,
                 ..Default::default()
             }
         );
@@ -2168,413 +5854,456 @@ mod tests {
     }
 
     #[test]
-    fn test_disk_parsing() -> Result<()> {
+    fn test_suspend_parsing() -> Result<()> {
+        assert_eq!(SuspendConfig::parse("")?, SuspendConfig::default());
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                ..Default::default()
+            SuspendConfig::parse("s3=on,s4_destination_url=file:///tmp/hibernate")?,
+            SuspendConfig {
+                s3: true,
+                s4_destination_url: Some("file:///tmp/hibernate".to_owned()),
             }
         );
+        assert!(SuspendConfig::parse("s3=maybe").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_platform_parsing() -> Result<()> {
+        assert_eq!(PlatformConfig::parse("")?, PlatformConfig::default());
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,id=mydisk0")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                id: Some("mydisk0".to_owned()),
+            PlatformConfig::parse("acpi=off")?,
+            PlatformConfig {
+                acpi: false,
                 ..Default::default()
             }
         );
         assert_eq!(
-            DiskConfig::parse("vhost_user=true,socket=/tmp/sock")?,
-            DiskConfig {
-                vhost_socket: Some(String::from("/tmp/sock")),
-                vhost_user: true,
+            PlatformConfig::parse("iommu=vtd")?,
+            PlatformConfig {
+                acpi: true,
+                iommu: IommuType::Vtd,
                 ..Default::default()
             }
         );
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,iommu=on")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                iommu: true,
+            PlatformConfig::parse("randomize_layout=on")?,
+            PlatformConfig {
+                randomize_layout: true,
                 ..Default::default()
             }
         );
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                iommu: true,
-                queue_size: 256,
+            PlatformConfig::parse("bounce_buffer_size=64M")?,
+            PlatformConfig {
+                bounce_buffer_size: Some(64 << 20),
                 ..Default::default()
             }
         );
+        assert!(PlatformConfig::parse("acpi=maybe").is_err());
+        assert!(PlatformConfig::parse("iommu=smmu").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_supply_parsing() -> Result<()> {
+        assert_eq!(PowerSupplyConfig::parse("")?, PowerSupplyConfig::default());
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256,num_queues=4")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                iommu: true,
-                queue_size: 256,
-                num_queues: 4,
-                ..Default::default()
+            PowerSupplyConfig::parse("ac_online=off,battery=on,battery_level=42")?,
+            PowerSupplyConfig {
+                ac_online: false,
+                battery: true,
+                battery_level: 42,
             }
         );
+        assert!(PowerSupplyConfig::parse("battery_level=142")?
+            .validate()
+            .is_err());
+        assert!(PowerSupplyConfig::parse("battery_level=-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_usb_parsing() -> Result<()> {
+        assert!(UsbConfig::parse("").is_err());
+        assert!(UsbConfig::parse("id=token0").is_err());
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,direct=on")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                direct: true,
-                ..Default::default()
+            UsbConfig::parse("path=/dev/bus/usb/001/004")?,
+            UsbConfig {
+                path: PathBuf::from("/dev/bus/usb/001/004"),
+                id: None,
             }
         );
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                ..Default::default()
+            UsbConfig::parse("path=/dev/bus/usb/001/004,id=token0")?,
+            UsbConfig {
+                path: PathBuf::from("/dev/bus/usb/001/004"),
+                id: Some("token0".to_owned()),
             }
         );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tpm_parsing() -> Result<()> {
+        assert!(TpmConfig::parse("").is_err());
+        assert!(TpmConfig::parse("socket=/tmp/swtpm.sock,device=/dev/tpmrm0").is_err());
+        assert!(TpmConfig::parse("device=/dev/tpmrm0,ctrl=/tmp/swtpm.ctrl").is_err());
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                ..Default::default()
+            TpmConfig::parse("socket=/tmp/swtpm.sock")?,
+            TpmConfig {
+                socket: Some(PathBuf::from("/tmp/swtpm.sock")),
+                device: None,
+                ctrl: None,
             }
         );
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,poll_queue=false")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                poll_queue: false,
-                ..Default::default()
+            TpmConfig::parse("socket=/tmp/swtpm.sock,ctrl=/tmp/swtpm.ctrl")?,
+            TpmConfig {
+                socket: Some(PathBuf::from("/tmp/swtpm.sock")),
+                device: None,
+                ctrl: Some(PathBuf::from("/tmp/swtpm.ctrl")),
             }
         );
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,poll_queue=true")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                poll_queue: true,
-                ..Default::default()
+            TpmConfig::parse("device=/dev/tpmrm0")?,
+            TpmConfig {
+                socket: None,
+                device: Some(PathBuf::from("/dev/tpmrm0")),
+                ctrl: None,
             }
         );
-
         Ok(())
     }
 
     #[test]
-    fn test_net_parsing() -> Result<()> {
-        // mac address is random
+    fn test_identity_parsing() -> Result<()> {
+        assert_eq!(IdentityConfig::parse("")?, IdentityConfig::default());
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                ..Default::default()
+            IdentityConfig::parse(
+                "uuid=6a6f6e2a-8e3c-4bd2-9b0e-0d4c1f6e0a2b,serial_number=vm-0042,\
+                 metadata=[owner=alice,role=db=primary]"
+            )?,
+            IdentityConfig {
+                uuid: Some("6a6f6e2a-8e3c-4bd2-9b0e-0d4c1f6e0a2b".to_owned()),
+                serial_number: Some("vm-0042".to_owned()),
+                created: None,
+                metadata: vec![
+                    ("owner".to_owned(), "alice".to_owned()),
+                    ("role".to_owned(), "db=primary".to_owned()),
+                ]
+                .into_iter()
+                .collect(),
             }
         );
+        assert!(IdentityConfig::parse("metadata=[owner]").is_err());
+        assert!(IdentityConfig::parse("metadata=[=alice]").is_err());
+        Ok(())
+    }
 
+    #[test]
+    fn test_input_log_parsing() -> Result<()> {
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                id: Some("mynet0".to_owned()),
-                ..Default::default()
+            InputLogConfig::parse("path=/tmp/inputs")?,
+            InputLogConfig {
+                path: PathBuf::from("/tmp/inputs"),
+                replay: false,
             }
         );
-
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
-            )?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                tap: Some("tap0".to_owned()),
-                ip: "192.168.100.1".parse().unwrap(),
-                mask: "255.255.255.128".parse().unwrap(),
-                ..Default::default()
+            InputLogConfig::parse("path=/tmp/inputs,replay=on")?,
+            InputLogConfig {
+                path: PathBuf::from("/tmp/inputs"),
+                replay: true,
             }
         );
+        assert!(InputLogConfig::parse("replay=on").is_err());
 
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
-            )?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                vhost_user: true,
-                vhost_socket: Some("/tmp/sock".to_owned()),
-                ..Default::default()
-            }
+            RestoreConfig::parse("source_url=/tmp/snapshot,replay_inputs=/tmp/inputs")?.input_log,
+            Some(InputLogConfig {
+                path: PathBuf::from("/tmp/inputs"),
+                replay: true,
+            })
         );
+        assert!(RestoreConfig::parse(
+            "source_url=/tmp/snapshot,record_inputs=/tmp/a,replay_inputs=/tmp/b"
+        )
+        .is_err());
+        Ok(())
+    }
 
+    #[test]
+    fn test_restore_parsing() -> Result<()> {
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                num_queues: 4,
-                queue_size: 1024,
-                iommu: true,
-                ..Default::default()
+            RestoreConfig::parse("source_url=/tmp/snapshot")?,
+            RestoreConfig {
+                source_url: PathBuf::from("/tmp/snapshot"),
+                prefault: false,
+                input_log: None,
+                host_time: false,
             }
         );
+        assert!(RestoreConfig::parse("source_url=/tmp/snapshot,host_time=on")?.host_time);
+        assert!(RestoreConfig::parse("source_url=/tmp/snapshot,host_time=now").is_err());
+        assert!(RestoreConfig::parse("prefault=on").is_err());
+        Ok(())
+    }
 
+    #[test]
+    fn test_disk_snapshot_parsing() -> Result<()> {
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=3:7,num_queues=4")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                fds: Some(vec![3, 7]),
-                num_queues: 4,
-                ..Default::default()
+            DiskSnapshotConfig::parse("id=disk0,overlay=/tmp/overlay")?,
+            DiskSnapshotConfig {
+                id: "disk0".to_owned(),
+                overlay: PathBuf::from("/tmp/overlay"),
             }
         );
-
+        assert!(DiskSnapshotConfig::parse("overlay=/tmp/overlay").is_err());
+        assert!(DiskSnapshotConfig::parse("id=disk0").is_err());
+        assert!(DiskSnapshotConfig::parse("id=disk0,overlay=/tmp/overlay,foo=bar").is_err());
         Ok(())
     }
 
     #[test]
-    fn test_parse_rng() -> Result<()> {
-        assert_eq!(RngConfig::parse("")?, RngConfig::default());
+    fn test_checkpoint_parsing() -> Result<()> {
         assert_eq!(
-            RngConfig::parse("src=/dev/random")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
+            CheckpointConfig::parse("destination_url=file:///tmp/checkpoint")?,
+            CheckpointConfig {
+                destination_url: "file:///tmp/checkpoint".to_owned(),
                 ..Default::default()
             }
         );
         assert_eq!(
-            RngConfig::parse("src=/dev/random,iommu=on")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
-                iommu: true,
+            CheckpointConfig::parse(
+                "destination_url=file:///tmp/checkpoint,overlay_dir=/tmp/overlays,\
+                 bandwidth=100,passes=2,quiesce=on"
+            )?,
+            CheckpointConfig {
+                destination_url: "file:///tmp/checkpoint".to_owned(),
+                overlay_dir: Some(PathBuf::from("/tmp/overlays")),
+                bandwidth: Some(100),
+                passes: 2,
+                quiesce: true,
             }
         );
-        assert_eq!(
-            RngConfig::parse("iommu=on")?,
-            RngConfig {
-                iommu: true,
-                ..Default::default()
-            }
+        assert!(CheckpointConfig::parse("overlay_dir=/tmp/overlays").is_err());
+        assert!(
+            CheckpointConfig::parse("destination_url=file:///tmp/checkpoint,passes=a").is_err()
         );
         Ok(())
     }
 
     #[test]
-    fn test_parse_fs() -> Result<()> {
-        // "tag" and "socket" must be supplied
-        assert!(FsConfig::parse("").is_err());
-        assert!(FsConfig::parse("tag=mytag").is_err());
-        assert!(FsConfig::parse("socket=/tmp/sock").is_err());
-        assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                ..Default::default()
-            }
-        );
+    fn test_cgroup_parsing() -> Result<()> {
+        assert_eq!(CgroupConfig::parse("")?, CgroupConfig::default());
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
+            CgroupConfig::parse("path=/machine.slice/vm0,memory_high=2G")?,
+            CgroupConfig {
+                path: Some("/machine.slice/vm0".to_owned()),
+                memory_high: Some(2 << 30),
                 ..Default::default()
             }
         );
+        let config = CgroupConfig::parse("io_device=/dev/sda,io_rbps=1048576,io_wiops=100")?;
+        assert_eq!(config.io_device, Some(PathBuf::from("/dev/sda")));
+        assert_eq!(config.io_limits(), vec![("rbps", 1048576), ("wiops", 100)]);
+        assert!(config.validate().is_ok());
+
+        let config = CgroupConfig::parse("io_wbps=1048576")?;
+        assert!(config.validate().is_err());
+        assert!(CgroupConfig::parse("memory_high=lots").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_numa_parsing() -> Result<()> {
+        let numa = NumaConfig::parse(
+            "guest_numa_id=1,cpus=[0-2,9],distances=[0@20,2@25],memory_zones=[mem0,mem1]",
+        )?;
+        assert_eq!(numa.guest_numa_id, 1);
+        assert_eq!(numa.cpus, Some(vec![0, 1, 2, 9]));
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock,num_queues=4,queue_size=1024")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                num_queues: 4,
-                queue_size: 1024,
-                ..Default::default()
-            }
+            numa.distances,
+            Some(vec![
+                NumaDistance {
+                    destination: 0,
+                    distance: 20,
+                },
+                NumaDistance {
+                    destination: 2,
+                    distance: 25,
+                },
+            ])
         );
-        // DAX on -> default cache size
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=on")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                dax: true,
-                cache_size: default_fsconfig_cache_size(),
-                ..Default::default()
-            }
+            numa.memory_zones,
+            Some(vec!["mem0".to_owned(), "mem1".to_owned()])
         );
+
+        // The lists separated with colons are still accepted.
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=on,cache_size=4G")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                dax: true,
-                cache_size: 4 << 30,
-                ..Default::default()
-            }
+            NumaConfig::parse(
+                "guest_numa_id=1,cpus=0-2:9,distances=0@20:2@25,memory_zones=mem0:mem1"
+            )?,
+            numa
         );
-        // Cache size without DAX is an error
-        assert!(FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=off,cache_size=4G").is_err());
+        assert!(NumaConfig::parse("cpus=[0,a]").is_err());
         Ok(())
     }
 
     #[test]
-    fn test_pmem_parsing() -> Result<()> {
-        // Must always give a file and size
-        assert!(PmemConfig::parse("").is_err());
-        assert!(PmemConfig::parse("size=128M").is_err());
+    fn test_numa_policy_parsing() {
+        assert_eq!("auto".parse::<NumaPolicy>().unwrap(), NumaPolicy::Auto);
+        assert_eq!("Manual".parse::<NumaPolicy>().unwrap(), NumaPolicy::Manual);
+        assert!("interleave".parse::<NumaPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_worker_failure_policy_parsing() {
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M")?,
-            PmemConfig {
-                file: PathBuf::from("/tmp/pmem"),
-                size: Some(128 << 20),
-                ..Default::default()
-            }
+            "pause".parse::<WorkerFailurePolicy>().unwrap(),
+            WorkerFailurePolicy::Pause
         );
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M,id=mypmem0")?,
-            PmemConfig {
-                file: PathBuf::from("/tmp/pmem"),
-                size: Some(128 << 20),
-                id: Some("mypmem0".to_owned()),
-                ..Default::default()
-            }
+            "Reset".parse::<WorkerFailurePolicy>().unwrap(),
+            WorkerFailurePolicy::Reset
         );
+        assert_eq!(WorkerFailurePolicy::default(), WorkerFailurePolicy::Ignore);
+        assert!("restart".parse::<WorkerFailurePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_hooks_parsing() -> Result<()> {
+        assert_eq!(HooksConfig::parse("")?, HooksConfig::default());
+        assert!(HooksConfig::parse("started=/bin/true").is_err());
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,mergeable=on,discard_writes=on")?,
-            PmemConfig {
-                file: PathBuf::from("/tmp/pmem"),
-                size: Some(128 << 20),
-                mergeable: true,
-                discard_writes: true,
-                iommu: true,
+            HooksConfig::parse("booted=/etc/ch/up,stopped=/etc/ch/down")?,
+            HooksConfig {
+                booted: Some(PathBuf::from("/etc/ch/up")),
+                stopped: Some(PathBuf::from("/etc/ch/down")),
                 ..Default::default()
             }
         );
-
         Ok(())
     }
 
     #[test]
-    fn test_console_parsing() -> Result<()> {
-        assert!(ConsoleConfig::parse("").is_err());
-        assert!(ConsoleConfig::parse("badmode").is_err());
+    fn test_sigterm_parsing() -> Result<()> {
+        assert_eq!(SigtermConfig::parse("")?, SigtermConfig::default());
+        assert_eq!(SigtermConfig::parse("exit")?, SigtermConfig::default());
         assert_eq!(
-            ConsoleConfig::parse("off")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Off,
-                iommu: false,
-                file: None,
+            SigtermConfig::parse("power-button")?,
+            SigtermConfig {
+                action: SigtermAction::PowerButton,
+                grace_period: DEFAULT_SIGTERM_GRACE_PERIOD,
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("pty")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Pty,
-                iommu: false,
-                file: None,
+            SigtermConfig::parse("action=power-button,grace_period=120")?,
+            SigtermConfig {
+                action: SigtermAction::PowerButton,
+                grace_period: 120,
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("tty")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Tty,
-                iommu: false,
-                file: None,
-            }
+            SigtermConfig::parse("power-button,grace_period=120")?,
+            SigtermConfig::parse("action=power-button,grace_period=120")?
         );
+        assert!(SigtermConfig::parse("reboot").is_err());
+        assert!(SigtermConfig::parse("action=exit,grace_period=-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_pressure_parsing() -> Result<()> {
         assert_eq!(
-            ConsoleConfig::parse("null")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Null,
-                iommu: false,
-                file: None,
-            }
+            MemoryPressureConfig::parse("")?,
+            MemoryPressureConfig::default()
         );
         assert_eq!(
-            ConsoleConfig::parse("file=/tmp/console")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::File,
-                iommu: false,
-                file: Some(PathBuf::from("/tmp/console"))
+            MemoryPressureConfig::parse("threshold=20.5,interval=500,step=128M")?,
+            MemoryPressureConfig {
+                threshold: 20.5,
+                interval: 500,
+                step: 128 << 20,
             }
         );
+        assert!(MemoryPressureConfig::parse("threshold=high").is_err());
+        assert!(MemoryPressureConfig::parse("period=10").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_api_vsock_parsing() -> Result<()> {
         assert_eq!(
-            ConsoleConfig::parse("null,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Null,
-                iommu: true,
-                file: None,
+            ApiVsockConfig::parse("port=1234,allowed_cids=[3,4]")?,
+            ApiVsockConfig {
+                port: 1234,
+                allowed_cids: vec![3, 4],
             }
         );
         assert_eq!(
-            ConsoleConfig::parse("file=/tmp/console,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::File,
-                iommu: true,
-                file: Some(PathBuf::from("/tmp/console"))
-            }
+            ApiVsockConfig::parse("port=1234,allowed_cids=[5]")?.allowed_cids,
+            vec![5]
         );
+        assert!(ApiVsockConfig::parse("port=1234").is_err());
+        assert!(ApiVsockConfig::parse("allowed_cids=[3]").is_err());
+        assert!(ApiVsockConfig::parse("port=1234,allowed_cids=[]").is_err());
         Ok(())
     }
 
     #[test]
-    fn test_device_parsing() -> Result<()> {
-        // Device must have a path provided
-        assert!(DeviceConfig::parse("").is_err());
+    fn test_pci_subsystem_parsing() -> Result<()> {
+        // id, vendor and device are required
+        assert!(PciSubsystemConfig::parse("").is_err());
+        assert!(PciSubsystemConfig::parse("id=_net0,vendor=0x8086").is_err());
+        assert!(PciSubsystemConfig::parse("vendor=0x8086,device=0x1234").is_err());
+        assert!(PciSubsystemConfig::parse("id=_net0,vendor=0x18086,device=0x1234").is_err());
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device")?,
-            DeviceConfig {
-                path: PathBuf::from("/path/to/device"),
-                id: None,
-                iommu: false
+            PciSubsystemConfig::parse("id=_net0,vendor=0x8086,device=1af4")?,
+            PciSubsystemConfig {
+                id: "_net0".to_owned(),
+                vendor: 0x8086,
+                device: 0x1af4,
             }
         );
+        Ok(())
+    }
 
+    #[test]
+    fn test_virtio_features_parsing() -> Result<()> {
+        // id is required
+        assert!(VirtioFeaturesConfig::parse("features_off=28").is_err());
+        assert!(VirtioFeaturesConfig::parse("id=_disk0,features_off=256").is_err());
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device,iommu=on")?,
-            DeviceConfig {
-                path: PathBuf::from("/path/to/device"),
-                id: None,
-                iommu: true
+            VirtioFeaturesConfig::parse("id=_disk0,features_off=28:29")?,
+            VirtioFeaturesConfig {
+                id: "_disk0".to_owned(),
+                features_off: vec![28, 29],
+                features_on: None,
             }
         );
-
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device,iommu=on,id=mydevice0")?,
-            DeviceConfig {
-                path: PathBuf::from("/path/to/device"),
-                id: Some("mydevice0".to_owned()),
-                iommu: true
+            VirtioFeaturesConfig::parse("id=_net0,features_on=0-5")?,
+            VirtioFeaturesConfig {
+                id: "_net0".to_owned(),
+                features_off: vec![],
+                features_on: Some(vec![0, 1, 2, 3, 4, 5]),
             }
         );
-
         Ok(())
     }
 
     #[test]
-    fn test_vsock_parsing() -> Result<()> {
-        // socket and cid is required
-        assert!(VsockConfig::parse("").is_err());
+    fn test_virtio_features_mask() -> Result<()> {
         assert_eq!(
-            VsockConfig::parse("socket=/tmp/sock,cid=1")?,
-            VsockConfig {
-                cid: 1,
-                socket: PathBuf::from("/tmp/sock"),
-                iommu: false,
-                id: None,
-            }
+            VirtioFeaturesConfig::parse("id=_disk0,features_off=28:29")?.mask(),
+            !(3 << 28)
         );
+        // The mandatory features are kept.
         assert_eq!(
-            VsockConfig::parse("socket=/tmp/sock,cid=1,iommu=on")?,
-            VsockConfig {
-                cid: 1,
-                socket: PathBuf::from("/tmp/sock"),
-                iommu: true,
-                id: None,
-            }
+            VirtioFeaturesConfig::parse("id=_net0,features_on=0:5")?.mask(),
+            (3 << 32) | (1 << 5) | 1
         );
         Ok(())
     }
@@ -2597,6 +6326,7 @@ mod tests {
                 hugepages: false,
                 hugepage_size: None,
                 zones: None,
+                cold_page_offload: None,
             },
             kernel: Some(KernelConfig {
                 path: PathBuf::from("/path/to/kernel"),
@@ -2609,7 +6339,7 @@ mod tests {
             net: None,
             rng: RngConfig {
                 src: PathBuf::from("/dev/urandom"),
-                iommu: false,
+                iommu: IommuMode::Off,
             },
             balloon: None,
             fs: None,
@@ -2617,12 +6347,16 @@ mod tests {
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
-                iommu: false,
+                iommu: IommuMode::Off,
+                log_file: None,
+                coalesce: false,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
-                iommu: false,
+                iommu: IommuMode::Off,
+                log_file: None,
+                coalesce: false,
             },
             devices: None,
             vsock: None,
@@ -2630,7 +6364,20 @@ mod tests {
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
             numa: None,
+            numa_policy: NumaPolicy::Manual,
+            on_worker_failure: WorkerFailurePolicy::Ignore,
+            pci_subsystems: None,
+            virtio_features: None,
             watchdog: false,
+            shared_event_loop: false,
+            suspend: None,
+            power_supply: None,
+            cgroup: None,
+            platform: None,
+            input_log: None,
+            usb: None,
+            tpm: None,
+            identity: IdentityConfig::default(),
             #[cfg(feature = "tdx")]
             tdx: None,
         };
@@ -2643,62 +6390,320 @@ mod tests {
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.kernel = None;
+        invalid_config.kernel = None;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::File;
+        invalid_config.serial.file = None;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Api;
+        invalid_config.console.mode = ConsoleOutputMode::Api;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.log_file = Some(PathBuf::from("/tmp/serial"));
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.console.log_file = Some(PathBuf::from("/tmp/console"));
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 32;
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.quota = Some(50_000);
+        assert!(still_valid_config.validate().is_ok());
+        still_valid_config.cpus.period = Some(1_000_000);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.quota = Some(500);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.quota = Some(50_000);
+        invalid_config.cpus.period = Some(2_000_000);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.period = Some(100_000);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: invalid_config.cpus.max_vcpus,
+            host_cpus: vec![0],
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidVcpu(_))
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 0,
+            host_cpus: vec![0],
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 0,
+            host_cpus: vec![0, libc::CPU_SETSIZE as usize],
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidHostCpu(_))
+        ));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.boot_vcpus = 2;
+            still_valid_config.cpus.max_vcpus = 2;
+            still_valid_config.cpus.core_types = CpuCoreType::parse_list("0@core:1@atom");
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config;
+            invalid_config.cpus.core_types = CpuCoreType::parse_list("1@atom");
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::CpuCoreTypeMissing(0))
+            ));
+
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.ptp_kvm = Some(true);
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.kvmclock = false;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::PtpKvmWithoutKvmClock)
+            ));
+
+            let mut invalid_config = still_valid_config;
+            invalid_config.cpus.kvm_hyperv = true;
+            assert!(matches!(
+                invalid_config.validate(),
+                Err(ValidationError::PtpKvmWithoutKvmClock)
+            ));
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 16;
+        invalid_config.cpus.topology = Some(CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 8,
+            dies_per_package: 1,
+            packages: 2,
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            queue_size: 100,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            queue_size: 0,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            queue_size: 32768,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.vsock = Some(VsockConfig {
+            queue_size: 65535,
+            ..Default::default()
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            overlay: Some(PathBuf::from("/path/to/overlay")),
+            readonly: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            backing_overlays: vec![PathBuf::from("/path/to/backing")],
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            overlay: Some(PathBuf::from("/path/to/overlay")),
+            backing_overlays: vec![PathBuf::from("/path/to/backing")],
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("nbd+unix:///disk0?socket=/tmp/nbd.sock")),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("nbd://storage.local/disk0")),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("rbd:disk0")),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("rbd:pool0/disk0")),
+            overlay: Some(PathBuf::from("/path/to/overlay")),
+            ..Default::default()
+        }]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.serial.mode = ConsoleOutputMode::File;
-        invalid_config.serial.file = None;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            model: DiskModel::Nvme,
+            iommu: IommuMode::On,
+            ..Default::default()
+        }]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 32;
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            serial: Some("0123456789abcdefghijk".to_owned()),
+            ..Default::default()
+        }]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 16;
-        invalid_config.cpus.topology = Some(CpuTopology {
-            threads_per_core: 2,
-            cores_per_die: 8,
-            dies_per_package: 1,
-            packages: 2,
-        });
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            model: DiskModel::Nvme,
+            serial: Some("DISK0".to_owned()),
+            ..Default::default()
+        }]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
-            vhost_socket: Some("/path/to/sock".to_owned()),
             path: Some(PathBuf::from("/path/to/image")),
+            model: DiskModel::Nvme,
+            poll_us: 50,
             ..Default::default()
         }]);
         assert!(invalid_config.validate().is_err());
 
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
+            path: Some(PathBuf::from("/path/to/image")),
+            poll_us: MAX_DISK_POLL_US + 1,
             ..Default::default()
         }]);
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            workers: MAX_DISK_WORKERS,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            workers: MAX_DISK_WORKERS + 1,
             ..Default::default()
         }]);
         assert!(invalid_config.validate().is_err());
 
         let mut still_valid_config = valid_config.clone();
         still_valid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            fd: Some(5),
             ..Default::default()
         }]);
-        still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            fd: Some(5),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            fd: Some(2),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.net = Some(vec![NetConfig {
             vhost_user: true,
@@ -2722,6 +6727,20 @@ mod tests {
         }]);
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            mtu: Some(MIN_NET_MTU - 1),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            mtu: Some(9000),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.fs = Some(vec![FsConfig {
             ..Default::default()
@@ -2732,6 +6751,149 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pci_subsystems = Some(vec![
+            PciSubsystemConfig {
+                id: "_net0".to_owned(),
+                vendor: 0x8086,
+                device: 0x1234,
+            },
+            PciSubsystemConfig {
+                id: "_net0".to_owned(),
+                vendor: 0x8086,
+                device: 0x5678,
+            },
+        ]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pci_subsystems = Some(vec![PciSubsystemConfig {
+            id: "_net0".to_owned(),
+            vendor: 0xffff,
+            device: 0x1234,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.pci_subsystems = Some(vec![PciSubsystemConfig {
+            id: "_net0".to_owned(),
+            vendor: 0x8086,
+            device: 0x1234,
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.virtio_features = Some(vec![
+            VirtioFeaturesConfig {
+                id: "_disk0".to_owned(),
+                features_off: vec![28],
+                features_on: None,
+            },
+            VirtioFeaturesConfig {
+                id: "_disk0".to_owned(),
+                features_off: vec![29],
+                features_on: None,
+            },
+        ]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.virtio_features = Some(vec![VirtioFeaturesConfig {
+            id: "_disk0".to_owned(),
+            features_off: vec![64],
+            features_on: None,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.virtio_features = Some(vec![VirtioFeaturesConfig {
+            id: "_disk0".to_owned(),
+            features_off: vec![32],
+            features_on: None,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.virtio_features = Some(vec![VirtioFeaturesConfig {
+            id: "_disk0".to_owned(),
+            features_off: vec![28],
+            features_on: Some(vec![28, 29]),
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.virtio_features = Some(vec![VirtioFeaturesConfig {
+            id: "_disk0".to_owned(),
+            features_off: vec![28],
+            features_on: Some(vec![29]),
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let usb_config = |path: &str| UsbConfig {
+            path: PathBuf::from(path),
+            id: None,
+        };
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.usb = Some(vec![
+            usb_config("/dev/bus/usb/001/004"),
+            usb_config("/dev/bus/usb/001/004"),
+        ]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.usb = Some(
+            (0..=MAX_USB_DEVICES)
+                .map(|i| usb_config(&format!("/dev/bus/usb/001/{:03}", i + 2)))
+                .collect(),
+        );
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.identity.uuid = Some("not-a-uuid".to_owned());
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config
+            .identity
+            .metadata
+            .insert("owner".to_owned(), "al\0ice".to_owned());
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.identity = IdentityConfig {
+            uuid: Some("6a6f6e2a-8e3c-4bd2-9b0e-0d4c1f6e0a2b".to_owned()),
+            serial_number: Some("vm-0042".to_owned()),
+            created: Some(1_634_000_000),
+            metadata: vec![("owner".to_owned(), "alice".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.tpm = Some(TpmConfig {
+            socket: None,
+            device: None,
+            ctrl: None,
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.tpm = Some(TpmConfig {
+            socket: None,
+            device: Some(PathBuf::from("/dev/tpmrm0")),
+            ctrl: Some(PathBuf::from("/tmp/swtpm.ctrl")),
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.usb = Some(vec![
+            usb_config("/dev/bus/usb/001/004"),
+            usb_config("/dev/bus/usb/002/002"),
+        ]);
+        assert!(still_valid_config.validate().is_ok());
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.memory.hugepages = true;
         assert!(still_valid_config.validate().is_ok());
@@ -2746,9 +6908,336 @@ mod tests {
         invalid_config.memory.hugepage_size = Some(2 << 20);
         assert!(invalid_config.validate().is_err());
 
-        let mut invalid_config = valid_config;
+        let mut invalid_config = valid_config.clone();
         invalid_config.memory.hugepages = true;
         invalid_config.memory.hugepage_size = Some(3 << 20);
         assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            acpi: false,
+            ..Default::default()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            acpi: false,
+            ..Default::default()
+        });
+        invalid_config.cpus.max_vcpus = 2;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            acpi: false,
+            ..Default::default()
+        });
+        invalid_config.memory.hotplug_size = Some(1 << 30);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            acpi: false,
+            ..Default::default()
+        });
+        invalid_config.iommu = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            acpi: false,
+            ..Default::default()
+        });
+        invalid_config.tpm = Some(TpmConfig {
+            socket: Some(PathBuf::from("/tmp/swtpm.sock")),
+            device: None,
+            ctrl: None,
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.serial.coalesce = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console.coalesce = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/path/to/device"),
+            id: None,
+            iommu: IommuMode::Bypass,
+            all_functions: false,
+            managed: false,
+            failover: None,
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.iommu = true;
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: PathBuf::from("/sys/bus/pci/devices/0000:01:00.0/"),
+            id: None,
+            iommu: IommuMode::On,
+            all_functions: true,
+            managed: false,
+            failover: None,
+        }]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VfioAllFunctionsIommu(_))
+        ));
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            id: Some("net0".to_owned()),
+            standby: true,
+            ..Default::default()
+        }]);
+        still_valid_config.devices = Some(vec![DeviceConfig::parse(
+            "path=/sys/bus/pci/devices/0000:01:00.1/,failover=net0",
+        )
+        .unwrap()]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig::parse(
+            "path=/sys/bus/pci/devices/0000:01:00.1/,failover=net1",
+        )
+        .unwrap()]);
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VfioFailoverNetMissing(_))
+        ));
+
+        let mut invalid_config = still_valid_config;
+        invalid_config.net.as_mut().unwrap()[0].vhost_user = true;
+        invalid_config.net.as_mut().unwrap()[0].vhost_socket = Some("/path/to/sock".to_owned());
+        invalid_config.memory.shared = true;
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VnetStandbyVhostUser)
+        ));
+
+        invalid_config.net.as_mut().unwrap()[0].standby = false;
+        invalid_config.net.as_mut().unwrap()[0].rx_filter = Some(PathBuf::from("/tmp/filter"));
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VnetRxFilterVhostUser)
+        ));
+
+        invalid_config.net.as_mut().unwrap()[0].vhost_user = false;
+        invalid_config.net.as_mut().unwrap()[0].rx_filter_ebpf = Some(PathBuf::from("/tmp/ebpf"));
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::VnetRxFilterConflict)
+        ));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                iommu: IommuType::Vtd,
+                ..Default::default()
+            });
+            still_valid_config.rng.iommu = IommuMode::On;
+            still_valid_config.iommu = true;
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.rng.iommu = IommuMode::Bypass;
+            assert!(invalid_config.validate().is_err());
+
+            let mut invalid_config = still_valid_config;
+            invalid_config.devices = Some(vec![DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: IommuMode::On,
+                all_functions: false,
+                managed: false,
+                failover: None,
+            }]);
+            assert!(invalid_config.validate().is_err());
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hugepages = true;
+        invalid_config.memory.hugepage_size = Some(1 << 30);
+        invalid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: false,
+            free_page_reporting: true,
+            id: None,
+            pressure_max: 0,
+            pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+        });
+        assert!(invalid_config.validate().is_err());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hugepages = true;
+        still_valid_config.memory.hugepage_size = Some(2 << 20);
+        still_valid_config.balloon = Some(BalloonConfig {
+            size: 0,
+            deflate_on_oom: false,
+            free_page_reporting: true,
+            id: None,
+            pressure_max: 0,
+            pressure_priority: DEFAULT_BALLOON_PRESSURE_PRIORITY,
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.cold_page_offload = Some(60);
+        assert!(still_valid_config.validate().is_ok());
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.memory.cold_page_offload = Some(0);
+        assert!(invalid_config.validate().is_err());
+        let mut invalid_config = still_valid_config;
+        invalid_config.memory.hugepages = true;
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            bounce_buffer_size: Some(64 << 20),
+            ..Default::default()
+        });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::BounceBufferNotConfidential)
+        ));
+        #[cfg(feature = "tdx")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.kernel = None;
+            still_valid_config.tdx = Some(TdxConfig {
+                firmware: PathBuf::from("/path/to/firmware"),
+            });
+            still_valid_config.platform = Some(PlatformConfig {
+                bounce_buffer_size: Some(64 << 20),
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+        }
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            bounce_buffer_size: Some(3 << 20),
+            ..Default::default()
+        });
+        assert!(matches!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidBounceBufferSize(_))
+        ));
+
+        let mut still_valid_config = valid_config;
+        still_valid_config.platform = Some(PlatformConfig {
+            acpi: false,
+            ..Default::default()
+        });
+        still_valid_config.memory.hotplug_method = HotplugMethod::VirtioMem;
+        still_valid_config.memory.hotplug_size = Some(1 << 30);
+        assert!(still_valid_config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_config_file() -> Result<()> {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let json = tmp_dir.as_path().join("vm.json");
+        std::fs::write(
+            &json,
+            r#"{"kernel": {"path": "/path/to/kernel"}, "cpus": {"boot_vcpus": 2, "max_vcpus": 4}}"#,
+        )
+        .unwrap();
+        let toml = tmp_dir.as_path().join("vm.toml");
+        std::fs::write(
+            &toml,
+            "[kernel]\npath = \"/path/to/kernel\"\n\n[cpus]\nboot_vcpus = 2\nmax_vcpus = 4\n",
+        )
+        .unwrap();
+
+        let config = VmConfig::from_file(&json)?;
+        assert_eq!(config, VmConfig::from_file(&toml)?);
+        assert_eq!(
+            config.kernel.as_ref().unwrap().path,
+            PathBuf::from("/path/to/kernel")
+        );
+        assert_eq!(config.cpus.max_vcpus, 4);
+        assert!(config.validate().is_ok());
+
+        // A TOML file isn't parsed as JSON.
+        let wrong_extension = tmp_dir.as_path().join("vm.conf");
+        std::fs::copy(&toml, &wrong_extension).unwrap();
+        assert!(VmConfig::from_file(&wrong_extension).is_err());
+        assert!(VmConfig::from_file(&tmp_dir.as_path().join("missing.json")).is_err());
+
+        // Only the options given on the command line replace the settings of
+        // the file, the defaulted ones being left alone.
+        let cli = VmConfig::from_params(VmParams {
+            cpus: "boot=1",
+            memory: "size=1G",
+            memory_zones: None,
+            kernel: None,
+            initramfs: None,
+            cmdline: None,
+            disks: Some(vec!["path=/path/to/disk"]),
+            net: None,
+            rng: "src=/dev/urandom",
+            balloon: None,
+            fs: None,
+            pmem: None,
+            serial: "null",
+            console: "tty",
+            devices: None,
+            vsock: None,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc: None,
+            numa: None,
+            numa_policy: None,
+            on_worker_failure: None,
+            pci_subsystems: None,
+            virtio_features: None,
+            watchdog: false,
+            shared_event_loop: false,
+            suspend: None,
+            power_supply: None,
+            cgroup: None,
+            platform: None,
+            input_log: None,
+            usb: None,
+            tpm: None,
+            identity: None,
+            #[cfg(feature = "tdx")]
+            tdx: None,
+        })?;
+        let mut overridden = config.clone();
+        overridden.override_with(cli.clone(), |name| name == "disk");
+        assert_eq!(overridden.cpus, config.cpus);
+        assert_eq!(overridden.memory, config.memory);
+        assert_eq!(overridden.disks, cli.disks);
+
+        overridden.override_with(cli.clone(), |name| name == "cpus");
+        assert_eq!(overridden.cpus, cli.cpus);
+        assert_eq!(overridden.kernel, config.kernel);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_device() -> Result<()> {
+        let mut config: VmConfig =
+            serde_json::from_str(r#"{"kernel": {"path": "/path/to/kernel"}}"#).unwrap();
+        config.disks = Some(vec![
+            DiskConfig::parse("path=/path/to_file,id=disk0")?,
+            DiskConfig::parse("path=/path/to_other_file")?,
+        ]);
+        config.vsock = Some(VsockConfig::parse("socket=/tmp/sock,cid=3,id=vsock0")?);
+
+        assert!(config.remove_device("disk0"));
+        assert_eq!(config.disks.as_ref().unwrap().len(), 1);
+        assert!(!config.remove_device("disk0"));
+
+        assert!(config.remove_device("vsock0"));
+        assert!(config.vsock.is_none());
+        Ok(())
     }
 }
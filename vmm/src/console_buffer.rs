@@ -0,0 +1,145 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Output of a console driven through the `vm.console` API endpoint.
+//!
+//! The API clients can't be handed the output as it comes, since each of
+//! their requests is answered once. The latest output is kept instead, and
+//! each client picks it up from where its previous request left it. Rather
+//! than polling, a request can wait for output to pick up, being woken up as
+//! soon as the console writes some.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// How much of the latest output is kept.
+const CONSOLE_BUFFER_SIZE: usize = 64 << 10;
+
+#[derive(Default)]
+struct Output {
+    data: VecDeque<u8>,
+    // Count of bytes written to the console since it was created, which is
+    // the offset the next byte is written at.
+    end: u64,
+}
+
+impl Output {
+    fn read(&self, offset: u64) -> (Vec<u8>, u64) {
+        let start = self.end - self.data.len() as u64;
+        let skip = offset.max(start).min(output.end) - start;
+
+        (
+            self.data.iter().skip(skip as usize).copied().collect(),
+            self.end,
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ConsoleBuffer {
+    output: Arc<(Mutex<Output>, Condvar)>,
+}
+
+impl ConsoleBuffer {
+    /// Returns the output written from `offset` on, along with the offset to
+    /// read the following output from. The output older than what the buffer
+    /// keeps is lost, it starts with the oldest output kept then.
+    pub fn read(&self, offset: u64) -> (Vec<u8>, u64) {
+        self.output.0.lock().unwrap().read(offset)
+    }
+
+    /// Same as `read()`, but waits up to `timeout` for output to be written
+    /// from `offset` on if there's none yet.
+    pub fn wait(&self, offset: u64, timeout: Duration) -> (Vec<u8>, u64) {
+        let (lock, written) = &*self.output;
+        let deadline = Instant::now() + timeout;
+        let mut output = lock.lock().unwrap();
+        while output.end <= offset {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            output = written.wait_timeout(output, deadline - now).unwrap().0;
+        }
+
+        output.read(offset)
+    }
+}
+
+impl io::Write for ConsoleBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (lock, written) = &*self.output;
+        let mut output = lock.lock().unwrap();
+        let kept = &buf[buf.len().saturating_sub(CONSOLE_BUFFER_SIZE)..];
+        let overflow = (output.data.len() + kept.len()).saturating_sub(CONSOLE_BUFFER_SIZE);
+        output.data.drain(..overflow);
+        output.data.extend(kept);
+        output.end += buf.len() as u64;
+        written.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_console_buffer() {
+        let mut buffer = ConsoleBuffer::default();
+        assert_eq!(buffer.read(0), (vec![], 0));
+
+        buffer.write_all(b"login: ").unwrap();
+        assert_eq!(buffer.read(0), (b"login: ".to_vec(), 7));
+        assert_eq!(buffer.read(5), (b": ".to_vec(), 7));
+        // An offset past the output is brought back to its end.
+        assert_eq!(buffer.read(10), (vec![], 7));
+
+        buffer.write_all(b"root\r\n").unwrap();
+        assert_eq!(buffer.read(7), (b"root\r\n".to_vec(), 13));
+
+        // The oldest output gets lost.
+        buffer.write_all(&vec![b'x'; CONSOLE_BUFFER_SIZE]).unwrap();
+        let (output, end) = buffer.read(0);
+        assert_eq!(output, vec![b'x'; CONSOLE_BUFFER_SIZE]);
+        assert_eq!(end, 13 + CONSOLE_BUFFER_SIZE as u64);
+
+        buffer.write_all(b"$ ").unwrap();
+        assert_eq!(buffer.read(end), (b"$ ".to_vec(), end + 2));
+    }
+
+    #[test]
+    fn test_console_buffer_wait() {
+        let mut buffer = ConsoleBuffer::default();
+        buffer.write_all(b"login: ").unwrap();
+
+        // The output already written is returned right away.
+        assert_eq!(
+            buffer.wait(0, Duration::from_secs(60)),
+            (b"login: ".to_vec(), 7)
+        );
+        // Nothing gets written.
+        assert_eq!(buffer.wait(7, Duration::from_millis(10)), (vec![], 7));
+
+        let mut writer = buffer.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            writer.write_all(b"root").unwrap();
+        });
+        assert_eq!(
+            buffer.wait(7, Duration::from_secs(60)),
+            (b"root".to_vec(), 11)
+        );
+        thread.join().unwrap();
+    }
+}
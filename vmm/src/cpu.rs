@@ -11,11 +11,15 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::cgroup::{gettid, Cgroup};
 #[cfg(target_arch = "x86_64")]
+use crate::config::CoreType;
 use crate::config::CpuTopology;
-use crate::config::CpusConfig;
+use crate::config::{CpusConfig, DEFAULT_CPUS_PERIOD};
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
+use crate::numa_placement::{set_thread_affinity, NumaPlacement};
+use crate::panic_dump;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 #[cfg(target_arch = "x86_64")]
 use crate::vm::physical_bits;
@@ -33,7 +37,7 @@ use arch::CpuidPatch;
 use arch::EntryPoint;
 use devices::interrupt_controller::InterruptController;
 #[cfg(target_arch = "aarch64")]
-use hypervisor::kvm::kvm_bindings;
+use hypervisor::kvm::{kvm_bindings, KVM_REG_ARM_VENDOR_HYP_BIT_PTP, KVM_REG_ARM_VENDOR_HYP_BMAP};
 use hypervisor::{vm::VmmOps, CpuState, HypervisorCpuError, VmExit};
 #[cfg(target_arch = "x86_64")]
 use hypervisor::{CpuId, CpuIdEntry};
@@ -42,7 +46,7 @@ use seccomp::{SeccompAction, SeccompFilter};
 #[cfg(feature = "acpi")]
 use std::collections::BTreeMap;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::{cmp, io, result, thread};
 use vm_device::BusDevice;
@@ -63,15 +67,23 @@ const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 #[cfg(target_arch = "x86_64")]
 const MTRR_EDX_BIT: u8 = 12; // Hypervisor ecx bit.
+#[cfg(target_arch = "x86_64")]
+const HYBRID_EDX_BIT: u8 = 15; // Hybrid CPU edx bit.
+
+// Core types reported by the CPUID leaf 0x1a
+#[cfg(target_arch = "x86_64")]
+const CORE_TYPE_ATOM: u32 = 0x20;
+#[cfg(target_arch = "x86_64")]
+const CORE_TYPE_CORE: u32 = 0x40;
 
 // KVM feature bits
 #[cfg(target_arch = "x86_64")]
 const KVM_FEATURE_ASYNC_PF_INT_BIT: u8 = 14;
-#[cfg(feature = "tdx")]
+#[cfg(target_arch = "x86_64")]
 const KVM_FEATURE_CLOCKSOURCE_BIT: u8 = 0;
-#[cfg(feature = "tdx")]
+#[cfg(target_arch = "x86_64")]
 const KVM_FEATURE_CLOCKSOURCE2_BIT: u8 = 3;
-#[cfg(feature = "tdx")]
+#[cfg(target_arch = "x86_64")]
 const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u8 = 24;
 #[cfg(feature = "tdx")]
 const KVM_FEATURE_ASYNC_PF_BIT: u8 = 4;
@@ -108,6 +120,22 @@ pub enum Error {
     /// Error doing vCPU init on Arm.
     VcpuArmInit(hypervisor::HypervisorCpuError),
 
+    #[cfg(target_arch = "aarch64")]
+    /// Error finalizing the vCPU features on Arm.
+    VcpuArmFinalize(hypervisor::HypervisorCpuError),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Error initializing the PMU on Arm.
+    VcpuArmPmu(hypervisor::HypervisorCpuError),
+
+    #[cfg(target_arch = "aarch64")]
+    /// The host doesn't support the requested vCPU feature.
+    VcpuArmFeatureUnsupported(&'static str),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Error selecting the KVM vendor hypervisor services on Arm.
+    VcpuArmHypServices(hypervisor::HypervisorCpuError),
+
     /// Failed to join on vCPU threads
     ThreadCleanup(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
 
@@ -123,6 +151,12 @@ pub enum Error {
     /// Cannot apply seccomp filter
     ApplySeccompFilter(seccomp::Error),
 
+    /// Cannot set the vCPUs cgroup up
+    VcpusCgroup(crate::cgroup::Error),
+
+    /// Cannot bind a vCPU thread to its host CPUs
+    VcpuAffinity(crate::numa_placement::Error),
+
     /// Error starting vCPU after restore
     StartRestoreVcpu(anyhow::Error),
 
@@ -144,6 +178,10 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     CpuidIdentification(vmm_sys_util::fam::Error),
 
+    /// Error populating CPUID with the hybrid core type
+    #[cfg(target_arch = "x86_64")]
+    CpuidCoreType(vmm_sys_util::fam::Error),
+
     #[cfg(feature = "tdx")]
     InitializeTdx(hypervisor::HypervisorCpuError),
 }
@@ -291,14 +329,16 @@ impl Vcpu {
     pub fn configure(
         &mut self,
         #[cfg(target_arch = "aarch64")] vm: &Arc<dyn hypervisor::Vm>,
+        #[cfg(target_arch = "aarch64")] config: &CpusConfig,
         kernel_entry_point: Option<EntryPoint>,
         vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
         #[cfg(target_arch = "x86_64")] cpuid: CpuId,
         #[cfg(target_arch = "x86_64")] kvm_hyperv: bool,
+        #[cfg(target_arch = "x86_64")] kvmclock: bool,
     ) -> Result<()> {
         #[cfg(target_arch = "aarch64")]
         {
-            self.init(vm)?;
+            self.init(vm, config)?;
             self.mpidr = arch::configure_vcpu(&self.vcpu, self.id, kernel_entry_point, vm_memory)
                 .map_err(Error::VcpuConfiguration)?;
         }
@@ -311,6 +351,7 @@ impl Vcpu {
             vm_memory,
             cpuid,
             kvm_hyperv,
+            kvmclock,
         )
         .map_err(Error::VcpuConfiguration)?;
 
@@ -331,7 +372,7 @@ impl Vcpu {
 
     /// Initializes an aarch64 specific vcpu for booting Linux.
     #[cfg(target_arch = "aarch64")]
-    pub fn init(&self, vm: &Arc<dyn hypervisor::Vm>) -> Result<()> {
+    pub fn init(&self, vm: &Arc<dyn hypervisor::Vm>, config: &CpusConfig) -> Result<()> {
         let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
 
         // This reads back the kernel's preferred target type.
@@ -343,7 +384,48 @@ impl Vcpu {
         if self.id > 0 {
             kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_POWER_OFF;
         }
-        self.vcpu.vcpu_init(&kvi).map_err(Error::VcpuArmInit)
+        // The optional features have been checked against the host when
+        // creating the CpuManager.
+        if config.pmu {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3;
+        }
+        if config.sve {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_SVE;
+        }
+        if config.ptrauth {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_ADDRESS;
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_GENERIC;
+        }
+        self.vcpu.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
+
+        // The SVE vector lengths are left to their default, which is every
+        // length supported by the host.
+        if config.sve {
+            self.vcpu
+                .vcpu_finalize(kvm_bindings::KVM_ARM_VCPU_SVE as i32)
+                .map_err(Error::VcpuArmFinalize)?;
+        }
+
+        // KVM offers PTP_KVM by default, which is left as it is unless turned
+        // on or off explicitly, the vendor hypervisor services bitmap being
+        // missing from the hosts predating it.
+        if let Some(ptp_kvm) = config.ptp_kvm {
+            let services = self
+                .vcpu
+                .get_reg(KVM_REG_ARM_VENDOR_HYP_BMAP)
+                .map_err(Error::VcpuArmHypServices)?;
+            let ptp = 1 << KVM_REG_ARM_VENDOR_HYP_BIT_PTP;
+            let services = if ptp_kvm {
+                services | ptp
+            } else {
+                services & !ptp
+            };
+            self.vcpu
+                .set_reg(KVM_REG_ARM_VENDOR_HYP_BMAP, services)
+                .map_err(Error::VcpuArmHypServices)?;
+        }
+
+        Ok(())
     }
 
     /// Runs the VCPU until it exits, returning the reason.
@@ -417,6 +499,10 @@ pub struct CpuManager {
     vcpus: Vec<Arc<Mutex<Vcpu>>>,
     seccomp_action: SeccompAction,
     vmmops: Arc<Box<dyn VmmOps>>,
+    // Throttles the CPU usage of the vCPU threads
+    vcpus_cgroup: Option<Arc<Cgroup>>,
+    // Host CPUs picked for the vCPU threads by the automatic NUMA placement
+    numa_placement: Option<NumaPlacement>,
     #[cfg(feature = "acpi")]
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     acpi_address: GuestAddress,
@@ -507,6 +593,7 @@ struct VcpuState {
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
+    exits: Arc<AtomicU64>,
 }
 
 impl VcpuState {
@@ -559,9 +646,13 @@ impl CpuManager {
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         seccomp_action: SeccompAction,
         vmmops: Arc<Box<dyn VmmOps>>,
+        numa_placement: Option<&NumaPlacement>,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         #[cfg(feature = "acpi")] numa_nodes: &NumaNodes,
     ) -> Result<Arc<Mutex<CpuManager>>> {
+        #[cfg(target_arch = "aarch64")]
+        CpuManager::check_arm_features(config, &vm)?;
+
         let guest_memory = memory_manager.lock().unwrap().guest_memory();
         let mut vcpu_states = Vec::with_capacity(usize::from(config.max_vcpus));
         vcpu_states.resize_with(usize::from(config.max_vcpus), VcpuState::default);
@@ -582,6 +673,7 @@ impl CpuManager {
                 sgx_epc_sections,
                 phys_bits,
                 config.kvm_hyperv,
+                config.kvmclock,
                 #[cfg(feature = "tdx")]
                 tdx_enabled,
             )?
@@ -609,6 +701,24 @@ impl CpuManager {
         .into_iter()
         .collect();
 
+        let vcpus_cgroup = if let Some(quota) = config.quota {
+            let period = config.period.unwrap_or(DEFAULT_CPUS_PERIOD);
+            let cgroup = Cgroup::current()
+                .and_then(|cgroup| {
+                    cgroup.create_threaded_child(
+                        &format!("cloud-hypervisor-{}-vcpus", std::process::id()),
+                        &["cpu"],
+                    )
+                })
+                .map_err(Error::VcpusCgroup)?;
+            cgroup
+                .write("cpu.max", &format!("{} {}", quota, period))
+                .map_err(Error::VcpusCgroup)?;
+            Some(Arc::new(cgroup))
+        } else {
+            None
+        };
+
         let cpu_manager = Arc::new(Mutex::new(CpuManager {
             config: config.clone(),
             interrupt_controller: device_manager.interrupt_controller().clone(),
@@ -625,6 +735,8 @@ impl CpuManager {
             vcpus: Vec::with_capacity(usize::from(config.max_vcpus)),
             seccomp_action,
             vmmops,
+            vcpus_cgroup,
+            numa_placement: numa_placement.cloned(),
             #[cfg(feature = "acpi")]
             acpi_address,
             #[cfg(feature = "acpi")]
@@ -638,6 +750,7 @@ impl CpuManager {
                 cpu_manager.clone(),
                 acpi_address.0,
                 CPU_MANAGER_ACPI_SIZE as u64,
+                "cpu_manager",
             )
             .map_err(Error::BusError)?;
 
@@ -651,6 +764,7 @@ impl CpuManager {
         sgx_epc_sections: Option<Vec<SgxEpcSection>>,
         phys_bits: u8,
         kvm_hyperv: bool,
+        kvmclock: bool,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
     ) -> Result<CpuId> {
         let cpuid_patches = vec![
@@ -722,6 +836,12 @@ impl CpuManager {
                 0x4000_0001 => {
                     entry.eax &= !(1 << KVM_FEATURE_ASYNC_PF_INT_BIT);
 
+                    if !kvmclock {
+                        entry.eax &= !(1 << KVM_FEATURE_CLOCKSOURCE_BIT
+                            | 1 << KVM_FEATURE_CLOCKSOURCE2_BIT
+                            | 1 << KVM_FEATURE_CLOCKSOURCE_STABLE_BIT)
+                    }
+
                     // These features are not supported by TDX
                     #[cfg(feature = "tdx")]
                     if tdx_enabled {
@@ -815,6 +935,46 @@ impl CpuManager {
         Ok(cpuid)
     }
 
+    // Reports the vCPU as a core of a hybrid CPU, through the hybrid bit of
+    // the leaf 0x7 and the core type of the leaf 0x1a.
+    #[cfg(target_arch = "x86_64")]
+    fn patch_cpuid_core_type(cpuid: &mut CpuId, core_type: CoreType) -> Result<()> {
+        CpuidPatch::patch_cpuid(
+            cpuid,
+            vec![CpuidPatch {
+                function: 7,
+                index: 0,
+                flags_bit: None,
+                eax_bit: None,
+                ebx_bit: None,
+                ecx_bit: None,
+                edx_bit: Some(HYBRID_EDX_BIT),
+            }],
+        );
+
+        let core_type = match core_type {
+            CoreType::Atom => CORE_TYPE_ATOM,
+            CoreType::Core => CORE_TYPE_CORE,
+        };
+        cpuid.retain(|c| c.function != 0x1a);
+        cpuid
+            .push(CpuIdEntry {
+                function: 0x1a,
+                eax: core_type << 24,
+                ..Default::default()
+            })
+            .map_err(Error::CpuidCoreType)?;
+
+        // The leaf 0x1a must be within the basic leaves.
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function == 0 && entry.eax < 0x1a {
+                entry.eax = 0x1a;
+            }
+        }
+
+        Ok(())
+    }
+
     fn create_vcpu(
         &mut self,
         cpu_id: u8,
@@ -828,7 +988,7 @@ impl CpuManager {
         if let Some(snapshot) = snapshot {
             // AArch64 vCPUs should be initialized after created.
             #[cfg(target_arch = "aarch64")]
-            vcpu.lock().unwrap().init(&self.vm)?;
+            vcpu.lock().unwrap().init(&self.vm, &self.config)?;
 
             vcpu.lock()
                 .unwrap()
@@ -837,21 +997,35 @@ impl CpuManager {
         } else {
             let vm_memory = self.vm_memory.clone();
 
+            #[cfg(target_arch = "x86_64")]
+            let mut cpuid = self.cpuid.clone();
+            #[cfg(target_arch = "x86_64")]
+            if let Some(core_type) = self
+                .config
+                .core_types
+                .iter()
+                .flatten()
+                .find(|c| c.vcpu == cpu_id)
+            {
+                CpuManager::patch_cpuid_core_type(&mut cpuid, core_type.core_type)?;
+            }
+
             #[cfg(target_arch = "x86_64")]
             vcpu.lock()
                 .unwrap()
                 .configure(
                     entry_point,
                     &vm_memory,
-                    self.cpuid.clone(),
+                    cpuid,
                     self.config.kvm_hyperv,
+                    self.config.kvmclock,
                 )
                 .expect("Failed to configure vCPU");
 
             #[cfg(target_arch = "aarch64")]
             vcpu.lock()
                 .unwrap()
-                .configure(&self.vm, entry_point, &vm_memory)
+                .configure(&self.vm, &self.config, entry_point, &vm_memory)
                 .expect("Failed to configure vCPU");
         }
 
@@ -899,6 +1073,7 @@ impl CpuManager {
         let vcpu_run_interrupted = self.vcpu_states[usize::from(cpu_id)]
             .vcpu_run_interrupted
             .clone();
+        let vcpu_exits = self.vcpu_states[usize::from(cpu_id)].exits.clone();
 
         info!("Starting vCPU: cpu_id = {}", cpu_id);
 
@@ -909,10 +1084,49 @@ impl CpuManager {
         #[cfg(target_arch = "x86_64")]
         let interrupt_controller_clone = self.interrupt_controller.as_ref().cloned();
 
+        let vcpus_cgroup = self.vcpus_cgroup.clone();
+        // The host CPUs given to the vCPU take precedence over the ones of
+        // the NUMA node its memory has been placed onto.
+        let vcpu_affinity = self
+            .config
+            .affinity
+            .iter()
+            .flatten()
+            .find(|a| a.vcpu == cpu_id)
+            .map(|a| a.host_cpus.as_slice())
+            .or_else(|| {
+                self.numa_placement
+                    .as_ref()
+                    .and_then(|numa_placement| numa_placement.vcpu_cpus(cpu_id))
+            })
+            .map(|cpus| cpus.to_vec());
+
         let handle = Some(
             thread::Builder::new()
                 .name(format!("vcpu{}", cpu_id))
                 .spawn(move || {
+                    // Bind the thread to its host CPUs.
+                    if let Some(cpus) = vcpu_affinity {
+                        if let Err(e) = set_thread_affinity(&cpus).map_err(Error::VcpuAffinity) {
+                            error!("Error binding vCPU thread to its host CPUs: {:?}", e);
+                            return;
+                        }
+                    }
+
+                    // Move the thread to the cgroup throttling its CPU usage,
+                    // which must happen before the seccomp filter is applied.
+                    // The cgroup isn't kept around by the thread, so that it
+                    // can be removed once the vCPUs are gone.
+                    if let Some(vcpus_cgroup) = vcpus_cgroup {
+                        if let Err(e) = vcpus_cgroup
+                            .add_thread(gettid())
+                            .map_err(Error::VcpusCgroup)
+                        {
+                            error!("Error moving vCPU thread to its cgroup: {:?}", e);
+                            return;
+                        }
+                    }
+
                     // Apply seccomp filter for vcpu thread.
                     if let Err(e) =
                         SeccompFilter::apply(vcpu_seccomp_filter).map_err(Error::ApplySeccompFilter)
@@ -958,8 +1172,11 @@ impl CpuManager {
                             break;
                         }
 
-                        // vcpu.run() returns false on a triple-fault so trigger a reset
-                        match vcpu.lock().unwrap().run() {
+                        // A triple fault exits with VmExit::Reset, like the
+                        // other reset requests, so trigger a reset
+                        let run = vcpu.lock().unwrap().run();
+                        vcpu_exits.fetch_add(1, Ordering::Relaxed);
+                        match run {
                             Ok(run) => match run {
                                 #[cfg(target_arch = "x86_64")]
                                 VmExit::IoapicEoi(vector) => {
@@ -1148,10 +1365,82 @@ impl CpuManager {
         Ok(())
     }
 
+    /// Lets the guest know its clock has been stopped, so that catching up
+    /// with the host time doesn't trigger its soft lockup detector.
+    #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+    pub fn notify_guest_clock_paused(&self) {
+        if self.config.kvm_hyperv || !self.config.kvmclock {
+            return;
+        }
+
+        for vcpu in self.vcpus.iter() {
+            // KVM rejects the notification unless the guest uses kvmclock.
+            if let Err(e) = vcpu.lock().unwrap().vcpu.notify_guest_clock_paused() {
+                debug!("Could not notify guest it has been paused: {:?}", e);
+            }
+        }
+    }
+
     pub fn boot_vcpus(&self) -> u8 {
         self.config.boot_vcpus
     }
 
+    /// Writes the registers of the vCPUs to the emergency dump. The vCPUs
+    /// are kicked out of the guest first, as their registers can't be read
+    /// while they run, and are left paused since the VMM is about to abort.
+    pub fn panic_dump(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        self.vcpus_pause_signalled.store(true, Ordering::SeqCst);
+        for state in self.vcpu_states.iter() {
+            // Unlike signal_thread(), this doesn't wait for the vCPU to
+            // acknowledge the signal, in case it is the one panicking.
+            if let Some(handle) = state.handle.as_ref() {
+                // Safe because the thread of the handle hasn't been joined.
+                unsafe {
+                    libc::pthread_kill(handle.as_pthread_t() as _, SIGRTMIN());
+                }
+            }
+        }
+
+        for (id, vcpu) in self.vcpus.iter().enumerate() {
+            let vcpu = match panic_dump::try_lock_for(vcpu, panic_dump::LOCK_TIMEOUT) {
+                Some(vcpu) => vcpu,
+                None => {
+                    writeln!(out, "vCPU {}: still running", id)?;
+                    continue;
+                }
+            };
+            #[cfg(target_arch = "x86_64")]
+            let regs = vcpu.vcpu.get_regs();
+            #[cfg(target_arch = "aarch64")]
+            let regs = {
+                let mut regs = kvm_bindings::kvm_regs::default();
+                vcpu.vcpu.core_registers(&mut regs).map(|_| regs)
+            };
+            match regs {
+                Ok(regs) => writeln!(out, "vCPU {} registers: {:?}", id, regs)?,
+                Err(e) => writeln!(out, "vCPU {}: {}", id, e)?,
+            }
+            #[cfg(target_arch = "x86_64")]
+            match vcpu.vcpu.get_sregs() {
+                Ok(sregs) => writeln!(out, "vCPU {} special registers: {:?}", id, sregs)?,
+                Err(e) => writeln!(out, "vCPU {}: {}", id, e)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of exits of each vCPU which has been started at
+    /// least once, indexed by vCPU id.
+    pub fn vcpu_exits(&self) -> Vec<(u8, u64)> {
+        self.vcpu_states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.active() || state.exits.load(Ordering::Relaxed) > 0)
+            .map(|(id, state)| (id as u8, state.exits.load(Ordering::Relaxed)))
+            .collect()
+    }
+
     pub fn max_vcpus(&self) -> u8 {
         self.config.max_vcpus
     }
@@ -1175,6 +1464,44 @@ impl CpuManager {
             .collect()
     }
 
+    #[cfg(target_arch = "aarch64")]
+    fn check_arm_features(config: &CpusConfig, vm: &Arc<dyn hypervisor::Vm>) -> Result<()> {
+        if config.pmu && !vm.check_extension(hypervisor::Cap::ArmPmuV3) {
+            return Err(Error::VcpuArmFeatureUnsupported("pmu"));
+        }
+        if config.sve && !vm.check_extension(hypervisor::Cap::ArmSve) {
+            return Err(Error::VcpuArmFeatureUnsupported("sve"));
+        }
+        if config.ptrauth
+            && !(vm.check_extension(hypervisor::Cap::ArmPtrAuthAddress)
+                && vm.check_extension(hypervisor::Cap::ArmPtrAuthGeneric))
+        {
+            return Err(Error::VcpuArmFeatureUnsupported("ptrauth"));
+        }
+
+        Ok(())
+    }
+
+    /// Initializes the PMU of every vCPU, if it has been enabled. This must
+    /// be done once the GIC has been created.
+    #[cfg(target_arch = "aarch64")]
+    pub fn init_pmu(&self) -> Result<()> {
+        if !self.config.pmu {
+            return Ok(());
+        }
+
+        let irq = arch::layout::PPI_BASE + arch::layout::PMU_PPI;
+        for vcpu in self.vcpus.iter() {
+            vcpu.lock()
+                .unwrap()
+                .vcpu
+                .init_pmu(irq)
+                .map_err(Error::VcpuArmPmu)?;
+        }
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "aarch64")]
     pub fn get_saved_states(&self) -> Vec<CpuState> {
         self.vcpus
@@ -1255,7 +1582,11 @@ impl CpuManager {
                     uid: cpu as u32,
                     flags: 1,
                     parking_version: 0,
-                    performance_interrupt: 0,
+                    performance_interrupt: if self.config.pmu {
+                        arch::layout::PPI_BASE + arch::layout::PMU_PPI
+                    } else {
+                        0
+                    },
                     parked_address: 0,
                     base_address: 0,
                     gicv_base_address: 0,
@@ -1649,7 +1980,8 @@ impl Pausable for CpuManager {
             let mut vcpu = vcpu.lock().unwrap();
             vcpu.pause()?;
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
-            if !self.config.kvm_hyperv {
+            // KVM rejects the notification unless the guest uses kvmclock.
+            if !self.config.kvm_hyperv && self.config.kvmclock {
                 vcpu.vcpu.notify_guest_clock_paused().map_err(|e| {
                     MigratableError::Pause(anyhow!(
                         "Could not notify guest it has been paused {:?}",
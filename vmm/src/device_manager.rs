@@ -10,9 +10,10 @@
 //
 
 use crate::config::{
-    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, VhostMode,
-    VmConfig, VsockConfig,
+    BalloonConfig, ConsoleOutputMode, DeviceConfig, DiskConfig, DiskModel, FsConfig, IommuMode,
+    IommuType, NetConfig, PmemConfig, VhostMode, VmConfig, VsockConfig,
 };
+use crate::console_buffer::ConsoleBuffer;
 use crate::device_tree::{DeviceNode, DeviceTree};
 #[cfg(feature = "kvm")]
 use crate::interrupt::kvm::KvmMsiInterruptManager as MsiInterruptManager;
@@ -22,6 +23,9 @@ use crate::interrupt::LegacyUserspaceInterruptManager;
 #[cfg(feature = "acpi")]
 use crate::memory_manager::MEMORY_MANAGER_ACPI_SIZE;
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+#[cfg(feature = "kvm")]
+use crate::vfio_functions;
 #[cfg(feature = "acpi")]
 use crate::vm::NumaNodes;
 use crate::GuestRegionMmap;
@@ -36,10 +40,14 @@ use arch::layout;
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 #[cfg(target_arch = "aarch64")]
 use arch::{DeviceType, MmioDeviceInfo};
+use block_util::nbd::{NbdAddress, NbdDiskSync};
+#[cfg(feature = "rbd")]
+use block_util::rbd::{RbdAddress, RbdDiskSync};
 use block_util::{
     async_io::DiskFile, block_io_uring_is_supported, detect_image_type,
-    fixed_vhd_async::FixedVhdDiskAsync, fixed_vhd_sync::FixedVhdDiskSync, qcow_sync::QcowDiskSync,
-    raw_async::RawFileDisk, raw_sync::RawFileDiskSync, ImageType,
+    fixed_vhd_async::FixedVhdDiskAsync, fixed_vhd_sync::FixedVhdDiskSync, overlay::OverlayDiskSync,
+    qcow_sync::QcowDiskSync, raw_async::RawFileDisk, raw_sync::RawFileDiskSync,
+    thread_pool::ThreadPoolDisk, ImageType,
 };
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
@@ -49,6 +57,8 @@ use devices::ioapic;
 use devices::legacy::Pl011;
 #[cfg(target_arch = "x86_64")]
 use devices::legacy::Serial;
+use devices::nvme::NvmeController;
+use devices::usb::{UsbHostDevice, XhciController};
 use devices::{
     interrupt_controller, interrupt_controller::InterruptController, AcpiNotificationFlags,
 };
@@ -60,38 +70,44 @@ use libc::{
     isatty, tcgetattr, tcsetattr, termios, ECHO, ICANON, ISIG, MAP_NORESERVE, MAP_PRIVATE,
     MAP_SHARED, O_TMPFILE, PROT_READ, PROT_WRITE, TCSANOW, TIOCGWINSZ,
 };
-#[cfg(feature = "kvm")]
-use pci::VfioPciDevice;
 use pci::{
-    DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
+    subsystem_id_from_register, DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo,
+    PciConfigMmio, PciDevice, PciRoot, SUBSYSTEM_ID_REG,
 };
+#[cfg(feature = "kvm")]
+use pci::{vfio_iova_ranges, VfioPciDevice};
 use seccomp::SeccompAction;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::{read_link, File, OpenOptions};
-use std::io::{self, sink, stdout, Seek, SeekFrom};
+use std::io::{self, sink, stdout, Seek, SeekFrom, Write};
 use std::mem::zeroed;
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Barrier, Mutex};
+#[cfg(feature = "kvm")]
+use std::thread;
 #[cfg(feature = "acpi")]
 use uuid::Uuid;
 #[cfg(feature = "kvm")]
 use vfio_ioctls::{VfioContainer, VfioDevice};
+use virtio_devices::input_log::{InputKind, InputLog, InputRecord};
 use virtio_devices::transport::VirtioPciDevice;
 use virtio_devices::transport::VirtioTransport;
 use virtio_devices::vhost_user::VhostUserConfig;
-use virtio_devices::{DmaRemapping, IommuMapping};
-use virtio_devices::{VirtioSharedMemory, VirtioSharedMemoryList};
+use virtio_devices::{DiskSwitchRequest, DmaRemapping, IommuMapping};
+use virtio_devices::{VirtioSharedMemory, VirtioSharedMemoryList, WorkerFailure, WorkerSupervisor};
 use vm_allocator::SystemAllocator;
 #[cfg(feature = "kvm")]
 use vm_device::dma_mapping::vfio::VfioDmaMapping;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
+#[cfg(target_arch = "x86_64")]
+use vm_device::interrupt::{InterruptSourceConfig, InterruptSourceGroup, MsiIrqSourceConfig};
 use vm_device::{Bus, BusDevice, Resource};
 use vm_memory::guest_memory::FileOffset;
 #[cfg(feature = "kvm")]
@@ -129,6 +145,8 @@ const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
 const RNG_DEVICE_NAME: &str = "_rng";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
 const WATCHDOG_DEVICE_NAME: &str = "_watchdog";
+const XHCI_DEVICE_NAME: &str = "_xhci";
+const USB_DEVICE_NAME_PREFIX: &str = "_usb";
 
 const IOMMU_DEVICE_NAME: &str = "_iommu";
 
@@ -152,6 +170,9 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-net device
     CreateVirtioNet(virtio_devices::net::Error),
 
+    /// Cannot load the RX filter of a virtio-net device
+    LoadNetRxFilter(io::Error),
+
     /// Cannot create virtio-console device
     CreateVirtioConsole(io::Error),
 
@@ -188,6 +209,9 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-watchdog device
     CreateVirtioWatchdog(io::Error),
 
+    /// Cannot create the event loop shared by low traffic virtio devices
+    CreateSharedEventLoop(virtio_devices::EpollHelperError),
+
     /// Failed parsing disk image format
     DetectImageType(io::Error),
 
@@ -260,11 +284,11 @@ pub enum DeviceManagerError {
     /// Failed to map VFIO MMIO region.
     VfioMapRegion(pci::VfioPciError),
 
-    /// Failed to DMA map VFIO device.
-    VfioDmaMap(pci::VfioPciError),
+    /// Failed to DMA map the guest memory in a VFIO container.
+    VfioDmaMap(vfio_ioctls::VfioError),
 
-    /// Failed to DMA unmap VFIO device.
-    VfioDmaUnmap(pci::VfioPciError),
+    /// Failed to DMA unmap the guest memory from a VFIO container.
+    VfioDmaUnmap(vfio_ioctls::VfioError),
 
     /// Failed to create the passthrough device.
     CreatePassthroughDevice(anyhow::Error),
@@ -296,6 +320,9 @@ pub enum DeviceManagerError {
     /// Failed creating interrupt controller.
     CreateInterruptController(interrupt_controller::Error),
 
+    /// Failed resetting interrupt controller.
+    ResetInterruptController(interrupt_controller::Error),
+
     /// Failed creating a new MmapRegion instance.
     NewMmapRegion(vm_memory::mmap::MmapRegionError),
 
@@ -335,6 +362,9 @@ pub enum DeviceManagerError {
     /// Not allowed to remove this type of device from the VM.
     RemovalNotAllowed(vm_virtio::VirtioDeviceType),
 
+    /// Not allowed to remove a device placed behind a PCI-to-PCI bridge.
+    RemovalBehindBridgeNotAllowed(u32),
+
     /// Failed to find device corresponding to the given identifier.
     UnknownDeviceId(String),
 
@@ -366,7 +396,7 @@ pub enum DeviceManagerError {
     VirtioMemRangeAllocation,
 
     /// Failed updating guest memory for VFIO PCI device.
-    UpdateMemoryForVfioPciDevice(pci::VfioPciError),
+    UpdateMemoryForVfioPciDevice(vfio_ioctls::VfioError),
 
     /// Trying to use a directory for pmem but no size specified
     PmemWithDirectorySizeMissing,
@@ -398,13 +428,47 @@ pub enum DeviceManagerError {
     /// Missing virtio-balloon, can't proceed as expected.
     MissingVirtioBalloon,
 
+    /// Failed to set the link state of a virtio-net device
+    SetNetLink(virtio_devices::net::Error),
+
     /// Failed to do power button notification
     PowerButtonNotification(io::Error),
 
+    /// Failed to do power supply notification
+    PowerSupplyNotification(io::Error),
+
+    /// Failed to do sleep button notification
+    SleepButtonNotification(io::Error),
+
+    /// Failed to inject an NMI
+    #[cfg(target_arch = "x86_64")]
+    NmiInjection(io::Error),
+
+    /// Failed to open the input log
+    InputLog(io::Error),
+
+    /// No power supply to update the state of
+    MissingPowerSupply,
+
+    /// Failed to create the TPM device
+    #[cfg(feature = "acpi")]
+    CreateTpm(devices::tpm::Error),
+
+    /// Neither an emulator socket nor a host device to back the TPM
+    MissingTpmBackend,
+
+    /// Failed to reset the TPM device
+    #[cfg(feature = "acpi")]
+    ResetTpm(devices::tpm::Error),
+
     /// Failed to do AArch64 GPIO power button notification
     #[cfg(target_arch = "aarch64")]
     AArch64PowerButtonNotification(devices::legacy::GpioDeviceError),
 
+    /// Failed to do AArch64 GPIO sleep button notification
+    #[cfg(target_arch = "aarch64")]
+    AArch64SleepButtonNotification(devices::legacy::GpioDeviceError),
+
     /// Failed to set O_DIRECT flag to file descriptor
     SetDirectIo,
 
@@ -414,16 +478,123 @@ pub enum DeviceManagerError {
     /// Failed to create FixedVhdDiskSync
     CreateFixedVhdDiskSync(io::Error),
 
+    /// Failed to create OverlayDiskSync
+    CreateOverlayDiskSync(io::Error),
+
+    /// Failed to create NbdDiskSync
+    CreateNbdDiskSync(io::Error),
+
+    /// Failed to create RbdDiskSync
+    #[cfg(feature = "rbd")]
+    CreateRbdDiskSync(io::Error),
+
+    /// RBD disks can't be used while the seccomp filters are enforced
+    #[cfg(feature = "rbd")]
+    RbdSeccompEnforced,
+
+    /// Disk overlay is only supported over RAW images
+    OverlayUnsupportedImageType,
+
+    /// Disk mirroring is only supported for local RAW images
+    MirrorUnsupportedDisk,
+
+    /// Failed to open the target of a disk mirror
+    OpenMirrorTarget(io::Error),
+
+    /// Failed to mirror a virtio-block device
+    MirrorDisk(virtio_devices::block::Error),
+
+    /// Disk snapshots are only supported for local RAW images
+    DiskSnapshotUnsupportedDisk,
+
+    /// Failed to switch a virtio-block device to a new disk image
+    DiskSwitch(virtio_devices::block::Error),
+
+    /// Failed to take a snapshot of a virtio-block device
+    DiskSnapshot(virtio_devices::block::Error),
+
+    /// Failed to create NVMe controller
+    CreateNvmeController(devices::nvme::Error),
+
+    /// Cannot create seccomp filter
+    CreateSeccompFilter(seccomp::SeccompError),
+
+    /// NVMe disks can't be hotplugged
+    NvmeHotplugUnsupported,
+
+    /// Failed to open host USB device
+    OpenUsbDevice(devices::usb::host::Error),
+
+    /// Failed to create xHCI controller
+    CreateUsbController(devices::usb::xhci::Error),
+
+    /// PCI subsystem IDs of a virtio device clash with an assigned device
+    PciSubsystemIdClash(String, String),
+
     /// Failed adding DMA mapping handler to virtio-mem device.
     AddDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
 
     /// Failed removing DMA mapping handler from virtio-mem device.
     RemoveDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
+
+    /// Missing ACPI devices, as ACPI is disabled for this VM.
+    AcpiDisabled,
+
+    /// Missing IOMMU, as no device was placed behind one at boot.
+    MissingIommu,
+
+    /// No free PCI slot matching the placement of the device relative to
+    /// the IOMMU.
+    NoIommuCompatiblePciSlot,
+
+    /// Failed to look up the functions of an assigned device.
+    VfioFunctions(io::Error),
+
+    /// The assigned function shares its IOMMU group with other functions
+    /// of the same device, which must be assigned along with it through
+    /// all_functions=on.
+    VfioFunctionNotIsolated(PathBuf, Vec<PathBuf>),
+
+    /// all_functions=on given for a device which isn't a PCI function.
+    VfioNotPciFunction(PathBuf),
+
+    /// The functions of a multifunction device can't be hot plugged.
+    VfioAllFunctionsHotplugUnsupported,
+
+    /// Not allowed to remove a function of a multifunction device.
+    RemovalOfMultifunctionDeviceNotAllowed(u32),
+
+    /// Failed to bind a function assigned with managed=on to vfio-pci.
+    VfioBind(PathBuf, io::Error),
+
+    /// Failed to spawn the thread mapping the guest memory in a VFIO container.
+    VfioDmaMapThreadSpawn(io::Error),
+
+    /// The thread mapping the guest memory in a VFIO container panicked.
+    VfioDmaMapThreadJoin,
+
+    /// Cannot restart a virtio device whose worker failed.
+    RestartVirtioDevice(virtio_devices::ActivateError),
+
+    /// Restarting this type of virtio device is not allowed.
+    RestartNotAllowed(vm_virtio::VirtioDeviceType),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
 type VirtioDeviceArc = Arc<Mutex<dyn virtio_devices::VirtioDevice>>;
 
+// Translation of the DMA through the emulated VT-d, for the virtio devices
+// placed behind it.
+#[cfg(target_arch = "x86_64")]
+struct VtdRemapping(Arc<devices::vtd::VtdMapping>);
+
+#[cfg(target_arch = "x86_64")]
+impl DmaRemapping for VtdRemapping {
+    fn translate(&self, id: u32, addr: u64) -> io::Result<u64> {
+        self.0.translate(id, addr)
+    }
+}
+
 #[cfg(feature = "acpi")]
 const DEVICE_MANAGER_ACPI_SIZE: usize = 0x10;
 
@@ -508,6 +679,11 @@ pub struct Console {
     serial: Option<Arc<Mutex<Pl011>>>,
     virtio_console_input: Option<Arc<virtio_devices::ConsoleInput>>,
     input: Option<ConsoleInput>,
+    // Device driven through the API, and its output
+    api_input: Option<ConsoleInput>,
+    api_output: ConsoleBuffer,
+    // Log the input received is recorded to, or replayed from
+    input_log: Option<Arc<InputLog>>,
 }
 
 impl Console {
@@ -527,6 +703,42 @@ impl Console {
     }
 
     pub fn queue_input_bytes_serial(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
+        if self.log_input(InputKind::Serial, out) {
+            self.inject_serial(out)?;
+        }
+        Ok(())
+    }
+
+    pub fn queue_input_bytes_console(&self, out: &[u8]) {
+        if self.log_input(InputKind::Console, out) {
+            self.inject_console(out);
+        }
+    }
+
+    /// Passes an input replayed from the input log to the guest.
+    pub fn replay_input(&self, record: &InputRecord) -> vmm_sys_util::errno::Result<()> {
+        match record.kind {
+            InputKind::Serial => self.inject_serial(&record.data)?,
+            InputKind::Console => self.inject_console(&record.data),
+            InputKind::Rng => {}
+        }
+        Ok(())
+    }
+
+    // Records the input received, and returns whether to pass it to the
+    // guest, which is not the case while the inputs are replayed.
+    fn log_input(&self, kind: InputKind, data: &[u8]) -> bool {
+        match &self.input_log {
+            Some(input_log) if input_log.is_replaying() => false,
+            Some(input_log) => {
+                input_log.record_input(kind, data);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn inject_serial(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
         if self.serial.is_some() {
             self.serial
                 .as_ref()
@@ -538,7 +750,7 @@ impl Console {
         Ok(())
     }
 
-    pub fn queue_input_bytes_console(&self, out: &[u8]) {
+    fn inject_console(&self, out: &[u8]) {
         if self.virtio_console_input.is_some() {
             self.virtio_console_input
                 .as_ref()
@@ -559,6 +771,58 @@ impl Console {
     pub fn input_enabled(&self) -> bool {
         self.input.is_some()
     }
+
+    /// Queues the input received through the API, and returns the output of
+    /// the device. Returns None if no device is driven through the API.
+    pub fn api_exchange(&self, input: &[u8]) -> vmm_sys_util::errno::Result<Option<ConsoleBuffer>> {
+        match self.api_input {
+            Some(ConsoleInput::Serial) => self.queue_input_bytes_serial(input)?,
+            Some(ConsoleInput::VirtioConsole) => self.queue_input_bytes_console(input),
+            None => return Ok(None),
+        }
+
+        Ok(Some(self.api_output.clone()))
+    }
+}
+
+// Duplicates the console output to a log file, so that it is kept around
+// while being watched live. Failing to log doesn't affect the output, the
+// logging just stops.
+struct ConsoleTee<W: Write> {
+    out: W,
+    log: Option<File>,
+}
+
+impl<W: Write> ConsoleTee<W> {
+    fn new(out: W, log_path: &Path) -> io::Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+
+        Ok(ConsoleTee {
+            out,
+            log: Some(log),
+        })
+    }
+}
+
+impl<W: Write> Write for ConsoleTee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.out.write(buf)?;
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.write_all(&buf[..count]) {
+                warn!("Failed logging the console output, giving up: {}", e);
+                self.log = None;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
 }
 
 struct AddressManager {
@@ -777,6 +1041,103 @@ impl DeviceRelocation for AddressManager {
     }
 }
 
+// Container shared by VFIO devices which are not attached to the virtual
+// IOMMU, along with the identifier it is registered with to the virtio-mem
+// devices and the PCI b/d/f of the devices assigned behind it.
+#[cfg(feature = "kvm")]
+struct VfioContainerMapping {
+    container: Arc<VfioContainer>,
+    id: u32,
+    devices: Vec<u32>,
+    // Guest memory mapped in the container, as (guest address, size, host
+    // address) ranges.
+    ranges: Vec<(u64, u64, u64)>,
+    // Thread mapping the guest memory in the container, until it's waited
+    // for.
+    mapping_thread: Option<thread::JoinHandle<result::Result<(), vfio_ioctls::VfioError>>>,
+}
+
+#[cfg(feature = "kvm")]
+impl VfioContainerMapping {
+    fn wait(&mut self) -> DeviceManagerResult<()> {
+        if let Some(mapping_thread) = self.mapping_thread.take() {
+            mapping_thread
+                .join()
+                .map_err(|_| DeviceManagerError::VfioDmaMapThreadJoin)?
+                .map_err(DeviceManagerError::VfioDmaMap)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Merges the guest memory ranges, as (guest address, size, host address),
+// which are contiguous both in the guest and in the VMM, for them to be
+// mapped with as few VFIO_IOMMU_MAP_DMA as possible, and unmapped at once.
+#[cfg(feature = "kvm")]
+fn merge_dma_ranges(mut ranges: Vec<(u64, u64, u64)>) -> Vec<(u64, u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64, u64)> = Vec::new();
+    for (gpa, size, hva) in ranges {
+        if let Some(last) = merged.last_mut() {
+            if last.0 + last.1 == gpa && last.2 + last.1 == hva {
+                last.1 += size;
+                continue;
+            }
+        }
+        merged.push((gpa, size, hva));
+    }
+    merged
+}
+
+// Size of the chunks the guest memory ranges are split in, for several
+// threads to map them. The chunks being aligned on the largest hugepage size
+// in the guest, no hugepage straddles two of them, and the host IOMMU can
+// still map the memory with its largest pages.
+#[cfg(feature = "kvm")]
+const VFIO_DMA_MAP_CHUNK_SIZE: u64 = 1 << 30;
+
+// Number of threads mapping the guest memory in a VFIO container.
+#[cfg(feature = "kvm")]
+const VFIO_DMA_MAP_THREADS: usize = 4;
+
+// Splits the guest memory ranges, as (guest address, size, host address), in
+// chunks which don't cross a `chunk_size` boundary in the guest.
+#[cfg(feature = "kvm")]
+fn split_dma_ranges(ranges: &[(u64, u64, u64)], chunk_size: u64) -> Vec<(u64, u64, u64)> {
+    let mut chunks = Vec::new();
+    for &(mut gpa, mut size, mut hva) in ranges {
+        while size > 0 {
+            let len = std::cmp::min(size, chunk_size - gpa % chunk_size);
+            chunks.push((gpa, len, hva));
+            gpa += len;
+            hva += len;
+            size -= len;
+        }
+    }
+    chunks
+}
+
+// Maps the guest memory chunks in `container` until there are none left. The
+// chunks left are dropped on failure, for the other threads to stop as well.
+#[cfg(feature = "kvm")]
+fn map_dma_chunks(
+    container: &VfioContainer,
+    chunks: &Mutex<std::vec::IntoIter<(u64, u64, u64)>>,
+) -> result::Result<(), vfio_ioctls::VfioError> {
+    loop {
+        let chunk = chunks.lock().unwrap().next();
+        let (gpa, size, hva) = match chunk {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+        if let Err(e) = container.vfio_dma_map(gpa, size, hva) {
+            *chunks.lock().unwrap() = Vec::new().into_iter();
+            return Err(e);
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct DeviceManagerState {
     device_tree: DeviceTree,
@@ -833,6 +1194,22 @@ pub struct DeviceManager {
     #[cfg(feature = "acpi")]
     ged_notification_device: Option<Arc<Mutex<devices::AcpiGedDevice>>>,
 
+    // ACPI shutdown device, also handling the sleep states
+    #[cfg(feature = "acpi")]
+    shutdown_device: Option<Arc<Mutex<devices::AcpiShutdownDevice>>>,
+
+    // ACPI AC adapter and battery
+    #[cfg(feature = "acpi")]
+    power_supply_device: Option<Arc<Mutex<devices::AcpiPowerSupplyDevice>>>,
+
+    // TPM, described to the guest through ACPI
+    #[cfg(feature = "acpi")]
+    tpm_device: Option<Arc<Mutex<devices::tpm::Tpm>>>,
+
+    // CMOS device, also providing the RTC alarm
+    #[cfg(all(target_arch = "x86_64", feature = "cmos"))]
+    cmos: Option<Arc<Mutex<devices::legacy::Cmos>>>,
+
     // VM configuration
     config: Arc<Mutex<VmConfig>>,
 
@@ -840,7 +1217,7 @@ pub struct DeviceManager {
     memory_manager: Arc<Mutex<MemoryManager>>,
 
     // The virtio devices on the system
-    virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+    virtio_devices: Vec<(VirtioDeviceArc, IommuMode, String)>,
 
     // List of bus devices
     // Let the DeviceManager keep strong references to the BusDevice devices.
@@ -862,18 +1239,39 @@ pub struct DeviceManager {
     // Legacy Interrupt Manager
     legacy_interrupt_manager: Option<Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>>,
 
+    // Interrupt broadcasting an NMI to the vCPUs
+    #[cfg(target_arch = "x86_64")]
+    nmi_interrupt_group: Option<Arc<Box<dyn InterruptSourceGroup>>>,
+
     // Passthrough device handle
     passthrough_device: Option<Arc<dyn hypervisor::Device>>,
 
     // Paravirtualized IOMMU
     iommu_device: Option<Arc<Mutex<virtio_devices::Iommu>>>,
 
+    // Translation of the DMA of the virtio devices attached to the
+    // paravirtualized IOMMU
+    iommu_mapping: Option<Arc<IommuMapping>>,
+
     // PCI information about devices attached to the paravirtualized IOMMU
     // It contains the virtual IOMMU PCI BDF along with the list of PCI BDF
     // representing the devices attached to the virtual IOMMU. This is useful
-    // information for filling the ACPI VIOT table.
+    // information for filling the ACPI VIOT table. The free slots of the root
+    // bus are part of the list, as the devices hot plugged behind the virtual
+    // IOMMU must be described by the table too.
     iommu_attached_devices: Option<(u32, Vec<u32>)>,
 
+    // Translation of the DMA of the virtio devices placed behind the IOMMU,
+    // whichever it is, kept for the devices hot plugged behind it.
+    dma_remapping: Option<Arc<dyn DmaRemapping>>,
+
+    // Address of the emulated VT-d registers along with the list of PCI BDF
+    // representing the devices placed behind it, for filling the ACPI DMAR
+    // table. The free slots of the root bus are part of the list, as for the
+    // VIOT table.
+    #[cfg(target_arch = "x86_64")]
+    vtd_attached_devices: Option<(GuestAddress, Vec<u32>)>,
+
     // Bitmap of PCI devices to hotplug.
     pci_devices_up: u32,
 
@@ -893,6 +1291,10 @@ pub struct DeviceManager {
 
     reset_evt: EventFd,
 
+    // Suspend event
+    #[cfg(feature = "acpi")]
+    suspend_evt: EventFd,
+
     #[cfg(target_arch = "aarch64")]
     id_to_dev_info: HashMap<(DeviceType, String), MmioDeviceInfo>,
 
@@ -906,19 +1308,49 @@ pub struct DeviceManager {
     // Possible handle to the virtio-balloon device
     balloon: Option<Arc<Mutex<virtio_devices::Balloon>>>,
 
+    // Event loop shared by low traffic virtio devices, if enabled
+    shared_event_loop: Option<Arc<virtio_devices::SharedEpollLoop>>,
+
     // Virtio Device activation EventFd to allow the VMM thread to trigger device
     // activation and thus start the threads from the VMM thread
     activate_evt: EventFd,
 
+    // Supervisor the worker threads of the virtio devices report to
+    worker_supervisor: WorkerSupervisor,
+
     #[cfg(feature = "acpi")]
     acpi_address: GuestAddress,
 
     // Possible handle to the virtio-balloon device
     virtio_mem_devices: Vec<Arc<Mutex<virtio_devices::Mem>>>,
 
+    // virtio-net devices, along with their identifiers, for their link
+    // state to be controlled
+    net_devices: Vec<(String, Arc<Mutex<virtio_devices::Net>>)>,
+
+    // virtio-block devices, along with their identifiers, for them to be
+    // mirrored, and the targets of the mirrors in progress
+    block_devices: Vec<(String, Arc<Mutex<virtio_devices::Block>>)>,
+    disk_mirrors: HashMap<String, PathBuf>,
+
     #[cfg(target_arch = "aarch64")]
     // GPIO device for AArch64
     gpio_device: Option<Arc<Mutex<devices::legacy::Gpio>>>,
+
+    // Functions bound to vfio-pci for the devices assigned with managed=on.
+    vfio_bindings: Vec<crate::vfio_functions::DriverBinding>,
+
+    // Containers of the VFIO devices which are not attached to the virtual
+    // IOMMU, the guest memory being mapped once per container.
+    #[cfg(feature = "kvm")]
+    vfio_containers: Vec<VfioContainerMapping>,
+
+    // Identifier of the next container registered to the virtio-mem devices.
+    #[cfg(feature = "kvm")]
+    vfio_container_id: u32,
+
+    // Log the non-deterministic inputs are recorded to, or replayed from
+    input_log: Option<Arc<InputLog>>,
 }
 
 impl DeviceManager {
@@ -929,9 +1361,11 @@ impl DeviceManager {
         memory_manager: Arc<Mutex<MemoryManager>>,
         _exit_evt: &EventFd,
         reset_evt: &EventFd,
+        _suspend_evt: &EventFd,
         seccomp_action: SeccompAction,
         #[cfg(feature = "acpi")] numa_nodes: NumaNodes,
         activate_evt: &EventFd,
+        worker_supervisor: WorkerSupervisor,
     ) -> DeviceManagerResult<Arc<Mutex<Self>>> {
         let device_tree = Arc::new(Mutex::new(DeviceTree::new()));
 
@@ -963,6 +1397,23 @@ impl DeviceManager {
             .unwrap()
             .allocate_mmio_addresses(None, DEVICE_MANAGER_ACPI_SIZE as u64, None)
             .ok_or(DeviceManagerError::AllocateIoPort)?;
+
+        let input_log = config
+            .lock()
+            .unwrap()
+            .input_log
+            .as_ref()
+            .map(|input_log| {
+                if input_log.replay {
+                    InputLog::replay(&input_log.path)
+                } else {
+                    InputLog::record(&input_log.path)
+                }
+            })
+            .transpose()
+            .map_err(DeviceManagerError::InputLog)?
+            .map(Arc::new);
+
         let device_manager = DeviceManager {
             address_manager: Arc::clone(&address_manager),
             console: Arc::new(Console::default()),
@@ -970,6 +1421,14 @@ impl DeviceManager {
             cmdline_additions: Vec::new(),
             #[cfg(feature = "acpi")]
             ged_notification_device: None,
+            #[cfg(feature = "acpi")]
+            shutdown_device: None,
+            #[cfg(feature = "acpi")]
+            power_supply_device: None,
+            #[cfg(feature = "acpi")]
+            tpm_device: None,
+            #[cfg(all(target_arch = "x86_64", feature = "cmos"))]
+            cmos: None,
             config,
             memory_manager,
             virtio_devices: Vec::new(),
@@ -978,9 +1437,15 @@ impl DeviceManager {
             pci_bus: None,
             msi_interrupt_manager,
             legacy_interrupt_manager: None,
+            #[cfg(target_arch = "x86_64")]
+            nmi_interrupt_group: None,
             passthrough_device: None,
             iommu_device: None,
+            iommu_mapping: None,
             iommu_attached_devices: None,
+            dma_remapping: None,
+            #[cfg(target_arch = "x86_64")]
+            vtd_attached_devices: None,
             pci_devices_up: 0,
             pci_devices_down: 0,
             pci_irq_slots: [0; 32],
@@ -988,22 +1453,37 @@ impl DeviceManager {
             #[cfg(feature = "acpi")]
             exit_evt: _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
             reset_evt: reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            #[cfg(feature = "acpi")]
+            suspend_evt: _suspend_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
             seccomp_action,
             #[cfg(feature = "acpi")]
             numa_nodes,
             balloon: None,
+            shared_event_loop: None,
             activate_evt: activate_evt
                 .try_clone()
                 .map_err(DeviceManagerError::EventFd)?,
+            worker_supervisor,
             #[cfg(feature = "acpi")]
             acpi_address,
             serial_pty: None,
             console_pty: None,
             virtio_mem_devices: Vec::new(),
+            net_devices: Vec::new(),
+            block_devices: Vec::new(),
+            disk_mirrors: HashMap::new(),
             #[cfg(target_arch = "aarch64")]
             gpio_device: None,
+            vfio_bindings: Vec::new(),
+            #[cfg(feature = "kvm")]
+            vfio_containers: Vec::new(),
+            #[cfg(feature = "kvm")]
+            vfio_container_id: 0,
+            input_log,
         };
 
         let device_manager = Arc::new(Mutex::new(device_manager));
@@ -1015,6 +1495,7 @@ impl DeviceManager {
                 Arc::clone(&device_manager) as Arc<Mutex<dyn BusDevice>>,
                 acpi_address.0,
                 DEVICE_MANAGER_ACPI_SIZE as u64,
+                "device_manager",
             )
             .map_err(DeviceManagerError::BusError)?;
 
@@ -1038,7 +1519,7 @@ impl DeviceManager {
         serial_pty: Option<PtyPair>,
         console_pty: Option<PtyPair>,
     ) -> DeviceManagerResult<()> {
-        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+        let mut virtio_devices: Vec<(VirtioDeviceArc, IommuMode, String)> = Vec::new();
 
         let interrupt_controller = self.add_interrupt_controller()?;
 
@@ -1051,7 +1532,10 @@ impl DeviceManager {
         )));
 
         #[cfg(feature = "acpi")]
-        {
+        let acpi_enabled = self.config.lock().unwrap().acpi_enabled();
+
+        #[cfg(feature = "acpi")]
+        if acpi_enabled {
             let memory_manager_acpi_address = self.memory_manager.lock().unwrap().acpi_address;
             self.address_manager
                 .mmio_bus
@@ -1059,6 +1543,7 @@ impl DeviceManager {
                     Arc::clone(&self.memory_manager) as Arc<Mutex<dyn BusDevice>>,
                     memory_manager_acpi_address.0,
                     MEMORY_MANAGER_ACPI_SIZE as u64,
+                    "memory_manager",
                 )
                 .map_err(DeviceManagerError::BusError)?;
         }
@@ -1074,7 +1559,7 @@ impl DeviceManager {
         self.add_legacy_devices(&legacy_interrupt_manager)?;
 
         #[cfg(feature = "acpi")]
-        {
+        if acpi_enabled {
             self.ged_notification_device = self.add_acpi_devices(
                 &legacy_interrupt_manager,
                 self.reset_evt
@@ -1083,6 +1568,9 @@ impl DeviceManager {
                 self.exit_evt
                     .try_clone()
                     .map_err(DeviceManagerError::EventFd)?,
+                self.suspend_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
             )?;
         }
 
@@ -1130,6 +1618,11 @@ impl DeviceManager {
         Ok(())
     }
 
+    /// Gets the IRQ reserved for the legacy interrupts of each PCI slot.
+    pub fn pci_irq_slots(&self) -> &[u8] {
+        &self.pci_irq_slots
+    }
+
     fn state(&self) -> DeviceManagerState {
         DeviceManagerState {
             device_tree: self.device_tree.lock().unwrap().clone(),
@@ -1151,7 +1644,7 @@ impl DeviceManager {
     #[allow(unused_variables)]
     fn add_pci_devices(
         &mut self,
-        virtio_devices: Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: Vec<(VirtioDeviceArc, IommuMode, String)>,
     ) -> DeviceManagerResult<()> {
         let pci_root = PciRoot::new(None);
         let mut pci_bus = PciBus::new(
@@ -1161,7 +1654,16 @@ impl DeviceManager {
 
         let iommu_id = String::from(IOMMU_DEVICE_NAME);
 
-        let (iommu_device, iommu_mapping) = if self.config.lock().unwrap().iommu {
+        let iommu_type = {
+            let config = self.config.lock().unwrap();
+            if config.iommu {
+                Some(config.iommu_type())
+            } else {
+                None
+            }
+        };
+
+        let (iommu_device, iommu_mapping) = if iommu_type == Some(IommuType::Virtio) {
             let (device, mapping) =
                 virtio_devices::Iommu::new(iommu_id.clone(), self.seccomp_action.clone())
                     .map_err(DeviceManagerError::CreateVirtioIommu)?;
@@ -1181,39 +1683,94 @@ impl DeviceManager {
             (None, None)
         };
 
+        let dma_remapping = iommu_mapping
+            .clone()
+            .map(|mapping| mapping as Arc<dyn DmaRemapping>);
+
+        #[cfg(target_arch = "x86_64")]
+        let (dma_remapping, vtd_address) = if iommu_type == Some(IommuType::Vtd) {
+            let (vtd_address, vtd_remapping) = self.add_vtd_device()?;
+            (Some(vtd_remapping), Some(vtd_address))
+        } else {
+            (dma_remapping, None)
+        };
+
         let mut iommu_attached_devices = Vec::new();
 
-        for (device, iommu_attached, id) in virtio_devices {
-            let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
-                &iommu_mapping
+        for (device, iommu, id) in virtio_devices {
+            let mapping: &Option<Arc<dyn DmaRemapping>> = if iommu.enabled() {
+                &dma_remapping
             } else {
                 &None
             };
 
             let dev_id = self.add_virtio_pci_device(device, &mut pci_bus, mapping, id)?;
 
-            if iommu_attached {
+            if iommu.enabled() {
                 iommu_attached_devices.push(dev_id);
             }
+            if let (IommuMode::Bypass, Some(mapping)) = (iommu, &iommu_mapping) {
+                mapping.add_bypass_endpoint(dev_id);
+            }
         }
 
         let mut vfio_iommu_device_ids = self.add_vfio_devices(&mut pci_bus)?;
 
+        self.add_nvme_devices(&mut pci_bus)?;
+
+        self.add_usb_controller(&mut pci_bus)?;
+
         iommu_attached_devices.append(&mut vfio_iommu_device_ids);
 
-        if let Some(iommu_device) = iommu_device {
-            let dev_id = self.add_virtio_pci_device(iommu_device, &mut pci_bus, &None, iommu_id)?;
-            self.iommu_attached_devices = Some((dev_id, iommu_attached_devices));
+        let iommu_bdf = if let Some(iommu_device) = iommu_device {
+            Some(self.add_virtio_pci_device(iommu_device, &mut pci_bus, &None, iommu_id)?)
+        } else {
+            None
+        };
+
+        // Only the slots of the root bus can be hot plugged through ACPI.
+        pci_bus.restrict_to_root_bus();
+
+        if dma_remapping.is_some() {
+            // The VIOT and DMAR tables can't be updated once the guest
+            // booted, which is why the first half of the free slots is
+            // described as behind the IOMMU as well, so that devices can be
+            // hot plugged behind it. The other half is kept for the devices
+            // hot plugged without the IOMMU.
+            let free_device_ids = pci_bus.free_root_device_ids();
+            let iommu_slots = (free_device_ids.len() + 1) / 2;
+            iommu_attached_devices.extend(
+                free_device_ids
+                    .into_iter()
+                    .take(iommu_slots)
+                    .map(|device_id| device_id << 3),
+            );
+            self.dma_remapping = dma_remapping;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(vtd_address) = vtd_address {
+            self.vtd_attached_devices = Some((vtd_address, iommu_attached_devices.clone()));
+        }
+
+        if let Some(iommu_bdf) = iommu_bdf {
+            self.iommu_attached_devices = Some((iommu_bdf, iommu_attached_devices));
+            self.iommu_mapping = iommu_mapping;
         }
 
         let pci_bus = Arc::new(Mutex::new(pci_bus));
-        let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(Arc::clone(&pci_bus))));
+        let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(
+            Arc::clone(&pci_bus),
+            self.reset_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+        )));
         self.bus_devices
             .push(Arc::clone(&pci_config_io) as Arc<Mutex<dyn BusDevice>>);
         #[cfg(target_arch = "x86_64")]
         self.address_manager
             .io_bus
-            .insert(pci_config_io, 0xcf8, 0x8)
+            .insert(pci_config_io, 0xcf8, 0x8, "pci_config_io")
             .map_err(DeviceManagerError::BusError)?;
         let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(&pci_bus))));
         self.bus_devices
@@ -1224,6 +1781,7 @@ impl DeviceManager {
                 pci_config_mmio,
                 arch::layout::PCI_MMCONFIG_START.0,
                 arch::layout::PCI_MMCONFIG_SIZE,
+                "pci_config_mmio",
             )
             .map_err(DeviceManagerError::BusError)?;
 
@@ -1279,7 +1837,12 @@ impl DeviceManager {
 
         self.address_manager
             .mmio_bus
-            .insert(interrupt_controller.clone(), IOAPIC_START.0, IOAPIC_SIZE)
+            .insert(
+                interrupt_controller.clone(),
+                IOAPIC_START.0,
+                IOAPIC_SIZE,
+                IOAPIC_DEVICE_NAME,
+            )
             .map_err(DeviceManagerError::BusError)?;
 
         self.bus_devices
@@ -1302,13 +1865,28 @@ impl DeviceManager {
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
         reset_evt: EventFd,
         exit_evt: EventFd,
+        suspend_evt: EventFd,
     ) -> DeviceManagerResult<Option<Arc<Mutex<devices::AcpiGedDevice>>>> {
+        // The guest can only enter the sleep states advertised in the DSDT.
+        let mut sleep_states = Vec::new();
+        if let Some(suspend) = &self.config.lock().unwrap().suspend {
+            if suspend.s3 {
+                sleep_states.push(devices::acpi::S3_SLEEP_VALUE);
+            }
+            if suspend.s4_destination_url.is_some() {
+                sleep_states.push(devices::acpi::S4_SLEEP_VALUE);
+            }
+        }
         let shutdown_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
-            exit_evt, reset_evt,
+            exit_evt,
+            reset_evt,
+            suspend_evt,
+            sleep_states,
         )));
 
         self.bus_devices
             .push(Arc::clone(&shutdown_device) as Arc<Mutex<dyn BusDevice>>);
+        self.shutdown_device = Some(Arc::clone(&shutdown_device));
 
         #[cfg(target_arch = "x86_64")]
         {
@@ -1321,7 +1899,7 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(shutdown_device, 0x3c0, 0x4)
+                .insert(shutdown_device, 0x3c0, 0x4, "acpi_shutdown")
                 .map_err(DeviceManagerError::BusError)?;
         }
 
@@ -1355,6 +1933,7 @@ impl DeviceManager {
                 ged_device.clone(),
                 ged_address.0,
                 devices::acpi::GED_DEVICE_ACPI_SIZE as u64,
+                "acpi_ged",
             )
             .map_err(DeviceManagerError::BusError)?;
         self.bus_devices
@@ -1376,8 +1955,78 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(pm_timer_device, 0xb008, 0x4)
+                .insert(pm_timer_device, 0xb008, 0x4, "acpi_pm_timer")
+                .map_err(DeviceManagerError::BusError)?;
+        }
+
+        let power_supply = self.config.lock().unwrap().power_supply.clone();
+        if let Some(power_supply) = power_supply {
+            let power_supply_address = self
+                .address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_addresses(
+                    None,
+                    devices::acpi::POWER_SUPPLY_DEVICE_ACPI_SIZE as u64,
+                    None,
+                )
+                .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+            let power_supply_device = Arc::new(Mutex::new(devices::AcpiPowerSupplyDevice::new(
+                power_supply.ac_online,
+                power_supply.battery,
+                power_supply.battery_level,
+                power_supply_address,
+            )));
+            self.address_manager
+                .mmio_bus
+                .insert(
+                    power_supply_device.clone(),
+                    power_supply_address.0,
+                    devices::acpi::POWER_SUPPLY_DEVICE_ACPI_SIZE as u64,
+                    "acpi_power_supply",
+                )
+                .map_err(DeviceManagerError::BusError)?;
+            self.bus_devices
+                .push(Arc::clone(&power_supply_device) as Arc<Mutex<dyn BusDevice>>);
+            self.power_supply_device = Some(power_supply_device);
+        }
+
+        let tpm = self.config.lock().unwrap().tpm.clone();
+        if let Some(tpm) = tpm {
+            #[cfg(target_arch = "x86_64")]
+            let tpm_address = Some(arch::layout::TPM_START);
+            #[cfg(target_arch = "aarch64")]
+            let tpm_address = None;
+            let tpm_address = self
+                .address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_addresses(tpm_address, devices::tpm::TPM_CRB_SIZE, None)
+                .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+            let tpm_device = match (&tpm.socket, &tpm.device) {
+                (Some(socket), _) => {
+                    devices::tpm::Tpm::connect(socket, tpm.ctrl.as_deref(), tpm_address)
+                }
+                (None, Some(device)) => devices::tpm::Tpm::open(device, tpm_address),
+                (None, None) => return Err(DeviceManagerError::MissingTpmBackend),
+            };
+            let tpm_device = Arc::new(Mutex::new(
+                tpm_device.map_err(DeviceManagerError::CreateTpm)?,
+            ));
+            self.address_manager
+                .mmio_bus
+                .insert(
+                    tpm_device.clone(),
+                    tpm_address.0,
+                    devices::tpm::TPM_CRB_SIZE,
+                    "tpm",
+                )
                 .map_err(DeviceManagerError::BusError)?;
+            self.bus_devices
+                .push(Arc::clone(&tpm_device) as Arc<Mutex<dyn BusDevice>>);
+            self.tpm_device = Some(tpm_device);
         }
 
         Ok(Some(ged_device))
@@ -1393,7 +2042,7 @@ impl DeviceManager {
 
         self.address_manager
             .io_bus
-            .insert(i8042, 0x61, 0x4)
+            .insert(i8042, 0x61, 0x4, "i8042")
             .map_err(DeviceManagerError::BusError)?;
         #[cfg(feature = "cmos")]
         {
@@ -1417,10 +2066,11 @@ impl DeviceManager {
 
             self.bus_devices
                 .push(Arc::clone(&cmos) as Arc<Mutex<dyn BusDevice>>);
+            self.cmos = Some(Arc::clone(&cmos));
 
             self.address_manager
                 .io_bus
-                .insert(cmos, 0x70, 0x2)
+                .insert(cmos, 0x70, 0x2, "cmos")
                 .map_err(DeviceManagerError::BusError)?;
         }
         #[cfg(feature = "fwdebug")]
@@ -1432,7 +2082,7 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(fwdebug, 0x402, 0x1)
+                .insert(fwdebug, 0x402, 0x1, "fwdebug")
                 .map_err(DeviceManagerError::BusError)?;
         }
 
@@ -1468,7 +2118,7 @@ impl DeviceManager {
 
         self.address_manager
             .mmio_bus
-            .insert(rtc_device, addr.0, MMIO_LEN)
+            .insert(rtc_device, addr.0, MMIO_LEN, "rtc")
             .map_err(DeviceManagerError::BusError)?;
 
         self.id_to_dev_info.insert(
@@ -1507,7 +2157,7 @@ impl DeviceManager {
 
         self.address_manager
             .mmio_bus
-            .insert(gpio_device.clone(), addr.0, MMIO_LEN)
+            .insert(gpio_device.clone(), addr.0, MMIO_LEN, &id)
             .map_err(DeviceManagerError::BusError)?;
 
         self.gpio_device = Some(gpio_device.clone());
@@ -1563,7 +2213,7 @@ impl DeviceManager {
 
         self.address_manager
             .io_bus
-            .insert(serial.clone(), 0x3f8, 0x8)
+            .insert(serial.clone(), 0x3f8, 0x8, &id)
             .map_err(DeviceManagerError::BusError)?;
 
         // Fill the device tree with a new node. In case of restore, we
@@ -1577,6 +2227,22 @@ impl DeviceManager {
         Ok(serial)
     }
 
+    // Only the writes to the data register are coalesced. Any other access
+    // of the guest exits, the pending writes reaching the device first.
+    fn coalesce_serial_writes(&self) {
+        #[cfg(target_arch = "x86_64")]
+        let (addr, size) = (IoEventAddress::Pio(0x3f8), 1);
+        #[cfg(target_arch = "aarch64")]
+        let (addr, size) = (
+            IoEventAddress::Mmio(arch::layout::LEGACY_SERIAL_MAPPED_IO_START),
+            4,
+        );
+
+        if let Err(e) = self.address_manager.vm.register_coalesced_mmio(&addr, size) {
+            warn!("Serial port writes can't be coalesced: {}", e);
+        }
+    }
+
     #[cfg(target_arch = "aarch64")]
     fn add_serial_device(
         &mut self,
@@ -1612,7 +2278,7 @@ impl DeviceManager {
 
         self.address_manager
             .mmio_bus
-            .insert(serial.clone(), addr.0, MMIO_LEN)
+            .insert(serial.clone(), addr.0, MMIO_LEN, &id)
             .map_err(DeviceManagerError::BusError)?;
 
         self.id_to_dev_info.insert(
@@ -1671,10 +2337,12 @@ impl DeviceManager {
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
-        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, String)>,
+        virtio_devices: &mut Vec<(VirtioDeviceArc, IommuMode, String)>,
         serial_pty: Option<PtyPair>,
         console_pty: Option<PtyPair>,
     ) -> DeviceManagerResult<Arc<Console>> {
+        let api_output = ConsoleBuffer::default();
+
         let serial_config = self.config.lock().unwrap().serial.clone();
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
             ConsoleOutputMode::File => Some(Box::new(
@@ -1699,10 +2367,23 @@ impl DeviceManager {
                 }
             }
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
+            ConsoleOutputMode::Api => Some(Box::new(api_output.clone())),
             ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
         };
+        let serial_writer = match &serial_config.log_file {
+            Some(log_path) => serial_writer
+                .map(|writer| ConsoleTee::new(writer, log_path))
+                .transpose()
+                .map_err(DeviceManagerError::SerialOutputFileOpen)?
+                .map(|tee| Box::new(tee) as Box<dyn io::Write + Send>),
+            None => serial_writer,
+        };
         let serial = if serial_config.mode != ConsoleOutputMode::Off {
-            Some(self.add_serial_device(interrupt_manager, serial_writer)?)
+            let serial = self.add_serial_device(interrupt_manager, serial_writer)?;
+            if serial_config.coalesce {
+                self.coalesce_serial_writes();
+            }
+            Some(serial)
         } else {
             None
         };
@@ -1732,9 +2413,18 @@ impl DeviceManager {
                 }
             }
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
+            ConsoleOutputMode::Api => Some(Box::new(api_output.clone())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
             ConsoleOutputMode::Off => None,
         };
+        let console_writer = match &console_config.log_file {
+            Some(log_path) => console_writer
+                .map(|writer| ConsoleTee::new(writer, log_path))
+                .transpose()
+                .map_err(DeviceManagerError::ConsoleOutputFileOpen)?
+                .map(|tee| Box::new(tee) as Box<dyn io::Write + Send + Sync>),
+            None => console_writer,
+        };
         let (col, row) = get_win_size();
         let virtio_console_input = if let Some(writer) = console_writer {
             let id = String::from(CONSOLE_DEVICE_NAME);
@@ -1744,7 +2434,7 @@ impl DeviceManager {
                 writer,
                 col,
                 row,
-                console_config.iommu,
+                console_config.iommu.enabled(),
                 self.seccomp_action.clone(),
             )
             .map_err(DeviceManagerError::CreateVirtioConsole)?;
@@ -1776,15 +2466,37 @@ impl DeviceManager {
             None
         };
 
+        let api_input = if serial_config.mode == ConsoleOutputMode::Api {
+            Some(ConsoleInput::Serial)
+        } else if console_config.mode == ConsoleOutputMode::Api {
+            Some(ConsoleInput::VirtioConsole)
+        } else {
+            None
+        };
+
         Ok(Arc::new(Console {
             serial,
             virtio_console_input,
             input,
+            api_input,
+            api_output,
+            input_log: self.input_log.clone(),
         }))
     }
 
-    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
-        let mut devices: Vec<(VirtioDeviceArc, bool, String)> = Vec::new();
+    fn make_virtio_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
+        let mut devices: Vec<(VirtioDeviceArc, IommuMode, String)> = Vec::new();
+
+        // Low traffic devices (rng/balloon/watchdog) can be handled from a
+        // single thread instead of getting one thread each.
+        if self.config.lock().unwrap().shared_event_loop {
+            self.shared_event_loop = Some(Arc::new(
+                virtio_devices::SharedEpollLoop::new(&self.seccomp_action)
+                    .map_err(DeviceManagerError::CreateSharedEventLoop)?,
+            ));
+        }
 
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
@@ -1811,10 +2523,141 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    fn open_disk_image(&self, disk_cfg: &DiskConfig) -> DeviceManagerResult<Box<dyn DiskFile>> {
+        let path = disk_cfg
+            .path
+            .as_ref()
+            .ok_or(DeviceManagerError::NoDiskPath)?;
+
+        // Synchronous backends can offload their blocking I/O to a pool
+        // of worker threads so that a slow request doesn't stall the
+        // whole queue.
+        let workers = disk_cfg.workers;
+        let sync_image = |image: Box<dyn DiskFile>| {
+            if workers > 0 {
+                info!("Using {} worker threads for disk I/O", workers);
+                Box::new(ThreadPoolDisk::new(image, workers)) as Box<dyn DiskFile>
+            } else {
+                image
+            }
+        };
+
+        if let Some(uri) = path.to_str().filter(|path| NbdAddress::is_nbd_uri(path)) {
+            let address = NbdAddress::parse(uri).map_err(DeviceManagerError::CreateNbdDiskSync)?;
+            info!("Using synchronous NBD disk {}", uri);
+            return Ok(sync_image(Box::new(
+                NbdDiskSync::new(&address, disk_cfg.readonly)
+                    .map_err(DeviceManagerError::CreateNbdDiskSync)?,
+            )));
+        }
+
+        #[cfg(feature = "rbd")]
+        if let Some(rbd_path) = path.to_str().filter(|path| RbdAddress::is_rbd_path(path)) {
+            // The threads librados spawns would be killed by the filters,
+            // which don't cover their system calls.
+            if !matches!(
+                self.seccomp_action,
+                SeccompAction::Allow | SeccompAction::Log
+            ) {
+                return Err(DeviceManagerError::RbdSeccompEnforced);
+            }
+            let address =
+                RbdAddress::parse(rbd_path).map_err(DeviceManagerError::CreateRbdDiskSync)?;
+            info!("Using synchronous RBD disk {}", rbd_path);
+            return Ok(sync_image(Box::new(
+                RbdDiskSync::new(&address, disk_cfg.readonly)
+                    .map_err(DeviceManagerError::CreateRbdDiskSync)?,
+            )));
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        // The base image is never modified when an overlay is used.
+        options.write(!disk_cfg.readonly && disk_cfg.overlay.is_none());
+        if disk_cfg.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let mut file = Self::open_disk_file(disk_cfg, &options)?;
+        let image_type =
+            detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
+
+        let image = match image_type {
+            ImageType::Raw if disk_cfg.overlay.is_some() => {
+                let overlay = disk_cfg.overlay.as_ref().unwrap();
+                info!("Using synchronous RAW disk file with overlay {:?}", overlay);
+                sync_image(Box::new(
+                    OverlayDiskSync::new(file, &disk_cfg.backing_overlays, overlay)
+                        .map_err(DeviceManagerError::CreateOverlayDiskSync)?,
+                ))
+            }
+            _ if disk_cfg.overlay.is_some() => {
+                return Err(DeviceManagerError::OverlayUnsupportedImageType);
+            }
+            ImageType::FixedVhd => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous fixed VHD disk file (io_uring)");
+                    Box::new(
+                        FixedVhdDiskAsync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
+                    ) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous fixed VHD disk file");
+                    sync_image(Box::new(
+                        FixedVhdDiskSync::new(file)
+                            .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
+                    ))
+                }
+            }
+            ImageType::Raw => {
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
+                    info!("Using asynchronous RAW disk file (io_uring)");
+                    Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
+                } else {
+                    info!("Using synchronous RAW disk file");
+                    sync_image(Box::new(RawFileDiskSync::new(file)))
+                }
+            }
+            ImageType::Qcow2 => {
+                info!("Using synchronous QCOW disk file");
+                sync_image(Box::new(QcowDiskSync::new(file, disk_cfg.direct)))
+            }
+        };
+
+        Ok(image)
+    }
+
+    // Opens the file backing a disk with `options`, unless the disk was given
+    // as a file descriptor, in which case its path might not even be
+    // reachable from the VMM.
+    fn open_disk_file(disk_cfg: &DiskConfig, options: &OpenOptions) -> DeviceManagerResult<File> {
+        if let Some(fd) = disk_cfg.fd {
+            // The file descriptor is duplicated as the devices are created
+            // again from the same configuration when the VM reboots.
+            // Safe because the return value is checked.
+            let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(DeviceManagerError::Disk(io::Error::last_os_error()));
+            }
+            // Safe because we own the fd we just duplicated.
+            Ok(unsafe { File::from_raw_fd(fd) })
+        } else {
+            let path = disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?;
+            // Open block device path
+            options.open(path).map_err(DeviceManagerError::Disk)
+        }
+    }
+
     fn make_virtio_block_device(
         &mut self,
         disk_cfg: &mut DiskConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, IommuMode, String)> {
         let id = if let Some(id) = &disk_cfg.id {
             id.clone()
         } else {
@@ -1841,6 +2684,9 @@ impl DeviceManager {
                 },
             ));
 
+            // The backend might support fewer queues than requested.
+            disk_cfg.num_queues = vhost_user_block_device.lock().unwrap().num_queues();
+
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
             // existing entry.
@@ -1851,63 +2697,11 @@ impl DeviceManager {
 
             Ok((
                 Arc::clone(&vhost_user_block_device) as VirtioDeviceArc,
-                false,
+                IommuMode::Off,
                 id,
             ))
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-            let image_type =
-                detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
-
-            let image = match image_type {
-                ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
-                        info!("Using asynchronous fixed VHD disk file (io_uring)");
-                        Box::new(
-                            FixedVhdDiskAsync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
-                        ) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous fixed VHD disk file");
-                        Box::new(
-                            FixedVhdDiskSync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
-                        ) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if block_io_uring_is_supported() && !disk_cfg.disable_io_uring {
-                        info!("Using asynchronous RAW disk file (io_uring)");
-                        Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Qcow2 => {
-                    info!("Using synchronous QCOW disk file");
-                    Box::new(QcowDiskSync::new(file, disk_cfg.direct)) as Box<dyn DiskFile>
-                }
-            };
+            let image = self.open_disk_image(disk_cfg)?;
 
             let dev = Arc::new(Mutex::new(
                 virtio_devices::Block::new(
@@ -1918,16 +2712,20 @@ impl DeviceManager {
                         .as_ref()
                         .ok_or(DeviceManagerError::NoDiskPath)?
                         .clone(),
+                    disk_cfg.serial.clone(),
                     disk_cfg.readonly,
-                    disk_cfg.iommu,
+                    disk_cfg.iommu.enabled(),
                     disk_cfg.num_queues,
                     disk_cfg.queue_size,
                     self.seccomp_action.clone(),
                     disk_cfg.rate_limiter_config,
+                    disk_cfg.poll_us,
                 )
                 .map_err(DeviceManagerError::CreateVirtioBlock)?,
             ));
 
+            self.block_devices.push((id.clone(), Arc::clone(&dev)));
+
             let virtio_device = Arc::clone(&dev) as VirtioDeviceArc;
             let migratable_device = dev as Arc<Mutex<dyn Migratable>>;
 
@@ -1945,12 +2743,16 @@ impl DeviceManager {
 
     fn make_virtio_block_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
 
         let mut block_devices = self.config.lock().unwrap().disks.clone();
         if let Some(disk_list_cfg) = &mut block_devices {
             for disk_cfg in disk_list_cfg.iter_mut() {
+                // NVMe disks are directly added to the PCI bus.
+                if disk_cfg.model == DiskModel::Nvme {
+                    continue;
+                }
                 devices.push(self.make_virtio_block_device(disk_cfg)?);
             }
         }
@@ -1962,7 +2764,7 @@ impl DeviceManager {
     fn make_virtio_net_device(
         &mut self,
         net_cfg: &mut NetConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, IommuMode, String)> {
         let id = if let Some(id) = &net_cfg.id {
             id.clone()
         } else {
@@ -1987,6 +2789,7 @@ impl DeviceManager {
                 match virtio_devices::vhost_user::Net::new(
                     id.clone(),
                     net_cfg.mac,
+                    net_cfg.mtu,
                     vu_cfg,
                     server,
                     self.seccomp_action.clone(),
@@ -1998,6 +2801,9 @@ impl DeviceManager {
                 },
             ));
 
+            // The backend might support fewer queues than requested.
+            net_cfg.num_queues = vhost_user_net_device.lock().unwrap().num_queues();
+
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
             // existing entry.
@@ -2021,7 +2827,9 @@ impl DeviceManager {
                         None,
                         Some(net_cfg.mac),
                         &mut net_cfg.host_mac,
-                        net_cfg.iommu,
+                        net_cfg.mtu,
+                        net_cfg.standby,
+                        net_cfg.iommu.enabled(),
                         net_cfg.num_queues,
                         net_cfg.queue_size,
                         self.seccomp_action.clone(),
@@ -2035,7 +2843,9 @@ impl DeviceManager {
                         id.clone(),
                         fds,
                         Some(net_cfg.mac),
-                        net_cfg.iommu,
+                        net_cfg.mtu,
+                        net_cfg.standby,
+                        net_cfg.iommu.enabled(),
                         net_cfg.queue_size,
                         self.seccomp_action.clone(),
                         net_cfg.rate_limiter_config,
@@ -2051,7 +2861,9 @@ impl DeviceManager {
                         Some(net_cfg.mask),
                         Some(net_cfg.mac),
                         &mut net_cfg.host_mac,
-                        net_cfg.iommu,
+                        net_cfg.mtu,
+                        net_cfg.standby,
+                        net_cfg.iommu.enabled(),
                         net_cfg.num_queues,
                         net_cfg.queue_size,
                         self.seccomp_action.clone(),
@@ -2061,6 +2873,21 @@ impl DeviceManager {
                 ))
             };
 
+            let rx_filter = match (&net_cfg.rx_filter, &net_cfg.rx_filter_ebpf) {
+                (Some(path), _) => Some(net_util::RxFilter::classic(path)),
+                (None, Some(path)) => Some(net_util::RxFilter::ebpf(path)),
+                (None, None) => None,
+            }
+            .transpose()
+            .map_err(DeviceManagerError::LoadNetRxFilter)?;
+            if let Some(rx_filter) = rx_filter {
+                virtio_net_device
+                    .lock()
+                    .unwrap()
+                    .set_rx_filter(&rx_filter)
+                    .map_err(DeviceManagerError::CreateVirtioNet)?;
+            }
+
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
             // existing entry.
@@ -2069,6 +2896,9 @@ impl DeviceManager {
                 .unwrap()
                 .insert(id.clone(), device_node!(id, virtio_net_device));
 
+            self.net_devices
+                .push((id.clone(), Arc::clone(&virtio_net_device)));
+
             Ok((
                 Arc::clone(&virtio_net_device) as VirtioDeviceArc,
                 net_cfg.iommu,
@@ -2080,7 +2910,7 @@ impl DeviceManager {
     /// Add virto-net and vhost-user-net devices
     fn make_virtio_net_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
         let mut net_devices = self.config.lock().unwrap().net.clone();
         if let Some(net_list_cfg) = &mut net_devices {
@@ -2095,7 +2925,7 @@ impl DeviceManager {
 
     fn make_virtio_rng_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
 
         // Add virtio-rng if required
@@ -2108,11 +2938,23 @@ impl DeviceManager {
                 virtio_devices::Rng::new(
                     id.clone(),
                     rng_path,
-                    rng_config.iommu,
+                    rng_config.iommu.enabled(),
                     self.seccomp_action.clone(),
                 )
                 .map_err(DeviceManagerError::CreateVirtioRng)?,
             ));
+            if let Some(shared_event_loop) = &self.shared_event_loop {
+                virtio_rng_device
+                    .lock()
+                    .unwrap()
+                    .set_shared_event_loop(shared_event_loop.clone());
+            }
+            if let Some(input_log) = &self.input_log {
+                virtio_rng_device
+                    .lock()
+                    .unwrap()
+                    .set_input_log(input_log.clone());
+            }
             devices.push((
                 Arc::clone(&virtio_rng_device) as VirtioDeviceArc,
                 rng_config.iommu,
@@ -2134,7 +2976,7 @@ impl DeviceManager {
     fn make_virtio_fs_device(
         &mut self,
         fs_cfg: &mut FsConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, IommuMode, String)> {
         let id = if let Some(id) = &fs_cfg.id {
             id.clone()
         } else {
@@ -2234,6 +3076,7 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::MemoryManager)?;
 
                 let region_list = vec![VirtioSharedMemory {
+                    id: virtio_devices::vhost_user::VIRTIO_FS_SHM_CACHE_ID,
                     offset: 0,
                     len: cache_size,
                 }];
@@ -2265,11 +3108,18 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::CreateVirtioFs)?,
             ));
 
+            // The backend might support fewer queues than requested.
+            fs_cfg.num_queues = virtio_fs_device.lock().unwrap().num_request_queues();
+
             // Update the device tree with the migratable device.
             node.migratable = Some(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
             self.device_tree.lock().unwrap().insert(id.clone(), node);
 
-            Ok((Arc::clone(&virtio_fs_device) as VirtioDeviceArc, false, id))
+            Ok((
+                Arc::clone(&virtio_fs_device) as VirtioDeviceArc,
+                IommuMode::Off,
+                id,
+            ))
         } else {
             Err(DeviceManagerError::NoVirtioFsSock)
         }
@@ -2277,7 +3127,7 @@ impl DeviceManager {
 
     fn make_virtio_fs_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
 
         let mut fs_devices = self.config.lock().unwrap().fs.clone();
@@ -2294,7 +3144,7 @@ impl DeviceManager {
     fn make_virtio_pmem_device(
         &mut self,
         pmem_cfg: &mut PmemConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, IommuMode, String)> {
         let id = if let Some(id) = &pmem_cfg.id {
             id.clone()
         } else {
@@ -2441,7 +3291,7 @@ impl DeviceManager {
                 GuestAddress(region_base),
                 mapping,
                 mmap_region,
-                pmem_cfg.iommu,
+                pmem_cfg.iommu.enabled(),
                 self.seccomp_action.clone(),
             )
             .map_err(DeviceManagerError::CreateVirtioPmem)?,
@@ -2465,7 +3315,7 @@ impl DeviceManager {
 
     fn make_virtio_pmem_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
         // Add virtio-pmem if required
         let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
@@ -2482,7 +3332,7 @@ impl DeviceManager {
     fn make_virtio_vsock_device(
         &mut self,
         vsock_cfg: &mut VsockConfig,
-    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, String)> {
+    ) -> DeviceManagerResult<(VirtioDeviceArc, IommuMode, String)> {
         let id = if let Some(id) = &vsock_cfg.id {
             id.clone()
         } else {
@@ -2507,7 +3357,8 @@ impl DeviceManager {
                 vsock_cfg.cid,
                 vsock_cfg.socket.clone(),
                 backend,
-                vsock_cfg.iommu,
+                vsock_cfg.iommu.enabled(),
+                vsock_cfg.queue_size,
                 self.seccomp_action.clone(),
             )
             .map_err(DeviceManagerError::CreateVirtioVsock)?,
@@ -2530,7 +3381,7 @@ impl DeviceManager {
 
     fn make_virtio_vsock_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
 
         let mut vsock = self.config.lock().unwrap().vsock.clone();
@@ -2544,7 +3395,7 @@ impl DeviceManager {
 
     fn make_virtio_mem_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
 
         let mm = self.memory_manager.clone();
@@ -2579,7 +3430,7 @@ impl DeviceManager {
 
                 devices.push((
                     Arc::clone(&virtio_mem_device) as VirtioDeviceArc,
-                    false,
+                    IommuMode::Off,
                     id.clone(),
                 ));
 
@@ -2596,45 +3447,68 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_balloon_devices(
+    fn make_virtio_balloon_device(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
-        let mut devices = Vec::new();
-
-        if let Some(balloon_config) = &self.config.lock().unwrap().balloon {
+        balloon_cfg: &mut BalloonConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, IommuMode, String)> {
+        let id = if let Some(id) = &balloon_cfg.id {
+            id.clone()
+        } else {
             let id = String::from(BALLOON_DEVICE_NAME);
-            info!("Creating virtio-balloon device: id = {}", id);
-
-            let virtio_balloon_device = Arc::new(Mutex::new(
-                virtio_devices::Balloon::new(
-                    id.clone(),
-                    balloon_config.size,
-                    balloon_config.deflate_on_oom,
-                    self.seccomp_action.clone(),
-                )
-                .map_err(DeviceManagerError::CreateVirtioBalloon)?,
-            ));
+            balloon_cfg.id = Some(id.clone());
+            id
+        };
 
-            self.balloon = Some(virtio_balloon_device.clone());
+        info!("Creating virtio-balloon device: id = {}", id);
 
-            devices.push((
-                Arc::clone(&virtio_balloon_device) as VirtioDeviceArc,
-                false,
+        let virtio_balloon_device = Arc::new(Mutex::new(
+            virtio_devices::Balloon::new(
                 id.clone(),
-            ));
-
-            self.device_tree
+                balloon_cfg.size,
+                balloon_cfg.deflate_on_oom,
+                balloon_cfg.free_page_reporting,
+                self.seccomp_action.clone(),
+            )
+            .map_err(DeviceManagerError::CreateVirtioBalloon)?,
+        ));
+        if let Some(shared_event_loop) = &self.shared_event_loop {
+            virtio_balloon_device
                 .lock()
                 .unwrap()
-                .insert(id.clone(), device_node!(id, virtio_balloon_device));
+                .set_shared_event_loop(shared_event_loop.clone());
+        }
+
+        self.balloon = Some(virtio_balloon_device.clone());
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, virtio_balloon_device));
+
+        Ok((
+            Arc::clone(&virtio_balloon_device) as VirtioDeviceArc,
+            IommuMode::Off,
+            id,
+        ))
+    }
+
+    fn make_virtio_balloon_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
+        let mut devices = Vec::new();
+
+        let mut balloon = self.config.lock().unwrap().balloon.clone();
+        if let Some(ref mut balloon_cfg) = &mut balloon {
+            devices.push(self.make_virtio_balloon_device(balloon_cfg)?);
         }
+        self.config.lock().unwrap().balloon = balloon;
 
         Ok(devices)
     }
 
     fn make_virtio_watchdog_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, IommuMode, String)>> {
         let mut devices = Vec::new();
 
         if !self.config.lock().unwrap().watchdog {
@@ -2652,9 +3526,15 @@ impl DeviceManager {
             )
             .map_err(DeviceManagerError::CreateVirtioWatchdog)?,
         ));
+        if let Some(shared_event_loop) = &self.shared_event_loop {
+            virtio_watchdog_device
+                .lock()
+                .unwrap()
+                .set_shared_event_loop(shared_event_loop.clone());
+        }
         devices.push((
             Arc::clone(&virtio_watchdog_device) as VirtioDeviceArc,
-            false,
+            IommuMode::Off,
             id.clone(),
         ));
 
@@ -2717,23 +3597,134 @@ impl DeviceManager {
         pci: &mut PciBus,
         device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<(u32, String)> {
+        if device_cfg.managed {
+            device_cfg.path = vfio_functions::function_path(&device_cfg.path);
+        }
+        let functions = vfio_functions::device_functions(&device_cfg.path)
+            .map_err(DeviceManagerError::VfioFunctions)?;
+
+        if !device_cfg.all_functions {
+            // Resetting the function would reset the other ones as well,
+            // unless they're assigned to the VM too.
+            let assigned: Vec<PathBuf> = self
+                .config
+                .lock()
+                .unwrap()
+                .devices
+                .iter()
+                .flatten()
+                .map(|device| device.path.clone())
+                .collect();
+            let unisolated =
+                vfio_functions::unisolated_functions(&device_cfg.path, &functions, &assigned);
+            if !unisolated.is_empty() {
+                return Err(DeviceManagerError::VfioFunctionNotIsolated(
+                    device_cfg.path.clone(),
+                    unisolated,
+                ));
+            }
+            if device_cfg.managed {
+                self.bind_vfio_function(&device_cfg.path)?;
+            }
+
+            // We need to shift the device id since the 3 first bits
+            // are dedicated to the PCI function. The bus number is
+            // already part of the device id.
+            let pci_device_bdf = self.next_pci_device_id(pci, device_cfg.iommu.enabled())? << 3;
+            let (vfio_device, vfio_container) =
+                self.open_vfio_device(&device_cfg.path, device_cfg.iommu.enabled())?;
+            let vfio_name = self.add_vfio_function(
+                pci,
+                device_cfg,
+                pci_device_bdf,
+                vfio_device,
+                vfio_container,
+                false,
+            )?;
+            return Ok((pci_device_bdf, vfio_name));
+        }
+
+        // All the functions share the container of their IOMMU group.
+        let path = device_cfg
+            .path
+            .canonicalize()
+            .map_err(DeviceManagerError::VfioFunctions)?;
+        if !functions.iter().any(|f| f.path == path) {
+            return Err(DeviceManagerError::VfioNotPciFunction(
+                device_cfg.path.clone(),
+            ));
+        }
+        if device_cfg.managed {
+            for function in functions.iter() {
+                self.bind_vfio_function(&function.path)?;
+            }
+        }
+        // The slot is placed according to the IOMMU, as the ones of the
+        // single functions are.
+        let pci_slot_bdf = self.next_pci_device_id(pci, device_cfg.iommu.enabled())? << 3;
+
+        let mut vfio_container = None;
+        let mut assigned = None;
+        for function in functions {
+            let pci_device_bdf = pci_slot_bdf | function.function as u32;
+            let (vfio_device, container) = match &vfio_container {
+                Some(container) => (
+                    VfioDevice::new(&function.path, Arc::clone(container))
+                        .map_err(DeviceManagerError::VfioCreate)?,
+                    Arc::clone(container),
+                ),
+                None => self.open_vfio_device(&function.path, device_cfg.iommu.enabled())?,
+            };
+            vfio_container = Some(container.clone());
+            if function.path == path {
+                let vfio_name = self.add_vfio_function(
+                    pci,
+                    device_cfg,
+                    pci_device_bdf,
+                    vfio_device,
+                    container,
+                    true,
+                )?;
+                assigned = Some((pci_device_bdf, vfio_name));
+            } else {
+                let mut function_cfg = DeviceConfig {
+                    path: function.path,
+                    iommu: device_cfg.iommu,
+                    id: None,
+                    all_functions: false,
+                    managed: false,
+                    failover: None,
+                };
+                self.add_vfio_function(
+                    pci,
+                    &mut function_cfg,
+                    pci_device_bdf,
+                    vfio_device,
+                    container,
+                    true,
+                )?;
+            }
+        }
+
+        assigned.ok_or_else(|| DeviceManagerError::VfioNotPciFunction(device_cfg.path.clone()))
+    }
+
+    #[cfg(feature = "kvm")]
+    fn bind_vfio_function(&mut self, path: &Path) -> DeviceManagerResult<()> {
+        let binding = vfio_functions::bind_to_vfio_pci(path)
+            .map_err(|e| DeviceManagerError::VfioBind(path.to_path_buf(), e))?;
+        self.vfio_bindings.extend(binding);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "kvm")]
+    fn create_vfio_container(&self) -> DeviceManagerResult<Arc<VfioContainer>> {
         let passthrough_device = self
             .passthrough_device
             .as_ref()
             .ok_or(DeviceManagerError::NoDevicePassthroughSupport)?;
 
-        // We need to shift the device id since the 3 first bits
-        // are dedicated to the PCI function, and we know we don't
-        // do multifunction. Also, because we only support one PCI
-        // bus, the bus 0, we don't need to add anything to the
-        // global device ID.
-        let pci_device_bdf = pci
-            .next_device_id()
-            .map_err(DeviceManagerError::NextPciDeviceId)?
-            << 3;
-
-        let memory = self.memory_manager.lock().unwrap().guest_memory();
-
         // Safe because we know the RawFd is valid.
         //
         // This dup() is mandatory to be able to give full ownership of the
@@ -2753,32 +3744,280 @@ impl DeviceManager {
         //   2. When running on KVM, passthrough_device wraps around DeviceFd.
         //   3. The conversion here extracts the raw fd and then turns the raw fd into a DeviceFd
         //      of the same (correct) type.
-        let vfio_container = Arc::new(
+        Ok(Arc::new(
             VfioContainer::new(Arc::new(unsafe { DeviceFd::from_raw_fd(dup_device_fd) }))
                 .map_err(DeviceManagerError::VfioCreate)?,
-        );
+        ))
+    }
 
-        let vfio_device = VfioDevice::new(&device_cfg.path, Arc::clone(&vfio_container))
+    // Opens the VFIO device at `path`. The devices which are not attached to
+    // the virtual IOMMU share a container, the guest memory being mapped in
+    // it, as long as the host IOMMU can place their group in it. The other
+    // ones get a container of their own, mapped by the guest through the
+    // virtual IOMMU.
+    #[cfg(feature = "kvm")]
+    fn open_vfio_device(
+        &mut self,
+        path: &Path,
+        iommu: bool,
+    ) -> DeviceManagerResult<(VfioDevice, Arc<VfioContainer>)> {
+        if iommu {
+            let vfio_container = self.create_vfio_container()?;
+            let vfio_device = VfioDevice::new(path, Arc::clone(&vfio_container))
+                .map_err(DeviceManagerError::VfioCreate)?;
+            return Ok((vfio_device, vfio_container));
+        }
+
+        for container in self.vfio_containers.iter() {
+            match VfioDevice::new(path, Arc::clone(&container.container)) {
+                Ok(vfio_device) => return Ok((vfio_device, Arc::clone(&container.container))),
+                Err(e) => debug!("Could not share a VFIO container with {:?}: {}", path, e),
+            }
+        }
+
+        let vfio_container = self.create_vfio_container()?;
+        let vfio_device = VfioDevice::new(path, Arc::clone(&vfio_container))
             .map_err(DeviceManagerError::VfioCreate)?;
+        self.map_vfio_container(Arc::clone(&vfio_container))?;
+
+        Ok((vfio_device, vfio_container))
+    }
+
+    // Maps the guest memory in a container the VFIO devices share, and lets
+    // the virtio-mem devices update the mappings. Pinning a large guest
+    // memory takes seconds, that's why it's done by a thread, overlapping
+    // with the rest of the VM creation, until `wait_vfio_dma_mappings()`.
+    #[cfg(feature = "kvm")]
+    fn map_vfio_container(
+        &mut self,
+        vfio_container: Arc<VfioContainer>,
+    ) -> DeviceManagerResult<()> {
+        // Do not register virtio-mem regions, as they are handled directly by
+        // virtio-mem device itself.
+        let mut ranges = Vec::new();
+        for (_, zone) in self.memory_manager.lock().unwrap().memory_zones().iter() {
+            for region in zone.regions() {
+                ranges.push((
+                    region.start_addr().raw_value(),
+                    region.len() as u64,
+                    region.as_ptr() as u64,
+                ));
+            }
+        }
+        let ranges = merge_dma_ranges(ranges);
+
+        // The pages of the chunks are pinned by several threads, the mapping
+        // thread being one of them.
+        let chunks = split_dma_ranges(&ranges, VFIO_DMA_MAP_CHUNK_SIZE);
+        let threads = std::cmp::min(VFIO_DMA_MAP_THREADS, chunks.len());
+        let chunks = Arc::new(Mutex::new(chunks.into_iter()));
+        let container = Arc::clone(&vfio_container);
+        let mapping_thread = thread::Builder::new()
+            .name("vfio-dma-map".to_string())
+            .spawn(move || {
+                let workers: Vec<_> = (1..threads)
+                    .filter_map(|_| {
+                        let container = Arc::clone(&container);
+                        let chunks = Arc::clone(&chunks);
+                        thread::Builder::new()
+                            .name("vfio-dma-map".to_string())
+                            .spawn(move || map_dma_chunks(&container, &chunks))
+                            .map_err(|e| warn!("Error spawning a VFIO DMA mapping thread: {}", e))
+                            .ok()
+                    })
+                    .collect();
+
+                let mut result = map_dma_chunks(&container, &chunks);
+                for worker in workers {
+                    let worker_result = worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                    result = result.and(worker_result);
+                }
+                result
+            })
+            .map_err(DeviceManagerError::VfioDmaMapThreadSpawn)?;
 
+        let id = self.vfio_container_id;
+        self.vfio_container_id = self.vfio_container_id.wrapping_add(1);
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
         let vfio_mapping = Arc::new(VfioDmaMapping::new(
             Arc::clone(&vfio_container),
             Arc::new(memory),
         ));
-        if device_cfg.iommu {
-            if let Some(iommu) = &self.iommu_device {
-                iommu
-                    .lock()
-                    .unwrap()
-                    .add_external_mapping(pci_device_bdf, vfio_mapping);
+        for virtio_mem_device in self.virtio_mem_devices.iter() {
+            virtio_mem_device
+                .lock()
+                .unwrap()
+                .add_dma_mapping_handler(id, vfio_mapping.clone())
+                .map_err(DeviceManagerError::AddDmaMappingHandlerVirtioMem)?;
+        }
+
+        self.vfio_containers.push(VfioContainerMapping {
+            container: vfio_container,
+            id,
+            devices: Vec::new(),
+            ranges,
+            mapping_thread: Some(mapping_thread),
+        });
+
+        Ok(())
+    }
+
+    /// Waits for the guest memory to be mapped in the VFIO containers, which
+    /// must be done before the guest runs.
+    #[cfg(feature = "kvm")]
+    pub fn wait_vfio_dma_mappings(&mut self) -> DeviceManagerResult<()> {
+        for container in self.vfio_containers.iter_mut() {
+            container.wait()?;
+        }
+
+        Ok(())
+    }
+
+    // Forgets about the VFIO device at `pci_device_bdf`, unmapping the guest
+    // memory from its container if no other device shares it.
+    #[cfg(feature = "kvm")]
+    fn remove_vfio_container_device(&mut self, pci_device_bdf: u32) -> DeviceManagerResult<()> {
+        let index = match self
+            .vfio_containers
+            .iter()
+            .position(|c| c.devices.contains(&pci_device_bdf))
+        {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let container = &mut self.vfio_containers[index];
+        container.devices.retain(|bdf| *bdf != pci_device_bdf);
+        if !container.devices.is_empty() {
+            return Ok(());
+        }
+
+        self.unmap_vfio_container(index)
+    }
+
+    // Forgets about the VFIO device at `pci_device_bdf`, which failed to be
+    // assigned behind `vfio_container`, unmapping the guest memory from the
+    // container if no other device shares it.
+    #[cfg(feature = "kvm")]
+    fn release_vfio_container(
+        &mut self,
+        vfio_container: &Arc<VfioContainer>,
+        pci_device_bdf: u32,
+    ) -> DeviceManagerResult<()> {
+        let index = match self
+            .vfio_containers
+            .iter()
+            .position(|c| Arc::ptr_eq(&c.container, vfio_container))
+        {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let container = &mut self.vfio_containers[index];
+        container.devices.retain(|bdf| *bdf != pci_device_bdf);
+        if !container.devices.is_empty() {
+            return Ok(());
+        }
+
+        self.unmap_vfio_container(index)
+    }
+
+    // Unmaps the guest memory from the shared VFIO container at `index`,
+    // which is dropped.
+    #[cfg(feature = "kvm")]
+    fn unmap_vfio_container(&mut self, index: usize) -> DeviceManagerResult<()> {
+        let mut container = self.vfio_containers.remove(index);
+        container.wait()?;
+        // Do not unregister the virtio-mem region, as it is directly handled
+        // by the virtio-mem device.
+        for (gpa, size, _) in container.ranges.iter() {
+            container
+                .container
+                .vfio_dma_unmap(*gpa, *size)
+                .map_err(DeviceManagerError::VfioDmaUnmap)?;
+        }
+        for virtio_mem_device in self.virtio_mem_devices.iter() {
+            virtio_mem_device
+                .lock()
+                .unwrap()
+                .remove_dma_mapping_handler(container.id)
+                .map_err(DeviceManagerError::RemoveDmaMappingHandlerVirtioMem)?;
+        }
+
+        Ok(())
+    }
+
+    // Assigns the function `device_cfg` describes at `pci_device_bdf`, behind
+    // the container `open_vfio_device()` placed it in. The device is only
+    // exposed as multifunction if `multifunction` is set. If the function
+    // can't be assigned, the container is released, and unmapped unless
+    // another device uses it.
+    #[cfg(feature = "kvm")]
+    fn add_vfio_function(
+        &mut self,
+        pci: &mut PciBus,
+        device_cfg: &mut DeviceConfig,
+        pci_device_bdf: u32,
+        vfio_device: VfioDevice,
+        vfio_container: Arc<VfioContainer>,
+        multifunction: bool,
+    ) -> DeviceManagerResult<String> {
+        let result = self.assign_vfio_function(
+            pci,
+            device_cfg,
+            pci_device_bdf,
+            vfio_device,
+            Arc::clone(&vfio_container),
+            multifunction,
+        );
+        if result.is_err() {
+            if device_cfg.iommu.enabled() {
+                if let Some(iommu) = &self.iommu_device {
+                    iommu
+                        .lock()
+                        .unwrap()
+                        .remove_external_mapping(pci_device_bdf);
+                }
             }
-        } else {
-            for virtio_mem_device in self.virtio_mem_devices.iter() {
-                virtio_mem_device
-                    .lock()
-                    .unwrap()
-                    .add_dma_mapping_handler(pci_device_bdf, vfio_mapping.clone())
-                    .map_err(DeviceManagerError::AddDmaMappingHandlerVirtioMem)?;
+            if let Err(e) = self.release_vfio_container(&vfio_container, pci_device_bdf) {
+                error!(
+                    "Error releasing the VFIO container of {:?}: {:?}",
+                    device_cfg.path, e
+                );
+            }
+        }
+
+        result
+    }
+
+    #[cfg(feature = "kvm")]
+    fn assign_vfio_function(
+        &mut self,
+        pci: &mut PciBus,
+        device_cfg: &mut DeviceConfig,
+        pci_device_bdf: u32,
+        vfio_device: VfioDevice,
+        vfio_container: Arc<VfioContainer>,
+        multifunction: bool,
+    ) -> DeviceManagerResult<String> {
+        if device_cfg.iommu.enabled() {
+            if let Some(iommu) = &self.iommu_device {
+                let memory = self.memory_manager.lock().unwrap().guest_memory();
+                let vfio_mapping = Arc::new(VfioDmaMapping::new(
+                    Arc::clone(&vfio_container),
+                    Arc::new(memory),
+                ));
+                let mut iommu = iommu.lock().unwrap();
+                iommu.add_external_mapping(pci_device_bdf, vfio_mapping);
+                // The guest must not allocate the IOVAs the host IOMMU
+                // reserves, the DMA to them would silently fail.
+                match vfio_iova_ranges(&vfio_container) {
+                    Ok(ranges) => iommu.add_host_iova_ranges(pci_device_bdf, &ranges),
+                    Err(e) => warn!(
+                        "Could not get the host IOVA ranges of {:?}: {}",
+                        device_cfg.path, e
+                    ),
+                }
             }
         }
 
@@ -2788,7 +4027,8 @@ impl DeviceManager {
             Some(
                 legacy_interrupt_manager
                     .create_group(LegacyIrqGroupConfig {
-                        irq: self.pci_irq_slots[(pci_device_bdf >> 3) as usize] as InterruptIndex,
+                        irq: self.pci_irq_slots[pci.root_device_id(pci_device_bdf >> 3) as usize]
+                            as InterruptIndex,
                     })
                     .map_err(DeviceManagerError::CreateInterruptGroup)?,
             )
@@ -2799,12 +4039,21 @@ impl DeviceManager {
         let mut vfio_pci_device = VfioPciDevice::new(
             &self.address_manager.vm,
             vfio_device,
-            vfio_container,
+            Arc::clone(&vfio_container),
             &self.msi_interrupt_manager,
             legacy_interrupt_group,
-            device_cfg.iommu,
+            device_cfg.iommu.enabled(),
         )
         .map_err(DeviceManagerError::VfioPciCreate)?;
+        vfio_pci_device.set_multifunction(multifunction);
+
+        // The function is only bound back to its driver once the device is
+        // closed.
+        if let Ok(path) = device_cfg.path.canonicalize() {
+            for binding in self.vfio_bindings.iter_mut().filter(|b| b.path() == path) {
+                binding.set_device(vfio_pci_device.vfio_device());
+            }
+        }
 
         let vfio_name = if let Some(id) = &device_cfg.id {
             if self.device_tree.lock().unwrap().contains_key(id) {
@@ -2818,6 +4067,22 @@ impl DeviceManager {
             id
         };
 
+        // Guest drivers could bind to the wrong device if a virtio device
+        // was exposing the same subsystem IDs as the assigned device.
+        let subsystem_id =
+            subsystem_id_from_register(vfio_pci_device.read_config_register(SUBSYSTEM_ID_REG));
+        if let Some(pci_subsystems) = &self.config.lock().unwrap().pci_subsystems {
+            if let Some(pci_subsystem) = pci_subsystems
+                .iter()
+                .find(|s| (s.vendor, s.device) == subsystem_id)
+            {
+                return Err(DeviceManagerError::PciSubsystemIdClash(
+                    pci_subsystem.id.clone(),
+                    vfio_name,
+                ));
+            }
+        }
+
         vfio_pci_device
             .map_mmio_regions(&self.address_manager.vm, || {
                 self.memory_manager.lock().unwrap().allocate_memory_slot()
@@ -2833,19 +4098,14 @@ impl DeviceManager {
             });
         }
 
-        // Register DMA mapping in IOMMU.
-        // Do not register virtio-mem regions, as they are handled directly by
-        // virtio-mem device itself.
-        for (_, zone) in self.memory_manager.lock().unwrap().memory_zones().iter() {
-            for region in zone.regions() {
-                vfio_pci_device
-                    .dma_map(
-                        region.start_addr().raw_value(),
-                        region.len() as u64,
-                        region.as_ptr() as u64,
-                    )
-                    .map_err(DeviceManagerError::VfioDmaMap)?;
-            }
+        // The guest memory stays mapped in the container as long as a device
+        // is assigned behind it.
+        if let Some(container) = self
+            .vfio_containers
+            .iter_mut()
+            .find(|c| Arc::ptr_eq(&c.container, &vfio_container))
+        {
+            container.devices.push(pci_device_bdf);
         }
 
         let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
@@ -2855,6 +4115,7 @@ impl DeviceManager {
             vfio_pci_device.clone(),
             vfio_pci_device.clone(),
             pci_device_bdf,
+            &vfio_name,
         )?;
 
         node.pci_bdf = Some(pci_device_bdf);
@@ -2865,7 +4126,7 @@ impl DeviceManager {
             .unwrap()
             .insert(vfio_name.clone(), node);
 
-        Ok((pci_device_bdf, vfio_name))
+        Ok(vfio_name)
     }
 
     fn add_pci_device(
@@ -2874,6 +4135,7 @@ impl DeviceManager {
         bus_device: Arc<Mutex<dyn BusDevice>>,
         pci_device: Arc<Mutex<dyn PciDevice>>,
         bdf: u32,
+        id: &str,
     ) -> DeviceManagerResult<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>> {
         let bars = pci_device
             .lock()
@@ -2890,6 +4152,7 @@ impl DeviceManager {
         pci_bus
             .register_mapping(
                 bus_device,
+                id,
                 #[cfg(target_arch = "x86_64")]
                 self.address_manager.io_bus.as_ref(),
                 self.address_manager.mmio_bus.as_ref(),
@@ -2907,7 +4170,7 @@ impl DeviceManager {
         if let Some(device_list_cfg) = &mut devices {
             for device_cfg in device_list_cfg.iter_mut() {
                 let (device_id, _) = self.add_passthrough_device(pci, device_cfg)?;
-                if device_cfg.iommu && self.iommu_device.is_some() {
+                if device_cfg.iommu.enabled() && self.iommu_device.is_some() {
                     iommu_attached_device_ids.push(device_id);
                 }
             }
@@ -2919,11 +4182,229 @@ impl DeviceManager {
         Ok(iommu_attached_device_ids)
     }
 
+    fn add_nvme_device(
+        &mut self,
+        pci: &mut PciBus,
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<()> {
+        let id = if let Some(id) = &disk_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(DISK_DEVICE_NAME_PREFIX)?;
+            disk_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating NVMe device: {:?}", disk_cfg);
+
+        let image = self.open_disk_image(disk_cfg)?;
+
+        // We need to shift the device id since the 3 first bits are dedicated
+        // to the PCI function, and we know we don't do multifunction.
+        let pci_device_bdf = pci
+            .next_device_id()
+            .map_err(DeviceManagerError::NextPciDeviceId)?
+            << 3;
+
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::Nvme)
+            .map_err(DeviceManagerError::CreateSeccompFilter)?;
+        let nvme_device = Arc::new(Mutex::new(
+            NvmeController::new(
+                id.clone(),
+                memory,
+                image,
+                disk_cfg.readonly,
+                disk_cfg.num_queues as u16,
+                disk_cfg.queue_size,
+                &self.msi_interrupt_manager,
+                pci_device_bdf,
+                seccomp_filter,
+            )
+            .map_err(DeviceManagerError::CreateNvmeController)?,
+        ));
+
+        let bars = self.add_pci_device(
+            pci,
+            nvme_device.clone(),
+            nvme_device.clone(),
+            pci_device_bdf,
+            &id,
+        )?;
+
+        let mut node = device_node!(id, nvme_device);
+        for pci_bar in bars.iter() {
+            node.resources.push(Resource::MmioAddressRange {
+                base: pci_bar.0.raw_value(),
+                size: pci_bar.1 as u64,
+            });
+        }
+        node.pci_bdf = Some(pci_device_bdf);
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    fn add_nvme_devices(&mut self, pci: &mut PciBus) -> DeviceManagerResult<()> {
+        let mut block_devices = self.config.lock().unwrap().disks.clone();
+        if let Some(disk_list_cfg) = &mut block_devices {
+            for disk_cfg in disk_list_cfg.iter_mut() {
+                if disk_cfg.model == DiskModel::Nvme {
+                    self.add_nvme_device(pci, disk_cfg)?;
+                }
+            }
+        }
+        self.config.lock().unwrap().disks = block_devices;
+
+        Ok(())
+    }
+
+    fn add_usb_controller(&mut self, pci: &mut PciBus) -> DeviceManagerResult<()> {
+        let mut usb_devices = self.config.lock().unwrap().usb.clone();
+        let usb_list_cfg = match &mut usb_devices {
+            Some(usb_list_cfg) if !usb_list_cfg.is_empty() => usb_list_cfg,
+            _ => return Ok(()),
+        };
+
+        let mut host_devices = Vec::new();
+        let mut usb_ids = Vec::new();
+        for usb_cfg in usb_list_cfg.iter_mut() {
+            let id = if let Some(id) = &usb_cfg.id {
+                id.clone()
+            } else {
+                let id = self.next_device_name(USB_DEVICE_NAME_PREFIX)?;
+                usb_cfg.id = Some(id.clone());
+                id
+            };
+
+            info!("Opening host USB device: {:?}", usb_cfg);
+
+            host_devices.push(
+                UsbHostDevice::new(&usb_cfg.path).map_err(DeviceManagerError::OpenUsbDevice)?,
+            );
+            usb_ids.push(id);
+        }
+
+        let id = XHCI_DEVICE_NAME.to_string();
+
+        // We need to shift the device id since the 3 first bits are dedicated
+        // to the PCI function, and we know we don't do multifunction.
+        let pci_device_bdf = pci
+            .next_device_id()
+            .map_err(DeviceManagerError::NextPciDeviceId)?
+            << 3;
+
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::Xhci)
+            .map_err(DeviceManagerError::CreateSeccompFilter)?;
+        let xhci_device = Arc::new(Mutex::new(
+            XhciController::new(
+                id.clone(),
+                memory,
+                host_devices,
+                &self.msi_interrupt_manager,
+                pci_device_bdf,
+                seccomp_filter,
+            )
+            .map_err(DeviceManagerError::CreateUsbController)?,
+        ));
+
+        let bars = self.add_pci_device(
+            pci,
+            xhci_device.clone(),
+            xhci_device.clone(),
+            pci_device_bdf,
+            &id,
+        )?;
+
+        let mut node = device_node!(id, xhci_device);
+        for pci_bar in bars.iter() {
+            node.resources.push(Resource::MmioAddressRange {
+                base: pci_bar.0.raw_value(),
+                size: pci_bar.1 as u64,
+            });
+        }
+        node.pci_bdf = Some(pci_device_bdf);
+        node.children = usb_ids.clone();
+
+        let mut device_tree = self.device_tree.lock().unwrap();
+        device_tree.insert(id.clone(), node);
+        for usb_id in usb_ids {
+            let mut usb_node = device_node!(usb_id);
+            usb_node.parent = Some(id.clone());
+            device_tree.insert(usb_id, usb_node);
+        }
+        drop(device_tree);
+
+        // Update the list of USB devices
+        self.config.lock().unwrap().usb = usb_devices;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn add_vtd_device(&mut self) -> DeviceManagerResult<(GuestAddress, Arc<dyn DmaRemapping>)> {
+        let vtd_address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_mmio_addresses(None, devices::vtd::VTD_SIZE, Some(devices::vtd::VTD_SIZE))
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+
+        let vtd = devices::vtd::Vtd::new(self.memory_manager.lock().unwrap().guest_memory());
+        let vtd_mapping = vtd.mapping();
+        let vtd = Arc::new(Mutex::new(vtd));
+
+        self.address_manager
+            .mmio_bus
+            .insert(vtd.clone(), vtd_address.0, devices::vtd::VTD_SIZE, "vtd")
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&vtd) as Arc<Mutex<dyn BusDevice>>);
+
+        Ok((vtd_address, Arc::new(VtdRemapping(vtd_mapping))))
+    }
+
+    // PCI BDF of the devices described as behind the IOMMU, if any.
+    fn iommu_attached_bdfs(&self) -> Option<&Vec<u32>> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some((_, bdfs)) = &self.vtd_attached_devices {
+            return Some(bdfs);
+        }
+
+        self.iommu_attached_devices.as_ref().map(|(_, bdfs)| bdfs)
+    }
+
+    // Once the VM is booted, the free slots of the root bus described as
+    // behind the IOMMU are kept for the devices attached to it, as the guest
+    // expects their DMA to be translated.
+    fn next_pci_device_id(
+        &self,
+        pci: &mut PciBus,
+        iommu_attached: bool,
+    ) -> DeviceManagerResult<u32> {
+        if let Some(iommu_attached_devices) = self.iommu_attached_bdfs() {
+            for device_id in pci.free_root_device_ids() {
+                if iommu_attached_devices.contains(&(device_id << 3)) == iommu_attached {
+                    pci.get_device_id(device_id as usize)
+                        .map_err(DeviceManagerError::GetPciDeviceId)?;
+                    return Ok(device_id);
+                }
+            }
+
+            return Err(DeviceManagerError::NoIommuCompatiblePciSlot);
+        }
+
+        pci.next_device_id()
+            .map_err(DeviceManagerError::NextPciDeviceId)
+    }
+
     fn add_virtio_pci_device(
         &mut self,
         virtio_device: VirtioDeviceArc,
         pci: &mut PciBus,
-        iommu_mapping: &Option<Arc<IommuMapping>>,
+        iommu_mapping: &Option<Arc<dyn DmaRemapping>>,
         virtio_device_id: String,
     ) -> DeviceManagerResult<u32> {
         let id = format!("{}-{}", VIRTIO_PCI_DEVICE_NAME_PREFIX, virtio_device_id);
@@ -2962,12 +4443,8 @@ impl DeviceManager {
             } else {
                 // We need to shift the device id since the 3 first bits are dedicated
                 // to the PCI function, and we know we don't do multifunction.
-                // Also, because we only support one PCI bus, the bus 0, we don't need
-                // to add anything to the global device ID.
-                let pci_device_bdf = pci
-                    .next_device_id()
-                    .map_err(DeviceManagerError::NextPciDeviceId)?
-                    << 3;
+                // The bus number is already part of the device id.
+                let pci_device_bdf = self.next_pci_device_id(pci, iommu_mapping.is_some())? << 3;
 
                 (pci_device_bdf, None)
             };
@@ -3005,7 +4482,12 @@ impl DeviceManager {
                 None
             };
 
-        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        virtio_device
+            .lock()
+            .unwrap()
+            .set_worker_supervisor(self.worker_supervisor.clone());
+
+        let memory = self.memory_manager.lock().unwrap().virtio_memory();
         let mut virtio_pci_device = VirtioPciDevice::new(
             id.clone(),
             memory,
@@ -3026,12 +4508,35 @@ impl DeviceManager {
             virtio_pci_device.set_config_bar_addr(addr);
         }
 
+        if let Some((vendor, device)) = self.pci_subsystem_id(&virtio_device_id)? {
+            virtio_pci_device.set_subsystem_id(vendor, device);
+        }
+
+        if let Some(features_mask) = self.virtio_features_mask(&virtio_device_id) {
+            virtio_pci_device.set_features_mask(features_mask);
+        }
+
+        // The driver must go through the DMA API of the guest, for its
+        // buffers to be bounced.
+        if self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|p| p.bounce_buffer_size)
+            .is_some()
+        {
+            virtio_pci_device.set_access_platform();
+        }
+
         let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
         let bars = self.add_pci_device(
             pci,
             virtio_pci_device.clone(),
             virtio_pci_device.clone(),
             pci_device_bdf,
+            &id,
         )?;
 
         let bar_addr = virtio_pci_device.lock().unwrap().config_bar_addr();
@@ -3058,6 +4563,49 @@ impl DeviceManager {
         Ok(pci_device_bdf)
     }
 
+    // Returns the subsystem IDs requested for the given virtio device, after
+    // making sure they don't clash with the ones of any assigned device.
+    fn pci_subsystem_id(&self, virtio_device_id: &str) -> DeviceManagerResult<Option<(u16, u16)>> {
+        let subsystem_id = match self
+            .config
+            .lock()
+            .unwrap()
+            .pci_subsystems
+            .as_ref()
+            .and_then(|l| l.iter().find(|s| s.id == virtio_device_id))
+        {
+            Some(s) => (s.vendor, s.device),
+            None => return Ok(None),
+        };
+
+        for (id, node) in self.device_tree.lock().unwrap().iter() {
+            if let Some(PciDeviceHandle::Vfio(vfio_pci_device)) = &node.pci_device_handle {
+                let register = vfio_pci_device
+                    .lock()
+                    .unwrap()
+                    .read_config_register(SUBSYSTEM_ID_REG);
+                if subsystem_id_from_register(register) == subsystem_id {
+                    return Err(DeviceManagerError::PciSubsystemIdClash(
+                        virtio_device_id.to_owned(),
+                        id.clone(),
+                    ));
+                }
+            }
+        }
+
+        Ok(Some(subsystem_id))
+    }
+
+    fn virtio_features_mask(&self, virtio_device_id: &str) -> Option<u64> {
+        self.config
+            .lock()
+            .unwrap()
+            .virtio_features
+            .as_ref()
+            .and_then(|l| l.iter().find(|f| f.id == virtio_device_id))
+            .map(|f| f.mask())
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn io_bus(&self) -> &Arc<Bus> {
         &self.address_manager.io_bus
@@ -3081,11 +4629,38 @@ impl DeviceManager {
         &self.console
     }
 
+    /// Brings the devices back to their power-on state on a reboot.
+    pub fn reset(&mut self) -> DeviceManagerResult<()> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(interrupt_controller) = &self.interrupt_controller {
+            interrupt_controller
+                .lock()
+                .unwrap()
+                .reset()
+                .map_err(DeviceManagerError::ResetInterruptController)?;
+        }
+
+        #[cfg(feature = "acpi")]
+        if let Some(tpm_device) = &self.tpm_device {
+            tpm_device
+                .lock()
+                .unwrap()
+                .reset()
+                .map_err(DeviceManagerError::ResetTpm)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn input_log(&self) -> Option<&Arc<InputLog>> {
+        self.input_log.as_ref()
+    }
+
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
     }
 
-    pub fn update_memory(&self, new_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
+    pub fn update_memory(&mut self, new_region: &Arc<GuestRegionMmap>) -> DeviceManagerResult<()> {
         for (virtio_device, _, _) in self.virtio_devices.iter() {
             virtio_device
                 .lock()
@@ -3096,43 +4671,93 @@ impl DeviceManager {
 
         // Take care of updating the memory for VFIO PCI devices.
         #[cfg(feature = "kvm")]
-        {
-            let device_tree = self.device_tree.lock().unwrap();
-            for pci_device_node in device_tree.pci_devices() {
-                if let PciDeviceHandle::Vfio(vfio_pci_device) = pci_device_node
-                    .pci_device_handle
-                    .as_ref()
-                    .ok_or(DeviceManagerError::MissingPciDevice)?
-                {
-                    vfio_pci_device
-                        .lock()
-                        .unwrap()
-                        .dma_map(
-                            new_region.start_addr().raw_value(),
-                            new_region.len() as u64,
-                            new_region.as_ptr() as u64,
-                        )
-                        .map_err(DeviceManagerError::UpdateMemoryForVfioPciDevice)?;
-                }
+        for container in self.vfio_containers.iter_mut() {
+            let range = (
+                new_region.start_addr().raw_value(),
+                new_region.len() as u64,
+                new_region.as_ptr() as u64,
+            );
+            container
+                .container
+                .vfio_dma_map(range.0, range.1, range.2)
+                .map_err(DeviceManagerError::UpdateMemoryForVfioPciDevice)?;
+            container.ranges.push(range);
+        }
+
+        Ok(())
+    }
+
+    pub fn activate_virtio_devices(&self) -> DeviceManagerResult<()> {
+        // Find virtio pci devices and activate any pending ones
+        let device_tree = self.device_tree.lock().unwrap();
+        for pci_device_node in device_tree.pci_devices() {
+            #[allow(irrefutable_let_patterns)]
+            if let PciDeviceHandle::Virtio(virtio_pci_device) = &pci_device_node
+                .pci_device_handle
+                .as_ref()
+                .ok_or(DeviceManagerError::MissingPciDevice)?
+            {
+                virtio_pci_device.lock().unwrap().maybe_activate();
             }
         }
+        Ok(())
+    }
+
+    /// Records the failure of a worker thread in the node of its device,
+    /// and restarts the device if asked to. The record is cleared once the
+    /// device has been restarted.
+    pub fn handle_worker_failure(
+        &self,
+        failure: &WorkerFailure,
+        restart: bool,
+    ) -> DeviceManagerResult<()> {
+        let mut device_tree = self.device_tree.lock().unwrap();
+        let node = device_tree
+            .get_mut(&failure.device_id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(failure.device_id.clone()))?;
+        node.worker_failure = Some(failure.clone());
+        if !restart {
+            return Ok(());
+        }
+
+        let parent = node.parent.clone().ok_or(DeviceManagerError::MissingNode)?;
+        let virtio_pci_device = match device_tree
+            .get(&parent)
+            .and_then(|node| node.pci_device_handle.as_ref())
+        {
+            Some(PciDeviceHandle::Virtio(virtio_pci_device)) => Arc::clone(virtio_pci_device),
+            _ => return Err(DeviceManagerError::MissingPciDevice),
+        };
+        drop(device_tree);
 
-        Ok(())
-    }
+        // The requests left in flight are processed again, which only the
+        // devices whose requests can be replayed without the guest noticing
+        // tolerate. A packet or a console write would be sent twice.
+        let device_type = VirtioDeviceType::from(
+            virtio_pci_device
+                .lock()
+                .unwrap()
+                .virtio_device()
+                .lock()
+                .unwrap()
+                .device_type(),
+        );
+        if !matches!(
+            device_type,
+            VirtioDeviceType::Block | VirtioDeviceType::Rng | VirtioDeviceType::Balloon
+        ) {
+            return Err(DeviceManagerError::RestartNotAllowed(device_type));
+        }
 
-    pub fn activate_virtio_devices(&self) -> DeviceManagerResult<()> {
-        // Find virtio pci devices and activate any pending ones
-        let device_tree = self.device_tree.lock().unwrap();
-        for pci_device_node in device_tree.pci_devices() {
-            #[allow(irrefutable_let_patterns)]
-            if let PciDeviceHandle::Virtio(virtio_pci_device) = &pci_device_node
-                .pci_device_handle
-                .as_ref()
-                .ok_or(DeviceManagerError::MissingPciDevice)?
-            {
-                virtio_pci_device.lock().unwrap().maybe_activate();
-            }
+        virtio_pci_device
+            .lock()
+            .unwrap()
+            .restart()
+            .map_err(DeviceManagerError::RestartVirtioDevice)?;
+        if let Some(node) = self.device_tree.lock().unwrap().get_mut(&failure.device_id) {
+            node.worker_failure = None;
         }
+
         Ok(())
     }
 
@@ -3144,7 +4769,7 @@ impl DeviceManager {
         return self
             .ged_notification_device
             .as_ref()
-            .unwrap()
+            .ok_or(DeviceManagerError::AcpiDisabled)?
             .lock()
             .unwrap()
             .notify(_notification_type)
@@ -3153,10 +4778,37 @@ impl DeviceManager {
         return Ok(());
     }
 
+    // The guest can only be notified about hotplugged devices through the
+    // ACPI GED device.
+    fn check_hotplug_support(&self) -> DeviceManagerResult<()> {
+        #[cfg(feature = "acpi")]
+        if self.ged_notification_device.is_none() {
+            return Err(DeviceManagerError::AcpiDisabled);
+        }
+
+        Ok(())
+    }
+
+    // Devices can only be hot plugged behind the IOMMU if it was created
+    // along with the VM.
+    fn check_hotplug_iommu(&self, iommu: IommuMode) -> DeviceManagerResult<()> {
+        if iommu.enabled() && self.dma_remapping.is_none() {
+            return Err(DeviceManagerError::MissingIommu);
+        }
+
+        Ok(())
+    }
+
     pub fn add_device(
         &mut self,
         device_cfg: &mut DeviceConfig,
     ) -> DeviceManagerResult<PciDeviceInfo> {
+        self.check_hotplug_support()?;
+        self.check_hotplug_iommu(device_cfg.iommu)?;
+        if device_cfg.all_functions {
+            return Err(DeviceManagerError::VfioAllFunctionsHotplugUnsupported);
+        }
+
         let pci = if let Some(pci_bus) = &self.pci_bus {
             Arc::clone(pci_bus)
         } else {
@@ -3166,6 +4818,10 @@ impl DeviceManager {
         let (device_id, device_name) =
             self.add_passthrough_device(&mut pci.lock().unwrap(), device_cfg)?;
 
+        // The guest can drive the device as soon as it's notified about it.
+        #[cfg(feature = "kvm")]
+        self.wait_vfio_dma_mappings()?;
+
         // Update the PCIU bitmap
         self.pci_devices_up |= 1 << (device_id >> 3);
 
@@ -3176,6 +4832,8 @@ impl DeviceManager {
     }
 
     pub fn remove_device(&mut self, id: String) -> DeviceManagerResult<()> {
+        self.check_hotplug_support()?;
+
         // The node can be directly a PCI node in case the 'id' refers to a
         // VFIO device or a virtio-pci one.
         // In case the 'id' refers to a virtio device, we must find the PCI
@@ -3204,6 +4862,24 @@ impl DeviceManager {
             .pci_device_handle
             .as_ref()
             .ok_or(DeviceManagerError::MissingPciDevice)?;
+
+        // Only the devices of the root bus can be ejected through ACPI.
+        if pci_device_bdf >> 8 != 0 {
+            return Err(DeviceManagerError::RemovalBehindBridgeNotAllowed(
+                pci_device_bdf,
+            ));
+        }
+
+        // Ejecting the device would remove all its functions.
+        if device_tree.pci_devices().iter().any(|node| {
+            node.pci_bdf.map(|bdf| bdf >> 3) == Some(pci_device_bdf >> 3)
+                && node.pci_bdf != Some(pci_device_bdf)
+        }) {
+            return Err(DeviceManagerError::RemovalOfMultifunctionDeviceNotAllowed(
+                pci_device_bdf,
+            ));
+        }
+
         #[allow(irrefutable_let_patterns)]
         if let PciDeviceHandle::Virtio(virtio_pci_device) = pci_device_handle {
             let device_type = VirtioDeviceType::from(
@@ -3220,7 +4896,8 @@ impl DeviceManager {
                 | VirtioDeviceType::Block
                 | VirtioDeviceType::Pmem
                 | VirtioDeviceType::Fs
-                | VirtioDeviceType::Vsock => {}
+                | VirtioDeviceType::Vsock
+                | VirtioDeviceType::Balloon => {}
                 _ => return Err(DeviceManagerError::RemovalNotAllowed(device_type)),
             }
         }
@@ -3242,12 +4919,29 @@ impl DeviceManager {
         // Convert the device ID into the corresponding b/d/f.
         let pci_device_bdf = (device_id as u32) << 3;
 
+        // Forget about the endpoint in the virtual IOMMU, as a different
+        // device can be hot plugged to the same slot.
+        if let Some(iommu_mapping) = &self.iommu_mapping {
+            iommu_mapping.remove_endpoint(pci_device_bdf);
+        }
+        if let Some(iommu_device) = &self.iommu_device {
+            iommu_device
+                .lock()
+                .unwrap()
+                .remove_external_mapping(pci_device_bdf);
+        }
+
         // Give the PCI device ID back to the PCI bus.
         pci.lock()
             .unwrap()
             .put_device_id(device_id as usize)
             .map_err(DeviceManagerError::PutPciDeviceId)?;
 
+        // Unmap the guest memory from the VFIO container of the device,
+        // unless other devices share it.
+        #[cfg(feature = "kvm")]
+        self.remove_vfio_container_device(pci_device_bdf)?;
+
         // Remove the device from the device tree along with its children.
         let mut device_tree = self.device_tree.lock().unwrap();
         let pci_device_node = device_tree
@@ -3262,38 +4956,11 @@ impl DeviceManager {
             .ok_or(DeviceManagerError::MissingPciDevice)?;
         let (pci_device, bus_device, virtio_device) = match pci_device_handle {
             #[cfg(feature = "kvm")]
-            PciDeviceHandle::Vfio(vfio_pci_device) => {
-                {
-                    // Unregister DMA mapping in IOMMU.
-                    // Do not unregister the virtio-mem region, as it is
-                    // directly handled by the virtio-mem device.
-                    let dev = vfio_pci_device.lock().unwrap();
-                    for (_, zone) in self.memory_manager.lock().unwrap().memory_zones().iter() {
-                        for region in zone.regions() {
-                            dev.dma_unmap(region.start_addr().raw_value(), region.len() as u64)
-                                .map_err(DeviceManagerError::VfioDmaUnmap)?;
-                        }
-                    }
-
-                    // Unregister the VFIO mapping handler from all virtio-mem
-                    // devices.
-                    if !dev.iommu_attached() {
-                        for virtio_mem_device in self.virtio_mem_devices.iter() {
-                            virtio_mem_device
-                                .lock()
-                                .unwrap()
-                                .remove_dma_mapping_handler(pci_device_bdf)
-                                .map_err(DeviceManagerError::RemoveDmaMappingHandlerVirtioMem)?;
-                        }
-                    }
-                }
-
-                (
-                    Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
-                    Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
-                    None as Option<VirtioDeviceArc>,
-                )
-            }
+            PciDeviceHandle::Vfio(vfio_pci_device) => (
+                Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
+                Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
+                None as Option<VirtioDeviceArc>,
+            ),
             PciDeviceHandle::Virtio(virtio_pci_device) => {
                 let bar_addr = virtio_pci_device.lock().unwrap().config_bar_addr();
                 for (event, addr) in virtio_pci_device.lock().unwrap().ioeventfds(bar_addr) {
@@ -3359,27 +5026,48 @@ impl DeviceManager {
 
             virtio_device.lock().unwrap().shutdown();
 
+            // There can be only one balloon, which is used when resizing.
+            if virtio_device.lock().unwrap().device_type() == VirtioDeviceType::Balloon as u32 {
+                self.balloon = None;
+            }
+
             self.virtio_devices
                 .retain(|(d, _, _)| !Arc::ptr_eq(d, &virtio_device));
+            let virtio_devices = &self.virtio_devices;
+            self.net_devices
+                .retain(|(id, _)| virtio_devices.iter().any(|(_, _, d_id)| d_id == id));
+            self.block_devices
+                .retain(|(id, _)| virtio_devices.iter().any(|(_, _, d_id)| d_id == id));
+            let block_devices = &self.block_devices;
+            self.disk_mirrors
+                .retain(|id, _| block_devices.iter().any(|(b_id, _)| b_id == id));
         }
 
         // At this point, the device has been removed from all the list and
         // buses where it was stored. At the end of this function, after
         // any_device, bus_device and pci_device are released, the actual
         // device will be dropped.
+        drop(device_tree);
+        drop(pci_device);
+        drop(bus_device);
+
+        // The functions of an ejected VFIO device are bound back to their
+        // driver from the VMM thread, as the vCPU threads aren't allowed to.
+        if !self.vfio_bindings.is_empty() {
+            self.activate_evt
+                .write(1)
+                .map_err(DeviceManagerError::EventFd)?;
+        }
+
         Ok(())
     }
 
     fn hotplug_virtio_pci_device(
         &mut self,
         device: VirtioDeviceArc,
-        iommu_attached: bool,
+        iommu: IommuMode,
         id: String,
     ) -> DeviceManagerResult<PciDeviceInfo> {
-        if iommu_attached {
-            warn!("Placing device behind vIOMMU is not available for hotplugged devices");
-        }
-
         let pci = if let Some(pci_bus) = &self.pci_bus {
             Arc::clone(pci_bus)
         } else {
@@ -3390,10 +5078,22 @@ impl DeviceManager {
         // as the list is used to notify virtio devices about memory updates
         // for instance.
         self.virtio_devices
-            .push((device.clone(), iommu_attached, id.clone()));
+            .push((device.clone(), iommu, id.clone()));
 
-        let device_id =
-            self.add_virtio_pci_device(device, &mut pci.lock().unwrap(), &None, id.clone())?;
+        let dma_remapping = if iommu.enabled() {
+            self.dma_remapping.clone()
+        } else {
+            None
+        };
+        let device_id = self.add_virtio_pci_device(
+            device,
+            &mut pci.lock().unwrap(),
+            &dma_remapping,
+            id.clone(),
+        )?;
+        if let (IommuMode::Bypass, Some(mapping)) = (iommu, &self.iommu_mapping) {
+            mapping.add_bypass_endpoint(device_id);
+        }
 
         // Update the PCIU bitmap
         self.pci_devices_up |= 1 << (device_id >> 3);
@@ -3402,28 +5102,56 @@ impl DeviceManager {
     }
 
     pub fn add_disk(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_block_device(disk_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        self.check_hotplug_support()?;
+        self.check_hotplug_iommu(disk_cfg.iommu)?;
+
+        if disk_cfg.model == DiskModel::Nvme {
+            return Err(DeviceManagerError::NvmeHotplugUnsupported);
+        }
+
+        let (device, iommu, id) = self.make_virtio_block_device(disk_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu, id)
     }
 
     pub fn add_fs(&mut self, fs_cfg: &mut FsConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_fs_device(fs_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        self.check_hotplug_support()?;
+
+        let (device, iommu, id) = self.make_virtio_fs_device(fs_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu, id)
     }
 
     pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_pmem_device(pmem_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        self.check_hotplug_support()?;
+        self.check_hotplug_iommu(pmem_cfg.iommu)?;
+
+        let (device, iommu, id) = self.make_virtio_pmem_device(pmem_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu, id)
     }
 
     pub fn add_net(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_net_device(net_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        self.check_hotplug_support()?;
+        self.check_hotplug_iommu(net_cfg.iommu)?;
+
+        let (device, iommu, id) = self.make_virtio_net_device(net_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu, id)
     }
 
     pub fn add_vsock(&mut self, vsock_cfg: &mut VsockConfig) -> DeviceManagerResult<PciDeviceInfo> {
-        let (device, iommu_attached, id) = self.make_virtio_vsock_device(vsock_cfg)?;
-        self.hotplug_virtio_pci_device(device, iommu_attached, id)
+        self.check_hotplug_support()?;
+        self.check_hotplug_iommu(vsock_cfg.iommu)?;
+
+        let (device, iommu, id) = self.make_virtio_vsock_device(vsock_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu, id)
+    }
+
+    pub fn add_balloon(
+        &mut self,
+        balloon_cfg: &mut BalloonConfig,
+    ) -> DeviceManagerResult<PciDeviceInfo> {
+        self.check_hotplug_support()?;
+
+        let (device, iommu, id) = self.make_virtio_balloon_device(balloon_cfg)?;
+        self.hotplug_virtio_pci_device(device, iommu, id)
     }
 
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
@@ -3436,6 +5164,20 @@ impl DeviceManager {
             }
         }
 
+        // The notifications trapping to the VMM are reported for the virtio
+        // device behind each virtio-pci transport.
+        for (_, node) in self.device_tree.lock().unwrap().iter() {
+            if let Some(PciDeviceHandle::Virtio(virtio_pci_device)) = &node.pci_device_handle {
+                let notification_traps = virtio_pci_device.lock().unwrap().notification_traps();
+                for child in node.children.iter() {
+                    counters
+                        .entry(child.clone())
+                        .or_insert_with(HashMap::new)
+                        .insert("notification_traps", notification_traps);
+                }
+            }
+        }
+
         counters
     }
 
@@ -3452,6 +5194,186 @@ impl DeviceManager {
         Err(DeviceManagerError::MissingVirtioBalloon)
     }
 
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> DeviceManagerResult<()> {
+        let (_, net_device) = self
+            .net_devices
+            .iter()
+            .find(|(net_id, _)| net_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        net_device
+            .lock()
+            .unwrap()
+            .set_link_up(up)
+            .map_err(DeviceManagerError::SetNetLink)
+    }
+
+    fn block_device(&self, id: &str) -> DeviceManagerResult<Arc<Mutex<virtio_devices::Block>>> {
+        self.block_devices
+            .iter()
+            .find(|(block_id, _)| block_id == id)
+            .map(|(_, block)| Arc::clone(block))
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))
+    }
+
+    /// Requests a disk to switch to a new disk image, which has to be
+    /// waited for before the switch can be carried out.
+    pub fn request_disk_switch(&self, id: &str) -> DeviceManagerResult<DiskSwitchRequest> {
+        Ok(self.block_device(id)?.lock().unwrap().request_disk_switch())
+    }
+
+    /// Starts mirroring a disk onto `target`, which is created if needed.
+    pub fn mirror_disk(
+        &mut self,
+        id: &str,
+        target: &Path,
+        request: DiskSwitchRequest,
+    ) -> DeviceManagerResult<()> {
+        let block = self.block_device(id)?;
+        let disk_cfg = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter()
+            .flatten()
+            .find(|disk| disk.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        if disk_cfg.overlay.is_some() {
+            return Err(DeviceManagerError::MirrorUnsupportedDisk);
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true);
+        if disk_cfg.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let mut source =
+            Self::open_disk_file(&disk_cfg, options.clone().write(!disk_cfg.readonly))?;
+        let image_type =
+            detect_image_type(&mut source).map_err(DeviceManagerError::DetectImageType)?;
+        if !matches!(image_type, ImageType::Raw) {
+            return Err(DeviceManagerError::MirrorUnsupportedDisk);
+        }
+
+        let target_file = options
+            .write(true)
+            .create(true)
+            .open(target)
+            .map_err(DeviceManagerError::OpenMirrorTarget)?;
+
+        block
+            .lock()
+            .unwrap()
+            .start_mirror(request, source, target_file)
+            .map_err(DeviceManagerError::MirrorDisk)?;
+        self.disk_mirrors
+            .insert(id.to_owned(), target.to_path_buf());
+
+        Ok(())
+    }
+
+    /// Switches a disk to the target of its mirror, which backs the disk
+    /// from now on, including once the VM is rebooted. The mirror must have
+    /// caught up already, its mirror_remaining_bytes counter being zero.
+    pub fn pivot_disk(&mut self, id: &str, request: DiskSwitchRequest) -> DeviceManagerResult<()> {
+        let block = self.block_device(id)?;
+        block
+            .lock()
+            .unwrap()
+            .pivot_mirror()
+            .map_err(DeviceManagerError::MirrorDisk)?;
+
+        let target = match self.disk_mirrors.remove(id) {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let disk_cfg = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk| disk.id.as_deref() == Some(id))
+            .map(|disk| {
+                // The file descriptor the disk was given, which
+                // open_disk_image() would prefer, is the source of the mirror.
+                disk.path = Some(target);
+                disk.fd = None;
+                disk.clone()
+            });
+
+        // The mirror keeps serving the disk from the target, synchronously,
+        // until the disk is switched to the backend it would be opened with.
+        if let Some(disk_cfg) = disk_cfg {
+            match self.open_disk_image(&disk_cfg) {
+                Ok(disk_image) => block
+                    .lock()
+                    .unwrap()
+                    .switch_disk(request, disk_image)
+                    .map_err(DeviceManagerError::MirrorDisk)?,
+                Err(e) => warn!("Disk {} kept on its pivoted mirror: {:?}", id, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Redirects the writes to a disk onto a new copy-on-write overlay, its
+    /// current image being left untouched from now on, including once the
+    /// VM is rebooted.
+    pub fn snapshot_disk(
+        &mut self,
+        id: &str,
+        overlay: &Path,
+        request: DiskSwitchRequest,
+    ) -> DeviceManagerResult<()> {
+        let block = self.block_device(id)?;
+        let disk_cfg = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter()
+            .flatten()
+            .find(|disk| disk.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+        // The overlay the disk writes to becomes read-only, below the new one.
+        let mut backing_overlays = disk_cfg.backing_overlays.clone();
+        backing_overlays.extend(disk_cfg.overlay.clone());
+
+        let mut base = Self::open_disk_file(&disk_cfg, OpenOptions::new().read(true))?;
+        let image_type =
+            detect_image_type(&mut base).map_err(DeviceManagerError::DetectImageType)?;
+        if !matches!(image_type, ImageType::Raw) {
+            return Err(DeviceManagerError::DiskSnapshotUnsupportedDisk);
+        }
+
+        block
+            .lock()
+            .unwrap()
+            .snapshot_disk(request, base, &backing_overlays, overlay)
+            .map_err(DeviceManagerError::DiskSnapshot)?;
+
+        if let Some(disk) = self
+            .config
+            .lock()
+            .unwrap()
+            .disks
+            .iter_mut()
+            .flatten()
+            .find(|disk| disk.id.as_deref() == Some(id))
+        {
+            disk.backing_overlays = backing_overlays;
+            disk.overlay = Some(overlay.to_path_buf());
+        }
+
+        Ok(())
+    }
+
     pub fn balloon_size(&self) -> u64 {
         if let Some(balloon) = &self.balloon {
             return balloon.lock().unwrap().get_actual();
@@ -3464,6 +5386,86 @@ impl DeviceManager {
         self.device_tree.clone()
     }
 
+    // The functions can only be bound back to their original driver once the
+    // devices are gone, which the caller must ensure before dropping them.
+    pub fn take_vfio_bindings(&mut self) -> Vec<crate::vfio_functions::DriverBinding> {
+        self.vfio_bindings.drain(..).collect()
+    }
+
+    // Binds the functions whose device is closed, such as after it was
+    // ejected, back to their original driver.
+    pub fn restore_vfio_bindings(&mut self) {
+        self.vfio_bindings.retain(|binding| binding.device_open());
+    }
+
+    // The buses keep the VFIO devices alive as long as the device manager,
+    // which they refer to as well. Removing the devices from the buses closes
+    // them, for their functions to be bound back to their original driver
+    // once the VM is gone.
+    #[cfg(feature = "kvm")]
+    pub fn release_vfio_devices(&mut self) {
+        let mut device_tree = self.device_tree.lock().unwrap();
+        let ids: Vec<String> = device_tree
+            .iter()
+            .filter(|(_, node)| matches!(node.pci_device_handle, Some(PciDeviceHandle::Vfio(_))))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            let vfio_pci_device = match device_tree
+                .get_mut(&id)
+                .and_then(|node| node.pci_device_handle.take())
+            {
+                Some(PciDeviceHandle::Vfio(vfio_pci_device)) => vfio_pci_device,
+                _ => continue,
+            };
+            let pci_device = Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>;
+            let bus_device = vfio_pci_device as Arc<Mutex<dyn BusDevice>>;
+
+            if let Some(pci_bus) = &self.pci_bus {
+                if let Err(e) = pci_bus.lock().unwrap().remove_by_device(&pci_device) {
+                    warn!("Error removing {} from the PCI bus: {:?}", id, e);
+                }
+            }
+            // The device isn't necessarily on both buses.
+            #[cfg(target_arch = "x86_64")]
+            self.io_bus().remove_by_device(&bus_device).ok();
+            self.mmio_bus().remove_by_device(&bus_device).ok();
+            self.bus_devices
+                .retain(|dev| !Arc::ptr_eq(dev, &bus_device));
+        }
+    }
+
+    #[cfg(feature = "acpi")]
+    pub fn sleep_state(&self) -> Option<u8> {
+        self.shutdown_device
+            .as_ref()
+            .and_then(|device| device.lock().unwrap().sleep_state())
+    }
+
+    #[cfg(feature = "acpi")]
+    pub fn wake(&self) {
+        if let Some(device) = &self.shutdown_device {
+            device.lock().unwrap().wake();
+        }
+    }
+
+    /// Time left before the RTC alarm programmed by the guest fires.
+    pub fn rtc_alarm_timeout(&self) -> Option<std::time::Duration> {
+        #[cfg(all(target_arch = "x86_64", feature = "cmos"))]
+        if let Some(cmos) = &self.cmos {
+            return cmos.lock().unwrap().alarm_timeout();
+        }
+
+        None
+    }
+
+    pub fn rtc_alarm_fired(&self) {
+        #[cfg(all(target_arch = "x86_64", feature = "cmos"))]
+        if let Some(cmos) = &self.cmos {
+            cmos.lock().unwrap().alarm_fired();
+        }
+    }
+
     pub fn restore_devices(
         &mut self,
         snapshot: Snapshot,
@@ -3502,13 +5504,79 @@ impl DeviceManager {
     pub fn notify_power_button(&self) -> DeviceManagerResult<()> {
         self.ged_notification_device
             .as_ref()
-            .unwrap()
+            .ok_or(DeviceManagerError::AcpiDisabled)?
             .lock()
             .unwrap()
             .notify(AcpiNotificationFlags::POWER_BUTTON_CHANGED)
             .map_err(DeviceManagerError::PowerButtonNotification)
     }
 
+    #[cfg(feature = "acpi")]
+    #[cfg(target_arch = "x86_64")]
+    pub fn notify_sleep_button(&self) -> DeviceManagerResult<()> {
+        self.ged_notification_device
+            .as_ref()
+            .ok_or(DeviceManagerError::AcpiDisabled)?
+            .lock()
+            .unwrap()
+            .notify(AcpiNotificationFlags::SLEEP_BUTTON_CHANGED)
+            .map_err(DeviceManagerError::SleepButtonNotification)
+    }
+
+    /// Sends an NMI to all the vCPUs, through an MSI broadcast to the local
+    /// APICs with the NMI delivery mode. The route is only set up the first
+    /// time, for the VMs never getting an NMI not to use a GSI for it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn inject_nmi(&mut self) -> DeviceManagerResult<()> {
+        if self.nmi_interrupt_group.is_none() {
+            let group = self
+                .msi_interrupt_manager
+                .create_group(MsiIrqGroupConfig { base: 0, count: 1 })
+                .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+            // Physical destination 0xff, the xAPIC broadcast, and the NMI
+            // delivery mode, the vector being ignored.
+            let config = MsiIrqSourceConfig {
+                high_addr: 0,
+                low_addr: 0xfee0_0000 | (0xff << 12),
+                data: 0b100 << 8,
+                devid: 0,
+            };
+            group
+                .update(0, InterruptSourceConfig::MsiIrq(config))
+                .map_err(DeviceManagerError::NmiInjection)?;
+            group.enable().map_err(DeviceManagerError::NmiInjection)?;
+
+            self.nmi_interrupt_group = Some(group);
+        }
+
+        self.nmi_interrupt_group
+            .as_ref()
+            .unwrap()
+            .trigger(0)
+            .map_err(DeviceManagerError::NmiInjection)
+    }
+
+    /// Updates the state of the AC adapter and battery, letting the guest
+    /// know about it.
+    #[cfg(feature = "acpi")]
+    pub fn set_power_supply(&self, ac_online: bool, battery_level: u8) -> DeviceManagerResult<()> {
+        self.power_supply_device
+            .as_ref()
+            .ok_or(DeviceManagerError::MissingPowerSupply)?
+            .lock()
+            .unwrap()
+            .set_state(ac_online, battery_level);
+
+        self.ged_notification_device
+            .as_ref()
+            .ok_or(DeviceManagerError::AcpiDisabled)?
+            .lock()
+            .unwrap()
+            .notify(AcpiNotificationFlags::POWER_SUPPLY_CHANGED)
+            .map_err(DeviceManagerError::PowerSupplyNotification)
+    }
+
     #[cfg(target_arch = "aarch64")]
     pub fn notify_power_button(&self) -> DeviceManagerResult<()> {
         self.gpio_device
@@ -3520,9 +5588,32 @@ impl DeviceManager {
             .map_err(DeviceManagerError::AArch64PowerButtonNotification)
     }
 
+    #[cfg(target_arch = "aarch64")]
+    pub fn notify_sleep_button(&self) -> DeviceManagerResult<()> {
+        self.gpio_device
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .trigger_key(4)
+            .map_err(DeviceManagerError::AArch64SleepButtonNotification)
+    }
+
     pub fn iommu_attached_devices(&self) -> &Option<(u32, Vec<u32>)> {
         &self.iommu_attached_devices
     }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn vtd_attached_devices(&self) -> &Option<(GuestAddress, Vec<u32>)> {
+        &self.vtd_attached_devices
+    }
+
+    #[cfg(feature = "acpi")]
+    pub fn tpm_address(&self) -> Option<GuestAddress> {
+        self.tpm_device
+            .as_ref()
+            .map(|tpm_device| tpm_device.lock().unwrap().address())
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -3761,6 +5852,7 @@ impl Aml for DeviceManager {
 
         let start_of_device_area = self.memory_manager.lock().unwrap().start_of_device_area().0;
         let end_of_device_area = self.memory_manager.lock().unwrap().end_of_device_area().0;
+        let end_of_mmio_hole = self.memory_manager.lock().unwrap().end_of_mmio_hole().0;
 
         let mut pci_dsdt_inner_data: Vec<&dyn aml::Aml> = Vec::new();
         let hid = aml::Name::new("_HID".into(), &aml::EisaName::new("PNP0A08"));
@@ -3776,8 +5868,8 @@ impl Aml for DeviceManager {
         let supp = aml::Name::new("SUPP".into(), &aml::ZERO);
         pci_dsdt_inner_data.push(&supp);
 
-        // Since Cloud Hypervisor supports only one PCI bus, it can be tied
-        // to the NUMA node 0. It's up to the user to organize the NUMA nodes
+        // Since Cloud Hypervisor supports only one PCI host bridge, it can be
+        // tied to the NUMA node 0. It's up to the user to organize the NUMA nodes
         // so that the PCI bus relates to the expected vCPUs and guest RAM.
         let proximity_domain = 0u32;
         let pxm_return = aml::Return::new(&proximity_domain);
@@ -3803,7 +5895,7 @@ impl Aml for DeviceManager {
                     aml::AddressSpaceCachable::NotCacheable,
                     true,
                     layout::MEM_32BIT_DEVICES_START.0 as u32,
-                    (layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE - 1) as u32,
+                    end_of_mmio_hole as u32,
                 ),
                 &aml::AddressSpace::new_memory(
                     aml::AddressSpaceCachable::NotCacheable,
@@ -3832,15 +5924,26 @@ impl Aml for DeviceManager {
         pci_dsdt_inner_data.push(&pci_device_methods);
 
         // Build PCI routing table, listing IRQs assigned to PCI devices.
-        let prt_package_list: Vec<(u32, u32)> = self
+        // The interrupt pins of the devices behind a bridge are swizzled
+        // by the guest, which is why all of them are routed to the IRQ of
+        // the slot of the bridge.
+        let prt_package_list: Vec<(u32, u8, u32)> = self
             .pci_irq_slots
             .iter()
             .enumerate()
-            .map(|(i, irq)| (((((i as u32) & 0x1fu32) << 16) | 0xffffu32), *irq as u32))
+            .flat_map(|(i, irq)| {
+                (0..4u8).map(move |pin| {
+                    (
+                        ((((i as u32) & 0x1fu32) << 16) | 0xffffu32),
+                        pin,
+                        *irq as u32,
+                    )
+                })
+            })
             .collect();
         let prt_package_list: Vec<aml::Package> = prt_package_list
             .iter()
-            .map(|(bdf, irq)| aml::Package::new(vec![bdf, &0u8, &0u8, irq]))
+            .map(|(bdf, pin, irq)| aml::Package::new(vec![bdf, pin, &0u8, irq]))
             .collect();
         let prt_package_list: Vec<&dyn Aml> = prt_package_list
             .iter()
@@ -3925,6 +6028,15 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes();
 
+        let sleep_button_dsdt_data = aml::Device::new(
+            "_SB_.SLPB".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EisaName::new("PNP0C0E")),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+            ],
+        )
+        .to_aml_bytes();
+
         let ged_data = self
             .ged_notification_device
             .as_ref()
@@ -3939,7 +6051,30 @@ impl Aml for DeviceManager {
             bytes.extend_from_slice(com1_dsdt_data.as_slice());
         }
         bytes.extend_from_slice(s5_sleep_data.as_slice());
+
+        // The sleep states are only advertised when the VMM knows what to do
+        // with them.
+        #[cfg(target_arch = "x86_64")]
+        if let Some(suspend) = &self.config.lock().unwrap().suspend {
+            if suspend.s3 {
+                bytes.extend_from_slice(
+                    &aml::Name::new("_S3_".into(), &aml::Package::new(vec![&3u8])).to_aml_bytes(),
+                );
+            }
+            if suspend.s4_destination_url.is_some() {
+                bytes.extend_from_slice(
+                    &aml::Name::new("_S4_".into(), &aml::Package::new(vec![&4u8])).to_aml_bytes(),
+                );
+            }
+        }
         bytes.extend_from_slice(power_button_dsdt_data.as_slice());
+        bytes.extend_from_slice(sleep_button_dsdt_data.as_slice());
+        if let Some(power_supply_device) = &self.power_supply_device {
+            bytes.extend_from_slice(&power_supply_device.lock().unwrap().to_aml_bytes());
+        }
+        if let Some(tpm_device) = &self.tpm_device {
+            bytes.extend_from_slice(&tpm_device.lock().unwrap().to_aml_bytes());
+        }
         bytes.extend_from_slice(ged_data.as_slice());
         bytes
     }
@@ -4087,5 +6222,11 @@ impl Drop for DeviceManager {
         for (device, _, _) in self.virtio_devices.drain(..) {
             device.lock().unwrap().shutdown();
         }
+
+        // The guest memory must not be unmapped while it's being pinned.
+        #[cfg(feature = "kvm")]
+        for mut container in self.vfio_containers.drain(..) {
+            let _ = container.wait();
+        }
     }
 }
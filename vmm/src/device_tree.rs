@@ -5,6 +5,7 @@
 use crate::device_manager::PciDeviceHandle;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use virtio_devices::WorkerFailure;
 use vm_device::Resource;
 use vm_migration::Migratable;
 
@@ -19,6 +20,9 @@ pub struct DeviceNode {
     pub pci_bdf: Option<u32>,
     #[serde(skip)]
     pub pci_device_handle: Option<PciDeviceHandle>,
+    // Failure of a worker thread leaving the device unable to make progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_failure: Option<WorkerFailure>,
 }
 
 impl DeviceNode {
@@ -31,6 +35,7 @@ impl DeviceNode {
             migratable,
             pci_bdf: None,
             pci_device_handle: None,
+            worker_failure: None,
         }
     }
 }
@@ -0,0 +1,271 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Executables run by the VMM when the VM goes through a lifecycle event.
+//!
+//! The hooks run from a thread of their own, started before any seccomp
+//! filter is applied: a filter is inherited by the processes spawned from the
+//! thread it applies to, and the VMM one would neither let a hook run nor let
+//! the VMM wait for it. The VMM threads only hand the hooks over to that
+//! thread, which runs them one at a time, in the order of the events, without
+//! the VM waiting for them.
+
+use crate::config::{HooksConfig, VmConfig};
+use crate::vm::VmState;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookEvent {
+    Booted,
+    Paused,
+    Resumed,
+    Stopped,
+    // The guest is about to be paused for a checkpoint, and is expected to
+    // have flushed and frozen its filesystems once the hook completes.
+    Freeze,
+    // The guest was resumed after a checkpoint.
+    Thaw,
+}
+
+impl HookEvent {
+    /// The event the VM went through when its state went from `previous` to
+    /// `current`, if any. No state means there's no VM running.
+    pub fn from_transition(previous: Option<VmState>, current: Option<VmState>) -> Option<Self> {
+        let was_running = matches!(
+            previous,
+            Some(VmState::Running) | Some(VmState::Paused) | Some(VmState::Suspended)
+        );
+
+        match current {
+            _ if current == previous => None,
+            Some(VmState::Running) if was_running => Some(HookEvent::Resumed),
+            Some(VmState::Running) => Some(HookEvent::Booted),
+            Some(VmState::Paused) => Some(HookEvent::Paused),
+            // The guest sleeping is not an event of its own.
+            Some(VmState::Suspended) => None,
+            None | Some(VmState::Created) | Some(VmState::Shutdown) if was_running => {
+                Some(HookEvent::Stopped)
+            }
+            None | Some(VmState::Created) | Some(VmState::Shutdown) => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HookEvent::Booted => "booted",
+            HookEvent::Paused => "paused",
+            HookEvent::Resumed => "resumed",
+            HookEvent::Stopped => "stopped",
+            HookEvent::Freeze => "freeze",
+            HookEvent::Thaw => "thaw",
+        }
+    }
+}
+
+enum HookRequest {
+    Run {
+        path: PathBuf,
+        env: Vec<(String, String)>,
+    },
+    // Answered once the hooks requested before have completed.
+    Sync(Sender<()>),
+}
+
+// Closes every file descriptor but the standard ones, in the child about to
+// execute a hook. Many of the VMM ones aren't close-on-exec, and a hook left
+// running would otherwise keep the VM resources, such as its eventfds, taps
+// and KVM file descriptors, alive after the VM is gone.
+fn close_inherited_fds() -> io::Result<()> {
+    // Safe because the syscall takes no pointer.
+    if unsafe { libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) } == 0 {
+        return Ok(());
+    }
+
+    // Kernels older than 5.9 don't have close_range().
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safe because limit outlives the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for fd in 3..limit.rlim_cur {
+        // Safe because nothing runs in the child anymore but the exec.
+        unsafe { libc::close(fd as libc::c_int) };
+    }
+
+    Ok(())
+}
+
+fn hook_command(path: &Path, env: Vec<(String, String)>) -> Command {
+    let mut command = Command::new(path);
+    // The terminal belongs to the VM.
+    command.envs(env).stdin(Stdio::null());
+    // Safe because close_inherited_fds() only makes async-signal-safe calls.
+    unsafe { command.pre_exec(close_inherited_fds) };
+    command
+}
+
+fn run_hooks(receiver: Receiver<HookRequest>) {
+    for request in receiver.iter() {
+        match request {
+            HookRequest::Run { path, env } => match hook_command(&path, env).status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("Hook {} failed: {}", path.display(), status),
+                Err(e) => error!("Error running hook {}: {}", path.display(), e),
+            },
+            HookRequest::Sync(sender) => {
+                let _ = sender.send(());
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hooks {
+    config: Arc<HooksConfig>,
+    sender: Sender<HookRequest>,
+    // The hosted VM the hooks are run for, if not the default one.
+    vm_id: Option<String>,
+}
+
+impl Hooks {
+    /// Starts the thread running the hooks. Must be called before the
+    /// seccomp filters are applied.
+    pub fn start(config: HooksConfig) -> io::Result<Self> {
+        let (sender, receiver) = channel();
+        thread::Builder::new()
+            .name("hooks".to_string())
+            .spawn(move || run_hooks(receiver))?;
+
+        Ok(Hooks {
+            config: Arc::new(config),
+            sender,
+            vm_id: None,
+        })
+    }
+
+    pub fn for_hosted_vm(&self, id: &str) -> Self {
+        Hooks {
+            vm_id: Some(id.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Hands the hook for `event` over to the hooks thread, if there's one.
+    pub fn run(&self, event: HookEvent, config: Option<&VmConfig>) {
+        let path = match event {
+            HookEvent::Booted => &self.config.booted,
+            HookEvent::Paused => &self.config.paused,
+            HookEvent::Resumed => &self.config.resumed,
+            HookEvent::Stopped => &self.config.stopped,
+            HookEvent::Freeze => &self.config.freeze,
+            HookEvent::Thaw => &self.config.thaw,
+        };
+        let path = match path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let mut env = vec![
+            ("CH_HOOK".to_string(), event.name().to_string()),
+            ("CH_VMM_PID".to_string(), std::process::id().to_string()),
+        ];
+        if let Some(id) = &self.vm_id {
+            env.push(("CH_VM_ID".to_string(), id.clone()));
+        }
+        if let Some(config) = config.and_then(|config| serde_json::to_string(config).ok()) {
+            env.push(("CH_VM_CONFIG".to_string(), config));
+        }
+
+        if self.sender.send(HookRequest::Run { path, env }).is_err() {
+            error!("Error running hook: the hooks thread is gone");
+        }
+    }
+
+    /// Whether there's a hook to quiesce the guest before a checkpoint.
+    pub fn can_freeze(&self) -> bool {
+        self.config.freeze.is_some()
+    }
+
+    /// Waits for the hooks handed over so far to complete.
+    pub fn wait(&self) {
+        let (sender, receiver) = channel();
+        if self.sender.send(HookRequest::Sync(sender)).is_ok() {
+            let _ = receiver.recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_event_from_transition() {
+        use VmState::*;
+
+        let transitions = [
+            (None, Some(Created), None),
+            (Some(Created), Some(Running), Some(HookEvent::Booted)),
+            (None, Some(Running), Some(HookEvent::Booted)),
+            (Some(Running), Some(Running), None),
+            (Some(Running), Some(Paused), Some(HookEvent::Paused)),
+            (Some(Paused), Some(Running), Some(HookEvent::Resumed)),
+            (Some(Running), Some(Suspended), None),
+            (Some(Suspended), Some(Running), Some(HookEvent::Resumed)),
+            (Some(Running), None, Some(HookEvent::Stopped)),
+            (Some(Paused), Some(Shutdown), Some(HookEvent::Stopped)),
+            (Some(Suspended), None, Some(HookEvent::Stopped)),
+            (Some(Created), None, None),
+        ];
+        for (previous, current, event) in transitions.iter() {
+            assert_eq!(
+                HookEvent::from_transition(*previous, *current),
+                *event,
+                "{:?} -> {:?}",
+                previous,
+                current
+            );
+        }
+    }
+
+    #[test]
+    fn test_hooks_run() {
+        let hooks = Hooks::start(HooksConfig {
+            booted: Some(PathBuf::from("/nonexistent/hook")),
+            stopped: Some(PathBuf::from("/bin/true")),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // A hook failing to run doesn't keep the others from running.
+        hooks.run(HookEvent::Booted, None);
+        hooks.run(HookEvent::Paused, None);
+        hooks.for_hosted_vm("vm0").run(HookEvent::Stopped, None);
+        hooks.wait();
+    }
+
+    #[test]
+    fn test_hook_fds() {
+        // Not close-on-exec, like most of the VMM file descriptors.
+        let evt = vmm_sys_util::eventfd::EventFd::new(0).unwrap();
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&evt);
+
+        let status = hook_command(Path::new("/bin/sh"), Vec::new())
+            .arg("-c")
+            .arg(format!("test ! -e /proc/self/fd/{}", fd))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
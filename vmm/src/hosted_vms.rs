@@ -0,0 +1,242 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! VMs hosted by the VMM process alongside the default one.
+//!
+//! Each hosted VM is driven by a VMM thread of its own, with its own control
+//! loop, API channel, events and state directory. The default VMM thread
+//! only keeps track of them, so that a guest shutting down or a VMM thread
+//! failing only takes its own VM down. The HTTP API reaches them through the
+//! `/api/v1/vms/<id>/` routes.
+
+use crate::api::{ApiRequest, ApiResponsePayload};
+use crate::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+const MAX_VM_ID_LEN: usize = 64;
+
+/// Whether `id` can name a hosted VM, which it does in the API routes and
+/// in the state directory.
+pub fn valid_vm_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_VM_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub struct HostedVm {
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl HostedVm {
+    pub fn new(
+        api_evt: EventFd,
+        api_sender: Sender<ApiRequest>,
+        thread: thread::JoinHandle<Result<()>>,
+    ) -> Self {
+        HostedVm {
+            api_evt,
+            api_sender,
+            thread,
+        }
+    }
+
+    /// Returns the API event and channel to send the VM requests to.
+    pub fn channel(&self) -> io::Result<(EventFd, Sender<ApiRequest>)> {
+        Ok((self.api_evt.try_clone()?, self.api_sender.clone()))
+    }
+
+    // Shuts the VM and its VMM thread down. A thread which already failed
+    // doesn't receive the request anymore, and is only joined.
+    fn shutdown(self, id: &str) {
+        let (response_sender, response_receiver) = channel();
+        if self
+            .api_sender
+            .send(ApiRequest::VmmShutdown(response_sender))
+            .is_ok()
+            && self.api_evt.write(1).is_ok()
+        {
+            match response_receiver.recv() {
+                Ok(Ok(ApiResponsePayload::Empty)) | Err(_) => {}
+                Ok(Ok(_)) => warn!("Unexpected response shutting VM {} down", id),
+                Ok(Err(e)) => error!("Error shutting VM {} down: {:?}", id, e),
+            }
+        }
+
+        match self.thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("VMM thread of VM {} failed: {}", id, e),
+            Err(_) => error!("VMM thread of VM {} panicked", id),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HostedVms {
+    vms: BTreeMap<String, HostedVm>,
+    // VMs being shut down in the background, which can't be hosted again
+    // until they're down. Each thread removes its VM from the set once done.
+    removing: Arc<Mutex<BTreeSet<String>>>,
+    removals: Vec<(String, thread::JoinHandle<()>)>,
+}
+
+impl HostedVms {
+    pub fn is_empty(&self) -> bool {
+        self.vms.is_empty()
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.vms.keys().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&HostedVm> {
+        self.vms.get(id)
+    }
+
+    pub fn insert(&mut self, id: String, vm: HostedVm) {
+        self.vms.insert(id, vm);
+    }
+
+    /// Whether the VM is still being shut down after being removed.
+    pub fn is_removing(&self, id: &str) -> bool {
+        self.removing.lock().unwrap().contains(id)
+    }
+
+    /// Forgets about the VM and shuts it down from a thread of its own, so
+    /// that the caller doesn't wait for the guest to go down. Returns false
+    /// if there's no such VM.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let vm = match self.vms.remove(id) {
+            Some(vm) => vm,
+            None => return false,
+        };
+
+        // Join the threads which are done with their VM.
+        let removing = self.removing.lock().unwrap().clone();
+        let (done, pending) = std::mem::take(&mut self.removals)
+            .into_iter()
+            .partition(|(id, _)| !removing.contains(id));
+        self.removals = pending;
+        Self::join_removals(done);
+
+        self.removing.lock().unwrap().insert(id.to_string());
+        let removing = self.removing.clone();
+        let vm_id = id.to_string();
+        // The VM is only handed over once the thread is spawned, so that it
+        // can still be shut down here otherwise.
+        let (vm_sender, vm_receiver) = channel::<HostedVm>();
+        let spawned = thread::Builder::new()
+            .name(format!("vmm-remove-{}", id))
+            .spawn(move || {
+                if let Ok(vm) = vm_receiver.recv() {
+                    vm.shutdown(&vm_id);
+                }
+                removing.lock().unwrap().remove(&vm_id);
+            });
+        match spawned {
+            Ok(thread) => {
+                // The thread can't be gone before receiving the VM.
+                vm_sender.send(vm).ok();
+                self.removals.push((id.to_string(), thread));
+            }
+            Err(e) => {
+                error!("Error spawning the thread shutting VM {} down: {}", id, e);
+                vm.shutdown(id);
+                self.removing.lock().unwrap().remove(id);
+            }
+        }
+
+        true
+    }
+
+    /// Shuts all the VMs down, waiting for them and for the ones being
+    /// removed to be down.
+    pub fn remove_all(&mut self) {
+        for (id, vm) in std::mem::take(&mut self.vms) {
+            vm.shutdown(&id);
+        }
+        Self::join_removals(std::mem::take(&mut self.removals));
+    }
+
+    fn join_removals(removals: Vec<(String, thread::JoinHandle<()>)>) {
+        for (id, thread) in removals {
+            if thread.join().is_err() {
+                error!("Thread shutting VM {} down panicked", id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use libc::EFD_NONBLOCK;
+
+    // A VMM thread only answering the shutdown request, or failing if
+    // `fail` is set.
+    fn fake_hosted_vm(fail: bool) -> HostedVm {
+        let api_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (api_sender, api_receiver) = channel();
+
+        let thread = thread::spawn(move || {
+            if fail {
+                return Err(Error::EventFdRead(io::Error::from_raw_os_error(
+                    libc::EBADF,
+                )));
+            }
+            match api_receiver.recv().unwrap() {
+                ApiRequest::VmmShutdown(sender) => {
+                    sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                    Ok(())
+                }
+                _ => unreachable!(),
+            }
+        });
+
+        HostedVm::new(api_evt, api_sender, thread)
+    }
+
+    #[test]
+    fn test_valid_vm_id() {
+        assert!(valid_vm_id("vm0"));
+        assert!(valid_vm_id("tenant_1-vm-2"));
+        assert!(!valid_vm_id(""));
+        assert!(!valid_vm_id("../vm0"));
+        assert!(!valid_vm_id("vm/0"));
+        assert!(!valid_vm_id(&"a".repeat(MAX_VM_ID_LEN + 1)));
+    }
+
+    #[test]
+    fn test_hosted_vms() {
+        let mut vms = HostedVms::default();
+        assert!(vms.is_empty());
+
+        vms.insert("vm1".to_string(), fake_hosted_vm(false));
+        vms.insert("vm0".to_string(), fake_hosted_vm(false));
+        // A failed VMM thread is still removed.
+        vms.insert("vm2".to_string(), fake_hosted_vm(true));
+        assert_eq!(vms.ids(), vec!["vm0", "vm1", "vm2"]);
+        assert!(vms.get("vm0").unwrap().channel().is_ok());
+        assert!(vms.get("vm3").is_none());
+
+        assert!(vms.remove("vm2"));
+        assert!(!vms.remove("vm2"));
+        assert_eq!(vms.ids(), vec!["vm0", "vm1"]);
+
+        vms.remove_all();
+        assert!(vms.is_empty());
+        // The removed VMs are down once all of them are.
+        assert!(!vms.is_removing("vm2"));
+    }
+}
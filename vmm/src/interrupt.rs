@@ -190,8 +190,11 @@ impl InterruptSourceGroup for MsiInterruptGroup<IrqRoutingEntry> {
                     format!("mask: No existing route for interrupt index {}", index),
                 ));
             }
-            self.set_gsi_routes(&routes)?;
-            return route.disable(&self.vm);
+            // The irq_fd must go first, as the interrupts it would deliver
+            // in between through the GSI without a route would be lost,
+            // while they remain pending on the irq_fd.
+            route.disable(&self.vm)?;
+            return self.set_gsi_routes(&routes);
         }
 
         Err(io::Error::new(
@@ -16,19 +16,30 @@ extern crate serde_derive;
 extern crate credibility;
 
 use crate::api::{
-    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, CheckpointState, VmCheckpointInfo,
+    VmConsoleData, VmInfo, VmMirrorDiskData, VmPivotDiskData, VmPowerSupplyData,
+    VmReceiveMigrationData, VmSendMigrationData, VmSetNetLinkData, VmmPingResponse,
 };
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+    ApiVsockConfig, BalloonConfig, CheckpointConfig, DeviceConfig, DiskConfig, DiskSnapshotConfig,
+    FsConfig, HooksConfig, MemoryPressureConfig, NetConfig, PmemConfig, RestoreConfig,
+    SigtermAction, SigtermConfig, VmConfig, VsockConfig,
 };
-use crate::migration::{get_vm_snapshot, recv_vm_snapshot};
+use crate::console_buffer::ConsoleBuffer;
+use crate::hooks::{HookEvent, Hooks};
+use crate::hosted_vms::{valid_vm_id, HostedVm, HostedVms};
+use crate::memory_manager::CopyThrottle;
+use crate::memory_pressure::{read_memory_pressure, MemoryPressureWatcher};
+use crate::migration::{get_vm_snapshot, recv_vm_snapshot, upgrade_snapshot};
+use crate::persistence::{PersistedVm, StateDir};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vm::{Error as VmError, Vm, VmState};
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
@@ -36,24 +47,42 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{result, thread};
 use thiserror::Error;
+use virtio_devices::WorkerSupervisor;
 use vm_memory::bitmap::AtomicBitmap;
 use vm_migration::protocol::*;
 use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 pub mod api;
+pub mod builder;
+pub mod cgroup;
+pub mod cold_pages;
 pub mod config;
+pub mod console_buffer;
 pub mod cpu;
 pub mod device_manager;
 pub mod device_tree;
+pub mod hooks;
+pub mod hosted_vms;
 pub mod interrupt;
+pub mod logger;
 pub mod memory_manager;
+pub mod memory_pressure;
+pub mod metrics;
 pub mod migration;
+pub mod numa_placement;
+pub mod panic_dump;
+pub mod persistence;
+pub mod resource_usage;
 pub mod seccomp_filters;
+pub mod vfio_functions;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -86,6 +115,22 @@ pub enum Error {
     #[error("Error creating EventFd: {0}")]
     EventFdCreate(#[source] io::Error),
 
+    /// Cannot create or arm the resume timer.
+    #[error("Error handling the resume timer: {0}")]
+    ResumeTimer(#[source] io::Error),
+
+    /// Cannot create or arm the timer of the termination grace period.
+    #[error("Error handling the termination grace period timer: {0}")]
+    SigtermTimer(#[source] io::Error),
+
+    /// Cannot create the thread handling the termination signals
+    #[error("Error spawning the termination signals thread: {0}")]
+    SigtermThreadSpawn(#[source] io::Error),
+
+    /// Cannot read the host memory pressure or arm its timer.
+    #[error("Error watching the host memory pressure: {0}")]
+    MemoryPressure(#[source] io::Error),
+
     /// Cannot read from EventFd.
     #[error("Error reading from EventFd: {0}")]
     EventFdRead(#[source] io::Error),
@@ -98,6 +143,10 @@ pub enum Error {
     #[error("Error spawning HTTP thread: {0}")]
     HttpThreadSpawn(#[source] io::Error),
 
+    /// Cannot create the thread running the hooks
+    #[error("Error spawning hooks thread: {0}")]
+    HooksThreadSpawn(#[source] io::Error),
+
     /// Cannot handle the VM STDIN stream
     #[error("Error handling VM stdin: {0:?}")]
     Stdin(VmError),
@@ -137,6 +186,18 @@ pub enum Error {
     /// Error binding API server socket
     #[error("Error creation API server's socket {0:?}")]
     CreateApiServerSocket(#[source] io::Error),
+
+    /// Error binding the API vsock socket
+    #[error("Error creating API server's vsock socket: {0}")]
+    CreateApiVsockSocket(#[source] io::Error),
+
+    /// The API is served on vsock without an API socket
+    #[error("The API vsock port requires an API socket")]
+    ApiVsockWithoutSocket,
+
+    /// Error getting the path of the API socket
+    #[error("Error getting the API socket path: {0}")]
+    ApiSocketPath(#[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -144,10 +205,17 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum EpollDispatch {
     Exit,
     Reset,
+    Suspend,
+    ResumeTimer,
+    Sigterm,
+    SigtermTimer,
+    MemoryPressure,
     Stdin,
     Api,
     ActivateVirtioDevices,
+    WorkerFailure,
     Pty,
+    Checkpoint,
 }
 
 pub struct EpollContext {
@@ -164,6 +232,7 @@ impl EpollContext {
         // Initial capacity needs to be large enough to hold:
         // * 1 exit event
         // * 1 reset event
+        // * 1 suspend event
         // * 1 stdin event
         // * 1 API event
         let mut dispatch_table = Vec::with_capacity(5);
@@ -240,76 +309,250 @@ impl Serialize for PciDeviceInfo {
     }
 }
 
+// Set once SIGTERM and SIGINT are handled by the VMM, in which case the
+// signal handler of the VM leaves them alone.
+static TERMINATION_SIGNALS_HANDLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn termination_signals_handled() -> bool {
+    TERMINATION_SIGNALS_HANDLED.load(Ordering::SeqCst)
+}
+
+// Forwards SIGTERM and SIGINT to `sigterm_evt`, for the VMM control loop to
+// act on them.
+fn start_sigterm_thread(sigterm_evt: EventFd, seccomp_action: &SeccompAction) -> Result<()> {
+    let mut signals = Signals::new(&[SIGTERM, SIGINT]).map_err(Error::SigtermThreadSpawn)?;
+    let seccomp_filter = get_seccomp_filter(seccomp_action, Thread::SignalHandler)
+        .map_err(Error::CreateSeccompFilter)?;
+    TERMINATION_SIGNALS_HANDLED.store(true, Ordering::SeqCst);
+
+    thread::Builder::new()
+        .name("sigterm_handler".to_string())
+        .spawn(move || {
+            if let Err(e) = SeccompFilter::apply(seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            for signal in signals.forever() {
+                info!("Received signal {}", signal);
+                if let Err(e) = sigterm_evt.write(1) {
+                    error!("Error forwarding signal {}: {}", signal, e);
+                    std::process::exit(1);
+                }
+            }
+        })
+        .map_err(Error::SigtermThreadSpawn)?;
+
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn start_vmm_thread(
     vmm_version: String,
     http_path: &Option<String>,
     http_fd: Option<RawFd>,
+    http_vsock: Option<ApiVsockConfig>,
     api_event: EventFd,
     api_sender: Sender<ApiRequest>,
     api_receiver: Receiver<ApiRequest>,
     seccomp_action: &SeccompAction,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    state_dir: Option<PathBuf>,
+    hooks: Option<HooksConfig>,
+    on_sigterm: Option<SigtermConfig>,
+    memory_pressure: Option<MemoryPressureConfig>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     let http_api_event = api_event.try_clone().map_err(Error::EventFdClone)?;
 
+    // The vsock connections are relayed to the API socket.
+    let http_vsock = if let Some(http_vsock) = http_vsock {
+        let api_path = if let Some(http_path) = http_path {
+            PathBuf::from(http_path)
+        } else if let Some(http_fd) = http_fd {
+            api::http_fd_path(http_fd).map_err(Error::ApiSocketPath)?
+        } else {
+            return Err(Error::ApiVsockWithoutSocket);
+        };
+        Some((http_vsock, api_path))
+    } else {
+        None
+    };
+
+    let hooks = hooks
+        .map(Hooks::start)
+        .transpose()
+        .map_err(Error::HooksThreadSpawn)?;
+
+    let thread = spawn_vmm_thread(
+        vmm_version,
+        api_event,
+        api_receiver,
+        seccomp_action,
+        hypervisor,
+        state_dir,
+        None,
+        hooks,
+        on_sigterm,
+        memory_pressure,
+    )?;
+
+    // The VMM thread is started, we can start serving HTTP requests
+    if let Some(http_path) = http_path {
+        api::start_http_path_thread(http_path, http_api_event, api_sender, seccomp_action)?;
+    } else if let Some(http_fd) = http_fd {
+        api::start_http_fd_thread(http_fd, http_api_event, api_sender, seccomp_action)?;
+    }
+    if let Some((http_vsock, api_path)) = http_vsock {
+        api::start_http_vsock_thread(http_vsock, &api_path, seccomp_action)?;
+    }
+    Ok(thread)
+}
+
+// Starts a VMM thread driving the default VM, or the hosted VM `hosted_id`
+// names.
+#[allow(clippy::too_many_arguments)]
+fn spawn_vmm_thread(
+    vmm_version: String,
+    api_event: EventFd,
+    api_receiver: Receiver<ApiRequest>,
+    seccomp_action: &SeccompAction,
+    hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    state_dir: Option<PathBuf>,
+    hosted_id: Option<String>,
+    hooks: Option<Hooks>,
+    on_sigterm: Option<SigtermConfig>,
+    memory_pressure: Option<MemoryPressureConfig>,
+) -> Result<thread::JoinHandle<Result<()>>> {
     // Retrieve seccomp filter
     let vmm_seccomp_filter =
         get_seccomp_filter(seccomp_action, Thread::Vmm).map_err(Error::CreateSeccompFilter)?;
 
+    let name = match &hosted_id {
+        Some(id) => format!("vmm-{}", id),
+        None => "vmm".to_string(),
+    };
     let vmm_seccomp_action = seccomp_action.clone();
-    let thread = thread::Builder::new()
-        .name("vmm".to_string())
+    thread::Builder::new()
+        .name(name)
         .spawn(move || {
             // Apply seccomp filter for VMM thread.
             SeccompFilter::apply(vmm_seccomp_filter).map_err(Error::ApplySeccompFilter)?;
 
-            let mut vmm = Vmm::new(
-                vmm_version.to_string(),
+            let hosted = hosted_id.is_some();
+            let result = Vmm::new(
+                vmm_version,
                 api_event,
                 vmm_seccomp_action,
                 hypervisor,
-            )?;
-
-            vmm.control_loop(Arc::new(api_receiver))
+                state_dir,
+                hosted,
+                hooks,
+                on_sigterm,
+                memory_pressure,
+            )
+            .and_then(|mut vmm| vmm.control_loop(Arc::new(api_receiver)));
+
+            // Nobody waits for a hosted VMM thread until its VM is removed.
+            if let (Some(id), Err(e)) = (&hosted_id, &result) {
+                error!("VMM thread of VM {} failed: {}", id, e);
+            }
+            result
         })
-        .map_err(Error::VmmThreadSpawn)?;
+        .map_err(Error::VmmThreadSpawn)
+}
 
-    // The VMM thread is started, we can start serving HTTP requests
-    if let Some(http_path) = http_path {
-        api::start_http_path_thread(http_path, http_api_event, api_sender, seccomp_action)?;
-    } else if let Some(http_fd) = http_fd {
-        api::start_http_fd_thread(http_fd, http_api_event, api_sender, seccomp_action)?;
-    }
-    Ok(thread)
+// Checkpoint whose guest memory is being copied, completed once the copy is
+// over.
+struct PendingCheckpoint {
+    config: CheckpointConfig,
+    disks: Vec<(String, PathBuf)>,
+    vm_config: VmConfig,
 }
 
 pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    suspend_evt: EventFd,
     api_evt: EventFd,
+    // Fires at the earliest of the scheduled resume and the RTC alarm.
+    resume_timer: TimerFd,
+    scheduled_resume: Option<SystemTime>,
+    rtc_alarm: Option<SystemTime>,
+    on_sigterm: Option<SigtermConfig>,
+    sigterm_evt: EventFd,
+    // Fires at the end of the grace period given to the guest to power off.
+    sigterm_timer: TimerFd,
+    // Whether the VMM exits once the VM is gone, a termination signal
+    // having been received.
+    terminating: bool,
+    memory_pressure: Option<MemoryPressureWatcher>,
+    // Fires at every interval the host memory pressure is read at.
+    memory_pressure_timer: TimerFd,
     version: String,
     vm: Option<Vm>,
     vm_config: Option<Arc<Mutex<VmConfig>>>,
     seccomp_action: SeccompAction,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     activate_evt: EventFd,
+    worker_supervisor: WorkerSupervisor,
+    state_dir: Option<StateDir>,
+    // Whether this VMM thread drives a hosted VM rather than the default one.
+    hosted: bool,
+    hosted_vms: HostedVms,
+    hooks: Option<Hooks>,
+    // State of the VM when the hooks were last looked at.
+    hooked_state: Option<VmState>,
+    // Fires once the guest memory of the pending checkpoint is copied.
+    checkpoint_evt: EventFd,
+    pending_checkpoint: Option<PendingCheckpoint>,
+    // Outcome of the checkpoint last taken, if any.
+    checkpoint_info: Option<VmCheckpointInfo>,
 }
 
 impl Vmm {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         vmm_version: String,
         api_evt: EventFd,
         seccomp_action: SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
+        state_dir: Option<PathBuf>,
+        hosted: bool,
+        hooks: Option<Hooks>,
+        on_sigterm: Option<SigtermConfig>,
+        memory_pressure: Option<MemoryPressureConfig>,
     ) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let suspend_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let activate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let worker_supervisor = WorkerSupervisor::new().map_err(Error::EventFdCreate)?;
+        let checkpoint_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let resume_timer = TimerFd::new().map_err(Error::ResumeTimer)?;
+        let sigterm_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let sigterm_timer = TimerFd::new().map_err(Error::SigtermTimer)?;
+        let mut memory_pressure_timer = TimerFd::new().map_err(Error::MemoryPressure)?;
+
+        let memory_pressure = memory_pressure.map(MemoryPressureWatcher::new);
+        if let Some(watcher) = &memory_pressure {
+            // Hosts without pressure stall information are caught early.
+            read_memory_pressure().map_err(Error::MemoryPressure)?;
+            memory_pressure_timer
+                .reset(watcher.interval(), Some(watcher.interval()))
+                .map_err(Error::MemoryPressure)?;
+        }
+
+        if on_sigterm.is_some() {
+            start_sigterm_thread(
+                sigterm_evt.try_clone().map_err(Error::EventFdClone)?,
+                &seccomp_action,
+            )?;
+        }
 
-        if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
+        // The terminal belongs to the default VM.
+        if !hosted && unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
             epoll.add_stdin().map_err(Error::Epoll)?;
         }
 
@@ -321,10 +564,38 @@ impl Vmm {
             .add_event(&reset_evt, EpollDispatch::Reset)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&suspend_evt, EpollDispatch::Suspend)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&resume_timer, EpollDispatch::ResumeTimer)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&sigterm_evt, EpollDispatch::Sigterm)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&sigterm_timer, EpollDispatch::SigtermTimer)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&memory_pressure_timer, EpollDispatch::MemoryPressure)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&activate_evt, EpollDispatch::ActivateVirtioDevices)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&worker_supervisor, EpollDispatch::WorkerFailure)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&checkpoint_evt, EpollDispatch::Checkpoint)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
@@ -333,20 +604,76 @@ impl Vmm {
             epoll,
             exit_evt,
             reset_evt,
+            suspend_evt,
             api_evt,
+            resume_timer,
+            scheduled_resume: None,
+            rtc_alarm: None,
+            on_sigterm,
+            sigterm_evt,
+            sigterm_timer,
+            terminating: false,
+            memory_pressure,
+            memory_pressure_timer,
             version: vmm_version,
             vm: None,
             vm_config: None,
             seccomp_action,
             hypervisor,
             activate_evt,
+            worker_supervisor,
+            state_dir: state_dir.map(StateDir::new),
+            hosted,
+            hosted_vms: HostedVms::default(),
+            hooks,
+            hooked_state: None,
+            checkpoint_evt,
+            pending_checkpoint: None,
+            checkpoint_info: None,
         })
     }
 
+    // Records the configuration and the state of the VM in the state
+    // directory, if any. Failing to do so must not take the VM down.
+    fn persist_vm(&mut self) {
+        let vm = self.vm_config.as_ref().map(|config| PersistedVm {
+            config: config.lock().unwrap().clone(),
+            state: self
+                .vm
+                .as_ref()
+                .and_then(|vm| vm.get_state().ok())
+                .unwrap_or(VmState::Created),
+        });
+
+        if let Some(state_dir) = self.state_dir.as_mut() {
+            if let Err(e) = state_dir.save(vm.as_ref()) {
+                error!("Error persisting the VM: {}", e);
+            }
+        }
+    }
+
+    // Runs the hook of the lifecycle event the VM went through since the
+    // hooks were last looked at, if any.
+    fn run_hooks(&mut self) {
+        let state = self.vm.as_ref().and_then(|vm| vm.get_state().ok());
+        let previous = std::mem::replace(&mut self.hooked_state, state);
+
+        if let (Some(hooks), Some(event)) =
+            (&self.hooks, HookEvent::from_transition(previous, state))
+        {
+            let config = self
+                .vm_config
+                .as_ref()
+                .map(|config| config.lock().unwrap().clone());
+            hooks.run(event, config.as_ref());
+        }
+    }
+
     fn vm_create(&mut self, config: Arc<Mutex<VmConfig>>) -> result::Result<(), VmError> {
         // We only store the passed VM config.
         // The VM will be created when being asked to boot it.
         if self.vm_config.is_none() {
+            Vm::assign_identity(&config)?;
             self.vm_config = Some(config);
             Ok(())
         } else {
@@ -364,6 +691,10 @@ impl Vmm {
         if self.vm.is_none() {
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let suspend_evt = self
+                .suspend_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
             let activate_evt = self
                 .activate_evt
                 .try_clone()
@@ -374,9 +705,11 @@ impl Vmm {
                     Arc::clone(vm_config),
                     exit_evt,
                     reset_evt,
+                    suspend_evt,
                     &self.seccomp_action,
                     self.hypervisor.clone(),
                     activate_evt,
+                    self.worker_supervisor.clone(),
                     None,
                     None,
                 )?;
@@ -412,20 +745,152 @@ impl Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            // Resuming a suspended VM is how the guest gets woken up.
+            #[cfg(feature = "acpi")]
+            if vm.get_state()? == VmState::Suspended {
+                self.rtc_alarm = None;
+                return vm.wake();
+            }
             vm.resume().map_err(VmError::Resume)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+    #[cfg(feature = "acpi")]
+    fn vm_sleep(&mut self) -> result::Result<(), VmError> {
+        let vm = match self.vm {
+            Some(ref mut vm) => vm,
+            None => return Ok(()),
+        };
+
+        match vm.sleep_state() {
+            Some(devices::acpi::S3_SLEEP_VALUE) => {
+                vm.suspend()?;
+
+                // The guest might have programmed the RTC alarm to be woken
+                // up later on.
+                self.rtc_alarm = vm
+                    .rtc_alarm_timeout()
+                    .map(|timeout| SystemTime::now() + timeout);
+                self.arm_resume_timer().map_err(VmError::ResumeTimer)
+            }
+            Some(devices::acpi::S4_SLEEP_VALUE) => {
+                let destination_url = vm
+                    .get_config()
+                    .lock()
+                    .unwrap()
+                    .suspend
+                    .as_ref()
+                    .and_then(|suspend| suspend.s4_destination_url.clone());
+                let destination_url = match destination_url {
+                    Some(destination_url) => destination_url,
+                    None => {
+                        warn!("S4 sleep state entered without any destination, waking up");
+                        return vm.wake();
+                    }
+                };
+
+                // Once restored, the guest resumes from its sleep state as
+                // if it had just been woken up. The VM is powered off after
+                // the snapshot has been taken.
+                vm.pause().map_err(VmError::Pause)?;
+                match self.vm_snapshot(&destination_url, None) {
+                    Ok(()) => {
+                        info!("VM suspended to {}", destination_url);
+                        self.exit_evt.write(1).map_err(VmError::EventfdError)
+                    }
+                    Err(e) => {
+                        error!("Error suspending VM to {}: {:?}", destination_url, e);
+                        let vm = self.vm.as_mut().unwrap();
+                        vm.resume().map_err(VmError::Resume)?;
+                        vm.wake()
+                    }
+                }
+            }
+            // Spurious event, the guest has already been woken up.
+            _ => Ok(()),
+        }
+    }
+
+    fn vm_schedule_resume(&mut self, time: Option<u64>) -> result::Result<(), VmError> {
+        if self.vm_config.is_none() {
+            return Err(VmError::VmMissingConfig);
+        }
+
+        self.scheduled_resume = time.map(|time| UNIX_EPOCH + Duration::from_secs(time));
+        self.arm_resume_timer().map_err(VmError::ResumeTimer)
+    }
+
+    // Arming or disarming the timer also acknowledges its past expirations.
+    fn arm_resume_timer(&mut self) -> io::Result<()> {
+        let deadline = [self.scheduled_resume, self.rtc_alarm]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+
+        match deadline {
+            Some(deadline) => {
+                // Deadlines already past must still arm the timer, which a
+                // zero timeout would disarm.
+                let timeout = deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .max(Duration::from_millis(1));
+                self.resume_timer.reset(timeout, None)
+            }
+            None => self.resume_timer.clear(),
+        }
+    }
+
+    fn vm_resume_timer_expired(&mut self) -> result::Result<(), VmError> {
+        let now = SystemTime::now();
+
+        if self.scheduled_resume.map_or(false, |time| time <= now) {
+            self.scheduled_resume = None;
+
+            // A VM which hasn't been booted yet, or which has been shut
+            // down, is booted. A paused or suspended VM is resumed.
+            match self.vm.as_ref().map(Vm::get_state).transpose()? {
+                None | Some(VmState::Created) => {
+                    info!("Booting VM as scheduled");
+                    self.vm_boot()?;
+                }
+                Some(VmState::Paused) | Some(VmState::Suspended) => {
+                    info!("Resuming VM as scheduled");
+                    self.vm_resume()?;
+                }
+                _ => {}
+            }
+        }
+
+        #[cfg(feature = "acpi")]
+        if self.rtc_alarm.map_or(false, |time| time <= now) {
+            self.rtc_alarm = None;
+
+            if let Some(ref mut vm) = self.vm {
+                if vm.get_state()? == VmState::Suspended {
+                    info!("Waking VM up on RTC alarm");
+                    vm.rtc_alarm_fired();
+                    vm.wake()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn vm_snapshot(
+        &mut self,
+        destination_url: &str,
+        parent_url: Option<&str>,
+    ) -> result::Result<(), VmError> {
+        if self.pending_checkpoint.is_some() {
+            return Err(VmError::CheckpointInProgress);
+        }
         if let Some(ref mut vm) = self.vm {
-            vm.snapshot()
-                .map_err(VmError::Snapshot)
-                .and_then(|snapshot| {
-                    vm.send(&snapshot, destination_url)
-                        .map_err(VmError::SnapshotSend)
-                })
+            vm.snapshot_to(destination_url, parent_url)
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -450,6 +915,10 @@ impl Vmm {
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let suspend_evt = self
+            .suspend_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
         let activate_evt = self
             .activate_evt
             .try_clone()
@@ -459,17 +928,24 @@ impl Vmm {
             &snapshot,
             exit_evt,
             reset_evt,
+            suspend_evt,
             Some(source_url),
             restore_cfg.prefault,
+            restore_cfg.input_log.clone(),
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
+            self.worker_supervisor.clone(),
         )?;
         self.vm = Some(vm);
 
         // Now we can restore the rest of the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.restore(snapshot).map_err(VmError::Restore)
+            vm.restore(snapshot).map_err(VmError::Restore)?;
+            if restore_cfg.host_time {
+                vm.inject_host_time();
+            }
+            Ok(())
         } else {
             Err(VmError::VmNotCreated)
         }
@@ -499,10 +975,19 @@ impl Vmm {
             let config = vm.get_config();
             let serial_pty = vm.serial_pty();
             let console_pty = vm.console_pty();
-            self.vm_shutdown()?;
+            if let Some(mut vm) = self.vm.take() {
+                vm.shutdown()?;
+                // The devices whose state outlives the VM are brought back to
+                // their power-on state, once the vCPUs are stopped.
+                vm.reset_devices()?;
+            }
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let suspend_evt = self
+                .suspend_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
             let activate_evt = self
                 .activate_evt
                 .try_clone()
@@ -518,12 +1003,19 @@ impl Vmm {
                 config,
                 exit_evt,
                 reset_evt,
+                suspend_evt,
                 &self.seccomp_action,
                 self.hypervisor.clone(),
                 activate_evt,
+                self.worker_supervisor.clone(),
                 serial_pty,
                 console_pty,
             )?);
+
+            // The balloon of the new VM starts from its configured size.
+            if let Some(watcher) = self.memory_pressure.as_mut() {
+                watcher.reset_balloon();
+            }
         }
 
         // Then we start the new VM.
@@ -579,18 +1071,179 @@ impl Vmm {
         }
 
         self.vm_config = None;
+        self.scheduled_resume = None;
+        self.rtc_alarm = None;
+        self.arm_resume_timer().map_err(VmError::ResumeTimer)?;
 
         event!("vm", "deleted");
 
         Ok(())
     }
 
+    // Acts on a termination signal, returning whether the VMM must exit right
+    // away rather than once the guest has powered off.
+    fn vmm_sigterm(&mut self) -> bool {
+        let config = self.on_sigterm.clone().unwrap_or_default();
+
+        // A signal received during the grace period ends it. Without any
+        // grace period, the guest has no time to power off, and a timer armed
+        // with a zero duration would be disarmed instead of expiring.
+        if self.terminating || config.action == SigtermAction::Exit || config.grace_period == 0 {
+            return true;
+        }
+        let running = self
+            .vm
+            .as_ref()
+            .map_or(false, |vm| matches!(vm.get_state(), Ok(VmState::Running)));
+        if !running {
+            return true;
+        }
+
+        if let Err(e) = self.vm_power_button() {
+            error!("Error pressing the power button: {:?}", e);
+            return true;
+        }
+        if let Err(e) = self
+            .sigterm_timer
+            .reset(Duration::from_secs(config.grace_period), None)
+        {
+            error!("Error arming the grace period timer: {}", e);
+            return true;
+        }
+        info!(
+            "Power button pressed, waiting up to {} seconds for the guest to power off",
+            config.grace_period
+        );
+        self.terminating = true;
+
+        false
+    }
+
+    // Reads the host memory pressure, reporting when it goes over or under
+    // the threshold, and inflates or deflates the balloon accordingly.
+    fn vmm_memory_pressure(&mut self) {
+        let watcher = match &mut self.memory_pressure {
+            Some(watcher) => watcher,
+            None => return,
+        };
+
+        let pressure = match read_memory_pressure() {
+            Ok(pressure) => pressure,
+            Err(e) => {
+                warn!("Error reading the host memory pressure: {}", e);
+                return;
+            }
+        };
+        match watcher.update(pressure) {
+            Some(true) => event!("vmm", "memory-pressure", "avg10", pressure.to_string()),
+            Some(false) => event!(
+                "vmm",
+                "memory-pressure-relieved",
+                "avg10",
+                pressure.to_string()
+            ),
+            None => {}
+        }
+
+        let vm = match &mut self.vm {
+            Some(vm) if matches!(vm.get_state(), Ok(VmState::Running)) => vm,
+            Some(_) => return,
+            None => {
+                watcher.reset_balloon();
+                return;
+            }
+        };
+        let (priority, max) = match vm.balloon_pressure_config() {
+            Some(config) => config,
+            None => return,
+        };
+
+        let previous = watcher.inflated();
+        let inflated = watcher.balloon_inflation(priority, max);
+        if inflated == previous {
+            return;
+        }
+        match vm.inflate_balloon_for_pressure(inflated) {
+            Ok(()) => event!("vm", "balloon-pressure", "inflated", inflated.to_string()),
+            Err(e) => error!("Error resizing the balloon under memory pressure: {:?}", e),
+        }
+    }
+
     fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
+        self.hosted_vms.remove_all();
         self.vm_delete()?;
         event!("vmm", "shutdown");
         Ok(())
     }
 
+    fn vmm_add_vm(&mut self, id: String) -> result::Result<ApiResponsePayload, ApiError> {
+        if let Some(vm) = self.hosted_vms.get(&id) {
+            let (api_evt, api_sender) = vm.channel().map_err(ApiError::VmmAddVm)?;
+            return Ok(ApiResponsePayload::VmChannel(api_evt, api_sender, false));
+        }
+        if self.hosted || !valid_vm_id(&id) {
+            return Err(ApiError::InvalidVmId(id));
+        }
+        // The VM being shut down still uses its state directory.
+        if self.hosted_vms.is_removing(&id) {
+            return Err(ApiError::VmBeingRemoved(id));
+        }
+
+        // Each hosted VM is persisted in a directory of its own.
+        let state_dir = match &self.state_dir {
+            Some(state_dir) => {
+                let path = state_dir.hosted_vm_path(&id);
+                std::fs::create_dir_all(&path).map_err(ApiError::VmmAddVm)?;
+                Some(path)
+            }
+            None => None,
+        };
+
+        let api_evt = EventFd::new(EFD_NONBLOCK).map_err(ApiError::VmmAddVm)?;
+        let (api_sender, api_receiver) = channel();
+        let thread = spawn_vmm_thread(
+            self.version.clone(),
+            api_evt.try_clone().map_err(ApiError::VmmAddVm)?,
+            api_receiver,
+            &self.seccomp_action,
+            self.hypervisor.clone(),
+            state_dir,
+            Some(id.clone()),
+            self.hooks.as_ref().map(|hooks| hooks.for_hosted_vm(&id)),
+            None,
+            self.memory_pressure
+                .as_ref()
+                .map(|watcher| watcher.config().clone()),
+        )
+        .map_err(|e| ApiError::VmmAddVm(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
+
+        let vm = HostedVm::new(api_evt, api_sender, thread);
+        let (api_evt, api_sender) = vm.channel().map_err(ApiError::VmmAddVm)?;
+        self.hosted_vms.insert(id.clone(), vm);
+        event!("vmm", "vm-added", "id", &id);
+
+        Ok(ApiResponsePayload::VmChannel(api_evt, api_sender, true))
+    }
+
+    fn vmm_get_vm(&self, id: String) -> result::Result<ApiResponsePayload, ApiError> {
+        match self.hosted_vms.get(&id) {
+            Some(vm) => {
+                let (api_evt, api_sender) = vm.channel().map_err(ApiError::VmmAddVm)?;
+                Ok(ApiResponsePayload::VmChannel(api_evt, api_sender, false))
+            }
+            None => Err(ApiError::UnknownVm(id)),
+        }
+    }
+
+    fn vmm_remove_vm(&mut self, id: String) -> result::Result<(), ApiError> {
+        if self.hosted_vms.remove(&id) {
+            event!("vmm", "vm-removed", "id", &id);
+            Ok(())
+        } else {
+            Err(ApiError::UnknownVm(id))
+        }
+    }
+
     fn vm_resize(
         &mut self,
         desired_vcpus: Option<u8>,
@@ -602,6 +1255,13 @@ impl Vmm {
                 error!("Error when resizing VM: {:?}", e);
                 Err(e)
             } else {
+                // The balloon is given the size asked for, without the bytes
+                // it was inflated by under memory pressure.
+                if desired_balloon.is_some() {
+                    if let Some(watcher) = self.memory_pressure.as_mut() {
+                        watcher.reset_balloon();
+                    }
+                }
                 Ok(())
             }
         } else {
@@ -622,18 +1282,48 @@ impl Vmm {
         }
     }
 
-    fn vm_add_device(&mut self, device_cfg: DeviceConfig) -> result::Result<Vec<u8>, VmError> {
+    // Adds a device to the VM, hotplugging it if the VM is booted. Before
+    // that, the device is only recorded in the configuration, and created
+    // along with the others when the VM boots, which is also when the
+    // configuration is validated.
+    fn vm_add<T>(
+        &mut self,
+        device_cfg: T,
+        what: &str,
+        hotplug: fn(&mut Vm, T) -> result::Result<PciDeviceInfo, VmError>,
+        add_to_config: impl FnOnce(&mut VmConfig, T) -> result::Result<(), VmError>,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_device(device_cfg).map_err(|e| {
-                error!("Error when adding new device to the VM: {:?}", e);
+            let info = hotplug(vm, device_cfg).map_err(|e| {
+                error!("Error when adding new {} to the VM: {:?}", what, e);
                 e
             })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            serde_json::to_vec(&info)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else if let Some(ref config) = self.vm_config {
+            add_to_config(&mut config.lock().unwrap(), device_cfg)?;
+            Ok(None)
         } else {
-            Err(VmError::VmNotRunning)
+            Err(VmError::VmNotCreated)
         }
     }
 
+    fn vm_add_device(
+        &mut self,
+        device_cfg: DeviceConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(
+            device_cfg,
+            "device",
+            Vm::add_device,
+            |config, device_cfg| {
+                Vm::add_to_config(&mut config.devices, device_cfg);
+                Ok(())
+            },
+        )
+    }
+
     fn vm_remove_device(&mut self, id: String) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             if let Err(e) = vm.remove_device(id) {
@@ -642,86 +1332,288 @@ impl Vmm {
             } else {
                 Ok(())
             }
+        } else if let Some(ref config) = self.vm_config {
+            if config.lock().unwrap().remove_device(&id) {
+                Ok(())
+            } else {
+                Err(VmError::UnknownDeviceId(id))
+            }
+        } else {
+            Err(VmError::VmNotCreated)
+        }
+    }
+
+    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(disk_cfg, "disk", Vm::add_disk, |config, disk_cfg| {
+            Vm::add_to_config(&mut config.disks, disk_cfg);
+            Ok(())
+        })
+    }
+
+    fn vm_add_fs(&mut self, fs_cfg: FsConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(fs_cfg, "fs", Vm::add_fs, |config, fs_cfg| {
+            Vm::add_to_config(&mut config.fs, fs_cfg);
+            Ok(())
+        })
+    }
+
+    fn vm_add_pmem(&mut self, pmem_cfg: PmemConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(pmem_cfg, "pmem device", Vm::add_pmem, |config, pmem_cfg| {
+            Vm::add_to_config(&mut config.pmem, pmem_cfg);
+            Ok(())
+        })
+    }
+
+    fn vm_add_net(&mut self, net_cfg: NetConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(net_cfg, "network device", Vm::add_net, |config, net_cfg| {
+            Vm::add_to_config(&mut config.net, net_cfg);
+            Ok(())
+        })
+    }
+
+    fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(
+            vsock_cfg,
+            "vsock device",
+            Vm::add_vsock,
+            |config, vsock_cfg| {
+                if config.vsock.is_some() {
+                    return Err(VmError::TooManyVsockDevices);
+                }
+                config.vsock = Some(vsock_cfg);
+                Ok(())
+            },
+        )
+    }
+
+    fn vm_add_balloon(
+        &mut self,
+        balloon_cfg: BalloonConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_add(
+            balloon_cfg,
+            "balloon device",
+            Vm::add_balloon,
+            |config, balloon_cfg| {
+                if config.balloon.is_some() {
+                    return Err(VmError::TooManyBalloonDevices);
+                }
+                config.balloon = Some(balloon_cfg);
+                Ok(())
+            },
+        )
+    }
+
+    fn vm_counters(&mut self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.counters().map_err(|e| {
+                error!("Error when getting counters from the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_memory_regions(&self) -> result::Result<Vec<u8>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.memory_regions()).map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_config(&self) -> result::Result<Vec<u8>, VmError> {
+        match &self.vm_config {
+            Some(config) => {
+                serde_json::to_vec(&*config.lock().unwrap()).map_err(VmError::SerializeJson)
+            }
+            None => Err(VmError::VmNotCreated),
+        }
+    }
+
+    fn vmm_metrics(&self) -> String {
+        match &self.vm {
+            Some(vm) => metrics::encode(&vm.metrics()),
+            None => String::new(),
+        }
+    }
+
+    fn vm_power_button(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.power_button()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_sleep_button(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.sleep_button()
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Vec<u8>, VmError> {
+    fn vm_nmi(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_disk(disk_cfg).map_err(|e| {
-                error!("Error when adding new disk to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            vm.nmi()
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_add_fs(&mut self, fs_cfg: FsConfig) -> result::Result<Vec<u8>, VmError> {
+    fn vm_power_supply(&mut self, data: &VmPowerSupplyData) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_fs(fs_cfg).map_err(|e| {
-                error!("Error when adding new fs to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            vm.set_power_supply(data.ac_online, data.battery_level)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_add_pmem(&mut self, pmem_cfg: PmemConfig) -> result::Result<Vec<u8>, VmError> {
+    fn vm_set_net_link(&mut self, data: &VmSetNetLinkData) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_pmem(pmem_cfg).map_err(|e| {
-                error!("Error when adding new pmem device to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            vm.set_net_link(&data.id, data.up)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_add_net(&mut self, net_cfg: NetConfig) -> result::Result<Vec<u8>, VmError> {
+    fn vm_mirror_disk(&mut self, data: &VmMirrorDiskData) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_net(net_cfg).map_err(|e| {
-                error!("Error when adding new network device to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            vm.mirror_disk(&data.id, &data.path)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> result::Result<Vec<u8>, VmError> {
+    fn vm_pivot_disk(&mut self, data: &VmPivotDiskData) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_vsock(vsock_cfg).map_err(|e| {
-                error!("Error when adding new vsock device to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            vm.pivot_disk(&data.id)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_counters(&mut self) -> result::Result<Vec<u8>, VmError> {
+    fn vm_disk_snapshot(&mut self, data: &DiskSnapshotConfig) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            let info = vm.counters().map_err(|e| {
-                error!("Error when getting counters from the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+            vm.disk_snapshot(&data.id, &data.overlay)
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_power_button(&mut self) -> result::Result<(), VmError> {
-        if let Some(ref mut vm) = self.vm {
-            vm.power_button()
+    fn vm_checkpoint(&mut self, config: &CheckpointConfig) -> result::Result<(), VmError> {
+        let vm = match self.vm {
+            Some(ref mut vm) => vm,
+            None => return Err(VmError::VmNotRunning),
+        };
+        if self.pending_checkpoint.is_some() {
+            return Err(VmError::CheckpointInProgress);
+        }
+        // Fail before any memory is copied.
+        if vm.has_vtd() {
+            return Err(VmError::Snapshot(MigratableError::Snapshot(anyhow!(
+                "Checkpoint not possible with the emulated VT-d"
+            ))));
+        }
+        if config.quiesce && !matches!(&self.hooks, Some(hooks) if hooks.can_freeze()) {
+            return Err(VmError::CheckpointQuiesce);
+        }
+        let vm_config = vm.get_config().lock().unwrap().clone();
+        let disks = match &config.overlay_dir {
+            Some(overlay_dir) => vm.checkpoint_disks(overlay_dir)?,
+            None => Vec::new(),
+        };
+
+        // The guest keeps running while most of its memory is copied, on its
+        // own thread, and is only paused once the copy is over for the
+        // memory written meanwhile to be copied again.
+        event!("vm", "checkpointing");
+        let throttle = CopyThrottle::new(config.bandwidth.map(|bandwidth| bandwidth << 20));
+        let done = self
+            .checkpoint_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        vm.start_checkpoint(&config.destination_url, config.passes, throttle, done)?;
+
+        self.pending_checkpoint = Some(PendingCheckpoint {
+            config: config.clone(),
+            disks,
+            vm_config,
+        });
+        self.checkpoint_info = Some(VmCheckpointInfo::default());
+
+        Ok(())
+    }
+
+    // Completes the pending checkpoint once its guest memory is copied,
+    // pausing the VM for the memory written meanwhile to be copied again.
+    fn complete_checkpoint(
+        &mut self,
+        checkpoint: PendingCheckpoint,
+    ) -> result::Result<VmCheckpointInfo, VmError> {
+        let vm = match self.vm {
+            Some(ref mut vm) => vm,
+            None => return Err(VmError::VmNotRunning),
+        };
+        let (passes, mut throttle) = vm.finish_checkpoint_copy()?;
+
+        let hooks = if checkpoint.config.quiesce {
+            self.hooks.as_ref()
+        } else {
+            None
+        };
+        if let Some(hooks) = hooks {
+            hooks.run(HookEvent::Freeze, Some(&checkpoint.vm_config));
+            hooks.wait();
+        }
+        let paused = Instant::now();
+        let result = vm.pause().map_err(VmError::Pause).and_then(|_| {
+            let result = vm.complete_checkpoint(
+                &checkpoint.config.destination_url,
+                &checkpoint.disks,
+                &mut throttle,
+            );
+            vm.resume().map_err(VmError::Resume).and(result)
+        });
+        let downtime = paused.elapsed();
+        if let Some(hooks) = hooks {
+            hooks.run(HookEvent::Thaw, Some(&checkpoint.vm_config));
+        }
+        result?;
+        event!("vm", "checkpointed");
+
+        Ok(VmCheckpointInfo {
+            state: CheckpointState::Completed,
+            passes,
+            copied_bytes: throttle.copied(),
+            downtime_ms: downtime.as_millis() as u64,
+            error: None,
+        })
+    }
+
+    fn vm_checkpoint_info(&self) -> result::Result<Vec<u8>, VmError> {
+        let mut info = self
+            .checkpoint_info
+            .clone()
+            .ok_or(VmError::NoCheckpointInProgress)?;
+        if info.state == CheckpointState::Copying {
+            if let Some((passes, copied_bytes)) =
+                self.vm.as_ref().and_then(|vm| vm.checkpoint_progress())
+            {
+                info.passes = passes;
+                info.copied_bytes = copied_bytes;
+            }
+        }
+
+        serde_json::to_vec(&info).map_err(VmError::SerializeJson)
+    }
+
+    fn vm_console(&self, data: &VmConsoleData) -> result::Result<ConsoleBuffer, VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.console_exchange(&data.input)
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -753,6 +1645,9 @@ impl Vmm {
         let reset_evt = self.reset_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning reset EventFd: {}", e))
         })?;
+        let suspend_evt = self.suspend_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning suspend EventFd: {}", e))
+        })?;
         let activate_evt = self.activate_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning activate EventFd: {}", e))
         })?;
@@ -762,9 +1657,11 @@ impl Vmm {
             self.vm_config.clone().unwrap(),
             exit_evt,
             reset_evt,
+            suspend_evt,
             &self.seccomp_action,
             self.hypervisor.clone(),
             activate_evt,
+            self.worker_supervisor.clone(),
         )
         .map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error creating VM from snapshot: {:?}", e))
@@ -792,9 +1689,13 @@ impl Vmm {
         socket
             .read_exact(&mut data)
             .map_err(MigratableError::MigrateSocket)?;
-        let snapshot: Snapshot = serde_json::from_slice(&data).map_err(|e| {
+        let mut snapshot: Snapshot = serde_json::from_slice(&data).map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error deserialising snapshot: {}", e))
         })?;
+        upgrade_snapshot(&mut snapshot).map_err(|e| {
+            Response::error().write_to(socket).ok();
+            e
+        })?;
 
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         vm.load_clock_from_snapshot(&snapshot)
@@ -979,109 +1880,137 @@ impl Vmm {
             "Sending migration: destination_url = {}",
             send_data_migration.destination_url
         );
+        if self.pending_checkpoint.is_some() {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Migration not possible while a checkpoint is taken"
+            )));
+        }
         if let Some(ref mut vm) = self.vm {
+            if vm.has_vtd() {
+                return Err(MigratableError::MigrateSend(anyhow!(
+                    "Migration not possible with the emulated VT-d"
+                )));
+            }
+
             let path = Self::socket_url_to_path(&send_data_migration.destination_url)?;
             let mut socket = UnixStream::connect(&path).map_err(|e| {
                 MigratableError::MigrateSend(anyhow!("Error connecting to UNIX socket: {}", e))
             })?;
 
-            // Start the migration
-            Request::start().write_to(&mut socket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error starting migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error starting migration"
-                )));
-            }
+            // Let the guest fail over from the assigned devices, which can't
+            // be migrated, to their standby virtio-net devices.
+            let failover_devices = vm.unplug_failover_devices().map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error unplugging failover devices: {:?}", e))
+            })?;
 
-            // Send config
-            let config_data = serde_json::to_vec(&vm.get_config()).unwrap();
-            Request::config(config_data.len() as u64).write_to(&mut socket)?;
-            socket
-                .write_all(&config_data)
-                .map_err(MigratableError::MigrateSocket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error during config migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error during config migration"
-                )));
+            let res = Self::vm_send_migration_data(vm, &mut socket);
+            if res.is_err() && !failover_devices.is_empty() {
+                // The guest fails back over to the assigned devices, which
+                // remain usable on the source.
+                vm.replug_failover_devices(failover_devices);
             }
+            res
+        } else {
+            Err(MigratableError::MigrateSend(anyhow!("VM is not running")))
+        }
+    }
 
-            // Start logging dirty pages
-            vm.start_memory_dirty_log()?;
-
-            // Send memory table
-            let table = vm.memory_range_table()?;
-            Request::memory(table.length())
-                .write_to(&mut socket)
-                .unwrap();
-            table.write_to(&mut socket)?;
-            // And then the memory itself
-            vm.send_memory_regions(&table, &mut socket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error during memory migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error during memory migration"
-                )));
-            }
+    fn vm_send_migration_data(
+        vm: &mut Vm,
+        socket: &mut UnixStream,
+    ) -> result::Result<(), MigratableError> {
+        // Start the migration
+        Request::start().write_to(socket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error starting migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error starting migration"
+            )));
+        }
 
-            // Try at most 5 passes of dirty memory sending
-            const MAX_DIRTY_MIGRATIONS: usize = 5;
-            for i in 0..MAX_DIRTY_MIGRATIONS {
-                info!("Dirty memory migration {} of {}", i, MAX_DIRTY_MIGRATIONS);
-                if !Self::vm_maybe_send_dirty_pages(vm, &mut socket)? {
-                    break;
-                }
-            }
+        // Send config
+        let config_data = serde_json::to_vec(&vm.get_config()).unwrap();
+        Request::config(config_data.len() as u64).write_to(socket)?;
+        socket
+            .write_all(&config_data)
+            .map_err(MigratableError::MigrateSocket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error during config migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error during config migration"
+            )));
+        }
 
-            // Now pause VM
-            vm.pause()?;
-
-            // Send last batch of dirty pages
-            Self::vm_maybe_send_dirty_pages(vm, &mut socket)?;
-
-            // Capture snapshot and send it
-            let vm_snapshot = vm.snapshot()?;
-            let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
-            Request::state(snapshot_data.len() as u64).write_to(&mut socket)?;
-            socket
-                .write_all(&snapshot_data)
-                .map_err(MigratableError::MigrateSocket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error during state migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error during state migration"
-                )));
-            }
+        // Start logging dirty pages
+        vm.start_memory_dirty_log()?;
 
-            // Complete the migration
-            Request::complete().write_to(&mut socket)?;
-            let res = Response::read_from(&mut socket)?;
-            if res.status() != Status::Ok {
-                warn!("Error completing migration");
-                Request::abandon().write_to(&mut socket)?;
-                Response::read_from(&mut socket).ok();
-                return Err(MigratableError::MigrateSend(anyhow!(
-                    "Error completing migration"
-                )));
+        // Send memory table
+        let table = vm.memory_range_table()?;
+        Request::memory(table.length()).write_to(socket).unwrap();
+        table.write_to(socket)?;
+        // And then the memory itself
+        vm.send_memory_regions(&table, socket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error during memory migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error during memory migration"
+            )));
+        }
+
+        // Try at most 5 passes of dirty memory sending
+        const MAX_DIRTY_MIGRATIONS: usize = 5;
+        for i in 0..MAX_DIRTY_MIGRATIONS {
+            info!("Dirty memory migration {} of {}", i, MAX_DIRTY_MIGRATIONS);
+            if !Self::vm_maybe_send_dirty_pages(vm, socket)? {
+                break;
             }
-            info!("Migration complete");
-            Ok(())
-        } else {
-            Err(MigratableError::MigrateSend(anyhow!("VM is not running")))
         }
+
+        // Now pause VM
+        vm.pause()?;
+
+        // Send last batch of dirty pages
+        Self::vm_maybe_send_dirty_pages(vm, socket)?;
+
+        // Capture snapshot and send it
+        let vm_snapshot = vm.snapshot()?;
+        let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
+        Request::state(snapshot_data.len() as u64).write_to(socket)?;
+        socket
+            .write_all(&snapshot_data)
+            .map_err(MigratableError::MigrateSocket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error during state migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error during state migration"
+            )));
+        }
+
+        // Complete the migration
+        Request::complete().write_to(socket)?;
+        let res = Response::read_from(socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error completing migration");
+            Request::abandon().write_to(socket)?;
+            Response::read_from(socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error completing migration"
+            )));
+        }
+        info!("Migration complete");
+        Ok(())
     }
 
     fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
@@ -1117,9 +2046,18 @@ impl Vmm {
                             info!("VM exit event");
                             // Consume the event.
                             self.exit_evt.read().map_err(Error::EventFdRead)?;
-                            self.vmm_shutdown().map_err(Error::VmmShutdown)?;
 
-                            break 'outer;
+                            // The other VMs of the process keep running, so
+                            // only the VM which exited is shut down.
+                            if !self.terminating && (self.hosted || !self.hosted_vms.is_empty()) {
+                                if let Err(e) = self.vm_shutdown() {
+                                    error!("Error shutting the VM down: {:?}", e);
+                                }
+                            } else {
+                                self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+
+                                break 'outer;
+                            }
                         }
                         EpollDispatch::Reset => {
                             info!("VM reset event");
@@ -1127,6 +2065,60 @@ impl Vmm {
                             self.reset_evt.read().map_err(Error::EventFdRead)?;
                             self.vm_reboot().map_err(Error::VmReboot)?;
                         }
+                        EpollDispatch::Suspend => {
+                            info!("VM suspend event");
+                            // Consume the event.
+                            self.suspend_evt.read().map_err(Error::EventFdRead)?;
+                            // The guest entering a sleep state isn't an API
+                            // request, so the errors can only be reported,
+                            // the guest being woken up rather than left
+                            // waiting for the wake status.
+                            #[cfg(feature = "acpi")]
+                            if let Err(e) = self.vm_sleep() {
+                                error!("Error entering guest sleep state: {:?}", e);
+                                event!("vm", "sleep-failed");
+                                if let Some(ref mut vm) = self.vm {
+                                    if let Err(e) = vm.wake() {
+                                        error!("Error waking the guest up: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        EpollDispatch::ResumeTimer => {
+                            info!("VM resume timer event");
+                            // The request which scheduled the resume has
+                            // already been answered, so the errors can only
+                            // be reported.
+                            if let Err(e) = self.vm_resume_timer_expired() {
+                                error!("Error handling scheduled VM resume: {:?}", e);
+                            }
+                            // Consume the event by re-arming the timer.
+                            self.arm_resume_timer().map_err(Error::ResumeTimer)?;
+                        }
+                        EpollDispatch::Sigterm => {
+                            info!("VMM termination signal event");
+                            // Consume the event.
+                            self.sigterm_evt.read().map_err(Error::EventFdRead)?;
+
+                            if self.vmm_sigterm() {
+                                self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+
+                                break 'outer;
+                            }
+                        }
+                        EpollDispatch::SigtermTimer => {
+                            warn!("The guest didn't power off within the grace period");
+                            self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+
+                            break 'outer;
+                        }
+                        EpollDispatch::MemoryPressure => {
+                            // Consume the event.
+                            self.memory_pressure_timer
+                                .wait()
+                                .map_err(Error::MemoryPressure)?;
+                            self.vmm_memory_pressure();
+                        }
                         EpollDispatch::Stdin => {
                             if let Some(ref vm) = self.vm {
                                 vm.handle_stdin().map_err(Error::Stdin)?;
@@ -1143,11 +2135,50 @@ impl Vmm {
                                     .map_err(Error::ActivateVirtioDevices)?;
                             }
                         }
+                        EpollDispatch::WorkerFailure => {
+                            // Consume the event.
+                            let failures = self.worker_supervisor.take_failures();
+                            if let Some(ref mut vm) = self.vm {
+                                for failure in failures.iter() {
+                                    if let Err(e) = vm.handle_worker_failure(failure) {
+                                        error!(
+                                            "Error handling worker failure of {}: {:?}",
+                                            failure.device_id, e
+                                        );
+                                    }
+                                }
+                            }
+                        }
                         EpollDispatch::Pty => {
                             if let Some(ref vm) = self.vm {
                                 vm.handle_pty().map_err(Error::Pty)?;
                             }
                         }
+                        EpollDispatch::Checkpoint => {
+                            // Consume the event.
+                            self.checkpoint_evt.read().map_err(Error::EventFdRead)?;
+                            if let Some(checkpoint) = self.pending_checkpoint.take() {
+                                let (passes, copied_bytes) = self
+                                    .vm
+                                    .as_ref()
+                                    .and_then(|vm| vm.checkpoint_progress())
+                                    .unwrap_or_default();
+                                self.checkpoint_info =
+                                    Some(match self.complete_checkpoint(checkpoint) {
+                                        Ok(info) => info,
+                                        Err(e) => {
+                                            error!("Error completing checkpoint: {:?}", e);
+                                            VmCheckpointInfo {
+                                                state: CheckpointState::Failed,
+                                                passes,
+                                                copied_bytes,
+                                                error: Some(format!("{:?}", e)),
+                                                ..Default::default()
+                                            }
+                                        }
+                                    });
+                            }
+                        }
                         EpollDispatch::Api => {
                             // Consume the event.
                             self.api_evt.read().map_err(Error::EventFdRead)?;
@@ -1156,6 +2187,7 @@ impl Vmm {
                             let api_request = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
 
                             info!("API request event: {:?}", api_request);
+                            panic_dump::record_api_request(api_request.kind());
                             match api_request {
                                 ApiRequest::VmCreate(config, sender) => {
                                     let response = self
@@ -1210,6 +2242,12 @@ impl Vmm {
 
                                     sender.send(Ok(response)).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmmMetrics(sender) => {
+                                    let response =
+                                        ApiResponsePayload::VmmMetrics(self.vmm_metrics());
+
+                                    sender.send(Ok(response)).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmPause(sender) => {
                                     let response = self
                                         .vm_pause()
@@ -1226,9 +2264,20 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmScheduleResume(schedule_data, sender) => {
+                                    let response = self
+                                        .vm_schedule_resume(schedule_data.time)
+                                        .map_err(ApiError::VmScheduleResume)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmSnapshot(snapshot_data, sender) => {
                                     let response = self
-                                        .vm_snapshot(&snapshot_data.destination_url)
+                                        .vm_snapshot(
+                                            &snapshot_data.destination_url,
+                                            snapshot_data.parent_url.as_deref(),
+                                        )
                                         .map_err(ApiError::VmSnapshot)
                                         .map(|_| ApiResponsePayload::Empty);
 
@@ -1322,11 +2371,34 @@ impl Vmm {
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmAddBalloon(add_balloon_data, sender) => {
+                                    let response = self
+                                        .vm_add_balloon(add_balloon_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddBalloon)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmCounters(sender) => {
                                     let response = self
                                         .vm_counters()
-                                        .map_err(ApiError::VmInfo)
-                                        .map(ApiResponsePayload::VmAction);
+                                        .map_err(ApiError::VmCounters)
+                                        .map(|info| ApiResponsePayload::VmAction(Some(info)));
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmMemoryRegions(sender) => {
+                                    let response = self
+                                        .vm_memory_regions()
+                                        .map_err(ApiError::VmMemoryRegions)
+                                        .map(|info| ApiResponsePayload::VmAction(Some(info)));
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmConfig(sender) => {
+                                    let response = self
+                                        .vm_config()
+                                        .map_err(ApiError::VmConfig)
+                                        .map(|config| ApiResponsePayload::VmAction(Some(config)));
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -1354,13 +2426,141 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmSleepButton(sender) => {
+                                    let response = self
+                                        .vm_sleep_button()
+                                        .map_err(ApiError::VmSleepButton)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmNmi(sender) => {
+                                    let response = self
+                                        .vm_nmi()
+                                        .map_err(ApiError::VmNmi)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmPowerSupply(power_supply_data, sender) => {
+                                    let response = self
+                                        .vm_power_supply(&power_supply_data)
+                                        .map_err(ApiError::VmPowerSupply)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetNetLink(net_link_data, sender) => {
+                                    let response = self
+                                        .vm_set_net_link(&net_link_data)
+                                        .map_err(ApiError::VmSetNetLink)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmMirrorDisk(mirror_disk_data, sender) => {
+                                    let response = self
+                                        .vm_mirror_disk(&mirror_disk_data)
+                                        .map_err(ApiError::VmMirrorDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmPivotDisk(pivot_disk_data, sender) => {
+                                    let response = self
+                                        .vm_pivot_disk(&pivot_disk_data)
+                                        .map_err(ApiError::VmPivotDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmCheckpoint(checkpoint_data, sender) => {
+                                    let response = self
+                                        .vm_checkpoint(&checkpoint_data)
+                                        .map_err(ApiError::VmCheckpoint)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmCheckpointInfo(sender) => {
+                                    let response = self
+                                        .vm_checkpoint_info()
+                                        .map_err(ApiError::VmCheckpointInfo)
+                                        .map(|info| ApiResponsePayload::VmAction(Some(info)));
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDiskSnapshot(disk_snapshot_data, sender) => {
+                                    let response = self
+                                        .vm_disk_snapshot(&disk_snapshot_data)
+                                        .map_err(ApiError::VmDiskSnapshot)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmConsole(console_data, sender) => {
+                                    let response = self
+                                        .vm_console(&console_data)
+                                        .map_err(ApiError::VmConsole)
+                                        .map(ApiResponsePayload::VmConsole);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmAddVm(id, sender) => {
+                                    let response = self.vmm_add_vm(id);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmGetVm(id, sender) => {
+                                    let response = self.vmm_get_vm(id);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmRemoveVm(id, sender) => {
+                                    let response =
+                                        self.vmm_remove_vm(id).map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmVms(sender) => {
+                                    let response =
+                                        ApiResponsePayload::VmmVms(self.hosted_vms.ids());
+
+                                    sender.send(Ok(response)).map_err(Error::ApiResponseSend)?;
+                                }
                             }
                         }
                     }
+
+                    // The VM can only be changed through the API, by the
+                    // guest resetting or suspending it, or by the policy
+                    // applied on device worker failures.
+                    if matches!(
+                        dispatch_type,
+                        EpollDispatch::Api
+                            | EpollDispatch::Exit
+                            | EpollDispatch::Reset
+                            | EpollDispatch::Suspend
+                            | EpollDispatch::ResumeTimer
+                            | EpollDispatch::WorkerFailure
+                    ) {
+                        self.persist_vm();
+                        self.run_hooks();
+                    }
                 }
             }
         }
 
+        // The VM is gone, there's nothing to re-create anymore.
+        self.persist_vm();
+        self.run_hooks();
+
+        // The process exits once the default VMM thread is done, the hooks
+        // still pending would be lost.
+        if let (false, Some(hooks)) = (self.hosted, &self.hooks) {
+            hooks.wait();
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,199 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Runtime control of the log records being emitted.
+//!
+//! The level selected from the command line can be changed while the VMM is
+//! running. All the records of a component, down to the trace ones, can also
+//! be enabled without lowering the level of the others, optionally for a
+//! single device only. The logger installed by the binary relies on
+//! `enabled()` to filter the records.
+
+use log::{LevelFilter, Metadata};
+use option_parser::{OptionParser, OptionParserError, Toggle};
+use std::sync::RwLock;
+
+/// Records of a component to enable or disable.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct TraceConfig {
+    /// Prefix of the module path the records come from, such as
+    /// `vm_virtio::queue`.
+    pub target: String,
+    /// Identifier of the device the records are restricted to, as the
+    /// threads of the devices are named after it.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub enabled: bool,
+}
+
+impl TraceConfig {
+    pub const SYNTAX: &'static str =
+        "Trace parameters \"target=<module_path>,id=<device_id>,enabled=on|off\"";
+    pub fn parse(trace: &str) -> Result<Self, OptionParserError> {
+        let mut parser = OptionParser::new();
+        parser.add("target").add("id").add("enabled");
+        parser.parse(trace)?;
+
+        let target = parser
+            .get("target")
+            .ok_or_else(|| OptionParserError::InvalidSyntax(trace.to_owned()))?;
+        let id = parser.get("id");
+        let enabled = parser
+            .convert::<Toggle>("enabled")?
+            .unwrap_or(Toggle(true))
+            .0;
+
+        Ok(TraceConfig {
+            target,
+            id,
+            enabled,
+        })
+    }
+
+    fn matches(&self, metadata: &Metadata) -> bool {
+        if !metadata.target().starts_with(&self.target) {
+            return false;
+        }
+
+        // The devices running several threads suffix their names.
+        match (&self.id, std::thread::current().name()) {
+            (None, _) => true,
+            (Some(id), Some(name)) => name
+                .strip_prefix(id.as_str())
+                .map_or(false, |suffix| suffix.is_empty() || suffix.starts_with('_')),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+struct LogFilter {
+    level: LevelFilter,
+    traces: Vec<TraceConfig>,
+}
+
+lazy_static! {
+    static ref LOG_FILTER: RwLock<LogFilter> = RwLock::new(LogFilter {
+        level: LevelFilter::Warn,
+        traces: Vec::new(),
+    });
+}
+
+// The records above the maximum level are discarded before reaching the
+// logger, which is why it's raised as long as some traces are enabled.
+fn update_max_level(filter: &LogFilter) {
+    log::set_max_level(if filter.traces.is_empty() {
+        filter.level
+    } else {
+        LevelFilter::Trace
+    });
+}
+
+/// Sets the level of the records being emitted.
+pub fn set_level(level: LevelFilter) {
+    let mut filter = LOG_FILTER.write().unwrap();
+    filter.level = level;
+    update_max_level(&filter);
+}
+
+/// Enables or disables all the records of a component.
+pub fn set_trace(trace: &TraceConfig) {
+    let mut filter = LOG_FILTER.write().unwrap();
+    filter
+        .traces
+        .retain(|t| t.target != trace.target || t.id != trace.id);
+    if trace.enabled {
+        filter.traces.push(trace.clone());
+    }
+    update_max_level(&filter);
+}
+
+/// Whether a record must be emitted.
+pub fn enabled(metadata: &Metadata) -> bool {
+    let filter = LOG_FILTER.read().unwrap();
+    metadata.level() <= filter.level || filter.traces.iter().any(|t| t.matches(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_trace_parsing() -> Result<(), OptionParserError> {
+        assert_eq!(
+            TraceConfig::parse("target=vm_virtio::queue")?,
+            TraceConfig {
+                target: "vm_virtio::queue".to_owned(),
+                id: None,
+                enabled: true,
+            }
+        );
+        assert_eq!(
+            TraceConfig::parse("target=virtio_devices,id=_disk0,enabled=off")?,
+            TraceConfig {
+                target: "virtio_devices".to_owned(),
+                id: Some("_disk0".to_owned()),
+                enabled: false,
+            }
+        );
+        assert!(TraceConfig::parse("id=_disk0").is_err());
+        Ok(())
+    }
+
+    fn metadata(level: Level, target: &str) -> Metadata {
+        Metadata::builder().level(level).target(target).build()
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let level = LOG_FILTER.read().unwrap().level;
+        set_level(LevelFilter::Info);
+        assert!(enabled(&metadata(Level::Info, "vmm::vm")));
+        assert!(!enabled(&metadata(Level::Debug, "vmm::vm")));
+        assert_eq!(log::max_level(), LevelFilter::Info);
+
+        let trace = TraceConfig {
+            target: "vm_virtio::queue".to_owned(),
+            id: None,
+            enabled: true,
+        };
+        set_trace(&trace);
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+        assert!(enabled(&metadata(Level::Trace, "vm_virtio::queue")));
+        assert!(!enabled(&metadata(Level::Debug, "vmm::vm")));
+
+        // The records are restricted to the threads of the device.
+        let device_trace = TraceConfig {
+            target: "vmm".to_owned(),
+            id: Some("_disk0".to_owned()),
+            enabled: true,
+        };
+        set_trace(&device_trace);
+        let thread_enabled = |name: &str| {
+            std::thread::Builder::new()
+                .name(name.to_owned())
+                .spawn(|| enabled(&metadata(Level::Debug, "vmm::vm")))
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        assert!(thread_enabled("_disk0_q0"));
+        assert!(!thread_enabled("_disk01_q0"));
+
+        set_trace(&TraceConfig {
+            enabled: false,
+            ..trace
+        });
+        set_trace(&TraceConfig {
+            enabled: false,
+            ..device_trace
+        });
+        assert_eq!(log::max_level(), LevelFilter::Info);
+        assert!(!enabled(&metadata(Level::Trace, "vm_virtio::queue")));
+
+        // The level is global to the process.
+        set_level(level);
+    }
+}
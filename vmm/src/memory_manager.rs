@@ -5,7 +5,11 @@
 #[cfg(target_arch = "x86_64")]
 use crate::config::SgxEpcConfig;
 use crate::config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
-use crate::migration::url_to_path;
+use crate::migration::{
+    check_snapshot_layout, recv_vm_snapshot, send_snapshot_metadata, snapshot_chain, url_to_path,
+    SnapshotMetadata, SNAPSHOT_MEMORY_RANGES_FILE,
+};
+use crate::numa_placement::NumaPlacement;
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 #[cfg(feature = "acpi")]
@@ -18,19 +22,25 @@ use arch::{layout, RegionType};
 use devices::ioapic;
 #[cfg(target_arch = "x86_64")]
 use libc::{MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi;
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Seek, SeekFrom};
 use std::ops::Deref;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 #[cfg(target_arch = "x86_64")]
+use virtio_devices::BounceBuffer;
 use vm_allocator::GsiApic;
 use vm_allocator::SystemAllocator;
 use vm_device::BusDevice;
@@ -49,7 +59,7 @@ use vm_migration::{
 #[cfg(feature = "acpi")]
 pub const MEMORY_MANAGER_ACPI_SIZE: usize = 0x18;
 
-const DEFAULT_MEMORY_ZONE: &str = "mem0";
+pub const DEFAULT_MEMORY_ZONE: &str = "mem0";
 
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
@@ -61,6 +71,124 @@ const MPOL_BIND: u32 = 2;
 const MPOL_MF_STRICT: u32 = 1;
 const MPOL_MF_MOVE: u32 = 1 << 1;
 
+// Bounds of the offsets the guest address layout is randomized with. None of
+// them takes more than a quarter of the address space left.
+const RANDOM_VIRTIO_MEM_GAP: u64 = 64 << 30;
+const RANDOM_DEVICE_AREA_GAP: u64 = 64 << 30;
+const RANDOM_DEVICE_AREA_ALIGN: u64 = 2 << 20;
+#[cfg(target_arch = "x86_64")]
+const RANDOM_MMIO_HOLE_GAP: u64 = 64 << 20;
+#[cfg(target_arch = "x86_64")]
+const RANDOM_MMIO_HOLE_ALIGN: u64 = 1 << 20;
+
+// Size of the chunks the guest memory is copied to a checkpoint by, bounding
+// how far the copy gets ahead of its bandwidth before being throttled.
+const CHECKPOINT_COPY_CHUNK: u64 = 4 << 20;
+
+/// Paces the guest memory copied to a checkpoint while the VM is running,
+/// and counts the bytes copied. The count and the cancellation are shared,
+/// the copy running on its own thread.
+pub struct CopyThrottle {
+    // Bytes per second, the copy being unlimited without it.
+    bandwidth: Option<u64>,
+    start: Instant,
+    copied: Arc<AtomicU64>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CopyThrottle {
+    pub fn new(bandwidth: Option<u64>) -> Self {
+        CopyThrottle {
+            bandwidth: bandwidth.filter(|bandwidth| *bandwidth > 0),
+            start: Instant::now(),
+            copied: Arc::new(AtomicU64::new(0)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Bytes copied so far.
+    pub fn copied(&self) -> u64 {
+        self.copied.load(Ordering::Acquire)
+    }
+
+    /// Counter of the bytes copied, readable while the copy goes on.
+    pub fn copied_counter(&self) -> Arc<AtomicU64> {
+        self.copied.clone()
+    }
+
+    /// Flag stopping the copy at its next chunk once set.
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Lifts the bandwidth limit, once the VM is paused.
+    pub fn unthrottle(&mut self) {
+        self.bandwidth = None;
+    }
+
+    fn account(&mut self, bytes: u64) -> std::result::Result<(), MigratableError> {
+        if self.cancelled.load(Ordering::Acquire) {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Checkpoint copy cancelled"
+            )));
+        }
+
+        let copied = self.copied.fetch_add(bytes, Ordering::AcqRel) + bytes;
+        if let Some(bandwidth) = self.bandwidth {
+            let due = Duration::from_secs_f64(copied as f64 / bandwidth as f64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Picks the offsets the guest address layout is randomized with. A given
+// seed always gives the same offsets, in the same order, which lets a VM be
+// restored or migrated with the layout it was booted with.
+struct LayoutRandomizer {
+    state: u64,
+}
+
+impl LayoutRandomizer {
+    fn new(seed: Option<u64>) -> Option<Self> {
+        seed.map(|state| LayoutRandomizer { state })
+    }
+
+    // SplitMix64, good enough to spread the offsets from a random seed.
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Returns a multiple of `align` below both `max` and a quarter of
+    // `space`.
+    fn offset(&mut self, max: u64, space: u64, align: u64) -> u64 {
+        let slots = std::cmp::min(max, space / 4) / align;
+        if slots == 0 {
+            return 0;
+        }
+        (self.next() % slots) * align
+    }
+}
+
+fn random_offset(
+    randomizer: &mut Option<LayoutRandomizer>,
+    max: u64,
+    space: u64,
+    align: u64,
+) -> u64 {
+    randomizer
+        .as_mut()
+        .map_or(0, |randomizer| randomizer.offset(max, space, align))
+}
+
 #[derive(Default)]
 struct HotPlugState {
     base: u64,
@@ -109,6 +237,64 @@ impl MemoryZone {
 
 pub type MemoryZones = HashMap<String, MemoryZone>;
 
+/// Host backing of a guest RAM region.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryBacking {
+    /// Anonymous memory file, only reachable through the VMM file descriptor.
+    Memfd,
+    /// File the user provided, reachable from the host filesystem.
+    File,
+}
+
+/// Description of a guest RAM region, as returned by the `vm.memory-regions`
+/// API, allowing external tools to map the guest RAM.
+#[derive(Clone, Debug, Serialize)]
+pub struct MemoryRegionInfo {
+    pub zone: String,
+    pub start_addr: u64,
+    pub size: u64,
+    pub shared: bool,
+    pub backing: MemoryBacking,
+    pub file_offset: u64,
+    /// File descriptor of the VMM backing the region, which other processes
+    /// can open through /proc/<pid>/fd/<fd>. It is only reported for shared
+    /// regions, as the file doesn't hold the guest writes otherwise.
+    pub fd: Option<RawFd>,
+}
+
+impl MemoryRegionInfo {
+    fn new(zone: &str, region: &GuestRegionMmap) -> Self {
+        let shared = region.flags() & libc::MAP_SHARED == libc::MAP_SHARED;
+        let (backing, file_offset, fd) = match region.file_offset() {
+            Some(file_offset) => {
+                let backing = if MemoryManager::is_hardlink(file_offset.file()) {
+                    MemoryBacking::File
+                } else {
+                    MemoryBacking::Memfd
+                };
+                let fd = if shared {
+                    Some(file_offset.file().as_raw_fd())
+                } else {
+                    None
+                };
+                (backing, file_offset.start(), fd)
+            }
+            None => (MemoryBacking::Memfd, 0, None),
+        };
+
+        MemoryRegionInfo {
+            zone: zone.to_string(),
+            start_addr: region.start_addr().raw_value(),
+            size: region.len(),
+            shared,
+            backing,
+            file_offset,
+            fd,
+        }
+    }
+}
+
 struct GuestRamMapping {
     slot: u32,
     gpa: u64,
@@ -121,6 +307,7 @@ pub struct MemoryManager {
     next_memory_slot: u32,
     start_of_device_area: GuestAddress,
     end_of_device_area: GuestAddress,
+    end_of_mmio_hole: GuestAddress,
     pub vm: Arc<dyn hypervisor::Vm>,
     hotplug_slots: Vec<HotPlugState>,
     selected_slot: usize,
@@ -138,6 +325,18 @@ pub struct MemoryManager {
     sgx_epc_region: Option<SgxEpcRegion>,
     user_provided_zones: bool,
     snapshot_memory_regions: Vec<MemoryRegion>,
+    // Snapshot last taken or restored from, the dirty log tracking the guest
+    // memory written since, for the next snapshot to be incremental.
+    last_snapshot_url: Option<String>,
+    // Parent of the incremental snapshot being taken, along with the guest
+    // memory written since the parent.
+    snapshot_parent: Option<(String, MemoryRangeTable)>,
+    // Whether the dirty log was reset for the snapshot being taken, which
+    // the next snapshot can then build upon.
+    snapshot_dirty_log_reset: bool,
+    // Whether the guest memory of the snapshot being taken has been copied
+    // to its memory region files already, by a checkpoint.
+    snapshot_precopied: bool,
     memory_zones: MemoryZones,
     log_dirty: bool, // Enable dirty logging for created RAM regions
 
@@ -146,6 +345,10 @@ pub struct MemoryManager {
     // slots that the mapping is created in.
     guest_ram_mappings: Vec<GuestRamMapping>,
 
+    // Region shared with the host the virtio devices are restricted to.
+    #[cfg(target_arch = "x86_64")]
+    bounce_buffer: Option<BounceBuffer>,
+
     #[cfg(feature = "acpi")]
     pub acpi_address: GuestAddress,
 }
@@ -158,6 +361,12 @@ pub enum Error {
     /// Failed to set shared file length.
     SharedFileSetLen(io::Error),
 
+    /// Failed to seal the size of the memfd backing the memory.
+    SharedFileSeal(io::Error),
+
+    /// A device DAX backing a memory zone can only be mapped shared.
+    DaxDeviceNotShared(PathBuf),
+
     /// Mmap backed guest memory error
     GuestMemory(MmapError),
 
@@ -274,6 +483,10 @@ pub enum Error {
 
     /// Failed to allocate MMIO address
     AllocateMmioAddress,
+
+    /// Failed to create the bounce buffers
+    #[cfg(target_arch = "x86_64")]
+    BounceBuffer(MmapError),
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -505,17 +718,43 @@ impl MemoryManager {
         Ok(())
     }
 
+    // Copies the guest memory ranges held by the incremental snapshot at
+    // `source_url` over the memory restored from its ancestors.
+    fn fill_saved_ranges(&self, source_url: &str, ranges: &[(u64, u64)]) -> Result<(), Error> {
+        let mut memory_ranges_path = url_to_path(source_url).map_err(Error::Restore)?;
+        memory_ranges_path.push(SNAPSHOT_MEMORY_RANGES_FILE);
+        let mut memory_ranges_file = OpenOptions::new()
+            .read(true)
+            .open(memory_ranges_path)
+            .map_err(Error::SnapshotOpen)?;
+
+        for (gpa, length) in ranges {
+            self.guest_memory
+                .memory()
+                .read_exact_from(
+                    GuestAddress(*gpa),
+                    &mut memory_ranges_file,
+                    *length as usize,
+                )
+                .map_err(Error::SnapshotCopy)?;
+        }
+
+        Ok(())
+    }
+
     pub fn new(
         vm: Arc<dyn hypervisor::Vm>,
         config: &MemoryConfig,
         prefault: bool,
         phys_bits: u8,
+        numa_placement: Option<&NumaPlacement>,
+        layout_seed: Option<u64>,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
         let user_provided_zones = config.size == 0;
         let mut allow_mem_hotplug: bool = false;
 
-        let (ram_size, zones) = if !user_provided_zones {
+        let (ram_size, mut zones) = if !user_provided_zones {
             if config.zones.is_some() {
                 error!(
                     "User defined memory regions can't be provided if the \
@@ -632,6 +871,17 @@ impl MemoryManager {
             (total_ram_size, zones)
         };
 
+        // Bind the zones without any explicit host NUMA node to the node
+        // picked by the automatic placement. Shared file backed zones can't
+        // be bound, as their pages belong to the page cache.
+        if let Some(numa_placement) = numa_placement {
+            for zone in zones.iter_mut() {
+                if zone.host_numa_node.is_none() && !(zone.shared && zone.file.is_some()) {
+                    zone.host_numa_node = numa_placement.memory_zone_node(&zone.id);
+                }
+            }
+        }
+
         // Init guest memory
         let arch_mem_regions = arch::arch_memory_regions(ram_size);
 
@@ -654,10 +904,11 @@ impl MemoryManager {
             (((mmio_address_space_size) >> 16) << 16),
             mmio_address_space_size
         );
-        let end_of_device_area = GuestAddress(mmio_address_space_size - 1);
+        let mut end_of_device_area = GuestAddress(mmio_address_space_size - 1);
 
         let mut start_of_device_area =
             MemoryManager::start_addr(guest_memory.last_addr(), allow_mem_hotplug)?;
+        let mut randomizer = LayoutRandomizer::new(layout_seed);
         let mut virtio_mem_regions: Vec<Arc<GuestRegionMmap>> = Vec::new();
 
         // Update list of memory zones for resize.
@@ -680,6 +931,14 @@ impl MemoryManager {
                                 / virtio_devices::VIRTIO_MEM_ALIGN_SIZE
                                 * virtio_devices::VIRTIO_MEM_ALIGN_SIZE,
                         );
+                        let start_addr = start_addr
+                            .checked_add(random_offset(
+                                &mut randomizer,
+                                RANDOM_VIRTIO_MEM_GAP,
+                                end_of_device_area.0.saturating_sub(start_addr.0),
+                                virtio_devices::VIRTIO_MEM_ALIGN_SIZE,
+                            ))
+                            .ok_or(Error::GuestAddressOverFlow)?;
 
                         let region = MemoryManager::create_ram_region(
                             &None,
@@ -713,6 +972,27 @@ impl MemoryManager {
             }
         }
 
+        // The devices are allocated from the top of the device areas, which
+        // randomly end below the top of the address space and of the 32-bit
+        // hole. RAM has to start where the boot protocols expect it to.
+        end_of_device_area = end_of_device_area.unchecked_sub(random_offset(
+            &mut randomizer,
+            RANDOM_DEVICE_AREA_GAP,
+            end_of_device_area.0.saturating_sub(start_of_device_area.0),
+            RANDOM_DEVICE_AREA_ALIGN,
+        ));
+        #[cfg(target_arch = "x86_64")]
+        let mmio_hole_size = layout::MEM_32BIT_DEVICES_SIZE
+            - random_offset(
+                &mut randomizer,
+                RANDOM_MMIO_HOLE_GAP,
+                layout::MEM_32BIT_DEVICES_SIZE,
+                RANDOM_MMIO_HOLE_ALIGN,
+            );
+        #[cfg(not(target_arch = "x86_64"))]
+        let mmio_hole_size = layout::MEM_32BIT_DEVICES_SIZE;
+        let end_of_mmio_hole = layout::MEM_32BIT_DEVICES_START.unchecked_add(mmio_hole_size - 1);
+
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
 
         let mut hotplug_slots = Vec::with_capacity(HOTPLUG_COUNT);
@@ -730,9 +1010,9 @@ impl MemoryManager {
                     1 << 16
                 },
                 GuestAddress(0),
-                mmio_address_space_size,
+                end_of_device_area.0 + 1,
                 layout::MEM_32BIT_DEVICES_START,
-                layout::MEM_32BIT_DEVICES_SIZE,
+                mmio_hole_size,
                 #[cfg(target_arch = "x86_64")]
                 vec![GsiApic::new(
                     X86_64_IRQ_BASE,
@@ -760,6 +1040,7 @@ impl MemoryManager {
             next_memory_slot: 0,
             start_of_device_area,
             end_of_device_area,
+            end_of_mmio_hole,
             vm,
             hotplug_slots,
             selected_slot: 0,
@@ -777,8 +1058,14 @@ impl MemoryManager {
             sgx_epc_region: None,
             user_provided_zones,
             snapshot_memory_regions: Vec::new(),
+            last_snapshot_url: None,
+            snapshot_parent: None,
+            snapshot_dirty_log_reset: false,
+            snapshot_precopied: false,
             memory_zones,
             guest_ram_mappings: Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            bounce_buffer: None,
             #[cfg(feature = "acpi")]
             acpi_address,
             log_dirty,
@@ -844,18 +1131,44 @@ impl MemoryManager {
         source_url: Option<&str>,
         prefault: bool,
         phys_bits: u8,
+        numa_placement: Option<&NumaPlacement>,
+        layout_seed: Option<u64>,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
         let mm = MemoryManager::new(
             vm,
             config,
             prefault,
             phys_bits,
+            numa_placement,
+            layout_seed,
             #[cfg(feature = "tdx")]
             false,
         )?;
 
         if let Some(source_url) = source_url {
-            let vm_snapshot_path = url_to_path(source_url).map_err(Error::Restore)?;
+            // An incremental snapshot only holds the memory written since its
+            // parent, the rest being restored from the full snapshot its
+            // chain starts from, with each incremental snapshot of the chain
+            // applied over it in order.
+            let chain = snapshot_chain(source_url).map_err(Error::Restore)?;
+            let full_url = &chain[0].0;
+            let full_snapshot;
+            let snapshot = if chain.len() == 1 {
+                snapshot
+            } else {
+                full_snapshot = recv_vm_snapshot(full_url).map_err(Error::Restore)?;
+                full_snapshot
+                    .snapshots
+                    .get(MEMORY_MANAGER_SNAPSHOT_ID)
+                    .map(|s| s.as_ref())
+                    .ok_or_else(|| {
+                        Error::Restore(MigratableError::Restore(anyhow!(
+                            "Missing memory manager snapshot in {}",
+                            full_url
+                        )))
+                    })?
+            };
+            let vm_snapshot_path = url_to_path(full_url).map_err(Error::Restore)?;
 
             let mem_snapshot: MemoryManagerSnapshotData = snapshot
                 .to_versioned_state(MEMORY_MANAGER_SNAPSHOT_ID)
@@ -878,7 +1191,22 @@ impl MemoryManager {
                 }
             }
 
-            mm.lock().unwrap().fill_saved_regions(saved_regions)?;
+            {
+                let mut mm = mm.lock().unwrap();
+                check_snapshot_layout(&chain, &mm.memory_layout())
+                    .map_err(|e| Error::Restore(MigratableError::Restore(e)))?;
+                mm.fill_saved_regions(saved_regions)?;
+                for (url, metadata) in chain.iter().skip(1) {
+                    mm.fill_saved_ranges(url, &metadata.memory_ranges)?;
+                }
+
+                // The memory written from now on is tracked for the next
+                // snapshot to build upon this one, if the dirty log is
+                // available.
+                if mm.start_memory_dirty_log().is_ok() {
+                    mm.last_snapshot_url = Some(source_url.to_owned());
+                }
+            }
 
             Ok(mm)
         } else {
@@ -896,6 +1224,22 @@ impl MemoryManager {
         }
     }
 
+    fn seal_size(file: &File) -> Result<(), io::Error> {
+        let res = unsafe {
+            libc::fcntl(
+                file.as_raw_fd(),
+                libc::F_ADD_SEALS,
+                libc::F_SEAL_GROW | libc::F_SEAL_SHRINK,
+            )
+        };
+
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
     fn mbind(
         addr: *mut u8,
         len: u64,
@@ -960,39 +1304,55 @@ impl MemoryManager {
                         .open(file)
                         .map_err(Error::SharedFileCreate)?;
 
+                    // Device DAX refuses private mappings, which would only
+                    // fail with EINVAL from mmap() otherwise.
+                    let is_char_device = f
+                        .metadata()
+                        .map_err(Error::SharedFileCreate)?
+                        .file_type()
+                        .is_char_device();
+                    if is_char_device && !shared {
+                        return Err(Error::DaxDeviceNotShared(file.clone()));
+                    }
+
                     (f, file_offset)
                 }
             }
             None => {
-                let fd = Self::memfd_create(
-                    &ffi::CString::new("ch_ram").unwrap(),
-                    if hugepages {
-                        libc::MFD_HUGETLB
-                            | if let Some(hugepage_size) = hugepage_size {
-                                /*
-                                 * From the Linux kernel:
-                                 * Several system calls take a flag to request "hugetlb" huge pages.
-                                 * Without further specification, these system calls will use the
-                                 * system's default huge page size.  If a system supports multiple
-                                 * huge page sizes, the desired huge page size can be specified in
-                                 * bits [26:31] of the flag arguments.  The value in these 6 bits
-                                 * will encode the log2 of the huge page size.
-                                 */
-
-                                hugepage_size.trailing_zeros() << 26
-                            } else {
-                                // Use the system default huge page size
-                                0
-                            }
-                    } else {
-                        0
-                    },
-                )
-                .map_err(Error::SharedFileCreate)?;
+                let mut flags = libc::MFD_ALLOW_SEALING;
+                flags |= if hugepages {
+                    libc::MFD_HUGETLB
+                        | if let Some(hugepage_size) = hugepage_size {
+                            /*
+                             * From the Linux kernel:
+                             * Several system calls take a flag to request "hugetlb" huge pages.
+                             * Without further specification, these system calls will use the
+                             * system's default huge page size.  If a system supports multiple
+                             * huge page sizes, the desired huge page size can be specified in
+                             * bits [26:31] of the flag arguments.  The value in these 6 bits
+                             * will encode the log2 of the huge page size.
+                             */
+
+                            hugepage_size.trailing_zeros() << 26
+                        } else {
+                            // Use the system default huge page size
+                            0
+                        }
+                } else {
+                    0
+                };
+                let fd = Self::memfd_create(&ffi::CString::new("ch_ram").unwrap(), flags)
+                    .map_err(Error::SharedFileCreate)?;
 
                 let f = unsafe { File::from_raw_fd(fd) };
                 f.set_len(size as u64).map_err(Error::SharedFileSetLen)?;
 
+                // Other processes mapping the shared memory, such as the
+                // vhost-user backends, must not see its size change.
+                if shared {
+                    Self::seal_size(&f).map_err(Error::SharedFileSeal)?;
+                }
+
                 (f, 0)
             }
         };
@@ -1185,6 +1545,64 @@ impl MemoryManager {
         self.boot_guest_memory.clone()
     }
 
+    /// Gets the guest memory the virtio devices access, which is only the
+    /// bounce buffers when there are some.
+    pub fn virtio_memory(&self) -> GuestMemoryAtomic<GuestMemoryMmap> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(bounce_buffer) = &self.bounce_buffer {
+            return bounce_buffer.memory();
+        }
+
+        self.guest_memory()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn bounce_buffer(&self) -> Option<&BounceBuffer> {
+        self.bounce_buffer.as_ref()
+    }
+
+    /// Reserves the region the guest bounces the DMA buffers of the virtio
+    /// devices through, in the device area. The region is mapped into the
+    /// guest but left out of the guest memory, so that it's neither exposed
+    /// as RAM nor saved along with the VM, only holding transient copies.
+    #[cfg(target_arch = "x86_64")]
+    pub fn reserve_bounce_buffer(&mut self, size: u64) -> Result<(), Error> {
+        let start_addr = self
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+            .ok_or(Error::AllocateMmioAddress)?;
+
+        let region = MemoryManager::create_ram_region(
+            &None,
+            0,
+            start_addr,
+            size as usize,
+            false,
+            self.shared,
+            self.hugepages,
+            self.hugepage_size,
+            None,
+        )?;
+        self.create_userspace_mapping(
+            region.start_addr().raw_value(),
+            region.len() as u64,
+            region.as_ptr() as u64,
+            false,
+            false,
+            false,
+        )?;
+        info!(
+            "Reserved {} bytes of bounce buffers at 0x{:x}",
+            size,
+            start_addr.raw_value()
+        );
+
+        self.bounce_buffer = Some(BounceBuffer::new(region).map_err(Error::BounceBuffer)?);
+        Ok(())
+    }
+
     pub fn allocator(&self) -> Arc<Mutex<SystemAllocator>> {
         self.allocator.clone()
     }
@@ -1197,6 +1615,10 @@ impl MemoryManager {
         self.end_of_device_area
     }
 
+    pub fn end_of_mmio_hole(&self) -> GuestAddress {
+        self.end_of_mmio_hole
+    }
+
     pub fn allocate_memory_slot(&mut self) -> u32 {
         let slot_id = self.next_memory_slot;
         self.next_memory_slot += 1;
@@ -1479,6 +1901,24 @@ impl MemoryManager {
         &self.sgx_epc_region
     }
 
+    // Whether the content of the region is saved through its backing file.
+    fn saved_by_user(region: &GuestRegionMmap) -> bool {
+        if let Some(file_offset) = region.file_offset() {
+            // In this very specific case, we know the memory region is
+            // backed by a file on the host filesystem that can be accessed
+            // by the user, and additionally the mapping is shared, which
+            // means that modifications to the content are written to the
+            // actual file.
+            // When meeting these conditions, we can skip the copy of the
+            // memory content for this specific region, as we can assume the
+            // user will have it saved through the backing file already.
+            return (region.flags() & libc::MAP_SHARED == libc::MAP_SHARED)
+                && Self::is_hardlink(file_offset.file());
+        }
+
+        false
+    }
+
     pub fn is_hardlink(f: &File) -> bool {
         let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
         let ret = unsafe { libc::fstat(f.as_raw_fd(), stat.as_mut_ptr()) };
@@ -1494,11 +1934,31 @@ impl MemoryManager {
         &self.memory_zones
     }
 
+    /// Lists the guest RAM regions of every memory zone, including the
+    /// virtio-mem ones, ordered by guest physical address.
+    pub fn memory_regions_info(&self) -> Vec<MemoryRegionInfo> {
+        let mut regions = Vec::new();
+        for (zone_id, memory_zone) in self.memory_zones.iter() {
+            let virtio_mem_region = memory_zone
+                .virtio_mem_zone()
+                .as_ref()
+                .map(|virtio_mem_zone| virtio_mem_zone.region());
+            for region in memory_zone.regions().iter().chain(virtio_mem_region) {
+                regions.push(MemoryRegionInfo::new(zone_id, region));
+            }
+        }
+        regions.sort_by_key(|region| region.start_addr);
+
+        regions
+    }
+
     // Generate a table for the pages that are dirty. The dirty pages are collapsed
     // together in the table if they are contiguous.
     pub fn dirty_memory_range_table(
-        &self,
+        &mut self,
     ) -> std::result::Result<MemoryRangeTable, MigratableError> {
+        // The memory written since the last snapshot is no longer tracked.
+        self.last_snapshot_url = None;
         let page_size = 4096; // TODO: Does this need to vary?
         let mut table = MemoryRangeTable::default();
         for r in &self.guest_ram_mappings {
@@ -1564,7 +2024,8 @@ impl MemoryManager {
     // The dirty log is cleared by the kernel by calling the KVM_GET_DIRTY_LOG ioctl.
     // Just before we do a bulk copy we want to clear the dirty log so that
     // pages touched during our bulk copy are tracked.
-    pub fn start_memory_dirty_log(&self) -> std::result::Result<(), MigratableError> {
+    pub fn start_memory_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
+        self.last_snapshot_url = None;
         for r in &self.guest_ram_mappings {
             self.vm.get_dirty_log(r.slot, r.size).map_err(|e| {
                 MigratableError::MigrateSend(anyhow!("Error getting VM dirty log {}", e))
@@ -1577,6 +2038,130 @@ impl MemoryManager {
 
         Ok(())
     }
+
+    /// Prepares the next snapshot, which is incremental when given the URL
+    /// of the snapshot last taken or restored from, holding only the guest
+    /// memory written since. Otherwise the snapshot is a full one.
+    pub fn prepare_snapshot(
+        &mut self,
+        parent_url: Option<&str>,
+    ) -> std::result::Result<(), MigratableError> {
+        self.snapshot_parent = None;
+        self.snapshot_precopied = false;
+        match parent_url {
+            Some(parent_url) => {
+                if self.last_snapshot_url.as_deref() != Some(parent_url) {
+                    return Err(MigratableError::Snapshot(anyhow!(
+                        "The memory written since snapshot {} is not tracked, \
+                        it is not the snapshot last taken or restored from",
+                        parent_url
+                    )));
+                }
+                // The memory of the snapshot is applied over the regions of
+                // the full snapshot its chain starts from.
+                let chain = snapshot_chain(parent_url)?;
+                check_snapshot_layout(&chain, &self.memory_layout())
+                    .map_err(MigratableError::Snapshot)?;
+                let dirty_ranges = self.dirty_memory_range_table()?;
+                self.snapshot_parent = Some((parent_url.to_owned(), dirty_ranges));
+                self.snapshot_dirty_log_reset = true;
+            }
+            // Without a dirty log, a full snapshot can't be built upon.
+            None => self.snapshot_dirty_log_reset = self.start_memory_dirty_log().is_ok(),
+        }
+
+        Ok(())
+    }
+
+    // The address and size of the guest memory regions.
+    fn memory_layout(&self) -> Vec<(u64, u64)> {
+        self.guest_memory
+            .memory()
+            .iter()
+            .map(|region| (region.start_addr().raw_value(), region.len()))
+            .collect()
+    }
+
+    /// Records the snapshot prepared as sent to `destination_url`, for the
+    /// next snapshot to build upon it.
+    pub fn snapshot_sent(&mut self, destination_url: &str) {
+        if self.snapshot_dirty_log_reset {
+            self.last_snapshot_url = Some(destination_url.to_owned());
+        }
+        self.snapshot_parent = None;
+        self.snapshot_dirty_log_reset = false;
+        self.snapshot_precopied = false;
+    }
+
+    /// Prepares the snapshot completing a checkpoint, the guest memory
+    /// having been copied to its memory region files already. The dirty log
+    /// was reset by the last copy, for the next snapshot to build upon it.
+    pub fn prepare_checkpoint(&mut self) {
+        self.snapshot_parent = None;
+        self.snapshot_dirty_log_reset = true;
+        self.snapshot_precopied = true;
+    }
+
+    /// Copies the `ranges` of guest memory to the memory region files of the
+    /// checkpoint at `destination_url`, creating them if needed. As for a
+    /// snapshot, the regions the user saves through their backing file are
+    /// skipped. The memory manager doesn't need to be held meanwhile.
+    pub fn precopy_memory(
+        guest_memory: &GuestMemoryMmap,
+        destination_url: &str,
+        ranges: &MemoryRangeTable,
+        throttle: &mut CopyThrottle,
+    ) -> std::result::Result<(), MigratableError> {
+        let vm_memory_snapshot_path = url_to_path(destination_url)?;
+        let mut region_files: HashMap<usize, File> = HashMap::new();
+
+        for range in ranges.regions() {
+            let (index, region) = guest_memory
+                .iter()
+                .enumerate()
+                .find(|(_, region)| region.address_in_range(GuestAddress(range.gpa)))
+                .ok_or_else(|| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "Error finding 'guest memory region' with address {:x}",
+                        range.gpa
+                    ))
+                })?;
+            if Self::saved_by_user(region) {
+                continue;
+            }
+
+            let memory_region_file = match region_files.entry(index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut memory_region_path = vm_memory_snapshot_path.clone();
+                    memory_region_path.push(format!("memory-region-{}", index));
+                    entry.insert(
+                        OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .open(memory_region_path)
+                            .map_err(|e| MigratableError::MigrateSend(e.into()))?,
+                    )
+                }
+            };
+
+            let mut offset = 0;
+            while offset < range.length {
+                let length = std::cmp::min(CHECKPOINT_COPY_CHUNK, range.length - offset);
+                let gpa = range.gpa + offset;
+                memory_region_file
+                    .seek(SeekFrom::Start(gpa - region.start_addr().raw_value()))
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                guest_memory
+                    .write_all_to(GuestAddress(gpa), memory_region_file, length as usize)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                throttle.account(length)?;
+                offset += length;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -1987,22 +2572,11 @@ impl Snapshottable for MemoryManager {
                 return Err(MigratableError::Snapshot(anyhow!("Zero length region")));
             }
 
+            // The memory of an incremental snapshot is saved through its
+            // memory ranges, on top of its parent's.
             let mut content = Some(PathBuf::from(format!("memory-region-{}", index)));
-            if let Some(file_offset) = region.file_offset() {
-                if (region.flags() & libc::MAP_SHARED == libc::MAP_SHARED)
-                    && Self::is_hardlink(file_offset.file())
-                {
-                    // In this very specific case, we know the memory region
-                    // is backed by a file on the host filesystem that can be
-                    // accessed by the user, and additionally the mapping is
-                    // shared, which means that modifications to the content
-                    // are written to the actual file.
-                    // When meeting these conditions, we can skip the copy of
-                    // the memory content for this specific region, as we can
-                    // assume the user will have it saved through the backing
-                    // file already.
-                    content = None;
-                }
+            if self.snapshot_parent.is_some() || Self::saved_by_user(region) {
+                content = None;
             }
 
             memory_regions.push(MemoryRegion {
@@ -2041,9 +2615,23 @@ impl Transportable for MemoryManager {
         destination_url: &str,
     ) -> result::Result<(), MigratableError> {
         let vm_memory_snapshot_path = url_to_path(destination_url)?;
+        let mut metadata = SnapshotMetadata {
+            memory_layout: self
+                .snapshot_memory_regions
+                .iter()
+                .map(|region| (region.start_addr, region.size))
+                .collect(),
+            ..Default::default()
+        };
 
         if let Some(guest_memory) = &*self.snapshot.lock().unwrap() {
-            for region in self.snapshot_memory_regions.iter() {
+            // A checkpoint copied the memory regions already.
+            let regions: &[MemoryRegion] = if self.snapshot_precopied {
+                &[]
+            } else {
+                self.snapshot_memory_regions.as_slice()
+            };
+            for region in regions.iter() {
                 if let Some(content) = &region.content {
                     let mut memory_region_path = vm_memory_snapshot_path.clone();
                     memory_region_path.push(content);
@@ -2065,8 +2653,33 @@ impl Transportable for MemoryManager {
                         .map_err(|e| MigratableError::MigrateSend(e.into()))?;
                 }
             }
+
+            if let Some((parent_url, dirty_ranges)) = &self.snapshot_parent {
+                let mut memory_ranges_path = vm_memory_snapshot_path;
+                memory_ranges_path.push(SNAPSHOT_MEMORY_RANGES_FILE);
+
+                let mut memory_ranges_file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .open(memory_ranges_path)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+                for range in dirty_ranges.regions() {
+                    guest_memory
+                        .write_all_to(
+                            GuestAddress(range.gpa),
+                            &mut memory_ranges_file,
+                            range.length as usize,
+                        )
+                        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                    metadata.memory_ranges.push((range.gpa, range.length));
+                }
+                metadata.parent_url = Some(parent_url.clone());
+            }
         }
-        Ok(())
+
+        send_snapshot_metadata(&metadata, destination_url)
     }
 }
 impl Migratable for MemoryManager {}
@@ -0,0 +1,170 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host memory pressure, as reported by the kernel pressure stall information.
+//!
+//! Every VMM thread watches the pressure on its own, and inflates the balloon
+//! of its VM when the host runs short of memory. The VMs don't know about each
+//! other, so the priority of a balloon is the number of intervals the pressure
+//! has to last before it's inflated: the balloons with the lowest value are
+//! inflated first, and the other ones only if that wasn't enough.
+
+use crate::config::MemoryPressureConfig;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+const MEMORY_PRESSURE_PATH: &str = "/proc/pressure/memory";
+
+/// Parses the share of time, in percent over the last 10 seconds, some tasks
+/// were stalled on memory.
+pub fn parse_memory_pressure(content: &str) -> Option<f64> {
+    // some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+    let line = content.lines().find(|l| l.starts_with("some "))?;
+    line.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))
+        .and_then(|avg10| avg10.parse().ok())
+}
+
+pub fn read_memory_pressure() -> io::Result<f64> {
+    let content = fs::read_to_string(MEMORY_PRESSURE_PATH)?;
+    parse_memory_pressure(&content).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected content of {}", MEMORY_PRESSURE_PATH),
+        )
+    })
+}
+
+pub struct MemoryPressureWatcher {
+    config: MemoryPressureConfig,
+    pressured: bool,
+    // Consecutive intervals spent on the same side of the threshold.
+    intervals: u32,
+    // Bytes the balloon was inflated past its configured size.
+    inflated: u64,
+}
+
+impl MemoryPressureWatcher {
+    pub fn new(config: MemoryPressureConfig) -> Self {
+        MemoryPressureWatcher {
+            config,
+            pressured: false,
+            intervals: 0,
+            inflated: 0,
+        }
+    }
+
+    pub fn config(&self) -> &MemoryPressureConfig {
+        &self.config
+    }
+
+    pub fn interval(&self) -> Duration {
+        // A zero interval would disarm the timer.
+        Duration::from_millis(self.config.interval.max(1))
+    }
+
+    pub fn inflated(&self) -> u64 {
+        self.inflated
+    }
+
+    /// Accounts for the pressure read at the end of an interval, returning
+    /// whether the host went under or out of pressure.
+    pub fn update(&mut self, pressure: f64) -> Option<bool> {
+        let pressured = pressure >= self.config.threshold;
+        if pressured == self.pressured {
+            self.intervals = self.intervals.saturating_add(1);
+            return None;
+        }
+
+        self.pressured = pressured;
+        self.intervals = 1;
+        Some(pressured)
+    }
+
+    /// Computes the bytes a balloon with the `priority` and allowed to grow
+    /// up to `max` bytes past its configured size should be inflated by.
+    pub fn balloon_inflation(&mut self, priority: u32, max: u64) -> u64 {
+        if self.intervals > priority {
+            self.inflated = if self.pressured {
+                self.inflated.saturating_add(self.config.step)
+            } else {
+                self.inflated.saturating_sub(self.config.step)
+            };
+        }
+        // The guest memory may have shrunk since.
+        self.inflated = self.inflated.min(max);
+        self.inflated
+    }
+
+    /// Forgets about the balloon, once the VM is gone, rebooted, or its
+    /// balloon resized through the API.
+    pub fn reset_balloon(&mut self) {
+        self.inflated = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory_pressure() {
+        let content = "some avg10=12.34 avg60=5.00 avg300=1.00 total=123456\n\
+                       full avg10=3.21 avg60=1.00 avg300=0.50 total=23456\n";
+        assert_eq!(parse_memory_pressure(content), Some(12.34));
+        assert_eq!(
+            parse_memory_pressure("full avg10=3.21 avg60=1.00 avg300=0.50 total=23456\n"),
+            None
+        );
+        assert_eq!(parse_memory_pressure("some avg10=high\n"), None);
+        assert_eq!(parse_memory_pressure(""), None);
+    }
+
+    #[test]
+    fn test_balloon_inflation() {
+        let mut watcher = MemoryPressureWatcher::new(MemoryPressureConfig {
+            threshold: 10.0,
+            interval: 1000,
+            step: 64 << 20,
+        });
+
+        assert_eq!(watcher.update(20.0), Some(true));
+        // A priority 1 balloon waits for the pressure to last a second
+        // interval.
+        assert_eq!(watcher.balloon_inflation(1, 100 << 20), 0);
+        assert_eq!(watcher.update(15.0), None);
+        assert_eq!(watcher.balloon_inflation(1, 100 << 20), 64 << 20);
+        assert_eq!(watcher.update(15.0), None);
+        assert_eq!(watcher.balloon_inflation(1, 100 << 20), 100 << 20);
+
+        assert_eq!(watcher.update(1.0), Some(false));
+        assert_eq!(watcher.balloon_inflation(1, 100 << 20), 100 << 20);
+        assert_eq!(watcher.update(1.0), None);
+        assert_eq!(watcher.balloon_inflation(1, 100 << 20), 36 << 20);
+        assert_eq!(watcher.update(1.0), None);
+        assert_eq!(watcher.balloon_inflation(1, 100 << 20), 0);
+        assert_eq!(watcher.inflated(), 0);
+    }
+
+    #[test]
+    fn test_balloon_inflation_shrunk() {
+        let mut watcher = MemoryPressureWatcher::new(MemoryPressureConfig {
+            threshold: 10.0,
+            interval: 1000,
+            step: 64 << 20,
+        });
+
+        assert_eq!(watcher.update(20.0), Some(true));
+        assert_eq!(watcher.balloon_inflation(0, 100 << 20), 64 << 20);
+        // The room left for the balloon shrinks along with the guest memory.
+        assert_eq!(watcher.update(20.0), None);
+        assert_eq!(watcher.balloon_inflation(0, 32 << 20), 32 << 20);
+        assert_eq!(watcher.inflated(), 32 << 20);
+
+        watcher.reset_balloon();
+        assert_eq!(watcher.inflated(), 0);
+    }
+}
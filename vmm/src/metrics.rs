@@ -0,0 +1,153 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Encoding of the VMM metrics in the Prometheus text exposition format.
+//!
+//! See https://prometheus.io/docs/instrumenting/exposition_formats/ for the
+//! description of the format.
+
+use std::fmt::Write;
+
+/// Prefix shared by the name of all the metrics.
+pub const METRICS_PREFIX: &str = "cloud_hypervisor";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Summary,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Summary => "summary",
+        }
+    }
+}
+
+/// A family of samples sharing the same name, type and help string, each
+/// sample being identified by its labels.
+pub struct Metric {
+    name: String,
+    help: String,
+    metric_type: MetricType,
+    samples: Vec<(String, Vec<(String, String)>, f64)>,
+}
+
+impl Metric {
+    pub fn new(name: &str, metric_type: MetricType, help: &str) -> Self {
+        Metric {
+            name: format!("{}_{}", METRICS_PREFIX, sanitize_name(name)),
+            help: help.to_string(),
+            metric_type,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds a sample, `suffix` being appended to the metric name, which is
+    /// needed for the "_sum" and "_count" samples of a summary.
+    pub fn add_sample(&mut self, suffix: &str, labels: &[(&str, &str)], value: f64) {
+        self.samples.push((
+            suffix.to_string(),
+            labels
+                .iter()
+                .map(|(name, value)| (sanitize_name(name), escape_label_value(value)))
+                .collect(),
+            value,
+        ));
+    }
+
+    pub fn encode(&self, output: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+
+        writeln!(output, "# HELP {} {}", self.name, escape_help(&self.help)).unwrap();
+        writeln!(output, "# TYPE {} {}", self.name, self.metric_type.as_str()).unwrap();
+        for (suffix, labels, value) in self.samples.iter() {
+            output.push_str(&self.name);
+            output.push_str(suffix);
+            if !labels.is_empty() {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, value))
+                    .collect();
+                write!(output, "{{{}}}", labels.join(",")).unwrap();
+            }
+            writeln!(output, " {}", value).unwrap();
+        }
+    }
+}
+
+/// Encodes a list of metrics into the exposition format.
+pub fn encode(metrics: &[Metric]) -> String {
+    let mut output = String::new();
+    for metric in metrics.iter() {
+        metric.encode(&mut output);
+    }
+    output
+}
+
+// Metric and label names can only contain [a-zA-Z0-9_].
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut exits = Metric::new("vcpu_exits_total", MetricType::Counter, "vCPU exits");
+        exits.add_sample("", &[("vcpu", "0")], 12.0);
+        exits.add_sample("", &[("vcpu", "1")], 3.0);
+
+        let mut latency = Metric::new(
+            "api_request_duration_seconds",
+            MetricType::Summary,
+            "API requests",
+        );
+        latency.add_sample("_sum", &[("endpoint", "/api/v1/vm.info")], 0.25);
+        latency.add_sample("_count", &[("endpoint", "/api/v1/vm.info")], 2.0);
+
+        let mut memory = Metric::new("memory.size-bytes", MetricType::Gauge, "Memory");
+        memory.add_sample("", &[("id", "a\"b\\c\nd")], 1073741824.0);
+
+        // Metrics without any sample are skipped.
+        let empty = Metric::new("empty", MetricType::Gauge, "Empty");
+
+        assert_eq!(
+            encode(&[exits, latency, memory, empty]),
+            "# HELP cloud_hypervisor_vcpu_exits_total vCPU exits\n\
+             # TYPE cloud_hypervisor_vcpu_exits_total counter\n\
+             cloud_hypervisor_vcpu_exits_total{vcpu=\"0\"} 12\n\
+             cloud_hypervisor_vcpu_exits_total{vcpu=\"1\"} 3\n\
+             # HELP cloud_hypervisor_api_request_duration_seconds API requests\n\
+             # TYPE cloud_hypervisor_api_request_duration_seconds summary\n\
+             cloud_hypervisor_api_request_duration_seconds_sum{endpoint=\"/api/v1/vm.info\"} 0.25\n\
+             cloud_hypervisor_api_request_duration_seconds_count{endpoint=\"/api/v1/vm.info\"} 2\n\
+             # HELP cloud_hypervisor_memory_size_bytes Memory\n\
+             # TYPE cloud_hypervisor_memory_size_bytes gauge\n\
+             cloud_hypervisor_memory_size_bytes{id=\"a\\\"b\\\\c\\nd\"} 1073741824\n"
+        );
+    }
+}
@@ -4,12 +4,83 @@
 
 use crate::vm::{VmSnapshot, VM_SNAPSHOT_ID};
 use anyhow::anyhow;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader};
 use std::path::PathBuf;
-use vm_migration::{MigratableError, Snapshot};
+use vm_migration::{MigratableError, Snapshot, MIN_SNAPSHOT_VERSION, SNAPSHOT_VERSION};
 
 pub const VM_SNAPSHOT_FILE: &str = "vm.json";
+pub const SNAPSHOT_METADATA_FILE: &str = "snapshot.json";
+pub const SNAPSHOT_MEMORY_RANGES_FILE: &str = "memory-ranges";
+
+/// Links a snapshot to the one it builds upon. An incremental snapshot only
+/// holds the guest memory written since its parent was taken or restored
+/// from, the rest of the memory being found in its ancestors, down to the
+/// full snapshot the chain starts from.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SnapshotMetadata {
+    /// URL of the parent snapshot, none for a full snapshot.
+    #[serde(default)]
+    pub parent_url: Option<String>,
+    /// Guest memory ranges, as their address and length, whose content is
+    /// found in order in the memory ranges file of an incremental snapshot.
+    #[serde(default)]
+    pub memory_ranges: Vec<(u64, u64)>,
+    /// Guest memory regions, as their address and size, when the snapshot
+    /// was taken. Not recorded by the older snapshots.
+    #[serde(default)]
+    pub memory_layout: Vec<(u64, u64)>,
+}
+
+/// Converts a snapshot to the next snapshot version.
+type SnapshotUpgrade = fn(&mut Snapshot) -> std::result::Result<(), MigratableError>;
+
+// Conversions of the snapshots from each supported version to the next one,
+// starting from MIN_SNAPSHOT_VERSION. They take care of the states serialized
+// with serde, renaming their fields or filling the new ones which can't
+// simply be defaulted, while the versioned states are converted through
+// their version map. The oldest conversion is dropped along with the support
+// for its snapshot version.
+const SNAPSHOT_UPGRADES: &[SnapshotUpgrade] = &[];
+
+/// Converts a snapshot taken by a previous release to the current snapshot
+/// version, failing if it is either too old or too recent to be restored.
+pub fn upgrade_snapshot(snapshot: &mut Snapshot) -> std::result::Result<(), MigratableError> {
+    upgrade_snapshot_with(snapshot, MIN_SNAPSHOT_VERSION, SNAPSHOT_UPGRADES)
+}
+
+fn upgrade_snapshot_with(
+    snapshot: &mut Snapshot,
+    min_version: u16,
+    upgrades: &[SnapshotUpgrade],
+) -> std::result::Result<(), MigratableError> {
+    let version = snapshot.version;
+    let current_version = min_version + upgrades.len() as u16;
+    if version > current_version {
+        return Err(MigratableError::Restore(anyhow!(
+            "Snapshot version {} is newer than the supported version {}",
+            version,
+            current_version
+        )));
+    }
+    if version < min_version {
+        return Err(MigratableError::Restore(anyhow!(
+            "Snapshot version {} is no longer supported, the oldest supported version being {}",
+            version,
+            min_version
+        )));
+    }
+
+    for (upgrade, from) in upgrades[(version - min_version) as usize..]
+        .iter()
+        .zip(version..)
+    {
+        info!("Converting snapshot from version {} to {}", from, from + 1);
+        upgrade(snapshot)?;
+    }
+
+    Ok(())
+}
 
 pub fn url_to_path(url: &str) -> std::result::Result<PathBuf, MigratableError> {
     let path: PathBuf = url
@@ -37,12 +108,101 @@ pub fn recv_vm_snapshot(source_url: &str) -> std::result::Result<Snapshot, Migra
     let vm_snapshot_file =
         File::open(vm_snapshot_path).map_err(|e| MigratableError::MigrateSend(e.into()))?;
     let vm_snapshot_reader = BufReader::new(vm_snapshot_file);
-    let vm_snapshot = serde_json::from_reader(vm_snapshot_reader)
+    let mut vm_snapshot = serde_json::from_reader(vm_snapshot_reader)
         .map_err(|e| MigratableError::MigrateReceive(e.into()))?;
+    upgrade_snapshot(&mut vm_snapshot)?;
 
     Ok(vm_snapshot)
 }
 
+/// Reads the metadata of the snapshot at `source_url`. The snapshots taken
+/// before the metadata was recorded are full snapshots.
+pub fn recv_snapshot_metadata(
+    source_url: &str,
+) -> std::result::Result<SnapshotMetadata, MigratableError> {
+    let mut metadata_path = url_to_path(source_url)?;
+    metadata_path.push(SNAPSHOT_METADATA_FILE);
+
+    let metadata_file = match File::open(metadata_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SnapshotMetadata::default()),
+        Err(e) => return Err(MigratableError::MigrateReceive(e.into())),
+    };
+    serde_json::from_reader(BufReader::new(metadata_file))
+        .map_err(|e| MigratableError::MigrateReceive(e.into()))
+}
+
+pub fn send_snapshot_metadata(
+    metadata: &SnapshotMetadata,
+    destination_url: &str,
+) -> std::result::Result<(), MigratableError> {
+    let mut metadata_path = url_to_path(destination_url)?;
+    metadata_path.push(SNAPSHOT_METADATA_FILE);
+
+    let metadata_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(metadata_path)
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+    serde_json::to_writer(metadata_file, metadata)
+        .map_err(|e| MigratableError::MigrateSend(e.into()))
+}
+
+/// Returns the snapshots the one at `source_url` is built from, along with
+/// their metadata, from the full snapshot down to `source_url` itself.
+pub fn snapshot_chain(
+    source_url: &str,
+) -> std::result::Result<Vec<(String, SnapshotMetadata)>, MigratableError> {
+    let mut chain: Vec<(String, SnapshotMetadata)> = Vec::new();
+    let mut url = Some(source_url.to_owned());
+    while let Some(current_url) = url {
+        if chain.iter().any(|(u, _)| *u == current_url) {
+            return Err(MigratableError::Restore(anyhow!(
+                "Snapshot {} is its own ancestor",
+                current_url
+            )));
+        }
+
+        let metadata = recv_snapshot_metadata(&current_url)?;
+        url = metadata.parent_url.clone();
+        chain.push((current_url, metadata));
+    }
+    chain.reverse();
+
+    Ok(chain)
+}
+
+/// Checks that the snapshots of `chain` share the guest memory `layout`, and
+/// that the memory ranges of the incremental ones are within it, as their
+/// memory is applied over the regions of the full snapshot.
+pub fn check_snapshot_layout(
+    chain: &[(String, SnapshotMetadata)],
+    layout: &[(u64, u64)],
+) -> std::result::Result<(), anyhow::Error> {
+    for (url, metadata) in chain {
+        if !metadata.memory_layout.is_empty() && metadata.memory_layout != layout {
+            return Err(anyhow!(
+                "The guest memory layout differs from the one of snapshot {}",
+                url
+            ));
+        }
+        for (gpa, length) in metadata.memory_ranges.iter() {
+            if !layout.iter().any(|(start, size)| {
+                *gpa >= *start && gpa.saturating_add(*length) <= start.saturating_add(*size)
+            }) {
+                return Err(anyhow!(
+                    "Memory range 0x{:x}-0x{:x} of snapshot {} is beyond the guest memory",
+                    gpa,
+                    gpa.saturating_add(*length),
+                    url
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, MigratableError> {
     if let Some(vm_section) = snapshot
         .snapshot_data
@@ -57,3 +217,144 @@ pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, M
         "Could not find VM config snapshot section"
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_migration::SnapshotDataSection;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_snapshot_upgrades() {
+        assert_eq!(
+            MIN_SNAPSHOT_VERSION + SNAPSHOT_UPGRADES.len() as u16,
+            SNAPSHOT_VERSION
+        );
+
+        fn rename_field(snapshot: &mut Snapshot) -> std::result::Result<(), MigratableError> {
+            snapshot
+                .snapshot_data
+                .get_mut("vm-section")
+                .unwrap()
+                .update_state(|state| {
+                    let value = state.as_object_mut().unwrap().remove("old").unwrap();
+                    state["new"] = value;
+                })
+        }
+        fn add_field(snapshot: &mut Snapshot) -> std::result::Result<(), MigratableError> {
+            snapshot
+                .snapshot_data
+                .get_mut("vm-section")
+                .unwrap()
+                .update_state(|state| state["added"] = 2.into())
+        }
+        let upgrades: &[SnapshotUpgrade] = &[rename_field, add_field];
+
+        let mut snapshot = Snapshot::new("vm");
+        snapshot.version = 3;
+        snapshot.add_data_section(SnapshotDataSection {
+            id: "vm-section".to_owned(),
+            snapshot: br#"{"old":1}"#.to_vec(),
+        });
+
+        // Version 3 only needs the second conversion.
+        let mut upgraded = snapshot.clone();
+        upgrade_snapshot_with(&mut upgraded, 2, upgrades).unwrap();
+        let state: serde_json::Value = upgraded.to_state("vm").unwrap();
+        assert_eq!(state, serde_json::json!({"old": 1, "added": 2}));
+
+        snapshot.version = 2;
+        upgrade_snapshot_with(&mut snapshot, 2, upgrades).unwrap();
+        let state: serde_json::Value = snapshot.to_state("vm").unwrap();
+        assert_eq!(state, serde_json::json!({"new": 1, "added": 2}));
+
+        snapshot.version = 1;
+        assert!(upgrade_snapshot_with(&mut snapshot, 2, upgrades).is_err());
+        snapshot.version = 5;
+        assert!(upgrade_snapshot_with(&mut snapshot, 2, upgrades).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_chain() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let url = |name: &str| format!("file://{}/{}", dir.as_path().display(), name);
+        for name in &["full", "first", "second", "loop"] {
+            std::fs::create_dir(dir.as_path().join(name)).unwrap();
+        }
+
+        // The full snapshot was taken before the metadata was recorded.
+        let first = SnapshotMetadata {
+            parent_url: Some(url("full")),
+            memory_ranges: vec![(0x1000, 0x2000)],
+            memory_layout: vec![(0, 0x10000)],
+        };
+        send_snapshot_metadata(&first, &url("first")).unwrap();
+        let second = SnapshotMetadata {
+            parent_url: Some(url("first")),
+            memory_ranges: vec![(0x1000, 0x1000), (0x8000, 0x1000)],
+            memory_layout: vec![(0, 0x10000)],
+        };
+        send_snapshot_metadata(&second, &url("second")).unwrap();
+
+        assert_eq!(
+            snapshot_chain(&url("second")).unwrap(),
+            vec![
+                (url("full"), SnapshotMetadata::default()),
+                (url("first"), first),
+                (url("second"), second),
+            ]
+        );
+        assert_eq!(
+            snapshot_chain(&url("full")).unwrap(),
+            vec![(url("full"), SnapshotMetadata::default())]
+        );
+
+        let cycle = SnapshotMetadata {
+            parent_url: Some(url("loop")),
+            memory_ranges: Vec::new(),
+            memory_layout: Vec::new(),
+        };
+        send_snapshot_metadata(&cycle, &url("loop")).unwrap();
+        assert!(snapshot_chain(&url("loop")).is_err());
+    }
+
+    #[test]
+    fn test_check_snapshot_layout() {
+        let layout = vec![(0, 0x10000), (0x100000, 0x10000)];
+        let full = SnapshotMetadata {
+            memory_layout: layout.clone(),
+            ..Default::default()
+        };
+        let increment = SnapshotMetadata {
+            parent_url: Some("file:///full".to_owned()),
+            memory_ranges: vec![(0x1000, 0x1000), (0x10f000, 0x1000)],
+            memory_layout: layout.clone(),
+        };
+        let chain = vec![
+            ("file:///full".to_owned(), full.clone()),
+            ("file:///increment".to_owned(), increment.clone()),
+        ];
+        assert!(check_snapshot_layout(&chain, &layout).is_ok());
+
+        // The older snapshots don't record their layout.
+        let old_chain = vec![
+            ("file:///full".to_owned(), SnapshotMetadata::default()),
+            ("file:///increment".to_owned(), increment.clone()),
+        ];
+        assert!(check_snapshot_layout(&old_chain, &layout).is_ok());
+
+        // Memory added since the full snapshot.
+        let resized = vec![(0, 0x10000), (0x100000, 0x20000)];
+        assert!(check_snapshot_layout(&chain, &resized).is_err());
+
+        // A memory range crossing the end of a region.
+        let mut beyond = increment;
+        beyond.memory_layout = Vec::new();
+        beyond.memory_ranges = vec![(0xf000, 0x2000)];
+        let chain = vec![
+            ("file:///full".to_owned(), full),
+            ("file:///increment".to_owned(), beyond),
+        ];
+        assert!(check_snapshot_layout(&chain, &layout).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Automatic placement of a VM onto the NUMA nodes of the host.
+//!
+//! Each guest NUMA node, or the whole VM if it doesn't define any, is placed
+//! onto the host node with the most free memory, so that its vCPUs, its
+//! memory zones and the threads handling its I/O share the same host node.
+
+use crate::config::VmConfig;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const NODE_SYSFS_PATH: &str = "/sys/devices/system/node";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot read the host NUMA topology.
+    ReadTopology(PathBuf, io::Error),
+
+    /// Cannot parse the host NUMA topology.
+    ParseTopology(PathBuf),
+
+    /// Cannot set the CPU affinity of a thread.
+    SetAffinity(io::Error),
+
+    /// The CPU doesn't fit in a cpu_set_t.
+    CpuOutOfRange(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostNumaNode {
+    pub id: u32,
+    pub cpus: Vec<usize>,
+    pub free_memory: u64,
+}
+
+impl HostNumaNode {
+    /// Reads the NUMA nodes of the host, with their CPUs and free memory.
+    pub fn host_nodes() -> Result<Vec<Self>> {
+        let online_path = Path::new(NODE_SYSFS_PATH).join("online");
+        let online = read_sysfs(&online_path)?;
+        let ids = parse_list(&online).ok_or(Error::ParseTopology(online_path))?;
+
+        let mut nodes = Vec::new();
+        for id in ids {
+            let node_path = Path::new(NODE_SYSFS_PATH).join(format!("node{}", id));
+
+            let cpulist_path = node_path.join("cpulist");
+            let cpulist = read_sysfs(&cpulist_path)?;
+            let cpus = parse_list(&cpulist).ok_or(Error::ParseTopology(cpulist_path))?;
+            // The vCPU and I/O threads couldn't be bound to these CPUs.
+            if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize) {
+                return Err(Error::CpuOutOfRange(*cpu));
+            }
+
+            let meminfo_path = node_path.join("meminfo");
+            let meminfo = read_sysfs(&meminfo_path)?;
+            let free_memory =
+                parse_free_memory(&meminfo).ok_or(Error::ParseTopology(meminfo_path))?;
+
+            nodes.push(HostNumaNode {
+                id: id as u32,
+                cpus,
+                free_memory,
+            });
+        }
+
+        Ok(nodes)
+    }
+}
+
+/// Host resources assigned to the memory zones, the vCPUs and the I/O
+/// threads of a VM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NumaPlacement {
+    memory_zones: HashMap<String, u32>,
+    vcpus: HashMap<u8, Vec<usize>>,
+    io_cpus: Vec<usize>,
+}
+
+impl NumaPlacement {
+    /// Places the VM onto the given host nodes. Nothing is placed if the
+    /// host has a single node, as there is nothing to gain.
+    pub fn new(config: &VmConfig, host_nodes: &[HostNumaNode]) -> Self {
+        NumaPlacement::from_groups(guest_groups(config), host_nodes)
+    }
+
+    fn from_groups(mut groups: Vec<GuestGroup>, host_nodes: &[HostNumaNode]) -> Self {
+        let mut placement = NumaPlacement::default();
+        if host_nodes.len() < 2 {
+            return placement;
+        }
+
+        let mut free_memory: HashMap<u32, u64> = host_nodes
+            .iter()
+            .map(|node| (node.id, node.free_memory))
+            .collect();
+
+        // The largest guest nodes are placed first, so that they get the
+        // host nodes with the most free memory.
+        groups.sort_by(|a, b| b.memory_size.cmp(&a.memory_size));
+
+        for group in groups {
+            let host_node = host_nodes
+                .iter()
+                .max_by_key(|node| (free_memory[&node.id], std::cmp::Reverse(node.id)))
+                .unwrap();
+            let node_free_memory = free_memory.get_mut(&host_node.id).unwrap();
+            if *node_free_memory < group.memory_size {
+                warn!(
+                    "Host NUMA node {} doesn't have enough free memory for {} bytes",
+                    host_node.id, group.memory_size
+                );
+            }
+            *node_free_memory = node_free_memory.saturating_sub(group.memory_size);
+
+            for zone in group.memory_zones {
+                placement.memory_zones.insert(zone, host_node.id);
+            }
+            for vcpu in group.vcpus {
+                placement.vcpus.insert(vcpu, host_node.cpus.clone());
+            }
+            for cpu in host_node.cpus.iter() {
+                if !placement.io_cpus.contains(cpu) {
+                    placement.io_cpus.push(*cpu);
+                }
+            }
+        }
+
+        placement.io_cpus.sort_unstable();
+        placement
+    }
+
+    /// Host node the memory zone is bound to.
+    pub fn memory_zone_node(&self, id: &str) -> Option<u32> {
+        self.memory_zones.get(id).copied()
+    }
+
+    /// Host CPUs the vCPU thread is bound to.
+    pub fn vcpu_cpus(&self, id: u8) -> Option<&[usize]> {
+        self.vcpus.get(&id).map(|cpus| cpus.as_slice())
+    }
+
+    /// Host CPUs the threads handling the I/O are bound to.
+    pub fn io_cpus(&self) -> Option<&[usize]> {
+        if self.io_cpus.is_empty() {
+            None
+        } else {
+            Some(&self.io_cpus)
+        }
+    }
+}
+
+/// Binds the calling thread, and the threads it creates afterwards, to a set
+/// of host CPUs.
+pub fn set_thread_affinity(cpus: &[usize]) -> Result<()> {
+    // Safe because the cpu_set_t is a plain bitmask, for which all zeroes is
+    // a valid value.
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus.iter() {
+        // CPU_SET() panics if the CPU doesn't fit in the set.
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(Error::CpuOutOfRange(*cpu));
+        }
+        // Safe because the CPU has been checked to fit in the set.
+        unsafe { libc::CPU_SET(*cpu, &mut cpuset) };
+    }
+
+    sched_setaffinity(&cpuset)
+}
+
+/// Affinity of the VMM thread, restored when dropped. The threads handling
+/// the I/O of the devices inherit it when they are created.
+pub struct IoAffinity {
+    previous: libc::cpu_set_t,
+}
+
+impl IoAffinity {
+    pub fn new(cpus: &[usize]) -> Result<Self> {
+        // Safe because the cpu_set_t is a plain bitmask, and the kernel
+        // writes at most its size.
+        let mut previous: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let ret = unsafe {
+            libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut previous)
+        };
+        if ret != 0 {
+            return Err(Error::SetAffinity(io::Error::last_os_error()));
+        }
+
+        set_thread_affinity(cpus)?;
+
+        Ok(IoAffinity { previous })
+    }
+}
+
+impl Drop for IoAffinity {
+    fn drop(&mut self) {
+        if let Err(e) = sched_setaffinity(&self.previous) {
+            warn!("Error restoring the VMM thread affinity: {:?}", e);
+        }
+    }
+}
+
+fn sched_setaffinity(cpuset: &libc::cpu_set_t) -> Result<()> {
+    // Safe because the kernel only reads the cpu_set_t.
+    let ret = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), cpuset) };
+    if ret != 0 {
+        return Err(Error::SetAffinity(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+// Resources of the VM placed onto the same host node.
+struct GuestGroup {
+    memory_zones: Vec<String>,
+    memory_size: u64,
+    vcpus: Vec<u8>,
+}
+
+// Without guest NUMA nodes, the whole VM is considered as a single node, the
+// memory being either the user defined memory zones or the default one.
+fn guest_groups(config: &VmConfig) -> Vec<GuestGroup> {
+    let zone_size = |id: &str| -> u64 {
+        config
+            .memory
+            .zones
+            .as_ref()
+            .and_then(|zones| zones.iter().find(|zone| zone.id == id))
+            .map_or(0, |zone| zone.size + zone.hotplug_size.unwrap_or(0))
+    };
+
+    match &config.numa {
+        Some(numa_nodes) if !numa_nodes.is_empty() => numa_nodes
+            .iter()
+            .map(|numa_node| {
+                let memory_zones = numa_node.memory_zones.clone().unwrap_or_default();
+                GuestGroup {
+                    memory_size: memory_zones.iter().map(|id| zone_size(id)).sum(),
+                    memory_zones,
+                    vcpus: numa_node.cpus.clone().unwrap_or_default(),
+                }
+            })
+            .collect(),
+        _ => {
+            let memory_zones: Vec<String> = match &config.memory.zones {
+                Some(zones) if config.memory.size == 0 => {
+                    zones.iter().map(|zone| zone.id.clone()).collect()
+                }
+                _ => vec![crate::memory_manager::DEFAULT_MEMORY_ZONE.to_string()],
+            };
+            let memory_size = if config.memory.size == 0 {
+                memory_zones.iter().map(|id| zone_size(id)).sum()
+            } else {
+                config.memory.size + config.memory.hotplug_size.unwrap_or(0)
+            };
+            vec![GuestGroup {
+                memory_zones,
+                memory_size,
+                vcpus: (0..config.cpus.max_vcpus).collect(),
+            }]
+        }
+    }
+}
+
+fn read_sysfs(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| Error::ReadTopology(path.to_path_buf(), e))
+}
+
+// Parses the list format used by sysfs, such as "0-3,8,10-11".
+fn parse_list(list: &str) -> Option<Vec<usize>> {
+    let mut items = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start: usize = bounds.next()?.parse().ok()?;
+        let end: usize = match bounds.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        items.extend(start..=end);
+    }
+
+    Some(items)
+}
+
+// Parses the "Node <id> MemFree: <size> kB" line of the node meminfo.
+fn parse_free_memory(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let mut fields = line.split_whitespace().skip(2);
+        if fields.next()? != "MemFree:" {
+            return None;
+        }
+        fields.next()?.parse::<u64>().ok().map(|kb| kb << 10)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_topology() {
+        assert_eq!(
+            parse_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_list("\n"), Some(vec![]));
+        assert_eq!(parse_list("0-x"), None);
+
+        let meminfo = "Node 1 MemTotal:       32768000 kB\n\
+                       Node 1 MemFree:        16384000 kB\n\
+                       Node 1 MemUsed:        16384000 kB\n";
+        assert_eq!(parse_free_memory(meminfo), Some(16_384_000 << 10));
+    }
+
+    #[test]
+    fn test_set_thread_affinity_out_of_range() {
+        // The affinity of the thread is left untouched.
+        assert!(matches!(
+            set_thread_affinity(&[0, libc::CPU_SETSIZE as usize]),
+            Err(Error::CpuOutOfRange(cpu)) if cpu == libc::CPU_SETSIZE as usize
+        ));
+    }
+
+    fn host_nodes() -> Vec<HostNumaNode> {
+        vec![
+            HostNumaNode {
+                id: 0,
+                cpus: vec![0, 1],
+                free_memory: 4 << 30,
+            },
+            HostNumaNode {
+                id: 1,
+                cpus: vec![2, 3],
+                free_memory: 8 << 30,
+            },
+        ]
+    }
+
+    fn guest_group(memory_zone: &str, memory_size: u64, vcpus: &[u8]) -> GuestGroup {
+        GuestGroup {
+            memory_zones: vec![memory_zone.to_owned()],
+            memory_size,
+            vcpus: vcpus.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_numa_placement() {
+        // A single host node leaves nothing to place.
+        assert_eq!(
+            NumaPlacement::from_groups(
+                vec![guest_group("mem0", 2 << 30, &[0, 1])],
+                &host_nodes()[..1]
+            ),
+            NumaPlacement::default()
+        );
+
+        // The whole VM goes to the node with the most free memory.
+        let placement =
+            NumaPlacement::from_groups(vec![guest_group("mem0", 2 << 30, &[0, 1])], &host_nodes());
+        assert_eq!(placement.memory_zone_node("mem0"), Some(1));
+        assert_eq!(placement.vcpu_cpus(0), Some(&[2, 3][..]));
+        assert_eq!(placement.vcpu_cpus(1), Some(&[2, 3][..]));
+        assert_eq!(placement.io_cpus(), Some(&[2, 3][..]));
+
+        // Each guest node gets its own host node, the largest first.
+        let placement = NumaPlacement::from_groups(
+            vec![
+                guest_group("z0", 2 << 30, &[0]),
+                guest_group("z1", 6 << 30, &[1]),
+            ],
+            &host_nodes(),
+        );
+        assert_eq!(placement.memory_zone_node("z1"), Some(1));
+        assert_eq!(placement.memory_zone_node("z0"), Some(0));
+        assert_eq!(placement.vcpu_cpus(0), Some(&[0, 1][..]));
+        assert_eq!(placement.vcpu_cpus(1), Some(&[2, 3][..]));
+        assert_eq!(placement.io_cpus(), Some(&[0, 1, 2, 3][..]));
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emergency dump written when a thread of the VMM panics.
+//!
+//! The panic hook hands the panic over to a thread of its own, which writes
+//! the last API requests, along with the devices and the vCPU registers of
+//! each VM, to the dump file before the process is aborted. The panicking
+//! thread might hold any lock and run under a seccomp filter not allowing to
+//! read the vCPU registers, hence it doesn't take part in the dump: the dump
+//! thread only takes the locks it gets within a short delay, and leaves out
+//! whatever it can't get.
+
+use crate::cpu::CpuManager;
+use crate::device_tree::DeviceTree;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use seccomp::{SeccompAction, SeccompError, SeccompFilter};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::panic::{self, PanicInfo};
+use std::process;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// API requests kept for the dump.
+const MAX_API_REQUESTS: usize = 32;
+// Time the panicking thread gives the dump before aborting.
+const DUMP_TIMEOUT: Duration = Duration::from_secs(5);
+// Time given to each lock the dump needs.
+pub const LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+const DUMP_THREAD_NAME: &str = "panic_dump";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Cannot create the seccomp filter of the dump thread
+    #[error("Error creating seccomp filter: {0}")]
+    CreateSeccompFilter(SeccompError),
+
+    /// Cannot create the dump thread
+    #[error("Error spawning panic dump thread: {0}")]
+    ThreadSpawn(#[source] io::Error),
+}
+
+struct VmSources {
+    // Name of the VMM thread driving the VM.
+    vmm_thread: String,
+    cpu_manager: Weak<Mutex<CpuManager>>,
+    device_tree: Weak<Mutex<DeviceTree>>,
+}
+
+#[derive(Default)]
+struct Sources {
+    api_requests: VecDeque<String>,
+    vms: Vec<VmSources>,
+}
+
+lazy_static! {
+    static ref SOURCES: Mutex<Sources> = Mutex::new(Sources::default());
+}
+
+/// Locks the mutex unless another thread holds it for longer than the
+/// timeout. A poisoned mutex is locked all the same, as whatever it holds is
+/// still worth dumping.
+pub fn try_lock_for<T: ?Sized>(mutex: &Mutex<T>, timeout: Duration) -> Option<MutexGuard<T>> {
+    let start = Instant::now();
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
+
+fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Records an API request handled by the VMM thread. Only the kind of the
+/// request is kept, its content possibly holding secrets.
+pub fn record_api_request(kind: &'static str) {
+    let mut sources = SOURCES.lock().unwrap_or_else(|e| e.into_inner());
+    if sources.api_requests.len() == MAX_API_REQUESTS {
+        sources.api_requests.pop_front();
+    }
+    let vmm_thread = thread::current().name().unwrap_or("").to_string();
+    sources
+        .api_requests
+        .push_back(format!("{:.3} {} {}", timestamp(), vmm_thread, kind));
+}
+
+/// Registers the components of a VM to dump, which are left out once the
+/// VM is gone.
+pub fn register_vm(cpu_manager: &Arc<Mutex<CpuManager>>, device_tree: &Arc<Mutex<DeviceTree>>) {
+    let mut sources = SOURCES.lock().unwrap_or_else(|e| e.into_inner());
+    sources.vms.retain(|vm| vm.cpu_manager.strong_count() > 0);
+    sources.vms.push(VmSources {
+        vmm_thread: thread::current().name().unwrap_or("").to_string(),
+        cpu_manager: Arc::downgrade(cpu_manager),
+        device_tree: Arc::downgrade(device_tree),
+    });
+}
+
+fn write_dump(out: &mut File, panic: &str) -> io::Result<()> {
+    writeln!(out, "==== Cloud Hypervisor emergency dump ====")?;
+    writeln!(out, "time: {:.3}", timestamp())?;
+    writeln!(out, "pid: {}", process::id())?;
+    writeln!(out, "{}", panic)?;
+
+    let sources = match try_lock_for(&SOURCES, LOCK_TIMEOUT) {
+        Some(sources) => sources,
+        None => return writeln!(out, "\nNothing else to dump: the sources are locked"),
+    };
+
+    writeln!(out, "\n== Last API requests ==")?;
+    for request in sources.api_requests.iter() {
+        writeln!(out, "{}", request)?;
+    }
+
+    for vm in sources.vms.iter() {
+        let (cpu_manager, device_tree) = match (vm.cpu_manager.upgrade(), vm.device_tree.upgrade())
+        {
+            (Some(cpu_manager), Some(device_tree)) => (cpu_manager, device_tree),
+            _ => continue,
+        };
+        writeln!(out, "\n== VM of thread {} ==", vm.vmm_thread)?;
+
+        writeln!(out, "-- Devices --")?;
+        match try_lock_for(&device_tree, LOCK_TIMEOUT) {
+            Some(device_tree) => match serde_json::to_string_pretty(&*device_tree) {
+                Ok(device_tree) => writeln!(out, "{}", device_tree)?,
+                Err(e) => writeln!(out, "Cannot serialize the device tree: {}", e)?,
+            },
+            None => writeln!(out, "The device tree is locked")?,
+        }
+
+        writeln!(out, "-- vCPUs --")?;
+        match try_lock_for(&cpu_manager, LOCK_TIMEOUT) {
+            Some(cpu_manager) => cpu_manager.panic_dump(out)?,
+            None => writeln!(out, "The CPU manager is locked")?,
+        }
+    }
+
+    writeln!(out)?;
+    out.flush()
+}
+
+fn run_dump_thread(mut out: File, panics: Receiver<String>, done: Sender<()>) {
+    // Only the first panic is dumped, the process being aborted right after.
+    if let Ok(panic) = panics.recv() {
+        if let Err(e) = write_dump(&mut out, &panic) {
+            eprintln!("Error writing the emergency dump: {}", e);
+        }
+        done.send(()).ok();
+    }
+}
+
+/// Installs the panic hook writing the emergency dump to the file, which
+/// must be opened before any seccomp filter is applied, and aborting the
+/// process. The dumps are appended to the file.
+pub fn install(out: File, seccomp_action: &SeccompAction) -> Result<(), Error> {
+    let seccomp_filter = get_seccomp_filter(seccomp_action, Thread::PanicDump)
+        .map_err(Error::CreateSeccompFilter)?;
+    let (panic_sender, panic_receiver) = channel();
+    let (done_sender, done_receiver) = channel();
+    thread::Builder::new()
+        .name(DUMP_THREAD_NAME.to_string())
+        .spawn(move || {
+            if let Err(e) = SeccompFilter::apply(seccomp_filter) {
+                error!("Error applying seccomp filter: {:?}", e);
+                return;
+            }
+
+            run_dump_thread(out, panic_receiver, done_sender);
+        })
+        .map_err(Error::ThreadSpawn)?;
+
+    let panic_sender = Mutex::new(panic_sender);
+    let done_receiver = Mutex::new(done_receiver);
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info: &PanicInfo| {
+        default_hook(info);
+
+        let thread = thread::current();
+        let name = thread.name().unwrap_or("<unnamed>");
+        if name != DUMP_THREAD_NAME {
+            let panic = format!("thread '{}' {}", name, info);
+            let sent = panic_sender
+                .lock()
+                .map(|sender| sender.send(panic).is_ok())
+                .unwrap_or(false);
+            if sent {
+                if let Ok(done) = done_receiver.lock() {
+                    done.recv_timeout(DUMP_TIMEOUT).ok();
+                }
+            }
+        }
+
+        process::abort();
+    }));
+
+    Ok(())
+}
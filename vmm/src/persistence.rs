@@ -0,0 +1,201 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Persistence of the VM across VMM restarts.
+//!
+//! When a state directory is provided, the configuration of the VM, which
+//! includes the devices hot plugged and unplugged since its creation, is
+//! written to it along with the state of the VM each time one of them
+//! changes. Should the VMM process die, a new one can re-create the VM from
+//! it. The file is replaced atomically so that it's never found half written.
+//! Each hosted VM is persisted the same way, to a directory of its own under
+//! `vms/`.
+
+use crate::config::VmConfig;
+use crate::hosted_vms::valid_vm_id;
+use crate::vm::VmState;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "vm.json";
+const STATE_TMP_FILE: &str = "vm.json.tmp";
+const HOSTED_VMS_DIR: &str = "vms";
+
+/// The VM as recorded in the state directory.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PersistedVm {
+    pub config: VmConfig,
+    pub state: VmState,
+}
+
+impl PersistedVm {
+    /// Whether the VM can be re-created by a new VMM process. The file
+    /// descriptors the network interfaces and the disks were created from
+    /// are owned by the process which passed them, and can't be re-opened.
+    pub fn resumable(&self) -> bool {
+        !self
+            .config
+            .net
+            .iter()
+            .flatten()
+            .any(|net| net.fds.is_some())
+            && !self
+                .config
+                .disks
+                .iter()
+                .flatten()
+                .any(|disk| disk.fd.is_some())
+    }
+}
+
+pub struct StateDir {
+    path: PathBuf,
+    // Content of the state file, to only write it when it changes.
+    content: Option<String>,
+}
+
+impl StateDir {
+    pub fn new(path: PathBuf) -> Self {
+        StateDir {
+            path,
+            content: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Directory the hosted VM `id` is persisted to.
+    pub fn hosted_vm_path(&self, id: &str) -> PathBuf {
+        self.path.join(HOSTED_VMS_DIR).join(id)
+    }
+
+    /// Records the VM, or removes the state file if there's no VM anymore.
+    pub fn save(&mut self, vm: Option<&PersistedVm>) -> io::Result<()> {
+        let content = vm.map(serde_json::to_string_pretty).transpose()?;
+        if content == self.content {
+            return Ok(());
+        }
+
+        let state_file = self.path.join(STATE_FILE);
+        if let Some(content) = &content {
+            let tmp_file = self.path.join(STATE_TMP_FILE);
+            let mut file = File::create(&tmp_file)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp_file, &state_file)?;
+        } else if let Err(e) = fs::remove_file(&state_file) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        // Make the rename or the removal durable.
+        File::open(&self.path)?.sync_all()?;
+
+        self.content = content;
+        Ok(())
+    }
+
+    /// Reads the VM recorded by a previous VMM process, if any.
+    pub fn load(&self) -> io::Result<Option<PersistedVm>> {
+        match fs::read_to_string(self.path.join(STATE_FILE)) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the hosted VMs recorded by a previous VMM process, along with
+    /// their identifiers.
+    pub fn load_hosted(&self) -> io::Result<Vec<(String, PersistedVm)>> {
+        let entries = match fs::read_dir(self.path.join(HOSTED_VMS_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut vms = Vec::new();
+        for entry in entries {
+            let id = match entry?.file_name().into_string() {
+                Ok(id) if valid_vm_id(&id) => id,
+                _ => continue,
+            };
+            if let Some(vm) = StateDir::new(self.hosted_vm_path(&id)).load()? {
+                vms.push((id, vm));
+            }
+        }
+        vms.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(vms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DiskConfig, NetConfig};
+
+    #[test]
+    fn test_state_dir() -> io::Result<()> {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let mut state_dir = StateDir::new(tmp_dir.as_path().to_path_buf());
+        assert_eq!(state_dir.load()?, None);
+
+        let mut vm = PersistedVm {
+            config: serde_json::from_str(r#"{"kernel": {"path": "/path/to/kernel"}}"#)?,
+            state: VmState::Running,
+        };
+        state_dir.save(Some(&vm))?;
+        assert_eq!(state_dir.load()?.as_ref(), Some(&vm));
+        assert!(vm.resumable());
+
+        vm.state = VmState::Paused;
+        vm.config.net = Some(vec![NetConfig {
+            fds: Some(vec![3, 4]),
+            ..Default::default()
+        }]);
+        state_dir.save(Some(&vm))?;
+        assert_eq!(state_dir.load()?.as_ref(), Some(&vm));
+        assert!(!vm.resumable());
+
+        vm.config.net = None;
+        vm.config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/disk")),
+            fd: Some(3),
+            ..Default::default()
+        }]);
+        assert!(!vm.resumable());
+
+        state_dir.save(None)?;
+        assert_eq!(state_dir.load()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_hosted() -> io::Result<()> {
+        let tmp_dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let state_dir = StateDir::new(tmp_dir.as_path().to_path_buf());
+        assert!(state_dir.load_hosted()?.is_empty());
+
+        let vm = PersistedVm {
+            config: serde_json::from_str(r#"{"kernel": {"path": "/path/to/kernel"}}"#)?,
+            state: VmState::Running,
+        };
+        for id in &["vm1", "vm0"] {
+            fs::create_dir_all(state_dir.hosted_vm_path(id))?;
+            StateDir::new(state_dir.hosted_vm_path(id)).save(Some(&vm))?;
+        }
+        // A VM deleted before the VMM went down leaves its directory behind.
+        fs::create_dir_all(state_dir.hosted_vm_path("vm2"))?;
+
+        assert_eq!(
+            state_dir.load_hosted()?,
+            vec![("vm0".to_string(), vm.clone()), ("vm1".to_string(), vm)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host resources used by the VMM process, as reported by procfs.
+//!
+//! The resident memory is split between the guest RAM and the VMM overhead,
+//! the CPU time is accounted per thread name, and the open file descriptors
+//! are attributed to the devices whose files or TAP interfaces they point to.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::num::Wrapping;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+// The hugetlb pages a mapping holds aren't accounted in its Rss.
+const RESIDENT_FIELDS: &[&str] = &["Rss:", "Shared_Hugetlb:", "Private_Hugetlb:"];
+
+/// Host resource backing a device, which its file descriptors point to.
+pub enum DeviceResource {
+    /// File, identified by its device and inode numbers rather than by the
+    /// path it was opened with.
+    File(u64, u64),
+    Tap(String),
+}
+
+impl DeviceResource {
+    /// Identifies the file at `path`.
+    pub fn file(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(DeviceResource::File(metadata.dev(), metadata.ino()))
+    }
+
+    /// Identifies the file the VMM's `fd` is open on.
+    pub fn fd(fd: RawFd) -> io::Result<Self> {
+        Self::file(Path::new(&format!("/proc/self/fd/{}", fd)))
+    }
+}
+
+/// Parses the resident memory, in bytes, of all the mappings and of the
+/// ones lying within the `guest_ranges` of host addresses.
+pub fn parse_smaps(content: &str, guest_ranges: &[(u64, u64)]) -> (u64, u64) {
+    let mut total = 0;
+    let mut guest = 0;
+    let mut in_guest_range = false;
+
+    for line in content.lines() {
+        let resident = RESIDENT_FIELDS
+            .iter()
+            .find_map(|field| line.strip_prefix(field));
+        if let Some(rss) = resident {
+            // Rss:                 132 kB
+            let bytes = rss
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .unwrap_or(0)
+                * 1024;
+            total += bytes;
+            if in_guest_range {
+                guest += bytes;
+            }
+        } else if let Some((start, end)) = parse_mapping(line) {
+            in_guest_range = guest_ranges
+                .iter()
+                .any(|(guest_start, guest_end)| start >= *guest_start && end <= *guest_end);
+        }
+    }
+
+    (total, guest)
+}
+
+// 7f2c34000000-7f2c74000000 rw-s 00000000 00:01 1045    /memfd:ch_ram (deleted)
+fn parse_mapping(line: &str) -> Option<(u64, u64)> {
+    let range = line.split_whitespace().next()?;
+    let mut addrs = range.splitn(2, '-');
+    let start = u64::from_str_radix(addrs.next()?, 16).ok()?;
+    let end = u64::from_str_radix(addrs.next()?, 16).ok()?;
+    Some((start, end))
+}
+
+/// Parses the name of a thread, and the user and system time it ran for, in
+/// clock ticks.
+pub fn parse_task_stat(content: &str) -> Option<(String, u64, u64)> {
+    // 1234 (vcpu0) S 1233 ... utime stime ...
+    // The name can hold spaces and parentheses, the last one closes it.
+    let start = content.find('(')?;
+    let end = content.rfind(')')?;
+    let name = content.get(start + 1..end)?.to_owned();
+    // utime and stime are the 14th and 15th fields, the state the 3rd.
+    let mut fields = content.get(end + 1..)?.split_whitespace().skip(11);
+    let utime = fields.next()?.parse().ok()?;
+    let stime = fields.next()?.parse().ok()?;
+    Some((name, utime, stime))
+}
+
+fn tap_name(fd: &str) -> Option<String> {
+    // iff:	tap0
+    fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("iff:"))
+        .map(|name| name.trim().to_owned())
+}
+
+/// Gathers the counters of the host resources the VMM uses: its memory and
+/// open file descriptors under "vmm", the CPU time of its threads under
+/// "vmm/threads/<name>", and the file descriptors each device holds under
+/// the device id.
+pub fn counters(
+    guest_ranges: &[(u64, u64)],
+    device_resources: &[(String, DeviceResource)],
+) -> io::Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
+    let mut counters = HashMap::new();
+
+    let (rss, guest_rss) = parse_smaps(&fs::read_to_string("/proc/self/smaps")?, guest_ranges);
+
+    // Safe because sysconf() has no side effect, and a failure is checked.
+    let ticks_per_sec = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    };
+    let mut threads = 0;
+    for task in fs::read_dir("/proc/self/task")? {
+        let stat = match fs::read_to_string(task?.path().join("stat")) {
+            Ok(stat) => stat,
+            // The thread exited in the meantime.
+            Err(_) => continue,
+        };
+        if let Some((name, utime, stime)) = parse_task_stat(&stat) {
+            let thread: &mut HashMap<&'static str, Wrapping<u64>> =
+                counters.entry(format!("vmm/threads/{}", name)).or_default();
+            *thread.entry("threads").or_default() += Wrapping(1);
+            *thread.entry("user_time_us").or_default() +=
+                Wrapping(utime * 1_000_000 / ticks_per_sec);
+            *thread.entry("system_time_us").or_default() +=
+                Wrapping(stime * 1_000_000 / ticks_per_sec);
+            threads += 1;
+        }
+    }
+
+    let mut fds = 0;
+    for fd in fs::read_dir("/proc/self/fd")? {
+        let fd = fd?;
+        fds += 1;
+        let (target, metadata) = match (fs::read_link(fd.path()), fs::metadata(fd.path())) {
+            (Ok(target), Ok(metadata)) => (target, metadata),
+            // The fd was the one reading the directory, or closed since.
+            _ => continue,
+        };
+        let tap = if target.to_str() == Some(TUN_PATH) {
+            fd.file_name().to_str().and_then(tap_name)
+        } else {
+            None
+        };
+        for (id, resource) in device_resources {
+            let owned = match resource {
+                DeviceResource::File(dev, ino) => metadata.dev() == *dev && metadata.ino() == *ino,
+                DeviceResource::Tap(name) => tap.as_ref() == Some(name),
+            };
+            if owned {
+                *counters
+                    .entry(id.clone())
+                    .or_default()
+                    .entry("open_fds")
+                    .or_default() += Wrapping(1);
+            }
+        }
+    }
+
+    let mut vmm = HashMap::new();
+    vmm.insert("rss_bytes", Wrapping(rss));
+    vmm.insert("guest_rss_bytes", Wrapping(guest_rss));
+    vmm.insert(
+        "overhead_rss_bytes",
+        Wrapping(rss.saturating_sub(guest_rss)),
+    );
+    vmm.insert("threads", Wrapping(threads));
+    vmm.insert("open_fds", Wrapping(fds));
+    counters.insert("vmm".to_owned(), vmm);
+
+    Ok(counters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_smaps() {
+        let smaps = "\
+55d4c8e00000-55d4c9000000 r-xp 00000000 fd:01 1234    /usr/bin/cloud-hypervisor
+Size:               2048 kB
+Rss:                1024 kB
+VmFlags: rd ex mr mw me dw
+7f0000000000-7f0000200000 rw-s 00000000 00:01 1045    /memfd:ch_ram (deleted)
+Size:               2048 kB
+Rss:                 512 kB
+VmFlags: rd wr sh mr mw me ms sd
+7f0000200000-7f0000400000 rw-p 00000000 00:00 0
+Size:               2048 kB
+Rss:                   4 kB
+7f0040000000-7f0080000000 rw-s 00000000 00:0f 2048    /dev/hugepages/ch_ram (deleted)
+Size:            1048576 kB
+Rss:                   0 kB
+Shared_Hugetlb:     4096 kB
+Private_Hugetlb:    2048 kB
+";
+        assert_eq!(
+            parse_smaps(smaps, &[(0x7f00_0000_0000, 0x7f00_0020_0000)]),
+            (7684 * 1024, 512 * 1024)
+        );
+        assert_eq!(
+            parse_smaps(smaps, &[(0x7f00_4000_0000, 0x7f00_8000_0000)]),
+            (7684 * 1024, 6144 * 1024)
+        );
+        assert_eq!(parse_smaps(smaps, &[]), (7684 * 1024, 0));
+    }
+
+    #[test]
+    fn test_parse_task_stat() {
+        let stat = "4242 (vcpu0) S 4240 4240 1234 34816 4240 4194560 1077 0 0 0 \
+                    250 17 0 0 20 0 9 0 38283 1129259008 12345 ";
+        assert_eq!(parse_task_stat(stat), Some(("vcpu0".to_owned(), 250, 17)));
+
+        let stat = "4243 (a (b) c) R 4240 4240 1234 34816 4240 4194560 1077 0 0 0 \
+                    3 4 0 0 20 0 9 0 38283 1129259008 12345 ";
+        assert_eq!(parse_task_stat(stat), Some(("a (b) c".to_owned(), 3, 4)));
+
+        assert_eq!(parse_task_stat("4244 (truncated) S 1"), None);
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = counters(&[], &[]).unwrap();
+        let vmm = &counters["vmm"];
+        assert!(vmm["rss_bytes"].0 > 0);
+        assert_eq!(vmm["guest_rss_bytes"].0, 0);
+        assert!(vmm["threads"].0 > 0);
+        assert!(vmm["open_fds"].0 > 0);
+    }
+
+    #[test]
+    fn test_counters_device_fds() {
+        let file = fs::File::open("/proc/self/exe").unwrap();
+        let resources = [
+            (
+                "by-path".to_owned(),
+                DeviceResource::file(Path::new("/proc/self/exe")).unwrap(),
+            ),
+            (
+                "by-fd".to_owned(),
+                DeviceResource::fd(std::os::unix::io::AsRawFd::as_raw_fd(&file)).unwrap(),
+            ),
+        ];
+        let counters = counters(&[], &resources).unwrap();
+        assert!(counters["by-path"]["open_fds"].0 > 0);
+        assert_eq!(
+            counters["by-path"]["open_fds"],
+            counters["by-fd"]["open_fds"]
+        );
+    }
+}
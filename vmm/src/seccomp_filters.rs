@@ -13,9 +13,16 @@ use std::convert::TryInto;
 
 pub enum Thread {
     Api,
+    ApiAudit,
+    ApiVsock,
+    ColdPages,
+    InputReplay,
+    Nvme,
+    PanicDump,
     SignalHandler,
     Vcpu,
     Vmm,
+    Xhci,
 }
 
 /// Shorthand for chaining `SeccompCondition`s with the `and` operator  in a `SeccompRule`.
@@ -52,12 +59,23 @@ const TIOCGTPEER: u64 = 0x5441;
 const FIOCLEX: u64 = 0x5451;
 const FIONBIO: u64 = 0x5421;
 
+// See include/uapi/linux/fs.h in the kernel code.
+const BLKSSZGET: u64 = 0x1268;
+const BLKIOMIN: u64 = 0x1278;
+const BLKIOOPT: u64 = 0x1279;
+const BLKPBSZGET: u64 = 0x127b;
+
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNGETIFF: u64 = 0x8004_54d2;
 const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
 const TUNGETFEATURES: u64 = 0x8004_54cf;
+const TUNATTACHFILTER: u64 = 0x4010_54d5;
+const TUNSETFILTEREBPF: u64 = 0x8004_54e1;
+
+// See include/uapi/linux/bpf.h in the kernel code.
+const BPF_OBJ_GET: u64 = 7;
 
 // See include/uapi/linux/sockios.h in the kernel code.
 const SIOCGIFFLAGS: u64 = 0x8913;
@@ -80,10 +98,24 @@ const VFIO_DEVICE_GET_REGION_INFO: u64 = 0x3b6c;
 const VFIO_DEVICE_GET_IRQ_INFO: u64 = 0x3b6d;
 const VFIO_DEVICE_SET_IRQS: u64 = 0x3b6e;
 const VFIO_DEVICE_RESET: u64 = 0x3b6f;
+const VFIO_IOMMU_GET_INFO: u64 = 0x3b70;
 const VFIO_IOMMU_MAP_DMA: u64 = 0x3b71;
 const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 const VFIO_DEVICE_IOEVENTFD: u64 = 0x3b74;
 
+// See include/uapi/linux/usbdevice_fs.h in the kernel code.
+const USBDEVFS_CONTROL: u64 = 0xc018_5500;
+const USBDEVFS_SETINTERFACE: u64 = 0x8008_5504;
+const USBDEVFS_SETCONFIGURATION: u64 = 0x8004_5505;
+const USBDEVFS_SUBMITURB: u64 = 0x8038_550a;
+const USBDEVFS_DISCARDURB: u64 = 0x550b;
+const USBDEVFS_REAPURBNDELAY: u64 = 0x4008_550d;
+const USBDEVFS_RELEASEINTERFACE: u64 = 0x8004_5510;
+const USBDEVFS_IOCTL: u64 = 0xc010_5512;
+const USBDEVFS_CLEAR_HALT: u64 = 0x8004_5515;
+const USBDEVFS_DISCONNECT_CLAIM: u64 = 0x8108_551b;
+const USBDEVFS_GET_SPEED: u64 = 0x551f;
+
 // See include/uapi/linux/kvm.h in the kernel code.
 #[cfg(feature = "kvm")]
 mod kvm {
@@ -101,6 +133,7 @@ mod kvm {
     pub const KVM_SET_USER_MEMORY_REGION: u64 = 0x4020_ae46;
     pub const KVM_IRQFD: u64 = 0x4020_ae76;
     pub const KVM_IOEVENTFD: u64 = 0x4040_ae79;
+    pub const KVM_REGISTER_COALESCED_MMIO: u64 = 0x4010_ae67;
     pub const KVM_SET_VCPU_EVENTS: u64 = 0x4040_aea0;
     pub const KVM_ENABLE_CAP: u64 = 0x4068_aea3;
     pub const KVM_SET_REGS: u64 = 0x4090_ae82;
@@ -187,6 +220,12 @@ fn create_vmm_ioctl_seccomp_rule_common_kvm() -> Result<Vec<SeccompRule>, Error>
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_IRQFD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_RUN)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_MEMORY_ENCRYPT_OP)?],
+        and![Cond::new(
+            1,
+            ArgLen::DWORD,
+            Eq,
+            KVM_REGISTER_COALESCED_MMIO
+        )?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_DEVICE_ATTR,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_GSI_ROUTING)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MP_STATE)?],
@@ -209,6 +248,10 @@ fn create_vmm_ioctl_seccomp_rule_hypervisor() -> Result<Vec<SeccompRule>, Error>
 
 fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
     let mut common_rules = or![
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKIOMIN)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKIOOPT)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKPBSZGET)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, BLKSSZGET)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, FIOCLEX)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, FIONBIO)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCGIFFLAGS)?],
@@ -222,8 +265,10 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGWINSZ)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TIOCSPTLCK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGTPEER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNATTACHFILTER)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETFEATURES)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETIFF)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETFILTEREBPF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
@@ -244,9 +289,17 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_GET_IRQ_INFO)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_SET_IRQS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_RESET)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_GET_INFO)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_MAP_DMA)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_IOMMU_UNMAP_DMA)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_DEVICE_IOEVENTFD)?],
+        // The host USB devices are opened, and closed, by the VMM thread,
+        // their transfers being left to the xHCI worker.
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_CONTROL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_RELEASEINTERFACE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_IOCTL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_DISCONNECT_CLAIM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_GET_SPEED)?],
     ];
 
     let hypervisor_rules = create_vmm_ioctl_seccomp_rule_hypervisor()?;
@@ -367,6 +420,164 @@ fn signal_handler_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     ])
 }
 
+fn cold_pages_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_pread64),
+        allow_syscall(libc::SYS_pwrite64),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+fn input_replay_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+fn nvme_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        #[cfg(feature = "mshv")]
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_epoll_create1),
+        allow_syscall(libc::SYS_epoll_ctl),
+        allow_syscall(libc::SYS_epoll_pwait),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_epoll_wait),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_fdatasync),
+        allow_syscall(libc::SYS_fsync),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(SYS_IO_URING_ENTER),
+        allow_syscall(libc::SYS_lseek),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_pread64),
+        allow_syscall(libc::SYS_preadv),
+        allow_syscall(libc::SYS_pwrite64),
+        allow_syscall(libc::SYS_pwritev),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+fn create_xhci_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_SETINTERFACE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_SETCONFIGURATION)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_SUBMITURB)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_DISCARDURB)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_REAPURBNDELAY)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_RELEASEINTERFACE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_IOCTL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_CLEAR_HALT)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, USBDEVFS_DISCONNECT_CLAIM)?],
+    ])
+}
+
+// The worker may drop a host device, unplugged from the host or reconfigured
+// by the guest, which releases its interfaces and closes its file.
+fn xhci_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        #[cfg(feature = "mshv")]
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_epoll_create1),
+        allow_syscall(libc::SYS_epoll_ctl),
+        allow_syscall(libc::SYS_epoll_pwait),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_epoll_wait),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall_if(libc::SYS_ioctl, create_xhci_ioctl_seccomp_rule()?),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+#[cfg(feature = "kvm")]
+fn create_panic_dump_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, Error> {
+    #[cfg(target_arch = "x86_64")]
+    const KVM_GET_SREGS: u64 = 0x8138_ae83;
+
+    let mut rules = or![and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_REGS)?]];
+    #[cfg(target_arch = "x86_64")]
+    rules.push(and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_SREGS)?]);
+    #[cfg(target_arch = "aarch64")]
+    rules.push(and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_ONE_REG)?]);
+
+    Ok(rules)
+}
+
+#[cfg(feature = "mshv")]
+fn create_panic_dump_ioctl_seccomp_rule_mshv() -> Result<Vec<SeccompRule>, Error> {
+    Ok(or![and![Cond::new(
+        1,
+        ArgLen::DWORD,
+        Eq,
+        MSHV_GET_VP_REGISTERS
+    )?]])
+}
+
+fn create_panic_dump_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    #[cfg(feature = "kvm")]
+    let rules = create_panic_dump_ioctl_seccomp_rule_kvm();
+
+    #[cfg(feature = "mshv")]
+    let rules = create_panic_dump_ioctl_seccomp_rule_mshv();
+
+    rules
+}
+
+// The filter of the thread writing the emergency dump, which reads the vCPU
+// registers once the vCPU threads have been signalled out of the guest.
+fn panic_dump_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_clock_nanosleep),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getpid),
+        allow_syscall_if(libc::SYS_ioctl, create_panic_dump_ioctl_seccomp_rule()?),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_nanosleep),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_tgkill),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
 // The filter containing the white listed syscall rules required by the VMM to
 // function.
 fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
@@ -377,6 +588,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "x86_64")]
         allow_syscall(libc::SYS_arch_prctl),
         allow_syscall(libc::SYS_bind),
+        allow_syscall_if(
+            libc::SYS_bpf,
+            or![and![Cond::new(0, ArgLen::DWORD, Eq, BPF_OBJ_GET)?]],
+        ),
         allow_syscall(libc::SYS_brk),
         allow_syscall(libc::SYS_clock_gettime),
         allow_syscall(libc::SYS_clock_nanosleep),
@@ -406,6 +621,7 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "aarch64")]
         allow_syscall(libc::SYS_newfstatat),
         allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getdents64),
         allow_syscall(libc::SYS_getpid),
         allow_syscall(libc::SYS_getrandom),
         allow_syscall(libc::SYS_gettid),
@@ -421,6 +637,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_madvise),
         allow_syscall(libc::SYS_mbind),
         allow_syscall(libc::SYS_memfd_create),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_mkdir),
+        #[cfg(target_arch = "aarch64")]
+        allow_syscall(libc::SYS_mkdirat),
         allow_syscall(libc::SYS_mmap),
         allow_syscall(libc::SYS_mprotect),
         allow_syscall(libc::SYS_mremap),
@@ -445,11 +665,18 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_readlinkat),
         allow_syscall(libc::SYS_recvfrom),
         allow_syscall(libc::SYS_recvmsg),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_rename),
+        #[cfg(target_arch = "aarch64")]
+        allow_syscall(libc::SYS_renameat2),
         allow_syscall(libc::SYS_restart_syscall),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_rmdir),
         allow_syscall(libc::SYS_rt_sigaction),
         allow_syscall(libc::SYS_rt_sigprocmask),
         allow_syscall(libc::SYS_rt_sigreturn),
         allow_syscall(libc::SYS_sched_getaffinity),
+        allow_syscall(libc::SYS_sched_setaffinity),
         allow_syscall(libc::SYS_sendmsg),
         allow_syscall(libc::SYS_sendto),
         allow_syscall(libc::SYS_set_robust_list),
@@ -460,6 +687,7 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
             or![
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
             ],
         ),
         allow_syscall(libc::SYS_socketpair),
@@ -476,6 +704,7 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         ),
         #[cfg(target_arch = "x86_64")]
         allow_syscall(libc::SYS_unlink),
+        // Also removes the cgroups on aarch64, which has no rmdir syscall.
         #[cfg(target_arch = "aarch64")]
         allow_syscall(libc::SYS_unlinkat),
         allow_syscall(libc::SYS_wait4),
@@ -539,6 +768,7 @@ fn vcpu_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_clock_gettime),
         allow_syscall(libc::SYS_clock_nanosleep),
         allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_connect),
         allow_syscall(libc::SYS_dup),
         allow_syscall(libc::SYS_exit),
         allow_syscall(libc::SYS_fstat),
@@ -555,6 +785,9 @@ fn vcpu_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "x86_64")]
         allow_syscall(libc::SYS_open),
         allow_syscall(libc::SYS_openat),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_poll),
+        allow_syscall(libc::SYS_ppoll),
         allow_syscall(libc::SYS_pread64),
         allow_syscall(libc::SYS_pwrite64),
         allow_syscall(libc::SYS_read),
@@ -564,6 +797,7 @@ fn vcpu_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_rt_sigreturn),
         allow_syscall(libc::SYS_sendmsg),
         allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_socket),
         allow_syscall(libc::SYS_tgkill),
         allow_syscall(libc::SYS_tkill),
         #[cfg(target_arch = "x86_64")]
@@ -601,12 +835,74 @@ fn api_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     ])
 }
 
+// The filter containing the white listed syscall rules required by the API
+// vsock thread, relaying the connections to the API socket.
+// The connection threads are spawned under the same filter.
+fn api_audit_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_accept4),
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clone),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_connect),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getsockopt),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mprotect),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sched_getaffinity),
+        allow_syscall(libc::SYS_set_robust_list),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_socket),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
+fn api_vsock_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_accept4),
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clone),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_connect),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mprotect),
+        allow_syscall(libc::SYS_munmap),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_poll),
+        allow_syscall(libc::SYS_ppoll),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sched_getaffinity),
+        allow_syscall(libc::SYS_set_robust_list),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_socket),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
 fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::Api => api_thread_rules()?,
+        Thread::ApiAudit => api_audit_thread_rules()?,
+        Thread::ApiVsock => api_vsock_thread_rules()?,
+        Thread::ColdPages => cold_pages_thread_rules()?,
+        Thread::InputReplay => input_replay_thread_rules()?,
+        Thread::Nvme => nvme_thread_rules()?,
+        Thread::PanicDump => panic_dump_thread_rules()?,
         Thread::SignalHandler => signal_handler_thread_rules()?,
         Thread::Vcpu => vcpu_thread_rules()?,
         Thread::Vmm => vmm_thread_rules()?,
+        Thread::Xhci => xhci_thread_rules()?,
     };
 
     SeccompFilter::new(rules.into_iter().collect(), SeccompAction::Trap)
@@ -615,9 +911,16 @@ fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error>
 fn get_seccomp_filter_log(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::Api => api_thread_rules()?,
+        Thread::ApiAudit => api_audit_thread_rules()?,
+        Thread::ApiVsock => api_vsock_thread_rules()?,
+        Thread::ColdPages => cold_pages_thread_rules()?,
+        Thread::InputReplay => input_replay_thread_rules()?,
+        Thread::Nvme => nvme_thread_rules()?,
+        Thread::PanicDump => panic_dump_thread_rules()?,
         Thread::SignalHandler => signal_handler_thread_rules()?,
         Thread::Vcpu => vcpu_thread_rules()?,
         Thread::Vmm => vmm_thread_rules()?,
+        Thread::Xhci => xhci_thread_rules()?,
     };
 
     SeccompFilter::new(rules.into_iter().collect(), SeccompAction::Log)
@@ -0,0 +1,367 @@
+// Copyright © 2021 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Functions of the host devices assigned through VFIO.
+//!
+//! The functions of a multifunction device often share a reset domain, in
+//! which case the host puts them in the same IOMMU group, as they can't be
+//! isolated from each other. Such functions are assigned together, on the
+//! same guest device and with the same function numbers as on the host.
+//!
+//! The functions of devices assigned with managed=on are bound to vfio-pci
+//! by the VMM, and bound back to their original driver once the VM is gone.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+use vfio_ioctls::VfioDevice;
+
+const VFIO_PCI_DRIVER: &str = "vfio-pci";
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostFunction {
+    pub function: u8,
+    pub path: PathBuf,
+}
+
+// Splits a PCI function name, such as "0000:01:00.1", into the name of its
+// device and its function number.
+fn parse_function_name(name: &str) -> Option<(&str, u8)> {
+    let (device, function) = name.split_at(name.rfind('.')?);
+    let function = function[1..].parse::<u8>().ok().filter(|f| *f < 8)?;
+    if device.split(':').count() != 3 {
+        return None;
+    }
+
+    Some((device, function))
+}
+
+fn iommu_group(path: &Path) -> Option<PathBuf> {
+    fs::read_link(path.join("iommu_group")).ok()
+}
+
+fn driver(path: &Path) -> Option<String> {
+    fs::read_link(path.join("driver"))
+        .ok()?
+        .file_name()?
+        .to_str()
+        .map(String::from)
+}
+
+/// Returns the sysfs path of the function `path` names, which is either a
+/// path already or the name of a PCI function, such as "0000:01:00.0".
+pub fn function_path(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(name) if parse_function_name(name).is_some() => {
+            Path::new(SYSFS_PCI_DEVICES).join(name)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+/// A function the VMM bound to vfio-pci. Dropping it binds the function back
+/// to its original driver, which can only happen once the VFIO device of the
+/// function is closed, as vfio-pci waits for it to be released.
+pub struct DriverBinding {
+    path: PathBuf,
+    name: String,
+    driver: Option<String>,
+    device: Option<Weak<VfioDevice>>,
+}
+
+impl DriverBinding {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sets the VFIO device the function is opened through.
+    pub fn set_device(&mut self, device: Weak<VfioDevice>) {
+        self.device = Some(device);
+    }
+
+    /// Whether the VFIO device of the function is still open, in which case
+    /// unbinding the function would block until it's closed.
+    pub fn device_open(&self) -> bool {
+        self.device
+            .as_ref()
+            .map_or(false, |device| device.strong_count() > 0)
+    }
+
+    fn restore(&self) -> io::Result<()> {
+        if driver(&self.path).as_deref() == Some(VFIO_PCI_DRIVER) {
+            fs::write(self.path.join("driver/unbind"), &self.name)?;
+        }
+        // An empty override lets the function match its drivers again.
+        fs::write(self.path.join("driver_override"), "\n")?;
+        if let Some(original) = &self.driver {
+            if driver(&self.path).is_none() {
+                fs::write(
+                    self.path
+                        .join("subsystem/drivers")
+                        .join(original)
+                        .join("bind"),
+                    &self.name,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for DriverBinding {
+    fn drop(&mut self) {
+        if self.device_open() {
+            warn!(
+                "Leaving {} bound to {}, its device is still open",
+                self.name, VFIO_PCI_DRIVER
+            );
+            return;
+        }
+        match self.restore() {
+            Ok(()) => info!(
+                "Bound {} back to {}",
+                self.name,
+                self.driver.as_deref().unwrap_or("no driver")
+            ),
+            Err(e) => error!("Error binding {} back to its driver: {}", self.name, e),
+        }
+    }
+}
+
+/// Binds the function `path` to vfio-pci, unbinding it from its driver
+/// first. Returns nothing if the function is bound to vfio-pci already, as
+/// it's then left as is once the VM is gone.
+pub fn bind_to_vfio_pci(path: &Path) -> io::Result<Option<DriverBinding>> {
+    let path = path.canonicalize()?;
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if parse_function_name(name).is_some() => name.to_owned(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a PCI function",
+            ))
+        }
+    };
+
+    let original = driver(&path);
+    if original.as_deref() == Some(VFIO_PCI_DRIVER) {
+        return Ok(None);
+    }
+
+    // From now on, the function is restored if anything goes wrong.
+    let binding = DriverBinding {
+        path,
+        name,
+        driver: original,
+        device: None,
+    };
+    let path = &binding.path;
+    fs::write(path.join("driver_override"), VFIO_PCI_DRIVER)?;
+    if binding.driver.is_some() {
+        fs::write(path.join("driver/unbind"), &binding.name)?;
+    }
+    fs::write(path.join("subsystem/drivers_probe"), &binding.name)?;
+
+    if driver(path).as_deref() != Some(VFIO_PCI_DRIVER) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "vfio-pci didn't bind to the function, is the vfio-pci module loaded?",
+        ));
+    }
+    info!(
+        "Bound {} to {} in place of {}",
+        binding.name,
+        VFIO_PCI_DRIVER,
+        binding.driver.as_deref().unwrap_or("no driver")
+    );
+
+    Ok(Some(binding))
+}
+
+/// Returns the functions of the host device `path` is a function of, sorted
+/// by function number. Returns nothing if `path` isn't a PCI function, such
+/// as for a mediated device.
+pub fn device_functions(path: &Path) -> io::Result<Vec<HostFunction>> {
+    let path = path.canonicalize()?;
+    let device = match path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(parse_function_name)
+    {
+        Some((device, _)) => device.to_owned(),
+        None => return Ok(Vec::new()),
+    };
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut functions = Vec::new();
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if let Some((d, function)) = name.to_str().and_then(parse_function_name) {
+            if d == device {
+                functions.push(HostFunction {
+                    function,
+                    path: entry.path(),
+                });
+            }
+        }
+    }
+    functions.sort_by_key(|f| f.function);
+
+    Ok(functions)
+}
+
+/// Returns the functions among `functions` other than `path` which can't be
+/// isolated from it, as they are in the same IOMMU group. The functions in
+/// `assigned`, which are assigned to the same VM, don't need to be.
+pub fn unisolated_functions(
+    path: &Path,
+    functions: &[HostFunction],
+    assigned: &[PathBuf],
+) -> Vec<PathBuf> {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return Vec::new(),
+    };
+    let group = match iommu_group(&path) {
+        Some(group) => group,
+        None => return Vec::new(),
+    };
+
+    let assigned: Vec<PathBuf> = assigned
+        .iter()
+        .filter_map(|path| function_path(path).canonicalize().ok())
+        .collect();
+
+    functions
+        .iter()
+        .filter(|f| f.path != path && iommu_group(&f.path).as_ref() == Some(&group))
+        .filter(|f| !assigned.contains(&f.path))
+        .map(|f| f.path.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_parse_function_name() {
+        assert_eq!(parse_function_name("0000:01:00.1"), Some(("0000:01:00", 1)));
+        assert_eq!(parse_function_name("0000:01:00.8"), None);
+        assert_eq!(parse_function_name("01:00.0"), None);
+        assert_eq!(
+            parse_function_name("c2d4e9a0-4f0b-4c8e-9c1a-0f6f2b1e5a11"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_device_functions() {
+        let tmp_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let sysfs = tmp_dir.as_path().canonicalize().unwrap();
+
+        // The functions 0 and 1 share an IOMMU group, the function 2 and
+        // the device 01:01 are isolated.
+        for (name, group) in [
+            ("0000:01:00.0", "10"),
+            ("0000:01:00.1", "10"),
+            ("0000:01:00.2", "11"),
+            ("0000:01:01.0", "12"),
+        ]
+        .iter()
+        {
+            let function = sysfs.join(name);
+            fs::create_dir(&function).unwrap();
+            symlink(
+                sysfs.join("iommu_groups").join(group),
+                function.join("iommu_group"),
+            )
+            .unwrap();
+        }
+
+        let functions = device_functions(&sysfs.join("0000:01:00.1")).unwrap();
+        assert_eq!(
+            functions.iter().map(|f| f.function).collect::<Vec<u8>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            unisolated_functions(&sysfs.join("0000:01:00.1"), &functions, &[]),
+            vec![sysfs.join("0000:01:00.0")]
+        );
+        assert!(unisolated_functions(&sysfs.join("0000:01:00.2"), &functions, &[]).is_empty());
+        // The function 0 is assigned to the VM as well.
+        assert!(unisolated_functions(
+            &sysfs.join("0000:01:00.1"),
+            &functions,
+            &[sysfs.join("0000:01:00.1"), sysfs.join("0000:01:00.0")]
+        )
+        .is_empty());
+
+        assert_eq!(
+            device_functions(&sysfs.join("0000:01:01.0")).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_function_path() {
+        assert_eq!(
+            function_path(Path::new("0000:af:00.0")),
+            PathBuf::from("/sys/bus/pci/devices/0000:af:00.0")
+        );
+        assert_eq!(
+            function_path(Path::new("/sys/bus/pci/devices/0000:af:00.0/")),
+            PathBuf::from("/sys/bus/pci/devices/0000:af:00.0/")
+        );
+    }
+
+    #[test]
+    fn test_bind_to_vfio_pci() {
+        let tmp_dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let sysfs = tmp_dir.as_path().canonicalize().unwrap();
+        let drivers = sysfs.join("drivers");
+        fs::create_dir_all(drivers.join("vfio-pci")).unwrap();
+        fs::create_dir_all(drivers.join("nvme")).unwrap();
+        fs::write(sysfs.join("drivers_probe"), "").unwrap();
+
+        for (name, driver) in [("0000:01:00.0", "vfio-pci"), ("0000:02:00.0", "nvme")].iter() {
+            let function = sysfs.join(name);
+            fs::create_dir(&function).unwrap();
+            fs::write(function.join("driver_override"), "").unwrap();
+            symlink(&sysfs, function.join("subsystem")).unwrap();
+            symlink(drivers.join(driver), function.join("driver")).unwrap();
+        }
+        fs::write(drivers.join("nvme/unbind"), "").unwrap();
+
+        // Nothing to do for a function bound to vfio-pci already.
+        assert!(bind_to_vfio_pci(&sysfs.join("0000:01:00.0"))
+            .unwrap()
+            .is_none());
+
+        // Nothing actually binds the function, which is left with the
+        // override cleared.
+        let function = sysfs.join("0000:02:00.0");
+        assert!(bind_to_vfio_pci(&function).is_err());
+        assert_eq!(
+            fs::read_to_string(drivers.join("nvme/unbind")).unwrap(),
+            "0000:02:00.0"
+        );
+        assert_eq!(
+            fs::read_to_string(function.join("driver_override")).unwrap(),
+            "\n"
+        );
+
+        assert!(bind_to_vfio_pci(&sysfs.join("0000:03:00.0")).is_err());
+    }
+}
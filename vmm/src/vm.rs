@@ -11,20 +11,30 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::cgroup::VmCgroup;
+use crate::cold_pages::{ColdPageCounters, ColdPageScanner};
 #[cfg(feature = "acpi")]
 use crate::config::NumaConfig;
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig, ValidationError,
-    VmConfig, VsockConfig,
+    BalloonConfig, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, InputLogConfig, IommuType,
+    NetConfig, NumaPolicy, PmemConfig, ValidationError, VmConfig, VsockConfig, WorkerFailurePolicy,
 };
+use crate::console_buffer::ConsoleBuffer;
 use crate::cpu;
 use crate::device_manager::{
     self, get_win_size, Console, DeviceManager, DeviceManagerError, PtyPair,
 };
 use crate::device_tree::DeviceTree;
-use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::memory_manager::{
+    CopyThrottle, Error as MemoryManagerError, MemoryManager, MemoryRegionInfo,
+};
+use crate::metrics::{Metric, MetricType};
 use crate::migration::{get_vm_snapshot, url_to_path, VM_SNAPSHOT_FILE};
+use crate::numa_placement::{HostNumaNode, IoAffinity, NumaPlacement};
+use crate::panic_dump;
+use crate::resource_usage::{self, DeviceResource};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::vfio_functions::DriverBinding;
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
@@ -61,8 +71,13 @@ use std::io::{self, Read, Write};
 use std::io::{Seek, SeekFrom};
 use std::num::Wrapping;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{result, str, thread};
+use virtio_devices::{DiskSwitchRequest, WorkerFailure, WorkerSupervisor};
 use vm_device::Bus;
 use vm_memory::{
     Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
@@ -141,6 +156,18 @@ pub enum Error {
     /// Cannot spawn a signal handler thread
     SignalHandlerSpawn(io::Error),
 
+    /// Cannot spawn the thread replaying the input log
+    InputReplaySpawn(io::Error),
+
+    /// Cannot start offloading the cold guest pages
+    ColdPageOffload(io::Error),
+
+    /// Cannot spawn the thread flushing the coalesced serial port writes
+    SerialFlushSpawn(io::Error),
+
+    /// Cannot spawn the thread copying the guest memory to a checkpoint
+    CheckpointSpawn(io::Error),
+
     /// Failed to join on vCPU threads
     ThreadCleanup(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
 
@@ -189,6 +216,15 @@ pub enum Error {
     /// Eventfd write error
     EventfdError(std::io::Error),
 
+    /// Cannot arm the resume timer
+    ResumeTimer(std::io::Error),
+
+    /// Cannot set the VM cgroup up
+    Cgroup(crate::cgroup::Error),
+
+    /// Cannot place the VM onto the host NUMA nodes
+    NumaPlacement(crate::numa_placement::Error),
+
     /// Cannot snapshot VM
     Snapshot(MigratableError),
 
@@ -198,6 +234,21 @@ pub enum Error {
     /// Cannot send VM snapshot
     SnapshotSend(MigratableError),
 
+    /// Cannot checkpoint VM
+    Checkpoint(MigratableError),
+
+    /// Disk can't be part of a checkpoint
+    CheckpointDisk(String),
+
+    /// No freeze hook to quiesce the guest with for a checkpoint
+    CheckpointQuiesce,
+
+    /// A checkpoint is already being taken
+    CheckpointInProgress,
+
+    /// No checkpoint is being taken
+    NoCheckpointInProgress,
+
     /// Cannot convert source URL from Path into &str
     RestoreSourceUrlPathToStr,
 
@@ -207,6 +258,12 @@ pub enum Error {
     /// No more that one virtio-vsock device
     TooManyVsockDevices,
 
+    /// No more that one virtio-balloon device
+    TooManyBalloonDevices,
+
+    /// No device with this identifier
+    UnknownDeviceId(String),
+
     /// Failed serializing into JSON
     SerializeJson(serde_json::Error),
 
@@ -225,15 +282,60 @@ pub enum Error {
     /// Cannot activate virtio devices
     ActivateVirtioDevices(device_manager::DeviceManagerError),
 
+    /// Cannot handle the failure of a virtio device worker
+    WorkerFailure(device_manager::DeviceManagerError),
+
     /// Power button not supported
     PowerButtonNotSupported,
 
     /// Error triggering power button
     PowerButton(device_manager::DeviceManagerError),
 
+    /// Sleep button not supported
+    SleepButtonNotSupported,
+
+    /// Error triggering sleep button
+    SleepButton(device_manager::DeviceManagerError),
+
+    /// NMI injection not supported
+    NmiNotSupported,
+
+    /// Error injecting an NMI
+    Nmi(device_manager::DeviceManagerError),
+
+    /// Power supply not supported
+    PowerSupplyNotSupported,
+
+    /// Error updating the power supply
+    PowerSupply(device_manager::DeviceManagerError),
+
+    /// Error setting the link state of a network device
+    SetNetLink(device_manager::DeviceManagerError),
+
+    /// Error starting the mirror of a disk
+    MirrorDisk(device_manager::DeviceManagerError),
+
+    /// Error pivoting a disk to its mirror
+    PivotDisk(device_manager::DeviceManagerError),
+
+    /// Error taking a snapshot of a disk
+    DiskSnapshot(device_manager::DeviceManagerError),
+
     /// Kernel lacks PVH header
     KernelMissingPvhHeader,
 
+    /// No console is driven through the API
+    NoApiConsole,
+
+    /// The guest didn't release a failover device in time
+    FailoverUnplugTimeout(String),
+
+    /// Cannot generate the seed of the randomized guest layout
+    LayoutSeed(io::Error),
+
+    /// Cannot generate the UUID of the VM
+    Identity(io::Error),
+
     /// Error doing I/O on TDX firmware file
     #[cfg(feature = "tdx")]
     LoadTdvf(std::io::Error),
@@ -310,13 +412,14 @@ pub enum VmState {
     Running,
     Shutdown,
     Paused,
+    Suspended,
 }
 
 impl VmState {
     fn valid_transition(self, new_state: VmState) -> Result<()> {
         match self {
             VmState::Created => match new_state {
-                VmState::Created | VmState::Shutdown => {
+                VmState::Created | VmState::Shutdown | VmState::Suspended => {
                     Err(Error::InvalidStateTransition(self, new_state))
                 }
                 VmState::Running | VmState::Paused => Ok(()),
@@ -326,18 +429,25 @@ impl VmState {
                 VmState::Created | VmState::Running => {
                     Err(Error::InvalidStateTransition(self, new_state))
                 }
-                VmState::Paused | VmState::Shutdown => Ok(()),
+                VmState::Paused | VmState::Shutdown | VmState::Suspended => Ok(()),
             },
 
             VmState::Shutdown => match new_state {
-                VmState::Paused | VmState::Created | VmState::Shutdown => {
+                VmState::Paused | VmState::Created | VmState::Shutdown | VmState::Suspended => {
                     Err(Error::InvalidStateTransition(self, new_state))
                 }
                 VmState::Running => Ok(()),
             },
 
             VmState::Paused => match new_state {
-                VmState::Created | VmState::Paused => {
+                VmState::Created | VmState::Paused | VmState::Suspended => {
+                    Err(Error::InvalidStateTransition(self, new_state))
+                }
+                VmState::Running | VmState::Shutdown => Ok(()),
+            },
+
+            VmState::Suspended => match new_state {
+                VmState::Created | VmState::Paused | VmState::Suspended => {
                     Err(Error::InvalidStateTransition(self, new_state))
                 }
                 VmState::Running | VmState::Shutdown => Ok(()),
@@ -488,6 +598,15 @@ pub fn physical_bits(max_phys_bits: Option<u8>) -> u8 {
     cmp::min(host_phys_bits, max_phys_bits.unwrap_or(host_phys_bits))
 }
 
+// Copy of the guest memory to a checkpoint, going on on its own thread while
+// the VM runs.
+struct CheckpointCopy {
+    pass: Arc<AtomicU32>,
+    copied: Arc<AtomicU64>,
+    cancel: Arc<AtomicBool>,
+    result: Receiver<(std::result::Result<u32, MigratableError>, CopyThrottle)>,
+}
+
 pub struct Vm {
     kernel: Option<File>,
     initramfs: Option<File>,
@@ -504,10 +623,25 @@ pub struct Vm {
     vm: Arc<dyn hypervisor::Vm>,
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     saved_clock: Option<hypervisor::ClockData>,
+    // Host time, in nanoseconds since the UNIX epoch, when the VM was last
+    // paused.
+    saved_host_time: Option<u64>,
     #[cfg(feature = "acpi")]
     numa_nodes: NumaNodes,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    io_affinity: Option<IoAffinity>,
+    // Dropped once the device manager is gone, along with the VFIO devices
+    // of the functions.
+    vfio_bindings: Vec<DriverBinding>,
+    // Dropped to stop the thread offloading the cold guest pages.
+    cold_pages: Option<Sender<()>>,
+    // Dropped to stop the thread flushing the coalesced serial port writes.
+    serial_flush: Option<Sender<()>>,
+    checkpoint: Option<CheckpointCopy>,
+    cold_page_counters: Arc<ColdPageCounters>,
+    // Dropped last, once the VM threads are gone.
+    cgroup: Option<VmCgroup>,
 }
 
 impl Vm {
@@ -518,12 +652,17 @@ impl Vm {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
+        cgroup: Option<VmCgroup>,
+        numa_placement: Option<NumaPlacement>,
+        io_affinity: Option<IoAffinity>,
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))] _saved_clock: Option<
             hypervisor::ClockData,
         >,
         activate_evt: EventFd,
+        worker_supervisor: WorkerSupervisor,
     ) -> Result<Self> {
         config
             .lock()
@@ -533,6 +672,24 @@ impl Vm {
 
         info!("Booting VM from config: {:?}", &config);
 
+        // The bounce buffers are reserved before any device is created, for
+        // them to be placed at the same address when the VM is restored.
+        #[cfg(target_arch = "x86_64")]
+        let bounce_buffer_size = config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|p| p.bounce_buffer_size);
+        #[cfg(target_arch = "x86_64")]
+        if let Some(size) = bounce_buffer_size {
+            memory_manager
+                .lock()
+                .unwrap()
+                .reserve_bounce_buffer(size)
+                .map_err(Error::MemoryManager)?;
+        }
+
         // Create NUMA nodes based on NumaConfig.
         #[cfg(feature = "acpi")]
         let numa_nodes =
@@ -544,10 +701,12 @@ impl Vm {
             memory_manager.clone(),
             &exit_evt,
             &reset_evt,
+            &suspend_evt,
             seccomp_action.clone(),
             #[cfg(feature = "acpi")]
             numa_nodes.clone(),
             &activate_evt,
+            worker_supervisor,
         )
         .map_err(Error::DeviceManager)?;
 
@@ -579,6 +738,7 @@ impl Vm {
             hypervisor,
             seccomp_action.clone(),
             vm_ops,
+            numa_placement.as_ref(),
             #[cfg(feature = "tdx")]
             tdx_enabled,
             #[cfg(feature = "acpi")]
@@ -586,6 +746,8 @@ impl Vm {
         )
         .map_err(Error::CpuManager)?;
 
+        panic_dump::register_vm(&cpu_manager, &device_manager.lock().unwrap().device_tree());
+
         let on_tty = unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0;
         let kernel = config
             .lock()
@@ -619,13 +781,56 @@ impl Vm {
             vm,
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             saved_clock: _saved_clock,
+            saved_host_time: None,
             #[cfg(feature = "acpi")]
             numa_nodes,
             seccomp_action: seccomp_action.clone(),
             exit_evt,
+            io_affinity,
+            vfio_bindings: Vec::new(),
+            cold_pages: None,
+            serial_flush: None,
+            checkpoint: None,
+            cold_page_counters: Arc::new(ColdPageCounters::default()),
+            cgroup,
         })
     }
 
+    // Moves the VMM to the cgroup of the VM, which must happen before the
+    // guest memory is allocated.
+    fn setup_cgroup(config: &Arc<Mutex<VmConfig>>) -> Result<Option<VmCgroup>> {
+        config
+            .lock()
+            .unwrap()
+            .cgroup
+            .as_ref()
+            .map(VmCgroup::new)
+            .transpose()
+            .map_err(Error::Cgroup)
+    }
+
+    // Places the VM onto the host NUMA nodes with the automatic policy. The
+    // VMM thread is bound to the host CPUs of these nodes, so that the
+    // threads of the devices, created from it, run there as well.
+    fn setup_numa_placement(
+        config: &Arc<Mutex<VmConfig>>,
+    ) -> Result<(Option<NumaPlacement>, Option<IoAffinity>)> {
+        let config = config.lock().unwrap();
+        if config.numa_policy != NumaPolicy::Auto {
+            return Ok((None, None));
+        }
+
+        let host_nodes = HostNumaNode::host_nodes().map_err(Error::NumaPlacement)?;
+        let numa_placement = NumaPlacement::new(&config, &host_nodes);
+        let io_affinity = numa_placement
+            .io_cpus()
+            .map(IoAffinity::new)
+            .transpose()
+            .map_err(Error::NumaPlacement)?;
+
+        Ok((Some(numa_placement), io_affinity))
+    }
+
     #[cfg(feature = "acpi")]
     fn create_numa_nodes(
         configs: Option<Vec<NumaConfig>>,
@@ -707,14 +912,73 @@ impl Vm {
         Ok(numa_nodes)
     }
 
+    // A VM with a randomized layout gets a new one on every boot. The seed
+    // it's derived from is kept in the config, for the VM to be restored or
+    // migrated with the same layout.
+    fn roll_layout_seed(config: &Arc<Mutex<VmConfig>>) -> Result<Option<u64>> {
+        let mut config = config.lock().unwrap();
+        let platform = match config.platform.as_mut() {
+            Some(platform) if platform.randomize_layout => platform,
+            _ => return Ok(None),
+        };
+
+        let mut seed = [0u8; 8];
+        File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut seed))
+            .map_err(Error::LayoutSeed)?;
+        platform.layout_seed = Some(u64::from_le_bytes(seed));
+
+        Ok(platform.layout_seed)
+    }
+
+    // The UUID and the creation time of a VM are assigned once, when it's
+    // created. Being kept in the config, they survive reboots, restores and
+    // migrations.
+    pub fn assign_identity(config: &Arc<Mutex<VmConfig>>) -> Result<()> {
+        let mut config = config.lock().unwrap();
+        let identity = &mut config.identity;
+
+        if identity.uuid.is_none() {
+            let mut bytes = [0u8; 16];
+            File::open("/dev/urandom")
+                .and_then(|mut f| f.read_exact(&mut bytes))
+                .map_err(Error::Identity)?;
+            let uuid = uuid::Builder::from_bytes(bytes)
+                .set_variant(uuid::Variant::RFC4122)
+                .set_version(uuid::Version::Random)
+                .build();
+            identity.uuid = Some(uuid.to_string());
+        }
+
+        if identity.created.is_none() {
+            identity.created = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|created| created.as_secs());
+        }
+
+        Ok(())
+    }
+
+    fn layout_seed(config: &Arc<Mutex<VmConfig>>) -> Option<u64> {
+        config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|platform| platform.layout_seed)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        worker_supervisor: WorkerSupervisor,
         serial_pty: Option<PtyPair>,
         console_pty: Option<PtyPair>,
     ) -> Result<Self> {
@@ -734,12 +998,17 @@ impl Vm {
 
         #[cfg(target_arch = "x86_64")]
         vm.enable_split_irq().unwrap();
+        let cgroup = Vm::setup_cgroup(&config)?;
+        let (numa_placement, io_affinity) = Vm::setup_numa_placement(&config)?;
         let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
+        let layout_seed = Vm::roll_layout_seed(&config)?;
         let memory_manager = MemoryManager::new(
             vm.clone(),
             &config.lock().unwrap().memory.clone(),
             false,
             phys_bits,
+            numa_placement.as_ref(),
+            layout_seed,
             #[cfg(feature = "tdx")]
             tdx_enabled,
         )
@@ -762,11 +1031,16 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            suspend_evt,
             seccomp_action,
             hypervisor,
+            cgroup,
+            numa_placement,
+            io_affinity,
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             None,
             activate_evt,
+            worker_supervisor,
         )?;
 
         // The device manager must create the devices from here as it is part
@@ -785,11 +1059,14 @@ impl Vm {
         snapshot: &Snapshot,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
         source_url: Option<&str>,
         prefault: bool,
+        input_log: Option<InputLogConfig>,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        worker_supervisor: WorkerSupervisor,
     ) -> Result<Self> {
         hypervisor.check_required_extensions().unwrap();
         let vm = hypervisor.create_vm().unwrap();
@@ -797,11 +1074,16 @@ impl Vm {
         vm.enable_split_irq().unwrap();
         let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::Restore)?;
         let config = vm_snapshot.config;
+        // The inputs of the restored VM are recorded or replayed as asked
+        // for on restore, rather than as they were when snapshotted.
+        config.lock().unwrap().input_log = input_log;
         if let Some(state) = vm_snapshot.state {
             vm.set_state(state)
                 .map_err(|e| Error::Restore(MigratableError::Restore(e.into())))?;
         }
 
+        let cgroup = Vm::setup_cgroup(&config)?;
+        let (numa_placement, io_affinity) = Vm::setup_numa_placement(&config)?;
         let memory_manager = if let Some(memory_manager_snapshot) =
             snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID)
         {
@@ -813,6 +1095,8 @@ impl Vm {
                 source_url,
                 prefault,
                 phys_bits,
+                numa_placement.as_ref(),
+                Vm::layout_seed(&config),
             )
             .map_err(Error::MemoryManager)?
         } else {
@@ -827,11 +1111,16 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            suspend_evt,
             seccomp_action,
             hypervisor,
+            cgroup,
+            numa_placement,
+            io_affinity,
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             vm_snapshot.clock,
             activate_evt,
+            worker_supervisor,
         )
     }
 
@@ -839,14 +1128,18 @@ impl Vm {
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         activate_evt: EventFd,
+        worker_supervisor: WorkerSupervisor,
     ) -> Result<Self> {
         hypervisor.check_required_extensions().unwrap();
         let vm = hypervisor.create_vm().unwrap();
         #[cfg(target_arch = "x86_64")]
         vm.enable_split_irq().unwrap();
+        let cgroup = Vm::setup_cgroup(&config)?;
+        let (numa_placement, io_affinity) = Vm::setup_numa_placement(&config)?;
         let phys_bits = physical_bits(config.lock().unwrap().cpus.max_phys_bits);
 
         let memory_manager = MemoryManager::new(
@@ -854,6 +1147,8 @@ impl Vm {
             &config.lock().unwrap().memory.clone(),
             false,
             phys_bits,
+            numa_placement.as_ref(),
+            Vm::layout_seed(&config),
             #[cfg(feature = "tdx")]
             false,
         )
@@ -865,11 +1160,16 @@ impl Vm {
             vm,
             exit_evt,
             reset_evt,
+            suspend_evt,
             seccomp_action,
             hypervisor,
+            cgroup,
+            numa_placement,
+            io_affinity,
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             None,
             activate_evt,
+            worker_supervisor,
         )
     }
 
@@ -992,12 +1292,13 @@ impl Vm {
         };
 
         let boot_vcpus = self.cpu_manager.lock().unwrap().boot_vcpus();
+        let pci_irq_slots = self.device_manager.lock().unwrap().pci_irq_slots().to_vec();
 
         #[allow(unused_mut, unused_assignments)]
         let mut rsdp_addr: Option<GuestAddress> = None;
 
         #[cfg(feature = "acpi")]
-        {
+        if self.config.lock().unwrap().acpi_enabled() {
             rsdp_addr = Some(crate::acpi::create_acpi_tables(
                 &mem,
                 &self.device_manager,
@@ -1019,13 +1320,29 @@ impl Vm {
             .as_ref()
             .cloned();
 
+        let identity = self.config.lock().unwrap().identity.clone();
+        let smbios_identity = arch::SmbiosIdentity {
+            serial_number: identity
+                .serial_number
+                .clone()
+                .or_else(|| identity.uuid.clone()),
+            uuid: identity
+                .uuid
+                .as_deref()
+                .and_then(|uuid| uuid::Uuid::parse_str(uuid).ok())
+                .map(|uuid| *uuid.as_bytes()),
+            oem_strings: identity.oem_strings(),
+        };
+
         arch::configure_system(
             &mem,
             arch::layout::CMDLINE_START,
             &initramfs_config,
             boot_vcpus,
+            &pci_irq_slots,
             rsdp_addr,
             sgx_epc_region,
+            &smbios_identity,
         )
         .map_err(Error::ConfigureSystem)?;
         Ok(())
@@ -1070,7 +1387,7 @@ impl Vm {
         let pci_space = (pci_space_start.0, pci_space_size);
 
         #[cfg(feature = "acpi")]
-        {
+        if self.config.lock().unwrap().acpi_enabled() {
             let _ = crate::acpi::create_acpi_tables(
                 &mem,
                 &self.device_manager,
@@ -1088,6 +1405,13 @@ impl Vm {
             Error::ConfigureSystem(arch::Error::AArch64Setup(arch::aarch64::Error::SetupGic(e)))
         })?;
 
+        // The PMU can only be initialized once the GIC exists.
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .init_pmu()
+            .map_err(Error::CpuManager)?;
+
         arch::configure_system(
             &mem,
             &cmdline_cstring,
@@ -1096,6 +1420,7 @@ impl Vm {
             &initramfs_config,
             &pci_space,
             &*gic_device,
+            self.config.lock().unwrap().cpus.pmu,
         )
         .map_err(Error::ConfigureSystem)?;
 
@@ -1131,6 +1456,16 @@ impl Vm {
         self.device_manager.lock().unwrap().console_pty()
     }
 
+    /// Brings the devices back to their power-on state, for the guest to be
+    /// rebooted.
+    pub fn reset_devices(&mut self) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .reset()
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         let new_state = VmState::Shutdown;
@@ -1151,6 +1486,22 @@ impl Vm {
             signals.close();
         }
 
+        // Trigger the termination of the input_replay thread
+        if let Some(input_log) = self.device_manager.lock().unwrap().input_log() {
+            input_log.stop();
+        }
+
+        // Trigger the termination of the cold_pages thread
+        self.cold_pages = None;
+
+        // Trigger the termination of the serial_flush thread
+        self.serial_flush = None;
+
+        // Trigger the termination of the checkpoint thread
+        if let Some(checkpoint) = self.checkpoint.take() {
+            checkpoint.cancel.store(true, Ordering::Release);
+        }
+
         // Wake up the DeviceManager threads so they will get terminated cleanly
         self.device_manager
             .lock()
@@ -1299,7 +1650,7 @@ impl Vm {
         Err(Error::ResizeZone)
     }
 
-    fn add_to_config<T>(devices: &mut Option<Vec<T>>, device: T) {
+    pub(crate) fn add_to_config<T>(devices: &mut Option<Vec<T>>, device: T) {
         if let Some(devices) = devices {
             devices.push(device);
         } else {
@@ -1347,41 +1698,85 @@ impl Vm {
 
         // Update VmConfig by removing the device. This is important to
         // ensure the device would not be created in case of a reboot.
-        let mut config = self.config.lock().unwrap();
+        self.config.lock().unwrap().remove_device(&_id);
 
-        // Remove if VFIO device
-        if let Some(devices) = config.devices.as_mut() {
-            devices.retain(|dev| dev.id.as_ref() != Some(&_id));
-        }
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+        Ok(())
+    }
 
-        // Remove if disk device
-        if let Some(disks) = config.disks.as_mut() {
-            disks.retain(|dev| dev.id.as_ref() != Some(&_id));
-        }
+    /// Unplugs the assigned devices backed up by a standby virtio-net device,
+    /// and waits for the guest to eject them, so that it fails over to the
+    /// virtio-net devices before the VM gets migrated. Returns the devices
+    /// which were unplugged, for them to be plugged back if the migration
+    /// fails.
+    pub fn unplug_failover_devices(&mut self) -> Result<Vec<DeviceConfig>> {
+        const EJECT_TIMEOUT: Duration = Duration::from_secs(10);
+        const EJECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-        // Remove if net device
-        if let Some(net) = config.net.as_mut() {
-            net.retain(|dev| dev.id.as_ref() != Some(&_id));
+        let devices: Vec<DeviceConfig> = self
+            .config
+            .lock()
+            .unwrap()
+            .devices
+            .iter()
+            .flatten()
+            .filter(|device| device.failover.is_some() && device.id.is_some())
+            .cloned()
+            .collect();
+
+        let mut unplugged = Vec::new();
+        for device in devices {
+            let id = device.id.clone().unwrap();
+            info!("Unplugging failover device {}", id);
+            if let Err(e) = self.remove_device(id) {
+                self.replug_failover_devices(unplugged);
+                return Err(e);
+            }
+            unplugged.push(device);
+        }
+
+        // The guest ejects the devices from a vCPU thread, which removes them
+        // from the device tree.
+        let device_tree = self.device_manager.lock().unwrap().device_tree();
+        let start = Instant::now();
+        for id in unplugged.iter().filter_map(|device| device.id.as_ref()) {
+            while device_tree.lock().unwrap().contains_key(id) {
+                if start.elapsed() > EJECT_TIMEOUT {
+                    let id = id.clone();
+                    self.replug_failover_devices(unplugged);
+                    return Err(Error::FailoverUnplugTimeout(id));
+                }
+                thread::sleep(EJECT_POLL_INTERVAL);
+            }
         }
 
-        // Remove if pmem device
-        if let Some(pmem) = config.pmem.as_mut() {
-            pmem.retain(|dev| dev.id.as_ref() != Some(&_id));
-        }
+        Ok(unplugged)
+    }
 
-        // Remove if vsock device
-        if let Some(vsock) = config.vsock.as_ref() {
-            if vsock.id.as_ref() == Some(&_id) {
-                config.vsock = None;
+    /// Plugs the devices unplugged for the migration back, once it failed.
+    /// The errors can only be reported, as the migration error is the one
+    /// returned.
+    pub fn replug_failover_devices(&mut self, devices: Vec<DeviceConfig>) {
+        let device_tree = self.device_manager.lock().unwrap().device_tree();
+        for device in devices {
+            let id = device.id.clone().unwrap_or_default();
+            // A device the guest hasn't ejected yet can't be added again.
+            if device_tree.lock().unwrap().contains_key(&id) {
+                warn!(
+                    "Failover device {} is still being ejected, not plugging it back",
+                    id
+                );
+                continue;
+            }
+            info!("Plugging failover device {} back", id);
+            if let Err(e) = self.add_device(device) {
+                error!("Error plugging failover device {} back: {:?}", id, e);
             }
         }
-
-        self.device_manager
-            .lock()
-            .unwrap()
-            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
-            .map_err(Error::DeviceManager)?;
-        Ok(())
     }
 
     pub fn add_disk(&mut self, mut _disk_cfg: DiskConfig) -> Result<PciDeviceInfo> {
@@ -1543,8 +1938,219 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn add_balloon(&mut self, mut _balloon_cfg: BalloonConfig) -> Result<PciDeviceInfo> {
+        if self.config.lock().unwrap().balloon.is_some() {
+            return Err(Error::TooManyBalloonDevices);
+        }
+
+        {
+            // Validate on a clone of the config
+            let mut config = self.config.lock().unwrap().clone();
+            config.balloon = Some(_balloon_cfg.clone());
+            config.validate().map_err(Error::ConfigValidation)?;
+        }
+
+        let pci_device_info = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .add_balloon(&mut _balloon_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new device. This is important to
+        // ensure the device would be created in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            config.balloon = Some(_balloon_cfg);
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+
+        Ok(pci_device_info)
+    }
+
+    /// Gathers the device counters, along with the host resources used by
+    /// the VMM.
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
+        let mut counters = self.device_manager.lock().unwrap().counters();
+
+        let guest_ranges: Vec<(u64, u64)> = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .memory()
+            .iter()
+            .map(|region| {
+                let addr = region.as_ptr() as u64;
+                (addr, addr + region.len())
+            })
+            .collect();
+
+        let mut device_resources = Vec::new();
+        let config = self.config.lock().unwrap();
+        for disk in config.disks.iter().flatten() {
+            let id = match &disk.id {
+                Some(id) => id,
+                None => continue,
+            };
+            // The image is opened through the fd the disk was given, if any.
+            let image = match (disk.fd, &disk.path) {
+                (Some(fd), _) => DeviceResource::fd(fd).ok(),
+                (None, Some(path)) => DeviceResource::file(path).ok(),
+                (None, None) => None,
+            };
+            let overlays = disk
+                .backing_overlays
+                .iter()
+                .chain(disk.overlay.iter())
+                .filter_map(|overlay| DeviceResource::file(overlay).ok());
+            for resource in image.into_iter().chain(overlays) {
+                device_resources.push((id.clone(), resource));
+            }
+        }
+        for net in config.net.iter().flatten() {
+            if let (Some(id), Some(tap)) = (&net.id, &net.tap) {
+                device_resources.push((id.clone(), DeviceResource::Tap(tap.clone())));
+            }
+        }
+        drop(config);
+
+        // The device counters are still reported when /proc can't be read,
+        // such as from a jail it isn't mounted in.
+        match resource_usage::counters(&guest_ranges, &device_resources) {
+            Ok(usage) => {
+                for (id, usage_counters) in usage {
+                    counters.entry(id).or_default().extend(usage_counters);
+                }
+            }
+            Err(e) => warn!("Error reading the host resources used by the VMM: {}", e),
+        }
+
+        Ok(counters)
+    }
+
+    /// Whether the VM has an emulated VT-d. Its state, including the
+    /// translation tables the guest points it to, isn't part of the
+    /// snapshots, which is why such a VM can't be snapshot nor migrated.
+    pub fn has_vtd(&self) -> bool {
+        self.config.lock().unwrap().iommu_type() == IommuType::Vtd
+    }
+
+    pub fn memory_regions(&self) -> Vec<MemoryRegionInfo> {
+        self.memory_manager.lock().unwrap().memory_regions_info()
+    }
+
+    /// Sends the input to the console driven through the API, and returns
+    /// its output.
+    pub fn console_exchange(&self, input: &[u8]) -> Result<ConsoleBuffer> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .console()
+            .api_exchange(input)
+            .map_err(Error::Console)?
+            .ok_or(Error::NoApiConsole)
+    }
+
+    /// Gathers the vCPU, memory and device metrics of the VM.
+    pub fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+
+        let mut vcpu_exits = Metric::new(
+            "vcpu_exits_total",
+            MetricType::Counter,
+            "Number of exits from the guest to the VMM, per vCPU.",
+        );
+        for (id, exits) in self.cpu_manager.lock().unwrap().vcpu_exits() {
+            vcpu_exits.add_sample("", &[("vcpu", &id.to_string())], exits as f64);
+        }
+        metrics.push(vcpu_exits);
+
+        let memory_size = self.config.lock().unwrap().memory.total_size();
+        let balloon_size = self.balloon_size();
+        let mut memory = Metric::new(
+            "memory_size_bytes",
+            MetricType::Gauge,
+            "Size of the guest memory.",
+        );
+        memory.add_sample("", &[], memory_size as f64);
+        metrics.push(memory);
+        let mut memory_actual = Metric::new(
+            "memory_actual_size_bytes",
+            MetricType::Gauge,
+            "Size of the guest memory, minus the memory held by the balloon.",
+        );
+        memory_actual.add_sample("", &[], memory_size.saturating_sub(balloon_size) as f64);
+        metrics.push(memory_actual);
+        if self.cold_pages.is_some() {
+            let mut offloaded = Metric::new(
+                "memory_offloaded_bytes_total",
+                MetricType::Counter,
+                "Guest memory paged out after being left untouched.",
+            );
+            offloaded.add_sample(
+                "",
+                &[],
+                self.cold_page_counters
+                    .offloaded_bytes
+                    .load(Ordering::Relaxed) as f64,
+            );
+            metrics.push(offloaded);
+            let mut refaulted = Metric::new(
+                "memory_refaulted_bytes_total",
+                MetricType::Counter,
+                "Guest memory paged out and then accessed again by the guest.",
+            );
+            refaulted.add_sample(
+                "",
+                &[],
+                self.cold_page_counters
+                    .refaulted_bytes
+                    .load(Ordering::Relaxed) as f64,
+            );
+            metrics.push(refaulted);
+        }
+
+        // The counters are grouped by name, each device being a different
+        // sample of the metric.
+        let mut device_counters: BTreeMap<&'static str, Vec<(String, u64)>> = BTreeMap::new();
+        for (device, counters) in self.device_manager.lock().unwrap().counters() {
+            for (name, value) in counters {
+                device_counters
+                    .entry(name)
+                    .or_default()
+                    .push((device.clone(), value.0));
+            }
+        }
+        for (name, mut samples) in device_counters {
+            samples.sort();
+            let mut metric = Metric::new(
+                &format!("device_{}_total", name),
+                MetricType::Counter,
+                &format!("Device \"{}\" counter.", name),
+            );
+            for (device, value) in samples {
+                metric.add_sample("", &[("device", &device)], value as f64);
+            }
+            metrics.push(metric);
+        }
+
+        metrics
+    }
+
+    // The signals the console signal handler acts on, the termination ones
+    // being left to the VMM when it handles them itself.
+    fn console_signals() -> &'static [libc::c_int] {
+        if crate::termination_signals_handled() {
+            &[SIGWINCH]
+        } else {
+            &[SIGWINCH, SIGINT, SIGTERM]
+        }
     }
 
     fn os_signal_handler(
@@ -1731,6 +2337,18 @@ impl Vm {
         )
         .map_err(Error::PopulateHob)?;
 
+        // The bounce buffers are shared with the host, not private memory.
+        let bounce_buffer = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .bounce_buffer()
+            .map(|b| b.range());
+        if let Some((start, size)) = bounce_buffer {
+            hob.add_shared_memory_resource(&mem, start.raw_value(), size)
+                .map_err(Error::PopulateHob)?;
+        }
+
         hob.finish(&mem).map_err(Error::PopulateHob)?;
 
         Ok(hob_offset)
@@ -1815,6 +2433,15 @@ impl Vm {
             self.vm.tdx_finalize().map_err(Error::FinalizeTdx)?;
         }
 
+        // The guest memory mapping of the VFIO devices overlapped with the
+        // VM creation so far, but must be complete before the guest runs.
+        #[cfg(feature = "kvm")]
+        self.device_manager
+            .lock()
+            .unwrap()
+            .wait_vfio_dma_mappings()
+            .map_err(Error::DeviceManager)?;
+
         self.cpu_manager
             .lock()
             .unwrap()
@@ -1829,7 +2456,7 @@ impl Vm {
             .input_enabled()
         {
             let console = self.device_manager.lock().unwrap().console().clone();
-            let signals = Signals::new(&[SIGWINCH, SIGINT, SIGTERM]);
+            let signals = Signals::new(Vm::console_signals());
             match signals {
                 Ok(signals) => {
                     self.signals = Some(signals.handle());
@@ -1865,12 +2492,133 @@ impl Vm {
             }
         }
 
+        self.start_input_replay()?;
+        self.start_cold_page_offload()?;
+        self.start_serial_flush()?;
+        if let Some(input_log) = self.device_manager.lock().unwrap().input_log() {
+            input_log.resume();
+        }
+
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         *state = new_state;
         event!("vm", "booted");
         Ok(())
     }
 
+    // Spawns the thread passing the inputs replayed from the input log to the
+    // guest, at the time they were recorded at.
+    fn start_input_replay(&mut self) -> Result<()> {
+        let dm = self.device_manager.lock().unwrap();
+        let input_log = match dm.input_log() {
+            Some(input_log) if input_log.is_replaying() => input_log.clone(),
+            _ => return Ok(()),
+        };
+        let console = dm.console().clone();
+        drop(dm);
+
+        let input_replay_seccomp_filter =
+            get_seccomp_filter(&self.seccomp_action, Thread::InputReplay)
+                .map_err(Error::CreateSeccompFilter)?;
+        self.threads.push(
+            thread::Builder::new()
+                .name("input_replay".to_string())
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(input_replay_seccomp_filter)
+                        .map_err(Error::ApplySeccompFilter)
+                    {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+
+                    while let Some(record) = input_log.next_replayed_input() {
+                        if let Err(e) = console.replay_input(&record) {
+                            warn!("Failed replaying {:?} input: {}", record.kind, e);
+                        }
+                    }
+                })
+                .map_err(Error::InputReplaySpawn)?,
+        );
+
+        Ok(())
+    }
+
+    // Spawns the thread paging out the guest pages left untouched for the
+    // configured time.
+    fn start_cold_page_offload(&mut self) -> Result<()> {
+        let period = match self.config.lock().unwrap().memory.cold_page_offload {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => return Ok(()),
+        };
+
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let mut scanner = ColdPageScanner::new(guest_memory, self.cold_page_counters.clone())
+            .map_err(Error::ColdPageOffload)?;
+        let cold_pages_seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::ColdPages)
+            .map_err(Error::CreateSeccompFilter)?;
+        let (stop_sender, stop_receiver) = channel();
+        self.threads.push(
+            thread::Builder::new()
+                .name("cold_pages".to_string())
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(cold_pages_seccomp_filter)
+                        .map_err(Error::ApplySeccompFilter)
+                    {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+
+                    scanner.run(period, stop_receiver);
+                })
+                .map_err(Error::ColdPageOffload)?,
+        );
+        self.cold_pages = Some(stop_sender);
+
+        Ok(())
+    }
+
+    // Spawns the thread flushing the serial port writes the guest coalesced.
+    // A guest waiting for the interrupt the serial port raises once the data
+    // is sent doesn't exit, so the writes would otherwise stay queued until a
+    // vCPU exits for another reason.
+    fn start_serial_flush(&mut self) -> Result<()> {
+        const SERIAL_FLUSH_INTERVAL: Duration = Duration::from_millis(1);
+
+        if !self.config.lock().unwrap().serial.coalesce {
+            return Ok(());
+        }
+
+        let vm = self.vm.clone();
+        // The writes are replayed as they are on the exits of the vCPUs.
+        let serial_flush_seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::Vcpu)
+            .map_err(Error::CreateSeccompFilter)?;
+        let (stop_sender, stop_receiver) = channel();
+        self.threads.push(
+            thread::Builder::new()
+                .name("serial_flush".to_string())
+                .spawn(move || {
+                    if let Err(e) = SeccompFilter::apply(serial_flush_seccomp_filter)
+                        .map_err(Error::ApplySeccompFilter)
+                    {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+
+                    while let Err(RecvTimeoutError::Timeout) =
+                        stop_receiver.recv_timeout(SERIAL_FLUSH_INTERVAL)
+                    {
+                        if let Err(e) = vm.flush_coalesced_mmio() {
+                            error!("Error flushing the serial port writes: {}", e);
+                            return;
+                        }
+                    }
+                })
+                .map_err(Error::SerialFlushSpawn)?,
+        );
+        self.serial_flush = Some(stop_sender);
+
+        Ok(())
+    }
+
     pub fn handle_pty(&self) -> Result<()> {
         // Could be a little dangerous, picks up a lock on device_manager
         // and goes into a blocking read. If the epoll loops starts to be
@@ -1941,6 +2689,101 @@ impl Vm {
             .map(|state| *state)
     }
 
+    /// Sleep state the guest entered through the ACPI sleep control
+    /// register, if it hasn't been woken up since then.
+    #[cfg(feature = "acpi")]
+    pub fn sleep_state(&self) -> Option<u8> {
+        self.device_manager.lock().unwrap().sleep_state()
+    }
+
+    /// Parks the vCPUs once the guest entered the S3 sleep state. Contrary
+    /// to a pause, the devices keep running and the guest is expected to
+    /// resume from where it left off.
+    #[cfg(feature = "acpi")]
+    pub fn suspend(&mut self) -> Result<()> {
+        event!("vm", "suspending");
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+        let new_state = VmState::Suspended;
+
+        state.valid_transition(new_state)?;
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .pause()
+            .map_err(Error::Pause)?;
+
+        *state = new_state;
+        event!("vm", "suspended");
+        Ok(())
+    }
+
+    /// Sets the wake status the guest polls after entering a sleep state,
+    /// and unparks the vCPUs if the VM has been suspended.
+    #[cfg(feature = "acpi")]
+    pub fn wake(&mut self) -> Result<()> {
+        event!("vm", "waking");
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+
+        self.device_manager.lock().unwrap().wake();
+
+        if *state == VmState::Suspended {
+            let new_state = VmState::Running;
+            state.valid_transition(new_state)?;
+
+            self.cpu_manager
+                .lock()
+                .unwrap()
+                .resume()
+                .map_err(Error::Resume)?;
+
+            *state = new_state;
+        }
+
+        event!("vm", "woken");
+        Ok(())
+    }
+
+    /// Time left before the RTC alarm programmed by the guest fires.
+    pub fn rtc_alarm_timeout(&self) -> Option<std::time::Duration> {
+        self.device_manager.lock().unwrap().rtc_alarm_timeout()
+    }
+
+    /// Lets the guest know the RTC alarm is the reason it's being woken up.
+    pub fn rtc_alarm_fired(&self) {
+        self.device_manager.lock().unwrap().rtc_alarm_fired();
+    }
+
+    /// Catches up with the time the host spent since the VM was paused
+    /// before being snapshotted. The kvmclock of the guest is advanced by
+    /// this time, and the event monitor reports it for an agent in the
+    /// guest to resync its clock from the RTC.
+    pub fn inject_host_time(&mut self) {
+        let host_time = match self.saved_host_time {
+            Some(host_time) => host_time,
+            None => {
+                warn!("No host time in the snapshot, leaving the guest clock as is");
+                return;
+            }
+        };
+        let now = host_time_ns();
+        let elapsed = now.saturating_sub(host_time);
+
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Some(clock) = self.saved_clock.as_mut() {
+            clock.clock += elapsed;
+            self.cpu_manager.lock().unwrap().notify_guest_clock_paused();
+        }
+        self.saved_host_time = Some(now);
+
+        event!(
+            "vm",
+            "host-time-injected",
+            "elapsed_ns",
+            elapsed.to_string()
+        );
+    }
+
     /// Load saved clock from snapshot
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     pub fn load_clock_from_snapshot(
@@ -2005,6 +2848,12 @@ impl Vm {
         let mut gic_device = create_gic(&self.vm, vcpu_numbers.try_into().unwrap())
             .map_err(|e| MigratableError::Restore(anyhow!("Could not create GIC: {:#?}", e)))?;
 
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .init_pmu()
+            .map_err(|e| MigratableError::Restore(anyhow!("Could not initialize PMU: {:?}", e)))?;
+
         // Here we prepare the GICR_TYPER registers from the restored vCPU states.
         gic_device.set_gicr_typers(&saved_vcpu_states);
 
@@ -2058,6 +2907,42 @@ impl Vm {
         self.device_manager.lock().unwrap().balloon_size()
     }
 
+    /// Gets how far, and with which priority, the balloon can be inflated
+    /// under host memory pressure, if at all. The balloon never grows past
+    /// the guest memory.
+    pub fn balloon_pressure_config(&self) -> Option<(u32, u64)> {
+        let config = self.config.lock().unwrap();
+        let memory_size = config.memory.total_size();
+        config
+            .balloon
+            .as_ref()
+            .filter(|balloon| balloon.pressure_max > 0)
+            .map(|balloon| {
+                (
+                    balloon.pressure_priority,
+                    balloon
+                        .pressure_max
+                        .min(memory_size.saturating_sub(balloon.size)),
+                )
+            })
+    }
+
+    /// Inflates the balloon `inflated` bytes past its configured size. The
+    /// configuration is left untouched, these bytes only relieving the host
+    /// for as long as it's under memory pressure.
+    pub fn inflate_balloon_for_pressure(&mut self, inflated: u64) -> Result<()> {
+        let size = match &self.config.lock().unwrap().balloon {
+            Some(balloon) => balloon.size,
+            None => return Ok(()),
+        };
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resize_balloon(size + inflated)
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn receive_memory_regions<F>(
         &mut self,
         ranges: &MemoryRangeTable,
@@ -2119,6 +3004,26 @@ impl Vm {
         Ok(table)
     }
 
+    /// Snapshots the paused VM to `destination_url`. Given the URL of the
+    /// snapshot last taken or restored from as its parent, the snapshot is
+    /// incremental, only holding the guest memory written since.
+    pub fn snapshot_to(&mut self, destination_url: &str, parent_url: Option<&str>) -> Result<()> {
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .prepare_snapshot(parent_url)
+            .map_err(Error::Snapshot)?;
+        let snapshot = self.snapshot().map_err(Error::Snapshot)?;
+        self.send(&snapshot, destination_url)
+            .map_err(Error::SnapshotSend)?;
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .snapshot_sent(destination_url);
+
+        Ok(())
+    }
+
     pub fn start_memory_dirty_log(&self) -> std::result::Result<(), MigratableError> {
         self.memory_manager.lock().unwrap().start_memory_dirty_log()
     }
@@ -2137,11 +3042,45 @@ impl Vm {
     }
 
     pub fn activate_virtio_devices(&self) -> Result<()> {
+        let mut device_manager = self.device_manager.lock().unwrap();
+        device_manager.restore_vfio_bindings();
+        device_manager
+            .activate_virtio_devices()
+            .map_err(Error::ActivateVirtioDevices)
+    }
+
+    /// Handles the failure of a worker thread of a virtio device, which is
+    /// recorded in the device tree before the policy of the VM is applied.
+    pub fn handle_worker_failure(&mut self, failure: &WorkerFailure) -> Result<()> {
+        event!(
+            "vm",
+            "device-worker-failed",
+            "id",
+            &failure.device_id,
+            "thread",
+            &failure.thread
+        );
+
+        let policy = self.config.lock().unwrap().on_worker_failure;
         self.device_manager
             .lock()
             .unwrap()
-            .activate_virtio_devices()
-            .map_err(Error::ActivateVirtioDevices)
+            .handle_worker_failure(failure, policy == WorkerFailurePolicy::Reset)
+            .map_err(Error::WorkerFailure)?;
+
+        match policy {
+            WorkerFailurePolicy::Ignore => {}
+            WorkerFailurePolicy::Pause => {
+                if self.get_state()? == VmState::Running {
+                    self.pause().map_err(Error::Pause)?;
+                }
+            }
+            WorkerFailurePolicy::Reset => {
+                event!("vm", "device-restarted", "id", &failure.device_id);
+            }
+        }
+
+        Ok(())
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -2165,6 +3104,319 @@ impl Vm {
             .notify_power_button()
             .map_err(Error::PowerButton)
     }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sleep_button(&self) -> Result<()> {
+        #[cfg(feature = "acpi")]
+        return self
+            .device_manager
+            .lock()
+            .unwrap()
+            .notify_sleep_button()
+            .map_err(Error::SleepButton);
+        #[cfg(not(feature = "acpi"))]
+        Err(Error::SleepButtonNotSupported)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn sleep_button(&self) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_sleep_button()
+            .map_err(Error::SleepButton)
+    }
+
+    /// Sends an NMI to all the vCPUs, for the guest to dump its state or
+    /// crash on purpose, depending on how it's set up.
+    pub fn nmi(&self) -> Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        return self
+            .device_manager
+            .lock()
+            .unwrap()
+            .inject_nmi()
+            .map_err(Error::Nmi);
+        #[cfg(not(target_arch = "x86_64"))]
+        Err(Error::NmiNotSupported)
+    }
+
+    /// Updates the state of the AC adapter and battery, keeping it in the
+    /// config for the VM to be rebooted or restored with the same state.
+    #[cfg(feature = "acpi")]
+    pub fn set_power_supply(
+        &mut self,
+        ac_online: Option<bool>,
+        battery_level: Option<u8>,
+    ) -> Result<()> {
+        let mut power_supply = self
+            .config
+            .lock()
+            .unwrap()
+            .power_supply
+            .clone()
+            .ok_or(Error::PowerSupplyNotSupported)?;
+        if let Some(ac_online) = ac_online {
+            power_supply.ac_online = ac_online;
+        }
+        if let Some(battery_level) = battery_level {
+            power_supply.battery_level = battery_level;
+        }
+        power_supply.validate().map_err(Error::ConfigValidation)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_power_supply(power_supply.ac_online, power_supply.battery_level)
+            .map_err(Error::PowerSupply)?;
+        self.config.lock().unwrap().power_supply = Some(power_supply);
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "acpi"))]
+    pub fn set_power_supply(
+        &mut self,
+        _ac_online: Option<bool>,
+        _battery_level: Option<u8>,
+    ) -> Result<()> {
+        Err(Error::PowerSupplyNotSupported)
+    }
+
+    /// Sets the link state of a virtio-net device. The state isn't kept in
+    /// the config, the link being up again once the VM is rebooted.
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_net_link(id, up)
+            .map_err(Error::SetNetLink)
+    }
+
+    // Switches a disk to a new disk image through `switch`, once the
+    // requests in flight completed. They are waited for without the device
+    // manager being locked, as the vCPUs may need it meanwhile.
+    fn switch_disk<F>(&self, id: &str, switch: F) -> device_manager::DeviceManagerResult<()>
+    where
+        F: FnOnce(&mut DeviceManager, DiskSwitchRequest) -> device_manager::DeviceManagerResult<()>,
+    {
+        let request = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .request_disk_switch(id)?;
+        request
+            .wait_drained()
+            .map_err(DeviceManagerError::DiskSwitch)?;
+        switch(&mut self.device_manager.lock().unwrap(), request)
+    }
+
+    /// Starts mirroring a disk onto a new backing file, the guest keeping
+    /// the use of the disk.
+    pub fn mirror_disk(&mut self, id: &str, path: &Path) -> Result<()> {
+        self.switch_disk(id, |device_manager, request| {
+            device_manager.mirror_disk(id, path, request)
+        })
+        .map_err(Error::MirrorDisk)
+    }
+
+    /// Switches a disk to the backing file it has been mirrored onto. The
+    /// config is updated for the VM to be rebooted with the new file.
+    pub fn pivot_disk(&mut self, id: &str) -> Result<()> {
+        self.switch_disk(id, |device_manager, request| {
+            device_manager.pivot_disk(id, request)
+        })
+        .map_err(Error::PivotDisk)
+    }
+
+    /// Redirects the writes to a disk onto a new copy-on-write overlay,
+    /// which the VM keeps using once rebooted.
+    pub fn disk_snapshot(&mut self, id: &str, overlay: &Path) -> Result<()> {
+        self.switch_disk(id, |device_manager, request| {
+            device_manager.snapshot_disk(id, overlay, request)
+        })
+        .map_err(Error::DiskSnapshot)
+    }
+
+    /// Returns the disks to switch to a new overlay in `overlay_dir` for a
+    /// checkpoint, along with their overlay, making sure they all can be.
+    pub fn checkpoint_disks(&self, overlay_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+        let mut disks = Vec::new();
+        for disk in self.config.lock().unwrap().disks.iter().flatten() {
+            let id = disk.id.clone().unwrap_or_default();
+            let overlay = overlay_dir.join(format!("{}.overlay", id));
+            if disk.vhost_user || disk.path.is_none() || overlay.exists() {
+                return Err(Error::CheckpointDisk(id));
+            }
+            disks.push((id, overlay));
+        }
+
+        Ok(disks)
+    }
+
+    /// Starts copying the guest memory of the running VM to the checkpoint
+    /// at `destination_url` on its own thread, then the memory written
+    /// meanwhile, at most `passes` times or until none is left. `done` is
+    /// written once the copy is over, for it to be completed.
+    pub fn start_checkpoint(
+        &mut self,
+        destination_url: &str,
+        passes: u32,
+        mut throttle: CopyThrottle,
+        done: EventFd,
+    ) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::Checkpoint(MigratableError::Snapshot(anyhow!(
+                "Trying to checkpoint while VM is not running"
+            ))));
+        }
+        if self.checkpoint.is_some() {
+            return Err(Error::CheckpointInProgress);
+        }
+
+        let memory_manager = self.memory_manager.clone();
+        let guest_memory = memory_manager.lock().unwrap().guest_memory();
+        self.start_memory_dirty_log().map_err(Error::Checkpoint)?;
+        let table = self.memory_range_table().map_err(Error::Checkpoint)?;
+        let destination_url = destination_url.to_owned();
+        let pass = Arc::new(AtomicU32::new(0));
+        let copy_pass = pass.clone();
+        let copied = throttle.copied_counter();
+        let cancel = throttle.cancel_flag();
+        let (result_sender, result) = channel();
+
+        let checkpoint_seccomp_filter = get_seccomp_filter(&self.seccomp_action, Thread::Vmm)
+            .map_err(Error::CreateSeccompFilter)?;
+        self.threads.push(
+            thread::Builder::new()
+                .name("checkpoint".to_string())
+                .spawn(move || {
+                    let result = SeccompFilter::apply(checkpoint_seccomp_filter)
+                        .map_err(|e| {
+                            MigratableError::Snapshot(anyhow!(
+                                "Error applying seccomp filter: {:?}",
+                                e
+                            ))
+                        })
+                        .and_then(|_| {
+                            let mut table = table;
+                            loop {
+                                MemoryManager::precopy_memory(
+                                    &guest_memory.memory(),
+                                    &destination_url,
+                                    &table,
+                                    &mut throttle,
+                                )?;
+                                let pass = copy_pass.load(Ordering::Acquire);
+                                event!(
+                                    "vm",
+                                    "checkpoint-progress",
+                                    "pass",
+                                    pass.to_string(),
+                                    "copied_bytes",
+                                    throttle.copied().to_string()
+                                );
+                                if pass == passes {
+                                    return Ok(pass);
+                                }
+
+                                table =
+                                    memory_manager.lock().unwrap().dirty_memory_range_table()?;
+                                if table.regions().is_empty() {
+                                    return Ok(pass);
+                                }
+                                copy_pass.store(pass + 1, Ordering::Release);
+                            }
+                        });
+
+                    // Nobody waits for the result anymore once the VM is shut
+                    // down, the checkpoint still being over.
+                    let _ = result_sender.send((result, throttle));
+                    if let Err(e) = done.write(1) {
+                        error!("Error signalling the checkpoint copy is over: {}", e);
+                    }
+                })
+                .map_err(Error::CheckpointSpawn)?,
+        );
+        self.checkpoint = Some(CheckpointCopy {
+            pass,
+            copied,
+            cancel,
+            result,
+        });
+
+        Ok(())
+    }
+
+    /// Pass and bytes copied so far of the checkpoint being taken.
+    pub fn checkpoint_progress(&self) -> Option<(u32, u64)> {
+        self.checkpoint.as_ref().map(|checkpoint| {
+            (
+                checkpoint.pass.load(Ordering::Acquire),
+                checkpoint.copied.load(Ordering::Acquire),
+            )
+        })
+    }
+
+    /// Returns the number of passes of the copy of the guest memory to the
+    /// checkpoint being taken, once over, along with its throttle.
+    pub fn finish_checkpoint_copy(&mut self) -> Result<(u32, CopyThrottle)> {
+        let checkpoint = self
+            .checkpoint
+            .take()
+            .ok_or(Error::NoCheckpointInProgress)?;
+        let (result, throttle) = checkpoint.result.recv().map_err(|_| {
+            Error::Checkpoint(MigratableError::Snapshot(anyhow!(
+                "Checkpoint copy thread exited"
+            )))
+        })?;
+
+        Ok((result.map_err(Error::Checkpoint)?, throttle))
+    }
+
+    /// Completes the checkpoint at `destination_url` of the paused VM,
+    /// copying the guest memory written since the last pass, and saving
+    /// the state of the VM. The `disks` are then switched to their new
+    /// overlay, their image being left as it was when the VM was paused.
+    pub fn complete_checkpoint(
+        &mut self,
+        destination_url: &str,
+        disks: &[(String, PathBuf)],
+        throttle: &mut CopyThrottle,
+    ) -> Result<()> {
+        throttle.unthrottle();
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let table = self.dirty_memory_range_table().map_err(Error::Checkpoint)?;
+        MemoryManager::precopy_memory(&guest_memory.memory(), destination_url, &table, throttle)
+            .map_err(Error::Checkpoint)?;
+
+        self.memory_manager.lock().unwrap().prepare_checkpoint();
+        let snapshot = self.snapshot().map_err(Error::Snapshot)?;
+        self.send(&snapshot, destination_url)
+            .map_err(Error::SnapshotSend)?;
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .snapshot_sent(destination_url);
+
+        for (id, overlay) in disks {
+            self.disk_snapshot(id, overlay)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        // Moved out of the device manager, which is dropped first, once the
+        // devices are closed.
+        if let Ok(mut device_manager) = self.device_manager.lock() {
+            #[cfg(feature = "kvm")]
+            device_manager.release_vfio_devices();
+            self.vfio_bindings = device_manager.take_vfio_bindings();
+        }
+    }
 }
 
 impl Pausable for Vm {
@@ -2190,8 +3442,12 @@ impl Pausable for Vm {
             clock.flags = 0;
             self.saved_clock = Some(clock);
         }
+        self.saved_host_time = Some(host_time_ns());
         self.cpu_manager.lock().unwrap().pause()?;
         self.device_manager.lock().unwrap().pause()?;
+        if let Some(input_log) = self.device_manager.lock().unwrap().input_log() {
+            input_log.pause();
+        }
 
         *state = new_state;
 
@@ -2221,6 +3477,9 @@ impl Pausable for Vm {
             }
         }
         self.device_manager.lock().unwrap().resume()?;
+        if let Some(input_log) = self.device_manager.lock().unwrap().input_log() {
+            input_log.resume();
+        }
 
         // And we're back to the Running state.
         *state = new_state;
@@ -2234,9 +3493,18 @@ pub struct VmSnapshot {
     pub config: Arc<Mutex<VmConfig>>,
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     pub clock: Option<hypervisor::ClockData>,
+    #[serde(default)]
+    pub host_time: Option<u64>,
     pub state: Option<hypervisor::VmState>,
 }
 
+// Returns the host time in nanoseconds since the UNIX epoch.
+fn host_time_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_nanos() as u64)
+}
+
 pub const VM_SNAPSHOT_ID: &str = "vm";
 impl Snapshottable for Vm {
     fn id(&self) -> String {
@@ -2255,6 +3523,12 @@ impl Snapshottable for Vm {
             }
         }
 
+        if self.has_vtd() {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Snapshot not possible with the emulated VT-d"
+            )));
+        }
+
         let current_state = self.get_state().unwrap();
         if current_state != VmState::Paused {
             return Err(MigratableError::Snapshot(anyhow!(
@@ -2271,6 +3545,7 @@ impl Snapshottable for Vm {
             config: self.get_config(),
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
             clock: self.saved_clock,
+            host_time: self.saved_host_time,
             state: Some(vm_state),
         })
         .map_err(|e| MigratableError::Snapshot(e.into()))?;
@@ -2303,6 +3578,8 @@ impl Snapshottable for Vm {
             MigratableError::Restore(anyhow!("Could not restore VM state: {:#?}", e))
         })?;
 
+        self.saved_host_time = get_vm_snapshot(&snapshot)?.host_time;
+
         if let Some(memory_manager_snapshot) = snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID) {
             self.memory_manager
                 .lock()
@@ -2350,6 +3627,15 @@ impl Snapshottable for Vm {
             )));
         }
 
+        #[cfg(feature = "kvm")]
+        self.device_manager
+            .lock()
+            .unwrap()
+            .wait_vfio_dma_mappings()
+            .map_err(|e| {
+                MigratableError::Restore(anyhow!("Cannot map the guest memory: {:#?}", e))
+            })?;
+
         // Now we can start all vCPUs from here.
         self.cpu_manager
             .lock()
@@ -2367,7 +3653,7 @@ impl Snapshottable for Vm {
             .input_enabled()
         {
             let console = self.device_manager.lock().unwrap().console().clone();
-            let signals = Signals::new(&[SIGWINCH, SIGINT, SIGTERM]);
+            let signals = Signals::new(Vm::console_signals());
             match signals {
                 Ok(signals) => {
                     self.signals = Some(signals.handle());
@@ -2420,6 +3706,17 @@ impl Snapshottable for Vm {
             }
         }
 
+        // The inputs are replayed once the restored VM is resumed.
+        self.start_input_replay().map_err(|e| {
+            MigratableError::Restore(anyhow!("Could not start replaying the inputs: {:?}", e))
+        })?;
+        self.start_cold_page_offload().map_err(|e| {
+            MigratableError::Restore(anyhow!("Could not start offloading cold pages: {:?}", e))
+        })?;
+        self.start_serial_flush().map_err(|e| {
+            MigratableError::Restore(anyhow!("Could not start flushing the serial port: {:?}", e))
+        })?;
+
         let mut state = self
             .state
             .try_write()
@@ -2486,6 +3783,7 @@ mod tests {
                 assert!(state.valid_transition(VmState::Running).is_ok());
                 assert!(state.valid_transition(VmState::Shutdown).is_err());
                 assert!(state.valid_transition(VmState::Paused).is_ok());
+                assert!(state.valid_transition(VmState::Suspended).is_err());
             }
             VmState::Running => {
                 // Check the transitions from Running
@@ -2493,6 +3791,7 @@ mod tests {
                 assert!(state.valid_transition(VmState::Running).is_err());
                 assert!(state.valid_transition(VmState::Shutdown).is_ok());
                 assert!(state.valid_transition(VmState::Paused).is_ok());
+                assert!(state.valid_transition(VmState::Suspended).is_ok());
             }
             VmState::Shutdown => {
                 // Check the transitions from Shutdown
@@ -2500,6 +3799,7 @@ mod tests {
                 assert!(state.valid_transition(VmState::Running).is_ok());
                 assert!(state.valid_transition(VmState::Shutdown).is_err());
                 assert!(state.valid_transition(VmState::Paused).is_err());
+                assert!(state.valid_transition(VmState::Suspended).is_err());
             }
             VmState::Paused => {
                 // Check the transitions from Paused
@@ -2507,6 +3807,15 @@ mod tests {
                 assert!(state.valid_transition(VmState::Running).is_ok());
                 assert!(state.valid_transition(VmState::Shutdown).is_ok());
                 assert!(state.valid_transition(VmState::Paused).is_err());
+                assert!(state.valid_transition(VmState::Suspended).is_err());
+            }
+            VmState::Suspended => {
+                // Check the transitions from Suspended
+                assert!(state.valid_transition(VmState::Created).is_err());
+                assert!(state.valid_transition(VmState::Running).is_ok());
+                assert!(state.valid_transition(VmState::Shutdown).is_ok());
+                assert!(state.valid_transition(VmState::Paused).is_err());
+                assert!(state.valid_transition(VmState::Suspended).is_err());
             }
         }
     }
@@ -2530,6 +3839,11 @@ mod tests {
     fn test_vm_paused_transitions() {
         test_vm_state_transitions(VmState::Paused);
     }
+
+    #[test]
+    fn test_vm_suspended_transitions() {
+        test_vm_state_transitions(VmState::Suspended);
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -2588,6 +3902,7 @@ mod tests {
             &*gic,
             &None,
             &(0x1_0000_0000, 0x1_0000),
+            false,
         )
         .is_ok())
     }